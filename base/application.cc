@@ -1,6 +1,8 @@
 #include "base/application.h"
+#include "base/exit_codes.h"
 
 #include <boost/lexical_cast.hpp>
+#include <errno.h>
 #include <libgen.h>
 #include <linux/limits.h>
 #include <string.h>
@@ -21,7 +23,7 @@ command::die(string const &msg)
 {
 	cerr << msg << endl;
 	usage(cerr);
-	exit(1);
+	exit(exit_codes::USAGE);
 }
 
 ::uint64_t
@@ -46,6 +48,91 @@ command::parse_uint64(char const *str, char const *desc)
 	return 0; // never get here
 }
 
+namespace {
+	bool unit_multiplier(char c, ::uint64_t &result) {
+		char const *binary = "bskmgtpe";
+		char const *decimal = "..KMGTPE";
+
+		if (c == 'b') {
+			result = 1;
+			return true;
+		}
+
+		if (c == 's') {
+			result = 512;
+			return true;
+		}
+
+		char const *b = strchr(binary + 2, c);
+		char const *d = strchr(decimal + 2, c);
+		if (!b && !d)
+			return false;
+
+		unsigned power = b ? (b - binary - 1) : (d - decimal - 1);
+		result = 1;
+		for (unsigned i = 0; i < power; i++)
+			result *= b ? 1024ull : 1000ull;
+
+		return true;
+	}
+}
+
+::uint64_t
+command::parse_size(char const *str, char const *desc,
+		    ::uint64_t default_multiplier, bool *has_suffix)
+{
+	if (!str) {
+		ostringstream out;
+		out << "Couldn't parse " << desc << ": NULL";
+		die(out.str());
+	}
+
+	char *end;
+	errno = 0;
+	unsigned long long n = strtoull(str, &end, 10);
+	::uint64_t multiplier = default_multiplier;
+
+	bool bad = errno || end == str || *str == '-';
+	if (!bad && *end) {
+		bad = end[1] != '\0' || !unit_multiplier(*end, multiplier);
+	}
+
+	if (bad) {
+		ostringstream out;
+		out << "Couldn't parse " << desc << ": '" << str << "'";
+		die(out.str());
+	}
+
+	if (n && multiplier > UINT64_MAX / n) {
+		ostringstream out;
+		out << desc << " is too large: '" << str << "'";
+		die(out.str());
+	}
+
+	if (has_suffix)
+		*has_suffix = *end != '\0';
+
+	return n * multiplier;
+}
+
+::uint64_t
+command::parse_sectors(char const *str, char const *desc)
+{
+	bool has_suffix;
+	::uint64_t bytes = parse_size(str, desc, 512, &has_suffix);
+
+	if (bytes % 512) {
+		ostringstream out;
+		out << desc << " is not a multiple of 512 bytes: '" << str << "'";
+		die(out.str());
+	}
+
+	if (has_suffix)
+		cerr << desc << ": " << str << " = " << bytes / 512 << " sectors" << endl;
+
+	return bytes / 512;
+}
+
 //----------------------------------------------------------------
 
 int
@@ -21,6 +21,18 @@ namespace base {
 		void die(std::string const &msg);
 		uint64_t parse_uint64(char const *str, char const *desc);
 
+		// Parses a size such as '2048', '128k' or '4T' into bytes.
+		// Lower case suffixes are powers of two, upper case powers
+		// of ten, as with thin_metadata_size.  A number without a
+		// suffix is multiplied by default_multiplier.
+		uint64_t parse_size(char const *str, char const *desc,
+				    uint64_t default_multiplier,
+				    bool *has_suffix = NULL);
+
+		// As parse_size, returning whole sectors.  Plain numbers
+		// are already in sectors.
+		uint64_t parse_sectors(char const *str, char const *desc);
+
 
 		virtual void usage(std::ostream &out) const = 0;
 		virtual int run(int argc, char **argv) = 0;
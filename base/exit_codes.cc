@@ -0,0 +1,38 @@
+#include "base/exit_codes.h"
+
+#include "persistent-data/errors.h"
+
+#include <signal.h>
+#include <unistd.h>
+
+using namespace base;
+
+//----------------------------------------------------------------
+
+namespace {
+	void interrupted(int sig) {
+		_exit(exit_codes::INTERRUPTED);
+	}
+}
+
+//----------------------------------------------------------------
+
+int
+base::exit_code(std::exception const &e)
+{
+	if (dynamic_cast<checksum_error const *>(&e) ||
+	    dynamic_cast<metadata_damage const *>(&e))
+		return exit_codes::NEEDS_REPAIR;
+
+	return exit_codes::FATAL;
+}
+
+void
+base::exit_on_interrupt()
+{
+	signal(SIGINT, interrupted);
+	signal(SIGTERM, interrupted);
+	signal(SIGHUP, interrupted);
+}
+
+//----------------------------------------------------------------
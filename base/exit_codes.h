@@ -0,0 +1,32 @@
+#ifndef BASE_EXIT_CODES_H
+#define BASE_EXIT_CODES_H
+
+#include <exception>
+
+//----------------------------------------------------------------
+
+namespace base {
+	// The exit codes shared with the rust tools; see
+	// src/commands/exit_codes.rs.  Scripts rely on these, so values
+	// must never be reused.
+	namespace exit_codes {
+		int const SUCCESS = 0;
+		int const FATAL = 1;
+		int const USAGE = 2;
+		int const NEEDS_REPAIR = 3;
+		int const INTERRUPTED = 4;
+		int const TIMED_OUT = 5;
+	}
+
+	// Damaged metadata, whether found by a bad checksum or by a
+	// walk, needs repairing.  Anything else is fatal.
+	int exit_code(std::exception const &e);
+
+	// Exits with INTERRUPTED on SIGINT, SIGTERM or SIGHUP.  Only for
+	// tools that don't leave anything half written.
+	void exit_on_interrupt();
+}
+
+//----------------------------------------------------------------
+
+#endif
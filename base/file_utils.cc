@@ -5,6 +5,7 @@
 #include <sstream>
 #include <stdexcept>
 #include <linux/fs.h>
+#include <sys/file.h>
 #include <sys/ioctl.h>
 #include <sys/stat.h>
 #include <fcntl.h>
@@ -44,6 +45,27 @@ file_utils::file_descriptor::file_descriptor(string const &path, int flags) {
 	if (fd_ < 0)
 		syscall_failed("open",
 			       "Note: you cannot run this tool with these options on live metadata.");
+
+	// Exclusive opens are of offline metadata, take an advisory lock
+	// so a second tool can't write it while we're using it.  Without
+	// O_EXCL we're looking at live metadata, which the kernel is
+	// changing anyway.
+	if (flags & O_EXCL) {
+		int op = (flags & O_ACCMODE) == O_RDONLY ? LOCK_SH : LOCK_EX;
+		if (::flock(fd_, op | LOCK_NB) < 0) {
+			int e = errno;
+			::close(fd_);
+			fd_ = -1;
+
+			ostringstream out;
+			if (e == EWOULDBLOCK)
+				out << "'" << path << "' is in use by another tool, refusing to "
+				    << (op == LOCK_SH ? "read" : "write") << " it";
+			else
+				out << "syscall 'flock' failed: " << base::error_string(e);
+			throw runtime_error(out.str());
+		}
+	}
 }
 
 file_utils::file_descriptor::~file_descriptor() {
@@ -117,42 +139,74 @@ file_utils::open_block_file(string const &path, off_t min_size, bool writeable,
 	return file_descriptor(path, flags);
 }
 
-uint64_t
-file_utils::get_file_length(string const &file) {
-	struct stat info;
-	uint64_t nr_bytes;
-
-	int r = ::stat(file.c_str(), &info);
-	if (r) {
-		ostringstream msg;
-		msg << file << ": " << base::error_string(errno);
-		throw runtime_error(msg.str());
-	}
+namespace {
+	// Block devices have to be opened to query them, stat
+	// doesn't know their size.
+	template <typename T>
+	T query_block_device(string const &file, unsigned long request,
+			     char const *request_name) {
+		T result;
 
-	if (S_ISREG(info.st_mode))
-		// It's okay to cast st_size to a uint64_t value.
-		// If LFS is enabled, st_size should not be negative for regular files.
-		nr_bytes = static_cast<uint64_t>(info.st_size);
-	else if (S_ISBLK(info.st_mode)) {
-		// To get the size of a block device we need to
-		// open it, and then make an ioctl call.
 		int fd = ::open(file.c_str(), O_RDONLY);
 		if (fd < 0)
-			throw runtime_error("couldn't open block device to ascertain size");
+			throw runtime_error("couldn't open block device to query it");
 
-		r = ::ioctl(fd, BLKGETSIZE64, &nr_bytes);
+		int r = ::ioctl(fd, request, &result);
 		if (r) {
 			::close(fd);
-			throw runtime_error("ioctl BLKGETSIZE64 failed");
+			ostringstream msg;
+			msg << "ioctl " << request_name << " failed";
+			throw runtime_error(msg.str());
 		}
 		::close(fd);
-	} else {
-		ostringstream msg;
-		msg << file << ": " << "Not a block device or regular file";
-		throw runtime_error(msg.str());
+
+		return result;
+	}
+
+	struct stat stat_file(string const &file) {
+		struct stat info;
+
+		int r = ::stat(file.c_str(), &info);
+		if (r) {
+			ostringstream msg;
+			msg << file << ": " << base::error_string(errno);
+			throw runtime_error(msg.str());
+		}
+
+		if (!S_ISREG(info.st_mode) && !S_ISBLK(info.st_mode)) {
+			ostringstream msg;
+			msg << file << ": " << "Not a block device or regular file";
+			throw runtime_error(msg.str());
+		}
+
+		return info;
 	}
+}
+
+uint64_t
+file_utils::get_file_length(string const &file) {
+	struct stat info = stat_file(file);
+
+	if (S_ISREG(info.st_mode))
+		// It's okay to cast st_size to a uint64_t value.
+		// If LFS is enabled, st_size should not be negative for regular files.
+		return static_cast<uint64_t>(info.st_size);
+
+	return query_block_device<uint64_t>(file, BLKGETSIZE64, "BLKGETSIZE64");
+}
+
+unsigned
+file_utils::get_logical_block_size(string const &file) {
+	struct stat info = stat_file(file);
+
+	if (S_ISREG(info.st_mode))
+		return DEFAULT_LOGICAL_BLOCK_SIZE;
+
+	int size = query_block_device<int>(file, BLKSSZGET, "BLKSSZGET");
+	if (size <= 0)
+		throw runtime_error("BLKSSZGET returned a bad sector size");
 
-	return nr_bytes;
+	return static_cast<unsigned>(size);
 }
 
 void
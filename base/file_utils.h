@@ -18,11 +18,18 @@ namespace file_utils {
 		int fd_;
 	};
 
+	// Regular files can be accessed at any sector.
+	unsigned const DEFAULT_LOGICAL_BLOCK_SIZE = 512;
+
 	bool file_exists(std::string const &path);
 	void check_file_exists(std::string const &file, bool must_be_regular_file = true);
 	file_descriptor create_block_file(std::string const &path, off_t file_size);
 	file_descriptor open_block_file(std::string const &path, off_t min_size, bool writeable, bool excl = true);
 	uint64_t get_file_length(std::string const &file);
+
+	// The smallest unit of io the device accepts, eg, 4096 for
+	// 4Kn drives.
+	unsigned get_logical_block_size(std::string const &file);
 	void zero_superblock(std::string const &path);
 }
 
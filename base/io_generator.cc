@@ -1,6 +1,5 @@
 #include "base/io_generator.h"
 #include "base/sequence_generator.h"
-#include <chrono>
 #include <random>
 #include <stdexcept>
 #include <cstdlib>
@@ -32,17 +31,19 @@ namespace {
 	public:
 		typedef std::shared_ptr<op_generator> ptr;
 
-		op_generator(base::req_op op1)
+		op_generator(uint64_t seed,
+			     base::req_op op1)
 			: op1_(op1), op2_(op1), op1_pct_(100),
-			  rand_seed_(std::chrono::high_resolution_clock::now().time_since_epoch().count()),
+			  rand_seed_(seed),
 			  op_engine_(rand_seed_) {
 		}
 
-		op_generator(base::req_op op1,
+		op_generator(uint64_t seed,
+			     base::req_op op1,
 			     base::req_op op2,
 			     unsigned op1_pct)
 			: op1_(op1), op2_(op2), op1_pct_(op1_pct),
-			  rand_seed_(std::chrono::high_resolution_clock::now().time_since_epoch().count()),
+			  rand_seed_(seed),
 			  op_engine_(rand_seed_) {
 			if (op1_pct > 100)
 				throw std::runtime_error("invalid percentage");
@@ -112,7 +113,8 @@ namespace {
 		if (opts.pattern_.is_random())
 			gen = create_random_sequence_generator(opts.offset_,
 					opts.size_, opts.block_size_,
-					opts.nr_seq_blocks_);
+					opts.nr_seq_blocks_,
+					opts.seed_);
 		else
 			gen = create_forward_sequence_generator(opts.offset_,
 					opts.size_, opts.block_size_);
@@ -126,21 +128,23 @@ namespace {
 		switch (opts.pattern_.val_) {
 		case io_pattern::READ:
 		case io_pattern::RAND_READ:
-			return op_generator::ptr(new op_generator(base::REQ_OP_READ));
+			return op_generator::ptr(new op_generator(opts.seed_, base::REQ_OP_READ));
 		case io_pattern::WRITE:
 		case io_pattern::RAND_WRITE:
-			return op_generator::ptr(new op_generator(base::REQ_OP_WRITE));
+			return op_generator::ptr(new op_generator(opts.seed_, base::REQ_OP_WRITE));
 		case io_pattern::TRIM:
 		case io_pattern::RAND_TRIM:
-			return op_generator::ptr(new op_generator(base::REQ_OP_DISCARD));
+			return op_generator::ptr(new op_generator(opts.seed_, base::REQ_OP_DISCARD));
 		case io_pattern::READ_WRITE:
 		case io_pattern::RAND_RW:
-			return op_generator::ptr(new op_generator(base::REQ_OP_READ,
+			return op_generator::ptr(new op_generator(opts.seed_,
+								  base::REQ_OP_READ,
 								  base::REQ_OP_WRITE,
 								  50));
 		case io_pattern::TRIM_WRITE:
 		case io_pattern::RAND_TW:
-			return op_generator::ptr(new op_generator(base::REQ_OP_DISCARD,
+			return op_generator::ptr(new op_generator(opts.seed_,
+								  base::REQ_OP_DISCARD,
 								  base::REQ_OP_WRITE,
 								  50));
 		default:
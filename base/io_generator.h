@@ -37,6 +37,7 @@ namespace base {
 		sector_t size_;
 		sector_t io_size_;
 		unsigned nr_seq_blocks_;
+		uint64_t seed_;
 	};
 
 	class io_generator {
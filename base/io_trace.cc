@@ -0,0 +1,193 @@
+#include "base/io_trace.h"
+
+#include <fstream>
+#include <sstream>
+#include <stdexcept>
+#include <vector>
+
+using namespace base;
+using namespace std;
+
+//----------------------------------------------------------------
+
+namespace {
+	struct extent {
+		req_op op_;
+		sector_t begin_;
+		sector_t end_;
+	};
+
+	vector<string> split(string const &line) {
+		istringstream in(line);
+		vector<string> tokens;
+		string t;
+		while (in >> t)
+			tokens.push_back(t);
+		return tokens;
+	}
+
+	uint64_t to_uint64(string const &str) {
+		size_t end;
+		uint64_t n = stoull(str, &end);
+		if (end != str.size())
+			throw runtime_error("bad number in trace: '" + str + "'");
+		return n;
+	}
+
+	// eg, '  8,0    3        1     0.000000000   697  Q  WS 3417048 + 8 [kjournald]'
+	bool parse_blktrace(vector<string> const &tokens, extent &e) {
+		if (tokens.size() < 10 || tokens[5] != "Q" || tokens[8] != "+")
+			return false;
+
+		string const &rwbs = tokens[6];
+		if (rwbs.find('D') != string::npos)
+			e.op_ = REQ_OP_DISCARD;
+		else if (rwbs.find('W') != string::npos)
+			e.op_ = REQ_OP_WRITE;
+		else
+			return false;
+
+		e.begin_ = to_uint64(tokens[7]);
+		e.end_ = e.begin_ + to_uint64(tokens[9]);
+		return true;
+	}
+
+	// v2: '<file> <action> <offset> <length>'
+	// v3: '<timestamp> <file> <action> <offset> <length>'
+	// offsets and lengths are in bytes.
+	bool parse_fio(vector<string> const &tokens, extent &e) {
+		size_t i;
+		if (tokens.size() == 4)
+			i = 1;
+		else if (tokens.size() == 5)
+			i = 2;
+		else
+			return false;
+
+		if (tokens[i] == "write")
+			e.op_ = REQ_OP_WRITE;
+		else if (tokens[i] == "trim")
+			e.op_ = REQ_OP_DISCARD;
+		else
+			return false;
+
+		// A discard only covers the sectors it fully spans.
+		uint64_t offset = to_uint64(tokens[i + 1]);
+		uint64_t len = to_uint64(tokens[i + 2]);
+		if (e.op_ == REQ_OP_DISCARD) {
+			e.begin_ = (offset + 511) / 512;
+			e.end_ = (offset + len) / 512;
+		} else {
+			e.begin_ = offset / 512;
+			e.end_ = (offset + len + 511) / 512;
+		}
+		return true;
+	}
+
+	class trace_io_generator : public io_generator {
+	public:
+		trace_io_generator(string const &path,
+				   sector_t block_size,
+				   sector_t offset,
+				   sector_t size)
+			: in_(path.c_str()),
+			  block_size_(block_size),
+			  offset_(offset),
+			  size_(size),
+			  fio_(false),
+			  pending_(false) {
+
+			if (!in_)
+				throw runtime_error("couldn't open trace '" + path + "'");
+
+			if (!block_size_)
+				throw runtime_error("block size must be non-zero");
+
+			// fio logs start with 'fio version N iolog'
+			string line;
+			streampos start = in_.tellg();
+			if (getline(in_, line) && line.compare(0, 11, "fio version") == 0)
+				fio_ = true;
+			else {
+				in_.clear();
+				in_.seekg(start);
+			}
+		}
+
+		virtual bool next(base::io &next_io) {
+			while (!pending_ || current_.begin_ >= current_.end_) {
+				pending_ = false;
+				if (!next_extent(current_))
+					return false;
+				pending_ = true;
+			}
+
+			// one io per block touched by the extent, or
+			// covered by a discard
+			sector_t b = current_.begin_ / block_size_;
+			next_io.op_ = current_.op_;
+			next_io.sector_ = b * block_size_;
+			next_io.size_ = block_size_;
+			current_.begin_ = (b + 1) * block_size_;
+
+			return true;
+		}
+
+	private:
+		bool next_extent(extent &e) {
+			string line;
+			while (getline(in_, line)) {
+				vector<string> tokens = split(line);
+				bool ok = fio_ ? parse_fio(tokens, e) : parse_blktrace(tokens, e);
+				if (!ok)
+					continue;
+
+				e.begin_ += offset_;
+				e.end_ += offset_;
+
+				if (size_) {
+					sector_t limit = offset_ + size_;
+					if (e.begin_ >= limit)
+						continue;
+					if (e.end_ > limit)
+						e.end_ = limit;
+				}
+
+				// The kernel only unmaps the blocks a
+				// discard fully covers.
+				if (e.op_ == REQ_OP_DISCARD) {
+					e.begin_ = (e.begin_ + block_size_ - 1) / block_size_ * block_size_;
+					e.end_ = e.end_ / block_size_ * block_size_;
+					if (e.begin_ >= e.end_)
+						continue;
+				}
+
+				return true;
+			}
+
+			return false;
+		}
+
+		ifstream in_;
+		sector_t block_size_;
+		sector_t offset_;
+		sector_t size_;
+		bool fio_;
+
+		bool pending_;
+		extent current_;
+	};
+}
+
+//----------------------------------------------------------------
+
+io_generator::ptr
+base::create_trace_io_generator(string const &path,
+				sector_t block_size,
+				sector_t offset,
+				sector_t size)
+{
+	return io_generator::ptr(new trace_io_generator(path, block_size, offset, size));
+}
+
+//----------------------------------------------------------------
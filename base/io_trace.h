@@ -0,0 +1,32 @@
+#ifndef BASE_IO_TRACE_H
+#define BASE_IO_TRACE_H
+
+#include "base/io_generator.h"
+
+#include <string>
+
+//----------------------------------------------------------------
+
+namespace base {
+	// Replays the write and discard extents recorded in a trace,
+	// split into one io per block touched.  Discards are trimmed to
+	// the blocks they fully cover, as the kernel only unmaps those.
+	// Two formats are read:
+	//
+	//   - the default text output of blkparse, using the queue (Q)
+	//     events,
+	//   - a fio iolog, version 2 or 3 (see fio's --write_iolog).
+	//
+	// The format is detected from the first line.  Extents are
+	// shifted by offset sectors, and anything beyond offset + size
+	// is dropped, unless size is zero.
+	io_generator::ptr
+	create_trace_io_generator(std::string const &path,
+				  sector_t block_size,
+				  sector_t offset,
+				  sector_t size);
+}
+
+//----------------------------------------------------------------
+
+#endif
@@ -74,15 +74,15 @@ namespace {
 	//   value is not aligned.
 	class random_sequence_generator: public base::sequence_generator {
 	public:
-		// TODO: load random seeds
 		random_sequence_generator(uint64_t begin,
 					  uint64_t size,
 					  uint64_t step,
-					  unsigned seq_nr = 1)
+					  unsigned seq_nr,
+					  uint64_t seed)
 			: begin_(begin),
 			  step_(step),
 			  max_forward_steps_(seq_nr),
-			  rand_seed_(std::chrono::high_resolution_clock::now().time_since_epoch().count()),
+			  rand_seed_(seed),
 			  results_engine_(rand_seed_),
 			  steps_engine_(rand_seed_),
 			  nr_generated_(0)
@@ -190,6 +190,12 @@ namespace {
 
 //----------------------------------------------------------------
 
+uint64_t
+base::default_seed()
+{
+	return std::chrono::high_resolution_clock::now().time_since_epoch().count();
+}
+
 base::sequence_generator::ptr
 base::create_forward_sequence_generator(uint64_t begin,
 					uint64_t size,
@@ -203,10 +209,11 @@ base::sequence_generator::ptr
 base::create_random_sequence_generator(uint64_t begin,
 				       uint64_t size,
 				       uint64_t step,
-				       unsigned seq_nr)
+				       unsigned seq_nr,
+				       uint64_t seed)
 {
 	return sequence_generator::ptr(
-			new random_sequence_generator(begin, size, step, seq_nr));
+			new random_sequence_generator(begin, size, step, seq_nr, seed));
 }
 
 //----------------------------------------------------------------
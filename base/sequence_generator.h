@@ -13,13 +13,17 @@ namespace base {
 		virtual uint64_t next() = 0;
 	};
 
+	// A seed for callers that weren't given one.
+	uint64_t default_seed();
+
 	sequence_generator::ptr
 	create_forward_sequence_generator(uint64_t begin, uint64_t size,
 			uint64_t step);
 
 	sequence_generator::ptr
 	create_random_sequence_generator(uint64_t begin, uint64_t size,
-			uint64_t step, unsigned seq_nr = 1);
+			uint64_t step, unsigned seq_nr = 1,
+			uint64_t seed = default_seed());
 }
 
 //----------------------------------------------------------------
@@ -7,9 +7,11 @@
 #include "version.h"
 
 #include <boost/optional.hpp>
+#include <chrono>
 #include <getopt.h>
 #include <string>
 #include <stdexcept>
+#include <thread>
 #include <boost/optional/optional_io.hpp>
 
 using namespace bcache;
@@ -34,7 +36,9 @@ namespace {
 			  origin_dev_offset(0),
 			  fast_dev_offset(0),
 			  list_failed_blocks(false),
-			  update_metadata(true) {
+			  update_metadata(true),
+			  batch_size(0),
+			  max_rate(0) {
 		}
 
 		// The sort buffers have a dramatic effect on the
@@ -45,6 +49,8 @@ namespace {
 			cache_size = cache_size - sbs;
 
 			sort_buffers = sbs / sizeof(copy_op);
+			if (batch_size)
+				sort_buffers = batch_size;
 		}
 
 		using maybe_string = boost::optional<string>;
@@ -58,6 +64,13 @@ namespace {
 		sector_t fast_dev_offset;
 		bool list_failed_blocks;
 		bool update_metadata;
+
+		// blocks sorted and issued together, 0 means size it
+		// from the buffer
+		unsigned batch_size;
+
+		// bytes per second, 0 means unthrottled
+		uint64_t max_rate;
 	};
 
 	//--------------------------------
@@ -102,14 +115,17 @@ namespace {
 	class copy_visitor : public mapping_visitor {
 	public:
 		copy_visitor(copier &c, unsigned sort_buffer, bool only_dirty,
-			     bool list_failed_blocks,
+			     bool list_failed_blocks, uint64_t max_rate,
 			     progress_monitor &monitor, unsigned cache_blocks)
 			: copier_(c),
 			  block_size_(c.get_block_size()),
 			  only_dirty_(only_dirty),
 			  batch_(sort_buffer),
 			  monitor_(monitor),
-			  cache_blocks_(cache_blocks) {
+			  cache_blocks_(cache_blocks),
+			  max_rate_(max_rate),
+			  nr_bytes_(0),
+			  start_(chrono::steady_clock::now()) {
 		}
 
 		virtual void visit(block_address cblock, mapping const &m) {
@@ -145,6 +161,7 @@ namespace {
 				copier_.issue(*it);
 				stats_.blocks_issued++;
 				update_monitor();
+				throttle();
 
 				check_for_completed_copies();
 			}
@@ -228,6 +245,17 @@ namespace {
 		}
 
 	private:
+		// Sleeps until the average rate since we started is
+		// back under the limit.
+		void throttle() {
+			if (!max_rate_)
+				return;
+
+			nr_bytes_ += static_cast<uint64_t>(block_size_) << SECTOR_SHIFT;
+			chrono::nanoseconds due(nr_bytes_ * 1000000000ull / max_rate_);
+			this_thread::sleep_until(start_ + due);
+		}
+
 		copier &copier_;
 		unsigned block_size_;
 		bool only_dirty_;
@@ -237,6 +265,10 @@ namespace {
 		progress_monitor &monitor_;
 		unsigned cache_blocks_;
 
+		uint64_t max_rate_;
+		uint64_t nr_bytes_;
+		chrono::steady_clock::time_point start_;
+
 		set<copy_op> failed_blocks_;
 		set<block_address> failed_cblocks_;
 	};
@@ -313,7 +345,7 @@ namespace {
 
 		auto bar = create_progress_bar("Copying data");
 		copy_visitor cv(c, f.sort_buffers, clean_shutdown(md), f.list_failed_blocks,
-				*bar, md.sb_.cache_blocks);
+				f.max_rate, *bar, md.sb_.cache_blocks);
 
 		ignore_damage_visitor dv;
 
@@ -372,6 +404,8 @@ cache_writeback_cmd::usage(std::ostream &out) const
 	    << "\t\t--no-metadata-update\n"
 	    << "\t\t--origin-device-offset <bytes>\n"
 	    << "\t\t--fast-device-offset <bytes>\n"
+	    << "\t\t--batch-size <blocks>\n"
+	    << "\t\t--max-rate <bytes copied per second, eg, 100M>\n"
 	    << "Options:\n"
 	    << "  {-h|--help}\n"
 	    << "  {-V|--version}" << endl;
@@ -392,6 +426,8 @@ cache_writeback_cmd::run(int argc, char **argv)
 		{ "no-metadata-update", no_argument, NULL, 5 },
 		{ "origin-device-offset", required_argument, NULL, 6 },
 		{ "fast-device-offset", required_argument, NULL, 7 },
+		{ "batch-size", required_argument, NULL, 8 },
+		{ "max-rate", required_argument, NULL, 9 },
 		{ "help", no_argument, NULL, 'h'},
 		{ "version", no_argument, NULL, 'V'},
 		{ NULL, no_argument, NULL, 0 }
@@ -431,6 +467,19 @@ cache_writeback_cmd::run(int argc, char **argv)
 			fs.fast_dev_offset = parse_uint64(optarg, "fast dev offset");
 			break;
 
+		case 8:
+			fs.batch_size = parse_uint64(optarg, "batch size");
+			if (!fs.batch_size) {
+				cerr << "Batch size must be non-zero\n\n";
+				usage(cerr);
+				return 1;
+			}
+			break;
+
+		case 9:
+			fs.max_rate = parse_size(optarg, "max rate", 1);
+			break;
+
 		case 'h':
 			usage(cout);
 			return 0;
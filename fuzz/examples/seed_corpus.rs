@@ -0,0 +1,102 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use thinp::checksum::{metadata_block_type, BT};
+use thinp::io_engine::BLOCK_SIZE;
+
+//------------------------------------------
+
+// Seeds the fuzz corpora from real metadata.  Each argument is either
+// a thin xml file, which is copied to the thin_xml corpus, or a
+// metadata device/image whose superblocks, btree nodes, index blocks
+// and bitmaps are split out to the matching corpus.
+//
+//   cargo run --example seed_corpus -- <metadata or xml>...
+
+fn corpus_dir(target: &str) -> io::Result<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("corpus")
+        .join(target);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn add_seed(target: &str, data: &[u8]) -> io::Result<()> {
+    let name = format!("seed-{:08x}", crc32c::crc32c(data));
+    fs::write(corpus_dir(target)?.join(name), data)
+}
+
+fn seed_from_metadata(path: &Path) -> io::Result<usize> {
+    let mut input = fs::File::open(path)?;
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut indexes = Vec::new();
+    let mut bitmaps = Vec::new();
+    let mut nr_seeds = 0;
+
+    loop {
+        match input.read_exact(&mut block) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+
+        match metadata_block_type(&block) {
+            BT::THIN_SUPERBLOCK => add_seed("superblock", &block)?,
+            BT::NODE => add_seed("btree_node", &block)?,
+            BT::INDEX => {
+                indexes.push(block.clone());
+                continue;
+            }
+            BT::BITMAP => {
+                bitmaps.push(block.clone());
+                continue;
+            }
+            _ => continue,
+        }
+        nr_seeds += 1;
+    }
+
+    // the space_map_index target takes an index followed by a bitmap
+    for (index, bitmap) in indexes.iter().cycle().zip(bitmaps.iter()) {
+        let mut seed = index.clone();
+        seed.extend_from_slice(bitmap);
+        add_seed("space_map_index", &seed)?;
+        nr_seeds += 1;
+    }
+
+    Ok(nr_seeds)
+}
+
+fn is_xml(path: &Path) -> io::Result<bool> {
+    let mut buf = [0; 1];
+    let n = fs::File::open(path)?.read(&mut buf)?;
+    Ok(n == 1 && buf[0] == b'<')
+}
+
+fn main() {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("Usage: seed_corpus <metadata or xml>...");
+        std::process::exit(2);
+    }
+
+    for path in paths {
+        let path = Path::new(&path);
+        let r = if is_xml(path).unwrap_or(false) {
+            fs::read(path).and_then(|data| add_seed("thin_xml", &data).map(|_| 1))
+        } else {
+            seed_from_metadata(path)
+        };
+
+        match r {
+            Ok(n) => println!("{}: {} seeds", path.display(), n),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,29 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use thinp::checksum::BT;
+use thinp::pdata::btree::unpack_node;
+use thinp::pdata::space_map_common::IndexEntry;
+use thinp::thin::block_time::BlockTime;
+use thinp::thin::device_detail::DeviceDetail;
+use thinp_fuzz::*;
+
+//------------------------------------------
+
+// Nodes are decoded with each of the value types the thin metadata
+// stores in its btrees.
+fuzz_target!(|data: &[u8]| {
+    let block = mk_block(data, BT::NODE);
+    set_input("btree_node", vec![(1, block.clone())]);
+
+    for &is_root in &[false, true] {
+        for &ignore_non_fatal in &[false, true] {
+            let _ = unpack_node::<u64>(&[0], &block, ignore_non_fatal, is_root);
+            let _ = unpack_node::<BlockTime>(&[0], &block, ignore_non_fatal, is_root);
+            let _ = unpack_node::<DeviceDetail>(&[0], &block, ignore_non_fatal, is_root);
+            let _ = unpack_node::<IndexEntry>(&[0], &block, ignore_non_fatal, is_root);
+        }
+    }
+});
+
+//------------------------------------------
@@ -0,0 +1,27 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use thinp::checksum::BT;
+use thinp::pdata::space_map_common::Bitmap;
+use thinp::pdata::space_map_metadata::MetadataIndex;
+use thinp::pdata::unpack::unpack;
+use thinp_fuzz::*;
+
+//------------------------------------------
+
+// The first half of the input is taken as the metadata space map
+// index, the second as a bitmap.
+fuzz_target!(|data: &[u8]| {
+    let (index, bitmap) = data.split_at(data.len() / 2);
+    let index = mk_block(index, BT::INDEX);
+    let bitmap = mk_block(bitmap, BT::BITMAP);
+    set_input(
+        "space_map_index",
+        vec![(1, index.clone()), (2, bitmap.clone())],
+    );
+
+    let _ = unpack::<MetadataIndex>(&index);
+    let _ = unpack::<Bitmap>(&bitmap);
+});
+
+//------------------------------------------
@@ -0,0 +1,22 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use thinp::checksum::BT;
+use thinp::pdata::space_map_common::unpack_root;
+use thinp::thin::superblock::{read_superblock, SUPERBLOCK_LOCATION};
+use thinp_fuzz::*;
+
+//------------------------------------------
+
+fuzz_target!(|data: &[u8]| {
+    let blocks = vec![(SUPERBLOCK_LOCATION, mk_block(data, BT::THIN_SUPERBLOCK))];
+    set_input("superblock", blocks.clone());
+
+    let engine = CoreIoEngine::new(&blocks);
+    if let Ok(sb) = read_superblock(&engine, SUPERBLOCK_LOCATION) {
+        let _ = unpack_root(&sb.data_sm_root);
+        let _ = unpack_root(&sb.metadata_sm_root);
+    }
+});
+
+//------------------------------------------
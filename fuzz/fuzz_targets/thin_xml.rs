@@ -0,0 +1,60 @@
+#![no_main]
+use anyhow::Result;
+use libfuzzer_sys::fuzz_target;
+
+use thinp::thin::ir::{self, MetadataVisitor, Visit};
+use thinp::thin::xml;
+
+//------------------------------------------
+
+struct NoopVisitor;
+
+impl MetadataVisitor for NoopVisitor {
+    fn superblock_b(&mut self, _sb: &ir::Superblock) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, _name: &str) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn device_b(&mut self, _d: &ir::Device) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn device_labels(&mut self, _labels: &ir::DeviceLabels) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, _m: &ir::Map) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, _name: &str) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Stop)
+    }
+}
+
+// XML crashes are plain text, so libfuzzer's own artifact is the
+// regression input; there's no metadata to pack.
+fuzz_target!(|data: &[u8]| {
+    let _ = xml::read(data, &mut NoopVisitor);
+});
+
+//------------------------------------------
@@ -0,0 +1,137 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Once;
+
+use thinp::checksum::{write_checksum, BT};
+use thinp::io_engine::{Block, IoEngine, BLOCK_SIZE};
+use thinp::pack::toplevel::pack;
+
+//------------------------------------------
+
+/// Builds a metadata block from the fuzzer's input, with a valid
+/// checksum for the given block type.  Without this nearly every
+/// input would be rejected by the checksum before reaching the
+/// decoder.
+pub fn mk_block(data: &[u8], kind: BT) -> Vec<u8> {
+    let mut block = vec![0; BLOCK_SIZE];
+    let len = std::cmp::min(data.len(), BLOCK_SIZE);
+    block[..len].copy_from_slice(&data[..len]);
+    write_checksum(&mut block, kind).unwrap();
+    block
+}
+
+//------------------------------------------
+
+/// An in memory io engine, so the decoders that read through an
+/// engine can be fuzzed without touching the disk.
+pub struct CoreIoEngine {
+    blocks: BTreeMap<u64, Vec<u8>>,
+    nr_blocks: u64,
+}
+
+impl CoreIoEngine {
+    pub fn new(blocks: &[(u64, Vec<u8>)]) -> CoreIoEngine {
+        let nr_blocks = blocks.iter().map(|(loc, _)| loc + 1).max().unwrap_or(0);
+        CoreIoEngine {
+            blocks: blocks.iter().cloned().collect(),
+            nr_blocks,
+        }
+    }
+}
+
+impl IoEngine for CoreIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn get_batch_size(&self) -> usize {
+        1
+    }
+
+    fn read(&self, loc: u64) -> io::Result<Block> {
+        let b = Block::zeroed(loc);
+        if loc >= self.nr_blocks {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "read beyond end",
+            ));
+        }
+        if let Some(data) = self.blocks.get(&loc) {
+            b.get_data().copy_from_slice(data);
+        }
+        Ok(b)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> io::Result<Vec<io::Result<Block>>> {
+        Ok(blocks.iter().map(|loc| self.read(*loc)).collect())
+    }
+
+    fn write(&self, _b: &Block) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "read only"))
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> io::Result<Vec<io::Result<()>>> {
+        Ok(blocks.iter().map(|b| self.write(b)).collect())
+    }
+}
+
+//------------------------------------------
+
+thread_local! {
+    static CURRENT: RefCell<Option<(&'static str, Vec<(u64, Vec<u8>)>)>> = RefCell::new(None);
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+/// Records the metadata blocks a target is about to decode.  If the
+/// decoder panics they're written out as a pack file, next to
+/// libfuzzer's own crash artifact, ready to be unpacked with
+/// thin_metadata_unpack for a regression test.
+pub fn set_input(target: &'static str, blocks: Vec<(u64, Vec<u8>)>) {
+    INSTALL_HOOK.call_once(|| {
+        // libfuzzer's hook aborts the process, so it has to run last.
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            CURRENT.with(|current| {
+                if let Some((target, blocks)) = current.borrow().as_ref() {
+                    match save_pack(target, blocks) {
+                        Ok(path) => eprintln!("metadata packed to {}", path.display()),
+                        Err(e) => eprintln!("couldn't pack metadata: {}", e),
+                    }
+                }
+            });
+            prev(info);
+        }));
+    });
+
+    CURRENT.with(|current| *current.borrow_mut() = Some((target, blocks)));
+}
+
+fn save_pack(
+    target: &str,
+    blocks: &[(u64, Vec<u8>)],
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut image = tempfile::NamedTempFile::new()?;
+    let mut hash = 0;
+    for (loc, data) in blocks {
+        image.seek(SeekFrom::Start(loc * BLOCK_SIZE as u64))?;
+        image.write_all(data)?;
+        hash = crc32c::crc32c_append(hash, data);
+    }
+    image.flush()?;
+
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("artifacts")
+        .join(target);
+    fs::create_dir_all(&dir)?;
+
+    let path = dir.join(format!("crash-{:08x}.pack", hash));
+    pack(image.path(), &path)?;
+    Ok(path)
+}
+
+//------------------------------------------
@@ -19,11 +19,91 @@
 #include "checksum.h"
 
 #include <boost/crc.hpp>
+#include <string.h>
+
+#if defined(__x86_64__)
+#include <nmmintrin.h>
+#elif defined(__aarch64__)
+#include <arm_acle.h>
+#include <asm/hwcap.h>
+#include <sys/auxv.h>
+#endif
 
 using namespace base;
 
 //----------------------------------------------------------------
 
+namespace {
+	uint32_t const CRC_INIT = 0xffffffff;
+
+	uint32_t crc32c_sw(void const *buffer, unsigned len) {
+		uint32_t const powers = 0x1EDC6F41;
+
+		boost::crc_optimal<32, powers, CRC_INIT, 0, true, true> crc;
+		crc.process_bytes(buffer, len);
+		return crc.checksum();
+	}
+
+	// The crc32 instructions compute the same (reflected, uninverted)
+	// value as the boost crc above.
+#if defined(__x86_64__)
+	bool have_hw_crc() {
+		static bool const r = __builtin_cpu_supports("sse4.2");
+		return r;
+	}
+
+	__attribute__((target("sse4.2")))
+	uint32_t crc32c_hw(void const *buffer, unsigned len) {
+		unsigned char const *p = static_cast<unsigned char const *>(buffer);
+		uint64_t crc = CRC_INIT;
+
+		for (; len >= 8; p += 8, len -= 8) {
+			uint64_t v;
+			memcpy(&v, p, sizeof(v));
+			crc = _mm_crc32_u64(crc, v);
+		}
+
+		uint32_t crc32 = static_cast<uint32_t>(crc);
+		for (; len; p++, len--)
+			crc32 = _mm_crc32_u8(crc32, *p);
+
+		return crc32;
+	}
+#elif defined(__aarch64__)
+	bool have_hw_crc() {
+		static bool const r = getauxval(AT_HWCAP) & HWCAP_CRC32;
+		return r;
+	}
+
+	__attribute__((target("+crc")))
+	uint32_t crc32c_hw(void const *buffer, unsigned len) {
+		unsigned char const *p = static_cast<unsigned char const *>(buffer);
+		uint32_t crc = CRC_INIT;
+
+		for (; len >= 8; p += 8, len -= 8) {
+			uint64_t v;
+			memcpy(&v, p, sizeof(v));
+			crc = __crc32cd(crc, v);
+		}
+
+		for (; len; p++, len--)
+			crc = __crc32cb(crc, *p);
+
+		return crc;
+	}
+#else
+	bool have_hw_crc() {
+		return false;
+	}
+
+	uint32_t crc32c_hw(void const *buffer, unsigned len) {
+		return crc32c_sw(buffer, len);
+	}
+#endif
+}
+
+//----------------------------------------------------------------
+
 crc32c::crc32c(uint32_t xor_value)
 	: xor_value_(xor_value),
 	  sum_(0)
@@ -33,11 +113,7 @@ crc32c::crc32c(uint32_t xor_value)
 void
 crc32c::append(void const *buffer, unsigned len)
 {
-	uint32_t const powers = 0x1EDC6F41;
-
-	boost::crc_optimal<32, powers, 0xffffffff, 0, true, true> crc;
-	crc.process_bytes(buffer, len);
-	sum_ = crc.checksum();
+	sum_ = have_hw_crc() ? crc32c_hw(buffer, len) : crc32c_sw(buffer, len);
 }
 
 uint32_t
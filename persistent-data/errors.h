@@ -30,6 +30,14 @@ namespace base {
 			: std::runtime_error(what) {
 		}
 	};
+
+	// Damage found walking the metadata, rather than by a checksum.
+	class metadata_damage : public std::runtime_error {
+	public:
+		explicit metadata_damage(std::string const &what)
+			: std::runtime_error(what) {
+		}
+	};
 }
 
 //----------------------------------------------------------------
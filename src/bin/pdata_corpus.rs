@@ -0,0 +1,174 @@
+extern crate clap;
+
+use anyhow::{anyhow, Result};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use thinp::commands::exit_codes::*;
+use thinp::corpus::*;
+
+//------------------------------------------
+
+fn cli<'a, 'b>() -> App<'a, 'b> {
+    let names = Arg::with_name("NAMES")
+        .help("Only use these entries")
+        .multiple(true)
+        .index(1);
+
+    App::new("pdata_corpus")
+        .version(thinp::version::tools_version())
+        .about("Maintain a corpus of packed metadata, and check the tools still behave as recorded")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("CORPUS")
+                .help("Specify the corpus directory")
+                .short("c")
+                .long("corpus")
+                .value_name("DIR")
+                .default_value("tests/corpus"),
+        )
+        .arg(
+            Arg::with_name("TOOLS")
+                .help("Specify the pdata_tools binary to run, rather than the one alongside this")
+                .long("tools")
+                .value_name("FILE"),
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Pack some metadata into a new entry, and record what the tools make of it")
+                .arg(
+                    Arg::with_name("DESCRIPTION")
+                        .help("Say where the metadata came from, eg. the bug it triggered")
+                        .long("description")
+                        .value_name("TEXT"),
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Specify the input device")
+                        .short("i")
+                        .long("input")
+                        .value_name("FILE")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("KIND")
+                        .help("Specify the kind of metadata")
+                        .long("kind")
+                        .value_name("KIND")
+                        .possible_values(&["thin", "cache", "era"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("NAME")
+                        .help("Name the new entry")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list").about("List the entries, with their descriptions"),
+        )
+        .subcommand(
+            SubCommand::with_name("record")
+                .about("Re-record the expected outputs, after an intended change in behaviour")
+                .arg(names.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Run the tools against the entries, and report any change in their output")
+                .arg(names),
+        )
+}
+
+// The binaries are built side by side.
+fn default_tools() -> Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let dir = exe
+        .parent()
+        .ok_or_else(|| anyhow!("couldn't find the directory of {:?}", exe))?;
+    Ok(dir.join("pdata_tools"))
+}
+
+fn names<'a>(matches: &'a ArgMatches) -> Vec<&'a str> {
+    matches
+        .values_of("NAMES")
+        .map_or_else(Vec::new, |names| names.collect())
+}
+
+fn add(corpus: &Corpus, runner: &Runner, matches: &ArgMatches) -> Result<i32> {
+    let entry = corpus.add(
+        matches.value_of("NAME").unwrap(),
+        matches.value_of("KIND").unwrap().parse()?,
+        Path::new(matches.value_of("INPUT").unwrap()),
+        matches.value_of("DESCRIPTION").unwrap_or(""),
+    )?;
+    record(&entry, &runner.run(&entry)?)?;
+    println!("added {}", entry.name);
+    Ok(SUCCESS)
+}
+
+fn list(corpus: &Corpus) -> Result<i32> {
+    for entry in corpus.entries(&[])? {
+        println!("{:<32} {:<6} {}", entry.name, entry.kind, entry.description);
+    }
+    Ok(SUCCESS)
+}
+
+fn rerecord(corpus: &Corpus, runner: &Runner, matches: &ArgMatches) -> Result<i32> {
+    for entry in corpus.entries(&names(matches))? {
+        record(&entry, &runner.run(&entry)?)?;
+        println!("recorded {}", entry.name);
+    }
+    Ok(SUCCESS)
+}
+
+fn run(corpus: &Corpus, runner: &Runner, matches: &ArgMatches) -> Result<i32> {
+    let mut nr_regressions = 0;
+    for entry in corpus.entries(&names(matches))? {
+        let regressions = compare(&entry, &runner.run(&entry)?)?;
+        if regressions.is_empty() {
+            println!("ok      {}", entry.name);
+        }
+
+        for r in &regressions {
+            println!("CHANGED {} {}", entry.name, r.run);
+            print!("{}", r.diff);
+        }
+        nr_regressions += regressions.len();
+    }
+
+    if nr_regressions > 0 {
+        println!("{} tool runs changed their output", nr_regressions);
+        return Ok(FATAL);
+    }
+    Ok(SUCCESS)
+}
+
+fn main_() -> Result<i32> {
+    let matches = cli().get_matches();
+    let corpus = Corpus::open(Path::new(matches.value_of("CORPUS").unwrap()))?;
+    let tools = match matches.value_of("TOOLS") {
+        Some(path) => PathBuf::from(path),
+        None => default_tools()?,
+    };
+    let runner = Runner::new(&tools)?;
+
+    match matches.subcommand() {
+        ("add", Some(m)) => add(&corpus, &runner, m),
+        ("list", _) => list(&corpus),
+        ("record", Some(m)) => rerecord(&corpus, &runner, m),
+        ("run", Some(m)) => run(&corpus, &runner, m),
+        _ => unreachable!(),
+    }
+}
+
+fn main() {
+    let code = main_().unwrap_or_else(|e| {
+        eprintln!("{:#}", e);
+        FATAL
+    });
+    exit(code)
+}
+
+//------------------------------------------
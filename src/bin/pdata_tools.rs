@@ -1,79 +1,181 @@
-use anyhow::{anyhow, ensure, Result};
+use clap::{value_t, App, Arg, Shell};
 use std::ffi::OsString;
+use std::io::stdout;
 use std::path::Path;
 use std::process::exit;
+use thinp::commands::exit_codes::*;
+use thinp::commands::utils::{exit_usage, get_matches, install_signal_handlers};
 use thinp::commands::*;
 
-fn name_eq(name: &Path, cmd: &str) -> bool {
-    name == Path::new(cmd)
+//------------------------------------------
+
+struct Command {
+    name: &'static str,
+    run: fn(&[OsString]),
+    cli: fn() -> App<'static, 'static>,
 }
 
-fn main_() -> Result<()> {
-    let mut args = std::env::args_os();
-    ensure!(args.len() > 0);
+macro_rules! command {
+    ($name: ident) => {
+        Command {
+            name: stringify!($name),
+            run: $name::run,
+            cli: $name::cli,
+        }
+    };
+}
 
-    let mut os_name = args.next().unwrap();
-    let mut name = Path::new(&os_name);
-    name = Path::new(name.file_name().unwrap());
+// Keep in sync with RUST_TOOLS in Makefile.in, which creates the symlinks.
+const COMMANDS: &[Command] = &[
+    command!(cache_check),
+    command!(cache_dump),
+    command!(cache_invalidate),
+    command!(cache_metadata_size),
+    command!(cache_repair),
+    command!(cache_restore),
+    command!(cache_stat),
+    command!(era_check),
+    command!(era_dump),
+    command!(era_invalidate),
+    command!(era_repair),
+    command!(era_restore),
+    command!(era_stat),
+    command!(thin_bench),
+    command!(thin_check),
+    command!(thin_dump),
+    command!(thin_metadata_diff),
+    command!(thin_metadata_pack),
+    command!(thin_metadata_size),
+    command!(thin_metadata_unpack),
+    command!(thin_metrics),
+    command!(thin_repair),
+    command!(thin_restore),
+    command!(thin_set_metadata_id),
+    command!(thin_shrink),
+];
 
-    if name == Path::new("pdata_tools") {
-        os_name = args.next().unwrap();
-        name = Path::new(&os_name);
-    }
+// Front ends that run the tool for whatever metadata the input holds.
+// Their names are too generic to install as symlinks, so they're only
+// reached through pdata_tools.
+const FRONT_ENDS: &[Command] = &[command!(check), command!(dump)];
 
-    let mut new_args = vec![OsString::from(&name)];
-    for a in args.into_iter() {
-        new_args.push(a);
+fn all_commands() -> impl Iterator<Item = &'static Command> {
+    COMMANDS.iter().chain(FRONT_ENDS.iter())
+}
+
+fn usage() {
+    eprintln!("Usage: <command> <args>");
+    eprintln!("commands:");
+    for cmd in COMMANDS {
+        eprintln!("  {}", cmd.name);
     }
+    eprintln!();
+    eprintln!("pdata_tools check|dump [--type thin|cache|era] <args>");
+    eprintln!("  runs the tool for the type of metadata on the input");
+    eprintln!();
+    eprintln!("pdata_tools completions <shell> [<command>...]");
+    eprintln!("  generates shell completion scripts for the commands");
+}
+
+// Completion scripts are generated from the same clap definitions
+// the tools parse their arguments with, so they can't drift.
+fn completions(args: &[OsString]) -> i32 {
+    let app = App::new("completions")
+        .about("Generate shell completion scripts for the tools")
+        .arg(
+            Arg::with_name("SHELL")
+                .help("Specify the shell to generate completions for")
+                .required(true)
+                .possible_values(&Shell::variants())
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("COMMANDS")
+                .help("Only generate completions for these commands")
+                .multiple(true)
+                .index(2),
+        );
+
+    let matches = get_matches(app, args);
+    let shell = value_t!(matches.value_of("SHELL"), Shell).unwrap_or_else(|e| exit_usage(e));
+    let selected: Vec<&Command> = match matches.values_of("COMMANDS") {
+        Some(names) => {
+            let mut cmds = Vec::new();
+            for name in names {
+                match all_commands().find(|cmd| cmd.name == name) {
+                    Some(cmd) => cmds.push(cmd),
+                    None => {
+                        eprintln!("Unknown command '{}'", name);
+                        return USAGE;
+                    }
+                }
+            }
+            cmds
+        }
+        None => all_commands().collect(),
+    };
 
-    if name_eq(name, "cache_check") {
-        cache_check::run(&new_args);
-    } else if name_eq(name, "cache_dump") {
-        cache_dump::run(&new_args);
-    } else if name_eq(name, "cache_metadata_size") {
-        cache_metadata_size::run(&new_args);
-    } else if name_eq(name, "cache_repair") {
-        cache_repair::run(&new_args);
-    } else if name_eq(name, "cache_restore") {
-        cache_restore::run(&new_args);
-    } else if name_eq(name, "era_check") {
-        era_check::run(&new_args);
-    } else if name_eq(name, "era_dump") {
-        era_dump::run(&new_args);
-    } else if name_eq(name, "era_restore") {
-        era_restore::run(&new_args);
-    } else if name_eq(name, "thin_check") {
-        thin_check::run(&new_args);
-    } else if name_eq(name, "thin_dump") {
-        thin_dump::run(&new_args);
-    } else if name_eq(name, "thin_metadata_pack") {
-        thin_metadata_pack::run(&new_args);
-    } else if name_eq(name, "thin_metadata_size") {
-        thin_metadata_size::run(&new_args);
-    } else if name_eq(name, "thin_metadata_unpack") {
-        thin_metadata_unpack::run(&new_args);
-    } else if name_eq(name, "thin_repair") {
-        thin_repair::run(&new_args);
-    } else if name_eq(name, "thin_restore") {
-        thin_restore::run(&new_args);
-    } else if name_eq(name, "thin_shrink") {
-        thin_shrink::run(&new_args);
-    } else {
-        return Err(anyhow!("unrecognised command"));
+    for cmd in selected {
+        (cmd.cli)().gen_completions_to(cmd.name, shell, &mut stdout());
     }
+    SUCCESS
+}
 
-    Ok(())
+fn basename(path: &OsString) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
 }
 
-fn main() {
-    let code = match main_() {
-        Ok(()) => 0,
-        Err(_) => {
-            // We don't print out the error since -q may be set
-            // eprintln!("{}", e);
-            1
+// The command is selected by the name we were invoked with, so
+// symlinks to this binary behave like the individual tools.  If
+// we're invoked as pdata_tools itself the command is taken from
+// the first argument instead.
+fn main_() -> i32 {
+    let mut args = std::env::args_os();
+
+    let mut name = match args.next() {
+        Some(arg0) => basename(&arg0),
+        None => {
+            usage();
+            return USAGE;
         }
     };
 
-    exit(code)
+    if name.starts_with("pdata_tools") {
+        match args.next() {
+            Some(cmd) => name = cmd.to_string_lossy().into_owned(),
+            None => {
+                usage();
+                return USAGE;
+            }
+        }
+    }
+
+    let mut new_args = vec![OsString::from(&name)];
+    new_args.extend(args);
+
+    if name == "completions" {
+        return completions(&new_args);
+    }
+
+    match all_commands().find(|cmd| cmd.name == name) {
+        Some(cmd) => {
+            (cmd.run)(&new_args);
+            SUCCESS
+        }
+        None => {
+            eprintln!("Unknown command '{}'", name);
+            usage();
+            USAGE
+        }
+    }
+}
+
+fn main() {
+    install_signal_handlers();
+    exit(main_())
 }
+
+//------------------------------------------
@@ -0,0 +1,350 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Result;
+use std::sync::{Arc, Mutex};
+
+use crate::io_engine::*;
+
+//------------------------------------------
+
+// Blocks held by the cache are never written to, so the handles can be
+// shared between threads.
+struct SharedBlock(Block);
+
+unsafe impl Sync for SharedBlock {}
+
+/// A reference counted handle to a cached block.  The block stays in
+/// memory for as long as a handle to it exists, even if it's been evicted
+/// from the cache.
+#[derive(Clone)]
+pub struct BlockRef {
+    block: Arc<SharedBlock>,
+}
+
+impl BlockRef {
+    pub fn loc(&self) -> u64 {
+        self.block.0.loc
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        self.block.0.get_data()
+    }
+
+    fn to_block(&self) -> Block {
+        let b = Block::new(self.loc());
+        b.get_data().copy_from_slice(self.get_data());
+        b
+    }
+}
+
+//------------------------------------------
+
+struct Entry {
+    block: BlockRef,
+    referenced: bool,
+}
+
+// Each shard is a clock: blocks get a second chance if they've been hit
+// since the hand last passed them, and blocks with live handles are
+// skipped altogether.
+struct Shard {
+    capacity: usize,
+    entries: HashMap<u64, Entry>,
+    clock: VecDeque<u64>,
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Shard {
+        Shard {
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            clock: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn lookup(&mut self, loc: u64) -> Option<BlockRef> {
+        self.entries.get_mut(&loc).map(|e| {
+            e.referenced = true;
+            e.block.clone()
+        })
+    }
+
+    fn evict(&mut self) {
+        // If every block is held we let the shard grow, rather than block.
+        for _ in 0..(2 * self.clock.len()) {
+            let loc = match self.clock.pop_front() {
+                Some(loc) => loc,
+                None => return,
+            };
+
+            let e = self.entries.get_mut(&loc).unwrap();
+            if e.referenced || Arc::strong_count(&e.block.block) > 1 {
+                e.referenced = false;
+                self.clock.push_back(loc);
+            } else {
+                self.entries.remove(&loc);
+                return;
+            }
+        }
+    }
+
+    fn insert(&mut self, block: BlockRef) -> BlockRef {
+        // Another thread may have read the block while we were.
+        if let Some(existing) = self.lookup(block.loc()) {
+            return existing;
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.evict();
+        }
+
+        self.clock.push_back(block.loc());
+        self.entries.insert(
+            block.loc(),
+            Entry {
+                block: block.clone(),
+                referenced: false,
+            },
+        );
+        block
+    }
+
+    fn invalidate(&mut self, loc: u64) {
+        if self.entries.remove(&loc).is_some() {
+            self.clock.retain(|l| *l != loc);
+        }
+    }
+}
+
+//------------------------------------------
+
+pub const DEFAULT_CACHE_BLOCKS: usize = 8192;
+const NR_SHARDS: usize = 64;
+
+// One block per shard.
+pub const MIN_CACHE_BLOCKS: usize = NR_SHARDS;
+
+/// A read cache in front of an io engine.  The blocks are spread across
+/// shards, each with its own lock, so many threads can walk the metadata
+/// at once without queuing for a single lock.  Reads through the IoEngine
+/// interface return copies; get() returns a handle to the cached block.
+pub struct BlockCache {
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl BlockCache {
+    pub fn new(engine: Arc<dyn IoEngine + Send + Sync>, nr_blocks: usize) -> BlockCache {
+        let per_shard = std::cmp::max(1, nr_blocks / NR_SHARDS);
+        let shards = (0..NR_SHARDS)
+            .map(|_| Mutex::new(Shard::new(per_shard)))
+            .collect();
+        BlockCache { engine, shards }
+    }
+
+    fn shard(&self, loc: u64) -> &Mutex<Shard> {
+        &self.shards[(loc % NR_SHARDS as u64) as usize]
+    }
+
+    fn insert(&self, b: Block) -> BlockRef {
+        let block = BlockRef {
+            block: Arc::new(SharedBlock(b)),
+        };
+        self.shard(block.loc()).lock().unwrap().insert(block)
+    }
+
+    pub fn get(&self, loc: u64) -> Result<BlockRef> {
+        if let Some(b) = self.shard(loc).lock().unwrap().lookup(loc) {
+            return Ok(b);
+        }
+
+        // The shard isn't locked during the io.
+        let b = self.engine.read(loc)?;
+        Ok(self.insert(b))
+    }
+
+    pub fn get_many(&self, blocks: &[u64]) -> Result<Vec<Result<BlockRef>>> {
+        let mut results: Vec<Option<Result<BlockRef>>> = Vec::with_capacity(blocks.len());
+        let mut misses = Vec::new();
+        for (i, loc) in blocks.iter().enumerate() {
+            let hit = self.shard(*loc).lock().unwrap().lookup(*loc);
+            if hit.is_none() {
+                misses.push(i);
+            }
+            results.push(hit.map(Ok));
+        }
+
+        if !misses.is_empty() {
+            let locs: Vec<u64> = misses.iter().map(|i| blocks[*i]).collect();
+            let rblocks = self.engine.read_many(&locs)?;
+            for (i, rb) in misses.into_iter().zip(rblocks) {
+                results[i] = Some(rb.map(|b| self.insert(b)));
+            }
+        }
+
+        Ok(results.into_iter().map(|r| r.unwrap()).collect())
+    }
+}
+
+impl IoEngine for BlockCache {
+    fn get_nr_blocks(&self) -> u64 {
+        self.engine.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.engine.get_batch_size()
+    }
+
+    fn read(&self, loc: u64) -> Result<Block> {
+        Ok(self.get(loc)?.to_block())
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        Ok(self
+            .get_many(blocks)?
+            .into_iter()
+            .map(|r| r.map(|b| b.to_block()))
+            .collect())
+    }
+
+    // Writes go straight through, dropping any cached copy.
+    fn write(&self, b: &Block) -> Result<()> {
+        self.shard(b.loc).lock().unwrap().invalidate(b.loc);
+        self.engine.write(b)
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        for b in blocks {
+            self.shard(b.loc).lock().unwrap().invalidate(b.loc);
+        }
+        self.engine.write_many(blocks)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    // Blocks are filled with their own location, and reads are counted.
+    struct CountingEngine {
+        nr_blocks: u64,
+        nr_reads: AtomicUsize,
+    }
+
+    impl IoEngine for CountingEngine {
+        fn get_nr_blocks(&self) -> u64 {
+            self.nr_blocks
+        }
+
+        fn get_batch_size(&self) -> usize {
+            1
+        }
+
+        fn read(&self, loc: u64) -> io::Result<Block> {
+            self.nr_reads.fetch_add(1, Ordering::SeqCst);
+            let b = Block::new(loc);
+            b.get_data().fill(loc as u8);
+            Ok(b)
+        }
+
+        fn read_many(&self, blocks: &[u64]) -> io::Result<Vec<io::Result<Block>>> {
+            Ok(blocks.iter().map(|b| self.read(*b)).collect())
+        }
+
+        fn write(&self, _b: &Block) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write_many(&self, blocks: &[Block]) -> io::Result<Vec<io::Result<()>>> {
+            Ok(blocks.iter().map(|b| self.write(b)).collect())
+        }
+    }
+
+    fn mk_cache(nr_blocks: usize) -> (Arc<CountingEngine>, BlockCache) {
+        let engine = Arc::new(CountingEngine {
+            nr_blocks: 1024,
+            nr_reads: AtomicUsize::new(0),
+        });
+        let cache = BlockCache::new(engine.clone(), nr_blocks);
+        (engine, cache)
+    }
+
+    #[test]
+    fn test_hits() {
+        let (engine, cache) = mk_cache(1024);
+        for _ in 0..3 {
+            for loc in 0..100 {
+                let b = cache.read(loc).unwrap();
+                assert_eq!(b.loc, loc);
+                assert!(b.get_data().iter().all(|v| *v == loc as u8));
+            }
+        }
+        assert_eq!(engine.nr_reads.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let (engine, cache) = mk_cache(NR_SHARDS);
+        for loc in 0..(4 * NR_SHARDS as u64) {
+            cache.get(loc).unwrap();
+        }
+        for shard in &cache.shards {
+            assert_eq!(shard.lock().unwrap().entries.len(), 1);
+        }
+
+        // the first blocks have gone
+        cache.get(0).unwrap();
+        assert_eq!(engine.nr_reads.load(Ordering::SeqCst), 4 * NR_SHARDS + 1);
+    }
+
+    #[test]
+    fn test_held_blocks_stay_valid() {
+        let (_engine, cache) = mk_cache(NR_SHARDS);
+        let held = cache.get(0).unwrap();
+        for loc in 1..(4 * NR_SHARDS as u64) {
+            cache.get(loc).unwrap();
+        }
+        assert_eq!(held.loc(), 0);
+        assert!(held.get_data().iter().all(|v| *v == 0));
+    }
+
+    #[test]
+    fn test_write_invalidates() {
+        let (engine, cache) = mk_cache(1024);
+        cache.read(7).unwrap();
+        cache.write(&Block::zeroed(7)).unwrap();
+        cache.read(7).unwrap();
+        assert_eq!(engine.nr_reads.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        let (_engine, cache) = mk_cache(256);
+        let cache = Arc::new(cache);
+        let threads: Vec<_> = (0..16)
+            .map(|t| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for i in 0..1000u64 {
+                        let loc = (i * 7 + t) % 1024;
+                        let b = cache.get(loc).unwrap();
+                        assert!(b.get_data().iter().all(|v| *v == loc as u8));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+}
+
+//------------------------------------------
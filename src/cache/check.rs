@@ -1,21 +1,30 @@
 use anyhow::anyhow;
 use std::collections::BTreeSet;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tracing::{instrument, Span};
 
 use crate::cache::hint::*;
 use crate::cache::mapping::*;
 use crate::cache::superblock::*;
 use crate::commands::utils::*;
 use crate::io_engine::{AsyncIoEngine, IoEngine, SyncIoEngine};
+use crate::journal::JournalEngine;
+use crate::memory;
 use crate::pdata::array::{self, ArrayBlock, ArrayError};
 use crate::pdata::array_walker::*;
 use crate::pdata::bitset::*;
 use crate::pdata::space_map::*;
 use crate::pdata::space_map_checker::*;
 use crate::pdata::space_map_common::*;
-use crate::pdata::unpack::unpack;
+use crate::pdata::space_map_metadata::{release_metadata_sm, rewrite_metadata_sm};
+use crate::pdata::unpack::{unpack, Unpack};
+use crate::policy::{Action, Category, Policy};
 use crate::report::*;
+use crate::transaction::TransactionEngine;
+use crate::write_batcher::WriteBatcher;
 
 //------------------------------------------
 
@@ -31,6 +40,50 @@ fn inc_superblock(sm: &ASpaceMap) -> anyhow::Result<()> {
 
 //------------------------------------------
 
+// Counts the array entries visited, to drive the progress bar.
+struct ProgressVisitor<'a, V: Unpack> {
+    inner: &'a dyn ArrayVisitor<V>,
+    nr_visited: &'a AtomicU64,
+}
+
+impl<'a, V: Unpack> ProgressVisitor<'a, V> {
+    fn new(inner: &'a dyn ArrayVisitor<V>, nr_visited: &'a AtomicU64) -> Self {
+        ProgressVisitor { inner, nr_visited }
+    }
+}
+
+impl<'a, V: Unpack> ArrayVisitor<V> for ProgressVisitor<'a, V> {
+    fn visit(&self, index: u64, b: ArrayBlock<V>) -> array::Result<()> {
+        self.nr_visited
+            .fetch_add(b.header.nr_entries as u64, Ordering::Relaxed);
+        self.inner.visit(index, b)
+    }
+}
+
+fn spawn_progress_thread(
+    nr_visited: Arc<AtomicU64>,
+    nr_total: u64,
+    report: Arc<Report>,
+) -> (JoinHandle<()>, Arc<AtomicBool>) {
+    let stop_progress = Arc::new(AtomicBool::new(false));
+
+    let tid = {
+        let stop_progress = stop_progress.clone();
+        thread::spawn(move || {
+            let interval = std::time::Duration::from_millis(250);
+            while !stop_progress.load(Ordering::Relaxed) {
+                let n = nr_visited.load(Ordering::Relaxed) * 100 / u64::max(nr_total, 1);
+                report.progress(u64::min(n, 100) as u8);
+                thread::sleep(interval);
+            }
+        })
+    };
+
+    (tid, stop_progress)
+}
+
+//------------------------------------------
+
 mod format1 {
     use super::*;
 
@@ -202,33 +255,144 @@ mod format2 {
 
 //------------------------------------------
 
-struct HintChecker;
+// The smq policy stores each block's level in its hint.  Other policies'
+// hints are opaque.
+const SMQ_NR_LEVELS: u32 = 64;
+
+fn max_hint(policy_name: &[u8]) -> Option<u32> {
+    match policy_name {
+        b"smq" | b"mq" => Some(SMQ_NR_LEVELS - 1),
+        _ => None,
+    }
+}
+
+struct HintChecker {
+    max_hint: Option<u32>,
+    inner: Mutex<HintInner>,
+}
+
+#[derive(Default)]
+struct HintInner {
+    nr_entries: u64,
+    nr_out_of_range: u64,
+    first_out_of_range: Option<u64>,
+}
 
 impl HintChecker {
-    fn new() -> HintChecker {
-        HintChecker
+    fn new(max_hint: Option<u32>) -> HintChecker {
+        HintChecker {
+            max_hint,
+            inner: Mutex::new(HintInner::default()),
+        }
     }
 }
 
 impl ArrayVisitor<Hint> for HintChecker {
-    fn visit(&self, _index: u64, _b: ArrayBlock<Hint>) -> array::Result<()> {
-        // TODO: check hints
+    fn visit(&self, index: u64, b: ArrayBlock<Hint>) -> array::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.nr_entries += b.header.nr_entries as u64;
+
+        if let Some(max) = self.max_hint {
+            let cbegin = index * b.header.max_entries as u64;
+            for (h, cblock) in b.values.iter().zip(cbegin..) {
+                if u32::from_le_bytes(h.hint) > max {
+                    inner.nr_out_of_range += 1;
+                    inner.first_out_of_range.get_or_insert(cblock);
+                }
+            }
+        }
+
         Ok(())
     }
 }
 
+// Hints are only advice to the policy, which starts afresh without
+// them, so problems are reported as non fatal.  Returns whether the
+// hints are sound.
+fn check_hint_array(
+    ctx: &Context,
+    sb: &Superblock,
+    metadata_sm: &ASpaceMap,
+    nr_visited: &AtomicU64,
+    ignore_non_fatal: bool,
+) -> anyhow::Result<bool> {
+    if sb.policy_hint_size != Hint::disk_size() {
+        return Err(anyhow!(
+            "cache_check only supports policy hint size of {}",
+            Hint::disk_size()
+        ));
+    }
+
+    let w = ArrayWalker::new_with_sm(ctx.engine.clone(), metadata_sm.clone(), ignore_non_fatal)?;
+    let c = HintChecker::new(max_hint(&sb.policy_name));
+    let mut v = ProgressVisitor::new(&c, nr_visited);
+    if let Err(e) = w.walk(&mut v, sb.hint_root) {
+        ctx.report.non_fatal(&format!("hint array: {}", e));
+        return Ok(false);
+    }
+
+    let mut ok = true;
+    let inner = c.inner.lock().unwrap();
+    if inner.nr_entries != sb.cache_blocks as u64 {
+        ctx.report.non_fatal(&format!(
+            "hint array holds {} entries, but there are {} cache blocks",
+            inner.nr_entries, sb.cache_blocks
+        ));
+        ok = false;
+    }
+
+    if let Some(cblock) = inner.first_out_of_range {
+        ctx.report.non_fatal(&format!(
+            "{} hints are out of range for the {} policy, the first for cache block {}",
+            inner.nr_out_of_range,
+            String::from_utf8_lossy(&sb.policy_name),
+            cblock
+        ));
+        ok = false;
+    }
+
+    Ok(ok)
+}
+
 //------------------------------------------
 
-// TODO: clear_needs_check, auto_repair
+/// The findings a --policy file can act on.  Metadata leaks don't fail
+/// the check by default.
+pub const POLICY_CATEGORIES: &[Category] = &[
+    Category {
+        name: "metadata_leaks",
+        default: Action::Ignore,
+        fixable: true,
+        lossy: false,
+    },
+    Category {
+        name: "hint_array",
+        default: Action::Fail,
+        fixable: false,
+        lossy: false,
+    },
+    Category {
+        name: "needs_check",
+        default: Action::Ignore,
+        fixable: false,
+        lossy: false,
+    },
+];
+
+// TODO: clear_needs_check
 pub struct CacheCheckOptions<'a> {
     pub dev: &'a Path,
     pub async_io: bool,
+    pub nr_io_threads: usize,
     pub sb_only: bool,
     pub skip_mappings: bool,
     pub skip_hints: bool,
     pub skip_discards: bool,
     pub ignore_non_fatal: bool,
-    pub auto_repair: bool,
+    pub policy: Policy,
+
+    /// Where to save the blocks any repair overwrites.
+    pub journal: Option<&'a Path>,
     pub report: Arc<Report>,
 }
 
@@ -236,21 +400,36 @@ pub struct CacheCheckOptions<'a> {
 struct Context {
     report: Arc<Report>,
     engine: Arc<dyn IoEngine + Send + Sync>,
+
+    // Holds any repairs until the check has passed.
+    txn: Option<Arc<TransactionEngine>>,
 }
 
 fn mk_context(opts: &CacheCheckOptions) -> anyhow::Result<Context> {
-    let engine: Arc<dyn IoEngine + Send + Sync>;
+    let mut engine: Arc<dyn IoEngine + Send + Sync>;
+    let writable = opts.policy.fixes_anything();
 
     if opts.async_io {
-        engine = Arc::new(AsyncIoEngine::new(opts.dev, MAX_CONCURRENT_IO, false)?);
+        engine = Arc::new(AsyncIoEngine::new(opts.dev, MAX_CONCURRENT_IO, writable)?);
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
-        engine = Arc::new(SyncIoEngine::new(opts.dev, nr_threads, false)?);
+        engine = Arc::new(SyncIoEngine::new(opts.dev, opts.nr_io_threads, writable)?);
+    }
+
+    if let Some(journal) = opts.journal {
+        engine = Arc::new(JournalEngine::new(engine, journal)?);
+    }
+
+    let mut txn = None;
+    if writable {
+        let t = Arc::new(TransactionEngine::new(engine));
+        engine = t.clone();
+        txn = Some(t);
     }
 
     Ok(Context {
         report: opts.report.clone(),
         engine,
+        txn,
     })
 }
 
@@ -261,10 +440,16 @@ fn check_superblock(sb: &Superblock) -> anyhow::Result<()> {
     Ok(())
 }
 
+#[instrument(skip_all, fields(nr_metadata_blocks))]
 pub fn check(opts: CacheCheckOptions) -> anyhow::Result<()> {
     let ctx = mk_context(&opts)?;
+    Span::current().record("nr_metadata_blocks", ctx.engine.get_nr_blocks());
 
     let engine = &ctx.engine;
+    let _metadata_sm_claim = memory::claim(
+        core_sm_bytes(engine.get_nr_blocks(), u8::MAX as u32),
+        "metadata space map",
+    )?;
     let metadata_sm = core_sm(engine.get_nr_blocks(), u8::MAX as u32);
     inc_superblock(&metadata_sm)?;
 
@@ -282,6 +467,42 @@ pub fn check(opts: CacheCheckOptions) -> anyhow::Result<()> {
         return Ok(());
     }
 
+    ctx.report.set_title("Checking cache metadata");
+
+    let check_hints = !opts.skip_hints && sb.hint_root != 0 && sb.policy_hint_size != 0;
+    let mut nr_total = 0;
+    if !opts.skip_mappings {
+        nr_total += sb.cache_blocks as u64;
+    }
+    if check_hints {
+        nr_total += sb.cache_blocks as u64;
+    }
+
+    let nr_visited = Arc::new(AtomicU64::new(0));
+    let (tid, stop_progress) =
+        spawn_progress_thread(nr_visited.clone(), nr_total, ctx.report.clone());
+
+    let mut r = check_metadata(&ctx, &opts, &sb, &metadata_sm, check_hints, &nr_visited);
+
+    stop_progress.store(true, Ordering::Relaxed);
+    tid.join().unwrap();
+
+    if let (Ok(()), Some(txn)) = (&r, &ctx.txn) {
+        r = txn.commit().map(|_| ()).map_err(|e| e.into());
+    }
+    r
+}
+
+fn check_metadata(
+    ctx: &Context,
+    opts: &CacheCheckOptions,
+    sb: &Superblock,
+    metadata_sm: &ASpaceMap,
+    check_hints: bool,
+    nr_visited: &AtomicU64,
+) -> anyhow::Result<()> {
+    let engine = &ctx.engine;
+
     // The discard bitset is optional and could be updated during device suspension.
     // A restored metadata therefore comes with a zero-sized discard bitset,
     // and also zeroed discard_block_size and discard_nr_blocks.
@@ -295,12 +516,14 @@ pub fn check(opts: CacheCheckOptions) -> anyhow::Result<()> {
 
     // TODO: factor out into check_mappings()
     if !opts.skip_mappings {
+        ctx.report.set_sub_title("mapping array");
         let w =
             ArrayWalker::new_with_sm(engine.clone(), metadata_sm.clone(), opts.ignore_non_fatal)?;
         match sb.version {
             1 => {
-                let mut c = format1::MappingChecker::new(nr_origin_blocks);
-                if let Err(e) = w.walk(&mut c, sb.mapping_root) {
+                let c = format1::MappingChecker::new(nr_origin_blocks);
+                let mut v = ProgressVisitor::new(&c, nr_visited);
+                if let Err(e) = w.walk(&mut v, sb.mapping_root) {
                     ctx.report.fatal(&format!("{}", e));
                 }
             }
@@ -315,8 +538,9 @@ pub fn check(opts: CacheCheckOptions) -> anyhow::Result<()> {
                 if err.is_some() {
                     ctx.report.fatal(&format!("{}", err.unwrap()));
                 }
-                let mut c = format2::MappingChecker::new(nr_origin_blocks, dirty_bits);
-                if let Err(e) = w.walk(&mut c, sb.mapping_root) {
+                let c = format2::MappingChecker::new(nr_origin_blocks, dirty_bits);
+                let mut v = ProgressVisitor::new(&c, nr_visited);
+                if let Err(e) = w.walk(&mut v, sb.mapping_root) {
                     ctx.report.fatal(&format!("{}", e));
                 }
             }
@@ -326,21 +550,16 @@ pub fn check(opts: CacheCheckOptions) -> anyhow::Result<()> {
         }
     }
 
-    if !opts.skip_hints && sb.hint_root != 0 && sb.policy_hint_size != 0 {
-        if sb.policy_hint_size != 4 {
-            return Err(anyhow!("cache_check only supports policy hint size of 4"));
-        }
-        let w =
-            ArrayWalker::new_with_sm(engine.clone(), metadata_sm.clone(), opts.ignore_non_fatal)?;
-        let mut c = HintChecker::new();
-        if let Err(e) = w.walk(&mut c, sb.hint_root) {
-            ctx.report.fatal(&format!("{}", e));
-        }
+    let mut hints_ok = true;
+    if check_hints {
+        ctx.report.set_sub_title("hint array");
+        hints_ok = check_hint_array(ctx, sb, metadata_sm, nr_visited, opts.ignore_non_fatal)?;
     }
 
     // The discard bitset might not be available if the cache has never been suspended,
     // e.g., a crash of freshly created cache.
     if !opts.skip_discards && sb.discard_root != 0 {
+        ctx.report.set_sub_title("discard bitset");
         let (_discard_bits, err) = read_bitset_with_sm(
             engine.clone(),
             sb.discard_root,
@@ -353,7 +572,20 @@ pub fn check(opts: CacheCheckOptions) -> anyhow::Result<()> {
         }
     }
 
+    ctx.report.set_sub_title("metadata space map");
     let root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
+
+    // The space map covers the whole device, so a smaller device has
+    // lost its end, eg, to a truncated copy of the metadata.
+    if root.nr_blocks > engine.get_nr_blocks() {
+        ctx.report.fatal(&format!(
+            "The metadata device holds {} blocks, but the metadata space map has {}, it may have been truncated",
+            engine.get_nr_blocks(),
+            root.nr_blocks
+        ));
+        return Err(anyhow!("the metadata device is smaller than the metadata"));
+    }
+
     let metadata_leaks = check_metadata_space_map(
         engine.clone(),
         ctx.report.clone(),
@@ -362,11 +594,53 @@ pub fn check(opts: CacheCheckOptions) -> anyhow::Result<()> {
         opts.ignore_non_fatal,
     )?;
 
-    if opts.auto_repair && !metadata_leaks.is_empty() {
+    let policy = &opts.policy;
+    policy.weigh(
+        "hint_array",
+        !hints_ok,
+        "hint array is damaged",
+        &ctx.report,
+    )?;
+    let fix_leaks = policy.weigh(
+        "metadata_leaks",
+        !metadata_leaks.is_empty(),
+        "metadata space map contains leaks",
+        &ctx.report,
+    )?;
+
+    if fix_leaks {
         ctx.report.info("Repairing metadata leaks.");
-        repair_space_map(ctx.engine.clone(), metadata_leaks, metadata_sm.clone())?;
+        repair_metadata_sm(ctx, sb, metadata_sm)?;
+    }
+
+    Ok(())
+}
+
+// Writes a new metadata space map, from the counts the check worked out,
+// to blocks the old metadata doesn't use, and points the superblock at
+// it.  The superblock is the only block overwritten.
+fn repair_metadata_sm(
+    ctx: &Context,
+    sb: &Superblock,
+    metadata_sm: &ASpaceMap,
+) -> anyhow::Result<()> {
+    let root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
+    let sm = CowSpaceMap::new(metadata_sm.clone(), root.nr_blocks)?;
+    if let Some(txn) = &ctx.txn {
+        txn.protect(sm.in_use());
     }
 
+    let mut w = WriteBatcher::new(
+        ctx.engine.clone(),
+        Arc::new(Mutex::new(sm)),
+        ctx.engine.get_batch_size(),
+    );
+    release_metadata_sm(&mut w, &root)?;
+    let root = rewrite_metadata_sm(&mut w)?;
+
+    let mut sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    sb.metadata_sm_root = pack_root(&root, SPACE_MAP_ROOT_SIZE)?;
+    write_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION, &sb)?;
     Ok(())
 }
 
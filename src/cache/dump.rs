@@ -5,6 +5,7 @@ use std::io::BufWriter;
 use std::io::Write;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use tracing::{instrument, Span};
 
 use crate::cache::hint::Hint;
 use crate::cache::ir::{self, MetadataVisitor};
@@ -198,6 +199,7 @@ pub struct CacheDumpOptions<'a> {
     pub input: &'a Path,
     pub output: Option<&'a Path>,
     pub async_io: bool,
+    pub nr_io_threads: usize,
     pub repair: bool,
 }
 
@@ -211,8 +213,7 @@ fn mk_context(opts: &CacheDumpOptions) -> anyhow::Result<Context> {
     if opts.async_io {
         engine = Arc::new(AsyncIoEngine::new(opts.input, MAX_CONCURRENT_IO, false)?);
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
-        engine = Arc::new(SyncIoEngine::new(opts.input, nr_threads, false)?);
+        engine = Arc::new(SyncIoEngine::new(opts.input, opts.nr_io_threads, false)?);
     }
 
     Ok(Context { engine })
@@ -283,8 +284,10 @@ pub fn dump_metadata(
     Ok(())
 }
 
+#[instrument(skip_all, fields(nr_metadata_blocks))]
 pub fn dump(opts: CacheDumpOptions) -> anyhow::Result<()> {
     let ctx = mk_context(&opts)?;
+    Span::current().record("nr_metadata_blocks", ctx.engine.get_nr_blocks());
     let sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
 
     let writer: Box<dyn Write>;
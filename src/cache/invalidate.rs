@@ -0,0 +1,260 @@
+use anyhow::{anyhow, Result};
+use fixedbitset::FixedBitSet;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::instrument;
+
+use crate::cache::dump::*;
+use crate::cache::ir::{self, MetadataVisitor, Visit};
+use crate::cache::restore::*;
+use crate::cache::superblock::*;
+use crate::io_engine::*;
+use crate::memory;
+use crate::pdata::space_map_metadata::*;
+use crate::report::*;
+use crate::write_batcher::*;
+
+//------------------------------------------
+
+const MAX_CONCURRENT_IO: u32 = 1024;
+
+//------------------------------------------
+
+/// Parses a comma separated list of blocks, or `begin-end` ranges where
+/// the end is exclusive, as the kernel's invalidate_cblocks message takes.
+pub fn parse_ranges(s: &str) -> Result<Vec<Range<u64>>> {
+    let mut ranges = Vec::new();
+
+    for item in s.split(',') {
+        let item = item.trim();
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<u64>()
+                .map_err(|_| anyhow!("invalid block range '{}'", item))
+        };
+
+        let r = match item.split_once('-') {
+            Some((begin, end)) => parse(begin)?..parse(end)?,
+            None => {
+                let b = parse(item)?;
+                b..b + 1
+            }
+        };
+
+        if r.is_empty() {
+            return Err(anyhow!("empty block range '{}'", item));
+        }
+        ranges.push(r);
+    }
+
+    Ok(ranges)
+}
+
+//------------------------------------------
+
+pub struct CacheInvalidateOptions<'a> {
+    pub input: &'a Path,
+    pub output: &'a Path,
+    pub async_io: bool,
+    pub nr_io_threads: usize,
+    pub cblocks: Vec<Range<u64>>,
+    pub oblocks: Vec<Range<u64>>,
+
+    /// Dirty blocks are only dropped if this is set, since their data
+    /// hasn't reached the origin.
+    pub force: bool,
+    pub report: Arc<Report>,
+}
+
+struct Context {
+    report: Arc<Report>,
+    engine_in: Arc<dyn IoEngine + Send + Sync>,
+    engine_out: Arc<dyn IoEngine + Send + Sync>,
+}
+
+fn new_context(opts: &CacheInvalidateOptions) -> Result<Context> {
+    let engine_in: Arc<dyn IoEngine + Send + Sync>;
+    let engine_out: Arc<dyn IoEngine + Send + Sync>;
+
+    if opts.async_io {
+        engine_in = Arc::new(AsyncIoEngine::new(opts.input, MAX_CONCURRENT_IO, false)?);
+        engine_out = Arc::new(AsyncIoEngine::new(opts.output, MAX_CONCURRENT_IO, true)?);
+    } else {
+        engine_in = Arc::new(SyncIoEngine::new(opts.input, opts.nr_io_threads, false)?);
+        engine_out = Arc::new(SyncIoEngine::new(opts.output, opts.nr_io_threads, true)?);
+    }
+
+    Ok(Context {
+        report: opts.report.clone(),
+        engine_in,
+        engine_out,
+    })
+}
+
+//------------------------------------------
+
+// Passes the metadata through to the restorer, minus the selected
+// mappings and their hints.
+struct Invalidator<'a> {
+    out: &'a mut dyn MetadataVisitor,
+    cblocks: &'a [Range<u64>],
+    oblocks: &'a [Range<u64>],
+    force: bool,
+
+    // The cache blocks whose mappings were dropped.
+    invalidated: FixedBitSet,
+    nr_invalidated: u64,
+    nr_dirty: u64,
+}
+
+impl<'a> Invalidator<'a> {
+    fn selected(&self, m: &ir::Map) -> bool {
+        let cblock = m.cblock as u64;
+        self.cblocks.iter().any(|r| r.contains(&cblock))
+            || self.oblocks.iter().any(|r| r.contains(&m.oblock))
+    }
+}
+
+impl<'a> MetadataVisitor for Invalidator<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.invalidated = FixedBitSet::with_capacity(sb.nr_cache_blocks as usize);
+        self.out.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.out.superblock_e()
+    }
+
+    fn mappings_b(&mut self) -> Result<Visit> {
+        self.out.mappings_b()
+    }
+
+    fn mappings_e(&mut self) -> Result<Visit> {
+        self.out.mappings_e()
+    }
+
+    fn mapping(&mut self, m: &ir::Map) -> Result<Visit> {
+        if !self.selected(m) {
+            return self.out.mapping(m);
+        }
+
+        if m.dirty {
+            if !self.force {
+                return Err(anyhow!(
+                    "cache block {} is dirty, use --force to drop it anyway",
+                    m.cblock
+                ));
+            }
+            self.nr_dirty += 1;
+        }
+
+        self.invalidated.insert(m.cblock as usize);
+        self.nr_invalidated += 1;
+        Ok(Visit::Continue)
+    }
+
+    fn hints_b(&mut self) -> Result<Visit> {
+        self.out.hints_b()
+    }
+
+    fn hints_e(&mut self) -> Result<Visit> {
+        self.out.hints_e()
+    }
+
+    fn hint(&mut self, h: &ir::Hint) -> Result<Visit> {
+        if self.invalidated.contains(h.cblock as usize) {
+            return Ok(Visit::Continue);
+        }
+        self.out.hint(h)
+    }
+
+    fn discards_b(&mut self) -> Result<Visit> {
+        self.out.discards_b()
+    }
+
+    fn discards_e(&mut self) -> Result<Visit> {
+        self.out.discards_e()
+    }
+
+    fn discard(&mut self, d: &ir::Discard) -> Result<Visit> {
+        self.out.discard(d)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.out.eof()
+    }
+}
+
+//------------------------------------------
+
+#[instrument(skip_all)]
+pub fn invalidate(opts: CacheInvalidateOptions) -> Result<()> {
+    let ctx = new_context(&opts)?;
+
+    let sb = read_superblock(ctx.engine_in.as_ref(), SUPERBLOCK_LOCATION)?;
+
+    let _metadata_sm_claim = memory::claim(
+        core_metadata_sm_bytes(ctx.engine_out.get_nr_blocks(), u32::MAX),
+        "metadata space map",
+    )?;
+    let sm = core_metadata_sm(ctx.engine_out.get_nr_blocks(), u32::MAX);
+    let mut w = WriteBatcher::new(
+        ctx.engine_out.clone(),
+        sm.clone(),
+        ctx.engine_out.get_batch_size(),
+    );
+
+    // Keep the input's format, this isn't an upgrade.
+    let format = OutputFormat {
+        metadata_version: sb.version,
+        ..Default::default()
+    };
+    let mut restorer = Restorer::with_format(&mut w, format);
+
+    let mut invalidator = Invalidator {
+        out: &mut restorer,
+        cblocks: &opts.cblocks,
+        oblocks: &opts.oblocks,
+        force: opts.force,
+        invalidated: FixedBitSet::new(),
+        nr_invalidated: 0,
+        nr_dirty: 0,
+    };
+    dump_metadata(ctx.engine_in, &mut invalidator, &sb, false)?;
+
+    ctx.report.info(&format!(
+        "invalidated {} cache blocks, {} of them dirty",
+        invalidator.nr_invalidated, invalidator.nr_dirty
+    ));
+
+    Ok(())
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(parse_ranges("5").unwrap(), vec![5..6]);
+        assert_eq!(
+            parse_ranges("1,10-20, 7").unwrap(),
+            vec![1..2, 10..20, 7..8]
+        );
+    }
+
+    #[test]
+    fn test_parse_bad_ranges() {
+        assert!(parse_ranges("").is_err());
+        assert!(parse_ranges("1,,2").is_err());
+        assert!(parse_ranges("x").is_err());
+        assert!(parse_ranges("5-5").is_err());
+        assert!(parse_ranges("8-4").is_err());
+        assert!(parse_ranges("1-2-3").is_err());
+    }
+}
+
+//------------------------------------------
@@ -1,10 +1,12 @@
 pub mod check;
 pub mod dump;
 pub mod hint;
+pub mod invalidate;
 pub mod ir;
 pub mod mapping;
 pub mod metadata_size;
 pub mod repair;
 pub mod restore;
+pub mod stat;
 pub mod superblock;
 pub mod xml;
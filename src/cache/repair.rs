@@ -1,11 +1,13 @@
 use anyhow::Result;
 use std::path::Path;
 use std::sync::Arc;
+use tracing::instrument;
 
 use crate::cache::dump::*;
 use crate::cache::restore::*;
 use crate::cache::superblock::*;
 use crate::io_engine::*;
+use crate::memory;
 use crate::pdata::space_map_metadata::*;
 use crate::report::*;
 use crate::write_batcher::*;
@@ -16,6 +18,9 @@ pub struct CacheRepairOptions<'a> {
     pub input: &'a Path,
     pub output: &'a Path,
     pub async_io: bool,
+    pub nr_io_threads: usize,
+    /// Version 1 metadata is upgraded unless this asks for version 1.
+    pub metadata_version: u32,
     pub report: Arc<Report>,
 }
 
@@ -35,9 +40,8 @@ fn new_context(opts: &CacheRepairOptions) -> Result<Context> {
         engine_in = Arc::new(AsyncIoEngine::new(opts.input, MAX_CONCURRENT_IO, false)?);
         engine_out = Arc::new(AsyncIoEngine::new(opts.output, MAX_CONCURRENT_IO, true)?);
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
-        engine_in = Arc::new(SyncIoEngine::new(opts.input, nr_threads, false)?);
-        engine_out = Arc::new(SyncIoEngine::new(opts.output, nr_threads, true)?);
+        engine_in = Arc::new(SyncIoEngine::new(opts.input, opts.nr_io_threads, false)?);
+        engine_out = Arc::new(SyncIoEngine::new(opts.output, opts.nr_io_threads, true)?);
     }
 
     Ok(Context {
@@ -49,18 +53,27 @@ fn new_context(opts: &CacheRepairOptions) -> Result<Context> {
 
 //------------------------------------------
 
+#[instrument(skip_all)]
 pub fn repair(opts: CacheRepairOptions) -> Result<()> {
     let ctx = new_context(&opts)?;
 
     let sb = read_superblock(ctx.engine_in.as_ref(), SUPERBLOCK_LOCATION)?;
 
+    let _metadata_sm_claim = memory::claim(
+        core_metadata_sm_bytes(ctx.engine_out.get_nr_blocks(), u32::MAX),
+        "metadata space map",
+    )?;
     let sm = core_metadata_sm(ctx.engine_out.get_nr_blocks(), u32::MAX);
     let mut w = WriteBatcher::new(
         ctx.engine_out.clone(),
         sm.clone(),
         ctx.engine_out.get_batch_size(),
     );
-    let mut restorer = Restorer::new(&mut w);
+    let format = OutputFormat {
+        metadata_version: opts.metadata_version,
+        ..Default::default()
+    };
+    let mut restorer = Restorer::with_format(&mut w, format);
 
     dump_metadata(ctx.engine_in, &mut restorer, &sb, true)
 }
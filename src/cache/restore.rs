@@ -4,6 +4,7 @@ use std::convert::TryInto;
 use std::fs::OpenOptions;
 use std::path::Path;
 use std::sync::Arc;
+use tracing::{instrument, Span};
 
 use crate::cache::hint::Hint;
 use crate::cache::ir::{self, MetadataVisitor, Visit};
@@ -12,9 +13,11 @@ use crate::cache::superblock::*;
 use crate::cache::xml;
 use crate::io_engine::*;
 use crate::math::*;
+use crate::memory;
 use crate::pdata::array_builder::*;
 use crate::pdata::space_map_common::pack_root;
 use crate::pdata::space_map_metadata::*;
+use crate::pdata::unpack::Unpack;
 use crate::report::*;
 use crate::write_batcher::*;
 
@@ -28,9 +31,63 @@ pub struct CacheRestoreOptions<'a> {
     pub input: &'a Path,
     pub output: &'a Path,
     pub async_io: bool,
+    pub nr_io_threads: usize,
+    pub format: OutputFormat,
     pub report: Arc<Report>,
 }
 
+/// How the restored metadata is laid out, where it may differ from the
+/// xml.
+#[derive(Clone)]
+pub struct OutputFormat {
+    pub metadata_version: u32,
+
+    /// Replaces the data block size given in the xml, in sectors.
+    pub data_block_size: Option<u32>,
+
+    /// Hints are truncated or zero padded to this many bytes.  A width
+    /// of zero drops them.
+    pub hint_width: Option<u32>,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat {
+            metadata_version: 2,
+            data_block_size: None,
+            hint_width: None,
+        }
+    }
+}
+
+// The kernel takes blocks from 32KiB to 1GiB, in multiples of 32KiB.
+const MIN_DATA_BLOCK_SIZE: u32 = 64;
+const MAX_DATA_BLOCK_SIZE: u32 = 2097152;
+
+fn check_format(format: &OutputFormat) -> Result<()> {
+    if format.metadata_version != 1 && format.metadata_version != 2 {
+        return Err(anyhow!(
+            "unsupported metadata version: {}",
+            format.metadata_version
+        ));
+    }
+
+    if let Some(bs) = format.data_block_size {
+        if !(MIN_DATA_BLOCK_SIZE..=MAX_DATA_BLOCK_SIZE).contains(&bs)
+            || bs % MIN_DATA_BLOCK_SIZE != 0
+        {
+            return Err(anyhow!(
+                "data block size must be a multiple of {} sectors, between {} and {}",
+                MIN_DATA_BLOCK_SIZE,
+                MIN_DATA_BLOCK_SIZE,
+                MAX_DATA_BLOCK_SIZE
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 struct Context {
     _report: Arc<Report>,
     engine: Arc<dyn IoEngine + Send + Sync>,
@@ -42,8 +99,7 @@ fn mk_context(opts: &CacheRestoreOptions) -> anyhow::Result<Context> {
     if opts.async_io {
         engine = Arc::new(AsyncIoEngine::new(opts.output, MAX_CONCURRENT_IO, true)?);
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
-        engine = Arc::new(SyncIoEngine::new(opts.output, nr_threads, true)?);
+        engine = Arc::new(SyncIoEngine::new(opts.output, opts.nr_io_threads, true)?);
     }
 
     Ok(Context {
@@ -74,11 +130,17 @@ pub struct Restorer<'a> {
     hint_root: Option<u64>,
     discard_root: Option<u64>,
     dirty_bits: (u32, u64), // (index in u64 array, value)
+    hint_width: u32,
+    format: OutputFormat,
     in_section: Section,
 }
 
 impl<'a> Restorer<'a> {
     pub fn new(w: &'a mut WriteBatcher) -> Restorer<'a> {
+        Restorer::with_format(w, OutputFormat::default())
+    }
+
+    pub fn with_format(w: &'a mut WriteBatcher, format: OutputFormat) -> Restorer<'a> {
         Restorer {
             write_batcher: w,
             sb: None,
@@ -90,6 +152,8 @@ impl<'a> Restorer<'a> {
             hint_root: None,
             discard_root: None,
             dirty_bits: (0, 0),
+            hint_width: 0,
+            format,
             in_section: Section::None,
         }
     }
@@ -136,10 +200,10 @@ impl<'a> Restorer<'a> {
                 needs_check: false,
             },
             block: SUPERBLOCK_LOCATION,
-            version: 2,
+            version: self.format.metadata_version,
             policy_name: src_sb.policy.as_bytes().to_vec(),
             policy_version: vec![2, 0, 0],
-            policy_hint_size: src_sb.hint_width,
+            policy_hint_size: self.hint_width,
             metadata_sm_root,
             mapping_root: *mapping_root,
             dirty_root: self.dirty_root, // dirty_root is optional
@@ -147,7 +211,7 @@ impl<'a> Restorer<'a> {
             discard_root: *discard_root,
             discard_block_size: 0,
             discard_nr_blocks: 0,
-            data_block_size: src_sb.block_size,
+            data_block_size: self.format.data_block_size.unwrap_or(src_sb.block_size),
             cache_blocks: src_sb.nr_cache_blocks,
             compat_flags: 0,
             compat_ro_flags: 0,
@@ -170,6 +234,12 @@ impl<'a> MetadataVisitor for Restorer<'a> {
             return Err(anyhow!("duplicated superblock"));
         }
 
+        // Hints are stored in a fixed size array entry.
+        self.hint_width = self.format.hint_width.unwrap_or(sb.hint_width);
+        if self.hint_width != 0 && self.hint_width != Hint::disk_size() {
+            return Err(anyhow!("unsupported hint width: {}", self.hint_width));
+        }
+
         self.sb = Some(sb.clone());
         let b = self.write_batcher.alloc()?;
         if b.loc != SUPERBLOCK_LOCATION {
@@ -177,8 +247,18 @@ impl<'a> MetadataVisitor for Restorer<'a> {
         }
 
         self.mapping_builder = Some(ArrayBuilder::new(sb.nr_cache_blocks as u64));
-        self.dirty_builder = Some(ArrayBuilder::new(div_up(sb.nr_cache_blocks as u64, 64)));
-        self.hint_builder = Some(ArrayBuilder::new(sb.nr_cache_blocks as u64));
+
+        // Version 1 keeps the dirty flags in the mappings.
+        if self.format.metadata_version >= 2 {
+            self.dirty_builder = Some(ArrayBuilder::new(div_up(sb.nr_cache_blocks as u64, 64)));
+        }
+
+        let nr_hints = if self.hint_width == 0 {
+            0
+        } else {
+            sb.nr_cache_blocks as u64
+        };
+        self.hint_builder = Some(ArrayBuilder::new(nr_hints));
 
         let discard_builder = ArrayBuilder::<u64>::new(0); // discard bitset is optional
         self.discard_root = Some(discard_builder.complete(self.write_batcher)?);
@@ -209,20 +289,21 @@ impl<'a> MetadataVisitor for Restorer<'a> {
     }
 
     fn mapping(&mut self, m: &ir::Map) -> Result<Visit> {
+        let mut flags = MappingFlags::Valid as u32;
+        if m.dirty && self.dirty_builder.is_none() {
+            flags |= MappingFlags::Dirty as u32;
+        }
         let map = Mapping {
             oblock: m.oblock,
-            flags: MappingFlags::Valid as u32,
+            flags,
         };
         let mapping_builder = self.mapping_builder.as_mut().unwrap();
         mapping_builder.push_value(self.write_batcher, m.cblock as u64, map)?;
 
-        if m.dirty {
+        if let (true, Some(dirty_builder)) = (m.dirty, self.dirty_builder.as_mut()) {
             let index = m.cblock >> 6;
             let mask = 1 << (m.cblock & 63);
-            if index == self.dirty_bits.0 {
-                self.dirty_bits.1 |= mask;
-            } else {
-                let dirty_builder = self.dirty_builder.as_mut().unwrap();
+            if index != self.dirty_bits.0 {
                 dirty_builder.push_value(
                     self.write_batcher,
                     self.dirty_bits.0 as u64,
@@ -231,6 +312,7 @@ impl<'a> MetadataVisitor for Restorer<'a> {
                 self.dirty_bits.0 = index;
                 self.dirty_bits.1 = 0;
             }
+            self.dirty_bits.1 |= mask;
         }
 
         Ok(Visit::Continue)
@@ -253,8 +335,14 @@ impl<'a> MetadataVisitor for Restorer<'a> {
     }
 
     fn hint(&mut self, h: &ir::Hint) -> Result<Visit> {
+        if self.hint_width == 0 {
+            return Ok(Visit::Continue);
+        }
+
+        let mut data = h.data.clone();
+        data.resize(self.hint_width as usize, 0);
         let hint = Hint {
-            hint: h.data[..].try_into().unwrap(),
+            hint: data[..].try_into().unwrap(),
         };
         let hint_builder = self.hint_builder.as_mut().unwrap();
         hint_builder.push_value(self.write_batcher, h.cblock as u64, hint)?;
@@ -292,20 +380,29 @@ fn build_metadata_sm(w: &mut WriteBatcher) -> Result<Vec<u8>> {
 
 //------------------------------------------
 
+#[instrument(skip_all, fields(nr_metadata_blocks, nr_allocated))]
 pub fn restore(opts: CacheRestoreOptions) -> Result<()> {
+    check_format(&opts.format)?;
+
     let input = OpenOptions::new()
         .read(true)
         .write(false)
         .open(opts.input)?;
 
     let ctx = mk_context(&opts)?;
+    Span::current().record("nr_metadata_blocks", ctx.engine.get_nr_blocks());
 
+    let _metadata_sm_claim = memory::claim(
+        core_metadata_sm_bytes(ctx.engine.get_nr_blocks(), u32::MAX),
+        "metadata space map",
+    )?;
     let sm = core_metadata_sm(ctx.engine.get_nr_blocks(), u32::MAX);
     let mut w = WriteBatcher::new(ctx.engine.clone(), sm.clone(), ctx.engine.get_batch_size());
 
     // build cache mappings
-    let mut restorer = Restorer::new(&mut w);
+    let mut restorer = Restorer::with_format(&mut w, opts.format.clone());
     xml::read(input, &mut restorer)?;
+    Span::current().record("nr_allocated", sm.lock().unwrap().get_nr_allocated()?);
 
     Ok(())
 }
@@ -0,0 +1,261 @@
+use anyhow::Result;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::cache::dump::dump_metadata;
+use crate::cache::ir::{self, MetadataVisitor, Visit};
+use crate::cache::superblock::*;
+use crate::io_engine::*;
+
+//------------------------------------------
+
+const MAX_CONCURRENT_IO: u32 = 1024;
+
+//------------------------------------------
+
+pub struct CacheStatOptions<'a> {
+    pub input: &'a Path,
+    pub async_io: bool,
+    pub nr_io_threads: usize,
+    pub nr_buckets: u64,
+}
+
+/// How many cache blocks map each slice of the origin.
+pub struct Bucket {
+    pub begin: u64,
+    pub end: u64,
+    pub nr_resident: u64,
+}
+
+pub struct CacheStats {
+    pub metadata_version: u32,
+    pub block_size: u32,
+    pub nr_cache_blocks: u32,
+    pub policy: String,
+    pub policy_version: Vec<u32>,
+    pub hint_width: u32,
+    pub nr_resident: u64,
+    pub nr_dirty: u64,
+
+    /// The size of the origin, in cache blocks, if the metadata records
+    /// it.  The histogram only covers the mapped blocks otherwise.
+    pub nr_origin_blocks: Option<u64>,
+    pub histogram: Vec<Bucket>,
+}
+
+//------------------------------------------
+
+// Gathers the mappings as they're dumped.
+#[derive(Default)]
+struct Collector {
+    nr_dirty: u64,
+    oblocks: Vec<u64>,
+}
+
+impl MetadataVisitor for Collector {
+    fn superblock_b(&mut self, _sb: &ir::Superblock) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn mappings_b(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn mappings_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn mapping(&mut self, m: &ir::Map) -> Result<Visit> {
+        if m.dirty {
+            self.nr_dirty += 1;
+        }
+        self.oblocks.push(m.oblock);
+        Ok(Visit::Continue)
+    }
+
+    fn hints_b(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn hints_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn hint(&mut self, _h: &ir::Hint) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn discards_b(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn discards_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn discard(&mut self, _d: &ir::Discard) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
+// The origin size can only be worked out from the discard bitset, which
+// is only trusted after a clean shutdown.
+fn nr_origin_blocks(sb: &Superblock) -> Option<u64> {
+    if sb.flags.clean_shutdown && sb.discard_block_size > 0 && sb.discard_nr_blocks > 0 {
+        Some(sb.discard_block_size * sb.discard_nr_blocks / sb.data_block_size as u64)
+    } else {
+        None
+    }
+}
+
+fn build_histogram(oblocks: &[u64], end: u64, nr_buckets: u64) -> Vec<Bucket> {
+    let nr_buckets = u64::max(1, u64::min(nr_buckets, end));
+    // Rounded up, so the buckets cover every block.
+    let mut width = end / nr_buckets;
+    if width * nr_buckets < end {
+        width += 1;
+    }
+
+    let mut histogram: Vec<Bucket> = (0..nr_buckets)
+        .map(|i| Bucket {
+            begin: i * width,
+            end: u64::min((i + 1) * width, end),
+            nr_resident: 0,
+        })
+        .filter(|b| b.begin < b.end)
+        .collect();
+
+    for b in oblocks {
+        histogram[(b / width) as usize].nr_resident += 1;
+    }
+
+    histogram
+}
+
+pub fn stat(opts: CacheStatOptions) -> Result<CacheStats> {
+    let engine: Arc<dyn IoEngine + Send + Sync> = if opts.async_io {
+        Arc::new(AsyncIoEngine::new(opts.input, MAX_CONCURRENT_IO, false)?)
+    } else {
+        Arc::new(SyncIoEngine::new(opts.input, opts.nr_io_threads, false)?)
+    };
+
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let mut collector = Collector::default();
+    dump_metadata(engine, &mut collector, &sb, false)?;
+
+    let nr_origin_blocks = nr_origin_blocks(&sb);
+    let mapped_end = collector.oblocks.iter().max().map_or(0, |b| b + 1);
+    let end = u64::max(nr_origin_blocks.unwrap_or(0), mapped_end);
+    let histogram = build_histogram(&collector.oblocks, end, opts.nr_buckets);
+
+    Ok(CacheStats {
+        metadata_version: sb.version,
+        block_size: sb.data_block_size,
+        nr_cache_blocks: sb.cache_blocks,
+        policy: String::from_utf8_lossy(&sb.policy_name).to_string(),
+        policy_version: sb.policy_version.clone(),
+        hint_width: sb.policy_hint_size,
+        nr_resident: collector.oblocks.len() as u64,
+        nr_dirty: collector.nr_dirty,
+        nr_origin_blocks,
+        histogram,
+    })
+}
+
+//------------------------------------------
+
+fn percent(n: u64, d: u64) -> f64 {
+    if d == 0 {
+        0.0
+    } else {
+        n as f64 * 100.0 / d as f64
+    }
+}
+
+pub fn write_stats<W: Write>(w: &mut W, stats: &CacheStats) -> Result<()> {
+    let version: Vec<String> = stats.policy_version.iter().map(|v| v.to_string()).collect();
+
+    writeln!(w, "metadata version: {}", stats.metadata_version)?;
+    writeln!(w, "block size:       {} sectors", stats.block_size)?;
+    writeln!(w, "cache blocks:     {}", stats.nr_cache_blocks)?;
+    writeln!(
+        w,
+        "resident:         {} ({:.2}%)",
+        stats.nr_resident,
+        percent(stats.nr_resident, stats.nr_cache_blocks as u64)
+    )?;
+    writeln!(
+        w,
+        "dirty:            {} ({:.2}% of resident)",
+        stats.nr_dirty,
+        percent(stats.nr_dirty, stats.nr_resident)
+    )?;
+    writeln!(
+        w,
+        "policy:           {} {}",
+        stats.policy,
+        version.join(".")
+    )?;
+    writeln!(w, "hint width:       {}", stats.hint_width)?;
+    match stats.nr_origin_blocks {
+        Some(n) => writeln!(w, "origin blocks:    {}", n)?,
+        None => writeln!(w, "origin blocks:    unknown")?,
+    }
+
+    if stats.histogram.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(w)?;
+    writeln!(w, "{:<24} resident", "origin blocks")?;
+    for b in &stats.histogram {
+        writeln!(
+            w,
+            "{:<24} {}",
+            format!("{}..{}", b.begin, b.end),
+            b.nr_resident
+        )?;
+    }
+
+    Ok(())
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let h = build_histogram(&[0, 1, 5, 9, 9], 10, 3);
+        let counts: Vec<(u64, u64, u64)> =
+            h.iter().map(|b| (b.begin, b.end, b.nr_resident)).collect();
+        assert_eq!(counts, vec![(0, 4, 2), (4, 8, 1), (8, 10, 2)]);
+    }
+
+    #[test]
+    fn test_histogram_more_buckets_than_blocks() {
+        let h = build_histogram(&[0, 2], 3, 10);
+        let counts: Vec<u64> = h.iter().map(|b| b.nr_resident).collect();
+        assert_eq!(counts, vec![1, 0, 1]);
+    }
+
+    #[test]
+    fn test_histogram_empty() {
+        assert!(build_histogram(&[], 0, 10).is_empty());
+    }
+}
+
+//------------------------------------------
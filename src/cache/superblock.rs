@@ -2,6 +2,7 @@ use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, WriteBytesExt};
 use nom::{bytes::complete::*, number::complete::*, IResult};
 use std::io::Cursor;
+use tracing::instrument;
 
 use crate::checksum::*;
 use crate::io_engine::*;
@@ -11,7 +12,7 @@ use crate::io_engine::*;
 pub const SPACE_MAP_ROOT_SIZE: usize = 128;
 pub const SUPERBLOCK_LOCATION: u64 = 0;
 
-const MAGIC: u64 = 0o6142003; // 0x18c403 in hex
+pub const MAGIC: u64 = 0o6142003; // 0x18c403 in hex
 const POLICY_NAME_SIZE: usize = 16;
 const UUID_SIZE: usize = 16;
 
@@ -131,6 +132,7 @@ fn unpack(data: &[u8]) -> IResult<&[u8], Superblock> {
     ))
 }
 
+#[instrument(level = "debug", skip(engine))]
 pub fn read_superblock(engine: &dyn IoEngine, loc: u64) -> Result<Superblock> {
     let b = engine.read(loc)?;
 
@@ -204,6 +206,7 @@ fn pack_superblock<W: WriteBytesExt>(sb: &Superblock, w: &mut W) -> Result<()> {
     Ok(())
 }
 
+#[instrument(level = "debug", skip(engine, sb))]
 pub fn write_superblock(engine: &dyn IoEngine, _loc: u64, sb: &Superblock) -> Result<()> {
     let b = Block::zeroed(SUPERBLOCK_LOCATION);
 
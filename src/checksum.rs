@@ -12,11 +12,122 @@ const BITMAP_CSUM_XOR: u32 = 240779;
 const INDEX_CSUM_XOR: u32 = 160478;
 const BTREE_CSUM_XOR: u32 = 121107;
 const ARRAY_CSUM_XOR: u32 = 595846735;
+const DEVICE_LABELS_CSUM_XOR: u32 = 271214;
+
+//------------------------------------------
+
+// The crc32 instructions have a latency of several cycles, but can issue
+// every cycle, so checksumming a few blocks at once, interleaved, keeps
+// the pipeline full.
+const INTERLEAVE: usize = 4;
+
+#[cfg(target_arch = "x86_64")]
+mod hw {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+    use std::convert::TryInto;
+
+    pub fn available() -> bool {
+        is_x86_feature_detected!("sse4.2")
+    }
+
+    /// The buffers must all be the same length.
+    #[target_feature(enable = "sse4.2")]
+    pub unsafe fn crc32c<const N: usize>(bufs: [&[u8]; N]) -> [u32; N] {
+        let len = bufs[0].len();
+        let mut crcs = [!0u64; N];
+
+        let words = len / 8;
+        for w in 0..words {
+            for i in 0..N {
+                let v = u64::from_le_bytes(bufs[i][w * 8..w * 8 + 8].try_into().unwrap());
+                crcs[i] = _mm_crc32_u64(crcs[i], v);
+            }
+        }
+
+        let mut result = [0; N];
+        for i in 0..N {
+            let mut crc = crcs[i] as u32;
+            for b in &bufs[i][words * 8..len] {
+                crc = _mm_crc32_u8(crc, *b);
+            }
+            result[i] = !crc;
+        }
+        result
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod hw {
+    use std::arch::aarch64::{__crc32cb, __crc32cd};
+    use std::convert::TryInto;
+
+    pub fn available() -> bool {
+        std::arch::is_aarch64_feature_detected!("crc")
+    }
+
+    /// The buffers must all be the same length.
+    #[target_feature(enable = "crc")]
+    pub unsafe fn crc32c<const N: usize>(bufs: [&[u8]; N]) -> [u32; N] {
+        let len = bufs[0].len();
+        let mut crcs = [!0u32; N];
+
+        let words = len / 8;
+        for w in 0..words {
+            for i in 0..N {
+                let v = u64::from_le_bytes(bufs[i][w * 8..w * 8 + 8].try_into().unwrap());
+                crcs[i] = __crc32cd(crcs[i], v);
+            }
+        }
+
+        let mut result = [0; N];
+        for i in 0..N {
+            let mut crc = crcs[i];
+            for b in &bufs[i][words * 8..len] {
+                crc = __crc32cb(crc, *b);
+            }
+            result[i] = !crc;
+        }
+        result
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod hw {
+    pub fn available() -> bool {
+        false
+    }
+
+    pub unsafe fn crc32c<const N: usize>(_bufs: [&[u8]; N]) -> [u32; N] {
+        unreachable!()
+    }
+}
+
+// 0 until the cpu has been probed, then 1 or 2.
+fn hw_available() -> bool {
+    use std::sync::atomic::{AtomicU8, Ordering};
+    static AVAILABLE: AtomicU8 = AtomicU8::new(0);
+
+    match AVAILABLE.load(Ordering::Relaxed) {
+        0 => {
+            let available = hw::available();
+            AVAILABLE.store(if available { 2 } else { 1 }, Ordering::Relaxed);
+            available
+        }
+        n => n == 2,
+    }
+}
 
 fn checksum(buf: &[u8]) -> u32 {
-    crc32c(&buf[4..]) ^ 0xffffffff
+    if hw_available() {
+        // Safe, the cpu supports the instructions.
+        unsafe { hw::crc32c([&buf[4..]])[0] ^ 0xffffffff }
+    } else {
+        crc32c(&buf[4..]) ^ 0xffffffff
+    }
 }
 
+//------------------------------------------
+
 #[derive(Debug, PartialEq)]
 #[allow(clippy::upper_case_acronyms)]
 #[allow(non_camel_case_types)]
@@ -28,6 +139,7 @@ pub enum BT {
     INDEX,
     BITMAP,
     ARRAY,
+    DEVICE_LABELS,
     UNKNOWN,
 }
 
@@ -36,10 +148,49 @@ pub fn metadata_block_type(buf: &[u8]) -> BT {
         return BT::UNKNOWN;
     }
 
+    block_type(buf, checksum(buf))
+}
+
+/// Equivalent to calling metadata_block_type() on each buffer, but
+/// faster when there are several blocks to verify.
+pub fn metadata_block_types(bufs: &[&[u8]]) -> Vec<BT> {
+    let mut types = Vec::with_capacity(bufs.len());
+
+    let mut chunks = bufs.chunks_exact(INTERLEAVE);
+    if hw_available() {
+        for chunk in &mut chunks {
+            if chunk.iter().any(|buf| buf.len() != BLOCK_SIZE as usize) {
+                types.extend(chunk.iter().map(|buf| metadata_block_type(buf)));
+                continue;
+            }
+
+            let mut data = [&chunk[0][4..]; INTERLEAVE];
+            for i in 1..INTERLEAVE {
+                data[i] = &chunk[i][4..];
+            }
+
+            // Safe, the cpu supports the instructions and the buffers
+            // are the same length.
+            let sums = unsafe { hw::crc32c(data) };
+            for (buf, sum) in chunk.iter().zip(sums.iter()) {
+                types.push(block_type(buf, sum ^ 0xffffffff));
+            }
+        }
+    }
+
+    let rest = if hw_available() {
+        chunks.remainder()
+    } else {
+        bufs
+    };
+    types.extend(rest.iter().map(|buf| metadata_block_type(buf)));
+    types
+}
+
+fn block_type(buf: &[u8], csum: u32) -> BT {
     // The checksum is always stored in the first u32 of the buffer.
     let mut rdr = Cursor::new(buf);
     let sum_on_disk = rdr.read_u32::<LittleEndian>().unwrap();
-    let csum = checksum(buf);
     let btype = csum ^ sum_on_disk;
 
     match btype {
@@ -50,6 +201,7 @@ pub fn metadata_block_type(buf: &[u8]) -> BT {
         BITMAP_CSUM_XOR => BT::BITMAP,
         INDEX_CSUM_XOR => BT::INDEX,
         ARRAY_CSUM_XOR => BT::ARRAY,
+        DEVICE_LABELS_CSUM_XOR => BT::DEVICE_LABELS,
         _ => BT::UNKNOWN,
     }
 }
@@ -68,6 +220,7 @@ pub fn write_checksum(buf: &mut [u8], kind: BT) -> Result<()> {
         BITMAP => BITMAP_CSUM_XOR,
         INDEX => INDEX_CSUM_XOR,
         ARRAY => ARRAY_CSUM_XOR,
+        DEVICE_LABELS => DEVICE_LABELS_CSUM_XOR,
         UNKNOWN => {
             return Err(anyhow!("Invalid block type"));
         }
@@ -78,3 +231,54 @@ pub fn write_checksum(buf: &mut [u8], kind: BT) -> Result<()> {
     out.write_u32::<LittleEndian>(csum)?;
     Ok(())
 }
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A few valid blocks of each type, followed by some garbage.
+    fn mk_blocks() -> Vec<Vec<u8>> {
+        let mut blocks = Vec::new();
+        for n in 0..11usize {
+            let mut b: Vec<u8> = (0..BLOCK_SIZE as usize)
+                .map(|i| (i * 31 + n * 7) as u8)
+                .collect();
+            let kind = match n {
+                0 => Some(BT::NODE),
+                1 => Some(BT::BITMAP),
+                2 => Some(BT::INDEX),
+                3 => Some(BT::THIN_SUPERBLOCK),
+                4 => Some(BT::ARRAY),
+                5 => Some(BT::DEVICE_LABELS),
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                write_checksum(&mut b, kind).unwrap();
+            }
+            blocks.push(b);
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_hw_matches_sw() {
+        let blocks = mk_blocks();
+        for b in &blocks {
+            assert_eq!(checksum(b), crc32c(&b[4..]) ^ 0xffffffff);
+        }
+    }
+
+    #[test]
+    fn test_batch() {
+        let blocks = mk_blocks();
+        let bufs: Vec<&[u8]> = blocks.iter().map(|b| &b[..]).collect();
+        let expected: Vec<BT> = bufs.iter().map(|b| metadata_block_type(b)).collect();
+        assert_eq!(expected[0], BT::NODE);
+        assert_eq!(expected[10], BT::UNKNOWN);
+        assert_eq!(metadata_block_types(&bufs), expected);
+    }
+}
+
+//------------------------------------------
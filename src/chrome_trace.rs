@@ -0,0 +1,184 @@
+use anyhow::Result;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+//------------------------------------------
+
+/// A tracing layer that writes every span, with its fields and duration,
+/// in the Chrome trace event format.  Load the file into chrome://tracing
+/// or ui.perfetto.dev.
+///
+/// Each span is written, and flushed, as it closes, so the trace is
+/// usable even if the tool exits without tidying up.  The format allows
+/// the closing ']' to be left off for just this reason.
+pub struct ChromeLayer {
+    out: Mutex<File>,
+    start: Instant,
+}
+
+// Kept in the extensions of each span until it closes.
+struct SpanTiming {
+    begin: Duration,
+    tid: u64,
+    args: json::JsonValue,
+}
+
+struct ArgVisitor<'a>(&'a mut json::JsonValue);
+
+impl<'a> Visit for ArgVisitor<'a> {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0[field.name()] = value.into();
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0[field.name()] = value.into();
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0[field.name()] = value.into();
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0[field.name()] = value.into();
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0[field.name()] = format!("{:?}", value).into();
+    }
+}
+
+fn micros(d: Duration) -> f64 {
+    d.as_secs_f64() * 1_000_000.0
+}
+
+fn thread_id() -> u64 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+}
+
+impl ChromeLayer {
+    pub fn new(path: &Path) -> Result<ChromeLayer> {
+        let mut out = File::create(path)?;
+        out.write_all(b"[\n")?;
+        Ok(ChromeLayer {
+            out: Mutex::new(out),
+            start: Instant::now(),
+        })
+    }
+}
+
+impl<S> Layer<S> for ChromeLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut args = json::JsonValue::new_object();
+            attrs.record(&mut ArgVisitor(&mut args));
+            span.extensions_mut().insert(SpanTiming {
+                begin: self.start.elapsed(),
+                tid: thread_id(),
+                args,
+            });
+        }
+    }
+
+    // Counts are often only known at the end of a phase, and recorded
+    // into fields that were left empty.
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<SpanTiming>() {
+                values.record(&mut ArgVisitor(&mut timing.args));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let extensions = span.extensions();
+        let timing = match extensions.get::<SpanTiming>() {
+            Some(timing) => timing,
+            None => return,
+        };
+
+        let event = json::object! {
+            name: span.name(),
+            cat: span.metadata().target(),
+            ph: "X",
+            ts: micros(timing.begin),
+            dur: micros(self.start.elapsed() - timing.begin),
+            pid: std::process::id(),
+            tid: timing.tid,
+            args: timing.args.clone(),
+        };
+
+        // Tracing can't report errors, and a trace missing its tail is
+        // still worth having.
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{},", event.dump());
+        let _ = out.flush();
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{info_span, subscriber};
+    use tracing_subscriber::prelude::*;
+
+    fn read_trace(path: &Path) -> json::JsonValue {
+        let text = std::fs::read_to_string(path).unwrap();
+        let text = format!("{}]", text.trim_end().trim_end_matches(','));
+        json::parse(&text).unwrap()
+    }
+
+    #[test]
+    fn test_spans_are_written_as_complete_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.json");
+        let layer = ChromeLayer::new(&path).unwrap();
+
+        subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            let outer = info_span!(
+                "restore",
+                nr_blocks = 1024u64,
+                nr_written = tracing::field::Empty
+            );
+            let _enter = outer.enter();
+            info_span!("inner").in_scope(|| {});
+            outer.record("nr_written", 17u64);
+        });
+
+        let trace = read_trace(&path);
+        assert_eq!(trace.len(), 2);
+
+        // Spans are written as they close, so inner ones come first.
+        assert_eq!(trace[0]["name"], "inner");
+        let outer = &trace[1];
+        assert_eq!(outer["name"], "restore");
+        assert_eq!(outer["ph"], "X");
+        assert_eq!(outer["pid"], std::process::id());
+        assert_eq!(outer["args"]["nr_blocks"], 1024);
+        assert_eq!(outer["args"]["nr_written"], 17);
+
+        let ts = |e: &json::JsonValue| e["ts"].as_f64().unwrap();
+        let dur = |e: &json::JsonValue| e["dur"].as_f64().unwrap();
+        assert!(ts(outer) <= ts(&trace[0]));
+        assert!(ts(&trace[0]) + dur(&trace[0]) <= ts(outer) + dur(outer));
+    }
+}
+
+//------------------------------------------
@@ -1,19 +1,20 @@
 extern crate clap;
 
-use atty::Stream;
 use clap::{App, Arg};
 use std::path::Path;
 use std::process;
-use std::sync::Arc;
 
-use crate::cache::check::{check, CacheCheckOptions};
+use crate::cache::check::{check, CacheCheckOptions, POLICY_CATEGORIES};
+use crate::cache::superblock::{read_superblock, SUPERBLOCK_LOCATION};
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
-use crate::report::*;
+use crate::io_engine::SyncIoEngine;
+use crate::policy::Action;
 
 //------------------------------------------
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("cache_check")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("cache_check")
         .version(crate::version::tools_version())
         // flags
         .arg(
@@ -22,21 +23,44 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
+        .arg(sync_io_arg())
         .arg(
             Arg::with_name("AUTO_REPAIR")
                 .help("Auto repair trivial issues.")
                 .long("auto-repair"),
         )
+        .arg(
+            Arg::with_name("ERROR_IF_NEEDS_CHECK")
+                .help("Fail if the needs_check flag is set, even if no damage is found")
+                .long("error-if-needs-check"),
+        )
         .arg(
             Arg::with_name("IGNORE_NON_FATAL")
                 .help("Only return a non-zero exit code if a fatal error is found.")
                 .long("ignore-non-fatal-errors"),
         )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        .arg(trace_output_arg())
+        .arg(journal_arg())
+        .arg(policy_arg())
         .arg(
-            Arg::with_name("QUIET")
-                .help("Suppress output messages, return only exit code.")
-                .short("q")
-                .long("quiet"),
+            Arg::with_name("ROLLBACK")
+                .help("Undo the repair recorded in this journal")
+                .long("rollback")
+                .value_name("JOURNAL")
+                .conflicts_with_all(&[
+                    "AUTO_REPAIR",
+                    "ERROR_IF_NEEDS_CHECK",
+                    "IGNORE_NON_FATAL",
+                    "POLICY",
+                    "SB_ONLY",
+                    "SKIP_DISCARDS",
+                    "SKIP_HINTS",
+                ]),
         )
         .arg(
             Arg::with_name("SB_ONLY")
@@ -59,37 +83,74 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .help("Specify the input device to check")
                 .required(true)
                 .index(1),
-        );
+        )
+}
 
-    let matches = parser.get_matches_from(args);
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
 
-    let report = if matches.is_present("QUIET") {
-        std::sync::Arc::new(mk_quiet_report())
-    } else if atty::is(Stream::Stdout) {
-        std::sync::Arc::new(mk_progress_bar_report())
-    } else {
-        Arc::new(mk_simple_report())
-    };
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
 
     check_input_file(input_file, &report);
     check_file_not_tiny(input_file, &report);
 
+    if let Some(journal) = matches.value_of("ROLLBACK") {
+        rollback_journal(input_file, Path::new(journal), &report);
+        return;
+    }
+
+    let mut policy = policy(&matches, POLICY_CATEGORIES, &report);
+    if matches.is_present("ERROR_IF_NEEDS_CHECK") {
+        policy.set("needs_check", Action::Fail).unwrap();
+    }
+
+    if matches.is_present("JOURNAL") && !policy.fixes_anything() {
+        report.fatal("--journal needs --auto-repair or a policy that fixes something");
+        process::exit(USAGE);
+    }
+
     let opts = CacheCheckOptions {
         dev: input_file,
-        async_io: matches.is_present("ASYNC_IO"),
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
         sb_only: matches.is_present("SB_ONLY"),
         skip_mappings: matches.is_present("SKIP_MAPPINGS"),
         skip_hints: matches.is_present("SKIP_HINTS"),
         skip_discards: matches.is_present("SKIP_DISCARDS"),
         ignore_non_fatal: matches.is_present("IGNORE_NON_FATAL"),
-        auto_repair: matches.is_present("AUTO_REPAIR"),
+        policy: policy.clone(),
+        journal: matches.value_of("JOURNAL").map(Path::new),
         report: report.clone(),
     };
 
     if let Err(reason) = check(opts) {
         report.fatal(&format!("{}", reason));
-        process::exit(1);
+        process::exit(check_failure(&reason));
+    }
+
+    if matches!(policy.action("needs_check"), Action::Warn | Action::Fail) {
+        let needs_check = SyncIoEngine::new(input_file, 1, false)
+            .map_err(anyhow::Error::from)
+            .and_then(|engine| read_superblock(&engine, SUPERBLOCK_LOCATION))
+            .map(|sb| sb.flags.needs_check);
+
+        match needs_check {
+            Ok(needs_check) => {
+                let msg = "The metadata is flagged as needing a check";
+                if let Err(e) = policy.weigh("needs_check", needs_check, msg, &report) {
+                    report.fatal(&format!("{}", e));
+                    process::exit(NEEDS_REPAIR);
+                }
+            }
+            Err(e) => {
+                report.fatal(&format!("{}", e));
+                process::exit(FATAL);
+            }
+        }
     }
 }
 
@@ -5,12 +5,13 @@ use std::path::Path;
 use std::process;
 
 use crate::cache::dump::{dump, CacheDumpOptions};
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
 
 //------------------------------------------
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("cache_dump")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("cache_dump")
         .version(crate::version::tools_version())
         .about("Dump the cache metadata to stdout in XML format")
         // flags
@@ -20,6 +21,13 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
+        .arg(sync_io_arg())
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        .arg(trace_output_arg())
         .arg(
             Arg::with_name("REPAIR")
                 .help("Repair the metadata whilst dumping it")
@@ -40,9 +48,13 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .help("Specify the input device to dump")
                 .required(true)
                 .index(1),
-        );
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
 
-    let matches = parser.get_matches_from(args);
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
     let output_file = if matches.is_present("OUTPUT") {
         Some(Path::new(matches.value_of("OUTPUT").unwrap()))
@@ -50,23 +62,22 @@ pub fn run(args: &[std::ffi::OsString]) {
         None
     };
 
-    // Create a temporary report just in case these checks
-    // need to report anything.
-    let report = std::sync::Arc::new(crate::report::mk_simple_report());
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
     check_input_file(input_file, &report);
     check_file_not_tiny(input_file, &report);
-    drop(report);
 
     let opts = CacheDumpOptions {
         input: input_file,
         output: output_file,
-        async_io: matches.is_present("ASYNC_IO"),
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
         repair: matches.is_present("REPAIR"),
     };
 
     if let Err(reason) = dump(opts) {
-        eprintln!("{}", reason);
-        process::exit(1);
+        report.fatal(&format!("{}", reason));
+        process::exit(FATAL);
     }
 }
 
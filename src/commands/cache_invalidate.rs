@@ -0,0 +1,113 @@
+extern crate clap;
+
+use clap::{App, Arg, ArgGroup};
+use std::path::Path;
+use std::process;
+
+use crate::cache::invalidate::{invalidate, parse_ranges, CacheInvalidateOptions};
+use crate::commands::exit_codes::*;
+use crate::commands::utils::*;
+
+//------------------------------------------
+
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("cache_invalidate")
+        .version(crate::version::tools_version())
+        .about("Remove selected mappings from cache metadata, writing the result to a different device or file")
+        // flags
+        .arg(
+            Arg::with_name("ASYNC_IO")
+                .help("Force use of io_uring for synchronous io")
+                .long("async-io")
+                .hidden(true),
+        )
+        .arg(sync_io_arg())
+        .arg(
+            Arg::with_name("FORCE")
+                .help("Drop dirty blocks too, losing any data not yet written back")
+                .long("force"),
+        )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        // options
+        .arg(
+            Arg::with_name("CBLOCKS")
+                .help("Invalidate these cache blocks, eg. 1,5,10-20")
+                .long("cblocks")
+                .value_name("LIST"),
+        )
+        .arg(
+            Arg::with_name("INPUT")
+                .help("Specify the input device")
+                .short("i")
+                .long("input")
+                .value_name("FILE")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("OBLOCKS")
+                .help("Invalidate the cache blocks mapping these origin blocks")
+                .long("oblocks")
+                .value_name("LIST"),
+        )
+        .arg(
+            Arg::with_name("OUTPUT")
+                .help("Specify the output device")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .required(true),
+        )
+        .group(
+            ArgGroup::with_name("selection")
+                .args(&["CBLOCKS", "OBLOCKS"])
+                .multiple(true)
+                .required(true),
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
+    let input_file = Path::new(matches.value_of("INPUT").unwrap());
+    let output_file = Path::new(matches.value_of("OUTPUT").unwrap());
+
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
+
+    let ranges = |name| {
+        matches.value_of(name).map_or_else(Vec::new, |s| {
+            parse_ranges(s).unwrap_or_else(|e| {
+                report.fatal(&format!("{}", e));
+                process::exit(USAGE);
+            })
+        })
+    };
+    let cblocks = ranges("CBLOCKS");
+    let oblocks = ranges("OBLOCKS");
+
+    check_input_file(input_file, &report);
+    check_file_not_tiny(input_file, &report);
+
+    let opts = CacheInvalidateOptions {
+        input: input_file,
+        output: output_file,
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
+        cblocks,
+        oblocks,
+        force: matches.is_present("FORCE"),
+        report: report.clone(),
+    };
+
+    if let Err(reason) = invalidate(opts) {
+        report.fatal(&format!("{}", reason));
+        process::exit(FATAL);
+    }
+}
+
+//------------------------------------------
@@ -1,20 +1,18 @@
 extern crate clap;
 
-use clap::{value_t_or_exit, App, Arg, ArgGroup};
+use clap::{value_t, App, Arg, ArgGroup};
 use std::ffi::OsString;
 use std::process;
 
 use crate::cache::metadata_size::{metadata_size, CacheMetadataSizeOptions};
+use crate::commands::exit_codes::*;
+use crate::commands::utils::{exit_usage, get_matches, sectors_or_exit};
 use crate::math::div_up;
 
 //------------------------------------------
 
-fn parse_args<I, T>(args: I) -> CacheMetadataSizeOptions
-where
-    I: IntoIterator<Item = T>,
-    T: Into<OsString> + Clone,
-{
-    let parser = App::new("cache_metadata_size")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("cache_metadata_size")
         .version(crate::version::tools_version())
         .about("Estimate the size of the metadata device needed for a given configuration.")
         .usage("cache_metadata_size [OPTIONS] <--device-size <SECTORS> --block-size <SECTORS> | --nr-blocks <NUM>>")
@@ -50,20 +48,34 @@ where
             ArgGroup::with_name("selection")
             .args(&["DEVICE_SIZE", "NR_BLOCKS"])
             .required(true)
-        );
+        )
+}
+
+fn parse_args<I, T>(args: I) -> CacheMetadataSizeOptions
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let parser = cli();
 
-    let matches = parser.get_matches_from(args);
+    let matches = get_matches(parser, args);
 
     let nr_blocks = matches.value_of("NR_BLOCKS").map_or_else(
         || {
-            let device_size = value_t_or_exit!(matches.value_of("DEVICE_SIZE"), u64);
-            let block_size = value_t_or_exit!(matches.value_of("BLOCK_SIZE"), u32);
-            div_up(device_size, block_size as u64)
+            let device_size =
+                sectors_or_exit(matches.value_of("DEVICE_SIZE").unwrap(), "device size");
+            let block_size = sectors_or_exit(matches.value_of("BLOCK_SIZE").unwrap(), "block size");
+            if block_size == 0 {
+                eprintln!("block size must be non-zero");
+                process::exit(USAGE);
+            }
+            div_up(device_size, block_size)
         },
-        |_| value_t_or_exit!(matches.value_of("NR_BLOCKS"), u64),
+        |_| value_t!(matches.value_of("NR_BLOCKS"), u64).unwrap_or_else(|e| exit_usage(e)),
     );
 
-    let max_hint_width = value_t_or_exit!(matches.value_of("MAX_HINT_WIDTH"), u32);
+    let max_hint_width =
+        value_t!(matches.value_of("MAX_HINT_WIDTH"), u32).unwrap_or_else(|e| exit_usage(e));
 
     CacheMetadataSizeOptions {
         nr_blocks,
@@ -80,7 +92,7 @@ pub fn run(args: &[std::ffi::OsString]) {
         }
         Err(reason) => {
             eprintln!("{}", reason);
-            process::exit(1);
+            process::exit(FATAL);
         }
     }
 }
@@ -1,17 +1,15 @@
 extern crate clap;
 
-use atty::Stream;
-use clap::{App, Arg};
+use clap::{value_t, App, Arg};
 use std::path::Path;
 use std::process;
-use std::sync::Arc;
 
 use crate::cache::repair::{repair, CacheRepairOptions};
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
-use crate::report::*;
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("cache_repair")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("cache_repair")
         .version(crate::version::tools_version())
         .about("Repair binary cache metadata, and write it to a different device or file")
         // flags
@@ -21,12 +19,12 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
-        .arg(
-            Arg::with_name("QUIET")
-                .help("Suppress output messages, return only exit code.")
-                .short("q")
-                .long("quiet"),
-        )
+        .arg(sync_io_arg())
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
         // options
         .arg(
             Arg::with_name("INPUT")
@@ -43,31 +41,40 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("output")
                 .value_name("FILE")
                 .required(true),
-        );
+        )
+        .arg(
+            Arg::with_name("METADATA_VERSION")
+                .help("Specify the output metadata version")
+                .long("metadata-version")
+                .value_name("NUM")
+                .possible_values(&["1", "2"])
+                .default_value("2"),
+        )
+}
 
-    let matches = parser.get_matches_from(args);
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
     let output_file = Path::new(matches.value_of("OUTPUT").unwrap());
 
-    let report = if matches.is_present("QUIET") {
-        std::sync::Arc::new(mk_quiet_report())
-    } else if atty::is(Stream::Stdout) {
-        std::sync::Arc::new(mk_progress_bar_report())
-    } else {
-        Arc::new(mk_simple_report())
-    };
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
 
     check_input_file(input_file, &report);
 
     let opts = CacheRepairOptions {
         input: input_file,
         output: output_file,
-        async_io: matches.is_present("ASYNC_IO"),
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
+        metadata_version: value_t!(matches.value_of("METADATA_VERSION"), u32).unwrap(),
         report: report.clone(),
     };
 
     if let Err(reason) = repair(opts) {
         report.fatal(&format!("{}", reason));
-        process::exit(1);
+        process::exit(FATAL);
     }
 }
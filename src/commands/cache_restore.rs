@@ -1,14 +1,15 @@
 extern crate clap;
 
-use clap::{App, Arg};
+use clap::{value_t, App, Arg};
 use std::path::Path;
 use std::process;
 
-use crate::cache::restore::{restore, CacheRestoreOptions};
+use crate::cache::restore::{restore, CacheRestoreOptions, OutputFormat};
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("cache_restore")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("cache_restore")
         .version(crate::version::tools_version())
         .about("Convert XML format metadata to binary.")
         // flags
@@ -18,12 +19,13 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
-        .arg(
-            Arg::with_name("QUIET")
-                .help("Suppress output messages, return only exit code.")
-                .short("q")
-                .long("quiet"),
-        )
+        .arg(sync_io_arg())
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        .arg(trace_output_arg())
         // options
         .arg(
             Arg::with_name("INPUT")
@@ -40,25 +42,63 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("output")
                 .value_name("FILE")
                 .required(true),
-        );
+        )
+        .arg(
+            Arg::with_name("METADATA_VERSION")
+                .help("Specify the output metadata version")
+                .long("metadata-version")
+                .value_name("NUM")
+                .possible_values(&["1", "2"])
+                .default_value("2"),
+        )
+        .arg(
+            Arg::with_name("BLOCK_SIZE")
+                .help("Replace the data block size, in sectors")
+                .long("override-block-size")
+                .value_name("SECTORS"),
+        )
+        .arg(
+            Arg::with_name("HINT_WIDTH")
+                .help("Convert the hints to this many bytes")
+                .long("hint-width")
+                .value_name("BYTES")
+                .possible_values(&["0", "4"]),
+        )
+}
 
-    let matches = parser.get_matches_from(args);
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
     let output_file = Path::new(matches.value_of("OUTPUT").unwrap());
 
-    let report = mk_report(matches.is_present("QUIET"));
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
     check_input_file(input_file, &report);
     check_output_file(output_file, &report);
 
+    let format = OutputFormat {
+        metadata_version: value_t!(matches.value_of("METADATA_VERSION"), u32).unwrap(),
+        data_block_size: matches.value_of("BLOCK_SIZE").map(|_| {
+            value_t!(matches.value_of("BLOCK_SIZE"), u32).unwrap_or_else(|e| exit_usage(e))
+        }),
+        hint_width: matches
+            .value_of("HINT_WIDTH")
+            .map(|_| value_t!(matches.value_of("HINT_WIDTH"), u32).unwrap()),
+    };
+
     let opts = CacheRestoreOptions {
         input: input_file,
         output: output_file,
-        async_io: matches.is_present("ASYNC_IO"),
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
+        format,
         report: report.clone(),
     };
 
     if let Err(reason) = restore(opts) {
         report.fatal(&format!("{}", reason));
-        process::exit(1);
+        process::exit(FATAL);
     }
 }
@@ -0,0 +1,73 @@
+extern crate clap;
+
+use clap::{value_t, App, Arg};
+use std::path::Path;
+use std::process;
+
+use crate::cache::stat::{stat, write_stats, CacheStatOptions};
+use crate::commands::exit_codes::*;
+use crate::commands::utils::*;
+
+//------------------------------------------
+
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("cache_stat")
+        .version(crate::version::tools_version())
+        .about("Summarise the cache metadata")
+        // flags
+        .arg(
+            Arg::with_name("ASYNC_IO")
+                .help("Force use of io_uring for synchronous io")
+                .long("async-io")
+                .hidden(true),
+        )
+        .arg(sync_io_arg())
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        // options
+        .arg(
+            Arg::with_name("BUCKETS")
+                .help("Split the origin into this many parts for the histogram")
+                .long("buckets")
+                .value_name("NUM")
+                .default_value("10"),
+        )
+        // arguments
+        .arg(
+            Arg::with_name("INPUT")
+                .help("Specify the input device")
+                .required(true)
+                .index(1),
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
+    let input_file = Path::new(matches.value_of("INPUT").unwrap());
+    let nr_buckets = value_t!(matches.value_of("BUCKETS"), u64).unwrap_or_else(|e| exit_usage(e));
+
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
+    check_input_file(input_file, &report);
+    check_file_not_tiny(input_file, &report);
+
+    let opts = CacheStatOptions {
+        input: input_file,
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
+        nr_buckets,
+    };
+
+    let r = stat(opts).and_then(|stats| write_stats(&mut std::io::stdout(), &stats));
+    if let Err(reason) = r {
+        report.fatal(&format!("{}", reason));
+        process::exit(FATAL);
+    }
+}
+
+//------------------------------------------
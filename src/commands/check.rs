@@ -0,0 +1,44 @@
+extern crate clap;
+
+use clap::App;
+use std::ffi::OsString;
+
+use crate::commands::front_end::{self, Tool};
+use crate::commands::*;
+use crate::metadata_type::MetadataType;
+
+//------------------------------------------
+
+const TOOLS: &[Tool] = &[
+    Tool {
+        metadata_type: MetadataType::Thin,
+        name: "thin_check",
+        run: thin_check::run,
+        cli: thin_check::cli,
+    },
+    Tool {
+        metadata_type: MetadataType::Cache,
+        name: "cache_check",
+        run: cache_check::run,
+        cli: cache_check::cli,
+    },
+    Tool {
+        metadata_type: MetadataType::Era,
+        name: "era_check",
+        run: era_check::run,
+        cli: era_check::cli,
+    },
+];
+
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    front_end::cli(
+        "check",
+        "Validates thin, cache or era metadata, whichever the device holds.",
+    )
+}
+
+pub fn run(args: &[OsString]) {
+    front_end::run(cli(), TOOLS, args)
+}
+
+//------------------------------------------
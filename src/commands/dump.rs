@@ -0,0 +1,44 @@
+extern crate clap;
+
+use clap::App;
+use std::ffi::OsString;
+
+use crate::commands::front_end::{self, Tool};
+use crate::commands::*;
+use crate::metadata_type::MetadataType;
+
+//------------------------------------------
+
+const TOOLS: &[Tool] = &[
+    Tool {
+        metadata_type: MetadataType::Thin,
+        name: "thin_dump",
+        run: thin_dump::run,
+        cli: thin_dump::cli,
+    },
+    Tool {
+        metadata_type: MetadataType::Cache,
+        name: "cache_dump",
+        run: cache_dump::run,
+        cli: cache_dump::cli,
+    },
+    Tool {
+        metadata_type: MetadataType::Era,
+        name: "era_dump",
+        run: era_dump::run,
+        cli: era_dump::cli,
+    },
+];
+
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    front_end::cli(
+        "dump",
+        "Dumps thin, cache or era metadata, whichever the device holds.",
+    )
+}
+
+pub fn run(args: &[OsString]) {
+    front_end::run(cli(), TOOLS, args)
+}
+
+//------------------------------------------
@@ -1,19 +1,17 @@
 extern crate clap;
 
-use atty::Stream;
 use clap::{App, Arg};
 use std::path::Path;
 use std::process;
-use std::sync::Arc;
 
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
 use crate::era::check::{check, EraCheckOptions};
-use crate::report::*;
 
 //------------------------------------------
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("era_check")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("era_check")
         .version(crate::version::tools_version())
         // flags
         .arg(
@@ -22,17 +20,18 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
+        .arg(sync_io_arg())
         .arg(
             Arg::with_name("IGNORE_NON_FATAL")
                 .help("Only return a non-zero exit code if a fatal error is found.")
                 .long("ignore-non-fatal-errors"),
         )
-        .arg(
-            Arg::with_name("QUIET")
-                .help("Suppress output messages, return only exit code.")
-                .short("q")
-                .long("quiet"),
-        )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        .arg(trace_output_arg())
         .arg(
             Arg::with_name("SB_ONLY")
                 .help("Only check the superblock.")
@@ -44,18 +43,17 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .help("Specify the input device to check")
                 .required(true)
                 .index(1),
-        );
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
 
-    let matches = parser.get_matches_from(args);
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
 
-    let report = if matches.is_present("QUIET") {
-        std::sync::Arc::new(mk_quiet_report())
-    } else if atty::is(Stream::Stdout) {
-        std::sync::Arc::new(mk_progress_bar_report())
-    } else {
-        Arc::new(mk_simple_report())
-    };
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
 
     check_input_file(input_file, &report);
     check_file_not_tiny(input_file, &report);
@@ -63,7 +61,8 @@ pub fn run(args: &[std::ffi::OsString]) {
 
     let opts = EraCheckOptions {
         dev: input_file,
-        async_io: matches.is_present("ASYNC_IO"),
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
         sb_only: matches.is_present("SB_ONLY"),
         ignore_non_fatal: matches.is_present("IGNORE_NON_FATAL"),
         report: report.clone(),
@@ -71,7 +70,7 @@ pub fn run(args: &[std::ffi::OsString]) {
 
     if let Err(reason) = check(&opts) {
         report.fatal(&format!("{}", reason));
-        process::exit(1);
+        process::exit(check_failure(&reason));
     }
 }
 
@@ -4,13 +4,14 @@ use clap::{App, Arg};
 use std::path::Path;
 use std::process;
 
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
-use crate::era::dump::{dump, EraDumpOptions};
+use crate::era::dump::{dump, EraDumpOptions, OutputFormat};
 
 //------------------------------------------
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("era_dump")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("era_dump")
         .version(crate::version::tools_version())
         .about("Dump the era metadata to stdout in XML format")
         // flags
@@ -20,11 +21,18 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
+        .arg(sync_io_arg())
         .arg(
             Arg::with_name("LOGICAL")
                 .help("Fold any unprocessed write sets into the final era array")
                 .long("logical"),
         )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        .arg(trace_output_arg())
         .arg(
             Arg::with_name("REPAIR")
                 .help("Repair the metadata whilst dumping it")
@@ -32,6 +40,17 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("repair"),
         )
         // options
+        .arg(
+            Arg::with_name("FORMAT")
+                .help("Write xml, or json ranges of blocks sharing an era")
+                .short("f")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["xml", "json"])
+                .hide_possible_values(true)
+                .default_value("xml")
+                .requires_if("json", "LOGICAL"),
+        )
         .arg(
             Arg::with_name("OUTPUT")
                 .help("Specify the output file rather than stdout")
@@ -45,9 +64,13 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .help("Specify the input device to dump")
                 .required(true)
                 .index(1),
-        );
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
 
-    let matches = parser.get_matches_from(args);
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
     let output_file = if matches.is_present("OUTPUT") {
         Some(Path::new(matches.value_of("OUTPUT").unwrap()))
@@ -55,24 +78,26 @@ pub fn run(args: &[std::ffi::OsString]) {
         None
     };
 
-    // Create a temporary report just in case these checks
-    // need to report anything.
-    let report = std::sync::Arc::new(crate::report::mk_simple_report());
+    let format: OutputFormat = matches.value_of("FORMAT").unwrap().parse().unwrap();
+
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
     check_input_file(input_file, &report);
     check_file_not_tiny(input_file, &report);
-    drop(report);
 
     let opts = EraDumpOptions {
         input: input_file,
         output: output_file,
-        async_io: matches.is_present("ASYNC_IO"),
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
         logical: matches.is_present("LOGICAL"),
         repair: matches.is_present("REPAIR"),
+        format,
     };
 
     if let Err(reason) = dump(opts) {
-        eprintln!("{}", reason);
-        process::exit(1);
+        report.fatal(&format!("{}", reason));
+        process::exit(FATAL);
     }
 }
 
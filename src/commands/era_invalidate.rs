@@ -4,13 +4,14 @@ use clap::{App, Arg};
 use std::path::Path;
 use std::process;
 
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
 use crate::era::invalidate::{invalidate, EraInvalidateOptions};
 
 //------------------------------------------
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("era_invalidate")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("era_invalidate")
         .version(crate::version::tools_version())
         .about("List blocks that may have changed since a given era")
         // flags
@@ -20,6 +21,12 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
+        .arg(sync_io_arg())
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
         // options
         .arg(
             Arg::with_name("OUTPUT")
@@ -41,9 +48,13 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("written-since")
                 .required(true)
                 .value_name("ERA"),
-        );
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
 
-    let matches = parser.get_matches_from(args);
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
     let output_file = if matches.is_present("OUTPUT") {
         Some(Path::new(matches.value_of("OUTPUT").unwrap()))
@@ -51,19 +62,17 @@ pub fn run(args: &[std::ffi::OsString]) {
         None
     };
 
-    // Create a temporary report just in case these checks
-    // need to report anything.
-    let report = std::sync::Arc::new(crate::report::mk_simple_report());
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
     check_input_file(input_file, &report);
     check_file_not_tiny(input_file, &report);
-    drop(report);
 
     let threshold = matches
         .value_of("WRITTEN_SINCE")
         .map(|s| {
             s.parse::<u32>().unwrap_or_else(|_| {
-                eprintln!("Couldn't parse written_since");
-                process::exit(1);
+                report.fatal("Couldn't parse written_since");
+                process::exit(USAGE);
             })
         })
         .unwrap_or(0);
@@ -71,14 +80,15 @@ pub fn run(args: &[std::ffi::OsString]) {
     let opts = EraInvalidateOptions {
         input: input_file,
         output: output_file,
-        async_io: matches.is_present("ASYNC_IO"),
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
         threshold,
         metadata_snap: matches.is_present("METADATA_SNAP"),
     };
 
     if let Err(reason) = invalidate(&opts) {
-        eprintln!("{}", reason);
-        process::exit(1);
+        report.fatal(&format!("{}", reason));
+        process::exit(FATAL);
     }
 }
 
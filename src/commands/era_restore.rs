@@ -4,11 +4,12 @@ use clap::{App, Arg};
 use std::path::Path;
 use std::process;
 
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
 use crate::era::restore::{restore, EraRestoreOptions};
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("era_restore")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("era_restore")
         .version(crate::version::tools_version())
         .about("Convert XML format metadata to binary.")
         // flags
@@ -18,12 +19,13 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
-        .arg(
-            Arg::with_name("QUIET")
-                .help("Suppress output messages, return only exit code.")
-                .short("q")
-                .long("quiet"),
-        )
+        .arg(sync_io_arg())
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        .arg(trace_output_arg())
         // options
         .arg(
             Arg::with_name("INPUT")
@@ -40,25 +42,31 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("output")
                 .value_name("FILE")
                 .required(true),
-        );
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
 
-    let matches = parser.get_matches_from(args);
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
     let output_file = Path::new(matches.value_of("OUTPUT").unwrap());
 
-    let report = mk_report(matches.is_present("QUIET"));
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
     check_input_file(input_file, &report);
     check_output_file(output_file, &report);
 
     let opts = EraRestoreOptions {
         input: input_file,
         output: output_file,
-        async_io: matches.is_present("ASYNC_IO"),
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
         report: report.clone(),
     };
 
     if let Err(reason) = restore(opts) {
         report.fatal(&format!("{}", reason));
-        process::exit(1);
+        process::exit(FATAL);
     }
 }
@@ -0,0 +1,33 @@
+//! The exit codes shared by all the rust tools.  Scripts, such as the
+//! lvm hooks, rely on these, so values must never be reused.
+//!
+//!   0  success
+//!   1  fatal error, eg, the input couldn't be opened or read
+//!   2  bad command line
+//!   3  the metadata is damaged, or flagged as needing a check, and
+//!      should be repaired
+//!   4  interrupted by a signal
+//!   5  ran out of time before the metadata could be fully checked
+//!   6  the metadata compared differ
+
+//------------------------------------------
+
+pub const SUCCESS: i32 = 0;
+pub const FATAL: i32 = 1;
+pub const USAGE: i32 = 2;
+pub const NEEDS_REPAIR: i32 = 3;
+pub const INTERRUPTED: i32 = 4;
+pub const TIMED_OUT: i32 = 5;
+pub const DIFFERENT: i32 = 6;
+
+/// Checkers report damaged metadata as NEEDS_REPAIR, but a failure to
+/// read the device at all is just FATAL.
+pub fn check_failure(e: &anyhow::Error) -> i32 {
+    if e.downcast_ref::<std::io::Error>().is_some() {
+        FATAL
+    } else {
+        NEEDS_REPAIR
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,165 @@
+extern crate clap;
+
+use clap::{App, Arg};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process;
+
+use crate::commands::exit_codes::*;
+use crate::commands::utils::*;
+use crate::io_engine::SyncIoEngine;
+use crate::metadata_type::{detect_metadata_type, MetadataType};
+use crate::report::mk_simple_report;
+
+//------------------------------------------
+
+/// The tool a front end runs for one kind of metadata.
+pub struct Tool {
+    pub metadata_type: MetadataType,
+    pub name: &'static str,
+    pub run: fn(&[OsString]),
+    pub cli: fn() -> App<'static, 'static>,
+}
+
+/// The front end's own arguments.  Everything but --type is passed on to
+/// the tool, which checks it.
+pub fn cli<'a, 'b>(name: &str, about: &'b str) -> App<'a, 'b> {
+    App::new(name)
+        .version(crate::version::tools_version())
+        .about(about)
+        .after_help("Any other options are passed on to the tool for the type of metadata.")
+        .arg(
+            Arg::with_name("TYPE")
+                .help("Use the tool for this type of metadata, rather than detecting it")
+                .long("type")
+                .value_name("TYPE")
+                .possible_values(&["thin", "cache", "era"]),
+        )
+        .arg(
+            Arg::with_name("INPUT")
+                .help("Specify the input device")
+                .required(true)
+                .index(1),
+        )
+}
+
+// Takes --type out of the arguments, leaving those for the tool.
+fn split_type(args: &[OsString]) -> (Option<OsString>, Vec<OsString>) {
+    let mut metadata_type = None;
+    let mut rest = Vec::with_capacity(args.len());
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let s = arg.to_string_lossy();
+        if s == "--type" {
+            metadata_type = it.next().cloned().or_else(|| Some(OsString::new()));
+        } else if let Some(t) = s.strip_prefix("--type=") {
+            metadata_type = Some(OsString::from(t));
+        } else {
+            rest.push(arg.clone());
+        }
+    }
+
+    (metadata_type, rest)
+}
+
+// The tools don't all take the same options, so the input is found by
+// parsing the arguments as each of them would.
+fn find_input(tools: &[Tool], args: &[OsString]) -> Option<PathBuf> {
+    tools.iter().find_map(|tool| {
+        (tool.cli)()
+            .get_matches_from_safe(args)
+            .ok()
+            .and_then(|m| m.value_of_os("INPUT").map(PathBuf::from))
+    })
+}
+
+/// Runs whichever of the tools handles the metadata on the input, or
+/// the one named by --type.
+pub fn run(app: App, tools: &[Tool], args: &[OsString]) {
+    let (metadata_type, rest) = split_type(args);
+
+    let metadata_type = match metadata_type {
+        Some(t) => {
+            // Let clap report a bad type.
+            let t = t.to_string_lossy().parse::<MetadataType>();
+            t.unwrap_or_else(|_| {
+                get_matches(app, args);
+                process::exit(USAGE);
+            })
+        }
+        None => {
+            // clap prints the version as it parses, so the front end
+            // answers for itself rather than trying the tools.
+            if rest
+                .iter()
+                .any(|a| a == "-h" || a == "--help" || a == "-V" || a == "--version")
+            {
+                get_matches(app, args);
+                process::exit(SUCCESS);
+            }
+
+            let input = find_input(tools, &rest).unwrap_or_else(|| {
+                // Shows the help, or why the arguments are wrong.
+                get_matches(app, args);
+                eprintln!("Couldn't find the input among the arguments.");
+                process::exit(USAGE);
+            });
+
+            check_input_file(&input, &mk_simple_report());
+            let detected = SyncIoEngine::new_with(&input, 1, false, false)
+                .map_err(anyhow::Error::from)
+                .and_then(|engine| detect_metadata_type(&engine));
+            match detected {
+                Ok(Some(t)) => t,
+                Ok(None) => {
+                    eprintln!(
+                        "Couldn't tell what type of metadata '{}' holds, use --type to say.",
+                        input.display()
+                    );
+                    process::exit(FATAL);
+                }
+                Err(e) => {
+                    eprintln!("Couldn't read '{}': {}", input.display(), e);
+                    process::exit(FATAL);
+                }
+            }
+        }
+    };
+
+    let tool = tools
+        .iter()
+        .find(|tool| tool.metadata_type == metadata_type)
+        .expect("no tool for the metadata type");
+
+    let mut tool_args = vec![OsString::from(tool.name)];
+    tool_args.extend(rest.into_iter().skip(1));
+    (tool.run)(&tool_args);
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_split_type() {
+        let (t, rest) = split_type(&os(&["check", "-q", "--type", "cache", "md"]));
+        assert_eq!(t, Some(OsString::from("cache")));
+        assert_eq!(rest, os(&["check", "-q", "md"]));
+
+        let (t, rest) = split_type(&os(&["dump", "--type=era", "md"]));
+        assert_eq!(t, Some(OsString::from("era")));
+        assert_eq!(rest, os(&["dump", "md"]));
+
+        let (t, _) = split_type(&os(&["check", "md"]));
+        assert_eq!(t, None);
+    }
+}
+
+//------------------------------------------
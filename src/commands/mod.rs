@@ -1,19 +1,30 @@
 pub mod cache_check;
 pub mod cache_dump;
+pub mod cache_invalidate;
 pub mod cache_metadata_size;
 pub mod cache_repair;
 pub mod cache_restore;
+pub mod cache_stat;
+pub mod check;
+pub mod dump;
 pub mod era_check;
 pub mod era_dump;
 pub mod era_invalidate;
 pub mod era_repair;
 pub mod era_restore;
+pub mod era_stat;
+pub mod exit_codes;
+pub mod front_end;
+pub mod thin_bench;
 pub mod thin_check;
 pub mod thin_dump;
+pub mod thin_metadata_diff;
 pub mod thin_metadata_pack;
 pub mod thin_metadata_size;
 pub mod thin_metadata_unpack;
+pub mod thin_metrics;
 pub mod thin_repair;
 pub mod thin_restore;
+pub mod thin_set_metadata_id;
 pub mod thin_shrink;
 pub mod utils;
@@ -0,0 +1,142 @@
+extern crate clap;
+
+use clap::{value_t, App, Arg};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+use crate::commands::exit_codes::*;
+use crate::commands::utils::*;
+use crate::thin::bench::{bench, ThinBenchOptions};
+
+//------------------------------------------
+
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_bench")
+        .version(crate::version::tools_version())
+        .about("Time restoring, checking and dumping generated thin metadata.")
+        // flags
+        .arg(
+            Arg::with_name("ASYNC_IO")
+                .help("Use io_uring rather than synchronous io")
+                .long("async-io"),
+        )
+        .arg(sync_io_arg().hidden(false))
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        // options
+        .arg(
+            Arg::with_name("DIR")
+                .help("Create the scratch files in this directory")
+                .long("dir")
+                .value_name("DIR"),
+        )
+        .arg(
+            Arg::with_name("NR_THINS")
+                .help("Number of thin devices to generate")
+                .long("nr-thins")
+                .value_name("NUM")
+                .default_value("4"),
+        )
+        .arg(
+            Arg::with_name("NR_MAPPINGS")
+                .help("Number of mappings in each thin device")
+                .long("nr-mappings")
+                .value_name("NUM")
+                .default_value("250000"),
+        )
+        .arg(
+            Arg::with_name("ITERATIONS")
+                .help("Number of times to run each operation")
+                .long("iterations")
+                .value_name("NUM")
+                .default_value("3"),
+        )
+        .arg(
+            Arg::with_name("IO_THREADS")
+                .help("Number of sync io threads, overriding the config file")
+                .long("io-threads")
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::with_name("SEED")
+                .help("Seed for the generated metadata")
+                .long("seed")
+                .value_name("NUM")
+                .default_value("0"),
+        )
+}
+
+fn fmt_secs(d: Duration) -> String {
+    format!("{:.3}s", d.as_secs_f64())
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
+    let dir = matches.value_of("DIR").map(Path::new);
+    let nr_thins = value_t!(matches.value_of("NR_THINS"), u32).unwrap_or_else(|e| exit_usage(e));
+    let nr_mappings =
+        value_t!(matches.value_of("NR_MAPPINGS"), u64).unwrap_or_else(|e| exit_usage(e));
+    let iterations =
+        value_t!(matches.value_of("ITERATIONS"), usize).unwrap_or_else(|e| exit_usage(e));
+    let seed = value_t!(matches.value_of("SEED"), u64).unwrap_or_else(|e| exit_usage(e));
+    if nr_thins == 0 || nr_mappings == 0 || iterations == 0 {
+        eprintln!("thins, mappings and iterations must be non-zero");
+        process::exit(USAGE);
+    }
+
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
+    let nr_io_threads = match matches.value_of("IO_THREADS") {
+        Some(_) => {
+            value_t!(matches.value_of("IO_THREADS"), usize).unwrap_or_else(|e| exit_usage(e))
+        }
+        None => config.nr_io_threads(),
+    };
+    if nr_io_threads == 0 {
+        eprintln!("io threads must be non-zero");
+        process::exit(USAGE);
+    }
+    let async_io = async_io(&matches, &config);
+
+    let opts = ThinBenchOptions {
+        dir,
+        nr_thins,
+        nr_mappings,
+        iterations,
+        seed,
+        async_io,
+        nr_io_threads,
+        report: report.clone(),
+    };
+
+    match bench(opts) {
+        Ok(timings) => {
+            if async_io {
+                println!("io engine: async");
+            } else {
+                println!("io engine: sync, {} threads", nr_io_threads);
+            }
+            println!("{:<10}{:>10}{:>10}", "operation", "best", "mean");
+            for t in timings {
+                println!(
+                    "{:<10}{:>10}{:>10}",
+                    t.name,
+                    fmt_secs(t.best()),
+                    fmt_secs(t.mean())
+                );
+            }
+        }
+        Err(reason) => {
+            report.fatal(&format!("{}", reason));
+            process::exit(FATAL);
+        }
+    }
+}
+
+//------------------------------------------
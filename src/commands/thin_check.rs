@@ -1,16 +1,24 @@
 extern crate clap;
 
-use clap::{App, Arg};
+use clap::{value_t, App, Arg};
 use std::path::Path;
 use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
+use crate::file_utils;
 use crate::io_engine::*;
-use crate::thin::check::{check, ThinCheckOptions, MAX_CONCURRENT_IO};
+use crate::journal::JournalEngine;
+use crate::policy::Action;
+use crate::thin::check::{
+    check, CheckTimedOut, ThinCheckOptions, MAX_CONCURRENT_IO, POLICY_CATEGORIES,
+};
+use crate::thin::superblock::{read_superblock, SUPERBLOCK_LOCATION};
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("thin_check")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_check")
         .version(crate::version::tools_version())
         .about("Validates thin provisioning metadata on a device or file.")
         // flags
@@ -20,6 +28,12 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
+        .arg(sync_io_arg())
+        .arg(
+            Arg::with_name("AUDIT")
+                .help("List every check made, and what it found")
+                .long("audit"),
+        )
         .arg(
             Arg::with_name("AUTO_REPAIR")
                 .help("Auto repair trivial issues.")
@@ -45,6 +59,23 @@ pub fn run(args: &[std::ffi::OsString]) {
                     "SB_ONLY",
                 ]),
         )
+        .arg(
+            Arg::with_name("ERROR_IF_NEEDS_CHECK")
+                .help("Fail if the needs_check flag is set, even if no damage is found")
+                .long("error-if-needs-check"),
+        )
+        .arg(
+            Arg::with_name("FIX_OUT_OF_RANGE")
+                .help("Drop mappings that point past the end of the pool")
+                .long("fix-out-of-range")
+                .conflicts_with_all(&[
+                    "IGNORE_NON_FATAL",
+                    "METADATA_SNAPSHOT",
+                    "OVERRIDE_MAPPING_ROOT",
+                    "SB_ONLY",
+                    "SKIP_MAPPINGS",
+                ]),
+        )
         .arg(
             Arg::with_name("IGNORE_NON_FATAL")
                 .help("Only return a non-zero exit code if a fatal error is found.")
@@ -56,12 +87,12 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .short("m")
                 .long("metadata-snapshot"),
         )
-        .arg(
-            Arg::with_name("QUIET")
-                .help("Suppress output messages, return only exit code.")
-                .short("q")
-                .long("quiet"),
-        )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        .arg(trace_output_arg())
         .arg(
             Arg::with_name("SB_ONLY")
                 .help("Only check the superblock.")
@@ -73,6 +104,13 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("skip-mappings"),
         )
         // options
+        .arg(
+            Arg::with_name("DATA_DEVICE")
+                .help("Check the pool fits on this data device")
+                .long("data-device")
+                .value_name("DEV"),
+        )
+        .arg(journal_arg())
         .arg(
             Arg::with_name("OVERRIDE_MAPPING_ROOT")
                 .help("Specify a mapping root to use")
@@ -80,49 +118,170 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .value_name("OVERRIDE_MAPPING_ROOT")
                 .takes_value(true),
         )
+        .arg(policy_arg())
+        .arg(
+            Arg::with_name("ROLLBACK")
+                .help("Undo the repair recorded in this journal")
+                .long("rollback")
+                .value_name("JOURNAL")
+                .conflicts_with_all(&[
+                    "AUDIT",
+                    "AUTO_REPAIR",
+                    "CLEAR_NEEDS_CHECK",
+                    "DATA_DEVICE",
+                    "ERROR_IF_NEEDS_CHECK",
+                    "FIX_OUT_OF_RANGE",
+                    "IGNORE_NON_FATAL",
+                    "JOURNAL",
+                    "METADATA_SNAPSHOT",
+                    "OVERRIDE_MAPPING_ROOT",
+                    "POLICY",
+                    "SB_ONLY",
+                    "SKIP_MAPPINGS",
+                    "TIMEOUT",
+                ]),
+        )
+        .arg(
+            Arg::with_name("TIMEOUT")
+                .help("Stop, changing nothing, after this many seconds")
+                .long("timeout")
+                .value_name("SECS"),
+        )
         // arguments
         .arg(
             Arg::with_name("INPUT")
                 .help("Specify the input device to check")
                 .required(true)
                 .index(1),
-        );
+        )
+}
 
-    let matches = parser.get_matches_from(args.iter());
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
 
-    let report = mk_report(matches.is_present("QUIET"));
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
     check_input_file(input_file, &report);
     check_file_not_tiny(input_file, &report);
     check_not_xml(input_file, &report);
 
+    if let Some(journal) = matches.value_of("ROLLBACK") {
+        rollback_journal(input_file, Path::new(journal), &report);
+        return;
+    }
+
+    let data_device_size = matches.value_of("DATA_DEVICE").map(|dev| {
+        let dev = Path::new(dev);
+        check_input_file(dev, &report);
+        file_utils::file_size(dev).unwrap_or_else(|e| {
+            report.fatal(&format!("couldn't get the size of the data device: {}", e));
+            process::exit(FATAL);
+        })
+    });
+
+    let timeout = matches.value_of("TIMEOUT").map(|_| {
+        let secs = value_t!(matches.value_of("TIMEOUT"), f64).unwrap_or_else(|e| exit_usage(e));
+        if !secs.is_finite() || secs <= 0.0 {
+            report.fatal("the timeout must be a positive number of seconds");
+            process::exit(USAGE);
+        }
+        Duration::from_secs_f64(secs)
+    });
+
+    let mut policy = policy(&matches, POLICY_CATEGORIES, &report);
+    if matches.is_present("FIX_OUT_OF_RANGE") {
+        policy.set("out_of_range", Action::Fix).unwrap();
+    }
+    if matches.is_present("CLEAR_NEEDS_CHECK") {
+        policy.set("needs_check", Action::Fix).unwrap();
+    } else if matches.is_present("ERROR_IF_NEEDS_CHECK")
+        && policy.action("needs_check") != Action::Fix
+    {
+        policy.set("needs_check", Action::Fail).unwrap();
+    }
+
     let engine: Arc<dyn IoEngine + Send + Sync>;
-    let writable = matches.is_present("AUTO_REPAIR") || matches.is_present("CLEAR_NEEDS_CHECK");
+    let writable = policy.fixes_anything();
+    if writable
+        && (matches.is_present("METADATA_SNAPSHOT") || matches.is_present("OVERRIDE_MAPPING_ROOT"))
+    {
+        report.fatal("the policy can't fix anything in a metadata snapshot or overridden root");
+        process::exit(USAGE);
+    }
+    // Dropped mappings can only be got back from the journal.
+    if policy.action("out_of_range") == Action::Fix && !matches.is_present("JOURNAL") {
+        report.fatal("dropping out of range mappings needs --journal, so it can be rolled back");
+        process::exit(USAGE);
+    }
+    if matches.is_present("JOURNAL") && !writable {
+        report.fatal("--journal needs --auto-repair, --clear-needs-check-flag, --fix-out-of-range or a policy that fixes something");
+        process::exit(USAGE);
+    }
 
-    if matches.is_present("ASYNC_IO") {
-        engine = Arc::new(
-            AsyncIoEngine::new(input_file, MAX_CONCURRENT_IO, writable)
-                .expect("unable to open input file"),
-        );
+    let result = if async_io(&matches, &config) {
+        AsyncIoEngine::new(input_file, MAX_CONCURRENT_IO, writable)
+            .map(|e| Arc::new(e) as Arc<dyn IoEngine + Send + Sync>)
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
-        engine = Arc::new(
-            SyncIoEngine::new(input_file, nr_threads, writable).expect("unable to open input file"),
-        );
+        SyncIoEngine::new(input_file, config.nr_io_threads(), writable)
+            .map(|e| Arc::new(e) as Arc<dyn IoEngine + Send + Sync>)
+    };
+
+    match result {
+        Ok(e) => engine = e,
+        Err(e) => {
+            report.fatal(&format!("unable to open input file: {}", e));
+            process::exit(FATAL);
+        }
     }
 
+    let engine = match matches.value_of("JOURNAL") {
+        Some(journal) => match JournalEngine::new(engine, Path::new(journal)) {
+            Ok(e) => Arc::new(e),
+            Err(e) => {
+                report.fatal(&format!("unable to create the journal: {}", e));
+                process::exit(FATAL);
+            }
+        },
+        None => engine,
+    };
+
     let opts = ThinCheckOptions {
-        engine,
+        engine: engine.clone(),
         sb_only: matches.is_present("SB_ONLY"),
         skip_mappings: matches.is_present("SKIP_MAPPINGS"),
         ignore_non_fatal: matches.is_present("IGNORE_NON_FATAL"),
-        auto_repair: matches.is_present("AUTO_REPAIR"),
-        clear_needs_check: matches.is_present("CLEAR_NEEDS_CHECK"),
+        policy: policy.clone(),
+        data_device_size,
+        timeout,
+        audit: matches.is_present("AUDIT"),
         report: report.clone(),
     };
 
     if let Err(reason) = check(opts) {
         report.fatal(&format!("{}", reason));
-        process::exit(1);
+        if reason.downcast_ref::<CheckTimedOut>().is_some() {
+            process::exit(TIMED_OUT);
+        }
+        process::exit(check_failure(&reason));
+    }
+
+    // Checked after any --clear-needs-check-flag has had its effect.
+    if matches!(policy.action("needs_check"), Action::Warn | Action::Fail) {
+        match read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION) {
+            Ok(sb) => {
+                let msg = "The metadata is flagged as needing a check";
+                if let Err(e) = policy.weigh("needs_check", sb.flags.needs_check, msg, &report) {
+                    report.fatal(&format!("{}", e));
+                    process::exit(NEEDS_REPAIR);
+                }
+            }
+            Err(e) => {
+                report.fatal(&format!("{}", e));
+                process::exit(FATAL);
+            }
+        }
     }
 }
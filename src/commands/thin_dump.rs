@@ -1,18 +1,16 @@
 extern crate clap;
 
-use atty::Stream;
-use clap::{App, Arg};
+use clap::{value_t, App, Arg};
 use std::path::Path;
 use std::process;
-use std::sync::Arc;
 
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
-use crate::report::*;
-use crate::thin::dump::{dump, ThinDumpOptions};
+use crate::thin::dump::{dump, OutputFormat, ThinDumpOptions};
 use crate::thin::metadata_repair::SuperblockOverrides;
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("thin_dump")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_dump")
         .version(crate::version::tools_version())
         .about("Dump thin-provisioning metadata to stdout in XML format")
         // flags
@@ -22,12 +20,29 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
+        .arg(sync_io_arg())
+        .arg(
+            Arg::with_name("ANNOTATE_SHARED")
+                .help("Mark each mapping with whether its data blocks are shared")
+                .long("annotate-shared"),
+        )
         .arg(
-            Arg::with_name("QUIET")
-                .help("Suppress output messages, return only exit code.")
-                .short("q")
-                .long("quiet"),
+            Arg::with_name("CANONICAL")
+                .help("Expand shared mappings so dumps of the same mappings are identical")
+                .long("canonical"),
         )
+        .arg(
+            Arg::with_name("NO_COALESCE")
+                .help("Write every mapping as a single mapping, rather than as ranges")
+                .long("no-coalesce")
+                .conflicts_with("MIN_RANGE"),
+        )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        .arg(trace_output_arg())
         .arg(
             Arg::with_name("REPAIR")
                 .help("Repair the metadata whilst dumping it")
@@ -46,6 +61,23 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("data-block-size")
                 .value_name("SECTORS"),
         )
+        .arg(
+            Arg::with_name("FORMAT")
+                .help("Write xml, or a human readable summary table")
+                .short("f")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["xml", "human"])
+                .hide_possible_values(true)
+                .default_value("xml"),
+        )
+        .arg(
+            Arg::with_name("INDEX")
+                .help("Reuse, or rebuild if stale, an index of the metadata layout")
+                .long("index")
+                .value_name("FILE")
+                .conflicts_with("REPAIR"),
+        )
         .arg(
             Arg::with_name("METADATA_SNAPSHOT")
                 .help("Access the metadata snapshot on a live pool")
@@ -53,6 +85,13 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("metadata-snapshot")
                 .value_name("METADATA_SNAPSHOT"),
         )
+        .arg(
+            Arg::with_name("MIN_RANGE")
+                .help("Shortest run of mappings to write as a range")
+                .long("min-range")
+                .value_name("NUM")
+                .default_value("2"),
+        )
         .arg(
             Arg::with_name("NR_DATA_BLOCKS")
                 .help("Override the number of data blocks if needed")
@@ -78,9 +117,13 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .help("Specify the input device to dump")
                 .required(true)
                 .index(1),
-        );
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
 
-    let matches = parser.get_matches_from(args);
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
     let output_file = if matches.is_present("OUTPUT") {
         Some(Path::new(matches.value_of("OUTPUT").unwrap()))
@@ -88,46 +131,46 @@ pub fn run(args: &[std::ffi::OsString]) {
         None
     };
 
-    let report = std::sync::Arc::new(mk_simple_report());
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
     check_input_file(input_file, &report);
 
     let transaction_id = matches.value_of("TRANSACTION_ID").map(|s| {
         s.parse::<u64>().unwrap_or_else(|_| {
-            eprintln!("Couldn't parse transaction_id");
-            process::exit(1);
-        })
-    });
-
-    let data_block_size = matches.value_of("DATA_BLOCK_SIZE").map(|s| {
-        s.parse::<u32>().unwrap_or_else(|_| {
-            eprintln!("Couldn't parse data_block_size");
-            process::exit(1);
+            report.fatal("Couldn't parse transaction_id");
+            process::exit(USAGE);
         })
     });
 
-    let nr_data_blocks = matches.value_of("NR_DATA_BLOCKS").map(|s| {
-        s.parse::<u64>().unwrap_or_else(|_| {
-            eprintln!("Couldn't parse nr_data_blocks");
-            process::exit(1);
-        })
-    });
+    let data_block_size = matches
+        .value_of("DATA_BLOCK_SIZE")
+        .map(|s| parse_block_size(s, &report));
 
-    let report;
+    let nr_data_blocks = matches
+        .value_of("NR_DATA_BLOCKS")
+        .map(|s| parse_nr_data_blocks(s, data_block_size, input_file, &report));
 
-    if matches.is_present("QUIET") {
-        report = std::sync::Arc::new(mk_quiet_report());
-    } else if atty::is(Stream::Stdout) {
-        report = std::sync::Arc::new(mk_progress_bar_report());
+    let min_range = if matches.is_present("NO_COALESCE") {
+        u64::MAX
     } else {
-        report = Arc::new(mk_simple_report());
-    }
+        value_t!(matches.value_of("MIN_RANGE"), u64).unwrap_or_else(|e| exit_usage(e))
+    };
+
+    let format: OutputFormat = matches.value_of("FORMAT").unwrap().parse().unwrap();
 
     let opts = ThinDumpOptions {
         input: input_file,
         output: output_file,
-        async_io: matches.is_present("ASYNC_IO"),
+        index: matches.value_of("INDEX").map(Path::new),
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
         report: report.clone(),
         repair: matches.is_present("REPAIR"),
+        canonical: matches.is_present("CANONICAL"),
+        skip_mappings: matches.is_present("SKIP_MAPPINGS"),
+        annotate_shared: matches.is_present("ANNOTATE_SHARED"),
+        min_range,
+        format,
         overrides: SuperblockOverrides {
             transaction_id,
             data_block_size,
@@ -137,6 +180,6 @@ pub fn run(args: &[std::ffi::OsString]) {
 
     if let Err(reason) = dump(opts) {
         report.fatal(&format!("{}", reason));
-        process::exit(1);
+        process::exit(FATAL);
     }
 }
@@ -0,0 +1,75 @@
+extern crate clap;
+
+use clap::{App, Arg};
+use std::path::Path;
+use std::process;
+
+use crate::commands::exit_codes::*;
+use crate::commands::utils::*;
+use crate::thin::metadata_diff::{metadata_diff, ThinMetadataDiffOptions};
+
+//------------------------------------------
+
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_metadata_diff")
+        .version(crate::version::tools_version())
+        .about("Compare two copies of thin metadata, and list everything that differs")
+        // flags
+        .arg(
+            Arg::with_name("ASYNC_IO")
+                .help("Force use of io_uring for synchronous io")
+                .long("async-io")
+                .hidden(true),
+        )
+        .arg(sync_io_arg())
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        // arguments
+        .arg(
+            Arg::with_name("LEFT")
+                .help("Specify the first metadata device, file or xml dump")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("RIGHT")
+                .help("Specify the second metadata device, file or xml dump")
+                .required(true)
+                .index(2),
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
+    let left = Path::new(matches.value_of("LEFT").unwrap());
+    let right = Path::new(matches.value_of("RIGHT").unwrap());
+
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
+    check_input_file(left, &report);
+    check_input_file(right, &report);
+
+    let opts = ThinMetadataDiffOptions {
+        left,
+        right,
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
+        report: report.clone(),
+    };
+
+    match metadata_diff(opts) {
+        Ok(0) => {}
+        Ok(_) => process::exit(DIFFERENT),
+        Err(reason) => {
+            report.fatal(&format!("{}", reason));
+            process::exit(FATAL);
+        }
+    }
+}
+
+//------------------------------------------
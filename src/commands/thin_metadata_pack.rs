@@ -4,11 +4,12 @@ use clap::{App, Arg};
 use std::path::Path;
 use std::process::exit;
 
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
-use crate::report::*;
+use crate::pack::toplevel::{pack_as, PackFormat};
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("thin_metadata_pack")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_metadata_pack")
 	.version(crate::version::tools_version())
         .about("Produces a compressed file of thin metadata.  Only packs metadata blocks that are actually used.")
         .arg(Arg::with_name("INPUT")
@@ -22,17 +23,34 @@ pub fn run(args: &[std::ffi::OsString]) {
             .required(true)
             .short("o")
             .value_name("FILE")
-            .takes_value(true));
+            .takes_value(true))
+        .arg(Arg::with_name("FORMAT")
+            .help("Specify the pack container to write")
+            .long("format")
+            .value_name("FORMAT")
+            .possible_values(&["native", "c-compat"])
+            .default_value("native"))
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
 
-    let matches = parser.get_matches_from(args);
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
     let output_file = Path::new(matches.value_of("OUTPUT").unwrap());
+    let format: PackFormat = matches.value_of("FORMAT").unwrap().parse().unwrap();
 
-    let report = std::sync::Arc::new(mk_simple_report());
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
     check_input_file(input_file, &report);
 
-    if let Err(reason) = crate::pack::toplevel::pack(input_file, output_file) {
+    if let Err(reason) = pack_as(input_file, output_file, format) {
         report.fatal(&format!("Application error: {}\n", reason));
-        exit(1);
+        exit(FATAL);
     }
 }
@@ -1,20 +1,18 @@
 extern crate clap;
 
-use clap::{value_t_or_exit, App, Arg};
+use clap::{value_t, App, Arg};
 use std::ffi::OsString;
 use std::process;
 
+use crate::commands::exit_codes::*;
+use crate::commands::utils::{exit_usage, get_matches, sectors_or_exit};
 use crate::thin::metadata_size::{metadata_size, ThinMetadataSizeOptions};
 use crate::units::*;
 
 //------------------------------------------
 
-fn parse_args<I, T>(args: I) -> (ThinMetadataSizeOptions, Units, bool)
-where
-    I: IntoIterator<Item = T>,
-    T: Into<OsString> + Clone,
-{
-    let parser = App::new("thin_metadata_size")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_metadata_size")
         .version(crate::version::tools_version())
         .about("Estimate the size of the metadata device needed for a given configuration.")
         // options
@@ -55,20 +53,31 @@ where
                 .help("Output numeric value only")
                 .short("n")
                 .long("numeric-only"),
-        );
+        )
+}
 
-    let matches = parser.get_matches_from(args);
+fn parse_args<I, T>(args: I) -> (ThinMetadataSizeOptions, Units, bool)
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
 
-    // TODO: handle unit suffix
-    let pool_size = value_t_or_exit!(matches.value_of("POOL_SIZE"), u64);
-    let block_size = value_t_or_exit!(matches.value_of("BLOCK_SIZE"), u32);
-    let max_thins = value_t_or_exit!(matches.value_of("MAX_THINS"), u64);
-    let unit = value_t_or_exit!(matches.value_of("UNIT"), Units);
+    let pool_size = sectors_or_exit(matches.value_of("POOL_SIZE").unwrap(), "pool size");
+    let block_size = sectors_or_exit(matches.value_of("BLOCK_SIZE").unwrap(), "block size");
+    if block_size == 0 {
+        eprintln!("block size must be non-zero");
+        process::exit(USAGE);
+    }
+    let max_thins = value_t!(matches.value_of("MAX_THINS"), u64).unwrap_or_else(|e| exit_usage(e));
+    let unit = value_t!(matches.value_of("UNIT"), Units).unwrap_or_else(|e| exit_usage(e));
     let numeric_only = matches.is_present("NUMERIC_ONLY");
 
     (
         ThinMetadataSizeOptions {
-            nr_blocks: pool_size / block_size as u64,
+            nr_blocks: pool_size / block_size,
             max_thins,
         },
         unit,
@@ -92,7 +101,7 @@ pub fn run(args: &[std::ffi::OsString]) {
         }
         Err(reason) => {
             eprintln!("{}", reason);
-            process::exit(1);
+            process::exit(FATAL);
         }
     }
 }
@@ -1,5 +1,7 @@
 extern crate clap;
 
+use crate::commands::exit_codes::*;
+use crate::commands::utils::*;
 use crate::file_utils;
 use clap::{App, Arg};
 use std::path::Path;
@@ -7,8 +9,8 @@ use std::process;
 
 use std::process::exit;
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("thin_metadata_unpack")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_metadata_unpack")
         .version(crate::version::tools_version())
         .about("Unpack a compressed file of thin metadata.")
         .arg(
@@ -26,19 +28,31 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .short("o")
                 .value_name("FILE")
                 .takes_value(true),
-        );
+        )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+}
 
-    let matches = parser.get_matches_from(args);
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
     let output_file = Path::new(matches.value_of("OUTPUT").unwrap());
 
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
+
     if !file_utils::is_file(input_file) {
-        eprintln!("Invalid input file '{}'.", input_file.display());
-        exit(1);
+        report.fatal(&format!("Invalid input file '{}'.", input_file.display()));
+        exit(FATAL);
     }
 
     if let Err(reason) = crate::pack::toplevel::unpack(input_file, output_file) {
-        eprintln!("Application error: {}", reason);
-        process::exit(1);
+        report.fatal(&format!("Application error: {}", reason));
+        process::exit(FATAL);
     }
 }
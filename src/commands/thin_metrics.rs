@@ -0,0 +1,103 @@
+extern crate clap;
+
+use clap::{value_t, App, Arg};
+use std::process;
+use std::time::Duration;
+
+use crate::commands::exit_codes::*;
+use crate::commands::utils::*;
+use crate::thin::metrics::{metrics, Pool, ThinMetricsOptions};
+
+//------------------------------------------
+
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_metrics")
+        .version(crate::version::tools_version())
+        .about("Export the usage and health of thin pools as Prometheus metrics")
+        // flags
+        .arg(
+            Arg::with_name("ASYNC_IO")
+                .help("Force use of io_uring for synchronous io")
+                .long("async-io")
+                .hidden(true),
+        )
+        .arg(sync_io_arg())
+        .arg(
+            Arg::with_name("METADATA_SNAPSHOT")
+                .help("Read the metadata snapshot of each live pool")
+                .short("m")
+                .long("metadata-snapshot"),
+        )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        // options
+        .arg(
+            Arg::with_name("INTERVAL")
+                .help("Seconds between refreshes of the served metrics")
+                .long("interval")
+                .value_name("SECS")
+                .default_value("60"),
+        )
+        .arg(
+            Arg::with_name("LISTEN")
+                .help("Serve the metrics over http, rather than printing them once")
+                .long("metrics-listen")
+                .value_name("ADDR"),
+        )
+        // arguments
+        .arg(
+            Arg::with_name("POOLS")
+                .help("Specify the metadata devices, each as [NAME=]DEVICE")
+                .required(true)
+                .multiple(true)
+                .index(1),
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
+    let interval = value_t!(matches.value_of("INTERVAL"), u64).unwrap_or_else(|e| exit_usage(e));
+    if interval == 0 {
+        eprintln!("interval must be non-zero");
+        process::exit(USAGE);
+    }
+
+    let mut pools = Vec::new();
+    for p in matches.values_of("POOLS").unwrap() {
+        match p.parse::<Pool>() {
+            Ok(pool) => pools.push(pool),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(USAGE);
+            }
+        }
+    }
+
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
+    for pool in &pools {
+        check_input_file(&pool.input, &report);
+    }
+
+    let opts = ThinMetricsOptions {
+        pools,
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
+        use_metadata_snap: matches.is_present("METADATA_SNAPSHOT"),
+        listen: matches.value_of("LISTEN").map(|s| s.to_string()),
+        interval: Duration::from_secs(interval),
+        report: report.clone(),
+    };
+
+    if let Err(reason) = metrics(opts) {
+        report.fatal(&format!("{}", reason));
+        process::exit(FATAL);
+    }
+}
+
+//------------------------------------------
@@ -4,12 +4,13 @@ use clap::{App, Arg};
 use std::path::Path;
 use std::process;
 
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
 use crate::thin::metadata_repair::SuperblockOverrides;
 use crate::thin::repair::{repair, ThinRepairOptions};
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("thin_repair")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_repair")
         .version(crate::version::tools_version())
         .about("Repair thin-provisioning metadata, and write it to different device or file")
         // flags
@@ -19,12 +20,23 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
+        .arg(sync_io_arg())
+        .arg(
+            Arg::with_name("BACKUP_SUPERBLOCK")
+                .help("Keep a backup copy of the superblock at the end of the metadata")
+                .long("backup-superblock"),
+        )
         .arg(
-            Arg::with_name("QUIET")
-                .help("Suppress output messages, return only exit code.")
-                .short("q")
-                .long("quiet"),
+            Arg::with_name("INTERACTIVE")
+                .help("Explain what the repair will do, and ask before each step that loses data")
+                .long("interactive")
+                .conflicts_with("QUIET"),
         )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
         // options
         .arg(
             Arg::with_name("DATA_BLOCK_SIZE")
@@ -59,51 +71,53 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .help("Override the transaction id if needed")
                 .long("transaction-id")
                 .value_name("NUM"),
-        );
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
 
-    let matches = parser.get_matches_from(args);
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
     let output_file = Path::new(matches.value_of("OUTPUT").unwrap());
 
-    let report = mk_report(matches.is_present("QUIET"));
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
     check_input_file(input_file, &report);
     check_output_file(output_file, &report);
 
     let transaction_id = matches.value_of("TRANSACTION_ID").map(|s| {
         s.parse::<u64>().unwrap_or_else(|_| {
             report.fatal("Couldn't parse transaction_id");
-            process::exit(1);
+            process::exit(USAGE);
         })
     });
 
-    let data_block_size = matches.value_of("DATA_BLOCK_SIZE").map(|s| {
-        s.parse::<u32>().unwrap_or_else(|_| {
-            report.fatal("Couldn't parse data_block_size");
-            process::exit(1);
-        })
-    });
+    let data_block_size = matches
+        .value_of("DATA_BLOCK_SIZE")
+        .map(|s| parse_block_size(s, &report));
 
-    let nr_data_blocks = matches.value_of("NR_DATA_BLOCKS").map(|s| {
-        s.parse::<u64>().unwrap_or_else(|_| {
-            report.fatal("Couldn't parse nr_data_blocks");
-            process::exit(1);
-        })
-    });
+    let nr_data_blocks = matches
+        .value_of("NR_DATA_BLOCKS")
+        .map(|s| parse_nr_data_blocks(s, data_block_size, input_file, &report));
 
     let opts = ThinRepairOptions {
         input: input_file,
         output: output_file,
-        async_io: matches.is_present("ASYNC_IO"),
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
         report: report.clone(),
         overrides: SuperblockOverrides {
             transaction_id,
             data_block_size,
             nr_data_blocks,
         },
+        backup_superblock: matches.is_present("BACKUP_SUPERBLOCK"),
+        interactive: matches.is_present("INTERACTIVE"),
     };
 
     if let Err(reason) = repair(opts) {
         report.fatal(&format!("{}", reason));
-        process::exit(1);
+        process::exit(FATAL);
     }
 }
@@ -4,11 +4,14 @@ use clap::{App, Arg};
 use std::path::Path;
 use std::process;
 
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
+use crate::thin::metadata_repair::SuperblockOverrides;
 use crate::thin::restore::{restore, ThinRestoreOptions};
+use crate::thin::validate::validate_dump;
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("thin_restore")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_restore")
         .version(crate::version::tools_version())
         .about("Convert XML format metadata to binary.")
         // flags
@@ -18,13 +21,43 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .long("async-io")
                 .hidden(true),
         )
+        .arg(sync_io_arg())
+        .arg(
+            Arg::with_name("BACKUP_SUPERBLOCK")
+                .help("Keep a backup copy of the superblock at the end of the metadata")
+                .long("backup-superblock"),
+        )
         .arg(
-            Arg::with_name("QUIET")
-                .help("Suppress output messages, return only exit code.")
-                .short("q")
-                .long("quiet"),
+            Arg::with_name("VALIDATE_ONLY")
+                .help("Check the input is consistent, without writing any metadata")
+                .long("validate-only")
+                .conflicts_with_all(&[
+                    "OUTPUT",
+                    "VERIFY",
+                    "BACKUP_SUPERBLOCK",
+                    "DATA_BLOCK_SIZE",
+                    "NR_DATA_BLOCKS",
+                    "TRANSACTION_ID",
+                ]),
         )
+        .arg(
+            Arg::with_name("VERIFY")
+                .help("Read back the restored metadata and compare it with the input")
+                .long("verify"),
+        )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        .arg(trace_output_arg())
         // options
+        .arg(
+            Arg::with_name("DATA_BLOCK_SIZE")
+                .help("Override the data block size in the xml")
+                .long("data-block-size")
+                .value_name("SECTORS"),
+        )
         .arg(
             Arg::with_name("INPUT")
                 .help("Specify the input xml")
@@ -33,32 +66,87 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .value_name("FILE")
                 .required(true),
         )
+        .arg(
+            Arg::with_name("NR_DATA_BLOCKS")
+                .help("Override the number of data blocks in the xml")
+                .long("nr-data-blocks")
+                .value_name("NUM"),
+        )
         .arg(
             Arg::with_name("OUTPUT")
                 .help("Specify the output device")
                 .short("o")
                 .long("output")
                 .value_name("FILE")
-                .required(true),
-        );
+                .required_unless("VALIDATE_ONLY"),
+        )
+        .arg(
+            Arg::with_name("TRANSACTION_ID")
+                .help("Override the transaction id in the xml")
+                .long("transaction-id")
+                .value_name("NUM"),
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
 
-    let matches = parser.get_matches_from(args);
+    let matches = get_matches(parser, args);
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
-    let output_file = Path::new(matches.value_of("OUTPUT").unwrap());
 
-    let report = mk_report(matches.is_present("QUIET"));
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
     check_input_file(input_file, &report);
+
+    if matches.is_present("VALIDATE_ONLY") {
+        match validate_dump(input_file, &report) {
+            Ok(summary) => report.info(&format!(
+                "The dump is consistent: {} devices, {} mappings",
+                summary.nr_devices, summary.nr_mappings
+            )),
+            Err(reason) => {
+                report.fatal(&format!("{}", reason));
+                process::exit(FATAL);
+            }
+        }
+        return;
+    }
+
+    let output_file = Path::new(matches.value_of("OUTPUT").unwrap());
     check_output_file(output_file, &report);
 
+    let transaction_id = matches.value_of("TRANSACTION_ID").map(|s| {
+        s.parse::<u64>().unwrap_or_else(|_| {
+            report.fatal("Couldn't parse transaction_id");
+            process::exit(USAGE);
+        })
+    });
+
+    let data_block_size = matches
+        .value_of("DATA_BLOCK_SIZE")
+        .map(|s| parse_block_size(s, &report));
+
+    let nr_data_blocks = matches
+        .value_of("NR_DATA_BLOCKS")
+        .map(|s| parse_xml_nr_data_blocks(s, data_block_size, input_file, &report));
+
     let opts = ThinRestoreOptions {
         input: input_file,
         output: output_file,
-        async_io: matches.is_present("ASYNC_IO"),
+        async_io: async_io(&matches, &config),
+        nr_io_threads: config.nr_io_threads(),
         report: report.clone(),
+        verify: matches.is_present("VERIFY"),
+        backup_superblock: matches.is_present("BACKUP_SUPERBLOCK"),
+        overrides: SuperblockOverrides {
+            transaction_id,
+            data_block_size,
+            nr_data_blocks,
+        },
     };
 
     if let Err(reason) = restore(opts) {
         report.fatal(&format!("{}", reason));
-        process::exit(1);
+        process::exit(FATAL);
     }
 }
@@ -0,0 +1,123 @@
+extern crate clap;
+
+use clap::{value_t, App, Arg, ArgGroup};
+use std::path::Path;
+use std::process;
+
+use crate::commands::exit_codes::*;
+use crate::commands::utils::*;
+use crate::thin::metadata_id::*;
+use crate::thin::superblock::parse_uuid;
+
+//------------------------------------------
+
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_set_metadata_id")
+        .version(crate::version::tools_version())
+        .about("Change the uuid or transaction id of thin metadata, eg, after cloning a pool")
+        // flags
+        .arg(
+            Arg::with_name("ASYNC_IO")
+                .help("Force use of io_uring for synchronous io")
+                .long("async-io")
+                .hidden(true),
+        )
+        .arg(sync_io_arg())
+        .arg(
+            Arg::with_name("BUMP_TRANSACTION_ID")
+                .help("Increment the transaction id")
+                .long("bump-transaction-id"),
+        )
+        .arg(
+            Arg::with_name("NEW_UUID")
+                .help("Generate a new, random, uuid")
+                .long("new-uuid"),
+        )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
+        // options
+        .arg(
+            Arg::with_name("TRANSACTION_ID")
+                .help("Set the transaction id")
+                .long("transaction-id")
+                .value_name("NUM"),
+        )
+        .arg(
+            Arg::with_name("UUID")
+                .help("Set the uuid, or clear it if given ''")
+                .long("uuid")
+                .value_name("UUID")
+                .empty_values(true),
+        )
+        // arguments
+        .arg(
+            Arg::with_name("INPUT")
+                .help("Specify the input device to change")
+                .required(true)
+                .index(1),
+        )
+        .group(ArgGroup::with_name("uuid").args(&["NEW_UUID", "UUID"]))
+        .group(
+            ArgGroup::with_name("transaction_id").args(&["BUMP_TRANSACTION_ID", "TRANSACTION_ID"]),
+        )
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
+
+    let matches = get_matches(parser, args);
+    let input_file = Path::new(matches.value_of("INPUT").unwrap());
+
+    let uuid = if matches.is_present("NEW_UUID") {
+        UuidChange::Generate
+    } else if let Some(s) = matches.value_of("UUID") {
+        match parse_uuid(s) {
+            Ok(uuid) => UuidChange::Set(uuid),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(USAGE);
+            }
+        }
+    } else {
+        UuidChange::Keep
+    };
+
+    let transaction_id = if matches.is_present("BUMP_TRANSACTION_ID") {
+        TransactionIdChange::Bump
+    } else if matches.is_present("TRANSACTION_ID") {
+        let tid =
+            value_t!(matches.value_of("TRANSACTION_ID"), u64).unwrap_or_else(|e| exit_usage(e));
+        TransactionIdChange::Set(tid)
+    } else {
+        TransactionIdChange::Keep
+    };
+
+    if let (UuidChange::Keep, TransactionIdChange::Keep) = (&uuid, &transaction_id) {
+        eprintln!("nothing to change, give a new uuid or transaction id");
+        process::exit(USAGE);
+    }
+
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
+    check_input_file(input_file, &report);
+    check_file_not_tiny(input_file, &report);
+    check_not_xml(input_file, &report);
+
+    let opts = ThinSetMetadataIdOptions {
+        input: input_file,
+        async_io: async_io(&matches, &config),
+        uuid,
+        transaction_id,
+        report: report.clone(),
+    };
+
+    if let Err(reason) = set_metadata_id(opts) {
+        report.fatal(&format!("{}", reason));
+        process::exit(FATAL);
+    }
+}
+
+//------------------------------------------
@@ -4,14 +4,22 @@
 
 extern crate clap;
 
-use clap::{App, Arg};
+use anyhow::Result;
+use clap::{value_t, App, Arg};
+use std::fs::File;
 use std::path::Path;
 use std::process::exit;
 
+use crate::commands::exit_codes::*;
 use crate::commands::utils::*;
+use crate::report::Report;
+use crate::shrink::policy::{mk_policy, POLICY_NAMES};
+use crate::shrink::toplevel::{plan, shrink, ThinShrinkOptions, ThinShrinkPlanOptions};
+use crate::thin::xml;
+use crate::units::BlockCount;
 
-pub fn run(args: &[std::ffi::OsString]) {
-    let parser = App::new("thin_shrink")
+pub fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("thin_shrink")
         .version(crate::version::tools_version())
         .about("Rewrite xml metadata and move data in an inactive pool.")
         .arg(
@@ -26,7 +34,7 @@ pub fn run(args: &[std::ffi::OsString]) {
         .arg(
             Arg::with_name("OUTPUT")
                 .help("Specify output xml file")
-                .required(true)
+                .required_unless("PLAN")
                 .short("o")
                 .long("output")
                 .value_name("FILE")
@@ -35,7 +43,7 @@ pub fn run(args: &[std::ffi::OsString]) {
         .arg(
             Arg::with_name("DATA")
                 .help("Specify pool data device where data will be moved")
-                .required(true)
+                .required_unless("PLAN")
                 .long("data")
                 .value_name("DATA")
                 .takes_value(true),
@@ -48,31 +56,159 @@ pub fn run(args: &[std::ffi::OsString]) {
                 .value_name("NOCOPY")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("VERIFY")
+                .help("Reread and compare the moved data before writing the new metadata")
+                .long("verify")
+                .conflicts_with("NOCOPY"),
+        )
+        .arg(
+            Arg::with_name("THROTTLE")
+                .help("Copy no faster than this many MB per second")
+                .long("throttle-mbps")
+                .value_name("MBPS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("POLICY")
+                .help("Choose where moved blocks go: first-fit, best-fit or near-owner")
+                .long("vacate-policy")
+                .value_name("POLICY")
+                .possible_values(&POLICY_NAMES)
+                .hide_possible_values(true)
+                .default_value("first-fit"),
+        )
+        .arg(
+            Arg::with_name("PLAN")
+                .help("Print stages that each move at most MAX blocks, then exit")
+                .long("plan")
+                .value_name("MAX")
+                .takes_value(true)
+                .conflicts_with_all(&["OUTPUT", "DATA", "NOCOPY", "VERIFY", "THROTTLE"]),
+        )
+        .arg(quiet_arg())
+        .arg(verbose_arg())
+        .arg(config_arg())
+        .arg(log_file_arg())
+        .arg(max_memory_arg())
         .arg(
             Arg::with_name("SIZE")
-                .help("Specify new size for the pool (in data blocks)")
+                .help(
+                    "Specify new size for the pool (in data blocks, or with a unit suffix, eg, 4T)",
+                )
                 .required(true)
                 .long("nr-blocks")
                 .value_name("SIZE")
                 .takes_value(true),
+        )
+}
+
+fn xml_data_block_size(input: &Path) -> Result<u32> {
+    let sb = xml::read_superblock(File::open(input)?)?;
+    Ok(sb.data_block_size)
+}
+
+// Sizes with a unit suffix are converted using the block size recorded
+// in the input xml.
+fn parse_new_size(s: &str, input: &Path, report: &Report) -> u64 {
+    let count = s.parse::<BlockCount>().unwrap_or_else(|e| {
+        report.fatal(&format!("Couldn't parse nr_blocks: {}", e));
+        exit(USAGE);
+    });
+
+    if !count.needs_block_size() {
+        return count.to_blocks(0).unwrap();
+    }
+
+    let block_size = xml_data_block_size(input).unwrap_or_else(|e| {
+        report.fatal(&format!("Couldn't read the data block size: {}", e));
+        exit(FATAL);
+    });
+    let nr_blocks = count.to_blocks(block_size as u64).unwrap_or_else(|e| {
+        report.fatal(&format!("Couldn't resolve nr_blocks: {}", e));
+        exit(FATAL);
+    });
+    report.info(&format!(
+        "new size: {} = {} blocks of {} sectors",
+        s, nr_blocks, block_size
+    ));
+    nr_blocks
+}
+
+fn print_plan(opts: ThinShrinkPlanOptions) -> Result<()> {
+    for (i, stage) in plan(opts)?.iter().enumerate() {
+        println!(
+            "stage {}: --nr-blocks {}, moving {} blocks",
+            i + 1,
+            stage.nr_blocks,
+            stage.nr_moved
         );
+    }
+    Ok(())
+}
+
+pub fn run(args: &[std::ffi::OsString]) {
+    let parser = cli();
 
-    let matches = parser.get_matches_from(args);
+    let matches = get_matches(parser, args);
 
     // FIXME: check these look like xml
     let input_file = Path::new(matches.value_of("INPUT").unwrap());
+
+    let config = config(&matches);
+    let report = mk_report(verbosity(&matches), &config);
+    check_input_file(input_file, &report);
+    let size = parse_new_size(matches.value_of("SIZE").unwrap(), input_file, &report);
+    let policy = mk_policy(matches.value_of("POLICY").unwrap()).unwrap();
+
+    if matches.is_present("PLAN") {
+        let max_moved = value_t!(matches.value_of("PLAN"), u64).unwrap_or_else(|e| exit_usage(e));
+        if max_moved == 0 {
+            report.fatal("stages must be allowed to move some blocks");
+            exit(USAGE);
+        }
+
+        let opts = ThinShrinkPlanOptions {
+            input: input_file,
+            nr_blocks: size,
+            max_moved,
+            policy,
+            report: report.clone(),
+        };
+
+        if let Err(reason) = print_plan(opts) {
+            report.fatal(&format!("Application error: {}\n", reason));
+            exit(FATAL);
+        }
+        return;
+    }
+
     let output_file = Path::new(matches.value_of("OUTPUT").unwrap());
-    let size = matches.value_of("SIZE").unwrap().parse::<u64>().unwrap();
     let data_file = Path::new(matches.value_of("DATA").unwrap());
     let do_copy = !matches.is_present("NOCOPY");
+    let throttle_mbps = matches.value_of("THROTTLE").map(|_| {
+        let mbps = value_t!(matches.value_of("THROTTLE"), u64).unwrap_or_else(|e| exit_usage(e));
+        if mbps == 0 {
+            report.fatal("throttle must be non-zero");
+            exit(USAGE);
+        }
+        mbps
+    });
 
-    let report = mk_report(false);
-    check_input_file(input_file, &report);
+    let opts = ThinShrinkOptions {
+        input: input_file,
+        output: output_file,
+        data_device: data_file,
+        nr_blocks: size,
+        do_copy,
+        verify: matches.is_present("VERIFY"),
+        throttle_mbps,
+        policy,
+        report: report.clone(),
+    };
 
-    if let Err(reason) =
-        crate::shrink::toplevel::shrink(input_file, output_file, data_file, size, do_copy)
-    {
-        eprintln!("Application error: {}\n", reason);
-        exit(1);
+    if let Err(reason) = shrink(opts) {
+        report.fatal(&format!("Application error: {}\n", reason));
+        exit(FATAL);
     }
 }
@@ -1,17 +1,36 @@
 use anyhow::Result;
 use atty::Stream;
-use std::fs::OpenOptions;
+use clap::{App, Arg, ArgMatches, ErrorKind};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
 use std::io::Read;
 use std::path::Path;
 use std::process::exit;
+use std::sync::Arc;
+use tracing::info;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
 
+use crate::chrome_trace::ChromeLayer;
+use crate::commands::exit_codes::*;
+use crate::config::*;
 use crate::file_utils;
+use crate::io_engine::SyncIoEngine;
+use crate::journal;
+use crate::log_file::LogFile;
+use crate::memory;
+use crate::policy::{Category, Policy};
 use crate::report::*;
+use crate::thin::superblock::{read_superblock, SUPERBLOCK_LOCATION};
+use crate::thin::xml;
+use crate::units::*;
 
 pub fn check_input_file(input_file: &Path, report: &Report) {
     if !file_utils::file_exists(input_file) {
         report.fatal(&format!("Couldn't find input file '{:?}'.", &input_file));
-        exit(1);
+        exit(FATAL);
     }
 
     if !file_utils::is_file_or_blk(input_file) {
@@ -19,14 +38,29 @@ pub fn check_input_file(input_file: &Path, report: &Report) {
             "Not a block device or regular file '{:?}'.",
             &input_file
         ));
-        exit(1);
+        exit(FATAL);
     }
 }
 
 pub fn check_file_not_tiny(input_file: &Path, report: &Report) {
     if file_utils::file_size(input_file).expect("couldn't get input size") < 4096 {
         report.fatal("Metadata device/file too small.  Is this binary metadata?");
-        exit(1);
+        exit(FATAL);
+    }
+}
+
+/// Undoes a repair by writing back the blocks saved in its journal.
+pub fn rollback_journal(input_file: &Path, journal: &Path, report: &Report) {
+    let result = SyncIoEngine::new(input_file, 1, true)
+        .map_err(anyhow::Error::from)
+        .and_then(|engine| journal::rollback(&engine, journal));
+
+    match result {
+        Ok(nr_blocks) => report.info(&format!("Restored {} blocks from the journal", nr_blocks)),
+        Err(e) => {
+            report.fatal(&format!("couldn't roll back the repair: {}", e));
+            exit(FATAL);
+        }
     }
 }
 
@@ -36,26 +70,405 @@ pub fn check_output_file(path: &Path, report: &Report) {
         Ok(size) => {
             if size < 40960 {
                 report.fatal("Output file too small.");
-                exit(1);
+                exit(FATAL);
             }
         }
         Err(e) => {
             report.fatal(&format!("{}", e));
-            exit(1);
+            exit(FATAL);
         }
     }
 }
 
-pub fn mk_report(quiet: bool) -> std::sync::Arc<Report> {
-    use std::sync::Arc;
+pub fn mk_report(verbosity: Verbosity, config: &Config) -> Arc<Report> {
+    let progress_bar = match config.report {
+        ReportFormat::Auto => atty::is(Stream::Stdout),
+        ReportFormat::ProgressBar => true,
+        ReportFormat::Simple => false,
+    };
 
-    if quiet {
-        Arc::new(mk_quiet_report())
-    } else if atty::is(Stream::Stdout) {
-        Arc::new(mk_progress_bar_report())
+    let mut report = if verbosity == Verbosity::Quiet {
+        mk_quiet_report()
+    } else if progress_bar {
+        mk_progress_bar_report()
     } else {
-        Arc::new(mk_simple_report())
+        mk_simple_report()
+    };
+    report.set_verbosity(verbosity);
+
+    Arc::new(report)
+}
+
+//---------------------------------------
+
+/// Parses the command line, and sets up tracing, which can't happen any
+/// earlier since --trace-output and --log-file are among the arguments.
+/// Unlike clap's get_matches_from() a bad command line exits with USAGE,
+/// rather than FATAL.
+pub fn get_matches<'a, I, T>(app: App<'a, '_>, args: I) -> ArgMatches<'a>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let args: Vec<std::ffi::OsString> = args.into_iter().map(Into::into).collect();
+    let matches = app
+        .get_matches_from_safe(&args)
+        .unwrap_or_else(|e| exit_usage(e));
+    init_tracing(
+        matches.value_of_os("TRACE_OUTPUT").map(Path::new),
+        matches.value_of_os("LOG_FILE").map(Path::new),
+    );
+
+    let args: Vec<_> = args.iter().map(|a| a.to_string_lossy()).collect();
+    info!(
+        "{} (version {})",
+        args.join(" "),
+        crate::version::tools_version()
+    );
+    matches
+}
+
+pub fn exit_usage(e: clap::Error) -> ! {
+    match e.kind {
+        // clap reports these as errors, but they exit successfully
+        ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => e.exit(),
+        _ => {
+            eprintln!("{}", e.message);
+            exit(USAGE);
+        }
+    }
+}
+
+extern "C" fn interrupted(_sig: libc::c_int) {
+    // Only async signal safe calls are allowed here.
+    unsafe { libc::_exit(INTERRUPTED) };
+}
+
+/// Makes SIGINT and SIGTERM exit with INTERRUPTED, so scripts can tell
+/// an aborted run from damaged metadata.
+pub fn install_signal_handlers() {
+    let handler = interrupted as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+//---------------------------------------
+
+/// Name of the environment variable holding the tracing filter,
+/// eg, THINP_LOG=thinp::thin=debug.
+pub const LOG_ENV: &str = "THINP_LOG";
+
+/// Sends tracing events, and the time spent in each span, to stderr if
+/// THINP_LOG is set, and the info level spans to a Chrome trace if the
+/// tool was given --trace-output.  Given --log-file, everything down to
+/// debug level, including every message the tool reports whatever its
+/// verbosity, is appended to the log.  Nothing is installed otherwise,
+/// so the spans cost next to nothing in normal use.
+pub fn init_tracing(trace_output: Option<&Path>, log_file: Option<&Path>) {
+    let log = std::env::var_os(LOG_ENV).is_some();
+    if !log && trace_output.is_none() && log_file.is_none() {
+        return;
     }
+
+    let fmt = if log {
+        let filter = match EnvFilter::try_from_env(LOG_ENV) {
+            Ok(filter) => filter,
+            Err(e) => {
+                eprintln!("invalid {}: {}", LOG_ENV, e);
+                exit(FATAL);
+            }
+        };
+
+        // The report's messages are already on the console.
+        let filter = filter.add_directive("thinp::report=off".parse().unwrap());
+        Some(
+            tracing_subscriber::fmt::layer()
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(std::io::stderr)
+                .with_ansi(atty::is(Stream::Stderr))
+                .with_filter(filter),
+        )
+    } else {
+        None
+    };
+
+    let chrome = trace_output.map(|path| match ChromeLayer::new(path) {
+        Ok(layer) => layer.with_filter(LevelFilter::INFO),
+        Err(e) => {
+            eprintln!("couldn't create trace file {:?}: {}", path, e);
+            exit(FATAL);
+        }
+    });
+
+    let log_file = log_file.map(|path| match LogFile::open(path) {
+        Ok(log) => tracing_subscriber::fmt::layer()
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(Arc::new(log))
+            .with_ansi(false)
+            .with_thread_ids(true)
+            .with_filter(LevelFilter::DEBUG),
+        Err(e) => {
+            eprintln!("couldn't open log file {:?}: {}", path, e);
+            exit(FATAL);
+        }
+    });
+
+    // Only the first tool in a process gets to install the subscriber.
+    let _ = tracing_subscriber::registry()
+        .with(fmt)
+        .with(chrome)
+        .with(log_file)
+        .try_init();
+}
+
+//---------------------------------------
+
+// Every tool accepts the same -q and -v flags, so they're defined once here.
+pub fn quiet_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("QUIET")
+        .help("Suppress output messages, return only exit code.")
+        .short("q")
+        .long("quiet")
+}
+
+pub fn verbose_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("VERBOSE")
+        .help("Increase the verbosity of output messages, may be repeated")
+        .short("v")
+        .long("verbose")
+        .multiple(true)
+        .conflicts_with("QUIET")
+}
+
+pub fn verbosity(matches: &ArgMatches) -> Verbosity {
+    Verbosity::from_flags(
+        matches.is_present("QUIET"),
+        matches.occurrences_of("VERBOSE"),
+    )
+}
+
+pub fn config_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("CONFIG")
+        .help("Read default options from this file instead of the system wide one")
+        .long("config")
+        .value_name("FILE")
+}
+
+// Hidden, like --async-io.
+pub fn sync_io_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("SYNC_IO")
+        .help("Use synchronous io, even if the config file selects io_uring")
+        .long("sync-io")
+        .conflicts_with("ASYNC_IO")
+        .hidden(true)
+}
+
+/// Whether to use the async io engine.  --async-io and --sync-io
+/// override the config file.
+pub fn async_io(matches: &ArgMatches, config: &Config) -> bool {
+    !matches.is_present("SYNC_IO") && (matches.is_present("ASYNC_IO") || config.async_io)
+}
+
+pub fn trace_output_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("TRACE_OUTPUT")
+        .help("Write a Chrome trace of the main phases to this file")
+        .long("trace-output")
+        .value_name("FILE")
+}
+
+pub fn journal_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("JOURNAL")
+        .help("Save overwritten blocks to this file, for --rollback")
+        .long("journal")
+        .value_name("FILE")
+}
+
+pub fn policy_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("POLICY")
+        .help("Read what to do about each kind of finding from this file")
+        .long("policy")
+        .value_name("FILE")
+}
+
+pub fn log_file_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("LOG_FILE")
+        .help("Append full diagnostics to this file")
+        .long("log-file")
+        .value_name("FILE")
+}
+
+pub fn max_memory_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("MAX_MEMORY")
+        .help("Limit memory use, in MiB unless a unit is given")
+        .long("max-memory")
+        .value_name("SIZE")
+}
+
+/// Reads any --policy file.  --ignore-non-fatal-errors and --auto-repair
+/// take precedence over it.
+pub fn policy(matches: &ArgMatches, categories: &'static [Category], report: &Report) -> Policy {
+    let mut policy = match matches.value_of("POLICY") {
+        Some(path) => match Policy::read(categories, Path::new(path)) {
+            Ok(policy) => policy,
+            Err(e) => {
+                report.fatal(&format!("{:#}", e));
+                exit(FATAL);
+            }
+        },
+        None => Policy::new(categories),
+    };
+
+    if matches.is_present("IGNORE_NON_FATAL") {
+        policy.fail_nothing();
+    }
+    if matches.is_present("AUTO_REPAIR") {
+        policy.fix_all();
+    }
+
+    policy
+}
+
+/// Loads the config file, exiting if a file given with --config is bad.
+/// Problems with the system wide file are only warned about.  This
+/// happens before the report exists, since the config may select its
+/// format.  The memory limit is applied here too, so it covers the whole
+/// run.
+pub fn config(matches: &ArgMatches) -> Config {
+    let mut config = match load_config(matches.value_of("CONFIG").map(Path::new)) {
+        Ok((config, warnings)) => {
+            for w in warnings {
+                eprintln!("warning: {}", w);
+            }
+            config
+        }
+        Err(e) => {
+            eprintln!("{:#}", e);
+            exit(FATAL);
+        }
+    };
+
+    if let Some(s) = matches.value_of("MAX_MEMORY") {
+        match parse_memory_size(s) {
+            Ok(bytes) => config.max_memory = Some(bytes),
+            Err(e) => {
+                eprintln!("Couldn't parse max memory: {}", e);
+                exit(USAGE);
+            }
+        }
+    }
+    memory::set_max_memory(config.max_memory);
+
+    config
+}
+
+//---------------------------------------
+
+/// Parses a size in sectors for the tools that don't have a report,
+/// noting the resolved value on stderr if a unit suffix was used.
+pub fn sectors_or_exit(s: &str, what: &str) -> u64 {
+    let sectors = parse_sectors(s).unwrap_or_else(|e| {
+        eprintln!("Couldn't parse {}: {}", what, e);
+        exit(USAGE);
+    });
+    if has_unit_suffix(s) {
+        eprintln!("{}: {} = {} sectors", what, s, sectors);
+    }
+    sectors
+}
+
+/// Parses a --data-block-size style argument, in sectors unless a unit
+/// suffix is given.
+pub fn parse_block_size(s: &str, report: &Report) -> u32 {
+    let sectors = parse_sectors(s).unwrap_or_else(|e| {
+        report.fatal(&format!("Couldn't parse data_block_size: {}", e));
+        exit(USAGE);
+    });
+    let block_size = u32::try_from(sectors).unwrap_or_else(|_| {
+        report.fatal("data_block_size is too large");
+        exit(USAGE);
+    });
+    if has_unit_suffix(s) {
+        report.info(&format!("data block size: {} = {} sectors", s, block_size));
+    }
+    block_size
+}
+
+fn read_data_block_size(input: &Path) -> Result<u32> {
+    let engine = SyncIoEngine::new(input, 1, false)?;
+    let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    Ok(sb.data_block_size)
+}
+
+/// Parses a --nr-data-blocks style argument.  A size with a unit suffix,
+/// eg, '4T', is converted with the given block size, falling back to the
+/// one recorded in the input's superblock.
+pub fn parse_nr_data_blocks(
+    s: &str,
+    data_block_size: Option<u32>,
+    input: &Path,
+    report: &Report,
+) -> u64 {
+    resolve_nr_data_blocks(s, data_block_size, || read_data_block_size(input), report)
+}
+
+fn read_xml_data_block_size(input: &Path) -> Result<u32> {
+    let sb = xml::read_superblock(File::open(input)?)?;
+    Ok(sb.data_block_size)
+}
+
+/// As parse_nr_data_blocks, for the tools whose input is xml.
+pub fn parse_xml_nr_data_blocks(
+    s: &str,
+    data_block_size: Option<u32>,
+    input: &Path,
+    report: &Report,
+) -> u64 {
+    resolve_nr_data_blocks(
+        s,
+        data_block_size,
+        || read_xml_data_block_size(input),
+        report,
+    )
+}
+
+fn resolve_nr_data_blocks<F>(
+    s: &str,
+    data_block_size: Option<u32>,
+    read_block_size: F,
+    report: &Report,
+) -> u64
+where
+    F: FnOnce() -> Result<u32>,
+{
+    let count = s.parse::<BlockCount>().unwrap_or_else(|e| {
+        report.fatal(&format!("Couldn't parse nr_data_blocks: {}", e));
+        exit(USAGE);
+    });
+
+    if !count.needs_block_size() {
+        return count.to_blocks(0).unwrap();
+    }
+
+    let block_size = match data_block_size {
+        Some(bs) => Ok(bs),
+        None => read_block_size(),
+    };
+    let block_size = block_size.unwrap_or_else(|_| {
+        report.fatal("Couldn't read the data block size, use --data-block-size with a unit suffixed nr_data_blocks");
+        exit(FATAL);
+    });
+
+    let nr_blocks = count.to_blocks(block_size as u64).unwrap_or_else(|e| {
+        report.fatal(&format!("Couldn't resolve nr_data_blocks: {}", e));
+        exit(FATAL);
+    });
+    report.info(&format!(
+        "nr data blocks: {} = {} blocks of {} sectors",
+        s, nr_blocks, block_size
+    ));
+    nr_blocks
 }
 
 fn is_xml(line: &[u8]) -> bool {
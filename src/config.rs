@@ -0,0 +1,268 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::units::{parse_size, Units};
+
+//------------------------------------------
+
+/// Site wide defaults are read from here, if it exists.  A different
+/// file can be given with --config.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/thin-provisioning-tools.conf";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    // progress bar if stdout is a tty, plain text otherwise
+    Auto,
+    ProgressBar,
+    Simple,
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ReportFormat::Auto),
+            "progress" => Ok(ReportFormat::ProgressBar),
+            "simple" => Ok(ReportFormat::Simple),
+            _ => Err(anyhow!("unknown report format '{}'", s)),
+        }
+    }
+}
+
+/// Defaults for the options the tools share.  Anything given on the
+/// command line takes precedence.
+///
+/// The file is a list of 'key = value' lines, '#' starts a comment:
+///
+///   io_engine = async         # sync | async
+///   sync_io_threads = 16
+///   report = simple           # auto | progress | simple
+///   max_memory = 512m         # see --max-memory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub async_io: bool,
+    pub sync_io_threads: Option<usize>,
+    pub report: ReportFormat,
+    pub max_memory: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            async_io: false,
+            sync_io_threads: None,
+            report: ReportFormat::Auto,
+            max_memory: None,
+        }
+    }
+}
+
+impl Config {
+    /// Number of threads a SyncIoEngine should be created with.
+    pub fn nr_io_threads(&self) -> usize {
+        self.sync_io_threads
+            .unwrap_or_else(|| std::cmp::max(8, num_cpus::get() * 2))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "io_engine" => {
+                self.async_io = match value {
+                    "sync" => false,
+                    "async" => true,
+                    _ => return Err(anyhow!("unknown io engine '{}'", value)),
+                }
+            }
+            "sync_io_threads" => {
+                let n = value
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("invalid thread count '{}'", value))?;
+                if n == 0 {
+                    return Err(anyhow!("thread count must be non-zero"));
+                }
+                self.sync_io_threads = Some(n);
+            }
+            "report" => self.report = value.parse()?,
+            "max_memory" => self.max_memory = Some(parse_memory_size(value)?),
+            _ => return Err(anyhow!("unknown key '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Parses a memory limit, which is in MiB unless a unit is given.
+pub fn parse_memory_size(s: &str) -> Result<u64> {
+    let bytes = parse_size(s, Units::Mebibyte)?;
+    if bytes == 0 {
+        return Err(anyhow!("memory limit must be non-zero"));
+    }
+    Ok(bytes)
+}
+
+// Sets each line in turn.  A bad line is passed to on_error, which
+// either gives up or skips it.
+fn parse_lines<F>(s: &str, mut on_error: F) -> Result<Config>
+where
+    F: FnMut(anyhow::Error) -> Result<()>,
+{
+    let mut config = Config::default();
+
+    for (n, line) in s.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        }
+        .trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let r = match line.split_once('=') {
+            Some((key, value)) => config.set(key.trim(), value.trim()),
+            None => Err(anyhow!("expected 'key = value'")),
+        };
+        if let Err(e) = r {
+            on_error(e.context(format!("line {}", n + 1)))?;
+        }
+    }
+
+    Ok(config)
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_lines(s, Err)
+    }
+}
+
+impl Config {
+    /// Parses the config, skipping any bad lines rather than failing.
+    /// Returns what was wrong with each of them.
+    pub fn parse_lenient(s: &str) -> (Config, Vec<String>) {
+        let mut warnings = Vec::new();
+        let config = parse_lines(s, |e| {
+            warnings.push(format!("{:#}", e));
+            Ok(())
+        })
+        .unwrap();
+        (config, warnings)
+    }
+}
+
+/// Reads the config file at path.
+pub fn read_config(path: &Path) -> Result<Config> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("couldn't read config file '{}'", path.display()))?;
+    text.parse::<Config>()
+        .with_context(|| format!("bad config file '{}'", path.display()))
+}
+
+/// Reads the system wide config file, if it's present.  Any problem
+/// with it is only a warning, since a mistake there would otherwise
+/// stop every tool from running, eg, thin_check at boot.
+pub fn read_system_config(path: &Path) -> (Config, Vec<String>) {
+    if !path.exists() {
+        return (Config::default(), Vec::new());
+    }
+
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            let (config, warnings) = Config::parse_lenient(&text);
+            let warnings = warnings
+                .into_iter()
+                .map(|w| format!("ignoring {} of config file '{}'", w, path.display()))
+                .collect();
+            (config, warnings)
+        }
+        Err(e) => (
+            Config::default(),
+            vec![format!(
+                "couldn't read config file '{}', using the defaults: {}",
+                path.display(),
+                e
+            )],
+        ),
+    }
+}
+
+/// Reads the explicitly requested config file, which must be valid, or
+/// the system wide one.  No file at all just means the built in
+/// defaults.  Also returns warnings about the system wide file.
+pub fn load_config(path: Option<&Path>) -> Result<(Config, Vec<String>)> {
+    match path {
+        Some(path) => Ok((read_config(path)?, Vec::new())),
+        None => Ok(read_system_config(Path::new(DEFAULT_CONFIG_PATH))),
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty() {
+        let config: Config = "# nothing here\n\n".parse().unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_all_keys() {
+        let text =
+            "io_engine = async\nsync_io_threads=4  # comment\nreport = simple\nmax_memory = 1g\n";
+        let config: Config = text.parse().unwrap();
+        assert!(config.async_io);
+        assert_eq!(config.nr_io_threads(), 4);
+        assert_eq!(config.report, ReportFormat::Simple);
+        assert_eq!(config.max_memory, Some(1 << 30));
+    }
+
+    #[test]
+    fn test_bad_lines() {
+        assert!("io_engine".parse::<Config>().is_err());
+        assert!("io_engine = fast".parse::<Config>().is_err());
+        assert!("sync_io_threads = 0".parse::<Config>().is_err());
+        assert!("colour = blue".parse::<Config>().is_err());
+        assert!("max_memory = 0".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_lenient() {
+        let text = "colour = blue\nsync_io_threads = 4\nio_engine\nreport = loud\n";
+        let (config, warnings) = Config::parse_lenient(text);
+        assert_eq!(config.nr_io_threads(), 4);
+        assert_eq!(config.report, ReportFormat::Auto);
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings[0].starts_with("line 1: unknown key 'colour'"));
+        assert!(warnings[1].starts_with("line 3:"));
+    }
+
+    #[test]
+    fn test_system_config() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let missing = dir.path().join("missing.conf");
+        assert_eq!(read_system_config(&missing), (Config::default(), vec![]));
+
+        let path = dir.path().join("thin.conf");
+        fs::write(&path, "io_engine = async\ncolour = blue\n").unwrap();
+        let (config, warnings) = read_system_config(&path);
+        assert!(config.async_io);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("unknown key 'colour'"));
+
+        // A directory can't be read, but does exist.
+        let (config, warnings) = read_system_config(dir.path());
+        assert_eq!(config, Config::default());
+        assert!(warnings[0].contains("using the defaults"));
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,476 @@
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use crate::file_utils;
+use crate::pack::toplevel::{pack, unpack};
+
+//------------------------------------------
+
+// A corpus is a directory of entries, each holding some packed metadata,
+// a description of where it came from (eg, the bug it once triggered),
+// and the output every tool gave when the entry was recorded:
+//
+//   <corpus>/<entry>/meta.pack
+//   <corpus>/<entry>/entry.json
+//   <corpus>/<entry>/expected/<run>.out
+
+const PACK_FILE: &str = "meta.pack";
+const ENTRY_FILE: &str = "entry.json";
+const EXPECTED_DIR: &str = "expected";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetadataKind {
+    Thin,
+    Cache,
+    Era,
+}
+
+impl FromStr for MetadataKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "thin" => Ok(MetadataKind::Thin),
+            "cache" => Ok(MetadataKind::Cache),
+            "era" => Ok(MetadataKind::Era),
+            _ => Err(anyhow!("unknown metadata kind '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for MetadataKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetadataKind::Thin => write!(f, "thin"),
+            MetadataKind::Cache => write!(f, "cache"),
+            MetadataKind::Era => write!(f, "era"),
+        }
+    }
+}
+
+//------------------------------------------
+
+// Each tool run is a sequence of commands, since the tools that write
+// metadata are followed by a dump of what they wrote.  "{md}" is
+// replaced with the entry's metadata, "{out}" with a scratch file of
+// the same size, and "{pack}" with a scratch pack file.
+struct ToolRun {
+    name: &'static str,
+    cmds: &'static [&'static [&'static str]],
+}
+
+const THIN_RUNS: &[ToolRun] = &[
+    ToolRun {
+        name: "thin_check",
+        cmds: &[&["thin_check", "{md}"]],
+    },
+    ToolRun {
+        name: "thin_dump",
+        cmds: &[&["thin_dump", "{md}"]],
+    },
+    ToolRun {
+        name: "thin_dump_repair",
+        cmds: &[&["thin_dump", "--repair", "{md}"]],
+    },
+    ToolRun {
+        name: "thin_repair",
+        cmds: &[
+            &["thin_repair", "-i", "{md}", "-o", "{out}"],
+            &["thin_dump", "{out}"],
+        ],
+    },
+    ToolRun {
+        name: "thin_metadata_pack",
+        cmds: &[
+            &["thin_metadata_pack", "-i", "{md}", "-o", "{pack}"],
+            &["thin_metadata_unpack", "-i", "{pack}", "-o", "{out}"],
+            &["thin_dump", "{out}"],
+        ],
+    },
+];
+
+const CACHE_RUNS: &[ToolRun] = &[
+    ToolRun {
+        name: "cache_check",
+        cmds: &[&["cache_check", "{md}"]],
+    },
+    ToolRun {
+        name: "cache_dump",
+        cmds: &[&["cache_dump", "{md}"]],
+    },
+    ToolRun {
+        name: "cache_dump_repair",
+        cmds: &[&["cache_dump", "--repair", "{md}"]],
+    },
+    ToolRun {
+        name: "cache_repair",
+        cmds: &[
+            &["cache_repair", "-i", "{md}", "-o", "{out}"],
+            &["cache_dump", "{out}"],
+        ],
+    },
+    ToolRun {
+        name: "cache_stat",
+        cmds: &[&["cache_stat", "{md}"]],
+    },
+    ToolRun {
+        name: "thin_metadata_pack",
+        cmds: &[
+            &["thin_metadata_pack", "-i", "{md}", "-o", "{pack}"],
+            &["thin_metadata_unpack", "-i", "{pack}", "-o", "{out}"],
+            &["cache_dump", "{out}"],
+        ],
+    },
+];
+
+const ERA_RUNS: &[ToolRun] = &[
+    ToolRun {
+        name: "era_check",
+        cmds: &[&["era_check", "{md}"]],
+    },
+    ToolRun {
+        name: "era_dump",
+        cmds: &[&["era_dump", "{md}"]],
+    },
+    ToolRun {
+        name: "era_dump_logical",
+        cmds: &[&["era_dump", "--logical", "{md}"]],
+    },
+    ToolRun {
+        name: "era_dump_repair",
+        cmds: &[&["era_dump", "--repair", "{md}"]],
+    },
+    ToolRun {
+        name: "era_repair",
+        cmds: &[
+            &["era_repair", "-i", "{md}", "-o", "{out}"],
+            &["era_dump", "{out}"],
+        ],
+    },
+    ToolRun {
+        name: "era_stat",
+        cmds: &[&["era_stat", "{md}"]],
+    },
+    ToolRun {
+        name: "thin_metadata_pack",
+        cmds: &[
+            &["thin_metadata_pack", "-i", "{md}", "-o", "{pack}"],
+            &["thin_metadata_unpack", "-i", "{pack}", "-o", "{out}"],
+            &["era_dump", "{out}"],
+        ],
+    },
+];
+
+fn tool_runs(kind: MetadataKind) -> &'static [ToolRun] {
+    match kind {
+        MetadataKind::Thin => THIN_RUNS,
+        MetadataKind::Cache => CACHE_RUNS,
+        MetadataKind::Era => ERA_RUNS,
+    }
+}
+
+//------------------------------------------
+
+pub struct Entry {
+    pub name: String,
+    pub kind: MetadataKind,
+    pub description: String,
+    dir: PathBuf,
+}
+
+impl Entry {
+    fn load(dir: &Path) -> Result<Entry> {
+        let name = dir
+            .file_name()
+            .ok_or_else(|| anyhow!("bad corpus entry path {:?}", dir))?
+            .to_string_lossy()
+            .into_owned();
+
+        let path = dir.join(ENTRY_FILE);
+        let text =
+            fs::read_to_string(&path).with_context(|| format!("couldn't read {:?}", path))?;
+        let json = json::parse(&text).with_context(|| format!("couldn't parse {:?}", path))?;
+        let kind = json["kind"]
+            .as_str()
+            .ok_or_else(|| anyhow!("no kind given in {:?}", path))?
+            .parse()?;
+        let description = json["description"].as_str().unwrap_or("").to_string();
+
+        Ok(Entry {
+            name,
+            kind,
+            description,
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    fn expected_path(&self, run: &str) -> PathBuf {
+        self.dir.join(EXPECTED_DIR).join(format!("{}.out", run))
+    }
+}
+
+pub struct Corpus {
+    dir: PathBuf,
+}
+
+impl Corpus {
+    pub fn open(dir: &Path) -> Result<Corpus> {
+        if !dir.is_dir() {
+            return Err(anyhow!("corpus directory {:?} not found", dir));
+        }
+        Ok(Corpus {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// The entries sorted by name, or just the named ones.
+    pub fn entries(&self, names: &[&str]) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for de in fs::read_dir(&self.dir)? {
+            let path = de?.path();
+            if path.join(ENTRY_FILE).is_file() {
+                entries.push(Entry::load(&path)?);
+            }
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for name in names {
+            if !entries.iter().any(|e| e.name == *name) {
+                return Err(anyhow!("no corpus entry named '{}'", name));
+            }
+        }
+        if !names.is_empty() {
+            entries.retain(|e| names.contains(&e.name.as_str()));
+        }
+
+        Ok(entries)
+    }
+
+    /// Packs the metadata into a new entry.  The expected outputs still
+    /// need recording.
+    pub fn add(
+        &self,
+        name: &str,
+        kind: MetadataKind,
+        metadata: &Path,
+        description: &str,
+    ) -> Result<Entry> {
+        let dir = self.dir.join(name);
+        if dir.exists() {
+            return Err(anyhow!("corpus entry '{}' already exists", name));
+        }
+        fs::create_dir_all(dir.join(EXPECTED_DIR))?;
+
+        pack(metadata, &dir.join(PACK_FILE))
+            .map_err(|e| anyhow!("couldn't pack metadata: {}", e))?;
+
+        let json = json::object! {
+            kind: kind.to_string(),
+            description: description,
+        };
+        fs::write(dir.join(ENTRY_FILE), json.pretty(4) + "\n")?;
+
+        Entry::load(&dir)
+    }
+}
+
+//------------------------------------------
+
+fn append_section(text: &mut String, name: &str, bytes: &[u8]) {
+    *text += &format!("--- {}\n", name);
+    *text += &String::from_utf8_lossy(bytes);
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+/// Runs the tools from a pdata_tools binary against corpus entries.
+pub struct Runner {
+    tools: PathBuf,
+    scratch: tempfile::TempDir,
+}
+
+pub struct RunOutput {
+    pub run: &'static str,
+    pub output: String,
+}
+
+impl Runner {
+    pub fn new(tools: &Path) -> Result<Runner> {
+        Ok(Runner {
+            tools: tools.to_path_buf(),
+            scratch: tempfile::Builder::new().prefix("pdata_corpus").tempdir()?,
+        })
+    }
+
+    fn exec(&self, args: &[String]) -> Result<String> {
+        let output = Command::new(&self.tools)
+            .args(args)
+            .output()
+            .with_context(|| format!("couldn't run {:?}", self.tools))?;
+
+        let mut text = format!("$ {}\n", args.join(" "));
+        match output.status.code() {
+            Some(code) => text += &format!("exit: {}\n", code),
+            None => text += "exit: killed by a signal\n",
+        }
+        append_section(&mut text, "stdout", &output.stdout);
+        append_section(&mut text, "stderr", &output.stderr);
+        Ok(text)
+    }
+
+    fn run_tool(&self, entry: &Entry, run: &ToolRun) -> Result<String> {
+        // Each run gets fresh copies, in case a tool changes its input.
+        let md = self.scratch.path().join("meta.bin");
+        let out = self.scratch.path().join("out.bin");
+        let packed = self.scratch.path().join("out.pack");
+
+        unpack(&entry.dir.join(PACK_FILE), &md)
+            .map_err(|e| anyhow!("couldn't unpack entry '{}': {}", entry.name, e))?;
+        file_utils::create_sized_file(&out, file_utils::file_size(&md)?)?;
+        let _ = fs::remove_file(&packed);
+
+        let substitutions = [("{md}", &md), ("{out}", &out), ("{pack}", &packed)];
+        let mut text = String::new();
+        for cmd in run.cmds {
+            let args: Vec<String> = cmd
+                .iter()
+                .map(
+                    |arg| match substitutions.iter().find(|(name, _)| name == arg) {
+                        Some((_, path)) => path.display().to_string(),
+                        None => arg.to_string(),
+                    },
+                )
+                .collect();
+
+            text += &self.exec(&args)?;
+        }
+
+        // Keep the scratch paths out of the recorded output.
+        for (name, path) in &substitutions {
+            text = text.replace(&path.display().to_string(), name);
+        }
+        Ok(text)
+    }
+
+    /// Runs every tool that handles the entry's kind of metadata.
+    pub fn run(&self, entry: &Entry) -> Result<Vec<RunOutput>> {
+        let mut outputs = Vec::new();
+        for run in tool_runs(entry.kind) {
+            outputs.push(RunOutput {
+                run: run.name,
+                output: self.run_tool(entry, run)?,
+            });
+        }
+        Ok(outputs)
+    }
+}
+
+//------------------------------------------
+
+/// Replaces the entry's expected outputs with the current ones.
+pub fn record(entry: &Entry, outputs: &[RunOutput]) -> Result<()> {
+    let dir = entry.dir.join(EXPECTED_DIR);
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+
+    for o in outputs {
+        fs::write(entry.expected_path(o.run), &o.output)?;
+    }
+    Ok(())
+}
+
+pub struct Regression {
+    pub run: &'static str,
+    pub diff: String,
+}
+
+/// Compares the outputs with those recorded, returning the runs whose
+/// output changed.
+pub fn compare(entry: &Entry, outputs: &[RunOutput]) -> Result<Vec<Regression>> {
+    let mut regressions = Vec::new();
+    for o in outputs {
+        let path = entry.expected_path(o.run);
+        if !path.exists() {
+            regressions.push(Regression {
+                run: o.run,
+                diff: "no expected output has been recorded\n".to_string(),
+            });
+            continue;
+        }
+
+        let expected = fs::read_to_string(&path)?;
+        if expected != o.output {
+            regressions.push(Regression {
+                run: o.run,
+                diff: first_difference(&expected, &o.output),
+            });
+        }
+    }
+    Ok(regressions)
+}
+
+const DIFF_CONTEXT: usize = 3;
+const DIFF_LINES: usize = 10;
+
+// A short description of where the outputs first diverge, rather than a
+// full diff, which for a dump could be huge.
+fn first_difference(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    let mut i = 0;
+    while i < expected.len() && i < actual.len() && expected[i] == actual[i] {
+        i += 1;
+    }
+
+    let mut diff = format!("first difference at line {}:\n", i + 1);
+    for line in &expected[i.saturating_sub(DIFF_CONTEXT)..i] {
+        diff += &format!(" {}\n", line);
+    }
+    for line in expected.iter().skip(i).take(DIFF_LINES) {
+        diff += &format!("-{}\n", line);
+    }
+    for line in actual.iter().skip(i).take(DIFF_LINES) {
+        diff += &format!("+{}\n", line);
+    }
+    diff
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kind() {
+        for kind in &[MetadataKind::Thin, MetadataKind::Cache, MetadataKind::Era] {
+            assert_eq!(kind.to_string().parse::<MetadataKind>().unwrap(), *kind);
+        }
+        assert!("btree".parse::<MetadataKind>().is_err());
+    }
+
+    #[test]
+    fn test_first_difference() {
+        let diff = first_difference("a\nb\nc\nd\n", "a\nb\nx\nd\n");
+        assert_eq!(
+            diff,
+            "first difference at line 3:\n a\n b\n-c\n-d\n+x\n+d\n"
+        );
+    }
+
+    #[test]
+    fn test_first_difference_in_length() {
+        let diff = first_difference("a\n", "a\nb\n");
+        assert_eq!(diff, "first difference at line 2:\n a\n+b\n");
+    }
+}
+
+//------------------------------------------
@@ -1,10 +1,12 @@
 use anyhow::{anyhow, Result};
 use std::path::Path;
 use std::sync::Arc;
+use tracing::{instrument, Span};
 
 use crate::era::superblock::*;
 use crate::era::writeset::*;
 use crate::io_engine::{AsyncIoEngine, IoEngine, SyncIoEngine};
+use crate::memory;
 use crate::pdata::array::{self, ArrayBlock, ArrayError};
 use crate::pdata::array_walker::*;
 use crate::pdata::bitset::*;
@@ -64,6 +66,7 @@ impl ArrayVisitor<u32> for EraChecker {
 pub struct EraCheckOptions<'a> {
     pub dev: &'a Path,
     pub async_io: bool,
+    pub nr_io_threads: usize,
     pub sb_only: bool,
     pub ignore_non_fatal: bool,
     pub report: Arc<Report>,
@@ -80,8 +83,7 @@ fn mk_context(opts: &EraCheckOptions) -> anyhow::Result<Context> {
     if opts.async_io {
         engine = Arc::new(AsyncIoEngine::new(opts.dev, MAX_CONCURRENT_IO, false)?);
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
-        engine = Arc::new(SyncIoEngine::new(opts.dev, nr_threads, false)?);
+        engine = Arc::new(SyncIoEngine::new(opts.dev, opts.nr_io_threads, false)?);
     }
 
     Ok(Context {
@@ -97,14 +99,20 @@ fn check_superblock(sb: &Superblock) -> anyhow::Result<()> {
     Ok(())
 }
 
+#[instrument(skip_all, fields(nr_metadata_blocks))]
 pub fn check(opts: &EraCheckOptions) -> Result<()> {
     let ctx = mk_context(opts)?;
+    Span::current().record("nr_metadata_blocks", ctx.engine.get_nr_blocks());
     let engine = &ctx.engine;
     let report = &ctx.report;
     let mut fatal = false;
 
     report.set_title("Checking era metadata");
 
+    let _metadata_sm_claim = memory::claim(
+        core_sm_bytes(engine.get_nr_blocks(), u8::MAX as u32),
+        "metadata space map",
+    )?;
     let metadata_sm = core_sm(engine.get_nr_blocks(), u8::MAX as u32);
     inc_superblock(&metadata_sm)?;
 
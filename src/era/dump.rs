@@ -6,9 +6,12 @@ use std::io::BufWriter;
 use std::io::Write;
 use std::ops::Deref;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use tracing::{instrument, Span};
 
 use crate::era::ir::{self, MetadataVisitor};
+use crate::era::json;
 use crate::era::superblock::*;
 use crate::era::writeset::Writeset;
 use crate::era::xml;
@@ -163,12 +166,34 @@ impl<'a> ArrayVisitor<u32> for LogicalEraEmitter<'a> {
 
 //------------------------------------------
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Xml,
+
+    // The logical era array, with runs of blocks sharing an era as ranges.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "xml" => Ok(OutputFormat::Xml),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(anyhow!("unknown output format '{}'", s)),
+        }
+    }
+}
+
 pub struct EraDumpOptions<'a> {
     pub input: &'a Path,
     pub output: Option<&'a Path>,
     pub async_io: bool,
+    pub nr_io_threads: usize,
     pub logical: bool,
     pub repair: bool,
+    pub format: OutputFormat,
 }
 
 struct Context {
@@ -181,8 +206,7 @@ fn mk_context(opts: &EraDumpOptions) -> anyhow::Result<Context> {
     if opts.async_io {
         engine = Arc::new(AsyncIoEngine::new(opts.input, MAX_CONCURRENT_IO, false)?);
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
-        engine = Arc::new(SyncIoEngine::new(opts.input, nr_threads, false)?);
+        engine = Arc::new(SyncIoEngine::new(opts.input, opts.nr_io_threads, false)?);
     }
 
     Ok(Context { engine })
@@ -397,8 +421,10 @@ pub fn dump_metadata_logical(
 
 //-----------------------------------------
 
+#[instrument(skip_all, fields(nr_metadata_blocks))]
 pub fn dump(opts: EraDumpOptions) -> anyhow::Result<()> {
     let ctx = mk_context(&opts)?;
+    Span::current().record("nr_metadata_blocks", ctx.engine.get_nr_blocks());
     let sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
 
     let writer: Box<dyn Write>;
@@ -407,13 +433,16 @@ pub fn dump(opts: EraDumpOptions) -> anyhow::Result<()> {
     } else {
         writer = Box::new(BufWriter::new(std::io::stdout()));
     }
-    let mut out = xml::XmlWriter::new(writer, false);
+    let mut out: Box<dyn MetadataVisitor> = match opts.format {
+        OutputFormat::Xml => Box::new(xml::XmlWriter::new(writer, false)),
+        OutputFormat::Json => Box::new(json::JsonWriter::new(writer)),
+    };
 
     let writesets = get_writesets_ordered(ctx.engine.clone(), &sb, opts.repair)?;
     if opts.logical && !writesets.is_empty() {
-        dump_metadata_logical(ctx.engine, &mut out, &sb, opts.repair)
+        dump_metadata_logical(ctx.engine, out.as_mut(), &sb, opts.repair)
     } else {
-        dump_metadata(ctx.engine, &mut out, &sb, opts.repair)
+        dump_metadata(ctx.engine, out.as_mut(), &sb, opts.repair)
     }
 }
 
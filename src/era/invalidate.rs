@@ -5,6 +5,7 @@ use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use tracing::instrument;
 
 use crate::era::superblock::*;
 use crate::era::writeset::*;
@@ -231,6 +232,7 @@ pub struct EraInvalidateOptions<'a> {
     pub input: &'a Path,
     pub output: Option<&'a Path>,
     pub async_io: bool,
+    pub nr_io_threads: usize,
     pub threshold: u32,
     pub metadata_snap: bool,
 }
@@ -250,10 +252,9 @@ fn mk_context(opts: &EraInvalidateOptions) -> anyhow::Result<Context> {
             !opts.metadata_snap,
         )?);
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
         engine = Arc::new(SyncIoEngine::new_with(
             opts.input,
-            nr_threads,
+            opts.nr_io_threads,
             false,
             !opts.metadata_snap,
         )?);
@@ -262,6 +263,7 @@ fn mk_context(opts: &EraInvalidateOptions) -> anyhow::Result<Context> {
     Ok(Context { engine })
 }
 
+#[instrument(skip_all)]
 pub fn invalidate(opts: &EraInvalidateOptions) -> Result<()> {
     let ctx = mk_context(opts)?;
 
@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Result};
+use std::io::Write;
+
+use crate::era::ir::*;
+
+//---------------------------------------
+
+// A run of consecutive blocks last written in the same era.
+struct EraRange {
+    begin: u32,
+    end: u32,
+    era: u32,
+}
+
+/// Writes the era array as json, coalescing consecutive blocks that
+/// share an era into a single range.  Only the logical view can be
+/// written, since write sets have no json representation.
+pub struct JsonWriter<W: Write> {
+    w: W,
+    current: Option<EraRange>,
+    nr_ranges: u64,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub fn new(w: W) -> JsonWriter<W> {
+        JsonWriter {
+            w,
+            current: None,
+            nr_ranges: 0,
+        }
+    }
+
+    fn flush_range(&mut self) -> Result<()> {
+        if let Some(r) = self.current.take() {
+            let obj = json::object! {
+                block_begin: r.begin,
+                block_end: r.end,
+                era: r.era,
+            };
+
+            if self.nr_ranges > 0 {
+                writeln!(self.w, ",")?;
+            }
+            write!(self.w, "    {}", obj.dump())?;
+            self.nr_ranges += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> MetadataVisitor for JsonWriter<W> {
+    fn superblock_b(&mut self, sb: &Superblock) -> Result<Visit> {
+        writeln!(self.w, "{{")?;
+        writeln!(self.w, "  \"uuid\": {},", json::stringify(sb.uuid.as_str()))?;
+        writeln!(self.w, "  \"block_size\": {},", sb.block_size)?;
+        writeln!(self.w, "  \"nr_blocks\": {},", sb.nr_blocks)?;
+        writeln!(self.w, "  \"current_era\": {},", sb.current_era)?;
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        writeln!(self.w, "}}")?;
+        Ok(Visit::Continue)
+    }
+
+    fn writeset_b(&mut self, _ws: &Writeset) -> Result<Visit> {
+        Err(anyhow!(
+            "write sets can't be written as json, fold them in with --logical"
+        ))
+    }
+
+    fn writeset_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn writeset_blocks(&mut self, _blocks: &MarkedBlocks) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn era_b(&mut self) -> Result<Visit> {
+        writeln!(self.w, "  \"eras\": [")?;
+        self.current = None;
+        self.nr_ranges = 0;
+        Ok(Visit::Continue)
+    }
+
+    fn era_e(&mut self) -> Result<Visit> {
+        self.flush_range()?;
+        if self.nr_ranges > 0 {
+            writeln!(self.w)?;
+        }
+        writeln!(self.w, "  ]")?;
+        Ok(Visit::Continue)
+    }
+
+    fn era(&mut self, era: &Era) -> Result<Visit> {
+        if let Some(r) = self.current.as_mut() {
+            if r.end == era.block && r.era == era.era {
+                r.end += 1;
+                return Ok(Visit::Continue);
+            }
+        }
+
+        self.flush_range()?;
+        self.current = Some(EraRange {
+            begin: era.block,
+            end: era.block + 1,
+            era: era.era,
+        });
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_eras(eras: &[(u32, u32)]) -> String {
+        let mut buf = Vec::new();
+        let mut w = JsonWriter::new(&mut buf);
+        let sb = Superblock {
+            uuid: "".to_string(),
+            block_size: 128,
+            nr_blocks: 8,
+            current_era: 5,
+        };
+        w.superblock_b(&sb).unwrap();
+        w.era_b().unwrap();
+        for (block, era) in eras {
+            w.era(&Era {
+                block: *block,
+                era: *era,
+            })
+            .unwrap();
+        }
+        w.era_e().unwrap();
+        w.superblock_e().unwrap();
+        w.eof().unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    fn ranges(output: &str) -> Vec<(u32, u32, u32)> {
+        let v = json::parse(output).unwrap();
+        v["eras"]
+            .members()
+            .map(|r| {
+                (
+                    r["block_begin"].as_u32().unwrap(),
+                    r["block_end"].as_u32().unwrap(),
+                    r["era"].as_u32().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_coalesces_eras() {
+        let out = write_eras(&[(0, 1), (1, 1), (2, 3), (3, 3), (4, 3), (5, 1), (7, 1)]);
+        let v = json::parse(&out).unwrap();
+        assert_eq!(v["current_era"].as_u32(), Some(5));
+        assert_eq!(
+            ranges(&out),
+            vec![(0, 2, 1), (2, 5, 3), (5, 6, 1), (7, 8, 1)]
+        );
+    }
+
+    #[test]
+    fn test_empty_era_array() {
+        assert!(ranges(&write_eras(&[])).is_empty());
+    }
+}
+
+//------------------------------------------
@@ -2,8 +2,10 @@ pub mod check;
 pub mod dump;
 pub mod invalidate;
 pub mod ir;
+pub mod json;
 pub mod repair;
 pub mod restore;
+pub mod stat;
 pub mod superblock;
 pub mod writeset;
 pub mod xml;
@@ -4,6 +4,7 @@ use std::collections::BTreeMap;
 use std::fs::OpenOptions;
 use std::path::Path;
 use std::sync::Arc;
+use tracing::{instrument, Span};
 
 use crate::era::ir::{self, MetadataVisitor, Visit};
 use crate::era::superblock::*;
@@ -11,6 +12,7 @@ use crate::era::writeset::Writeset;
 use crate::era::xml;
 use crate::io_engine::*;
 use crate::math::*;
+use crate::memory;
 use crate::pdata::array_builder::*;
 use crate::pdata::btree_builder::*;
 use crate::pdata::space_map_common::pack_root;
@@ -28,6 +30,7 @@ pub struct EraRestoreOptions<'a> {
     pub input: &'a Path,
     pub output: &'a Path,
     pub async_io: bool,
+    pub nr_io_threads: usize,
     pub report: Arc<Report>,
 }
 
@@ -42,8 +45,7 @@ fn mk_context(opts: &EraRestoreOptions) -> anyhow::Result<Context> {
     if opts.async_io {
         engine = Arc::new(AsyncIoEngine::new(opts.output, MAX_CONCURRENT_IO, true)?);
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
-        engine = Arc::new(SyncIoEngine::new(opts.output, nr_threads, true)?);
+        engine = Arc::new(SyncIoEngine::new(opts.output, opts.nr_io_threads, true)?);
     }
 
     Ok(Context {
@@ -293,6 +295,7 @@ fn build_metadata_sm(w: &mut WriteBatcher) -> Result<Vec<u8>> {
 
 //------------------------------------------
 
+#[instrument(skip_all, fields(nr_metadata_blocks, nr_allocated))]
 pub fn restore(opts: EraRestoreOptions) -> Result<()> {
     let input = OpenOptions::new()
         .read(true)
@@ -300,12 +303,18 @@ pub fn restore(opts: EraRestoreOptions) -> Result<()> {
         .open(opts.input)?;
 
     let ctx = mk_context(&opts)?;
+    Span::current().record("nr_metadata_blocks", ctx.engine.get_nr_blocks());
 
+    let _metadata_sm_claim = memory::claim(
+        core_metadata_sm_bytes(ctx.engine.get_nr_blocks(), u32::MAX),
+        "metadata space map",
+    )?;
     let sm = core_metadata_sm(ctx.engine.get_nr_blocks(), u32::MAX);
     let mut w = WriteBatcher::new(ctx.engine.clone(), sm.clone(), ctx.engine.get_batch_size());
 
     let mut restorer = Restorer::new(&mut w);
     xml::read(input, &mut restorer)?;
+    Span::current().record("nr_allocated", sm.lock().unwrap().get_nr_allocated()?);
 
     Ok(())
 }
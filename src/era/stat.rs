@@ -0,0 +1,228 @@
+use anyhow::Result;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::era::dump::{dump_metadata, dump_metadata_logical};
+use crate::era::ir::{self, MetadataVisitor, Visit};
+use crate::era::superblock::*;
+use crate::io_engine::*;
+
+//------------------------------------------
+
+const MAX_CONCURRENT_IO: u32 = 1024;
+
+//------------------------------------------
+
+pub struct EraStatOptions<'a> {
+    pub input: &'a Path,
+    pub async_io: bool,
+    pub nr_io_threads: usize,
+}
+
+/// The number of blocks marked in a write set that hasn't been folded
+/// into the era array yet.
+pub struct WritesetStats {
+    pub era: u32,
+    pub nr_marked: u64,
+}
+
+/// Blocks last written between `min_age` and `max_age` eras ago.
+pub struct AgeBucket {
+    pub min_age: u32,
+    pub max_age: u32,
+    pub nr_blocks: u64,
+}
+
+pub struct EraStats {
+    pub block_size: u32,
+    pub nr_blocks: u32,
+    pub current_era: u32,
+    pub writesets: Vec<WritesetStats>,
+    pub histogram: Vec<AgeBucket>,
+}
+
+//------------------------------------------
+
+// Gathers the write sets and the era of each block as they're dumped.
+#[derive(Default)]
+struct Collector {
+    writesets: Vec<WritesetStats>,
+    eras: Vec<u32>,
+}
+
+impl MetadataVisitor for Collector {
+    fn superblock_b(&mut self, _sb: &ir::Superblock) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn writeset_b(&mut self, ws: &ir::Writeset) -> Result<Visit> {
+        self.writesets.push(WritesetStats {
+            era: ws.era,
+            nr_marked: 0,
+        });
+        Ok(Visit::Continue)
+    }
+
+    fn writeset_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn writeset_blocks(&mut self, blocks: &ir::MarkedBlocks) -> Result<Visit> {
+        if let Some(ws) = self.writesets.last_mut() {
+            ws.nr_marked += blocks.len as u64;
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn era_b(&mut self) -> Result<Visit> {
+        self.eras.clear();
+        Ok(Visit::Continue)
+    }
+
+    fn era_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn era(&mut self, era: &ir::Era) -> Result<Visit> {
+        self.eras.push(era.era);
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
+// Ages double with each bucket: 0, 1, 2-3, 4-7 ...
+fn age_bucket(age: u32) -> usize {
+    (u32::BITS - age.leading_zeros()) as usize
+}
+
+fn bucket_range(index: usize) -> (u32, u32) {
+    match index {
+        0 => (0, 0),
+        i => (1 << (i - 1), ((1u64 << i) - 1) as u32),
+    }
+}
+
+fn build_histogram(eras: &[u32], current_era: u32) -> Vec<AgeBucket> {
+    let mut counts: Vec<u64> = Vec::new();
+    for era in eras {
+        let i = age_bucket(current_era.wrapping_sub(*era));
+        if counts.len() <= i {
+            counts.resize(i + 1, 0);
+        }
+        counts[i] += 1;
+    }
+
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(i, nr_blocks)| {
+            let (min_age, max_age) = bucket_range(i);
+            AgeBucket {
+                min_age,
+                max_age,
+                nr_blocks,
+            }
+        })
+        .collect()
+}
+
+pub fn stat(opts: EraStatOptions) -> Result<EraStats> {
+    let engine: Arc<dyn IoEngine + Send + Sync> = if opts.async_io {
+        Arc::new(AsyncIoEngine::new(opts.input, MAX_CONCURRENT_IO, false)?)
+    } else {
+        Arc::new(SyncIoEngine::new(opts.input, opts.nr_io_threads, false)?)
+    };
+
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let mut collector = Collector::default();
+    dump_metadata(engine.clone(), &mut collector, &sb, false)?;
+
+    // Ages are taken from the era array with the write sets folded in,
+    // as the kernel would see it.
+    if !collector.writesets.is_empty() {
+        dump_metadata_logical(engine, &mut collector, &sb, false)?;
+    }
+
+    let histogram = build_histogram(&collector.eras, sb.current_era);
+    Ok(EraStats {
+        block_size: sb.data_block_size,
+        nr_blocks: sb.nr_blocks,
+        current_era: sb.current_era,
+        writesets: collector.writesets,
+        histogram,
+    })
+}
+
+//------------------------------------------
+
+pub fn write_stats<W: Write>(w: &mut W, stats: &EraStats) -> Result<()> {
+    writeln!(w, "current era:      {}", stats.current_era)?;
+    writeln!(w, "block size:       {} sectors", stats.block_size)?;
+    writeln!(w, "blocks:           {}", stats.nr_blocks)?;
+    writeln!(w, "writesets:        {}", stats.writesets.len())?;
+    for ws in &stats.writesets {
+        writeln!(w, "  era {}: {} blocks marked", ws.era, ws.nr_marked)?;
+    }
+
+    if stats.histogram.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(w)?;
+    writeln!(w, "{:<24} blocks", "age (eras)")?;
+    for b in &stats.histogram {
+        let age = if b.min_age == b.max_age {
+            format!("{}", b.min_age)
+        } else {
+            format!("{}-{}", b.min_age, b.max_age)
+        };
+        writeln!(w, "{:<24} {}", age, b.nr_blocks)?;
+    }
+
+    Ok(())
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_age_buckets() {
+        assert_eq!(age_bucket(0), 0);
+        assert_eq!(age_bucket(1), 1);
+        assert_eq!(age_bucket(3), 2);
+        assert_eq!(age_bucket(4), 3);
+        assert_eq!(age_bucket(u32::MAX), 32);
+
+        assert_eq!(bucket_range(0), (0, 0));
+        assert_eq!(bucket_range(2), (2, 3));
+        assert_eq!(bucket_range(32), (1 << 31, u32::MAX));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let h = build_histogram(&[10, 9, 9, 5, 1], 10);
+        let counts: Vec<(u32, u32, u64)> = h
+            .iter()
+            .map(|b| (b.min_age, b.max_age, b.nr_blocks))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![(0, 0, 1), (1, 1, 2), (2, 3, 0), (4, 7, 1), (8, 15, 1)]
+        );
+    }
+}
+
+//------------------------------------------
@@ -2,6 +2,7 @@ use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, WriteBytesExt};
 use nom::{bytes::complete::*, number::complete::*, IResult};
 use std::io::Cursor;
+use tracing::instrument;
 
 use crate::checksum::*;
 use crate::era::writeset::Writeset;
@@ -12,7 +13,7 @@ use crate::io_engine::*;
 pub const SPACE_MAP_ROOT_SIZE: usize = 128;
 pub const SUPERBLOCK_LOCATION: u64 = 0;
 
-const MAGIC: u64 = 0o17660203573; // 0x7EC1077B in hex
+pub const MAGIC: u64 = 0o17660203573; // 0x7EC1077B in hex
 const UUID_SIZE: usize = 16;
 
 //------------------------------------------
@@ -83,6 +84,7 @@ fn unpack(data: &[u8]) -> IResult<&[u8], Superblock> {
     ))
 }
 
+#[instrument(level = "debug", skip(engine))]
 pub fn read_superblock(engine: &dyn IoEngine, loc: u64) -> Result<Superblock> {
     let b = engine.read(loc)?;
 
@@ -133,6 +135,7 @@ fn pack_superblock<W: WriteBytesExt>(sb: &Superblock, w: &mut W) -> Result<()> {
     Ok(())
 }
 
+#[instrument(level = "debug", skip(engine, sb))]
 pub fn write_superblock(engine: &dyn IoEngine, _loc: u64, sb: &Superblock) -> Result<()> {
     let b = Block::zeroed(SUPERBLOCK_LOCATION);
 
@@ -0,0 +1,570 @@
+use anyhow::anyhow;
+use std::io::{self, Result};
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::io_engine::*;
+
+//------------------------------------------
+
+// An IoEngine that wraps another, and injects faults into the io passing
+// through it as a script directs.  Tests use it to reach error handling
+// that real devices rarely exercise.  A script is a list of rules, one
+// per line:
+//
+//   seed 1234
+//   read 0 eio                     # the superblock can't be read
+//   read 100-200 short 512         # reads of these blocks stop early
+//   write * torn 2048 percent=10   # a tenth of the blocks tear
+//   any * latency 5 after=1000 times=10
+//
+// Block ranges exclude their end, as elsewhere.  Which blocks a
+// percentage selects depends only on the seed, so they're the same on
+// every run, however the io is spread across threads.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultOp {
+    Read,
+    Write,
+    Any,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FaultKind {
+    /// The io fails with EIO.
+    Eio,
+
+    /// Only the first so many bytes of the block are read, the rest are
+    /// zeroed.  The read still succeeds.
+    ShortRead(usize),
+
+    /// Only the first so many bytes of the block reach the device, as
+    /// if the power failed mid write.  The write still succeeds.
+    TornWrite(usize),
+
+    /// The io is delayed.
+    Latency(Duration),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultRule {
+    pub op: FaultOp,
+
+    /// The blocks the rule applies to, or all of them if None.
+    pub blocks: Option<Range<u64>>,
+    pub kind: FaultKind,
+
+    /// The percentage of the matching blocks that are faulty.
+    pub percent: u32,
+
+    /// The number of matching ios to let through before the first fault.
+    pub after: u64,
+
+    /// The most faults the rule injects, or no limit if None.
+    pub times: Option<u64>,
+}
+
+impl FaultRule {
+    pub fn new(op: FaultOp, blocks: Option<Range<u64>>, kind: FaultKind) -> FaultRule {
+        FaultRule {
+            op,
+            blocks,
+            kind,
+            percent: 100,
+            after: 0,
+            times: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaultScript {
+    pub seed: u64,
+    pub rules: Vec<FaultRule>,
+}
+
+//------------------------------------------
+
+fn parse_num<T: FromStr>(s: &str, what: &str) -> anyhow::Result<T> {
+    s.parse::<T>()
+        .map_err(|_| anyhow!("invalid {} '{}'", what, s))
+}
+
+fn parse_blocks(s: &str) -> anyhow::Result<Option<Range<u64>>> {
+    if s == "*" {
+        return Ok(None);
+    }
+
+    let r = match s.split_once('-') {
+        Some((begin, end)) => parse_num(begin, "block")?..parse_num(end, "block")?,
+        None => {
+            let b = parse_num(s, "block")?;
+            b..b + 1
+        }
+    };
+
+    if r.is_empty() {
+        return Err(anyhow!("empty block range '{}'", s));
+    }
+    Ok(Some(r))
+}
+
+fn parse_len(s: Option<&str>) -> anyhow::Result<usize> {
+    let s = s.ok_or_else(|| anyhow!("missing length"))?;
+    let len = parse_num(s, "length")?;
+    if len >= BLOCK_SIZE {
+        return Err(anyhow!("length {} isn't within a block", len));
+    }
+    Ok(len)
+}
+
+fn parse_rule(line: &str) -> anyhow::Result<FaultRule> {
+    let mut words = line.split_whitespace();
+    let mut next = |what| words.next().ok_or_else(|| anyhow!("missing {}", what));
+
+    let op = match next("operation")? {
+        "read" => FaultOp::Read,
+        "write" => FaultOp::Write,
+        "any" => FaultOp::Any,
+        op => return Err(anyhow!("unknown operation '{}'", op)),
+    };
+    let blocks = parse_blocks(next("blocks")?)?;
+
+    let kind = match next("fault")? {
+        "eio" => FaultKind::Eio,
+        "short" if op == FaultOp::Read => FaultKind::ShortRead(parse_len(words.next())?),
+        "torn" if op == FaultOp::Write => FaultKind::TornWrite(parse_len(words.next())?),
+        "short" => return Err(anyhow!("short only applies to reads")),
+        "torn" => return Err(anyhow!("torn only applies to writes")),
+        "latency" => {
+            let ms = words.next().ok_or_else(|| anyhow!("missing latency"))?;
+            FaultKind::Latency(Duration::from_millis(parse_num(ms, "latency")?))
+        }
+        fault => return Err(anyhow!("unknown fault '{}'", fault)),
+    };
+
+    let mut rule = FaultRule::new(op, blocks, kind);
+    for opt in words {
+        match opt.split_once('=') {
+            Some(("percent", n)) => {
+                rule.percent = parse_num(n, "percentage")?;
+                if rule.percent > 100 {
+                    return Err(anyhow!("invalid percentage '{}'", n));
+                }
+            }
+            Some(("after", n)) => rule.after = parse_num(n, "count")?,
+            Some(("times", n)) => rule.times = Some(parse_num(n, "count")?),
+            _ => return Err(anyhow!("unknown option '{}'", opt)),
+        }
+    }
+
+    Ok(rule)
+}
+
+impl FromStr for FaultScript {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<FaultScript> {
+        let mut script = FaultScript::default();
+
+        for (n, line) in s.lines().enumerate() {
+            let line = match line.split_once('#') {
+                Some((code, _comment)) => code,
+                None => line,
+            }
+            .trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let r = match line.strip_prefix("seed ") {
+                Some(seed) => parse_num(seed.trim(), "seed").map(|seed| script.seed = seed),
+                None => parse_rule(line).map(|rule| script.rules.push(rule)),
+            };
+            r.map_err(|e| anyhow!("fault script line {}: {}", n + 1, e))?;
+        }
+
+        Ok(script)
+    }
+}
+
+//------------------------------------------
+
+struct ActiveRule {
+    rule: FaultRule,
+    nr_matched: AtomicU64,
+    nr_fired: AtomicU64,
+}
+
+pub struct FaultEngine {
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    seed: u64,
+    rules: Vec<ActiveRule>,
+    nr_injected: AtomicU64,
+}
+
+// splitmix64, a cheap and well mixed hash of the seed, rule and block.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn eio() -> io::Error {
+    io::Error::from_raw_os_error(libc::EIO)
+}
+
+impl FaultEngine {
+    pub fn new(engine: Arc<dyn IoEngine + Send + Sync>, script: FaultScript) -> FaultEngine {
+        FaultEngine {
+            engine,
+            seed: script.seed,
+            rules: script
+                .rules
+                .into_iter()
+                .map(|rule| ActiveRule {
+                    rule,
+                    nr_matched: AtomicU64::new(0),
+                    nr_fired: AtomicU64::new(0),
+                })
+                .collect(),
+            nr_injected: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of faults injected so far.
+    pub fn nr_injected(&self) -> u64 {
+        self.nr_injected.load(Ordering::SeqCst)
+    }
+
+    fn selected(&self, index: usize, rule: &FaultRule, loc: u64) -> bool {
+        rule.percent >= 100 || mix(self.seed ^ mix(index as u64) ^ loc) % 100 < rule.percent as u64
+    }
+
+    // The faults to inject into an io, latency first.
+    fn faults(&self, op: FaultOp, loc: u64) -> Vec<FaultKind> {
+        let mut faults = Vec::new();
+
+        for (index, r) in self.rules.iter().enumerate() {
+            let rule = &r.rule;
+            if (rule.op != FaultOp::Any && rule.op != op)
+                || matches!(&rule.blocks, Some(bs) if !bs.contains(&loc))
+                || !self.selected(index, rule, loc)
+            {
+                continue;
+            }
+
+            if r.nr_matched.fetch_add(1, Ordering::SeqCst) < rule.after {
+                continue;
+            }
+
+            if let Some(times) = rule.times {
+                if r.nr_fired.fetch_add(1, Ordering::SeqCst) >= times {
+                    continue;
+                }
+            }
+
+            self.nr_injected.fetch_add(1, Ordering::SeqCst);
+            faults.push(rule.kind.clone());
+        }
+
+        faults.sort_by_key(|f| !matches!(f, FaultKind::Latency(_)));
+        faults
+    }
+
+    // Applies the faults to the result of a read.
+    fn read_fault(faults: &[FaultKind], b: Result<Block>) -> Result<Block> {
+        let b = b?;
+        for f in faults {
+            match f {
+                FaultKind::Latency(d) => thread::sleep(*d),
+                FaultKind::Eio => return Err(eio()),
+                FaultKind::ShortRead(len) => b.get_data()[*len..].fill(0),
+                FaultKind::TornWrite(_) => {}
+            }
+        }
+        Ok(b)
+    }
+
+    // The block to actually write, or an error if the write fails.
+    fn write_fault(&self, faults: &[FaultKind], b: &Block) -> Result<Option<Block>> {
+        let mut torn = None;
+        for f in faults {
+            match f {
+                FaultKind::Latency(d) => thread::sleep(*d),
+                FaultKind::Eio => return Err(eio()),
+                FaultKind::TornWrite(len) => {
+                    let old = self.engine.read(b.loc)?;
+                    old.get_data()[..*len].copy_from_slice(&b.get_data()[..*len]);
+                    torn = Some(old);
+                }
+                FaultKind::ShortRead(_) => {}
+            }
+        }
+        Ok(torn)
+    }
+}
+
+impl IoEngine for FaultEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.engine.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.engine.get_batch_size()
+    }
+
+    fn read(&self, loc: u64) -> Result<Block> {
+        // A read that's going to fail never reaches the engine.
+        let faults = self.faults(FaultOp::Read, loc);
+        if faults.contains(&FaultKind::Eio) {
+            return Self::read_fault(&faults, Ok(Block::new(loc)));
+        }
+        Self::read_fault(&faults, self.engine.read(loc))
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        let faults: Vec<Vec<FaultKind>> = blocks
+            .iter()
+            .map(|loc| self.faults(FaultOp::Read, *loc))
+            .collect();
+
+        Ok(self
+            .engine
+            .read_many(blocks)?
+            .into_iter()
+            .zip(faults.iter())
+            .map(|(b, faults)| Self::read_fault(faults, b))
+            .collect())
+    }
+
+    fn write(&self, b: &Block) -> Result<()> {
+        let faults = self.faults(FaultOp::Write, b.loc);
+        match self.write_fault(&faults, b)? {
+            Some(torn) => self.engine.write(&torn),
+            None => self.engine.write(b),
+        }
+    }
+
+    // Blocks that fail never reach the engine, the rest are written
+    // together so any coalescing still happens.
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        let mut results: Vec<Option<Result<()>>> = Vec::with_capacity(blocks.len());
+        let mut indexes = Vec::new();
+        let mut pass = Vec::new();
+
+        for (i, b) in blocks.iter().enumerate() {
+            let faults = self.faults(FaultOp::Write, b.loc);
+            match self.write_fault(&faults, b) {
+                Err(e) => results.push(Some(Err(e))),
+                Ok(torn) => {
+                    results.push(None);
+                    indexes.push(i);
+                    pass.push(torn.unwrap_or_else(|| b.clone()));
+                }
+            }
+        }
+
+        for (i, r) in indexes.into_iter().zip(self.engine.write_many(&pass)?) {
+            results[i] = Some(r);
+        }
+
+        Ok(results.into_iter().map(|r| r.unwrap()).collect())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thin::superblock::*;
+
+    fn mk_engine(script: &str) -> (tempfile::NamedTempFile, FaultEngine) {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        file.as_file().set_len(64 * BLOCK_SIZE as u64).unwrap();
+        let engine = SyncIoEngine::new_with(file.path(), 1, true, false).unwrap();
+        for loc in 0..64 {
+            let b = Block::new(loc);
+            b.get_data().fill(loc as u8 + 1);
+            engine.write(&b).unwrap();
+        }
+
+        let script = script.parse::<FaultScript>().unwrap();
+        (file, FaultEngine::new(Arc::new(engine), script))
+    }
+
+    fn is_eio<T>(r: &Result<T>) -> bool {
+        match r {
+            Err(e) => e.raw_os_error() == Some(libc::EIO),
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let script: FaultScript = "
+            seed 42
+            read 0 eio              # superblock
+            write 10-20 torn 512 percent=50
+            any * latency 3 after=2 times=1
+        "
+        .parse()
+        .unwrap();
+
+        assert_eq!(script.seed, 42);
+        assert_eq!(
+            script.rules,
+            vec![
+                FaultRule::new(FaultOp::Read, Some(0..1), FaultKind::Eio),
+                FaultRule {
+                    percent: 50,
+                    ..FaultRule::new(FaultOp::Write, Some(10..20), FaultKind::TornWrite(512))
+                },
+                FaultRule {
+                    after: 2,
+                    times: Some(1),
+                    ..FaultRule::new(
+                        FaultOp::Any,
+                        None,
+                        FaultKind::Latency(Duration::from_millis(3))
+                    )
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_bad_script() {
+        for s in &[
+            "seek 0 eio",
+            "read eio",
+            "read 5-5 eio",
+            "read * melt",
+            "write * short 10",
+            "read * torn 10",
+            "read * short 4096",
+            "read * eio percent=101",
+            "read * eio sometimes",
+            "seed x",
+        ] {
+            assert!(s.parse::<FaultScript>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_eio() {
+        let (_file, engine) = mk_engine("read 3-5 eio");
+        assert!(engine.read(2).is_ok());
+        assert!(is_eio(&engine.read(3)));
+        assert!(is_eio(&engine.read(4)));
+        assert!(engine.write(&Block::zeroed(3)).is_ok());
+
+        let rs = engine.read_many(&[2, 3, 5]).unwrap();
+        assert!(rs[0].is_ok() && is_eio(&rs[1]) && rs[2].is_ok());
+        assert_eq!(engine.nr_injected(), 3);
+    }
+
+    #[test]
+    fn test_failed_writes_leave_block() {
+        let (_file, engine) = mk_engine("write 7 eio");
+        let rs = engine
+            .write_many(&[Block::zeroed(6), Block::zeroed(7)])
+            .unwrap();
+        assert!(rs[0].is_ok() && is_eio(&rs[1]));
+        assert!(engine.read(6).unwrap().get_data().iter().all(|v| *v == 0));
+        assert!(engine.read(7).unwrap().get_data().iter().all(|v| *v == 8));
+    }
+
+    #[test]
+    fn test_short_read() {
+        let (_file, engine) = mk_engine("read 1 short 100");
+        let b = engine.read(1).unwrap();
+        assert!(b.get_data()[..100].iter().all(|v| *v == 2));
+        assert!(b.get_data()[100..].iter().all(|v| *v == 0));
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let (_file, engine) = mk_engine("write * torn 1024");
+        let b = Block::zeroed(9);
+        engine.write(&b).unwrap();
+
+        let b = engine.read(9).unwrap();
+        assert!(b.get_data()[..1024].iter().all(|v| *v == 0));
+        assert!(b.get_data()[1024..].iter().all(|v| *v == 10));
+    }
+
+    #[test]
+    fn test_after_and_times() {
+        let (_file, engine) = mk_engine("read * eio after=2 times=3");
+        let rs: Vec<bool> = (0..8).map(|loc| is_eio(&engine.read(loc))).collect();
+        assert_eq!(
+            rs,
+            vec![false, false, true, true, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn test_percent_is_decided_by_seed() {
+        let failing = |seed| {
+            let (_file, engine) = mk_engine(&format!("seed {}\nread * eio percent=25", seed));
+            let fs: Vec<u64> = (0..64).filter(|loc| engine.read(*loc).is_err()).collect();
+
+            // the same blocks fail every time they're read
+            for loc in 0..64 {
+                assert_eq!(engine.read(loc).is_err(), fs.contains(&loc));
+            }
+            fs
+        };
+
+        let fs = failing(1);
+        assert!(!fs.is_empty() && fs.len() < 32);
+        assert_eq!(fs, failing(1));
+        assert_ne!(fs, failing(2));
+    }
+
+    #[test]
+    fn test_short_superblock_read() {
+        let (_file, engine) = mk_engine("read 0 short 64");
+        let sb = Superblock {
+            flags: SuperblockFlags {
+                needs_check: false,
+                unknown: 0,
+            },
+            block: 0,
+            uuid: [0; UUID_SIZE],
+            version: 2,
+            time: 0,
+            transaction_id: 1,
+            metadata_snap: 0,
+            data_sm_root: vec![0u8; SPACE_MAP_ROOT_SIZE],
+            metadata_sm_root: vec![0u8; SPACE_MAP_ROOT_SIZE],
+            mapping_root: 1,
+            details_root: 2,
+            data_block_size: 128,
+            nr_metadata_blocks: 64,
+            compat_flags: 0,
+            compat_ro_flags: 0,
+            incompat_flags: 0,
+            backup_superblock: 0,
+            labels_root: 0,
+        };
+        write_superblock(&engine, SUPERBLOCK_LOCATION, &sb).unwrap();
+
+        let e = read_superblock(&engine, SUPERBLOCK_LOCATION).unwrap_err();
+        assert_eq!(e.to_string(), "bad checksum in superblock");
+    }
+}
+
+//------------------------------------------
@@ -1,36 +1,39 @@
-use nix::sys::stat;
-use nix::sys::stat::{FileStat, SFlag};
-use std::fs::{File, OpenOptions};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use std::fs::{File, FileType, OpenOptions};
 use std::io;
 use std::io::{Seek, Write};
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use tempfile::tempfile;
 
 //---------------------------------------
 
-fn test_bit(mode: u32, flag: SFlag) -> bool {
-    SFlag::from_bits_truncate(mode).contains(flag)
+// std uses the 64 bit stat calls, whereas nix's stat() fails with
+// EOVERFLOW on 32 bit hosts for files over 2G.
+fn file_type(path: &Path) -> io::Result<FileType> {
+    std::fs::metadata(path).map(|md| md.file_type())
 }
 
-pub fn is_file_or_blk_(info: FileStat) -> bool {
-    test_bit(info.st_mode, SFlag::S_IFBLK) || test_bit(info.st_mode, SFlag::S_IFREG)
+pub fn is_file_or_blk_(ft: FileType) -> bool {
+    ft.is_block_device() || ft.is_file()
 }
 
 pub fn file_exists(path: &Path) -> bool {
-    matches!(stat::stat(path), Ok(_))
+    std::fs::metadata(path).is_ok()
 }
 
 pub fn is_file_or_blk(path: &Path) -> bool {
-    match stat::stat(path) {
-        Ok(info) => is_file_or_blk_(info),
+    match file_type(path) {
+        Ok(ft) => is_file_or_blk_(ft),
         _ => false,
     }
 }
 
 pub fn is_file(path: &Path) -> bool {
-    match stat::stat(path) {
-        Ok(info) => test_bit(info.st_mode, SFlag::S_IFREG),
+    match file_type(path) {
+        Ok(ft) => ft.is_file(),
         _ => false,
     }
 }
@@ -41,6 +44,18 @@ const BLKGETSIZE64_CODE: u8 = 0x12;
 const BLKGETSIZE64_SEQ: u8 = 114;
 ioctl_read!(ioctl_blkgetsize64, BLKGETSIZE64_CODE, BLKGETSIZE64_SEQ, u64);
 
+// BLKSSZGET predates the size encoding in ioctl numbers.
+const BLKSSZGET_CODE: u8 = 0x12;
+const BLKSSZGET_SEQ: u8 = 104;
+ioctl_read_bad!(
+    ioctl_blksszget,
+    request_code_none!(BLKSSZGET_CODE, BLKSSZGET_SEQ),
+    libc::c_int
+);
+
+/// Regular files can be accessed at any sector.
+pub const DEFAULT_LOGICAL_BLOCK_SIZE: u64 = 512;
+
 pub fn fail<T>(msg: &str) -> io::Result<T> {
     let e = io::Error::new(io::ErrorKind::Other, msg);
     Err(e)
@@ -58,12 +73,28 @@ fn get_device_size(path: &Path) -> io::Result<u64> {
     }
 }
 
+fn get_device_logical_block_size(path: &Path) -> io::Result<u64> {
+    let file = File::open(path)?;
+    let fd = file.as_raw_fd();
+    let mut size: libc::c_int = 0;
+    unsafe {
+        match ioctl_blksszget(fd, &mut size) {
+            Ok(_) if size > 0 => Ok(size as u64),
+            _ => fail("BLKSSZGET ioctl failed"),
+        }
+    }
+}
+
+/// The size in bytes of a regular file or block device.  The size of
+/// a block device comes from the device itself, since stat reports
+/// zero for them.
 pub fn file_size(path: &Path) -> io::Result<u64> {
-    match stat::stat(path) {
-        Ok(info) => {
-            if test_bit(info.st_mode, SFlag::S_IFREG) {
-                Ok(info.st_size as u64)
-            } else if test_bit(info.st_mode, SFlag::S_IFBLK) {
+    match std::fs::metadata(path) {
+        Ok(md) => {
+            let ft = md.file_type();
+            if ft.is_file() {
+                Ok(md.len())
+            } else if ft.is_block_device() {
                 get_device_size(path)
             } else {
                 fail("Not a block device or regular file")
@@ -73,6 +104,78 @@ pub fn file_size(path: &Path) -> io::Result<u64> {
     }
 }
 
+/// The smallest unit, in bytes, that the device can address, eg, 4096
+/// for a 4Kn drive.  Any io to the device has to be aligned to this.
+pub fn logical_block_size(path: &Path) -> io::Result<u64> {
+    match file_type(path) {
+        Ok(ft) => {
+            if ft.is_file() {
+                Ok(DEFAULT_LOGICAL_BLOCK_SIZE)
+            } else if ft.is_block_device() {
+                get_device_logical_block_size(path)
+            } else {
+                fail("Not a block device or regular file")
+            }
+        }
+        _ => fail("stat failed"),
+    }
+}
+
+//---------------------------------------
+
+/// An advisory lock on a metadata file or device, released when
+/// dropped.  Writers hold it exclusively, so one tool can't modify
+/// metadata that another is reading or writing, eg, thin_restore
+/// racing thin_check.
+pub struct FileLock {
+    _file: File,
+}
+
+pub fn lock_file(path: &Path, exclusive: bool) -> io::Result<FileLock> {
+    let file = File::open(path)?;
+    let arg = if exclusive {
+        FlockArg::LockExclusiveNonblock
+    } else {
+        FlockArg::LockSharedNonblock
+    };
+
+    match flock(file.as_raw_fd(), arg) {
+        Ok(()) => Ok(FileLock { _file: file }),
+        Err(Errno::EWOULDBLOCK) => Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!(
+                "'{}' is in use by another tool, refusing to {} it",
+                path.display(),
+                if exclusive { "write" } else { "read" }
+            ),
+        )),
+        Err(e) => Err(io::Error::from(e)),
+    }
+}
+
+//---------------------------------------
+
+/// Deallocates a byte range of a regular file, leaving a hole that
+/// reads back as zeroes, without changing the file's size.  Filesystems
+/// that can't punch holes are left as they are, since the range is
+/// only ever freed to save space.
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    let r = unsafe { libc::fallocate64(file.as_raw_fd(), mode, offset as i64, len as i64) };
+    if r == 0 {
+        return Ok(());
+    }
+
+    match Errno::last() {
+        Errno::EOPNOTSUPP | Errno::ENOSYS => Ok(()),
+        e => Err(io::Error::from(e)),
+    }
+}
+
 //---------------------------------------
 
 fn set_size<W: Write + Seek>(w: &mut W, nr_bytes: u64) -> io::Result<()> {
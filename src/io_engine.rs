@@ -10,6 +10,7 @@ use std::ops::{Deref, DerefMut};
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
+use std::sync::atomic::{AtomicPtr, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 
 use crate::file_utils;
@@ -20,7 +21,83 @@ pub const BLOCK_SIZE: usize = 4096;
 pub const SECTOR_SHIFT: usize = 9;
 const ALIGN: usize = 4096;
 
-#[derive(Clone, Debug)]
+// The largest single write a SyncIoEngine will issue, in blocks.
+const MAX_COALESCED_BLOCKS: usize = 256;
+
+// Blocks are allocated and freed at a great rate when walking big trees,
+// so freed buffers are kept on a free list shared by all the engines,
+// rather than going back to the allocator.  The list is capped, so a burst
+// of blocks doesn't pin memory once it's over.
+const MAX_POOLED_BUFFERS: usize = 1024;
+
+struct BufferPool {
+    // Page aligned buffers of BLOCK_SIZE, held as addresses so the pool
+    // can be shared between threads.  A Mutex can't be built in a static
+    // on older compilers, so the list is allocated on first use, and
+    // never freed.
+    free: AtomicPtr<Mutex<Vec<usize>>>,
+}
+
+impl BufferPool {
+    const fn new() -> BufferPool {
+        BufferPool {
+            free: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+
+    fn free(&self) -> &Mutex<Vec<usize>> {
+        let mut ptr = self.free.load(Ordering::Acquire);
+        if ptr.is_null() {
+            let new = Box::into_raw(Box::new(Mutex::new(Vec::new())));
+            ptr = match self.free.compare_exchange(
+                std::ptr::null_mut(),
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => new,
+                Err(current) => {
+                    // Another thread got there first.
+                    drop(unsafe { Box::from_raw(new) });
+                    current
+                }
+            };
+        }
+
+        // Safe, the list is never freed.
+        unsafe { &*ptr }
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(BLOCK_SIZE, ALIGN).unwrap()
+    }
+
+    fn get(&self) -> *mut u8 {
+        if let Some(addr) = self.free().lock().unwrap().pop() {
+            return addr as *mut u8;
+        }
+
+        let ptr = unsafe { alloc(Self::layout()) };
+        assert!(!ptr.is_null(), "out of memory");
+        ptr
+    }
+
+    fn put(&self, ptr: *mut u8) {
+        let mut free = self.free().lock().unwrap();
+        if free.len() < MAX_POOLED_BUFFERS {
+            free.push(ptr as usize);
+        } else {
+            drop(free);
+            unsafe {
+                dealloc(ptr, Self::layout());
+            }
+        }
+    }
+}
+
+static BUFFER_POOL: BufferPool = BufferPool::new();
+
+#[derive(Debug)]
 pub struct Block {
     pub loc: u64,
     data: *mut u8,
@@ -28,12 +105,13 @@ pub struct Block {
 
 impl Block {
     // Creates a new block that corresponds to the given location.  The
-    // memory is not initialised.
+    // memory is not initialised, and may hold the contents of a block
+    // that has since been dropped.
     pub fn new(loc: u64) -> Block {
-        let layout = Layout::from_size_align(BLOCK_SIZE, ALIGN).unwrap();
-        let ptr = unsafe { alloc(layout) };
-        assert!(!ptr.is_null(), "out of memory");
-        Block { loc, data: ptr }
+        Block {
+            loc,
+            data: BUFFER_POOL.get(),
+        }
     }
 
     pub fn zeroed(loc: u64) -> Block {
@@ -53,12 +131,19 @@ impl Block {
     }
 }
 
+// A clone gets its own buffer; sharing one would hand it back to the
+// pool twice.
+impl Clone for Block {
+    fn clone(&self) -> Block {
+        let b = Block::new(self.loc);
+        b.get_data().copy_from_slice(self.get_data());
+        b
+    }
+}
+
 impl Drop for Block {
     fn drop(&mut self) {
-        let layout = Layout::from_size_align(BLOCK_SIZE, ALIGN).unwrap();
-        unsafe {
-            dealloc(self.data, layout);
-        }
+        BUFFER_POOL.put(self.data);
     }
 }
 
@@ -77,16 +162,50 @@ pub trait IoEngine {
     fn write(&self, block: &Block) -> Result<()>;
     // The whole io could fail, or individual blocks
     fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>>;
+
+    // Returns once the writes so far are on stable storage.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 fn get_nr_blocks(path: &Path) -> io::Result<u64> {
     Ok(file_utils::file_size(path)? / (BLOCK_SIZE as u64))
 }
 
+/// The error for reading a block past the end of the metadata, as
+/// happens when a device or image has been truncated.  It's still an
+/// UnexpectedEof, like a short read, but says which block is missing.
+pub fn past_the_end(loc: u64, nr_blocks: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!(
+            "block {} is past the end of the metadata device, which has {} blocks",
+            loc, nr_blocks
+        ),
+    )
+}
+
+// Engines opened without O_EXCL are looking at live metadata, via the
+// metadata snapshot, which the kernel is changing anyway, so there's
+// nothing to lock.
+fn lock_engine_file(
+    path: &Path,
+    writable: bool,
+    excl: bool,
+) -> io::Result<Option<file_utils::FileLock>> {
+    if excl {
+        Ok(Some(file_utils::lock_file(path, writable)?))
+    } else {
+        Ok(None)
+    }
+}
+
 //------------------------------------------
 
 pub struct SyncIoEngine {
     nr_blocks: u64,
+    _lock: Option<file_utils::FileLock>,
     files: Mutex<Vec<File>>,
     cvar: Condvar,
 }
@@ -152,6 +271,7 @@ impl SyncIoEngine {
         excl: bool,
     ) -> Result<SyncIoEngine> {
         let nr_blocks = get_nr_blocks(path)?; // check file mode eariler
+        let lock = lock_engine_file(path, writable, excl)?;
         let mut files = Vec::with_capacity(nr_files);
         for _n in 0..nr_files {
             files.push(SyncIoEngine::open_file(path, writable, excl)?);
@@ -159,6 +279,7 @@ impl SyncIoEngine {
 
         Ok(SyncIoEngine {
             nr_blocks,
+            _lock: lock,
             files: Mutex::new(files),
             cvar: Condvar::new(),
         })
@@ -192,6 +313,21 @@ impl SyncIoEngine {
         output.write_all(b.get_data())?;
         Ok(())
     }
+
+    // Writes blocks with consecutive locations in a single io.
+    fn write_run_(output: &mut File, run: &[&Block]) -> Result<()> {
+        if run.len() == 1 {
+            return SyncIoEngine::write_(output, run[0]);
+        }
+
+        let mut buf = Vec::with_capacity(run.len() * BLOCK_SIZE);
+        for b in run {
+            buf.extend_from_slice(b.get_data());
+        }
+        output.seek(io::SeekFrom::Start(run[0].loc * BLOCK_SIZE as u64))?;
+        output.write_all(&buf)?;
+        Ok(())
+    }
 }
 
 impl IoEngine for SyncIoEngine {
@@ -199,11 +335,15 @@ impl IoEngine for SyncIoEngine {
         self.nr_blocks
     }
 
+    // Large enough to give write_many() something to coalesce.
     fn get_batch_size(&self) -> usize {
-        1
+        MAX_COALESCED_BLOCKS
     }
 
     fn read(&self, loc: u64) -> Result<Block> {
+        if loc >= self.nr_blocks {
+            return Err(past_the_end(loc, self.nr_blocks));
+        }
         SyncIoEngine::read_(&mut self.get(), loc)
     }
 
@@ -211,7 +351,11 @@ impl IoEngine for SyncIoEngine {
         let mut input = self.get();
         let mut bs = Vec::new();
         for b in blocks {
-            bs.push(SyncIoEngine::read_(&mut input, *b));
+            if *b >= self.nr_blocks {
+                bs.push(Err(past_the_end(*b, self.nr_blocks)));
+            } else {
+                bs.push(SyncIoEngine::read_(&mut input, *b));
+            }
         }
         Ok(bs)
     }
@@ -220,13 +364,42 @@ impl IoEngine for SyncIoEngine {
         SyncIoEngine::write_(&mut self.get(), b)
     }
 
+    // Adjacent blocks are coalesced into larger writes, which matters a
+    // lot on spinning disks.  The results are still per block.
     fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        let mut order: Vec<usize> = (0..blocks.len()).collect();
+        order.sort_by_key(|i| blocks[*i].loc);
+
         let mut output = self.get();
-        let mut bs = Vec::new();
-        for b in blocks {
-            bs.push(SyncIoEngine::write_(&mut output, b));
+        let mut bs: Vec<Option<Result<()>>> = (0..blocks.len()).map(|_| None).collect();
+        let mut begin = 0;
+        while begin < order.len() {
+            let mut end = begin + 1;
+            while end < order.len()
+                && end - begin < MAX_COALESCED_BLOCKS
+                && blocks[order[end]].loc == blocks[order[end - 1]].loc + 1
+            {
+                end += 1;
+            }
+
+            let run: Vec<&Block> = order[begin..end].iter().map(|i| &blocks[*i]).collect();
+            let r = SyncIoEngine::write_run_(&mut output, &run);
+            for i in &order[begin..end] {
+                bs[*i] = Some(match &r {
+                    Ok(()) => Ok(()),
+                    Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                });
+            }
+            begin = end;
         }
-        Ok(bs)
+
+        Ok(bs.into_iter().map(|r| r.unwrap()).collect())
+    }
+
+    // The files are all opened on the same device, so syncing one
+    // syncs the writes made through any of them.
+    fn flush(&self) -> Result<()> {
+        self.get().sync_data()
     }
 }
 
@@ -238,6 +411,7 @@ pub struct AsyncIoEngine_ {
     nr_blocks: u64,
     fd: RawFd,
     input: Arc<File>,
+    lock: Option<Arc<file_utils::FileLock>>,
 }
 
 pub struct AsyncIoEngine {
@@ -256,6 +430,7 @@ impl AsyncIoEngine {
         excl: bool,
     ) -> Result<AsyncIoEngine> {
         let nr_blocks = get_nr_blocks(path)?; // check file mode earlier
+        let lock = lock_engine_file(path, writable, excl)?;
         let mut flags = libc::O_DIRECT;
         if excl {
             flags |= libc::O_EXCL;
@@ -273,6 +448,7 @@ impl AsyncIoEngine {
                 nr_blocks,
                 fd: input.as_raw_fd(),
                 input: Arc::new(input),
+                lock: lock.map(Arc::new),
             }),
         })
     }
@@ -375,7 +551,6 @@ impl AsyncIoEngine {
 impl Clone for AsyncIoEngine {
     fn clone(&self) -> AsyncIoEngine {
         let inner = self.inner.lock().unwrap();
-        eprintln!("in clone, queue_len = {}", inner.queue_len);
         AsyncIoEngine {
             inner: Mutex::new(AsyncIoEngine_ {
                 queue_len: inner.queue_len,
@@ -383,6 +558,7 @@ impl Clone for AsyncIoEngine {
                 nr_blocks: inner.nr_blocks,
                 fd: inner.fd,
                 input: inner.input.clone(),
+                lock: inner.lock.clone(),
             }),
         }
     }
@@ -400,6 +576,9 @@ impl IoEngine for AsyncIoEngine {
 
     fn read(&self, b: u64) -> Result<Block> {
         let mut inner = self.inner.lock().unwrap();
+        if b >= inner.nr_blocks {
+            return Err(past_the_end(b, inner.nr_blocks));
+        }
         let fd = types::Fd(inner.input.as_raw_fd());
         let b = Block::new(b);
         let read_e = opcode::Read::new(fd, b.data, BLOCK_SIZE as u32)
@@ -423,7 +602,7 @@ impl IoEngine for AsyncIoEngine {
             let error = Error::from_raw_os_error(-r);
             Err(error)
         } else if r != BLOCK_SIZE as i32 {
-            Err(Error::new(ErrorKind::UnexpectedEof, "short write"))
+            Err(Error::new(ErrorKind::UnexpectedEof, "short read"))
         } else {
             Ok(b)
         }
@@ -432,16 +611,31 @@ impl IoEngine for AsyncIoEngine {
     fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
         let inner = self.inner.lock().unwrap();
         let queue_len = inner.queue_len as usize;
+        let nr_blocks = inner.nr_blocks;
         drop(inner);
 
         let mut results = Vec::new();
         for cs in blocks.chunks(queue_len) {
             let mut bs = Vec::new();
             for b in cs {
-                bs.push(Block::new(*b));
+                if *b < nr_blocks {
+                    bs.push(Block::new(*b));
+                }
             }
 
-            results.append(&mut self.read_many_(bs)?);
+            // Blocks past the end aren't submitted.
+            let mut rs = if bs.is_empty() {
+                Vec::new().into_iter()
+            } else {
+                self.read_many_(bs)?.into_iter()
+            };
+            for b in cs {
+                if *b < nr_blocks {
+                    results.push(rs.next().unwrap());
+                } else {
+                    results.push(Err(past_the_end(*b, nr_blocks)));
+                }
+            }
         }
 
         Ok(results)
@@ -492,6 +686,82 @@ impl IoEngine for AsyncIoEngine {
 
         Ok(results)
     }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.lock().unwrap().input.sync_data()
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pooled_buffers() {
+        let bs: Vec<Block> = (0..(MAX_POOLED_BUFFERS as u64 * 2))
+            .map(Block::new)
+            .collect();
+        for b in &bs {
+            assert_eq!(b.data as usize % ALIGN, 0);
+        }
+        drop(bs);
+        assert!(BUFFER_POOL.free().lock().unwrap().len() <= MAX_POOLED_BUFFERS);
+
+        // clones must not share a buffer
+        let a = Block::zeroed(1);
+        let b = a.clone();
+        a.get_data()[0] = 1;
+        assert_eq!(b.get_data()[0], 0);
+        assert_ne!(a.data, b.data);
+    }
+
+    #[test]
+    fn test_coalesced_writes() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(64 * BLOCK_SIZE as u64)?;
+        let engine = SyncIoEngine::new_with(file.path(), 1, true, false)?;
+
+        // out of order, with a gap
+        let locs = [5u64, 3, 4, 10, 2, 11];
+        let blocks: Vec<Block> = locs
+            .iter()
+            .map(|loc| {
+                let b = Block::new(*loc);
+                b.get_data().fill(*loc as u8);
+                b
+            })
+            .collect();
+        let results = engine.write_many(&blocks)?;
+        assert_eq!(results.len(), locs.len());
+        assert!(results.iter().all(|r| r.is_ok()));
+
+        for loc in 0..16 {
+            let b = engine.read(loc)?;
+            let expected = if locs.contains(&loc) { loc as u8 } else { 0 };
+            assert!(b.get_data().iter().all(|v| *v == expected));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_past_the_end() -> Result<()> {
+        // half a block at the end, as a truncated copy would leave
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(4 * BLOCK_SIZE as u64 + 100)?;
+        let engine = SyncIoEngine::new_with(file.path(), 1, false, false)?;
+        assert_eq!(engine.get_nr_blocks(), 4);
+
+        let e = engine.read(4).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(e.to_string().contains("block 4 is past the end"));
+
+        let rs = engine.read_many(&[3, 4, 2, 9])?;
+        let ok: Vec<bool> = rs.iter().map(|r| r.is_ok()).collect();
+        assert_eq!(ok, vec![true, false, true, false]);
+        Ok(())
+    }
 }
 
 //------------------------------------------
@@ -0,0 +1,332 @@
+use anyhow::anyhow;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Result, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::io_engine::*;
+
+//------------------------------------------
+
+// A write ahead journal of the blocks a repair changes.  Before a block
+// is first written its original contents are appended to the journal,
+// and the journal synced, so however the repair ends the journal holds
+// everything needed to undo it.
+//
+// The journal starts with a header:
+//
+//   magic        8 bytes
+//   version      u32
+//   padding      u32
+//   nr_blocks    u64, the size of the metadata device
+//
+// followed by a record for each block:
+//
+//   loc          u64
+//   checksum     u32, crc32c of the data
+//   padding      u32
+//   data         BLOCK_SIZE bytes
+
+const MAGIC: &[u8; 8] = b"thinpjnl";
+const VERSION: u32 = 1;
+const RECORD_HEADER_SIZE: usize = 16;
+
+struct Journal {
+    file: File,
+    recorded: HashSet<u64>,
+}
+
+/// An IoEngine that journals the original contents of every block
+/// before it's overwritten.  rollback() undoes the writes.
+pub struct JournalEngine {
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    journal: Mutex<Journal>,
+}
+
+impl JournalEngine {
+    /// Creates the journal, which mustn't already exist, since it could
+    /// be all that's left of an earlier repair.
+    pub fn new(engine: Arc<dyn IoEngine + Send + Sync>, path: &Path) -> Result<JournalEngine> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(MAGIC)?;
+        file.write_u32::<LittleEndian>(VERSION)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u64::<LittleEndian>(engine.get_nr_blocks())?;
+        file.sync_all()?;
+
+        Ok(JournalEngine {
+            engine,
+            journal: Mutex::new(Journal {
+                file,
+                recorded: HashSet::new(),
+            }),
+        })
+    }
+
+    // Journals the blocks not already in the journal.  Nothing is
+    // written to the engine until this returns.
+    fn record(&self, locs: &[u64]) -> Result<()> {
+        let mut journal = self.journal.lock().unwrap();
+        let mut new: Vec<u64> = locs
+            .iter()
+            .filter(|loc| !journal.recorded.contains(loc))
+            .copied()
+            .collect();
+        new.sort_unstable();
+        new.dedup();
+        if new.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = Vec::with_capacity(new.len() * (RECORD_HEADER_SIZE + BLOCK_SIZE));
+        for (loc, b) in new.iter().zip(self.engine.read_many(&new)?) {
+            let b = b?;
+            buf.write_u64::<LittleEndian>(*loc)?;
+            buf.write_u32::<LittleEndian>(crc32c::crc32c(b.get_data()))?;
+            buf.write_u32::<LittleEndian>(0)?;
+            buf.write_all(b.get_data())?;
+        }
+        journal.file.write_all(&buf)?;
+        journal.file.sync_data()?;
+        journal.recorded.extend(new);
+        Ok(())
+    }
+}
+
+impl IoEngine for JournalEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.engine.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.engine.get_batch_size()
+    }
+
+    fn read(&self, loc: u64) -> Result<Block> {
+        self.engine.read(loc)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        self.engine.read_many(blocks)
+    }
+
+    fn write(&self, b: &Block) -> Result<()> {
+        self.record(&[b.loc])?;
+        self.engine.write(b)
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        let locs: Vec<u64> = blocks.iter().map(|b| b.loc).collect();
+        self.record(&locs)?;
+        self.engine.write_many(blocks)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+}
+
+//------------------------------------------
+
+// Reads a record, or None at the end of the journal.  A final record cut
+// short, or garbled, by a crash is also taken as the end, since the block
+// it was for can't have been written.
+fn read_record(r: &mut BufReader<File>) -> anyhow::Result<Option<Block>> {
+    let mut header = [0u8; RECORD_HEADER_SIZE];
+    match r.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut header = &header[..];
+    let loc = header.read_u64::<LittleEndian>()?;
+    let csum = header.read_u32::<LittleEndian>()?;
+
+    let b = Block::new(loc);
+    match r.read_exact(b.get_data()) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    if crc32c::crc32c(b.get_data()) != csum {
+        if r.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        return Err(anyhow!("journal record for block {} is corrupt", loc));
+    }
+    Ok(Some(b))
+}
+
+/// Writes back the original contents of every block in the journal,
+/// undoing the repair that made it.  Returns the number of blocks
+/// restored.
+pub fn rollback(engine: &dyn IoEngine, path: &Path) -> anyhow::Result<u64> {
+    let mut r = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)
+        .map_err(|_| anyhow!("{} isn't a repair journal", path.display()))?;
+    if &magic != MAGIC {
+        return Err(anyhow!("{} isn't a repair journal", path.display()));
+    }
+    let version = r.read_u32::<LittleEndian>()?;
+    if version != VERSION {
+        return Err(anyhow!("unsupported journal version {}", version));
+    }
+    let _padding = r.read_u32::<LittleEndian>()?;
+    let nr_blocks = r.read_u64::<LittleEndian>()?;
+    if nr_blocks != engine.get_nr_blocks() {
+        return Err(anyhow!(
+            "the journal is for metadata of {} blocks, not {}",
+            nr_blocks,
+            engine.get_nr_blocks()
+        ));
+    }
+
+    // Every record is checked before any is written back.
+    let mut blocks = Vec::new();
+    while let Some(b) = read_record(&mut r)? {
+        if b.loc >= nr_blocks {
+            return Err(anyhow!(
+                "journal record for block {} is out of range",
+                b.loc
+            ));
+        }
+        blocks.push(b);
+    }
+
+    for chunk in blocks.chunks(engine.get_batch_size()) {
+        for r in engine.write_many(chunk)? {
+            r?;
+        }
+    }
+    Ok(blocks.len() as u64)
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_engine(dir: &Path, nr_blocks: u64) -> Arc<dyn IoEngine + Send + Sync> {
+        let path = dir.join("md.bin");
+        let file = File::create(&path).unwrap();
+        file.set_len(nr_blocks * BLOCK_SIZE as u64).unwrap();
+        let engine = SyncIoEngine::new(&path, 1, true).unwrap();
+        for loc in 0..nr_blocks {
+            let b = Block::new(loc);
+            b.get_data().fill(loc as u8);
+            engine.write(&b).unwrap();
+        }
+        Arc::new(engine)
+    }
+
+    fn filled(loc: u64, byte: u8) -> Block {
+        let b = Block::new(loc);
+        b.get_data().fill(byte);
+        b
+    }
+
+    fn contents(engine: &dyn IoEngine, loc: u64) -> u8 {
+        let b = engine.read(loc).unwrap();
+        let data = b.get_data();
+        assert!(data.iter().all(|byte| *byte == data[0]));
+        data[0]
+    }
+
+    #[test]
+    fn test_rollback_restores_originals() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let engine = mk_engine(dir.path(), 16);
+        let journal = dir.path().join("journal");
+
+        let je = JournalEngine::new(engine.clone(), &journal)?;
+        je.write(&filled(3, 0xaa))?;
+        je.write(&filled(3, 0xbb))?;
+        je.write_many(&[filled(5, 0xcc), filled(9, 0xdd)])?;
+        drop(je);
+        assert_eq!(contents(engine.as_ref(), 3), 0xbb);
+
+        // Block 3 is only journalled the first time.
+        assert_eq!(rollback(engine.as_ref(), &journal)?, 3);
+        for loc in 0..16 {
+            assert_eq!(contents(engine.as_ref(), loc), loc as u8);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_record_is_ignored() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let engine = mk_engine(dir.path(), 16);
+        let journal = dir.path().join("journal");
+
+        let je = JournalEngine::new(engine.clone(), &journal)?;
+        je.write_many(&[filled(1, 0xaa), filled(2, 0xaa)])?;
+        drop(je);
+
+        let len = std::fs::metadata(&journal)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&journal)?
+            .set_len(len - 100)?;
+
+        assert_eq!(rollback(engine.as_ref(), &journal)?, 1);
+        assert_eq!(contents(engine.as_ref(), 1), 1);
+        assert_eq!(contents(engine.as_ref(), 2), 0xaa);
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_record_is_an_error() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let engine = mk_engine(dir.path(), 16);
+        let journal = dir.path().join("journal");
+
+        let je = JournalEngine::new(engine.clone(), &journal)?;
+        je.write_many(&[filled(1, 0xaa), filled(2, 0xaa)])?;
+        drop(je);
+
+        // Garble the data of the first record.
+        let mut bytes = std::fs::read(&journal)?;
+        bytes[24 + RECORD_HEADER_SIZE + 10] ^= 0xff;
+        std::fs::write(&journal, bytes)?;
+
+        assert!(rollback(engine.as_ref(), &journal).is_err());
+        assert_eq!(contents(engine.as_ref(), 1), 0xaa);
+        Ok(())
+    }
+
+    #[test]
+    fn test_journal_is_never_overwritten() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let engine = mk_engine(dir.path(), 4);
+        let journal = dir.path().join("journal");
+        JournalEngine::new(engine.clone(), &journal)?;
+        assert!(JournalEngine::new(engine, &journal).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback_checks_the_device() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let engine = mk_engine(dir.path(), 4);
+        let journal = dir.path().join("journal");
+        JournalEngine::new(engine, &journal)?;
+
+        let other = tempfile::tempdir()?;
+        let engine = mk_engine(other.path(), 8);
+        assert!(rollback(engine.as_ref(), &journal).is_err());
+
+        std::fs::write(&journal, b"not a journal")?;
+        assert!(rollback(engine.as_ref(), &journal).is_err());
+        Ok(())
+    }
+}
+
+//------------------------------------------
@@ -15,18 +15,29 @@ extern crate quickcheck;
 #[cfg(test)]
 extern crate quickcheck_macros;
 
+pub mod block_cache;
 pub mod cache;
 pub mod checksum;
+pub mod chrome_trace;
 pub mod commands;
+pub mod config;
+pub mod corpus;
 pub mod era;
+pub mod fault_engine;
 pub mod file_utils;
 pub mod io_engine;
+pub mod journal;
+pub mod log_file;
 pub mod math;
+pub mod memory;
+pub mod metadata_type;
 pub mod pack;
 pub mod pdata;
+pub mod policy;
 pub mod report;
 pub mod shrink;
 pub mod thin;
+pub mod transaction;
 pub mod units;
 pub mod version;
 pub mod write_batcher;
@@ -0,0 +1,98 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+//------------------------------------------
+
+/// A log that's only ever appended to, and that follows the path if the
+/// file is rotated away, eg, by logrotate, so each run carries on in the
+/// current log.  A log truncated in place is appended to from its new
+/// end, since it's opened with O_APPEND.
+pub struct LogFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl LogFile {
+    pub fn open(path: &Path) -> io::Result<LogFile> {
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            file: Mutex::new(open_append(path)?),
+        })
+    }
+
+    // Has the path been moved, or removed, since the file was opened?
+    fn rotated(&self, file: &File) -> io::Result<bool> {
+        let current = match std::fs::metadata(&self.path) {
+            Ok(md) => md,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        let md = file.metadata()?;
+        Ok(current.dev() != md.dev() || current.ino() != md.ino())
+    }
+}
+
+// Each line is written with a single call, which O_APPEND keeps whole
+// even if other processes are writing to the same log.
+impl Write for &LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self.file.lock().unwrap();
+        if self.rotated(&file)? {
+            *file = open_append(&self.path)?;
+        }
+        file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.lock().unwrap().flush()
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_appends() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("tool.log");
+        std::fs::write(&path, "earlier run\n")?;
+
+        let log = LogFile::open(&path)?;
+        (&log).write_all(b"this run\n")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "earlier run\nthis run\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_follows_rotation() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("tool.log");
+        let rotated = dir.path().join("tool.log.1");
+
+        let log = LogFile::open(&path)?;
+        (&log).write_all(b"one\n")?;
+        std::fs::rename(&path, &rotated)?;
+        (&log).write_all(b"two\n")?;
+
+        // Truncated in place, as logrotate's copytruncate does.
+        std::fs::write(&path, "")?;
+        (&log).write_all(b"three\n")?;
+
+        assert_eq!(std::fs::read_to_string(&rotated)?, "one\n");
+        assert_eq!(std::fs::read_to_string(&path)?, "three\n");
+        Ok(())
+    }
+}
+
+//------------------------------------------
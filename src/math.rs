@@ -1,4 +1,5 @@
 use std::cmp::PartialEq;
+use std::convert::TryFrom;
 use std::ops::{Add, Div, Rem};
 
 //-----------------------------------------
@@ -22,6 +23,14 @@ pub fn div_down<T: Integer>(v: T, divisor: T) -> T {
     v / divisor
 }
 
+/// Converts a block number, or count, to an index into an in core
+/// structure.  usize is only 32 bits on some hosts, eg, armv7, where a
+/// cast would wrap large values onto small ones, so this saturates
+/// instead and the lookup fails.
+pub fn to_index(v: u64) -> usize {
+    usize::try_from(v).unwrap_or(usize::MAX)
+}
+
 //-----------------------------------------
 
 impl Integer for usize {
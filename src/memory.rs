@@ -0,0 +1,241 @@
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::io_engine::BLOCK_SIZE;
+
+//------------------------------------------
+
+pub(crate) struct Budget {
+    // Zero means no limit.
+    max: AtomicU64,
+    claimed: AtomicU64,
+}
+
+// The budget for the process.  Tests make their own, so they don't
+// disturb each other.
+static BUDGET: Budget = Budget::new();
+
+fn fmt_size(bytes: u64) -> String {
+    if bytes >= 1 << 20 {
+        format!("{}MiB", bytes >> 20)
+    } else {
+        format!("{}KiB", std::cmp::max(1, bytes >> 10))
+    }
+}
+
+impl Budget {
+    pub(crate) const fn new() -> Budget {
+        Budget {
+            max: AtomicU64::new(0),
+            claimed: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn set_max(&self, limit: Option<u64>) {
+        self.max.store(limit.unwrap_or(0), Ordering::SeqCst);
+        self.claimed.store(0, Ordering::SeqCst);
+    }
+
+    fn max(&self) -> Option<u64> {
+        match self.max.load(Ordering::SeqCst) {
+            0 => None,
+            n => Some(n),
+        }
+    }
+
+    fn remaining(&self) -> Option<u64> {
+        self.max()
+            .map(|max| max.saturating_sub(self.claimed.load(Ordering::SeqCst)))
+    }
+
+    fn fits(&self, bytes: u64) -> bool {
+        match self.remaining() {
+            None => true,
+            Some(remaining) => bytes <= remaining,
+        }
+    }
+
+    fn release(&self, bytes: u64) {
+        // The budget may have been reset since the claim was made.
+        let _ = self
+            .claimed
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |claimed| {
+                Some(claimed.saturating_sub(bytes))
+            });
+    }
+
+    fn new_claim(&'static self, bytes: u64) -> Claim {
+        self.claimed.fetch_add(bytes, Ordering::SeqCst);
+        Claim {
+            budget: self,
+            bytes,
+        }
+    }
+
+    pub(crate) fn claim(&'static self, bytes: u64, what: &str) -> Result<Claim> {
+        // Only possible on 32 bit hosts, with multi terabyte devices.
+        if usize::try_from(bytes).is_err() {
+            return Err(anyhow!(
+                "the {} needs {}, which is more than this host can address",
+                what,
+                fmt_size(bytes)
+            ));
+        }
+
+        if let Some(max) = self.max() {
+            if !self.fits(bytes) {
+                return Err(anyhow!(
+                    "the {} needs {}, which is more than remains of the {} memory limit",
+                    what,
+                    fmt_size(bytes),
+                    fmt_size(max)
+                ));
+            }
+        }
+        Ok(self.new_claim(bytes))
+    }
+
+    pub(crate) fn claim_up_to(&'static self, wanted: u64, min: u64) -> Claim {
+        let bytes = match self.remaining() {
+            None => wanted,
+            Some(bytes) => std::cmp::max(min, std::cmp::min(wanted, bytes / 4)),
+        };
+        self.new_claim(bytes)
+    }
+}
+
+/// A claim on the memory budget, which is given back when it's dropped.
+/// It should live as long as whatever it was claimed for.
+#[must_use]
+pub struct Claim {
+    budget: &'static Budget,
+    bytes: u64,
+}
+
+impl Claim {
+    /// The amount claimed, which may be less than was wanted.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Claims more, if it's within the budget.
+    pub fn try_grow(&mut self, bytes: u64) -> bool {
+        if !self.budget.fits(bytes) {
+            return false;
+        }
+        self.grow(bytes);
+        true
+    }
+
+    /// Claims more, whatever the budget, for memory that's already in
+    /// use.
+    pub fn grow(&mut self, bytes: u64) {
+        self.budget.claimed.fetch_add(bytes, Ordering::SeqCst);
+        self.bytes += bytes;
+    }
+
+    /// Gives back some of the claim.
+    pub fn shrink(&mut self, bytes: u64) {
+        let bytes = std::cmp::min(bytes, self.bytes);
+        self.budget.release(bytes);
+        self.bytes -= bytes;
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+//------------------------------------------
+
+/// Sets the memory budget for the process, from --max-memory or the
+/// config file.  The big allocations, caches, queues and in core space
+/// maps, are claimed against it; small ones aren't tracked.
+pub fn set_max_memory(limit: Option<u64>) {
+    BUDGET.set_max(limit);
+}
+
+pub fn max_memory() -> Option<u64> {
+    BUDGET.max()
+}
+
+/// Claims memory that the tool can't work without.  Fails, rather than
+/// letting the process be OOM killed part way through, if it would take
+/// the total over the budget.
+pub fn claim(bytes: u64, what: &str) -> Result<Claim> {
+    BUDGET.claim(bytes, what)
+}
+
+/// Claims up to `wanted` bytes, for something that can get by with
+/// less, eg, by going back to the metadata for what it can't hold.  At
+/// most a quarter of what remains is used, leaving the rest for the space
+/// maps, and the result is never below `min`.
+pub fn claim_up_to(wanted: u64, min: u64) -> Claim {
+    BUDGET.claim_up_to(wanted, min)
+}
+
+/// Claims space for a cache or queue of up to `wanted` blocks, as
+/// claim_up_to(), returning the number of blocks.
+pub fn claim_blocks(wanted: usize, min: usize) -> (usize, Claim) {
+    let claim = claim_up_to((wanted * BLOCK_SIZE) as u64, (min * BLOCK_SIZE) as u64);
+    ((claim.bytes() / BLOCK_SIZE as u64) as usize, claim)
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+pub(crate) fn test_budget(limit: Option<u64>) -> &'static Budget {
+    let budget: &'static Budget = Box::leak(Box::new(Budget::new()));
+    budget.set_max(limit);
+    budget
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited() {
+        let budget = test_budget(None);
+        assert!(budget.claim(u64::MAX / 2, "unlimited").is_ok());
+        assert_eq!(budget.claim_up_to(100, 1).bytes(), 100);
+    }
+
+    #[test]
+    fn test_limited() {
+        let budget = test_budget(Some(1 << 20));
+        let cache = budget.claim_up_to(4 << 20, 32 << 10);
+        assert_eq!(cache.bytes(), 256 << 10);
+        let sm = budget.claim(512 << 10, "space map");
+        assert!(sm.is_ok());
+        assert!(budget.claim(512 << 10, "space map").is_err());
+        assert_eq!(budget.claim_up_to(4 << 20, 32 << 10).bytes(), 64 << 10);
+        assert_eq!(budget.claim_up_to(4 << 20, 128 << 10).bytes(), 128 << 10);
+
+        // dropped claims are given back
+        drop(sm);
+        assert!(budget.claim(512 << 10, "space map").is_ok());
+    }
+
+    #[test]
+    fn test_grow_and_shrink() {
+        let budget = test_budget(Some(1 << 20));
+        let mut c = budget.claim_up_to(1 << 20, 0);
+        assert_eq!(c.bytes(), 256 << 10);
+        assert!(!c.try_grow(1 << 20));
+        assert!(c.try_grow(512 << 10));
+        assert!(!c.try_grow(512 << 10));
+        c.shrink(512 << 10);
+        assert!(c.try_grow(512 << 10));
+
+        c.grow(1 << 20);
+        assert_eq!(budget.remaining(), Some(0));
+        drop(c);
+        assert_eq!(budget.remaining(), Some(1 << 20));
+    }
+}
+
+//------------------------------------------
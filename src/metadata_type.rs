@@ -0,0 +1,137 @@
+use anyhow::{anyhow, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cache;
+use crate::checksum::{metadata_block_type, BT};
+use crate::era;
+use crate::io_engine::IoEngine;
+use crate::thin;
+
+//------------------------------------------
+
+// Thin, cache and era superblocks all start with a checksum, flags,
+// their own location and a uuid, followed by the magic.
+const MAGIC_OFFSET: usize = 32;
+
+/// The kinds of metadata the tools handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataType {
+    Thin,
+    Cache,
+    Era,
+}
+
+impl FromStr for MetadataType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "thin" => Ok(MetadataType::Thin),
+            "cache" => Ok(MetadataType::Cache),
+            "era" => Ok(MetadataType::Era),
+            _ => Err(anyhow!("unknown metadata type '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for MetadataType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetadataType::Thin => write!(f, "thin"),
+            MetadataType::Cache => write!(f, "cache"),
+            MetadataType::Era => write!(f, "era"),
+        }
+    }
+}
+
+/// Identifies the metadata a superblock belongs to.  The checksum is
+/// the surest guide, but a damaged superblock is still identified by
+/// its magic, so it can be handed to the right tool to report on.
+pub fn sniff_superblock(data: &[u8]) -> Option<MetadataType> {
+    match metadata_block_type(data) {
+        BT::THIN_SUPERBLOCK => return Some(MetadataType::Thin),
+        BT::CACHE_SUPERBLOCK => return Some(MetadataType::Cache),
+        BT::ERA_SUPERBLOCK => return Some(MetadataType::Era),
+        _ => {}
+    }
+
+    if data.len() < MAGIC_OFFSET + 8 {
+        return None;
+    }
+    match LittleEndian::read_u64(&data[MAGIC_OFFSET..]) {
+        thin::superblock::MAGIC => Some(MetadataType::Thin),
+        cache::superblock::MAGIC => Some(MetadataType::Cache),
+        era::superblock::MAGIC => Some(MetadataType::Era),
+        _ => None,
+    }
+}
+
+/// Reads the superblock, in block 0 for every kind of metadata, to
+/// find out which kind the engine holds.
+pub fn detect_metadata_type(engine: &dyn IoEngine) -> Result<Option<MetadataType>> {
+    let b = engine.read(0)?;
+    Ok(sniff_superblock(b.get_data()))
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::write_checksum;
+    use crate::io_engine::BLOCK_SIZE;
+
+    fn mk_superblock(magic: u64) -> Vec<u8> {
+        let mut data = vec![0u8; BLOCK_SIZE];
+        LittleEndian::write_u64(&mut data[MAGIC_OFFSET..], magic);
+        data
+    }
+
+    #[test]
+    fn test_sniff_checksummed() -> Result<()> {
+        for (magic, kind, t) in [
+            (
+                thin::superblock::MAGIC,
+                BT::THIN_SUPERBLOCK,
+                MetadataType::Thin,
+            ),
+            (
+                cache::superblock::MAGIC,
+                BT::CACHE_SUPERBLOCK,
+                MetadataType::Cache,
+            ),
+            (
+                era::superblock::MAGIC,
+                BT::ERA_SUPERBLOCK,
+                MetadataType::Era,
+            ),
+        ] {
+            let mut data = mk_superblock(magic);
+            write_checksum(&mut data, kind)?;
+            assert_eq!(sniff_superblock(&data), Some(t));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_sniff_by_magic() {
+        // no valid checksum
+        let data = mk_superblock(cache::superblock::MAGIC);
+        assert_eq!(sniff_superblock(&data), Some(MetadataType::Cache));
+
+        assert_eq!(sniff_superblock(&mk_superblock(12345)), None);
+        assert_eq!(sniff_superblock(&[0u8; 16]), None);
+    }
+
+    #[test]
+    fn test_parse_type() {
+        for t in [MetadataType::Thin, MetadataType::Cache, MetadataType::Era] {
+            assert_eq!(t.to_string().parse::<MetadataType>().unwrap(), t);
+        }
+        assert!("vdo".parse::<MetadataType>().is_err());
+    }
+}
+
+//------------------------------------------
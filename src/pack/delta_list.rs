@@ -26,7 +26,10 @@ pub fn to_delta(ns: &[u64]) -> Vec<Delta> {
                 Less => {
                     let delta = base - n;
                     let mut count = 1;
-                    while i < ns.len() && (ns[i] + (count * delta) == base) {
+                    // checked, since a far larger ns[i] would overflow
+                    while i < ns.len()
+                        && delta.checked_mul(count).and_then(|d| ns[i].checked_add(d)) == Some(base)
+                    {
                         i += 1;
                         count += 1;
                     }
@@ -46,7 +49,10 @@ pub fn to_delta(ns: &[u64]) -> Vec<Delta> {
                 Greater => {
                     let delta = n - base;
                     let mut count = 1;
-                    while i < ns.len() && (ns[i] == (base + (count * delta))) {
+                    // checked, since the next step may pass u64::MAX
+                    while i < ns.len()
+                        && delta.checked_mul(count).and_then(|d| base.checked_add(d)) == Some(ns[i])
+                    {
                         i += 1;
                         count += 1;
                     }
@@ -157,6 +163,31 @@ mod tests {
                     Const { count: 3 },
                 ],
             ),
+            TestCase(
+                vec![10, 5, u64::MAX],
+                vec![
+                    Base { n: 10 },
+                    Neg { delta: 5, count: 1 },
+                    Pos {
+                        delta: u64::MAX - 5,
+                        count: 1,
+                    },
+                ],
+            ),
+            TestCase(
+                vec![1, u64::MAX - 1, 5],
+                vec![
+                    Base { n: 1 },
+                    Pos {
+                        delta: u64::MAX - 2,
+                        count: 1,
+                    },
+                    Neg {
+                        delta: u64::MAX - 6,
+                        count: 1,
+                    },
+                ],
+            ),
         ];
 
         for t in &cases {
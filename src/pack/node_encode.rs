@@ -115,4 +115,8 @@ pub fn pack_array<W: Write>(w: &mut W, bytes: &[u8]) -> PResult<()> {
     io_to_pr(pack_literal(w, bytes))
 }
 
+pub fn pack_device_labels<W: Write>(w: &mut W, bytes: &[u8]) -> PResult<()> {
+    io_to_pr(pack_literal(w, bytes))
+}
+
 //-------------------------------------
@@ -12,6 +12,7 @@ use std::{
     io::Write,
     ops::DerefMut,
     path::Path,
+    str::FromStr,
     sync::{Arc, Mutex},
     thread::spawn,
 };
@@ -27,6 +28,47 @@ const BLOCK_SIZE: u64 = 4096;
 const MAGIC: u64 = 0xa537a0aa6309ef77;
 const PACK_VERSION: u64 = 3;
 
+// The original container stored the blocks verbatim inside the zlib
+// chunks, rather than encoding them.  Older unpackers only understand
+// this.
+const LEGACY_PACK_VERSION: u64 = 1;
+
+/// The container written by pack.  Unpack accepts either.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PackFormat {
+    Native,
+    CCompat,
+}
+
+impl PackFormat {
+    fn version(self) -> u64 {
+        match self {
+            PackFormat::Native => PACK_VERSION,
+            PackFormat::CCompat => LEGACY_PACK_VERSION,
+        }
+    }
+
+    fn from_version(version: u64) -> Option<PackFormat> {
+        match version {
+            PACK_VERSION => Some(PackFormat::Native),
+            LEGACY_PACK_VERSION => Some(PackFormat::CCompat),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for PackFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(PackFormat::Native),
+            "c-compat" => Ok(PackFormat::CCompat),
+            _ => Err(anyhow!("unknown pack format '{}'", s)),
+        }
+    }
+}
+
 fn shuffle<T>(v: &mut Vec<T>) {
     let mut rng = rand::thread_rng();
     v.shuffle(&mut rng);
@@ -66,6 +108,14 @@ fn mk_chunk_vecs(nr_blocks: u64, nr_jobs: u64) -> Vec<Vec<(u64, u64)>> {
 }
 
 pub fn pack(input_file: &Path, output_file: &Path) -> Result<(), Box<dyn Error>> {
+    pack_as(input_file, output_file, PackFormat::Native)
+}
+
+pub fn pack_as(
+    input_file: &Path,
+    output_file: &Path,
+    format: PackFormat,
+) -> Result<(), Box<dyn Error>> {
     let nr_blocks = get_nr_blocks(input_file)?;
     let nr_jobs = std::cmp::max(1, std::cmp::min(num_cpus::get() as u64, nr_blocks / 128));
     let chunk_vecs = mk_chunk_vecs(nr_blocks, nr_jobs);
@@ -83,7 +133,7 @@ pub fn pack(input_file: &Path, output_file: &Path) -> Result<(), Box<dyn Error>>
         .truncate(true)
         .open(output_file)?;
 
-    write_header(&output, nr_blocks).context("unable to write pack file header")?;
+    write_header(&output, format, nr_blocks).context("unable to write pack file header")?;
 
     let sync_input = Arc::new(Mutex::new(input));
     let sync_output = Arc::new(Mutex::new(output));
@@ -93,7 +143,9 @@ pub fn pack(input_file: &Path, output_file: &Path) -> Result<(), Box<dyn Error>>
         let sync_input = Arc::clone(&sync_input);
         let sync_output = Arc::clone(&sync_output);
         let chunks = chunk_vecs[job as usize].clone();
-        threads.push(spawn(move || crunch(sync_input, sync_output, chunks)));
+        threads.push(spawn(move || {
+            crunch(sync_input, sync_output, chunks, format)
+        }));
     }
 
     for t in threads {
@@ -102,7 +154,12 @@ pub fn pack(input_file: &Path, output_file: &Path) -> Result<(), Box<dyn Error>>
     Ok(())
 }
 
-fn crunch<R, W>(input: Arc<Mutex<R>>, output: Arc<Mutex<W>>, ranges: Vec<(u64, u64)>) -> Result<()>
+fn crunch<R, W>(
+    input: Arc<Mutex<R>>,
+    output: Arc<Mutex<W>>,
+    ranges: Vec<(u64, u64)>,
+    format: PackFormat,
+) -> Result<()>
 where
     R: Read + Seek,
     W: Write,
@@ -116,13 +173,15 @@ where
         let big_data = read_blocks(input.deref_mut(), lo, hi - lo)?;
         drop(input);
 
-        for b in lo..hi {
-            let block_start = ((b - lo) * BLOCK_SIZE) as usize;
-            let data = &big_data[block_start..(block_start + BLOCK_SIZE as usize)];
-            let kind = metadata_block_type(data);
+        let blocks: Vec<&[u8]> = big_data.chunks(BLOCK_SIZE as usize).collect();
+        let kinds = metadata_block_types(&blocks);
+        for (b, (data, kind)) in (lo..hi).zip(blocks.iter().zip(kinds)) {
             if kind != BT::UNKNOWN {
                 z.write_u64::<LittleEndian>(b)?;
-                pack_block(&mut z, kind, data)?;
+                match format {
+                    PackFormat::Native => pack_block(&mut z, kind, data)?,
+                    PackFormat::CCompat => z.write_all(data)?,
+                }
 
                 written += 1;
                 if written == 1024 {
@@ -147,19 +206,19 @@ where
     Ok(())
 }
 
-fn write_header<W>(mut w: W, nr_blocks: u64) -> io::Result<()>
+fn write_header<W>(mut w: W, format: PackFormat, nr_blocks: u64) -> io::Result<()>
 where
     W: byteorder::WriteBytesExt,
 {
     w.write_u64::<LittleEndian>(MAGIC)?;
-    w.write_u64::<LittleEndian>(PACK_VERSION)?;
+    w.write_u64::<LittleEndian>(format.version())?;
     w.write_u64::<LittleEndian>(4096)?;
     w.write_u64::<LittleEndian>(nr_blocks)?;
 
     Ok(())
 }
 
-fn read_header<R>(mut r: R) -> io::Result<u64>
+fn read_header<R>(mut r: R) -> io::Result<(PackFormat, u64)>
 where
     R: byteorder::ReadBytesExt,
 {
@@ -172,12 +231,12 @@ where
     }
 
     let version = r.read_u64::<LittleEndian>()?;
-    if version != PACK_VERSION {
-        return Err(io::Error::new(
+    let format = PackFormat::from_version(version).ok_or_else(|| {
+        io::Error::new(
             io::ErrorKind::InvalidData,
-            format!("unsupported pack file version ({}).", PACK_VERSION),
-        ));
-    }
+            format!("unsupported pack file version ({}).", version),
+        )
+    })?;
 
     let block_size = r.read_u64::<LittleEndian>()?;
     if block_size != BLOCK_SIZE {
@@ -187,7 +246,8 @@ where
         ));
     }
 
-    r.read_u64::<LittleEndian>()
+    let nr_blocks = r.read_u64::<LittleEndian>()?;
+    Ok((format, nr_blocks))
 }
 
 fn get_nr_blocks(path: &Path) -> io::Result<u64> {
@@ -216,6 +276,7 @@ fn pack_block<W: Write>(w: &mut W, kind: BT, buf: &[u8]) -> Result<()> {
         BT::INDEX => pack_index(w, buf).context("unable to pack space map index")?,
         BT::BITMAP => pack_bitmap(w, buf).context("unable to pack space map bitmap")?,
         BT::ARRAY => pack_array(w, buf).context("unable to pack array block")?,
+        BT::DEVICE_LABELS => pack_device_labels(w, buf).context("unable to pack device labels")?,
         BT::UNKNOWN => return Err(anyhow!("asked to pack an unknown block type")),
     }
 
@@ -244,7 +305,18 @@ where
     Ok(())
 }
 
-fn decode_worker<W>(rx: Receiver<Vec<u8>>, w: Arc<Mutex<W>>) -> io::Result<()>
+fn unpack_block<R: Read>(r: &mut R, format: PackFormat) -> io::Result<Vec<u8>> {
+    match format {
+        PackFormat::Native => crate::pack::vm::unpack(r, BLOCK_SIZE as usize),
+        PackFormat::CCompat => {
+            let mut block = vec![0; BLOCK_SIZE as usize];
+            r.read_exact(&mut block)?;
+            Ok(block)
+        }
+    }
+}
+
+fn decode_worker<W>(rx: Receiver<Vec<u8>>, w: Arc<Mutex<W>>, format: PackFormat) -> io::Result<()>
 where
     W: Write + Seek,
 {
@@ -254,7 +326,7 @@ where
         let mut z = ZlibDecoder::new(&bytes[0..]);
 
         while let Ok(b) = z.read_u64::<LittleEndian>() {
-            let block = crate::pack::vm::unpack(&mut z, BLOCK_SIZE as usize).unwrap();
+            let block = unpack_block(&mut z, format)?;
             assert!(metadata_block_type(&block[0..]) != BT::UNKNOWN);
             blocks.push((b, block));
 
@@ -274,7 +346,7 @@ pub fn unpack(input_file: &Path, output_file: &Path) -> Result<(), Box<dyn Error
         .write(false)
         .open(input_file)?;
 
-    let nr_blocks = read_header(&input)?;
+    let (format, nr_blocks) = read_header(&input)?;
 
     let mut output = OpenOptions::new()
         .read(false)
@@ -283,8 +355,13 @@ pub fn unpack(input_file: &Path, output_file: &Path) -> Result<(), Box<dyn Error
         .truncate(true)
         .open(output_file)?;
 
-    // zero the last block to size the file
-    write_zero_block(&mut output, nr_blocks - 1)?;
+    // Size a regular file without writing to it, so the blocks that
+    // aren't in the pack are left as holes.
+    if output.metadata()?.file_type().is_file() {
+        output.set_len(nr_blocks * BLOCK_SIZE)?;
+    } else {
+        write_zero_block(&mut output, nr_blocks - 1)?;
+    }
 
     // Run until we hit the end
     let output = Arc::new(Mutex::new(output));
@@ -298,7 +375,7 @@ pub fn unpack(input_file: &Path, output_file: &Path) -> Result<(), Box<dyn Error
         let (tx, rx) = sync_channel(1);
         let output = Arc::clone(&output);
         senders.push(tx);
-        threads.push(spawn(move || decode_worker(rx, output)));
+        threads.push(spawn(move || decode_worker(rx, output, format)));
     }
 
     // Read z compressed chunk, and hand to worker thread.
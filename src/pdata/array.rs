@@ -77,6 +77,9 @@ pub enum ArrayError {
     //#[error("io_error {0}")]
     IoError(u64),
 
+    //#[error("missing block {0}")]
+    MissingBlock(u64),
+
     //#[error("block error: {0}")]
     BlockError(String),
 
@@ -100,6 +103,11 @@ impl fmt::Display for ArrayError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ArrayError::IoError(b) => write!(f, "io error {}", b),
+            ArrayError::MissingBlock(b) => write!(
+                f,
+                "missing block {}, past the end of the metadata device",
+                b
+            ),
             ArrayError::BlockError(msg) => write!(f, "block error: {}", msg),
             ArrayError::ValueError(msg) => write!(f, "value error: {}", msg),
             ArrayError::IndexContext(idx, e) => {
@@ -122,6 +130,16 @@ pub fn io_err(path: &[u64], blocknr: u64) -> ArrayError {
     ArrayError::Path(path.to_vec(), Box::new(ArrayError::IoError(blocknr)))
 }
 
+/// As io_err, but a read that ran off the end of the metadata is
+/// reported as a missing block.
+pub fn read_err(path: &[u64], blocknr: u64, e: &std::io::Error) -> ArrayError {
+    if e.kind() == std::io::ErrorKind::UnexpectedEof {
+        ArrayError::Path(path.to_vec(), Box::new(ArrayError::MissingBlock(blocknr)))
+    } else {
+        io_err(path, blocknr)
+    }
+}
+
 pub fn array_block_err(path: &[u64], msg: &str) -> ArrayError {
     ArrayError::Path(
         path.to_vec(),
@@ -83,9 +83,10 @@ impl<'a, V: Unpack + Copy> NodeVisitor<u64> for BlockValueVisitor<'a, V> {
             Ok(rblocks) => {
                 for (i, rb) in rblocks.into_iter().enumerate() {
                     match rb {
-                        Err(_) => {
+                        Err(e) => {
                             let mut array_errs = self.array_errs.lock().unwrap();
-                            array_errs.push(array::io_err(path, values[i]).index_context(keys[i]));
+                            array_errs
+                                .push(array::read_err(path, values[i], &e).index_context(keys[i]));
                         }
                         Ok(b) => {
                             let mut path = path.to_vec();
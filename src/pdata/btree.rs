@@ -320,6 +320,9 @@ pub enum BTreeError {
     // #[error("io error")]
     IoError, //   (std::io::Error), // FIXME: we can't clone an io_error
 
+    // #[error("missing block {0}")]
+    MissingBlock(u64),
+
     // #[error("node error: {0}")]
     NodeError(String),
 
@@ -340,6 +343,11 @@ impl fmt::Display for BTreeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             BTreeError::IoError => write!(f, "io error"),
+            BTreeError::MissingBlock(b) => write!(
+                f,
+                "missing block {}, past the end of the metadata device",
+                b
+            ),
             BTreeError::NodeError(msg) => write!(f, "node error: {}", msg),
             BTreeError::ValueError(msg) => write!(f, "value error: {}", msg),
             BTreeError::KeyContext(kr, be) => write!(f, "{}, effecting keys {}", be, kr),
@@ -368,6 +376,23 @@ pub fn io_err(path: &[u64]) -> BTreeError {
     BTreeError::Path(path.to_vec(), Box::new(BTreeError::IoError))
 }
 
+/// A node that should be at block b, which is past the end of the
+/// metadata, as happens when the device or image has been truncated.
+pub fn missing_block_err(path: &[u64], b: u64) -> BTreeError {
+    BTreeError::Path(path.to_vec(), Box::new(BTreeError::MissingBlock(b)))
+}
+
+/// The error for a node that couldn't be read.  Running off the end of
+/// the metadata is damage, like any other, so it's reported as a
+/// missing block rather than a bare io error.
+pub fn read_err(path: &[u64], b: u64, e: &std::io::Error) -> BTreeError {
+    if e.kind() == std::io::ErrorKind::UnexpectedEof {
+        missing_block_err(path, b)
+    } else {
+        io_err(path)
+    }
+}
+
 pub fn value_err(msg: String) -> BTreeError {
     BTreeError::ValueError(msg)
 }
@@ -380,10 +405,93 @@ impl BTreeError {
     pub fn keys_context(self, keys: &KeyRange) -> BTreeError {
         BTreeError::KeyContext(keys.clone(), Box::new(self))
     }
+
+    /// Lists the ranges of keys this error made unreadable, and why.
+    pub fn damaged_ranges(&self) -> DamagedRanges<'_> {
+        DamagedRanges {
+            stack: vec![(self, None, None)],
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, BTreeError>;
 
+/// A range of keys lost to a damaged node.  The path leads to the node
+/// holding the bad child, and the range is unbounded where the error
+/// couldn't be pinned down any further, eg, an unreadable root.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DamagedRange {
+    pub path: Vec<u64>,
+    pub keys: KeyRange,
+    pub cause: String,
+}
+
+/// Walks the tree of errors built up by a tolerant btree walk, yielding
+/// each underlying failure with the innermost, and so narrowest, path
+/// and key range that it was reported with.
+pub struct DamagedRanges<'a> {
+    stack: Vec<ErrorContext<'a>>,
+}
+
+// An error, with the innermost path and keys reported around it.
+type ErrorContext<'a> = (&'a BTreeError, Option<&'a [u64]>, Option<&'a KeyRange>);
+
+impl<'a> Iterator for DamagedRanges<'a> {
+    type Item = DamagedRange;
+
+    fn next(&mut self) -> Option<DamagedRange> {
+        while let Some((e, path, keys)) = self.stack.pop() {
+            match e {
+                BTreeError::Aggregate(errs) => {
+                    for e in errs.iter().rev() {
+                        self.stack.push((e, path, keys));
+                    }
+                }
+                BTreeError::Path(p, e) => self.stack.push((e, Some(p), keys)),
+                BTreeError::KeyContext(kr, e) => self.stack.push((e, path, Some(kr))),
+                _ => {
+                    return Some(DamagedRange {
+                        path: path.map_or_else(Vec::new, |p| p.to_vec()),
+                        keys: keys.cloned().unwrap_or_default(),
+                        cause: e.to_string(),
+                    })
+                }
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn test_damaged_ranges() {
+    let kr = |start, end| KeyRange { start, end };
+    let e = aggregate_error(vec![
+        io_err(&[0, 7]).keys_context(&kr(Some(10), Some(20))),
+        node_err(&[0, 7], "bad").keys_context(&kr(Some(20), None)),
+        aggregate_error(vec![BTreeError::Path(
+            vec![0, 7, 9],
+            Box::new(value_err("worse".to_string()).keys_context(&kr(Some(25), Some(30)))),
+        )
+        .keys_context(&kr(Some(20), None))]),
+        io_err(&[0]),
+    ]);
+
+    let ranges: Vec<DamagedRange> = e.damaged_ranges().collect();
+    let summary: Vec<(Vec<u64>, KeyRange, &str)> = ranges
+        .iter()
+        .map(|r| (r.path.clone(), r.keys.clone(), r.cause.as_str()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (vec![0, 7], kr(Some(10), Some(20)), "io error"),
+            (vec![0, 7], kr(Some(20), None), "node error: bad"),
+            (vec![0, 7, 9], kr(Some(25), Some(30)), "value error: worse"),
+            (vec![0], kr(None, None), "io error"),
+        ]
+    );
+}
+
 //------------------------------------------
 
 #[derive(Debug, Clone, Copy)]
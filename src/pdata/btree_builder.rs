@@ -61,6 +61,37 @@ impl RefCounter<u64> for SMRefCounter {
     }
 }
 
+/// Collects the values it's asked to dec, so whatever they refer to can
+/// be released once the tree holding them has been.
+pub struct ReleasedValues<Value> {
+    pub values: Vec<Value>,
+}
+
+impl<Value> ReleasedValues<Value> {
+    pub fn new() -> ReleasedValues<Value> {
+        ReleasedValues { values: Vec::new() }
+    }
+}
+
+impl<Value> Default for ReleasedValues<Value> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Value: Clone> RefCounter<Value> for ReleasedValues<Value> {
+    fn get(&self, _v: &Value) -> Result<u32> {
+        Ok(0)
+    }
+    fn inc(&mut self, _v: &Value) -> Result<()> {
+        Ok(())
+    }
+    fn dec(&mut self, v: &Value) -> Result<()> {
+        self.values.push(v.clone());
+        Ok(())
+    }
+}
+
 //------------------------------------------
 
 // Building a btree for a given set of values is straight forward.
@@ -260,6 +291,19 @@ pub struct NodeSummary {
     shared: bool,
 }
 
+impl NodeSummary {
+    /// Describes a node that's already on disk, so it can be pushed into
+    /// a new tree alongside it.
+    pub fn existing(block: u64, key: u64, nr_entries: usize) -> NodeSummary {
+        NodeSummary {
+            block,
+            key,
+            nr_entries,
+            shared: true,
+        }
+    }
+}
+
 impl<'a, V: Pack + Unpack + Clone> NodeBuilder<V> {
     /// Create a new NodeBuilder
     pub fn new(nio: Box<dyn NodeIO<V>>, value_rc: Box<dyn RefCounter<V>>, shared: bool) -> Self {
@@ -574,3 +618,36 @@ pub fn release_leaves<V: Pack + Unpack>(
 }
 
 //------------------------------------------
+
+// Drops a reference to a tree.  Nodes left unreferenced are released in
+// turn, and so on down to the leaves, whose values are dec'd with
+// value_rc.  Nodes still referenced elsewhere, eg, from a metadata
+// snapshot, are left alone.
+pub fn release_btree<V: Pack + Unpack>(
+    w: &mut WriteBatcher,
+    root: u64,
+    value_rc: &mut dyn RefCounter<V>,
+) -> Result<()> {
+    let deleted = w.sm.lock().unwrap().dec(root)?;
+    if !deleted {
+        return Ok(());
+    }
+
+    let b = w.read(root)?;
+    match unpack_node::<V>(&[root], b.get_data(), true, true)? {
+        Node::Internal { values, .. } => {
+            for child in values {
+                release_btree(w, child, value_rc)?;
+            }
+        }
+        Node::Leaf { values, .. } => {
+            for v in values {
+                value_rc.dec(&v)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//------------------------------------------
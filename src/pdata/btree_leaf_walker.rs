@@ -3,6 +3,7 @@ use std::sync::Arc;
 
 use crate::checksum;
 use crate::io_engine::*;
+use crate::math::to_index;
 use crate::pdata::btree::*;
 use crate::pdata::space_map::*;
 use crate::pdata::unpack::*;
@@ -50,7 +51,7 @@ impl<'a> LeafWalker<'a> {
         sm: &'a mut dyn SpaceMap,
         ignore_non_fatal: bool,
     ) -> LeafWalker<'a> {
-        let nr_blocks = engine.get_nr_blocks() as usize;
+        let nr_blocks = to_index(engine.get_nr_blocks());
         LeafWalker {
             engine,
             sm,
@@ -141,7 +142,7 @@ impl<'a> LeafWalker<'a> {
                 for i in 0..krs.len() {
                     self.sm.inc(values[i], 1).expect("sm.inc() failed");
                     for v in &values {
-                        self.leaves.insert(*v as usize);
+                        self.leaves.insert(to_index(*v));
                     }
                     visitor.visit(&krs[i], values[i])?;
                 }
@@ -213,7 +214,7 @@ impl<'a> LeafWalker<'a> {
         self.sm_inc(root);
         if depth == 0 {
             // root is a leaf
-            self.leaves.insert(root as usize);
+            self.leaves.insert(to_index(root));
             visitor.visit(&kr, root)?;
             Ok(())
         } else {
@@ -1,9 +1,12 @@
 use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use std::sync::{Arc, Mutex};
 use threadpool::ThreadPool;
 
 use crate::checksum;
 use crate::io_engine::*;
+use crate::math::to_index;
+use crate::memory;
 use crate::pdata::btree::*;
 use crate::pdata::space_map::*;
 use crate::pdata::unpack::*;
@@ -29,22 +32,87 @@ pub trait NodeVisitor<V: Unpack> {
     fn end_walk(&self) -> Result<()>;
 }
 
+// Records how each node was first reached: the type of tree it's in, and
+// its height above the leaves.  A node that's reached again, through
+// another parent, has to fit there too.  Otherwise the block is aliased,
+// eg, it's a leaf in one device's tree but an internal node in another's,
+// which would be double counted rather than reported.  If the memory
+// budget is short, only the nodes in the first blocks are tracked.
+struct NodeRoles {
+    types: Mutex<Vec<&'static str>>,
+
+    // (type << 8) | height, with type counting from 1 so zero means the
+    // node hasn't been seen, or is still being walked.
+    roles: Vec<AtomicU16>,
+    _claim: memory::Claim,
+}
+
+impl NodeRoles {
+    fn new(nr_blocks: u64) -> NodeRoles {
+        let role_size = std::mem::size_of::<AtomicU16>() as u64;
+        let claim = memory::claim_up_to(nr_blocks * role_size, 0);
+        let nr_tracked = claim.bytes() / role_size;
+
+        NodeRoles {
+            types: Mutex::new(Vec::new()),
+            roles: (0..nr_tracked).map(|_| AtomicU16::new(0)).collect(),
+            _claim: claim,
+        }
+    }
+
+    fn type_index<V>(&self) -> u16 {
+        let name = std::any::type_name::<V>();
+        let mut types = self.types.lock().unwrap();
+        let index = match types.iter().position(|t| *t == name) {
+            Some(index) => index,
+            None => {
+                types.push(name);
+                types.len() - 1
+            }
+        };
+        assert!(index < 255, "too many btree value types");
+        index as u16 + 1
+    }
+
+    // Returns (type, height)
+    fn get(&self, b: u64) -> Option<(u16, u16)> {
+        match self.roles.get(to_index(b))?.load(Ordering::Acquire) {
+            0 => None,
+            role => Some((role >> 8, role & 0xff)),
+        }
+    }
+
+    fn set(&self, b: u64, ty: u16, height: u16) {
+        if let Some(role) = self.roles.get(to_index(b)) {
+            role.store((ty << 8) | (height & 0xff), Ordering::Release);
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct BTreeWalker {
     engine: Arc<dyn IoEngine + Send + Sync>,
     sm: Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     fails: Arc<Mutex<BTreeMap<u64, BTreeError>>>,
     ignore_non_fatal: bool,
+
+    // Only tracked by the checkers, which pass in a space map.
+    roles: Option<Arc<NodeRoles>>,
+
+    // Once set, no more nodes are read.
+    abort: Option<Arc<AtomicBool>>,
 }
 
 impl BTreeWalker {
     pub fn new(engine: Arc<dyn IoEngine + Send + Sync>, ignore_non_fatal: bool) -> BTreeWalker {
-        let nr_blocks = engine.get_nr_blocks() as usize;
+        let nr_blocks = to_index(engine.get_nr_blocks());
         let r: BTreeWalker = BTreeWalker {
             engine,
             sm: Arc::new(Mutex::new(RestrictedSpaceMap::new(nr_blocks as u64))),
             fails: Arc::new(Mutex::new(BTreeMap::new())),
             ignore_non_fatal,
+            roles: None,
+            abort: None,
         };
         r
     }
@@ -59,14 +127,28 @@ impl BTreeWalker {
             assert_eq!(sm.get_nr_blocks().unwrap(), engine.get_nr_blocks());
         }
 
+        let nr_blocks = engine.get_nr_blocks();
         Ok(BTreeWalker {
             engine,
             sm,
             fails: Arc::new(Mutex::new(BTreeMap::new())),
             ignore_non_fatal,
+            roles: Some(Arc::new(NodeRoles::new(nr_blocks))),
+            abort: None,
         })
     }
 
+    /// Stops the walk, without error, as soon as the flag is set.  The
+    /// caller should check the flag afterwards, since what was visited
+    /// is incomplete.
+    pub fn set_abort(&mut self, abort: Arc<AtomicBool>) {
+        self.abort = Some(abort);
+    }
+
+    fn aborted(&self) -> bool {
+        matches!(&self.abort, Some(a) if a.load(Ordering::Relaxed))
+    }
+
     fn failed(&self, b: u64) -> Option<BTreeError> {
         let fails = self.fails.lock().unwrap();
         fails.get(&b).cloned()
@@ -78,6 +160,11 @@ impl BTreeWalker {
         fails.insert(b, err);
     }
 
+    // Blocks past the end of the metadata can't be read, or counted.
+    fn is_missing(&self, b: u64) -> bool {
+        b >= self.engine.get_nr_blocks()
+    }
+
     // Atomically increments the ref count, and returns the _old_ count.
     fn sm_inc(&self, b: u64) -> u32 {
         let mut sm = self.sm.lock().unwrap();
@@ -86,6 +173,71 @@ impl BTreeWalker {
         count
     }
 
+    fn set_leaf_role<V>(&self, b: u64) {
+        if let Some(roles) = &self.roles {
+            roles.set(b, roles.type_index::<V>(), 0);
+        }
+    }
+
+    // A node that's been seen before must be from the same type of tree.
+    fn check_shared_role<V>(&self, path: &[u64], b: u64) -> Result<()> {
+        if let Some(roles) = &self.roles {
+            if let Some((ty, _)) = roles.get(b) {
+                if ty != roles.type_index::<V>() {
+                    return Err(node_err_s(
+                        path,
+                        format!("node {} is shared with a different type of btree", b),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Checks that the children of an internal node are from this type of
+    // tree, and all at the same height, then records the node's own role.
+    // Children that failed, or are still being walked by another thread,
+    // have no role and are skipped.
+    fn check_children<V>(&self, path: &[u64], b: u64, children: &[u64]) -> Result<()> {
+        let roles = match &self.roles {
+            Some(roles) => roles,
+            None => return Ok(()),
+        };
+
+        let ty = roles.type_index::<V>();
+        let mut first: Option<(u64, u16)> = None;
+        for child in children {
+            let (child_ty, height) = match roles.get(*child) {
+                Some(role) => role,
+                None => continue,
+            };
+            if child_ty != ty {
+                return Err(node_err_s(
+                    path,
+                    format!("node {} is shared with a different type of btree", child),
+                ));
+            }
+            match first {
+                None => first = Some((*child, height)),
+                Some((other, other_height)) if other_height != height => {
+                    return Err(node_err_s(
+                        path,
+                        format!(
+                            "children {} and {} are at heights {} and {}, one is aliased with another tree",
+                            other, child, other_height, height
+                        ),
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+
+        if let Some((_, height)) = first {
+            roles.set(b, ty, height + 1);
+        }
+        Ok(())
+    }
+
     fn build_aggregate(&self, b: u64, errs: Vec<BTreeError>) -> Result<()> {
         match errs.len() {
             0 => Ok(()),
@@ -115,10 +267,34 @@ impl BTreeWalker {
     {
         assert_eq!(krs.len(), bs.len());
         let mut errs: Vec<BTreeError> = Vec::new();
+        if self.aborted() {
+            return errs;
+        }
 
         let mut blocks = Vec::with_capacity(bs.len());
         let mut filtered_krs = Vec::with_capacity(krs.len());
         for i in 0..bs.len() {
+            if self.is_missing(bs[i]) {
+                errs.push(missing_block_err(path, bs[i]).keys_context(&krs[i]));
+                continue;
+            }
+
+            if path.contains(&bs[i]) {
+                // Following it would loop, and count the blocks again.
+                errs.push(
+                    node_err_s(
+                        path,
+                        format!(
+                            "node {} points back to its ancestor {}",
+                            path.last().unwrap_or(&0),
+                            bs[i]
+                        ),
+                    )
+                    .keys_context(&krs[i]),
+                );
+                continue;
+            }
+
             if self.sm_inc(bs[i]) == 0 {
                 // Node not yet seen
                 blocks.push(bs[i]);
@@ -153,8 +329,8 @@ impl BTreeWalker {
             Ok(rblocks) => {
                 for (i, rb) in rblocks.into_iter().enumerate() {
                     match rb {
-                        Err(_) => {
-                            let e = io_err(path).keys_context(&filtered_krs[i]);
+                        Err(e) => {
+                            let e = read_err(path, blocks[i], &e).keys_context(&filtered_krs[i]);
                             errs.push(e.clone());
                             self.set_fail(blocks[i], e);
                         }
@@ -195,12 +371,16 @@ impl BTreeWalker {
             .keys_context(kr));
         }
 
-        let node = unpack_node::<V>(path, b.get_data(), self.ignore_non_fatal, is_root)?;
+        let node = unpack_node::<V>(path, b.get_data(), self.ignore_non_fatal, is_root)
+            .map_err(|e| e.keys_context(kr))?;
 
         match node {
             Internal { keys, values, .. } => {
                 let krs = split_key_ranges(path, kr, &keys)?;
-                let errs = self.walk_nodes(path, visitor, &krs, &values);
+                let mut errs = self.walk_nodes(path, visitor, &krs, &values);
+                if let Err(e) = self.check_children::<V>(path, b.loc, &values) {
+                    errs.push(e);
+                }
                 return self.build_aggregate(b.loc, errs);
             }
             Leaf {
@@ -208,6 +388,7 @@ impl BTreeWalker {
                 keys,
                 values,
             } => {
+                self.set_leaf_role::<V>(b.loc);
                 if let Err(e) = visitor.visit(path, kr, &header, &keys, &values) {
                     let e = BTreeError::Path(path.clone(), Box::new(e));
                     self.set_fail(b.loc, e.clone());
@@ -243,14 +424,22 @@ impl BTreeWalker {
         NV: NodeVisitor<V>,
         V: Unpack,
     {
+        if self.is_missing(root) {
+            return Err(missing_block_err(path, root));
+        }
+
         if self.sm_inc(root) > 0 {
             if let Some(e) = self.failed(root) {
                 Err(e)
             } else {
+                self.check_shared_role::<V>(path, root)?;
                 visitor.visit_again(path, root)
             }
         } else {
-            let root = self.engine.read(root).map_err(|_| io_err(path))?;
+            let root = self
+                .engine
+                .read(root)
+                .map_err(|e| read_err(path, root, &e))?;
             let kr = KeyRange {
                 start: None,
                 end: None,
@@ -286,12 +475,16 @@ where
         .keys_context(kr));
     }
 
-    let node = unpack_node::<V>(path, b.get_data(), w.ignore_non_fatal, is_root)?;
+    let node = unpack_node::<V>(path, b.get_data(), w.ignore_non_fatal, is_root)
+        .map_err(|e| e.keys_context(kr))?;
 
     match node {
         Internal { keys, values, .. } => {
             let krs = split_key_ranges(path, kr, &keys)?;
-            let errs = walk_nodes_threaded(w.clone(), path, pool, visitor, &krs, &values);
+            let mut errs = walk_nodes_threaded(w.clone(), path, pool, visitor, &krs, &values);
+            if let Err(e) = w.check_children::<V>(path, b.loc, &values) {
+                errs.push(e);
+            }
             return w.build_aggregate(b.loc, errs);
         }
         Leaf {
@@ -299,6 +492,7 @@ where
             keys,
             values,
         } => {
+            w.set_leaf_role::<V>(b.loc);
             visitor.visit(path, kr, &header, &keys, &values)?;
         }
     }
@@ -340,10 +534,34 @@ where
 {
     assert_eq!(krs.len(), bs.len());
     let mut errs: Vec<BTreeError> = Vec::new();
+    if w.aborted() {
+        return errs;
+    }
 
     let mut blocks = Vec::with_capacity(bs.len());
     let mut filtered_krs = Vec::with_capacity(krs.len());
     for i in 0..bs.len() {
+        if w.is_missing(bs[i]) {
+            errs.push(missing_block_err(path, bs[i]).keys_context(&krs[i]));
+            continue;
+        }
+
+        if path.contains(&bs[i]) {
+            // Following it would loop, and count the blocks again.
+            errs.push(
+                node_err_s(
+                    path,
+                    format!(
+                        "node {} points back to its ancestor {}",
+                        path.last().unwrap_or(&0),
+                        bs[i]
+                    ),
+                )
+                .keys_context(&krs[i]),
+            );
+            continue;
+        }
+
         if w.sm_inc(bs[i]) == 0 {
             // Node not yet seen
             blocks.push(bs[i]);
@@ -380,8 +598,8 @@ where
 
             for (i, rb) in rblocks.into_iter().enumerate() {
                 match rb {
-                    Err(_) => {
-                        let e = io_err(path).keys_context(&filtered_krs[i]);
+                    Err(e) => {
+                        let e = read_err(path, blocks[i], &e).keys_context(&filtered_krs[i]);
                         let mut errs = child_errs.lock().unwrap();
                         errs.push(e.clone());
                         w.set_fail(blocks[i], e);
@@ -426,14 +644,19 @@ where
     NV: NodeVisitor<V> + Send + Sync + 'static,
     V: Unpack,
 {
+    if w.is_missing(root) {
+        return Err(missing_block_err(path, root));
+    }
+
     if w.sm_inc(root) > 0 {
         if let Some(e) = w.failed(root) {
             Err(e)
         } else {
+            w.check_shared_role::<V>(path, root)?;
             visitor.visit_again(path, root)
         }
     } else {
-        let root = w.engine.read(root).map_err(|_| io_err(path))?;
+        let root = w.engine.read(root).map_err(|e| read_err(path, root, &e))?;
         let kr = KeyRange {
             start: None,
             end: None,
@@ -495,6 +718,19 @@ pub fn btree_to_map<V: Unpack + Copy>(
     Ok(visitor.values.into_inner().unwrap())
 }
 
+/// Like btree_to_map, but keeps whatever values could be read from a
+/// damaged tree, along with the error saying which keys were lost.
+pub fn btree_to_map_with_damage<V: Unpack + Copy>(
+    path: &mut Vec<u64>,
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    root: u64,
+) -> (BTreeMap<u64, V>, Option<BTreeError>) {
+    let walker = BTreeWalker::new(engine, true);
+    let visitor = ValueCollector::<V>::new();
+    let err = walker.walk(path, &visitor, root).err();
+    (visitor.values.into_inner().unwrap(), err)
+}
+
 pub fn btree_to_map_with_sm<V: Unpack + Copy>(
     path: &mut Vec<u64>,
     engine: Arc<dyn IoEngine + Send + Sync>,
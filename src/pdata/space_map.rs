@@ -5,6 +5,8 @@ use std::boxed::Box;
 use std::convert::{TryFrom, TryInto};
 use std::sync::{Arc, Mutex};
 
+use crate::math::to_index;
+
 //------------------------------------------
 
 pub trait SpaceMap {
@@ -56,7 +58,7 @@ where
         CoreSpaceMap {
             nr_allocated: 0,
             alloc_begin: 0,
-            counts: vec![V::default(); nr_entries as usize],
+            counts: vec![V::default(); to_index(nr_entries)],
         }
     }
 }
@@ -83,13 +85,13 @@ where
     }
 
     fn get(&self, b: u64) -> Result<u32> {
-        Ok(self.counts[b as usize].into())
+        Ok(self.counts[to_index(b)].into())
     }
 
     fn set(&mut self, b: u64, v: u32) -> Result<u32> {
-        let old = self.counts[b as usize];
+        let old = self.counts[to_index(b)];
         assert!(v <= V::max_value().into());
-        self.counts[b as usize] = v.try_into().unwrap(); // FIXME: do not panic
+        self.counts[to_index(b)] = v.try_into().unwrap(); // FIXME: do not panic
 
         if old == V::from(0u8) && v != 0 {
             self.nr_allocated += 1;
@@ -102,7 +104,7 @@ where
 
     fn inc(&mut self, begin: u64, len: u64) -> Result<()> {
         for b in begin..(begin + len) {
-            let c = &mut self.counts[b as usize];
+            let c = &mut self.counts[to_index(b)];
             assert!(*c < V::max_value());
             if *c == V::from(0u8) {
                 // FIXME: can we get a ref to save dereferencing counts twice?
@@ -124,7 +126,7 @@ where
             }
         }
 
-        self.counts[b.unwrap() as usize] = V::from(1u8);
+        self.counts[to_index(b.unwrap())] = V::from(1u8);
         self.nr_allocated += 1;
         self.alloc_begin = b.unwrap() + 1;
 
@@ -133,7 +135,7 @@ where
 
     fn find_free(&mut self, begin: u64, end: u64) -> Result<Option<u64>> {
         for b in begin..end {
-            if self.counts[b as usize] == V::from(0u8) {
+            if self.counts[to_index(b)] == V::from(0u8) {
                 return Ok(Some(b));
             }
         }
@@ -145,6 +147,18 @@ where
     }
 }
 
+/// The memory needed by core_sm() for the given size.
+pub fn core_sm_bytes(nr_entries: u64, max_count: u32) -> u64 {
+    let width = if max_count <= u8::MAX as u32 {
+        1
+    } else if max_count <= u16::MAX as u32 {
+        2
+    } else {
+        4
+    };
+    nr_entries * width
+}
+
 pub fn core_sm(nr_entries: u64, max_count: u32) -> Arc<Mutex<dyn SpaceMap + Send + Sync>> {
     if max_count <= u8::MAX as u32 {
         Arc::new(Mutex::new(CoreSpaceMap::<u8>::new(nr_entries)))
@@ -180,7 +194,7 @@ impl RestrictedSpaceMap {
     pub fn new(nr_entries: u64) -> RestrictedSpaceMap {
         RestrictedSpaceMap {
             nr_allocated: 0,
-            counts: FixedBitSet::with_capacity(nr_entries as usize),
+            counts: FixedBitSet::with_capacity(to_index(nr_entries)),
             alloc_begin: 0,
         }
     }
@@ -196,7 +210,7 @@ impl SpaceMap for RestrictedSpaceMap {
     }
 
     fn get(&self, b: u64) -> Result<u32> {
-        if self.counts.contains(b as usize) {
+        if self.counts.contains(to_index(b)) {
             Ok(1)
         } else {
             Ok(0)
@@ -204,18 +218,18 @@ impl SpaceMap for RestrictedSpaceMap {
     }
 
     fn set(&mut self, b: u64, v: u32) -> Result<u32> {
-        let old = self.counts.contains(b as usize);
+        let old = self.counts.contains(to_index(b));
 
         if v > 0 {
             if !old {
                 self.nr_allocated += 1;
             }
-            self.counts.insert(b as usize);
+            self.counts.insert(to_index(b));
         } else {
             if old {
                 self.nr_allocated -= 1;
             }
-            self.counts.set(b as usize, false);
+            self.counts.set(to_index(b), false);
         }
 
         Ok(if old { 1 } else { 0 })
@@ -223,9 +237,9 @@ impl SpaceMap for RestrictedSpaceMap {
 
     fn inc(&mut self, begin: u64, len: u64) -> Result<()> {
         for b in begin..(begin + len) {
-            if !self.counts.contains(b as usize) {
+            if !self.counts.contains(to_index(b)) {
                 self.nr_allocated += 1;
-                self.counts.insert(b as usize);
+                self.counts.insert(to_index(b));
             }
         }
         Ok(())
@@ -240,16 +254,16 @@ impl SpaceMap for RestrictedSpaceMap {
             }
         }
 
-        self.counts.insert(b.unwrap() as usize);
+        self.counts.insert(to_index(b.unwrap()));
         self.nr_allocated += 1;
-        self.alloc_begin = b.unwrap() as usize + 1;
+        self.alloc_begin = to_index(b.unwrap()) + 1;
 
         Ok(b)
     }
 
     fn find_free(&mut self, begin: u64, end: u64) -> Result<Option<u64>> {
         for b in begin..end {
-            if !self.counts.contains(b as usize) {
+            if !self.counts.contains(to_index(b)) {
                 return Ok(Some(b));
             }
         }
@@ -262,3 +276,92 @@ impl SpaceMap for RestrictedSpaceMap {
 }
 
 //------------------------------------------
+
+// Wraps the ref counts of metadata that's already on disk, eg, those a
+// check has worked out, so new copies of it can be written without
+// disturbing the old.  Only blocks that were free to begin with are
+// handed out, even once the old copy releases its blocks.  The wrapped
+// space map may cover more than nr_blocks, eg, the whole device.
+pub struct CowSpaceMap {
+    sm: ASpaceMap,
+    nr_blocks: u64,
+    in_use: FixedBitSet,
+}
+
+impl CowSpaceMap {
+    pub fn new(sm: ASpaceMap, nr_blocks: u64) -> Result<CowSpaceMap> {
+        let mut in_use = FixedBitSet::with_capacity(to_index(nr_blocks));
+        {
+            let sm = sm.lock().unwrap();
+            for b in 0..nr_blocks {
+                if sm.get(b)? > 0 {
+                    in_use.insert(to_index(b));
+                }
+            }
+        }
+
+        Ok(CowSpaceMap {
+            sm,
+            nr_blocks,
+            in_use,
+        })
+    }
+
+    /// The blocks in use to begin with.
+    pub fn in_use(&self) -> &FixedBitSet {
+        &self.in_use
+    }
+}
+
+impl SpaceMap for CowSpaceMap {
+    fn get_nr_blocks(&self) -> Result<u64> {
+        Ok(self.nr_blocks)
+    }
+
+    fn get_nr_allocated(&self) -> Result<u64> {
+        let sm = self.sm.lock().unwrap();
+        let mut nr_allocated = 0;
+        for b in 0..self.nr_blocks {
+            if sm.get(b)? > 0 {
+                nr_allocated += 1;
+            }
+        }
+        Ok(nr_allocated)
+    }
+
+    fn get(&self, b: u64) -> Result<u32> {
+        self.sm.lock().unwrap().get(b)
+    }
+
+    fn set(&mut self, b: u64, v: u32) -> Result<u32> {
+        self.sm.lock().unwrap().set(b, v)
+    }
+
+    fn inc(&mut self, begin: u64, len: u64) -> Result<()> {
+        self.sm.lock().unwrap().inc(begin, len)
+    }
+
+    fn alloc(&mut self) -> Result<Option<u64>> {
+        let b = self.find_free(0, self.nr_blocks)?;
+        if let Some(b) = b {
+            self.set(b, 1)?;
+        }
+        Ok(b)
+    }
+
+    fn find_free(&mut self, begin: u64, end: u64) -> Result<Option<u64>> {
+        let sm = self.sm.lock().unwrap();
+        for b in begin..std::cmp::min(end, self.nr_blocks) {
+            if !self.in_use.contains(to_index(b)) && sm.get(b)? == 0 {
+                return Ok(Some(b));
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_alloc_begin(&self) -> Result<u64> {
+        Ok(0)
+    }
+}
+
+//------------------------------------------
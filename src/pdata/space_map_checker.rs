@@ -1,9 +1,12 @@
 use anyhow::{anyhow, Result};
-use std::io::Cursor;
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use threadpool::ThreadPool;
+use tracing::instrument;
 
 use crate::checksum;
 use crate::io_engine::IoEngine;
+use crate::math::div_up;
 use crate::pdata::btree::{self, *};
 use crate::pdata::btree_walker::*;
 use crate::pdata::space_map::*;
@@ -41,9 +44,16 @@ impl<'a> NodeVisitor<u32> for OverflowChecker<'a> {
         keys: &[u64],
         values: &[u32],
     ) -> btree::Result<()> {
+        let nr_blocks = self.sm.get_nr_blocks().unwrap();
         for n in 0..keys.len() {
             let k = keys[n];
             let v = values[n];
+            if k >= nr_blocks {
+                return Err(value_err(format!(
+                    "Reference count for {} block {} lies beyond the end of the space map, which has {} blocks.",
+                    self.kind, k, nr_blocks
+                )));
+            }
             let expected = self.sm.get(k).unwrap();
             if expected != v {
                 return Err(value_err(format!(
@@ -76,6 +86,101 @@ fn inc_entries(sm: &ASpaceMap, entries: &[IndexEntry]) -> Result<()> {
     Ok(())
 }
 
+// The bitmaps are checked in shards of this many, across a thread pool.
+const BITMAPS_PER_SHARD: usize = 64;
+
+#[derive(Default)]
+struct ShardResult {
+    leaks: u64,
+
+    // in use entries past nr_blocks, left by an interrupted resize
+    leaks_beyond: u64,
+    errors: Vec<String>,
+    bitmap_leaks: Vec<BitmapLeak>,
+}
+
+// Compares the ref counts in a run of consecutive bitmaps, the first of
+// which covers `blocknr` onwards.  Errors are collected rather than
+// reported, so the output doesn't depend on how the threads are scheduled.
+fn check_bitmap_shard(
+    engine: &dyn IoEngine,
+    kind: &str,
+    entries: &[IndexEntry],
+    mut blocknr: u64,
+    sm: &ASpaceMap,
+) -> Result<ShardResult> {
+    let blocks: Vec<u64> = entries.iter().map(|ie| ie.blocknr).collect();
+    let blocks = engine
+        .read_many(&blocks)?
+        .into_iter()
+        .take(entries.len())
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| anyhow!("Unable to read bitmap block: {}", e))?;
+
+    // verify the checksums as a batch
+    let data: Vec<&[u8]> = blocks.iter().map(|b| b.get_data() as &[u8]).collect();
+    let bts = checksum::metadata_block_types(&data);
+
+    let mut result = ShardResult::default();
+    for (b, bt) in blocks.iter().zip(bts) {
+        if bt != checksum::BT::BITMAP {
+            result.errors.push(format!(
+                "Index entry points to block ({}) that isn't a bitmap",
+                b.loc
+            ));
+
+            // FIXME: revert the ref-count at b.loc?
+        }
+
+        let bitmap = unpack::<Bitmap>(b.get_data())?;
+        let first_blocknr = blocknr;
+        let mut contains_leak = false;
+
+        let sm = sm.lock().unwrap();
+        let nr_blocks = sm.get_nr_blocks()?;
+        for e in bitmap.entries.iter() {
+            if blocknr >= nr_blocks {
+                if *e != BitmapEntry::Small(0) {
+                    result.leaks_beyond += 1;
+                    contains_leak = true;
+                }
+                blocknr += 1;
+                continue;
+            }
+
+            match e {
+                BitmapEntry::Small(actual) => {
+                    let expected = sm.get(blocknr)?;
+                    if *actual == 1 && expected == 0 {
+                        result.leaks += 1;
+                        contains_leak = true;
+                    } else if *actual != expected as u8 {
+                        result.errors.push(format!("Bad reference count for {} block {}.  Expected {}, but space map contains {}.",
+                                  kind, blocknr, expected, actual));
+                    }
+                }
+                BitmapEntry::Overflow => {
+                    let expected = sm.get(blocknr)?;
+                    if expected < 3 {
+                        result.errors.push(format!("Bad reference count for {} block {}.  Expected {}, but space map says it's >= 3.",
+                                          kind, blocknr, expected));
+                    }
+                }
+            }
+            blocknr += 1;
+        }
+
+        if contains_leak {
+            result.bitmap_leaks.push(BitmapLeak {
+                blocknr: first_blocknr,
+                loc: b.loc,
+            });
+        }
+    }
+
+    Ok(result)
+}
+
 // Compare the refernece counts in bitmaps against the expected values
 //
 // `sm` - The in-core space map of expected reference counts
@@ -86,84 +191,58 @@ fn check_low_ref_counts(
     entries: Vec<IndexEntry>,
     sm: ASpaceMap,
 ) -> Result<Vec<BitmapLeak>> {
-    // gathering bitmap blocknr
-    let mut blocks = Vec::with_capacity(entries.len());
-    for i in &entries {
-        blocks.push(i.blocknr);
+    let nr_shards = div_up(entries.len() as u64, BITMAPS_PER_SHARD as u64) as usize;
+    let nr_threads = std::cmp::max(1, std::cmp::min(num_cpus::get(), nr_shards));
+    let pool = ThreadPool::new(nr_threads);
+
+    let entries = Arc::new(entries);
+    let results = Arc::new(Mutex::new(BTreeMap::new()));
+    for shard in 0..nr_shards {
+        let engine = engine.clone();
+        let kind = kind.to_string();
+        let entries = entries.clone();
+        let sm = sm.clone();
+        let results = results.clone();
+
+        pool.execute(move || {
+            let begin = shard * BITMAPS_PER_SHARD;
+            let end = std::cmp::min(begin + BITMAPS_PER_SHARD, entries.len());
+            let blocknr = (begin * ENTRIES_PER_BITMAP) as u64;
+            let r = check_bitmap_shard(engine.as_ref(), &kind, &entries[begin..end], blocknr, &sm);
+            results.lock().unwrap().insert(shard, r);
+        });
     }
+    pool.join();
 
-    // read bitmap blocks
-    // FIXME: we should do this in batches
-    let blocks = engine.read_many(&blocks)?;
-
-    // compare ref-counts in bitmap blocks
+    // merge the results in bitmap order
+    let results = std::mem::take(&mut *results.lock().unwrap());
     let mut leaks = 0;
+    let mut leaks_beyond = 0;
     let mut failed = false;
-    let mut blocknr = 0;
     let mut bitmap_leaks = Vec::new();
-    let sm = sm.lock().unwrap();
-    let nr_blocks = sm.get_nr_blocks()?;
-    for b in blocks.iter().take(entries.len()) {
-        match b {
-            Err(_e) => {
-                return Err(anyhow!("Unable to read bitmap block"));
-            }
-            Ok(b) => {
-                if checksum::metadata_block_type(b.get_data()) != checksum::BT::BITMAP {
-                    report.fatal(&format!(
-                        "Index entry points to block ({}) that isn't a bitmap",
-                        b.loc
-                    ));
-                    failed = true;
-
-                    // FIXME: revert the ref-count at b.loc?
-                }
-
-                let bitmap = unpack::<Bitmap>(b.get_data())?;
-                let first_blocknr = blocknr;
-                let mut contains_leak = false;
-                for e in bitmap.entries.iter() {
-                    if blocknr >= nr_blocks {
-                        break;
-                    }
-
-                    match e {
-                        BitmapEntry::Small(actual) => {
-                            let expected = sm.get(blocknr)?;
-                            if *actual == 1 && expected == 0 {
-                                leaks += 1;
-                                contains_leak = true;
-                            } else if *actual != expected as u8 {
-                                report.fatal(&format!("Bad reference count for {} block {}.  Expected {}, but space map contains {}.",
-                                          kind, blocknr, expected, actual));
-                                failed = true;
-                            }
-                        }
-                        BitmapEntry::Overflow => {
-                            let expected = sm.get(blocknr)?;
-                            if expected < 3 {
-                                report.fatal(&format!("Bad reference count for {} block {}.  Expected {}, but space map says it's >= 3.",
-                                                  kind, blocknr, expected));
-                                failed = true;
-                            }
-                        }
-                    }
-                    blocknr += 1;
-                }
-                if contains_leak {
-                    bitmap_leaks.push(BitmapLeak {
-                        blocknr: first_blocknr,
-                        loc: b.loc,
-                    });
-                }
-            }
+    for (_, r) in results {
+        let r = r?;
+        for msg in &r.errors {
+            report.fatal(msg);
+            failed = true;
         }
+        leaks += r.leaks;
+        leaks_beyond += r.leaks_beyond;
+        bitmap_leaks.extend(r.bitmap_leaks);
     }
 
     if leaks > 0 {
         report.non_fatal(&format!("{} {} blocks have leaked.", leaks, kind));
     }
 
+    if leaks_beyond > 0 {
+        let nr_blocks = sm.lock().unwrap().get_nr_blocks()?;
+        report.non_fatal(&format!(
+            "{} {} blocks beyond the end of the space map, which has {} blocks, are marked in use.",
+            leaks_beyond, kind, nr_blocks
+        ));
+    }
+
     if failed {
         Err(anyhow!("Fatal errors in {} space map", kind))
     } else {
@@ -185,6 +264,20 @@ fn gather_disk_index_entries(
         bitmap_root,
     )?;
 
+    // The entries are indexed by bitmap, and each must be present for
+    // the blocks after it to be found.
+    if let Some(missing) = entries_map
+        .keys()
+        .enumerate()
+        .find(|(i, k)| **k != *i as u64)
+        .map(|(i, _)| i)
+    {
+        return Err(anyhow!(
+            "The data space map index has no bitmap {}",
+            missing
+        ));
+    }
+
     let entries: Vec<IndexEntry> = entries_map.values().cloned().collect();
     inc_entries(&metadata_sm, &entries[0..])?;
 
@@ -206,10 +299,40 @@ fn gather_metadata_index_entries(
 
 //------------------------------------------
 
+// The pool's size is the nr_blocks in the space map root, held in the
+// superblock, and the index must have a bitmap for each of them.  A resize
+// that was interrupted can leave these out of step.  Extra bitmaps are
+// harmless, so long as nothing past the end is marked in use, but a
+// short index means part of the pool has no ref counts.
+fn check_index_covers(report: &Report, root: &SMRoot, nr_bitmaps: usize) -> Result<()> {
+    let needed = div_up(root.nr_blocks, ENTRIES_PER_BITMAP as u64);
+    let nr_bitmaps = nr_bitmaps as u64;
+    if nr_bitmaps < needed {
+        report.fatal(&format!(
+            "The data space map has {} bitmaps, covering {} blocks, but the superblock says the pool has {}",
+            nr_bitmaps,
+            nr_bitmaps * ENTRIES_PER_BITMAP as u64,
+            root.nr_blocks
+        ));
+        return Err(anyhow!("data space map doesn't cover all the data blocks"));
+    }
+
+    if nr_bitmaps > needed {
+        report.info(&format!(
+            "The data space map has {} bitmaps beyond the {} needed for {} blocks",
+            nr_bitmaps - needed,
+            needed,
+            root.nr_blocks
+        ));
+    }
+    Ok(())
+}
+
 // This checks the space map and returns any leak blocks for auto-repair to process.
 //
 // `disk_sm` - The in-core space map of expected data block ref-counts
 // `metadata_sm` - The in-core space for storing ref-counts of verified blocks
+#[instrument(skip_all, fields(nr_blocks = root.nr_blocks))]
 pub fn check_disk_space_map(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
@@ -224,6 +347,7 @@ pub fn check_disk_space_map(
         metadata_sm.clone(),
         ignore_non_fatal,
     )?;
+    check_index_covers(&report, &root, entries.len())?;
 
     // check overflow ref-counts
     {
@@ -240,6 +364,7 @@ pub fn check_disk_space_map(
 // This checks the space map and returns any leak blocks for auto-repair to process.
 //
 // `metadata_sm`: The in-core space map of expected metadata block ref-counts
+#[instrument(skip_all, fields(nr_blocks = root.nr_blocks))]
 pub fn check_metadata_space_map(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
@@ -269,58 +394,33 @@ pub fn check_metadata_space_map(
     check_low_ref_counts(engine, report, "metadata", entries, metadata_sm)
 }
 
-// This assumes the only errors in the space map are leaks.  Entries should just be
-// those that contain leaks.
-pub fn repair_space_map(
+// Repairs are written by rewriting the space maps from the counts the
+// check worked out, which drops any leaks.  This puts them back, for
+// when a space map is rewritten but its leaks aren't to be repaired.
+#[instrument(skip_all)]
+pub fn keep_leaks(
     engine: Arc<dyn IoEngine + Send + Sync>,
-    entries: Vec<BitmapLeak>,
-    sm: ASpaceMap,
+    entries: &[BitmapLeak],
+    sm: &ASpaceMap,
 ) -> Result<()> {
-    let sm = sm.lock().unwrap();
-
-    let mut blocks = Vec::with_capacity(entries.len());
-    for i in &entries {
-        blocks.push(i.loc);
-    }
-
-    // FIXME: we should do this in batches
+    let blocks: Vec<u64> = entries.iter().map(|e| e.loc).collect();
     let rblocks = engine.read_many(&blocks[0..])?;
-    let mut write_blocks = Vec::new();
-
-    for (i, rb) in rblocks.into_iter().enumerate() {
-        if let Ok(b) = rb {
-            let be = &entries[i];
-            let mut blocknr = be.blocknr;
-            let mut bitmap = unpack::<Bitmap>(b.get_data())?;
-            for e in bitmap.entries.iter_mut() {
-                if blocknr >= sm.get_nr_blocks()? {
-                    break;
-                }
 
-                if let BitmapEntry::Small(actual) = e {
-                    let expected = sm.get(blocknr)?;
-                    if *actual == 1 && expected == 0 {
-                        *e = BitmapEntry::Small(0);
-                    }
+    let mut sm = sm.lock().unwrap();
+    let nr_blocks = sm.get_nr_blocks()?;
+    for (be, rb) in entries.iter().zip(rblocks) {
+        let b = rb.map_err(|_| anyhow!("Unable to reread bitmap blocks"))?;
+        let bitmap = unpack::<Bitmap>(b.get_data())?;
+        for (i, e) in bitmap.entries.iter().enumerate() {
+            let blocknr = be.blocknr + i as u64;
+            if blocknr >= nr_blocks {
+                break;
+            }
+            if let BitmapEntry::Small(1) = e {
+                if sm.get(blocknr)? == 0 {
+                    sm.set(blocknr, 1)?;
                 }
-
-                blocknr += 1;
             }
-
-            let mut out = Cursor::new(b.get_data());
-            bitmap.pack(&mut out)?;
-            checksum::write_checksum(b.get_data(), checksum::BT::BITMAP)?;
-
-            write_blocks.push(b);
-        } else {
-            return Err(anyhow!("Unable to reread bitmap blocks for repair"));
-        }
-    }
-
-    let results = engine.write_many(&write_blocks[0..])?;
-    for ret in results {
-        if ret.is_err() {
-            return Err(anyhow!("Unable to repair space map: {:?}", ret));
         }
     }
     Ok(())
@@ -309,6 +309,7 @@ pub fn write_metadata_common(w: &mut WriteBatcher) -> Result<(Vec<IndexEntry>, u
         if limit < len {
             entries.resize_with(len as usize, || BitmapEntry::Small(0));
         }
+        nr_free -= mark_pinned(w.get_pinned(), begin + limit, begin, &mut entries);
 
         let blocknr = write_bitmap(w, entries)?;
 
@@ -325,13 +326,14 @@ pub fn write_metadata_common(w: &mut WriteBatcher) -> Result<(Vec<IndexEntry>, u
     for bm in nr_used_bitmaps..nr_bitmaps {
         let begin = bm as u64 * ENTRIES_PER_BITMAP as u64;
         let len = std::cmp::min(nr_blocks - begin, ENTRIES_PER_BITMAP as u64);
-        let entries = vec![BitmapEntry::Small(0); ENTRIES_PER_BITMAP];
+        let mut entries = vec![BitmapEntry::Small(0); ENTRIES_PER_BITMAP];
+        let nr_pinned = mark_pinned(w.get_pinned(), begin, begin, &mut entries);
         let blocknr = write_bitmap(w, entries)?;
 
         // Insert into the index list
         let ie = IndexEntry {
             blocknr,
-            nr_free: len as u32,
+            nr_free: len as u32 - nr_pinned,
             none_free_before: 0,
         };
         index_entries.push(ie);
@@ -341,6 +343,19 @@ pub fn write_metadata_common(w: &mut WriteBatcher) -> Result<(Vec<IndexEntry>, u
     Ok((index_entries, ref_count_root))
 }
 
+// Pinned blocks lie outside the reserved range, so have to be marked
+// separately.  Returns the number of entries marked.
+fn mark_pinned(pinned: &[u64], from: u64, begin: u64, entries: &mut [BitmapEntry]) -> u32 {
+    let mut nr_marked = 0;
+    for b in pinned {
+        if *b >= from && *b < begin + entries.len() as u64 {
+            entries[(*b - begin) as usize] = BitmapEntry::Small(1);
+            nr_marked += 1;
+        }
+    }
+    nr_marked
+}
+
 fn write_bitmap(w: &mut WriteBatcher, entries: Vec<BitmapEntry>) -> Result<u64> {
     // allocate a new block
     let b = w.alloc_zeroed()?;
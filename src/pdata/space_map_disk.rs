@@ -1,8 +1,14 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use fixedbitset::FixedBitSet;
+use std::sync::Arc;
 
+use crate::checksum;
+use crate::io_engine::*;
 use crate::pdata::btree_builder::*;
+use crate::pdata::btree_walker::*;
 use crate::pdata::space_map::*;
 use crate::pdata::space_map_common::*;
+use crate::pdata::unpack::*;
 use crate::write_batcher::*;
 
 //------------------------------------------
@@ -26,4 +32,60 @@ pub fn write_disk_sm(w: &mut WriteBatcher, sm: &dyn SpaceMap) -> Result<SMRoot>
     })
 }
 
+// Drops the metadata blocks holding an on-disk data space map: the
+// index, the bitmaps and the overflow tree.  Their ref counts are in w.sm.
+pub fn release_disk_sm(w: &mut WriteBatcher, root: &SMRoot) -> Result<()> {
+    let mut bitmaps = ReleasedValues::<IndexEntry>::new();
+    release_btree(w, root.bitmap_root, &mut bitmaps)?;
+    {
+        let mut sm = w.sm.lock().unwrap();
+        for ie in bitmaps.values {
+            sm.dec(ie.blocknr)?;
+        }
+    }
+    release_btree::<u32>(w, root.ref_count_root, &mut NoopRC {})
+}
+
+//------------------------------------------
+
+/// Reads the bitmaps of an on-disk space map, returning the blocks with
+/// a ref count above one.  The overflow btree is never consulted, since
+/// an overflowed count is always at least three.
+pub fn read_shared_blocks(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    root: &SMRoot,
+) -> Result<FixedBitSet> {
+    let entries =
+        btree_to_map::<IndexEntry>(&mut vec![0], engine.clone(), false, root.bitmap_root)?;
+    let mut shared = FixedBitSet::with_capacity(root.nr_blocks as usize);
+
+    let indexed: Vec<(u64, u64)> = entries.iter().map(|(i, ie)| (*i, ie.blocknr)).collect();
+    for chunk in indexed.chunks(engine.get_batch_size()) {
+        let locs: Vec<u64> = chunk.iter().map(|(_, loc)| *loc).collect();
+        let blocks = engine.read_many(&locs)?;
+        for ((i, loc), b) in chunk.iter().zip(blocks) {
+            let b = b.map_err(|_| anyhow!("unable to read bitmap block {}", loc))?;
+            if checksum::metadata_block_type(b.get_data()) != checksum::BT::BITMAP {
+                return Err(anyhow!("block {} isn't a bitmap", loc));
+            }
+
+            let bitmap = unpack::<Bitmap>(b.get_data())?;
+            let first = *i as usize * ENTRIES_PER_BITMAP;
+            for (n, e) in bitmap.entries.iter().enumerate() {
+                let blocknr = first + n;
+                if blocknr >= shared.len() {
+                    break;
+                }
+                match e {
+                    BitmapEntry::Small(count) if *count > 1 => shared.insert(blocknr),
+                    BitmapEntry::Overflow => shared.insert(blocknr),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Ok(shared)
+}
+
 //------------------------------------------
@@ -6,6 +6,9 @@ use std::sync::{Arc, Mutex};
 
 use crate::checksum;
 use crate::io_engine::*;
+use crate::math::div_up;
+use crate::pdata::btree_builder::*;
+use crate::pdata::btree_walker::btree_to_map;
 use crate::pdata::space_map::*;
 use crate::pdata::space_map_common::*;
 use crate::pdata::unpack::*;
@@ -14,7 +17,7 @@ use crate::write_batcher::*;
 //------------------------------------------
 
 const MAX_METADATA_BITMAPS: usize = 255;
-const MAX_METADATA_BLOCKS: usize = MAX_METADATA_BITMAPS * ENTRIES_PER_BITMAP;
+pub const MAX_METADATA_BLOCKS: usize = MAX_METADATA_BITMAPS * ENTRIES_PER_BITMAP;
 
 //------------------------------------------
 
@@ -107,6 +110,14 @@ fn adjust_counts(
 
 //------------------------------------------
 
+/// The memory needed by core_metadata_sm() for the given size.
+pub fn core_metadata_sm_bytes(nr_blocks: u64, max_count: u32) -> u64 {
+    core_sm_bytes(
+        std::cmp::min(nr_blocks, MAX_METADATA_BLOCKS as u64),
+        max_count,
+    )
+}
+
 pub fn core_metadata_sm(nr_blocks: u64, max_count: u32) -> Arc<Mutex<dyn SpaceMap + Send + Sync>> {
     core_sm(
         std::cmp::min(nr_blocks, MAX_METADATA_BLOCKS as u64),
@@ -114,6 +125,35 @@ pub fn core_metadata_sm(nr_blocks: u64, max_count: u32) -> Arc<Mutex<dyn SpaceMa
     )
 }
 
+/// Reads the reference count of a single block from the on-disk metadata
+/// space map.
+pub fn read_metadata_ref_count(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    root: &SMRoot,
+    b: u64,
+) -> Result<u32> {
+    if b >= root.nr_blocks {
+        return Err(anyhow!("block {} is beyond the end of the metadata", b));
+    }
+
+    let index = unpack::<MetadataIndex>(engine.read(root.bitmap_root)?.get_data())?;
+    let ie = index
+        .indexes
+        .get(block_to_bitmap(b))
+        .ok_or_else(|| anyhow!("no bitmap covers block {}", b))?;
+    let bitmap = unpack::<Bitmap>(engine.read(ie.blocknr)?.get_data())?;
+    match bitmap.entries[b as usize % ENTRIES_PER_BITMAP] {
+        BitmapEntry::Small(count) => Ok(count as u32),
+        BitmapEntry::Overflow => {
+            let counts = btree_to_map::<u32>(&mut vec![0], engine, false, root.ref_count_root)?;
+            counts
+                .get(&b)
+                .cloned()
+                .ok_or_else(|| anyhow!("no overflow ref count for block {}", b))
+        }
+    }
+}
+
 pub fn write_metadata_sm(w: &mut WriteBatcher) -> Result<SMRoot> {
     let r1 = w.get_reserved_range();
 
@@ -164,3 +204,115 @@ pub fn write_metadata_sm(w: &mut WriteBatcher) -> Result<SMRoot> {
 }
 
 //------------------------------------------
+
+// Writes the counts in w.sm as a new metadata space map, for metadata
+// that's already in use, eg, being repaired copy-on-write.  Unlike
+// write_metadata_sm(), this doesn't assume everything past the blocks
+// allocated so far is free, so the blocks for the space map itself are
+// allocated before any counts are read.
+pub fn rewrite_metadata_sm(w: &mut WriteBatcher) -> Result<SMRoot> {
+    use BitmapEntry::*;
+
+    let nr_blocks = w.sm.lock().unwrap().get_nr_blocks()?;
+    let nr_bitmaps = div_up(nr_blocks, ENTRIES_PER_BITMAP as u64) as usize;
+    if nr_bitmaps > MAX_METADATA_BITMAPS {
+        return Err(anyhow!("too many metadata blocks for the space map"));
+    }
+
+    let index_block = w.alloc_zeroed()?;
+    let mut bitmaps = Vec::with_capacity(nr_bitmaps);
+    for _ in 0..nr_bitmaps {
+        bitmaps.push(w.alloc_zeroed()?);
+    }
+
+    // Overflowed counts are at least three, so the blocks the overflow
+    // tree is written to, each with a count of one, don't change them.
+    let mut overflow = Vec::new();
+    {
+        let sm = w.sm.lock().unwrap();
+        for b in 0..nr_blocks {
+            let rc = sm.get(b)?;
+            if rc > 2 {
+                overflow.push((b, rc));
+            }
+        }
+    }
+    let mut overflow_builder: BTreeBuilder<u32> = BTreeBuilder::new(Box::new(NoopRC {}));
+    for (b, rc) in overflow {
+        overflow_builder.push_value(w, b, rc)?;
+    }
+    let ref_count_root = overflow_builder.complete(w)?;
+
+    let mut indexes = Vec::with_capacity(nr_bitmaps);
+    let mut nr_allocated = 0;
+    for (bm, blk) in bitmaps.into_iter().enumerate() {
+        let begin = bm as u64 * ENTRIES_PER_BITMAP as u64;
+        let len = std::cmp::min(nr_blocks - begin, ENTRIES_PER_BITMAP as u64);
+        let mut entries = Vec::with_capacity(ENTRIES_PER_BITMAP);
+        let mut first_free: Option<u32> = None;
+        let mut nr_free: u32 = 0;
+
+        {
+            let sm = w.sm.lock().unwrap();
+            for i in 0..len {
+                let e = match sm.get(begin + i)? {
+                    0 => {
+                        nr_free += 1;
+                        if first_free.is_none() {
+                            first_free = Some(i as u32);
+                        }
+                        Small(0)
+                    }
+                    1 => Small(1),
+                    2 => Small(2),
+                    _ => Overflow,
+                };
+                entries.push(e);
+            }
+        }
+        nr_allocated += len - nr_free as u64;
+
+        let blocknr = blk.loc;
+        let bitmap = Bitmap { blocknr, entries };
+        bitmap.pack(&mut Cursor::new(blk.get_data()))?;
+        w.write(blk, checksum::BT::BITMAP)?;
+
+        indexes.push(IndexEntry {
+            blocknr,
+            nr_free,
+            none_free_before: first_free.unwrap_or(len as u32),
+        });
+    }
+
+    let bitmap_root = index_block.loc;
+    let metadata_index = MetadataIndex {
+        blocknr: bitmap_root,
+        indexes,
+    };
+    metadata_index.pack(&mut Cursor::new(index_block.get_data()))?;
+    w.write(index_block, checksum::BT::INDEX)?;
+    w.flush()?;
+
+    Ok(SMRoot {
+        nr_blocks,
+        nr_allocated,
+        bitmap_root,
+        ref_count_root,
+    })
+}
+
+// Drops the blocks holding an on-disk metadata space map, whose ref
+// counts, including those of its own blocks, are in w.sm.
+pub fn release_metadata_sm(w: &mut WriteBatcher, root: &SMRoot) -> Result<()> {
+    let index = unpack::<MetadataIndex>(w.read(root.bitmap_root)?.get_data())?;
+    {
+        let mut sm = w.sm.lock().unwrap();
+        sm.dec(root.bitmap_root)?;
+        for ie in &index.indexes {
+            sm.dec(ie.blocknr)?;
+        }
+    }
+    release_btree::<u32>(w, root.ref_count_root, &mut NoopRC {})
+}
+
+//------------------------------------------
@@ -0,0 +1,251 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::report::Report;
+
+//------------------------------------------
+
+/// What a checker does about a finding that isn't fatal damage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    // Pass, mentioning it only with -v
+    Ignore,
+
+    // Pass, with a warning
+    Warn,
+
+    // Exit with NEEDS_REPAIR
+    Fail,
+
+    // Repair it in place
+    Fix,
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(Action::Ignore),
+            "warn" => Ok(Action::Warn),
+            "fail" => Ok(Action::Fail),
+            "fix" => Ok(Action::Fix),
+            _ => Err(anyhow!("unknown action '{}'", s)),
+        }
+    }
+}
+
+/// A kind of finding a checker can act on, with the action taken when
+/// neither a policy file nor a command line flag says otherwise.  A
+/// lossy fix throws something away, eg, mappings, so it's only made when
+/// asked for by name, never by --auto-repair.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Category {
+    pub name: &'static str,
+    pub default: Action,
+    pub fixable: bool,
+    pub lossy: bool,
+}
+
+/// Maps each category of finding to an action, so sites can decide what
+/// should stop a pool from being activated.  Fatal damage, eg, an
+/// unreadable btree, always fails.
+///
+/// The file is a list of 'category = action' lines, '#' starts a
+/// comment, and the actions are ignore, warn, fail and fix:
+///
+///   data_leaks = fix
+///   needs_check = fail
+///
+/// Categories that aren't listed keep their defaults.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Policy {
+    actions: Vec<(&'static Category, Action)>,
+}
+
+impl Policy {
+    pub fn new(categories: &'static [Category]) -> Policy {
+        Policy {
+            actions: categories.iter().map(|c| (c, c.default)).collect(),
+        }
+    }
+
+    pub fn parse(categories: &'static [Category], text: &str) -> Result<Policy> {
+        let mut policy = Policy::new(categories);
+
+        for (n, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(i) => &line[..i],
+                None => line,
+            }
+            .trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let (name, action) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("line {}: expected 'category = action'", n + 1))?;
+            action
+                .trim()
+                .parse()
+                .and_then(|action| policy.set(name.trim(), action))
+                .with_context(|| format!("line {}", n + 1))?;
+        }
+
+        Ok(policy)
+    }
+
+    /// Reads the policy file at path.
+    pub fn read(categories: &'static [Category], path: &Path) -> Result<Policy> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("couldn't read policy file '{}'", path.display()))?;
+        Policy::parse(categories, &text)
+            .with_context(|| format!("bad policy file '{}'", path.display()))
+    }
+
+    pub fn set(&mut self, name: &str, action: Action) -> Result<()> {
+        let entry = self
+            .actions
+            .iter_mut()
+            .find(|(c, _)| c.name == name)
+            .ok_or_else(|| anyhow!("unknown category '{}'", name))?;
+        if action == Action::Fix && !entry.0.fixable {
+            return Err(anyhow!("{} can't be fixed", name));
+        }
+        entry.1 = action;
+        Ok(())
+    }
+
+    pub fn action(&self, name: &str) -> Action {
+        self.actions
+            .iter()
+            .find(|(c, _)| c.name == name)
+            .map(|(_, action)| *action)
+            .unwrap_or_else(|| panic!("unknown category '{}'", name))
+    }
+
+    /// Whether acting on the policy may write to the metadata.
+    pub fn fixes_anything(&self) -> bool {
+        self.actions
+            .iter()
+            .any(|(_, action)| *action == Action::Fix)
+    }
+
+    /// Decides what to do about a finding, described by msg.  Returns an
+    /// error if it should fail the check, or whether it should be fixed.
+    /// Every finding should be weighed before anything is fixed, so a
+    /// failing check leaves the metadata untouched.
+    pub fn weigh(&self, name: &str, found: bool, msg: &str, report: &Report) -> Result<bool> {
+        if !found {
+            return Ok(false);
+        }
+
+        match self.action(name) {
+            Action::Ignore => {
+                report.verbose(&format!("{}, ignored by policy", msg));
+                Ok(false)
+            }
+            Action::Warn => {
+                report.info(&format!("warning: {}", msg));
+                Ok(false)
+            }
+            Action::Fail => Err(anyhow!("{}", msg)),
+            Action::Fix => Ok(true),
+        }
+    }
+
+    /// As --auto-repair, fixes everything that can be fixed without
+    /// losing anything.
+    pub fn fix_all(&mut self) {
+        for (c, action) in &mut self.actions {
+            if c.fixable && !c.lossy {
+                *action = Action::Fix;
+            }
+        }
+    }
+
+    /// As --ignore-non-fatal-errors, warns about what would fail.
+    pub fn fail_nothing(&mut self) {
+        for (_, action) in &mut self.actions {
+            if *action == Action::Fail {
+                *action = Action::Warn;
+            }
+        }
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CATEGORIES: &[Category] = &[
+        Category {
+            name: "leaks",
+            default: Action::Fail,
+            fixable: true,
+            lossy: false,
+        },
+        Category {
+            name: "hints",
+            default: Action::Ignore,
+            fixable: false,
+            lossy: false,
+        },
+        Category {
+            name: "orphans",
+            default: Action::Fail,
+            fixable: true,
+            lossy: true,
+        },
+    ];
+
+    #[test]
+    fn test_defaults() {
+        let policy = Policy::parse(CATEGORIES, "# nothing here\n\n").unwrap();
+        assert_eq!(policy, Policy::new(CATEGORIES));
+        assert_eq!(policy.action("leaks"), Action::Fail);
+        assert!(!policy.fixes_anything());
+    }
+
+    #[test]
+    fn test_set_actions() {
+        let mut policy = Policy::parse(CATEGORIES, "leaks = fix  # comment\nhints=warn\n").unwrap();
+        assert_eq!(policy.action("leaks"), Action::Fix);
+        assert_eq!(policy.action("hints"), Action::Warn);
+        assert!(policy.fixes_anything());
+
+        policy.set("leaks", Action::Fail).unwrap();
+        policy.fail_nothing();
+        assert_eq!(policy.action("leaks"), Action::Warn);
+        policy.fix_all();
+        assert_eq!(policy.action("leaks"), Action::Fix);
+        assert_eq!(policy.action("hints"), Action::Warn);
+    }
+
+    #[test]
+    fn test_lossy_fixes_are_never_automatic() {
+        let mut policy = Policy::new(CATEGORIES);
+        policy.fix_all();
+        assert_eq!(policy.action("leaks"), Action::Fix);
+        assert_eq!(policy.action("orphans"), Action::Fail);
+
+        policy.set("orphans", Action::Fix).unwrap();
+        assert_eq!(policy.action("orphans"), Action::Fix);
+    }
+
+    #[test]
+    fn test_bad_lines() {
+        assert!(Policy::parse(CATEGORIES, "leaks").is_err());
+        assert!(Policy::parse(CATEGORIES, "leaks = mend").is_err());
+        assert!(Policy::parse(CATEGORIES, "colour = warn").is_err());
+        assert!(Policy::parse(CATEGORIES, "hints = fix").is_err());
+    }
+}
+
+//------------------------------------------
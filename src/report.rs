@@ -1,5 +1,6 @@
 use indicatif::{ProgressBar, ProgressStyle};
 use std::sync::Mutex;
+use tracing::{debug, error, info, warn};
 
 //------------------------------------------
 
@@ -12,6 +13,30 @@ pub enum ReportOutcome {
 
 use ReportOutcome::*;
 
+/// How much a tool should say about what it's doing.  Selected with
+/// -q/--quiet and a repeated -v/--verbose on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    Debug,
+}
+
+impl Verbosity {
+    pub fn from_flags(quiet: bool, nr_verbose: u64) -> Verbosity {
+        if quiet {
+            Verbosity::Quiet
+        } else {
+            match nr_verbose {
+                0 => Verbosity::Normal,
+                1 => Verbosity::Verbose,
+                _ => Verbosity::Debug,
+            }
+        }
+    }
+}
+
 impl ReportOutcome {
     pub fn combine(lhs: &ReportOutcome, rhs: &ReportOutcome) -> ReportOutcome {
         match (lhs, rhs) {
@@ -26,6 +51,7 @@ impl ReportOutcome {
 
 pub struct Report {
     outcome: Mutex<ReportOutcome>,
+    verbosity: Verbosity,
     inner: Mutex<Box<dyn ReportInner + Send>>,
 }
 
@@ -42,21 +68,47 @@ impl Report {
     pub fn new(inner: Box<dyn ReportInner + Send>) -> Report {
         Report {
             outcome: Mutex::new(Success),
+            verbosity: Verbosity::Normal,
             inner: Mutex::new(inner),
         }
     }
 
+    pub fn set_verbosity(&mut self, verbosity: Verbosity) {
+        self.verbosity = verbosity;
+    }
+
+    pub fn get_verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    // Every message is also traced, so a --log-file gets them all,
+    // whatever the verbosity.
+    fn log_at(&self, level: Verbosity, txt: &str) {
+        if level >= Verbosity::Debug {
+            debug!("{}", txt);
+        } else {
+            info!("{}", txt);
+        }
+
+        if self.verbosity >= level {
+            let mut inner = self.inner.lock().unwrap();
+            inner.log(txt)
+        }
+    }
+
     fn update_outcome(&self, rhs: ReportOutcome) {
         let mut lhs = self.outcome.lock().unwrap();
         *lhs = ReportOutcome::combine(&lhs, &rhs);
     }
 
     pub fn set_title(&self, txt: &str) {
+        info!("{}", txt);
         let mut inner = self.inner.lock().unwrap();
         inner.set_title(txt)
     }
 
     pub fn set_sub_title(&self, txt: &str) {
+        info!("{}", txt);
         let mut inner = self.inner.lock().unwrap();
         inner.set_sub_title(txt)
     }
@@ -67,17 +119,28 @@ impl Report {
     }
 
     pub fn info(&self, txt: &str) {
-        let mut inner = self.inner.lock().unwrap();
-        inner.log(txt)
+        self.log_at(Verbosity::Normal, txt)
+    }
+
+    // Extra detail, only shown with -v
+    pub fn verbose(&self, txt: &str) {
+        self.log_at(Verbosity::Verbose, txt)
+    }
+
+    // Developer level detail, only shown with -vv
+    pub fn debug(&self, txt: &str) {
+        self.log_at(Verbosity::Debug, txt)
     }
 
     pub fn non_fatal(&self, txt: &str) {
+        warn!("{}", txt);
         self.update_outcome(NonFatal);
         let mut inner = self.inner.lock().unwrap();
         inner.log(txt)
     }
 
     pub fn fatal(&self, txt: &str) {
+        error!("{}", txt);
         self.update_outcome(Fatal);
         let mut inner = self.inner.lock().unwrap();
         inner.log(txt)
@@ -96,6 +159,7 @@ impl Report {
     // Force a message to be printed to stdout.  eg,
     // TRANSACTION_ID = <blah>
     pub fn to_stdout(&self, txt: &str) {
+        info!("{}", txt);
         let mut inner = self.inner.lock().unwrap();
         inner.to_stdout(txt)
     }
@@ -115,8 +179,8 @@ impl ReportInner for PBInner {
     }
 
     fn set_sub_title(&mut self, txt: &str) {
-        //let mut fmt = "".to_string(); //Checking thin metadata".to_string(); //self.title.clone();
-        let mut fmt = "Checking thin metadata [{bar:40}] Remaining {eta}, ".to_string();
+        let mut fmt = self.title.clone();
+        fmt.push_str(" [{bar:40}] Remaining {eta}, ");
         fmt.push_str(txt);
         self.bar.set_style(
             ProgressStyle::default_bar()
@@ -214,7 +278,9 @@ impl ReportInner for QuietInner {
 }
 
 pub fn mk_quiet_report() -> Report {
-    Report::new(Box::new(QuietInner {}))
+    let mut report = Report::new(Box::new(QuietInner {}));
+    report.set_verbosity(Verbosity::Quiet);
+    report
 }
 
 //------------------------------------------
@@ -1,9 +1,13 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::fs::OpenOptions;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
+use std::time::{Duration, Instant};
 //use std::os::unix::fs::OpenOptionsExt;
 
+use crate::report::Report;
+
 pub type Sector = u64;
 
 #[derive(Debug)]
@@ -25,18 +29,55 @@ where
     Ok(())
 }
 
-fn copy_region<W>(file: &mut W, r: &Region) -> Result<()>
+// Holds the copy back to a given rate, averaged over the whole copy.
+struct Throttle {
+    bytes_per_sec: u64,
+    start: Instant,
+    copied: u64,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Throttle {
+        Throttle {
+            bytes_per_sec,
+            start: Instant::now(),
+            copied: 0,
+        }
+    }
+
+    // About a tenth of a second's worth, so the copy is spread out
+    // rather than done in bursts.
+    fn step_bytes(&self) -> u64 {
+        u64::max(self.bytes_per_sec / 10, 64 * 1024)
+    }
+
+    fn copied(&mut self, bytes: u64) {
+        self.copied += bytes;
+        let due = Duration::from_secs_f64(self.copied as f64 / self.bytes_per_sec as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        }
+    }
+}
+
+fn copy_region<W>(file: &mut W, r: &Region, throttle: &mut Option<Throttle>) -> Result<()>
 where
     W: Write + Seek + Read,
 {
     const MAX_BYTES: Sector = 1024 * 1024 * 64;
 
+    let max_step = match throttle {
+        Some(t) => u64::min(t.step_bytes(), MAX_BYTES),
+        None => MAX_BYTES,
+    };
+
     let src_bytes = r.src * 512;
     let dest_bytes = r.dest * 512;
     let len_bytes = r.len * 512;
     let mut written = 0;
     while written != len_bytes {
-        let step = u64::min(len_bytes - written, MAX_BYTES);
+        let step = u64::min(len_bytes - written, max_step);
         copy_step(
             file,
             src_bytes + written,
@@ -44,22 +85,173 @@ where
             step as usize,
         )?;
         written += step;
+
+        if let Some(t) = throttle {
+            t.copied(step);
+        }
     }
     Ok(())
 }
 
-pub fn copy(path: &Path, regions: &[Region]) -> Result<()> {
+/// Copies the regions within the file or device at `path`, no faster
+/// than `bytes_per_sec`, if given.
+pub fn copy(
+    path: &Path,
+    regions: &[Region],
+    bytes_per_sec: Option<u64>,
+    report: &Report,
+) -> Result<()> {
     let mut input = OpenOptions::new()
         .read(true)
         .write(true)
         //.custom_flags(libc::O_DIRECT)
         .open(path)?;
 
+    let mut throttle = bytes_per_sec.map(Throttle::new);
     for r in regions {
-        eprintln!("copying {:?}", r);
-        copy_region(&mut input, r)?;
+        report.debug(&format!("copying {:?}", r));
+        copy_region(&mut input, r, &mut throttle)?;
     }
     input.flush()?;
 
     Ok(())
 }
+
+// Returns the offset, within the region, of the first byte that
+// differs between the source and destination.
+fn verify_region<R>(
+    file: &mut R,
+    r: &Region,
+    throttle: &mut Option<Throttle>,
+) -> Result<Option<u64>>
+where
+    R: Seek + Read,
+{
+    const MAX_BYTES: Sector = 1024 * 1024 * 16;
+
+    let max_step = match throttle {
+        Some(t) => u64::min(t.step_bytes(), MAX_BYTES),
+        None => MAX_BYTES,
+    };
+
+    let src_bytes = r.src * 512;
+    let dest_bytes = r.dest * 512;
+    let len_bytes = r.len * 512;
+    let mut checked = 0;
+    while checked != len_bytes {
+        let step = u64::min(len_bytes - checked, max_step);
+        let mut src = vec![0; step as usize];
+        let mut dest = vec![0; step as usize];
+        file.seek(SeekFrom::Start(src_bytes + checked))?;
+        file.read_exact(&mut src)?;
+        file.seek(SeekFrom::Start(dest_bytes + checked))?;
+        file.read_exact(&mut dest)?;
+
+        if let Some(i) = src.iter().zip(dest.iter()).position(|(s, d)| s != d) {
+            return Ok(Some(checked + i as u64));
+        }
+        checked += step;
+
+        if let Some(t) = throttle {
+            t.copied(step);
+        }
+    }
+    Ok(None)
+}
+
+/// Rereads every region copied, and checks the destination matches the
+/// source.  The page cache is dropped first, so the data is read back
+/// from the device rather than from memory.
+pub fn verify(
+    path: &Path,
+    regions: &[Region],
+    bytes_per_sec: Option<u64>,
+    report: &Report,
+) -> Result<()> {
+    let mut input = OpenOptions::new().read(true).open(path)?;
+    input.sync_all()?;
+    unsafe {
+        libc::posix_fadvise(input.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+
+    let mut throttle = bytes_per_sec.map(Throttle::new);
+    let mut nr_bad = 0;
+    for r in regions {
+        report.debug(&format!("verifying {:?}", r));
+        if let Some(offset) = verify_region(&mut input, r, &mut throttle)? {
+            report.fatal(&format!(
+                "copy of sector {} to sector {} differs at byte {} of {}",
+                r.src,
+                r.dest,
+                offset,
+                r.len * 512
+            ));
+            nr_bad += 1;
+        }
+    }
+
+    if nr_bad > 0 {
+        return Err(anyhow!(
+            "{} of {} copied regions failed verification",
+            nr_bad,
+            regions.len()
+        ));
+    }
+    Ok(())
+}
+
+//---------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::mk_quiet_report;
+
+    #[test]
+    fn throttled_copy_is_held_back() -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(&vec![1u8; 2 << 20])?;
+        file.write_all(&vec![0u8; 2 << 20])?;
+
+        let regions = [Region {
+            src: 0,
+            dest: 4096,
+            len: 4096,
+        }];
+
+        // 2MiB at 4MiB/s
+        let start = Instant::now();
+        copy(file.path(), &regions, Some(4 << 20), &mk_quiet_report())?;
+        assert!(start.elapsed() >= Duration::from_millis(450));
+
+        let mut buf = vec![0u8; 2 << 20];
+        file.seek(SeekFrom::Start(2 << 20))?;
+        file.read_exact(&mut buf)?;
+        assert!(buf.iter().all(|b| *b == 1));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_detects_bad_copies() -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(&vec![1u8; 1 << 20])?;
+        file.write_all(&vec![0u8; 1 << 20])?;
+
+        let regions = [Region {
+            src: 0,
+            dest: 2048,
+            len: 2048,
+        }];
+        let report = mk_quiet_report();
+        assert!(verify(file.path(), &regions, None, &report).is_err());
+
+        copy(file.path(), &regions, None, &report)?;
+        verify(file.path(), &regions, None, &report)?;
+
+        file.seek(SeekFrom::Start((1 << 20) + 12345))?;
+        file.write_all(&[7])?;
+        file.flush()?;
+        assert!(verify(file.path(), &regions, None, &report).is_err());
+        Ok(())
+    }
+}
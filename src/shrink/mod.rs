@@ -1,3 +1,4 @@
+pub mod policy;
 pub mod toplevel;
 
 mod copier;
@@ -0,0 +1,392 @@
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+//---------------------------------------
+
+pub type BlockRange = std::ops::Range<u64>;
+
+/// For each run of mappings beyond the new end of the pool, keyed by
+/// its first block, the block just after the owning device's last
+/// mapping below the new end.
+pub type Hints = BTreeMap<u64, u64>;
+
+/// Decides where the blocks beyond the new end of the pool are moved to.
+pub trait VacatePolicy {
+    /// Chooses destinations within `free` for every range in `above`.
+    /// Both are sorted, and `free` has room for all of `above`.  The
+    /// remaps returned must be sorted by source.
+    fn build_remaps(
+        &self,
+        above: Vec<BlockRange>,
+        free: Vec<BlockRange>,
+        hints: &Hints,
+    ) -> Vec<(BlockRange, BlockRange)>;
+}
+
+pub const POLICY_NAMES: [&str; 3] = ["first-fit", "best-fit", "near-owner"];
+
+pub fn mk_policy(name: &str) -> Result<Box<dyn VacatePolicy>> {
+    match name {
+        "first-fit" => Ok(Box::new(FirstFit {})),
+        "best-fit" => Ok(Box::new(BestFit {})),
+        "near-owner" => Ok(Box::new(NearOwner {})),
+        _ => Err(anyhow!("unknown vacate policy '{}'", name)),
+    }
+}
+
+fn range_len(r: &BlockRange) -> u64 {
+    r.end - r.start
+}
+
+//---------------------------------------
+
+/// Fills the free space from the start of the pool, taking the ranges
+/// in order.  Moves the fewest blocks furthest, but splits ranges across
+/// whatever holes come first.
+pub struct FirstFit {}
+
+impl VacatePolicy for FirstFit {
+    fn build_remaps(
+        &self,
+        ranges: Vec<BlockRange>,
+        free: Vec<BlockRange>,
+        _hints: &Hints,
+    ) -> Vec<(BlockRange, BlockRange)> {
+        use std::cmp::Ordering;
+
+        let mut remap = Vec::new();
+        let mut range_iter = ranges.into_iter();
+        let mut free_iter = free.into_iter();
+
+        let mut r_ = range_iter.next();
+        let mut f_ = free_iter.next();
+
+        while let (Some(r), Some(f)) = (r_, f_) {
+            let rlen = range_len(&r);
+            let flen = range_len(&f);
+
+            match rlen.cmp(&flen) {
+                Ordering::Less => {
+                    // range fits into the free chunk
+                    remap.push((r, f.start..(f.start + rlen)));
+                    f_ = Some((f.start + rlen)..f.end);
+                    r_ = range_iter.next();
+                }
+                Ordering::Equal => {
+                    remap.push((r, f));
+                    r_ = range_iter.next();
+                    f_ = free_iter.next();
+                }
+                Ordering::Greater => {
+                    remap.push((r.start..(r.start + flen), f));
+                    r_ = Some((r.start + flen)..r.end);
+                    f_ = free_iter.next();
+                }
+            }
+        }
+
+        remap
+    }
+}
+
+//---------------------------------------
+
+// The free holes, indexed both by position and by size.
+struct Holes {
+    by_start: BTreeMap<u64, u64>,
+    by_len: BTreeSet<(u64, u64)>,
+}
+
+impl Holes {
+    fn new(free: Vec<BlockRange>) -> Holes {
+        let mut holes = Holes {
+            by_start: BTreeMap::new(),
+            by_len: BTreeSet::new(),
+        };
+        for f in free {
+            holes.insert(f);
+        }
+        holes
+    }
+
+    fn insert(&mut self, r: BlockRange) {
+        if r.start < r.end {
+            self.by_start.insert(r.start, r.end);
+            self.by_len.insert((range_len(&r), r.start));
+        }
+    }
+
+    fn remove(&mut self, start: u64) -> BlockRange {
+        let end = self.by_start.remove(&start).unwrap();
+        self.by_len.remove(&(end - start, start));
+        start..end
+    }
+
+    // Takes up to `len` blocks from `at` onwards, which must be within
+    // a hole, returning what was taken.
+    fn take(&mut self, at: u64, len: u64) -> BlockRange {
+        let (&start, _) = self.by_start.range(..=at).next_back().unwrap();
+        let hole = self.remove(start);
+        let taken = at..std::cmp::min(hole.end, at + len);
+        self.insert(hole.start..at);
+        self.insert(taken.end..hole.end);
+        taken
+    }
+
+    // The smallest hole holding at least `len` blocks.
+    fn smallest_fitting(&self, len: u64) -> Option<u64> {
+        self.by_len
+            .range((len, 0)..)
+            .next()
+            .map(|(_, start)| *start)
+    }
+
+    fn largest(&self) -> Option<u64> {
+        self.by_len.iter().next_back().map(|(_, start)| *start)
+    }
+
+    // The block to allocate from that's closest to, at or after, `near`.
+    fn nearest(&self, near: u64) -> Option<u64> {
+        if let Some((_, &end)) = self.by_start.range(..=near).next_back() {
+            if near < end {
+                return Some(near);
+            }
+        }
+        self.by_start
+            .range(near..)
+            .next()
+            .or_else(|| self.by_start.iter().next_back())
+            .map(|(start, _)| *start)
+    }
+}
+
+fn sort_remaps(mut remaps: Vec<(BlockRange, BlockRange)>) -> Vec<(BlockRange, BlockRange)> {
+    remaps.sort_by_key(|(from, _)| from.start);
+    remaps
+}
+
+//---------------------------------------
+
+/// Moves each range into the smallest hole that holds it whole, so
+/// ranges stay contiguous and the large holes are kept for large
+/// ranges.  Ranges that fit nowhere are split across the largest holes.
+pub struct BestFit {}
+
+impl VacatePolicy for BestFit {
+    fn build_remaps(
+        &self,
+        above: Vec<BlockRange>,
+        free: Vec<BlockRange>,
+        _hints: &Hints,
+    ) -> Vec<(BlockRange, BlockRange)> {
+        let mut holes = Holes::new(free);
+        let mut remaps = Vec::new();
+
+        for mut r in above {
+            while r.start < r.end {
+                let start = match holes
+                    .smallest_fitting(range_len(&r))
+                    .or_else(|| holes.largest())
+                {
+                    Some(start) => start,
+                    None => return sort_remaps(remaps),
+                };
+                let to = holes.take(start, range_len(&r));
+                let len = range_len(&to);
+                remaps.push((r.start..(r.start + len), to));
+                r = (r.start + len)..r.end;
+            }
+        }
+
+        sort_remaps(remaps)
+    }
+}
+
+//---------------------------------------
+
+/// Moves each run of a device's mappings as close after that device's
+/// other data as there's room for, so a device's blocks stay together.
+/// Runs without a hint are placed from the start of the pool.
+pub struct NearOwner {}
+
+impl VacatePolicy for NearOwner {
+    fn build_remaps(
+        &self,
+        above: Vec<BlockRange>,
+        free: Vec<BlockRange>,
+        hints: &Hints,
+    ) -> Vec<(BlockRange, BlockRange)> {
+        let mut holes = Holes::new(free);
+        let mut remaps = Vec::new();
+
+        for r in above {
+            // Each hinted run within the range is placed separately.
+            let mut cuts: Vec<u64> = hints.range(r.start + 1..r.end).map(|(b, _)| *b).collect();
+            cuts.push(r.end);
+
+            let mut begin = r.start;
+            for end in cuts {
+                let mut near = hints
+                    .range(r.start..=begin)
+                    .next_back()
+                    .map(|(_, near)| *near)
+                    .unwrap_or(0);
+
+                while begin < end {
+                    let at = match holes.nearest(near) {
+                        Some(at) => at,
+                        None => return sort_remaps(remaps),
+                    };
+                    let to = holes.take(at, end - begin);
+                    let len = range_len(&to);
+                    near = to.end;
+                    remaps.push((begin..(begin + len), to));
+                    begin += len;
+                }
+            }
+        }
+
+        sort_remaps(remaps)
+    }
+}
+
+//---------------------------------------
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    struct Test {
+        ranges: Vec<BlockRange>,
+        free: Vec<BlockRange>,
+        result: Vec<(BlockRange, BlockRange)>,
+    }
+
+    fn run_tests(policy: &dyn VacatePolicy, hints: &Hints, tests: Vec<Test>) {
+        for t in tests {
+            assert_eq!(policy.build_remaps(t.ranges, t.free, hints), t.result);
+        }
+    }
+
+    #[test]
+    fn test_first_fit() {
+        let tests = vec![
+            Test {
+                ranges: vec![],
+                free: vec![],
+                result: vec![],
+            },
+            Test {
+                ranges: vec![],
+                free: vec![0..100],
+                result: vec![],
+            },
+            Test {
+                ranges: vec![1000..1002],
+                free: vec![0..100],
+                result: vec![(1000..1002, 0..2)],
+            },
+            Test {
+                ranges: vec![1000..1002, 1100..1110],
+                free: vec![0..100],
+                result: vec![(1000..1002, 0..2), (1100..1110, 2..12)],
+            },
+            Test {
+                ranges: vec![100..120],
+                free: vec![0..5, 20..23, 30..50],
+                result: vec![(100..105, 0..5), (105..108, 20..23), (108..120, 30..42)],
+            },
+        ];
+
+        run_tests(&FirstFit {}, &Hints::new(), tests);
+    }
+
+    #[test]
+    fn test_best_fit() {
+        let tests = vec![
+            Test {
+                ranges: vec![100..110],
+                free: vec![0..50, 60..70, 80..90],
+                result: vec![(100..110, 60..70)],
+            },
+            Test {
+                ranges: vec![100..103, 200..210],
+                free: vec![0..5, 20..30],
+                result: vec![(100..103, 0..3), (200..210, 20..30)],
+            },
+            Test {
+                ranges: vec![100..120],
+                free: vec![0..5, 20..23, 30..50],
+                result: vec![(100..120, 30..50)],
+            },
+            Test {
+                ranges: vec![100..110],
+                free: vec![0..4, 10..16],
+                result: vec![(100..106, 10..16), (106..110, 0..4)],
+            },
+        ];
+
+        run_tests(&BestFit {}, &Hints::new(), tests);
+    }
+
+    #[test]
+    fn test_near_owner() {
+        let mut hints = Hints::new();
+        hints.insert(100, 40);
+        hints.insert(105, 10);
+
+        let tests = vec![
+            Test {
+                ranges: vec![100..110],
+                free: vec![0..5, 8..20, 30..50],
+                result: vec![(100..105, 40..45), (105..110, 10..15)],
+            },
+            Test {
+                // no room after the hint, so wrap back
+                ranges: vec![100..104],
+                free: vec![0..10],
+                result: vec![(100..104, 0..4)],
+            },
+            Test {
+                // unhinted ranges fill from the start
+                ranges: vec![200..203],
+                free: vec![0..5, 60..70],
+                result: vec![(200..203, 0..3)],
+            },
+        ];
+
+        run_tests(&NearOwner {}, &hints, tests);
+    }
+
+    // Every block is moved exactly once, into free space.
+    #[test]
+    fn test_policies_cover_ranges() {
+        let above = vec![1000..1003, 1010..1050, 1060..1061, 1070..1100];
+        let free = vec![1..4, 10..30, 35..36, 50..100];
+        let mut hints = Hints::new();
+        hints.insert(1010, 60);
+        hints.insert(1070, 12);
+
+        for name in &POLICY_NAMES {
+            let policy = mk_policy(name).unwrap();
+            let remaps = policy.build_remaps(above.clone(), free.clone(), &hints);
+
+            let from: Vec<u64> = remaps.iter().flat_map(|(f, _)| f.clone()).collect();
+            let expected: Vec<u64> = above.iter().flat_map(|r| r.clone()).collect();
+            assert_eq!(from, expected, "{}", name);
+
+            let mut to: Vec<u64> = remaps.iter().flat_map(|(_, t)| t.clone()).collect();
+            to.sort_unstable();
+            to.dedup();
+            assert_eq!(to.len(), expected.len(), "{}", name);
+            assert!(to.iter().all(|b| free.iter().any(|f| f.contains(b))));
+
+            for (f, t) in &remaps {
+                assert_eq!(range_len(f), range_len(t), "{}", name);
+            }
+        }
+    }
+}
+
+//---------------------------------------
@@ -4,8 +4,12 @@ use std::fs::OpenOptions;
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
+use std::sync::Arc;
 
+use crate::file_utils;
+use crate::report::Report;
 use crate::shrink::copier::{self, Region};
+use crate::shrink::policy::*;
 use crate::thin::ir::{self, MetadataVisitor, Visit};
 use crate::thin::xml;
 
@@ -22,6 +26,13 @@ struct Pass1 {
     /// will need to be moved.
     nr_high_blocks: u64,
     block_size: Option<u64>,
+
+    /// Where each run of high blocks would sit next to the rest of its
+    /// device, for the vacate policy.
+    hints: Hints,
+
+    // The end of the current device's last run of low blocks.
+    last_low: Option<u64>,
 }
 
 impl Pass1 {
@@ -31,6 +42,8 @@ impl Pass1 {
             nr_blocks,
             nr_high_blocks: 0,
             block_size: None,
+            hints: Hints::new(),
+            last_low: None,
         }
     }
 }
@@ -55,6 +68,7 @@ impl MetadataVisitor for Pass1 {
     }
 
     fn device_b(&mut self, _d: &ir::Device) -> Result<Visit> {
+        self.last_low = None;
         Ok(Visit::Continue)
     }
 
@@ -62,6 +76,10 @@ impl MetadataVisitor for Pass1 {
         Ok(Visit::Continue)
     }
 
+    fn device_labels(&mut self, _labels: &ir::DeviceLabels) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
     fn map(&mut self, m: &ir::Map) -> Result<Visit> {
         for i in m.data_begin..(m.data_begin + m.len) {
             if i > self.nr_blocks {
@@ -69,6 +87,17 @@ impl MetadataVisitor for Pass1 {
             }
             self.allocated_blocks.insert(i as usize);
         }
+
+        let end = m.data_begin + m.len;
+        if m.data_begin < self.nr_blocks {
+            self.last_low = Some(std::cmp::min(end, self.nr_blocks));
+        }
+        if end > self.nr_blocks {
+            if let Some(near) = self.last_low {
+                let high = std::cmp::max(m.data_begin, self.nr_blocks);
+                self.hints.entry(high).or_insert(near);
+            }
+        }
         Ok(Visit::Continue)
     }
 
@@ -125,6 +154,10 @@ impl<W: Write> MetadataVisitor for Pass2<W> {
         self.writer.device_e()
     }
 
+    fn device_labels(&mut self, labels: &ir::DeviceLabels) -> Result<Visit> {
+        self.writer.device_labels(labels)
+    }
+
     fn map(&mut self, m: &ir::Map) -> Result<Visit> {
         if m.data_begin + m.len < self.nr_blocks {
             // no remapping needed.
@@ -140,6 +173,7 @@ impl<W: Write> MetadataVisitor for Pass2<W> {
                     data_begin: r.start,
                     time: m.time,
                     len: range_len(&r),
+                    shared: m.shared,
                 })?;
                 written += range_len(&r);
             }
@@ -159,8 +193,6 @@ impl<W: Write> MetadataVisitor for Pass2<W> {
 
 //---------------------------------------
 
-type BlockRange = std::ops::Range<u64>;
-
 fn bits_to_ranges(bits: &FixedBitSet) -> Vec<BlockRange> {
     let mut ranges = Vec::new();
     let mut start = None;
@@ -241,85 +273,6 @@ fn ranges_total(rs: &[BlockRange]) -> u64 {
     rs.iter().fold(0, |sum, r| sum + range_len(r))
 }
 
-// Assumes there is enough space to remap.
-fn build_remaps(ranges: Vec<BlockRange>, free: Vec<BlockRange>) -> Vec<(BlockRange, BlockRange)> {
-    use std::cmp::Ordering;
-
-    let mut remap = Vec::new();
-    let mut range_iter = ranges.into_iter();
-    let mut free_iter = free.into_iter();
-
-    let mut r_ = range_iter.next();
-    let mut f_ = free_iter.next();
-
-    while let (Some(r), Some(f)) = (r_, f_) {
-        let rlen = range_len(&r);
-        let flen = range_len(&f);
-
-        match rlen.cmp(&flen) {
-            Ordering::Less => {
-                // range fits into the free chunk
-                remap.push((r, f.start..(f.start + rlen)));
-                f_ = Some((f.start + rlen)..f.end);
-                r_ = range_iter.next();
-            }
-            Ordering::Equal => {
-                remap.push((r, f));
-                r_ = range_iter.next();
-                f_ = free_iter.next();
-            }
-            Ordering::Greater => {
-                remap.push((r.start..(r.start + flen), f));
-                r_ = Some((r.start + flen)..r.end);
-                f_ = free_iter.next();
-            }
-        }
-    }
-
-    remap
-}
-
-#[test]
-fn test_build_remaps() {
-    struct Test {
-        ranges: Vec<BlockRange>,
-        free: Vec<BlockRange>,
-        result: Vec<(BlockRange, BlockRange)>,
-    }
-
-    let tests = vec![
-        Test {
-            ranges: vec![],
-            free: vec![],
-            result: vec![],
-        },
-        Test {
-            ranges: vec![],
-            free: vec![0..100],
-            result: vec![],
-        },
-        Test {
-            ranges: vec![1000..1002],
-            free: vec![0..100],
-            result: vec![(1000..1002, 0..2)],
-        },
-        Test {
-            ranges: vec![1000..1002, 1100..1110],
-            free: vec![0..100],
-            result: vec![(1000..1002, 0..2), (1100..1110, 2..12)],
-        },
-        Test {
-            ranges: vec![100..120],
-            free: vec![0..5, 20..23, 30..50],
-            result: vec![(100..105, 0..5), (105..108, 20..23), (108..120, 30..42)],
-        },
-    ];
-
-    for t in tests {
-        assert_eq!(build_remaps(t.ranges, t.free), t.result);
-    }
-}
-
 fn overlaps(r1: &BlockRange, r2: &BlockRange, index: usize) -> Option<usize> {
     if r1.start >= r2.end {
         return None;
@@ -505,50 +458,191 @@ fn process_xml<MV: MetadataVisitor>(input_path: &Path, pass: &mut MV) -> Result<
     Ok(())
 }
 
-pub fn shrink(
-    input_path: &Path,
-    output_path: &Path,
-    data_path: &Path,
-    nr_blocks: u64,
-    do_copy: bool,
-) -> Result<()> {
+// The data device must hold the whole of the current pool, and data
+// blocks have to be whole multiples of the device's logical block size
+// or the copies will fail on 4Kn drives.
+fn check_data_device(path: &Path, nr_blocks: u64, block_size: u64) -> Result<()> {
+    let block_bytes = block_size * 512;
+    let needed = nr_blocks
+        .checked_mul(block_bytes)
+        .ok_or_else(|| anyhow!("pool size overflows"))?;
+
+    let dev_size = file_utils::file_size(path)
+        .map_err(|e| anyhow!("couldn't get size of data device: {}", e))?;
+    if dev_size < needed {
+        return Err(anyhow!(
+            "data device too small: {} bytes, but the pool needs {} bytes",
+            dev_size,
+            needed
+        ));
+    }
+
+    let sector_size = file_utils::logical_block_size(path)
+        .map_err(|e| anyhow!("couldn't get sector size of data device: {}", e))?;
+    if block_bytes / sector_size * sector_size != block_bytes {
+        return Err(anyhow!(
+            "data block size ({} bytes) isn't a multiple of the data device's sector size ({} bytes)",
+            block_bytes,
+            sector_size
+        ));
+    }
+
+    Ok(())
+}
+
+pub struct ThinShrinkOptions<'a> {
+    pub input: &'a Path,
+    pub output: &'a Path,
+    pub data_device: &'a Path,
+    pub nr_blocks: u64,
+    pub do_copy: bool,
+    /// Rereads and compares the moved data before writing the new xml.
+    pub verify: bool,
+    /// Limits the copy to this many MB (10^6 bytes) per second.
+    pub throttle_mbps: Option<u64>,
+    pub policy: Box<dyn VacatePolicy>,
+    pub report: Arc<Report>,
+}
+
+pub fn shrink(opts: ThinShrinkOptions) -> Result<()> {
+    let report = &opts.report;
+    let nr_blocks = opts.nr_blocks;
+
     let mut pass1 = Pass1::new(nr_blocks);
-    eprint!("Reading xml...");
-    process_xml(input_path, &mut pass1)?;
-    eprintln!("done");
-    eprintln!("{} blocks need moving", pass1.nr_high_blocks);
+    report.verbose("Reading xml...");
+    process_xml(opts.input, &mut pass1)?;
+    report.info(&format!("{} blocks need moving", pass1.nr_high_blocks));
 
     let ranges = bits_to_ranges(&pass1.allocated_blocks);
     let (below, above) = ranges_split(&ranges, nr_blocks);
 
     let free = negate_ranges(&below, nr_blocks);
     let free_blocks = ranges_total(&free);
-    eprintln!("{} free blocks.", free_blocks);
+    report.info(&format!("{} free blocks.", free_blocks));
 
     if free_blocks < pass1.nr_high_blocks {
         return Err(anyhow!("Insufficient space"));
     }
 
-    let remaps = build_remaps(above, free);
-
-    if do_copy {
-        let regions = build_copy_regions(&remaps, pass1.block_size.unwrap() as u64);
-        copier::copy(data_path, &regions)?;
+    let remaps = opts.policy.build_remaps(above, free, &pass1.hints);
+
+    if opts.do_copy {
+        let block_size = pass1.block_size.unwrap();
+        check_data_device(
+            opts.data_device,
+            pass1.allocated_blocks.len() as u64,
+            block_size,
+        )?;
+        let regions = build_copy_regions(&remaps, block_size);
+        let bytes_per_sec = opts.throttle_mbps.map(|mbps| mbps * 1_000_000);
+        copier::copy(opts.data_device, &regions, bytes_per_sec, report)?;
+
+        // Nothing refers to the new locations until the xml is written,
+        // so a failed verification leaves the pool as it was.
+        if opts.verify {
+            report.info("verifying copied data");
+            copier::verify(opts.data_device, &regions, bytes_per_sec, report)?;
+        }
     } else {
-        eprintln!("skipping copy");
+        report.info("skipping copy");
     }
 
     let output = OpenOptions::new()
         .read(false)
         .write(true)
         .create(true)
-        .open(output_path)?;
+        .open(opts.output)?;
     let mut pass2 = Pass2::new(output, nr_blocks, remaps);
-    eprint!("writing new xml...");
-    process_xml(input_path, &mut pass2)?;
-    eprintln!("done.");
+    report.verbose("writing new xml...");
+    process_xml(opts.input, &mut pass2)?;
 
     Ok(())
 }
 
 //---------------------------------------
+
+/// One step of a staged shrink.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Stage {
+    /// The size of the pool once the stage is done.
+    pub nr_blocks: u64,
+
+    /// How many blocks the stage moves.
+    pub nr_moved: u64,
+}
+
+pub struct ThinShrinkPlanOptions<'a> {
+    pub input: &'a Path,
+    pub nr_blocks: u64,
+    pub max_moved: u64,
+    pub policy: Box<dyn VacatePolicy>,
+    pub report: Arc<Report>,
+}
+
+// The smallest size, no lower than the target, that leaves no more than
+// max_moved allocated blocks beyond it.
+fn stage_boundary(allocated: &FixedBitSet, size: u64, target: u64, max_moved: u64) -> u64 {
+    let mut boundary = size;
+    let mut nr_moved = 0;
+
+    while boundary > target {
+        if allocated[(boundary - 1) as usize] {
+            if nr_moved == max_moved {
+                break;
+            }
+            nr_moved += 1;
+        }
+        boundary -= 1;
+    }
+
+    boundary
+}
+
+/// Splits a shrink into stages that each move at most `max_moved`
+/// blocks.  Each stage is an ordinary shrink to its `nr_blocks`, run
+/// against the metadata written by the stage before.  The moves are
+/// simulated with the chosen policy, so later stages account for blocks
+/// an earlier stage placed beyond the target.
+pub fn plan(opts: ThinShrinkPlanOptions) -> Result<Vec<Stage>> {
+    let report = &opts.report;
+    let target = opts.nr_blocks;
+
+    if opts.max_moved == 0 {
+        return Err(anyhow!("stages must be allowed to move some blocks"));
+    }
+
+    let mut pass1 = Pass1::new(target);
+    report.verbose("Reading xml...");
+    process_xml(opts.input, &mut pass1)?;
+
+    let mut allocated = pass1.allocated_blocks;
+    if allocated.count_ones(..) as u64 > target {
+        return Err(anyhow!("Insufficient space"));
+    }
+
+    let mut stages = Vec::new();
+    let mut size = allocated.len() as u64;
+    while size > target {
+        let boundary = stage_boundary(&allocated, size, target, opts.max_moved);
+
+        let ranges = bits_to_ranges(&allocated);
+        let (below, above) = ranges_split(&ranges, boundary);
+        let free = negate_ranges(&below, boundary);
+        let nr_moved = ranges_total(&above);
+
+        for (from, to) in opts.policy.build_remaps(above, free, &pass1.hints) {
+            allocated.set_range(from.start as usize..from.end as usize, false);
+            allocated.set_range(to.start as usize..to.end as usize, true);
+        }
+
+        stages.push(Stage {
+            nr_blocks: boundary,
+            nr_moved,
+        });
+        size = boundary;
+    }
+
+    Ok(stages)
+}
+
+//---------------------------------------
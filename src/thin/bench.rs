@@ -0,0 +1,282 @@
+use anyhow::Result;
+use rand::prelude::*;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::io_engine::*;
+use crate::policy::Policy;
+use crate::report::*;
+use crate::thin::check::{check, ThinCheckOptions, POLICY_CATEGORIES};
+use crate::thin::dump::{dump, OutputFormat, ThinDumpOptions};
+use crate::thin::ir::{self, MetadataVisitor};
+use crate::thin::metadata_repair::SuperblockOverrides;
+use crate::thin::metadata_size::{metadata_size, ThinMetadataSizeOptions};
+use crate::thin::restore::{restore, ThinRestoreOptions};
+use crate::thin::xml;
+
+//------------------------------------------
+
+const MAX_CONCURRENT_IO: u32 = 1024;
+
+// The longest run of consecutive mappings, and the largest hole between
+// runs, in the generated devices.
+const MAX_RUN_LEN: u64 = 32;
+const MAX_GAP: u64 = 16;
+
+// Each snapshot has this percentage of its origin's mappings overwritten.
+const SNAP_CHANGE_PCT: u64 = 10;
+
+pub struct ThinBenchOptions<'a> {
+    pub dir: Option<&'a Path>,
+    pub nr_thins: u32,
+    pub nr_mappings: u64,
+    pub iterations: usize,
+    pub seed: u64,
+    pub async_io: bool,
+    pub nr_io_threads: usize,
+    pub report: Arc<Report>,
+}
+
+pub struct Timing {
+    pub name: &'static str,
+    pub times: Vec<Duration>,
+}
+
+impl Timing {
+    pub fn best(&self) -> Duration {
+        self.times.iter().min().cloned().unwrap_or_default()
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.times.is_empty() {
+            return Duration::default();
+        }
+        self.times.iter().sum::<Duration>() / self.times.len() as u32
+    }
+}
+
+//------------------------------------------
+
+// Device 0 is an origin, the rest are each a snapshot of the one before,
+// with some of the mappings broken by writes, so the metadata has both
+// long runs and shared leaves to work through.
+fn generate(out: &mut dyn MetadataVisitor, opts: &ThinBenchOptions) -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(opts.seed);
+
+    let mut mappings = Vec::with_capacity(opts.nr_mappings as usize);
+    let mut thin_block = 0;
+    let mut data_block = 0;
+    while (mappings.len() as u64) < opts.nr_mappings {
+        let len = std::cmp::min(
+            rng.gen_range(1..=MAX_RUN_LEN),
+            opts.nr_mappings - mappings.len() as u64,
+        );
+        for i in 0..len {
+            mappings.push((thin_block + i, data_block + i));
+        }
+        thin_block += len + rng.gen_range(0..=MAX_GAP);
+        data_block += len;
+    }
+
+    let nr_changes = opts.nr_mappings * SNAP_CHANGE_PCT / 100;
+    let mut devs = Vec::new();
+    for dev_id in 0..opts.nr_thins {
+        if dev_id > 0 {
+            for _ in 0..nr_changes {
+                let i = rng.gen_range(0..mappings.len());
+                mappings[i].1 = data_block;
+                data_block += 1;
+            }
+        }
+        devs.push(mappings.clone());
+    }
+
+    out.superblock_b(&ir::Superblock {
+        uuid: "".to_string(),
+        time: opts.nr_thins,
+        transaction: opts.nr_thins as u64,
+        flags: None,
+        version: Some(2),
+        data_block_size: 128,
+        nr_data_blocks: data_block,
+        metadata_snap: None,
+    })?;
+
+    for (dev_id, mappings) in devs.iter().enumerate() {
+        out.device_b(&ir::Device {
+            dev_id: dev_id as u32,
+            mapped_blocks: mappings.len() as u64,
+            transaction: dev_id as u64,
+            creation_time: dev_id as u32,
+            snap_time: dev_id as u32,
+        })?;
+
+        let mut run: Option<ir::Map> = None;
+        for (thin, data) in mappings {
+            if let Some(m) = &mut run {
+                if m.thin_begin + m.len == *thin && m.data_begin + m.len == *data {
+                    m.len += 1;
+                    continue;
+                }
+                out.map(m)?;
+            }
+            run = Some(ir::Map {
+                thin_begin: *thin,
+                data_begin: *data,
+                time: dev_id as u32,
+                len: 1,
+                shared: None,
+            });
+        }
+        if let Some(m) = &run {
+            out.map(m)?;
+        }
+
+        out.device_e()?;
+    }
+
+    out.superblock_e()?;
+    out.eof()?;
+    Ok(())
+}
+
+// Twice the estimate from thin_metadata_size, since nothing is shared
+// when the xml is restored.
+fn mk_metadata_file(path: &Path, opts: &ThinBenchOptions) -> Result<()> {
+    let sectors = metadata_size(&ThinMetadataSizeOptions {
+        nr_blocks: opts.nr_mappings * opts.nr_thins as u64,
+        max_thins: opts.nr_thins as u64,
+    })?;
+    let bytes = std::cmp::max(sectors * 512 * 2, 4 << 20);
+    let file = File::create(path)?;
+    file.set_len(bytes)?;
+    Ok(())
+}
+
+fn open_engine(path: &Path, opts: &ThinBenchOptions) -> Result<Arc<dyn IoEngine + Send + Sync>> {
+    let engine: Arc<dyn IoEngine + Send + Sync> = if opts.async_io {
+        Arc::new(AsyncIoEngine::new(path, MAX_CONCURRENT_IO, false)?)
+    } else {
+        Arc::new(SyncIoEngine::new(path, opts.nr_io_threads, false)?)
+    };
+    Ok(engine)
+}
+
+fn time<F>(f: F) -> Result<Duration>
+where
+    F: FnOnce() -> Result<()>,
+{
+    let start = Instant::now();
+    f()?;
+    Ok(start.elapsed())
+}
+
+//------------------------------------------
+
+/// Generates metadata in a scratch directory and times restoring,
+/// checking and dumping it with the given io settings.  The scratch
+/// directory is removed afterwards.
+pub fn bench(opts: ThinBenchOptions) -> Result<Vec<Timing>> {
+    let tmp = match opts.dir {
+        Some(dir) => tempfile::Builder::new()
+            .prefix("thin_bench")
+            .tempdir_in(dir)?,
+        None => tempfile::Builder::new().prefix("thin_bench").tempdir()?,
+    };
+    let xml_path: PathBuf = tmp.path().join("metadata.xml");
+    let md_path: PathBuf = tmp.path().join("metadata.bin");
+    let dump_path: PathBuf = tmp.path().join("dump.xml");
+
+    opts.report.info(&format!(
+        "generating {} devices of {} mappings",
+        opts.nr_thins, opts.nr_mappings
+    ));
+    {
+        let mut w = xml::XmlWriter::new(BufWriter::new(File::create(&xml_path)?));
+        generate(&mut w, &opts)?;
+    }
+    mk_metadata_file(&md_path, &opts)?;
+
+    let mut timings = vec![
+        Timing {
+            name: "restore",
+            times: Vec::new(),
+        },
+        Timing {
+            name: "check",
+            times: Vec::new(),
+        },
+        Timing {
+            name: "dump",
+            times: Vec::new(),
+        },
+    ];
+
+    for i in 0..opts.iterations {
+        opts.report
+            .info(&format!("iteration {} of {}", i + 1, opts.iterations));
+
+        timings[0].times.push(time(|| {
+            restore(ThinRestoreOptions {
+                input: &xml_path,
+                output: &md_path,
+                async_io: opts.async_io,
+                nr_io_threads: opts.nr_io_threads,
+                report: Arc::new(mk_quiet_report()),
+                verify: false,
+                backup_superblock: false,
+                overrides: SuperblockOverrides {
+                    transaction_id: None,
+                    data_block_size: None,
+                    nr_data_blocks: None,
+                },
+            })
+        })?);
+
+        timings[1].times.push(time(|| {
+            check(ThinCheckOptions {
+                engine: open_engine(&md_path, &opts)?,
+                sb_only: false,
+                skip_mappings: false,
+                ignore_non_fatal: false,
+                policy: Policy::new(POLICY_CATEGORIES),
+                data_device_size: None,
+                timeout: None,
+                audit: false,
+                report: Arc::new(mk_quiet_report()),
+            })
+        })?);
+
+        timings[2].times.push(time(|| {
+            dump(ThinDumpOptions {
+                input: &md_path,
+                output: Some(&dump_path),
+                index: None,
+                async_io: opts.async_io,
+                nr_io_threads: opts.nr_io_threads,
+                report: Arc::new(mk_quiet_report()),
+                repair: false,
+                canonical: false,
+                skip_mappings: false,
+                annotate_shared: false,
+                min_range: xml::DEFAULT_MIN_RANGE,
+                format: OutputFormat::Xml,
+                overrides: SuperblockOverrides {
+                    transaction_id: None,
+                    data_block_size: None,
+                    nr_data_blocks: None,
+                },
+            })
+        })?);
+
+        // Each restore starts from a clean file, as the tools would.
+        mk_metadata_file(&md_path, &opts)?;
+    }
+
+    Ok(timings)
+}
+
+//------------------------------------------
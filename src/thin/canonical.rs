@@ -0,0 +1,531 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+
+use crate::thin::ir::{self, MetadataVisitor, Visit};
+
+//------------------------------------------
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanonicalSuperblock {
+    pub time: u32,
+    pub transaction: u64,
+    pub data_block_size: u32,
+    pub nr_data_blocks: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanonicalDevice {
+    pub mapped_blocks: u64,
+    pub transaction: u64,
+    pub creation_time: u32,
+    pub snap_time: u32,
+
+    /// (thin_begin, data_begin, len, time), sorted by thin_begin.
+    pub runs: Vec<(u64, u64, u64, u32)>,
+}
+
+/// An in core copy of thin metadata, with shared definitions expanded
+/// and mapping runs coalesced.  Two copies compare equal if they hold
+/// the same mappings, however the runs were split or shared.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CanonicalMetadata {
+    pub sb: Option<CanonicalSuperblock>,
+    pub devs: BTreeMap<u32, CanonicalDevice>,
+}
+
+fn coalesce(mut runs: Vec<(u64, u64, u64, u32)>) -> Vec<(u64, u64, u64, u32)> {
+    runs.sort_unstable();
+
+    let mut result: Vec<(u64, u64, u64, u32)> = Vec::with_capacity(runs.len());
+    for r in runs {
+        if r.2 == 0 {
+            continue;
+        }
+
+        if let Some(last) = result.last_mut() {
+            if last.0 + last.2 == r.0 && last.1 + last.2 == r.1 && last.3 == r.3 {
+                last.2 += r.2;
+                continue;
+            }
+        }
+        result.push(r);
+    }
+
+    result
+}
+
+//------------------------------------------
+
+/// Builds a CanonicalMetadata from a metadata walk, eg, xml::read()
+/// or dump_metadata().
+#[derive(Default)]
+pub struct CanonicalBuilder {
+    md: CanonicalMetadata,
+    defs: BTreeMap<String, Vec<(u64, u64, u64, u32)>>,
+    current_def: Option<String>,
+    current_dev: Option<(u32, CanonicalDevice)>,
+    runs: Vec<(u64, u64, u64, u32)>,
+}
+
+impl CanonicalBuilder {
+    pub fn new() -> CanonicalBuilder {
+        CanonicalBuilder::default()
+    }
+
+    pub fn complete(self) -> CanonicalMetadata {
+        self.md
+    }
+}
+
+impl MetadataVisitor for CanonicalBuilder {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.md.sb = Some(CanonicalSuperblock {
+            time: sb.time,
+            transaction: sb.transaction,
+            data_block_size: sb.data_block_size,
+            nr_data_blocks: sb.nr_data_blocks,
+        });
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.current_def = Some(name.to_string());
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        let name = self
+            .current_def
+            .take()
+            .ok_or_else(|| anyhow!("unexpected </def>"))?;
+        let runs = std::mem::take(&mut self.runs);
+        self.defs.insert(name, runs);
+        Ok(Visit::Continue)
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        self.current_dev = Some((
+            d.dev_id,
+            CanonicalDevice {
+                mapped_blocks: d.mapped_blocks,
+                transaction: d.transaction,
+                creation_time: d.creation_time,
+                snap_time: d.snap_time,
+                runs: Vec::new(),
+            },
+        ));
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        let (dev_id, mut dev) = self
+            .current_dev
+            .take()
+            .ok_or_else(|| anyhow!("unexpected </device>"))?;
+        dev.runs = coalesce(std::mem::take(&mut self.runs));
+        if self.md.devs.insert(dev_id, dev).is_some() {
+            return Err(anyhow!("duplicate device {}", dev_id));
+        }
+        Ok(Visit::Continue)
+    }
+
+    // Labels are only for userland, and aren't compared.
+    fn device_labels(&mut self, _labels: &ir::DeviceLabels) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        self.runs.push((m.thin_begin, m.data_begin, m.len, m.time));
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        let runs = self
+            .defs
+            .get(name)
+            .ok_or_else(|| anyhow!("couldn't find sub tree '{}'", name))?;
+        self.runs.extend_from_slice(runs);
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
+fn first_run_difference(
+    lhs: &[(u64, u64, u64, u32)],
+    rhs: &[(u64, u64, u64, u32)],
+) -> Option<String> {
+    for (l, r) in lhs.iter().zip(rhs.iter()) {
+        if l != r {
+            return Some(format!(
+                "run at thin block {} differs: {:?} vs {:?}",
+                std::cmp::min(l.0, r.0),
+                l,
+                r
+            ));
+        }
+    }
+
+    if lhs.len() != rhs.len() {
+        return Some(format!("{} runs vs {} runs", lhs.len(), rhs.len()));
+    }
+
+    None
+}
+
+/// Describes the first difference found between two copies of the
+/// metadata, or returns None if they hold the same mappings.
+pub fn first_difference(lhs: &CanonicalMetadata, rhs: &CanonicalMetadata) -> Option<String> {
+    if lhs.sb != rhs.sb {
+        return Some(format!("superblocks differ: {:?} vs {:?}", lhs.sb, rhs.sb));
+    }
+
+    for (dev_id, l) in &lhs.devs {
+        let r = match rhs.devs.get(dev_id) {
+            Some(r) => r,
+            None => return Some(format!("device {} is missing", dev_id)),
+        };
+
+        if l.mapped_blocks != r.mapped_blocks
+            || l.transaction != r.transaction
+            || l.creation_time != r.creation_time
+            || l.snap_time != r.snap_time
+        {
+            return Some(format!("details of device {} differ", dev_id));
+        }
+
+        if let Some(msg) = first_run_difference(&l.runs, &r.runs) {
+            return Some(format!("mappings of device {} differ, {}", dev_id, msg));
+        }
+    }
+
+    for dev_id in rhs.devs.keys() {
+        if !lhs.devs.contains_key(dev_id) {
+            return Some(format!("unexpected device {}", dev_id));
+        }
+    }
+
+    None
+}
+
+//------------------------------------------
+
+/// A difference between two copies of the metadata, with the finer
+/// differences that make it up, eg, a device's differing fields.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Difference {
+    pub what: String,
+    pub children: Vec<Difference>,
+}
+
+impl Difference {
+    fn leaf(what: String) -> Difference {
+        Difference {
+            what,
+            children: Vec::new(),
+        }
+    }
+}
+
+fn field_difference<T: PartialEq + std::fmt::Display>(
+    name: &str,
+    lhs: T,
+    rhs: T,
+) -> Option<Difference> {
+    if lhs == rhs {
+        None
+    } else {
+        Some(Difference::leaf(format!("{}: {} vs {}", name, lhs, rhs)))
+    }
+}
+
+fn superblock_differences(lhs: &CanonicalSuperblock, rhs: &CanonicalSuperblock) -> Vec<Difference> {
+    vec![
+        field_difference("time", lhs.time, rhs.time),
+        field_difference("transaction", lhs.transaction, rhs.transaction),
+        field_difference("data_block_size", lhs.data_block_size, rhs.data_block_size),
+        field_difference("nr_data_blocks", lhs.nr_data_blocks, rhs.nr_data_blocks),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+fn detail_differences(lhs: &CanonicalDevice, rhs: &CanonicalDevice) -> Vec<Difference> {
+    vec![
+        field_difference("mapped_blocks", lhs.mapped_blocks, rhs.mapped_blocks),
+        field_difference("transaction", lhs.transaction, rhs.transaction),
+        field_difference("creation_time", lhs.creation_time, rhs.creation_time),
+        field_difference("snap_time", lhs.snap_time, rhs.snap_time),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+// What a run of thin blocks maps to, (data_begin, time).
+type Target = Option<(u64, u32)>;
+
+fn describe_target(t: Target, len: u64) -> String {
+    match t {
+        Some((data_begin, time)) => {
+            format!("data {}..{} at time {}", data_begin, data_begin + len, time)
+        }
+        None => "unmapped".to_string(),
+    }
+}
+
+fn next_target(t: Target, len: u64) -> Target {
+    t.map(|(data_begin, time)| (data_begin + len, time))
+}
+
+// Splits two sorted run lists at every run boundary, returning the
+// stretches, (thin_begin, len, lhs, rhs), in which they disagree.
+// Adjacent stretches that carry on from each other are merged.
+fn run_differences(
+    lhs: &[(u64, u64, u64, u32)],
+    rhs: &[(u64, u64, u64, u32)],
+) -> Vec<(u64, u64, Target, Target)> {
+    // Where each run starts and ends.
+    let mut bounds: Vec<u64> = lhs
+        .iter()
+        .chain(rhs.iter())
+        .flat_map(|r| [r.0, r.0 + r.2])
+        .collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let target_at = |runs: &[(u64, u64, u64, u32)], b: u64| -> Target {
+        let i = runs.partition_point(|r| r.0 + r.2 <= b);
+        runs.get(i)
+            .filter(|r| r.0 <= b)
+            .map(|r| (r.1 + (b - r.0), r.3))
+    };
+
+    let mut result: Vec<(u64, u64, Target, Target)> = Vec::new();
+    for w in bounds.windows(2) {
+        let (begin, len) = (w[0], w[1] - w[0]);
+        let l = target_at(lhs, begin);
+        let r = target_at(rhs, begin);
+        if l == r {
+            continue;
+        }
+
+        if let Some(last) = result.last_mut() {
+            if last.0 + last.1 == begin
+                && next_target(last.2, last.1) == l
+                && next_target(last.3, last.1) == r
+            {
+                last.1 += len;
+                continue;
+            }
+        }
+        result.push((begin, len, l, r));
+    }
+
+    result
+}
+
+fn mapping_differences(lhs: &CanonicalDevice, rhs: &CanonicalDevice) -> Option<Difference> {
+    let diffs = run_differences(&lhs.runs, &rhs.runs);
+    if diffs.is_empty() {
+        return None;
+    }
+
+    let nr_blocks: u64 = diffs.iter().map(|d| d.1).sum();
+    Some(Difference {
+        what: format!(
+            "mappings: {} blocks differ, in {} runs",
+            nr_blocks,
+            diffs.len()
+        ),
+        children: diffs
+            .into_iter()
+            .map(|(begin, len, l, r)| {
+                Difference::leaf(format!(
+                    "thin {}..{}: {} vs {}",
+                    begin,
+                    begin + len,
+                    describe_target(l, len),
+                    describe_target(r, len)
+                ))
+            })
+            .collect(),
+    })
+}
+
+/// Lists every difference between two copies of the metadata, the
+/// superblock first, then the devices in id order.  The list is empty
+/// if they hold the same mappings.
+pub fn differences(lhs: &CanonicalMetadata, rhs: &CanonicalMetadata) -> Vec<Difference> {
+    let mut result = Vec::new();
+
+    match (&lhs.sb, &rhs.sb) {
+        (Some(l), Some(r)) => {
+            let children = superblock_differences(l, r);
+            if !children.is_empty() {
+                result.push(Difference {
+                    what: "superblock".to_string(),
+                    children,
+                });
+            }
+        }
+        (Some(_), None) => result.push(Difference::leaf("superblock: only in left".to_string())),
+        (None, Some(_)) => result.push(Difference::leaf("superblock: only in right".to_string())),
+        (None, None) => {}
+    }
+
+    let dev_ids: std::collections::BTreeSet<&u32> =
+        lhs.devs.keys().chain(rhs.devs.keys()).collect();
+    for dev_id in dev_ids {
+        let (l, r) = match (lhs.devs.get(dev_id), rhs.devs.get(dev_id)) {
+            (Some(l), Some(r)) => (l, r),
+            (Some(l), None) => {
+                result.push(Difference::leaf(format!(
+                    "device {}: only in left, {} mapped blocks",
+                    dev_id, l.mapped_blocks
+                )));
+                continue;
+            }
+            (None, Some(r)) => {
+                result.push(Difference::leaf(format!(
+                    "device {}: only in right, {} mapped blocks",
+                    dev_id, r.mapped_blocks
+                )));
+                continue;
+            }
+            (None, None) => continue,
+        };
+
+        let mut children = Vec::new();
+        let details = detail_differences(l, r);
+        if !details.is_empty() {
+            children.push(Difference {
+                what: "details".to_string(),
+                children: details,
+            });
+        }
+        children.extend(mapping_differences(l, r));
+
+        if !children.is_empty() {
+            result.push(Difference {
+                what: format!("device {}", dev_id),
+                children,
+            });
+        }
+    }
+
+    result
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalesce() {
+        let runs = vec![(10, 20, 5, 0), (0, 0, 5, 0), (5, 5, 5, 0), (15, 25, 1, 1)];
+        assert_eq!(
+            coalesce(runs),
+            vec![(0, 0, 10, 0), (10, 20, 5, 0), (15, 25, 1, 1)]
+        );
+    }
+
+    #[test]
+    fn test_shared_defs_expand() -> Result<()> {
+        let sb = ir::Superblock {
+            uuid: "".to_string(),
+            time: 0,
+            transaction: 1,
+            flags: None,
+            version: Some(2),
+            data_block_size: 128,
+            nr_data_blocks: 100,
+            metadata_snap: None,
+        };
+        let dev = ir::Device {
+            dev_id: 1,
+            mapped_blocks: 4,
+            transaction: 0,
+            creation_time: 0,
+            snap_time: 0,
+        };
+        let m1 = ir::Map {
+            thin_begin: 0,
+            data_begin: 10,
+            time: 0,
+            len: 2,
+            shared: None,
+        };
+        let m2 = ir::Map {
+            thin_begin: 2,
+            data_begin: 12,
+            time: 0,
+            len: 2,
+            shared: None,
+        };
+
+        let mut shared = CanonicalBuilder::new();
+        shared.superblock_b(&sb)?;
+        shared.def_shared_b("7")?;
+        shared.map(&m1)?;
+        shared.def_shared_e()?;
+        shared.device_b(&dev)?;
+        shared.ref_shared("7")?;
+        shared.map(&m2)?;
+        shared.device_e()?;
+        shared.superblock_e()?;
+
+        let mut flat = CanonicalBuilder::new();
+        flat.superblock_b(&sb)?;
+        flat.device_b(&dev)?;
+        flat.map(&ir::Map { len: 4, ..m1 })?;
+        flat.device_e()?;
+        flat.superblock_e()?;
+
+        let lhs = shared.complete();
+        let mut rhs = flat.complete();
+        assert_eq!(first_difference(&lhs, &rhs), None);
+
+        rhs.devs.get_mut(&1).unwrap().runs[0].3 = 1;
+        assert!(first_difference(&lhs, &rhs).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_differences() {
+        let lhs = vec![(0, 100, 10, 0), (20, 200, 5, 0)];
+        let rhs = vec![(0, 100, 4, 0), (4, 300, 6, 0), (20, 200, 5, 1)];
+        assert_eq!(
+            run_differences(&lhs, &rhs),
+            vec![
+                (4, 6, Some((104, 0)), Some((300, 0))),
+                (20, 5, Some((200, 0)), Some((200, 1))),
+            ]
+        );
+
+        // a stretch only one side maps
+        let rhs = vec![(0, 100, 30, 0)];
+        assert_eq!(
+            run_differences(&lhs, &rhs),
+            vec![
+                (10, 10, None, Some((110, 0))),
+                (20, 5, Some((200, 0)), Some((120, 0))),
+                (25, 5, None, Some((125, 0))),
+            ]
+        );
+        assert!(run_differences(&lhs, &lhs).is_empty());
+    }
+}
+
+//------------------------------------------
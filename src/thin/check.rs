@@ -1,26 +1,118 @@
 use anyhow::{anyhow, Result};
-use std::collections::BTreeMap;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use threadpool::ThreadPool;
+use tracing::{info_span, instrument, Span};
 
+use crate::block_cache::*;
 use crate::io_engine::IoEngine;
+use crate::math::to_index;
+use crate::memory;
 use crate::pdata::btree::{self, *};
+use crate::pdata::btree_builder::*;
 use crate::pdata::btree_walker::*;
 use crate::pdata::space_map::*;
 use crate::pdata::space_map_checker::*;
 use crate::pdata::space_map_common::*;
+use crate::pdata::space_map_disk::{release_disk_sm, write_disk_sm};
+use crate::pdata::space_map_metadata::*;
 use crate::pdata::unpack::*;
+use crate::policy::{Action, Category, Policy};
 use crate::report::*;
 use crate::thin::block_time::*;
 use crate::thin::device_detail::*;
+use crate::thin::labels::read_labels;
 use crate::thin::superblock::*;
+use crate::transaction::TransactionEngine;
+use crate::write_batcher::WriteBatcher;
 
 //------------------------------------------
 
+// The number of entries in each mapping leaf, plus one so unvisited
+// blocks read as zero.  Shared subtrees are only walked once, so these
+// are needed to total up the devices that share them afterwards.  If
+// the memory budget is short, only the leaves in the first blocks are
+// counted, and the rest are read again.
+struct LeafCounts {
+    counts: Vec<AtomicU16>,
+    _claim: memory::Claim,
+}
+
+impl LeafCounts {
+    fn new(nr_blocks: u64) -> LeafCounts {
+        let entry_size = std::mem::size_of::<AtomicU16>() as u64;
+        let claim = memory::claim_up_to(nr_blocks * entry_size, 0);
+        let nr_counted = to_index(claim.bytes() / entry_size);
+
+        let mut counts = Vec::with_capacity(nr_counted);
+        counts.resize_with(nr_counted, AtomicU16::default);
+        LeafCounts {
+            counts,
+            _claim: claim,
+        }
+    }
+
+    fn set(&self, b: u64, nr_entries: usize) {
+        if let Some(c) = self.counts.get(to_index(b)) {
+            c.store(nr_entries as u16 + 1, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self, b: u64) -> Option<u64> {
+        match self.counts.get(to_index(b))?.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(n as u64 - 1),
+        }
+    }
+}
+
+// Mappings to data blocks past the end of the pool, eg, left behind by a
+// botched shrink.  They're counted for each leaf, as the mappings are, so
+// they can be totalled for each device afterwards.
+struct OutOfRange {
+    nr_data_blocks: u64,
+    leaf_counts: LeafCounts,
+    leaves: Mutex<BTreeSet<u64>>,
+}
+
+impl OutOfRange {
+    fn new(nr_data_blocks: u64, nr_metadata_blocks: u64) -> OutOfRange {
+        OutOfRange {
+            nr_data_blocks,
+            leaf_counts: LeafCounts::new(nr_metadata_blocks),
+            leaves: Mutex::new(BTreeSet::new()),
+        }
+    }
+}
+
 struct BottomLevelVisitor {
     data_sm: ASpaceMap,
+    leaf_counts: Arc<LeafCounts>,
+    out_of_range: Arc<OutOfRange>,
+    nr_mappings: AtomicU64,
+    nr_out_of_range: AtomicU64,
+    shared: Mutex<Vec<u64>>,
+}
+
+impl BottomLevelVisitor {
+    fn new(
+        data_sm: ASpaceMap,
+        leaf_counts: Arc<LeafCounts>,
+        out_of_range: Arc<OutOfRange>,
+    ) -> BottomLevelVisitor {
+        BottomLevelVisitor {
+            data_sm,
+            leaf_counts,
+            out_of_range,
+            nr_mappings: AtomicU64::new(0),
+            nr_out_of_range: AtomicU64::new(0),
+            shared: Mutex::new(Vec::new()),
+        }
+    }
 }
 
 //------------------------------------------
@@ -30,23 +122,38 @@ impl NodeVisitor<BlockTime> for BottomLevelVisitor {
         &self,
         _path: &[u64],
         _kr: &KeyRange,
-        _h: &NodeHeader,
+        h: &NodeHeader,
         _k: &[u64],
         values: &[BlockTime],
     ) -> btree::Result<()> {
         // FIXME: do other checks
 
-        if values.is_empty() {
-            return Ok(());
+        self.leaf_counts.set(h.block, values.len());
+        self.nr_mappings
+            .fetch_add(values.len() as u64, Ordering::Relaxed);
+
+        // Blocks past the end of the pool have no ref counts to inc.
+        let nr_data_blocks = self.out_of_range.nr_data_blocks;
+        let nr_out_of_range = values.iter().filter(|v| v.block >= nr_data_blocks).count();
+        self.out_of_range.leaf_counts.set(h.block, nr_out_of_range);
+        if nr_out_of_range > 0 {
+            self.nr_out_of_range
+                .fetch_add(nr_out_of_range as u64, Ordering::Relaxed);
+            self.out_of_range.leaves.lock().unwrap().insert(h.block);
         }
 
-        let mut data_sm = self.data_sm.lock().unwrap();
-
-        let mut start = values[0].block;
+        let mut blocks = values
+            .iter()
+            .map(|v| v.block)
+            .filter(|b| *b < nr_data_blocks);
+        let mut start = match blocks.next() {
+            Some(b) => b,
+            None => return Ok(()),
+        };
         let mut len = 1;
 
-        for b in values.iter().skip(1) {
-            let block = b.block;
+        let mut data_sm = self.data_sm.lock().unwrap();
+        for block in blocks {
             if block == start + len {
                 len += 1;
             } else {
@@ -60,7 +167,8 @@ impl NodeVisitor<BlockTime> for BottomLevelVisitor {
         Ok(())
     }
 
-    fn visit_again(&self, _path: &[u64], _b: u64) -> btree::Result<()> {
+    fn visit_again(&self, _path: &[u64], b: u64) -> btree::Result<()> {
+        self.shared.lock().unwrap().push(b);
         Ok(())
     }
 
@@ -71,31 +179,135 @@ impl NodeVisitor<BlockTime> for BottomLevelVisitor {
 
 //------------------------------------------
 
-fn inc_superblock(sm: &ASpaceMap) -> Result<()> {
+fn inc_superblock(engine: &dyn IoEngine, sb: &Superblock, sm: &ASpaceMap) -> Result<()> {
     let mut sm = sm.lock().unwrap();
     sm.inc(SUPERBLOCK_LOCATION, 1)?;
+
+    // A backup copy, and the device labels, keep their blocks allocated.
+    if sb.backup_superblock != 0 {
+        if sb.backup_superblock >= sm.get_nr_blocks()? {
+            return Err(anyhow!(
+                "backup superblock location {} is past the end of the metadata",
+                sb.backup_superblock
+            ));
+        }
+        sm.inc(sb.backup_superblock, 1)?;
+    }
+    if sb.labels_root != 0 {
+        let area = read_labels(engine, sb.labels_root)
+            .map_err(|e| anyhow!("couldn't read the device labels: {}", e))?;
+        for b in area.blocks {
+            sm.inc(b, 1)?;
+        }
+    }
     Ok(())
 }
 
+// Point the user at the backup superblock if the primary can't be read.
+fn read_primary_superblock(engine: &dyn IoEngine, report: &Report) -> Result<Superblock> {
+    let r = read_superblock(engine, SUPERBLOCK_LOCATION);
+    if r.is_err() {
+        if let Ok(backup) = read_backup_superblock(engine) {
+            report.info(&format!(
+                "a backup superblock was found at block {}, thin_repair can recover from it",
+                backup.block
+            ));
+        }
+    }
+    r
+}
+
 //------------------------------------------
 
 pub const MAX_CONCURRENT_IO: u32 = 1024;
 
+/// The findings a --policy file can act on.  By default they all fail
+/// the check, except a set needs_check flag.
+pub const POLICY_CATEGORIES: &[Category] = &[
+    Category {
+        name: "out_of_range",
+        default: Action::Fail,
+        fixable: true,
+        lossy: true,
+    },
+    Category {
+        name: "mapped_blocks",
+        default: Action::Fail,
+        fixable: true,
+        lossy: false,
+    },
+    Category {
+        name: "metadata_snap",
+        default: Action::Fail,
+        fixable: true,
+        lossy: false,
+    },
+    Category {
+        name: "data_leaks",
+        default: Action::Fail,
+        fixable: true,
+        lossy: false,
+    },
+    Category {
+        name: "metadata_leaks",
+        default: Action::Fail,
+        fixable: true,
+        lossy: false,
+    },
+    Category {
+        name: "needs_check",
+        default: Action::Ignore,
+        fixable: true,
+        lossy: false,
+    },
+];
+
 pub struct ThinCheckOptions {
     pub engine: Arc<dyn IoEngine + Send + Sync>,
     pub sb_only: bool,
     pub skip_mappings: bool,
     pub ignore_non_fatal: bool,
-    pub auto_repair: bool,
-    pub clear_needs_check: bool,
+    pub policy: Policy,
+    pub data_device_size: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub audit: bool,
     pub report: Arc<Report>,
 }
 
+/// Returned by check() if the timeout expires before it's complete.
+/// Nothing will have been written.
+#[derive(Debug)]
+pub struct CheckTimedOut;
+
+impl fmt::Display for CheckTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the check ran out of time")
+    }
+}
+
+impl std::error::Error for CheckTimedOut {}
+
+// Stops and joins the progress thread when dropped, so it doesn't go
+// on redrawing the progress bar over an error.
+struct ProgressThread {
+    tid: Option<JoinHandle<()>>,
+    stop_progress: Arc<AtomicBool>,
+}
+
+impl Drop for ProgressThread {
+    fn drop(&mut self) {
+        self.stop_progress.store(true, Ordering::Relaxed);
+        if let Some(tid) = self.tid.take() {
+            let _ = tid.join();
+        }
+    }
+}
+
 fn spawn_progress_thread(
     sm: Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     nr_allocated_metadata: u64,
     report: Arc<Report>,
-) -> Result<(JoinHandle<()>, Arc<AtomicBool>)> {
+) -> Result<ProgressThread> {
     let tid;
     let stop_progress = Arc::new(AtomicBool::new(false));
 
@@ -121,50 +333,363 @@ fn spawn_progress_thread(
         });
     }
 
-    Ok((tid, stop_progress))
+    Ok(ProgressThread {
+        tid: Some(tid),
+        stop_progress,
+    })
+}
+
+fn spawn_timeout_thread(timeout: Duration, timed_out: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        thread::sleep(timeout);
+        timed_out.store(true, Ordering::Relaxed);
+    });
+}
+
+//------------------------------------------
+
+// The pool is sized from the data device when it's created, so if the
+// device has since been shrunk, eg, by an lvreduce of the wrong volume,
+// the end of the pool is gone.  Returns the number of data blocks the
+// device can actually hold if that's too few.
+// The kernel takes data blocks in multiples of 64KiB.
+const DATA_BLOCK_SIZE_MULTIPLE: u32 = 128;
+
+fn check_data_block_size(sb: &Superblock) -> Result<()> {
+    let nr_multiples = sb.data_block_size / DATA_BLOCK_SIZE_MULTIPLE;
+    if nr_multiples == 0 || nr_multiples * DATA_BLOCK_SIZE_MULTIPLE != sb.data_block_size {
+        return Err(anyhow!(
+            "data block size of {} sectors is not a non-zero multiple of {} sectors",
+            sb.data_block_size,
+            DATA_BLOCK_SIZE_MULTIPLE
+        ));
+    }
+    Ok(())
+}
+
+fn check_data_device_size(
+    sb: &Superblock,
+    nr_data_blocks: u64,
+    dev_bytes: u64,
+    report: &Report,
+) -> Option<u64> {
+    let block_bytes = sb.data_block_size as u64 * 512;
+    let dev_blocks = dev_bytes / block_bytes;
+    if dev_blocks >= nr_data_blocks {
+        return None;
+    }
+
+    report.fatal(&format!(
+        "The data device holds {} blocks of {} sectors, but the pool has {}",
+        dev_blocks, sb.data_block_size, nr_data_blocks
+    ));
+    Some(dev_blocks)
+}
+
+// Lists the mappings that point past the end of the data device, which
+// is where the damage will be.
+fn report_mappings_beyond(
+    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    dev_blocks: u64,
+    nr_data_blocks: u64,
+    report: &Report,
+) -> Result<()> {
+    let data_sm = data_sm.lock().unwrap();
+    let mut first = None;
+    let mut nr_mapped = 0;
+    for b in dev_blocks..nr_data_blocks {
+        if data_sm.get(b)? > 0 {
+            first.get_or_insert(b);
+            nr_mapped += 1;
+        }
+    }
+
+    if let Some(first) = first {
+        report.fatal(&format!(
+            "{} mapped data blocks lie beyond the end of the data device, the first is block {}",
+            nr_mapped, first
+        ));
+    } else {
+        report.info("No mappings lie beyond the end of the data device");
+    }
+    Ok(())
+}
+
+fn data_device_too_small() -> anyhow::Error {
+    anyhow!("the data device is smaller than the pool")
+}
+
+// The metadata space map covers the whole metadata device, so a device
+// with fewer blocks has lost its end, eg, to a truncated copy of the
+// metadata.  Whatever was there is reported as missing by the checks
+// that follow.  Returns the number of blocks the device has if that's
+// too few.
+fn check_metadata_device_size(
+    nr_metadata_blocks: u64,
+    dev_blocks: u64,
+    report: &Report,
+) -> Option<u64> {
+    if dev_blocks >= nr_metadata_blocks {
+        return None;
+    }
+
+    report.fatal(&format!(
+        "The metadata device holds {} blocks, but the metadata space map has {}, it may have been truncated",
+        dev_blocks, nr_metadata_blocks
+    ));
+    Some(dev_blocks)
+}
+
+fn metadata_device_too_small() -> anyhow::Error {
+    anyhow!("the metadata device is smaller than the metadata")
+}
+
+//------------------------------------------
+
+// The checks, in the order they're made.
+const AUDITED_CHECKS: &[&str] = &[
+    "superblock",
+    "data device size",
+    "metadata device size",
+    "device details tree",
+    "mapping tree top level",
+    "mapping tree bottom level",
+    "mapped_blocks counts",
+    "metadata snapshot",
+    "data space map",
+    "metadata space map",
+];
+
+// --skip-mappings omits everything from here on.
+const FIRST_MAPPING_CHECK: &str = "mapping tree bottom level";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AuditOutcome {
+    Pass,
+    Fail,
+    Skip,
+}
+
+impl fmt::Display for AuditOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditOutcome::Pass => write!(f, "pass"),
+            AuditOutcome::Fail => write!(f, "fail"),
+            AuditOutcome::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+// What each check found, for --audit.  Checks that were never reached
+// have no entry.
+#[derive(Default)]
+struct Audit {
+    entries: HashMap<&'static str, (AuditOutcome, String)>,
+}
+
+impl Audit {
+    fn record(&mut self, check: &'static str, outcome: AuditOutcome, detail: String) {
+        self.entries.insert(check, (outcome, detail));
+    }
+
+    fn repaired(&mut self, check: &'static str) {
+        if let Some((_, detail)) = self.entries.get_mut(check) {
+            detail.push_str(", repaired");
+        }
+    }
+
+    // Explains why a check has no entry.
+    fn not_reached(check: &str, opts: &ThinCheckOptions, timed_out: bool) -> &'static str {
+        let skipping_mappings = AUDITED_CHECKS.iter().position(|c| *c == check)
+            >= AUDITED_CHECKS
+                .iter()
+                .position(|c| *c == FIRST_MAPPING_CHECK);
+
+        if timed_out {
+            "not reached before the time ran out"
+        } else if opts.sb_only
+            && check != "superblock"
+            && check != "data device size"
+            && check != "metadata device size"
+        {
+            "omitted by --super-block-only"
+        } else if opts.skip_mappings && skipping_mappings {
+            "omitted by --skip-mappings"
+        } else {
+            "not reached, an earlier check failed"
+        }
+    }
+
+    fn lines(&self, opts: &ThinCheckOptions, timed_out: bool) -> Vec<String> {
+        let mut lines = vec!["Checks performed:".to_string()];
+        for check in AUDITED_CHECKS {
+            let (outcome, detail) = match self.entries.get(check) {
+                Some((outcome, detail)) => (*outcome, detail.clone()),
+                None => (
+                    AuditOutcome::Skip,
+                    Audit::not_reached(check, opts, timed_out).to_string(),
+                ),
+            };
+            lines.push(format!("  {:<26} {}  {}", check, outcome, detail));
+        }
+        lines
+    }
 }
 
+//------------------------------------------
+
 struct Context {
     report: Arc<Report>,
     engine: Arc<dyn IoEngine + Send + Sync>,
     pool: ThreadPool,
+
+    // Holds any repairs until the check has passed.
+    txn: Option<Arc<TransactionEngine>>,
+
+    // Set once the time allowed for the check has passed.
+    timed_out: Arc<AtomicBool>,
+
+    audit: Mutex<Audit>,
+
+    // The block cache's share of the memory budget.
+    _cache_claim: memory::Claim,
+}
+
+impl Context {
+    fn audit(&self, check: &'static str, outcome: AuditOutcome, detail: String) {
+        self.audit.lock().unwrap().record(check, outcome, detail);
+    }
+
+    // Records a check that failed with an error, passing the result on.
+    fn audit_err<T, E: fmt::Display>(
+        &self,
+        check: &'static str,
+        r: std::result::Result<T, E>,
+    ) -> std::result::Result<T, E> {
+        if let Err(e) = &r {
+            self.audit(check, AuditOutcome::Fail, format!("{}", e));
+        }
+        r
+    }
+}
+
+// Stops the check, before anything is written, once the time's up.
+fn check_time(ctx: &Context, verified: &[&str]) -> Result<()> {
+    if !ctx.timed_out.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    if verified.is_empty() {
+        ctx.report
+            .info("Nothing was verified before the time ran out");
+    } else {
+        ctx.report.info(&format!(
+            "Verified before the time ran out: {}",
+            verified.join(", ")
+        ));
+    }
+    Err(anyhow::Error::new(CheckTimedOut))
+}
+
+// Totals the mappings below a node that was reached from more than one
+// device, and so only walked once.  Leaves that weren't counted on the
+// walk are read again, and counted with count_leaf.
+fn count_subtree(
+    engine: &dyn IoEngine,
+    leaf_counts: &LeafCounts,
+    count_leaf: &dyn Fn(&[BlockTime]) -> u64,
+    subtree_counts: &mut HashMap<u64, u64>,
+    b: u64,
+) -> Result<u64> {
+    if let Some(n) = leaf_counts.get(b) {
+        return Ok(n);
+    }
+    if let Some(n) = subtree_counts.get(&b) {
+        return Ok(*n);
+    }
+
+    let blk = engine.read(b)?;
+    let n = match unpack_node::<BlockTime>(&[b], blk.get_data(), true, false)? {
+        Node::Internal { values, .. } => {
+            let mut n = 0;
+            for child in values {
+                n += count_subtree(engine, leaf_counts, count_leaf, subtree_counts, child)?;
+            }
+            n
+        }
+        Node::Leaf { values, .. } => count_leaf(&values),
+    };
+    subtree_counts.insert(b, n);
+    Ok(n)
+}
+
+// What the walk of the mappings found.
+#[derive(Default)]
+struct MappingCounts {
+    // The number of mappings held by each device
+    mapped: BTreeMap<u64, u64>,
+
+    // How many of those point past the end of the pool, for the devices
+    // that have any, and the leaves holding them.
+    out_of_range: BTreeMap<u64, u64>,
+    out_of_range_leaves: BTreeSet<u64>,
 }
 
 // Check the mappings filling in the data_sm as we go.
+#[instrument(skip_all, fields(nr_devices = roots.len(), nr_mappings))]
 fn check_mapping_bottom_level(
     ctx: &Context,
     metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     roots: &BTreeMap<u64, (Vec<u64>, u64)>,
     ignore_non_fatal: bool,
-) -> Result<()> {
+) -> Result<MappingCounts> {
     ctx.report.set_sub_title("mapping tree");
 
-    let w = Arc::new(BTreeWalker::new_with_sm(
-        ctx.engine.clone(),
-        metadata_sm.clone(),
-        ignore_non_fatal,
-    )?);
+    let mut w =
+        BTreeWalker::new_with_sm(ctx.engine.clone(), metadata_sm.clone(), ignore_non_fatal)?;
+    w.set_abort(ctx.timed_out.clone());
+    let w = Arc::new(w);
+
+    let nr_blocks = ctx.engine.get_nr_blocks();
+    let leaf_counts = Arc::new(LeafCounts::new(nr_blocks));
+    let nr_data_blocks = data_sm.lock().unwrap().get_nr_blocks()?;
+    let out_of_range = Arc::new(OutOfRange::new(nr_data_blocks, nr_blocks));
+    let mut visitors = BTreeMap::new();
 
     // We want to print out errors as we progress, so we aggregate for each thin and print
     // at that point.
     let mut failed = false;
+    let nr_done = Arc::new(AtomicU64::new(0));
 
     if roots.len() > 64 {
         let errs = Arc::new(Mutex::new(Vec::new()));
-        for (path, root) in roots.values() {
+        for (thin_id, (path, root)) in roots {
             let data_sm = data_sm.clone();
             let root = *root;
-            let v = BottomLevelVisitor { data_sm };
+            let v = Arc::new(BottomLevelVisitor::new(
+                data_sm,
+                leaf_counts.clone(),
+                out_of_range.clone(),
+            ));
+            visitors.insert(*thin_id, v.clone());
             let w = w.clone();
             let mut path = path.clone();
             let errs = errs.clone();
+            let timed_out = ctx.timed_out.clone();
+            let nr_done = nr_done.clone();
 
             ctx.pool.execute(move || {
-                if let Err(e) = w.walk(&mut path, &v, root) {
+                if timed_out.load(Ordering::Relaxed) {
+                    return;
+                }
+                if let Err(e) = w.walk(&mut path, v.as_ref(), root) {
                     let mut errs = errs.lock().unwrap();
                     errs.push(e);
                 }
+                if !timed_out.load(Ordering::Relaxed) {
+                    nr_done.fetch_add(1, Ordering::Relaxed);
+                }
             });
         }
         ctx.pool.join();
@@ -174,41 +699,441 @@ fn check_mapping_bottom_level(
             failed = true;
         }
     } else {
-        for (path, root) in roots.values() {
+        for (thin_id, (path, root)) in roots {
+            if ctx.timed_out.load(Ordering::Relaxed) {
+                break;
+            }
+
             let w = w.clone();
             let data_sm = data_sm.clone();
             let root = *root;
-            let v = Arc::new(BottomLevelVisitor { data_sm });
+            let v = Arc::new(BottomLevelVisitor::new(
+                data_sm,
+                leaf_counts.clone(),
+                out_of_range.clone(),
+            ));
+            visitors.insert(*thin_id, v.clone());
             let mut path = path.clone();
 
             if let Err(e) = walk_threaded(&mut path, w, &ctx.pool, v, root) {
                 failed = true;
                 ctx.report.fatal(&format!("{}", e));
             }
+            if !ctx.timed_out.load(Ordering::Relaxed) {
+                nr_done.fetch_add(1, Ordering::Relaxed);
+            }
         }
     }
 
     if failed {
-        Err(anyhow!("Check of mappings failed"))
-    } else {
+        return Err(anyhow!("Check of mappings failed"));
+    }
+
+    // The walks were cut short, so the counts are meaningless.
+    if ctx.timed_out.load(Ordering::Relaxed) {
+        ctx.report.info(&format!(
+            "Checked the mappings of {} of {} devices",
+            nr_done.load(Ordering::Relaxed),
+            roots.len()
+        ));
+        return Ok(MappingCounts::default());
+    }
+
+    let mut counts = MappingCounts {
+        out_of_range_leaves: out_of_range.leaves.lock().unwrap().clone(),
+        ..Default::default()
+    };
+    let mut subtree_counts = HashMap::new();
+    let mut out_of_range_subtree_counts = HashMap::new();
+    let count_mapped = |values: &[BlockTime]| values.len() as u64;
+    let count_out_of_range =
+        |values: &[BlockTime]| values.iter().filter(|v| v.block >= nr_data_blocks).count() as u64;
+    for (thin_id, v) in visitors {
+        let mut total = v.nr_mappings.load(Ordering::Relaxed);
+        for b in v.shared.lock().unwrap().iter() {
+            total += count_subtree(
+                ctx.engine.as_ref(),
+                &leaf_counts,
+                &count_mapped,
+                &mut subtree_counts,
+                *b,
+            )?;
+        }
+        counts.mapped.insert(thin_id, total);
+
+        // Nothing's out of range in most pools, so the shared subtrees
+        // are only counted again if something is.
+        if counts.out_of_range_leaves.is_empty() {
+            continue;
+        }
+        let mut total = v.nr_out_of_range.load(Ordering::Relaxed);
+        for b in v.shared.lock().unwrap().iter() {
+            total += count_subtree(
+                ctx.engine.as_ref(),
+                &out_of_range.leaf_counts,
+                &count_out_of_range,
+                &mut out_of_range_subtree_counts,
+                *b,
+            )?;
+        }
+        if total > 0 {
+            counts.out_of_range.insert(thin_id, total);
+        }
+    }
+    Span::current().record("nr_mappings", counts.mapped.values().sum::<u64>());
+    Ok(counts)
+}
+
+// Reports the devices with mappings past the end of the pool.  Returns
+// the corrected details of those devices, for once the mappings are
+// dropped.
+fn check_out_of_range(
+    devs: &BTreeMap<u64, DeviceDetail>,
+    counts: &MappingCounts,
+    nr_data_blocks: u64,
+    report: &Report,
+) -> BTreeMap<u64, DeviceDetail> {
+    let mut fixes = BTreeMap::new();
+    for (thin_id, nr_out_of_range) in &counts.out_of_range {
+        report.non_fatal(&format!(
+            "device {}: {} mappings point past the end of the pool, which has {} data blocks",
+            thin_id, nr_out_of_range, nr_data_blocks
+        ));
+        if let Some(detail) = devs.get(thin_id) {
+            let mut detail = *detail;
+            let mapped = counts.mapped.get(thin_id).cloned().unwrap_or(0);
+            detail.mapped_blocks = mapped - nr_out_of_range;
+            fixes.insert(*thin_id, detail);
+        }
+    }
+    fixes
+}
+
+// Ref counts for the mappings in a new tree.  Data blocks past the end
+// of the pool have none.
+struct DataRC {
+    sm: ASpaceMap,
+    nr_blocks: u64,
+}
+
+impl RefCounter<BlockTime> for DataRC {
+    fn get(&self, v: &BlockTime) -> Result<u32> {
+        if v.block >= self.nr_blocks {
+            return Ok(0);
+        }
+        self.sm.lock().unwrap().get(v.block)
+    }
+
+    fn inc(&mut self, v: &BlockTime) -> Result<()> {
+        if v.block < self.nr_blocks {
+            self.sm.lock().unwrap().inc(v.block, 1)?;
+        }
+        Ok(())
+    }
+
+    fn dec(&mut self, v: &BlockTime) -> Result<()> {
+        if v.block < self.nr_blocks {
+            self.sm.lock().unwrap().dec(v.block)?;
+        }
         Ok(())
     }
 }
 
-fn mk_context(engine: Arc<dyn IoEngine + Send + Sync>, report: Arc<Report>) -> Result<Context> {
+// Pushes the mappings below a node, in order, into a new tree for the
+// device.  Leaves holding nothing past the end of the pool are shared
+// with the old tree, the others are copied without those mappings.  The
+// builder rebalances the copies, so none are left empty.
+fn copy_mappings(
+    w: &mut WriteBatcher,
+    builder: &mut BTreeBuilder<BlockTime>,
+    b: u64,
+    leaves: &BTreeSet<u64>,
+    nr_data_blocks: u64,
+) -> Result<()> {
+    let blk = w.read(b)?;
+    match unpack_node::<BlockTime>(&[b], blk.get_data(), true, true)? {
+        Node::Internal { values, .. } => {
+            for child in values {
+                copy_mappings(w, builder, child, leaves, nr_data_blocks)?;
+            }
+        }
+        Node::Leaf { keys, values, .. } => {
+            if !leaves.contains(&b) {
+                if let Some(first) = keys.first() {
+                    let leaf = NodeSummary::existing(b, *first, keys.len());
+                    builder.push_leaves(w, &[leaf])?;
+                }
+            } else {
+                for (k, v) in keys.into_iter().zip(values) {
+                    if v.block < nr_data_blocks {
+                        builder.push_value(w, k, v)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Stale counts confuse anything that watches how full the thins are,
+// eg, lvm's threshold based auto extension.  Returns the corrected
+// details of the devices that are wrong.
+fn check_mapped_blocks(
+    devs: &BTreeMap<u64, DeviceDetail>,
+    mapped: &BTreeMap<u64, u64>,
+    report: &Report,
+) -> BTreeMap<u64, DeviceDetail> {
+    let mut wrong = BTreeMap::new();
+    for (thin_id, detail) in devs {
+        let actual = mapped.get(thin_id).cloned().unwrap_or(0);
+        if detail.mapped_blocks != actual {
+            report.non_fatal(&format!(
+                "device {}: mapped_blocks is {} but its mapping tree holds {}",
+                thin_id, detail.mapped_blocks, actual
+            ));
+            let mut detail = *detail;
+            detail.mapped_blocks = actual;
+            wrong.insert(*thin_id, detail);
+        }
+    }
+    wrong
+}
+
+// The repairs that change more than the superblock.  They're written
+// copy-on-write, to blocks the old metadata doesn't use, along with new
+// space maps, and only take effect once the superblock points at them.
+// Anything shared with a metadata snapshot is left as it was.
+struct Rewrite<'a> {
+    sb: &'a Superblock,
+    devs: &'a BTreeMap<u64, DeviceDetail>,
+    roots: &'a BTreeMap<u64, (Vec<u64>, u64)>,
+
+    // Set if the mappings past the end of the pool are to be dropped.
+    out_of_range: Option<&'a MappingCounts>,
+
+    // Corrected details for some of the devices.
+    details: BTreeMap<u64, DeviceDetail>,
+
+    // The leaks found in each space map, and whether to repair them.
+    data_leaks: (Vec<BitmapLeak>, bool),
+    metadata_leaks: (Vec<BitmapLeak>, bool),
+}
+
+fn rewrite_metadata(
+    ctx: &Context,
+    rw: Rewrite,
+    metadata_sm: &ASpaceMap,
+    data_sm: &ASpaceMap,
+) -> Result<()> {
+    let engine = &ctx.engine;
+    let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    check_writable(&sb)?;
+    let data_root = unpack::<SMRoot>(&rw.sb.data_sm_root[0..])?;
+    let metadata_root = unpack::<SMRoot>(&rw.sb.metadata_sm_root[0..])?;
+
+    // The space maps are written afresh from the counts the check
+    // worked out, which have no leaks.
+    let (data_leaks, fix_data_leaks) = rw.data_leaks;
+    let new_data_sm = rw.out_of_range.is_some() || fix_data_leaks;
+    if new_data_sm && !fix_data_leaks {
+        keep_leaks(engine.clone(), &data_leaks, data_sm)?;
+    }
+    let (metadata_leaks, fix_metadata_leaks) = rw.metadata_leaks;
+    if !fix_metadata_leaks {
+        keep_leaks(engine.clone(), &metadata_leaks, metadata_sm)?;
+    }
+
+    let sm = CowSpaceMap::new(metadata_sm.clone(), metadata_root.nr_blocks)?;
+    if let Some(txn) = &ctx.txn {
+        txn.protect(sm.in_use());
+    }
+    let mut w = WriteBatcher::new(
+        engine.clone(),
+        Arc::new(Mutex::new(sm)),
+        engine.get_batch_size(),
+    );
+
+    if !rw.details.is_empty() {
+        let mut builder = BTreeBuilder::<DeviceDetail>::new(Box::new(NoopRC {}));
+        for (thin_id, detail) in rw.devs {
+            let detail = rw.details.get(thin_id).unwrap_or(detail);
+            builder.push_value(&mut w, *thin_id, *detail)?;
+        }
+        sb.details_root = builder.complete(&mut w)?;
+        release_btree::<DeviceDetail>(&mut w, rw.sb.details_root, &mut NoopRC {})?;
+    }
+
+    if let Some(counts) = rw.out_of_range {
+        let mut data_rc = DataRC {
+            sm: data_sm.clone(),
+            nr_blocks: data_root.nr_blocks,
+        };
+
+        // The devices without mappings past the end keep their trees.
+        let mut builder = BTreeBuilder::<u64>::new(Box::new(NoopRC {}));
+        for (thin_id, (_, root)) in rw.roots {
+            let root = if counts.out_of_range.contains_key(thin_id) {
+                let mut mappings = BTreeBuilder::<BlockTime>::new(Box::new(DataRC {
+                    sm: data_sm.clone(),
+                    nr_blocks: data_root.nr_blocks,
+                }));
+                copy_mappings(
+                    &mut w,
+                    &mut mappings,
+                    *root,
+                    &counts.out_of_range_leaves,
+                    data_root.nr_blocks,
+                )?;
+                mappings.complete(&mut w)?
+            } else {
+                w.sm.lock().unwrap().inc(*root, 1)?;
+                *root
+            };
+            builder.push_value(&mut w, *thin_id, root)?;
+        }
+        sb.mapping_root = builder.complete(&mut w)?;
+
+        let mut released = ReleasedValues::<u64>::new();
+        release_btree(&mut w, rw.sb.mapping_root, &mut released)?;
+        for root in released.values {
+            release_btree(&mut w, root, &mut data_rc)?;
+        }
+    }
+
+    if new_data_sm {
+        release_disk_sm(&mut w, &data_root)?;
+        let root = write_disk_sm(&mut w, &*data_sm.lock().unwrap())?;
+        sb.data_sm_root = pack_root(&root, SPACE_MAP_ROOT_SIZE)?;
+    }
+
+    // Last, since it counts the blocks everything else was written to.
+    release_metadata_sm(&mut w, &metadata_root)?;
+    let root = rewrite_metadata_sm(&mut w)?;
+    sb.metadata_sm_root = pack_root(&root, SPACE_MAP_ROOT_SIZE)?;
+
+    write_superblock(engine.as_ref(), SUPERBLOCK_LOCATION, &sb)
+}
+
+// A metadata snapshot holds references to the trees as they were when
+// it was taken, so anything only reachable from it must still be
+// allocated, or the kernel could hand those blocks out again.  Returns
+// false if metadata_snap is stale, ie, points at a free block, in which
+// case nothing is counted for it.
+fn check_metadata_snap(
+    ctx: &Context,
+    sb: &Superblock,
+    metadata_root: &SMRoot,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    ignore_non_fatal: bool,
+) -> Result<bool> {
+    if sb.metadata_snap == 0 {
+        return Ok(true);
+    }
+
+    ctx.report.set_sub_title("metadata snapshot");
+    let count = read_metadata_ref_count(ctx.engine.clone(), metadata_root, sb.metadata_snap)?;
+    if count == 0 {
+        ctx.report.non_fatal(&format!(
+            "metadata_snap points at block {}, which is free, so the snapshot is stale",
+            sb.metadata_snap
+        ));
+        return Ok(false);
+    }
+
+    let snap = read_superblock(ctx.engine.as_ref(), sb.metadata_snap).map_err(|e| {
+        ctx.report.fatal(&format!(
+            "metadata_snap points at block {}, which isn't a superblock: {}",
+            sb.metadata_snap, e
+        ));
+        anyhow!("metadata snapshot is damaged")
+    })?;
+    metadata_sm.lock().unwrap().inc(sb.metadata_snap, 1)?;
+
+    // Roots shared with the live metadata are just counted again, and
+    // not descended into.
+    let mut path = vec![0];
+    btree_to_map_with_sm::<DeviceDetail>(
+        &mut path,
+        ctx.engine.clone(),
+        metadata_sm.clone(),
+        ignore_non_fatal,
+        snap.details_root,
+    )?;
+    let roots = btree_to_map_with_path::<u64>(
+        &mut path,
+        ctx.engine.clone(),
+        metadata_sm.clone(),
+        ignore_non_fatal,
+        snap.mapping_root,
+    )?;
+    check_mapping_bottom_level(ctx, metadata_sm, data_sm, &roots, ignore_non_fatal)?;
+    Ok(true)
+}
+
+fn clear_metadata_snap(engine: &dyn IoEngine) -> Result<()> {
+    let mut sb = read_superblock(engine, SUPERBLOCK_LOCATION)?;
+    check_writable(&sb)?;
+    sb.metadata_snap = 0;
+    write_superblock(engine, SUPERBLOCK_LOCATION, &sb)
+}
+
+fn mk_context(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    txn: Option<Arc<TransactionEngine>>,
+    report: Arc<Report>,
+) -> Result<Context> {
     let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
     let pool = ThreadPool::new(nr_threads);
 
+    // Some trees are walked more than once, eg, the device details.
+    let (nr_cache_blocks, cache_claim) =
+        memory::claim_blocks(DEFAULT_CACHE_BLOCKS, MIN_CACHE_BLOCKS);
+    let engine = Arc::new(BlockCache::new(engine, nr_cache_blocks));
+
     Ok(Context {
         report,
         engine,
         pool,
+        txn,
+        timed_out: Arc::new(AtomicBool::new(false)),
+        audit: Mutex::new(Audit::default()),
+        _cache_claim: cache_claim,
     })
 }
 
+#[instrument(skip_all, fields(nr_metadata_blocks = opts.engine.get_nr_blocks()))]
 pub fn check(opts: ThinCheckOptions) -> Result<()> {
-    let ctx = mk_context(opts.engine.clone(), opts.report.clone())?;
+    // Repairs are only written once the whole check has passed.
+    let txn = if opts.policy.fixes_anything() {
+        Some(Arc::new(TransactionEngine::new(opts.engine.clone())))
+    } else {
+        None
+    };
+    let engine = match &txn {
+        Some(txn) => txn.clone() as Arc<dyn IoEngine + Send + Sync>,
+        None => opts.engine.clone(),
+    };
+
+    let ctx = mk_context(engine, txn.clone(), opts.report.clone())?;
+    if let Some(timeout) = opts.timeout {
+        spawn_timeout_thread(timeout, ctx.timed_out.clone());
+    }
 
+    let mut r = check_(&ctx, &opts);
+    if let (Ok(()), Some(txn)) = (&r, &txn) {
+        r = txn.commit().map(|_| ()).map_err(|e| e.into());
+    }
+    if opts.audit {
+        let timed_out = ctx.timed_out.load(Ordering::Relaxed);
+        for line in ctx.audit.lock().unwrap().lines(&opts, timed_out) {
+            ctx.report.to_stdout(&line);
+        }
+    }
+    r
+}
+
+fn check_(ctx: &Context, opts: &ThinCheckOptions) -> Result<()> {
     // FIXME: temporarily get these out
     let report = &ctx.report;
     let engine = &ctx.engine;
@@ -216,40 +1141,123 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
     report.set_title("Checking thin metadata");
 
     // superblock
-    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let sb = ctx.audit_err(
+        "superblock",
+        read_primary_superblock(engine.as_ref(), report),
+    )?;
+    ctx.audit_err(
+        "superblock",
+        check_features(&sb, report, opts.policy.fixes_anything()),
+    )?;
+    ctx.audit_err("superblock", check_data_block_size(&sb))?;
+    ctx.audit(
+        "superblock",
+        AuditOutcome::Pass,
+        format!("transaction id {}", sb.transaction_id),
+    );
 
     report.to_stdout(&format!("TRANSACTION_ID={}", sb.transaction_id));
 
-    if opts.sb_only {
-        return Ok(());
+    let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+    let short_data_dev = opts
+        .data_device_size
+        .and_then(|bytes| check_data_device_size(&sb, data_root.nr_blocks, bytes, report));
+    match (opts.data_device_size, short_data_dev) {
+        (None, _) => ctx.audit(
+            "data device size",
+            AuditOutcome::Skip,
+            "no --data-device given".to_string(),
+        ),
+        (Some(_), None) => ctx.audit(
+            "data device size",
+            AuditOutcome::Pass,
+            format!("the device holds all {} data blocks", data_root.nr_blocks),
+        ),
+        (Some(_), Some(dev_blocks)) => ctx.audit(
+            "data device size",
+            AuditOutcome::Fail,
+            format!(
+                "the device holds {} of {} data blocks",
+                dev_blocks, data_root.nr_blocks
+            ),
+        ),
     }
 
     let metadata_root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
+    let short_metadata_dev =
+        check_metadata_device_size(metadata_root.nr_blocks, engine.get_nr_blocks(), report);
+    match short_metadata_dev {
+        None => ctx.audit(
+            "metadata device size",
+            AuditOutcome::Pass,
+            format!(
+                "the device holds all {} metadata blocks",
+                metadata_root.nr_blocks
+            ),
+        ),
+        Some(dev_blocks) => ctx.audit(
+            "metadata device size",
+            AuditOutcome::Fail,
+            format!(
+                "the device holds {} of {} metadata blocks",
+                dev_blocks, metadata_root.nr_blocks
+            ),
+        ),
+    }
+
+    if opts.sb_only {
+        return match (short_data_dev, short_metadata_dev) {
+            (Some(_), _) => Err(data_device_too_small()),
+            (None, Some(_)) => Err(metadata_device_too_small()),
+            (None, None) => Ok(()),
+        };
+    }
+
+    let mut verified = vec!["superblock"];
+    check_time(ctx, &verified)?;
+
     let mut path = vec![0];
 
     // Device details.   We read this once to get the number of thin devices, and hence the
     // maximum metadata ref count.  Then create metadata space map, and reread to increment
     // the ref counts for that metadata.
-    let devs = btree_to_map::<DeviceDetail>(
-        &mut path,
-        engine.clone(),
-        opts.ignore_non_fatal,
-        sb.details_root,
+    let devs = ctx.audit_err(
+        "device details tree",
+        btree_to_map::<DeviceDetail>(
+            &mut path,
+            engine.clone(),
+            opts.ignore_non_fatal,
+            sb.details_root,
+        ),
     )?;
     let nr_devs = devs.len();
+    let _metadata_sm_claim = memory::claim(
+        core_sm_bytes(engine.get_nr_blocks(), nr_devs as u32),
+        "metadata space map",
+    )?;
     let metadata_sm = core_sm(engine.get_nr_blocks(), nr_devs as u32);
-    inc_superblock(&metadata_sm)?;
+    inc_superblock(engine.as_ref(), &sb, &metadata_sm)?;
 
     report.set_sub_title("device details tree");
-    let _devs = btree_to_map_with_sm::<DeviceDetail>(
-        &mut path,
-        engine.clone(),
-        metadata_sm.clone(),
-        opts.ignore_non_fatal,
-        sb.details_root,
-    )?;
-
-    let (tid, stop_progress) = spawn_progress_thread(
+    let details = info_span!("device_details_tree", nr_devices = nr_devs).in_scope(|| {
+        btree_to_map_with_sm::<DeviceDetail>(
+            &mut path,
+            engine.clone(),
+            metadata_sm.clone(),
+            opts.ignore_non_fatal,
+            sb.details_root,
+        )
+    });
+    ctx.audit_err("device details tree", details)?;
+    ctx.audit(
+        "device details tree",
+        AuditOutcome::Pass,
+        format!("{} devices", nr_devs),
+    );
+    verified.push("device details tree");
+    check_time(ctx, &verified)?;
+
+    let progress = spawn_progress_thread(
         metadata_sm.clone(),
         metadata_root.nr_allocated,
         report.clone(),
@@ -257,31 +1265,133 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
 
     // mapping top level
     report.set_sub_title("mapping tree");
-    let roots = btree_to_map_with_path::<u64>(
-        &mut path,
-        engine.clone(),
-        metadata_sm.clone(),
-        opts.ignore_non_fatal,
-        sb.mapping_root,
-    )?;
+    let roots = info_span!("mapping_tree_top_level").in_scope(|| {
+        btree_to_map_with_path::<u64>(
+            &mut path,
+            engine.clone(),
+            metadata_sm.clone(),
+            opts.ignore_non_fatal,
+            sb.mapping_root,
+        )
+    });
+    let roots = ctx.audit_err("mapping tree top level", roots)?;
+    ctx.audit(
+        "mapping tree top level",
+        AuditOutcome::Pass,
+        format!("{} devices", roots.len()),
+    );
+    verified.push("mapping tree top level");
+    check_time(ctx, &verified)?;
 
     if opts.skip_mappings {
-        let cleared = clear_needs_check_flag(ctx.engine.clone())?;
-        if cleared {
-            ctx.report.info("Cleared needs_check flag");
+        if short_data_dev.is_some() {
+            return Err(data_device_too_small());
+        }
+        if short_metadata_dev.is_some() {
+            return Err(metadata_device_too_small());
+        }
+        if opts.policy.action("needs_check") == Action::Fix {
+            let cleared = clear_needs_check_flag(ctx.engine.clone())?;
+            if cleared {
+                ctx.report.info("Cleared needs_check flag");
+            }
         }
         return Ok(());
     }
 
     // mapping bottom level
     let root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+    let _data_sm_claim = memory::claim(
+        core_sm_bytes(root.nr_blocks, nr_devs as u32),
+        "data space map",
+    )?;
     let data_sm = core_sm(root.nr_blocks, nr_devs as u32);
-    check_mapping_bottom_level(&ctx, &metadata_sm, &data_sm, &roots, opts.ignore_non_fatal)?;
+    let counts =
+        check_mapping_bottom_level(ctx, &metadata_sm, &data_sm, &roots, opts.ignore_non_fatal);
+    let counts = ctx.audit_err("mapping tree bottom level", counts)?;
+    check_time(ctx, &verified)?;
+    verified.push("mappings");
+    let nr_mappings = counts.mapped.values().sum::<u64>();
+    let nr_out_of_range = counts.out_of_range.values().sum::<u64>();
+    if nr_out_of_range == 0 {
+        ctx.audit(
+            "mapping tree bottom level",
+            AuditOutcome::Pass,
+            format!("{} mappings", nr_mappings),
+        );
+    } else {
+        ctx.audit(
+            "mapping tree bottom level",
+            AuditOutcome::Fail,
+            format!(
+                "{} mappings, {} past the end of the pool",
+                nr_mappings, nr_out_of_range
+            ),
+        );
+    }
+
+    // Nothing should be repaired, or the needs_check flag cleared, until
+    // the data and metadata devices are sorted out.
+    if let Some(dev_blocks) = short_data_dev {
+        report_mappings_beyond(&data_sm, dev_blocks, root.nr_blocks, report)?;
+        return Err(data_device_too_small());
+    }
+    if short_metadata_dev.is_some() {
+        return Err(metadata_device_too_small());
+    }
+
+    let out_of_range_fixes = check_out_of_range(&devs, &counts, data_root.nr_blocks, report);
+    let mut mapped_fixes = check_mapped_blocks(&devs, &counts.mapped, report);
+    if mapped_fixes.is_empty() {
+        ctx.audit(
+            "mapped_blocks counts",
+            AuditOutcome::Pass,
+            format!("{} devices", devs.len()),
+        );
+    } else {
+        ctx.audit(
+            "mapped_blocks counts",
+            AuditOutcome::Fail,
+            format!("{} of {} devices are wrong", mapped_fixes.len(), devs.len()),
+        );
+    }
+
+    let snap_ok = check_metadata_snap(
+        ctx,
+        &sb,
+        &metadata_root,
+        &metadata_sm,
+        &data_sm,
+        opts.ignore_non_fatal,
+    );
+    let snap_ok = ctx.audit_err("metadata snapshot", snap_ok)?;
+    check_time(ctx, &verified)?;
+    if sb.metadata_snap == 0 {
+        ctx.audit(
+            "metadata snapshot",
+            AuditOutcome::Skip,
+            "no snapshot is held".to_string(),
+        );
+    } else if snap_ok {
+        verified.push("metadata snapshot");
+        ctx.audit(
+            "metadata snapshot",
+            AuditOutcome::Pass,
+            format!("block {}", sb.metadata_snap),
+        );
+    } else {
+        ctx.audit(
+            "metadata snapshot",
+            AuditOutcome::Fail,
+            format!("block {} is free, the snapshot is stale", sb.metadata_snap),
+        );
+    }
 
     //-----------------------------------------
 
     report.set_sub_title("data space map");
     let root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+    let nr_blocks = (root.nr_allocated, root.nr_blocks);
     let data_leaks = check_disk_space_map(
         engine.clone(),
         report.clone(),
@@ -289,7 +1399,11 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
         data_sm.clone(),
         metadata_sm.clone(),
         opts.ignore_non_fatal,
-    )?;
+    );
+    let data_leaks = ctx.audit_err("data space map", data_leaks)?;
+    audit_space_map(ctx, "data space map", nr_blocks, &data_leaks);
+    verified.push("data space map");
+    check_time(ctx, &verified)?;
 
     //-----------------------------------------
 
@@ -301,59 +1415,154 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
     ));
 
     // Now the counts should be correct and we can check it.
+    let nr_blocks = (root.nr_allocated, root.nr_blocks);
     let metadata_leaks = check_metadata_space_map(
         engine.clone(),
         report.clone(),
         root,
         metadata_sm.clone(),
         opts.ignore_non_fatal,
-    )?;
+    );
+    let metadata_leaks = ctx.audit_err("metadata space map", metadata_leaks)?;
+    audit_space_map(ctx, "metadata space map", nr_blocks, &metadata_leaks);
 
     //-----------------------------------------
 
-    if opts.auto_repair {
-        if !data_leaks.is_empty() {
-            ctx.report.info("Repairing data leaks.");
-            repair_space_map(ctx.engine.clone(), data_leaks, data_sm.clone())?;
-        }
+    let policy = &opts.policy;
+    let fix_out_of_range = policy.weigh(
+        "out_of_range",
+        !out_of_range_fixes.is_empty(),
+        "mappings point past the end of the pool",
+        report,
+    )?;
 
-        if !metadata_leaks.is_empty() {
-            ctx.report.info("Repairing metadata leaks.");
-            repair_space_map(ctx.engine.clone(), metadata_leaks, metadata_sm.clone())?;
-        }
+    // Dropping the mappings corrects the counts of those devices anyway.
+    if fix_out_of_range {
+        mapped_fixes.retain(|thin_id, _| !out_of_range_fixes.contains_key(thin_id));
+    }
+    let fix_mapped = policy.weigh(
+        "mapped_blocks",
+        !mapped_fixes.is_empty(),
+        "device details hold incorrect mapped_blocks",
+        report,
+    )?;
+    let fix_snap = policy.weigh(
+        "metadata_snap",
+        !snap_ok,
+        "metadata snapshot is stale",
+        report,
+    )?;
+    let fix_data_leaks = policy.weigh(
+        "data_leaks",
+        !data_leaks.is_empty(),
+        "data space map contains leaks",
+        report,
+    )?;
+    let fix_metadata_leaks = policy.weigh(
+        "metadata_leaks",
+        !metadata_leaks.is_empty(),
+        "metadata space map contains leaks",
+        report,
+    )?;
 
-        let cleared = clear_needs_check_flag(ctx.engine.clone())?;
-        if cleared {
-            ctx.report.info("Cleared needs_check flag");
+    if fix_snap {
+        ctx.report.info("Clearing the stale metadata_snap.");
+        clear_metadata_snap(ctx.engine.as_ref())?;
+        ctx.audit.lock().unwrap().repaired("metadata snapshot");
+    }
+
+    let mut details = BTreeMap::new();
+    if fix_out_of_range {
+        ctx.report.info(&format!(
+            "Dropping {} mappings past the end of the pool.",
+            nr_out_of_range
+        ));
+        details.extend(out_of_range_fixes);
+    }
+    if fix_mapped {
+        ctx.report
+            .info("Repairing mapped_blocks in the device details.");
+        details.extend(mapped_fixes);
+    }
+    if fix_data_leaks {
+        ctx.report.info("Repairing data leaks.");
+    }
+    if fix_metadata_leaks {
+        ctx.report.info("Repairing metadata leaks.");
+    }
+
+    if fix_out_of_range || fix_mapped || fix_data_leaks || fix_metadata_leaks {
+        let rw = Rewrite {
+            sb: &sb,
+            devs: &devs,
+            roots: &roots,
+            out_of_range: if fix_out_of_range {
+                Some(&counts)
+            } else {
+                None
+            },
+            details,
+            data_leaks: (data_leaks, fix_data_leaks),
+            metadata_leaks: (metadata_leaks, fix_metadata_leaks),
+        };
+        rewrite_metadata(ctx, rw, &metadata_sm, &data_sm)?;
+
+        let mut audit = ctx.audit.lock().unwrap();
+        if fix_out_of_range {
+            audit.repaired("mapping tree bottom level");
         }
-    } else if !opts.ignore_non_fatal {
-        if !data_leaks.is_empty() {
-            return Err(anyhow!("data space map contains leaks"));
+        if fix_mapped {
+            audit.repaired("mapped_blocks counts");
         }
-
-        if !metadata_leaks.is_empty() {
-            return Err(anyhow!("metadata space map contains leaks"));
+        if fix_data_leaks {
+            audit.repaired("data space map");
+        }
+        if fix_metadata_leaks {
+            audit.repaired("metadata space map");
         }
+    }
 
-        if opts.clear_needs_check {
-            let cleared = clear_needs_check_flag(ctx.engine.clone())?;
-            if cleared {
-                ctx.report.info("Cleared needs_check flag");
-            }
+    if policy.action("needs_check") == Action::Fix {
+        let cleared = clear_needs_check_flag(ctx.engine.clone())?;
+        if cleared {
+            ctx.report.info("Cleared needs_check flag");
         }
     }
 
-    stop_progress.store(true, Ordering::Relaxed);
-    tid.join().unwrap();
+    drop(progress);
 
     Ok(())
 }
 
+// nr_blocks is the number allocated, and the total, from the space map root.
+fn audit_space_map(
+    ctx: &Context,
+    check: &'static str,
+    nr_blocks: (u64, u64),
+    leaks: &[BitmapLeak],
+) {
+    if leaks.is_empty() {
+        ctx.audit(
+            check,
+            AuditOutcome::Pass,
+            format!("{} of {} blocks allocated", nr_blocks.0, nr_blocks.1),
+        );
+    } else {
+        ctx.audit(
+            check,
+            AuditOutcome::Fail,
+            format!("{} bitmaps hold leaked blocks", leaks.len()),
+        );
+    }
+}
+
+#[instrument(skip_all)]
 pub fn clear_needs_check_flag(engine: Arc<dyn IoEngine + Send + Sync>) -> Result<bool> {
     let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
     if !sb.flags.needs_check {
         return Ok(false);
     }
+    check_writable(&sb)?;
     sb.flags.needs_check = false;
     write_superblock(engine.as_ref(), SUPERBLOCK_LOCATION, &sb).map(|_| true)
 }
@@ -366,11 +1575,12 @@ pub struct CheckMaps {
     pub data_sm: Arc<Mutex<dyn SpaceMap + Send + Sync>>,
 }
 
+#[instrument(skip_all)]
 pub fn check_with_maps(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
 ) -> Result<CheckMaps> {
-    let ctx = mk_context(engine.clone(), report.clone())?;
+    let ctx = mk_context(engine.clone(), None, report.clone())?;
     report.set_title("Checking thin metadata");
 
     // superblock
@@ -386,19 +1596,25 @@ pub fn check_with_maps(
     // the ref counts for that metadata.
     let devs = btree_to_map::<DeviceDetail>(&mut path, engine.clone(), false, sb.details_root)?;
     let nr_devs = devs.len();
+    let _metadata_sm_claim = memory::claim(
+        core_sm_bytes(engine.get_nr_blocks(), nr_devs as u32),
+        "metadata space map",
+    )?;
     let metadata_sm = core_sm(engine.get_nr_blocks(), nr_devs as u32);
-    inc_superblock(&metadata_sm)?;
+    inc_superblock(engine.as_ref(), &sb, &metadata_sm)?;
 
     report.set_sub_title("device details tree");
-    let _devs = btree_to_map_with_sm::<DeviceDetail>(
-        &mut path,
-        engine.clone(),
-        metadata_sm.clone(),
-        false,
-        sb.details_root,
-    )?;
-
-    let (tid, stop_progress) = spawn_progress_thread(
+    let _devs = info_span!("device_details_tree", nr_devices = nr_devs).in_scope(|| {
+        btree_to_map_with_sm::<DeviceDetail>(
+            &mut path,
+            engine.clone(),
+            metadata_sm.clone(),
+            false,
+            sb.details_root,
+        )
+    })?;
+
+    let progress = spawn_progress_thread(
         metadata_sm.clone(),
         metadata_root.nr_allocated,
         report.clone(),
@@ -406,18 +1622,25 @@ pub fn check_with_maps(
 
     // mapping top level
     report.set_sub_title("mapping tree");
-    let roots = btree_to_map_with_path::<u64>(
-        &mut path,
-        engine.clone(),
-        metadata_sm.clone(),
-        false,
-        sb.mapping_root,
-    )?;
+    let roots = info_span!("mapping_tree_top_level").in_scope(|| {
+        btree_to_map_with_path::<u64>(
+            &mut path,
+            engine.clone(),
+            metadata_sm.clone(),
+            false,
+            sb.mapping_root,
+        )
+    })?;
 
     // mapping bottom level
     let root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+    let _data_sm_claim = memory::claim(
+        core_sm_bytes(root.nr_blocks, nr_devs as u32),
+        "data space map",
+    )?;
     let data_sm = core_sm(root.nr_blocks, nr_devs as u32);
     check_mapping_bottom_level(&ctx, &metadata_sm, &data_sm, &roots, false)?;
+    check_metadata_snap(&ctx, &sb, &metadata_root, &metadata_sm, &data_sm, false)?;
 
     //-----------------------------------------
 
@@ -447,8 +1670,7 @@ pub fn check_with_maps(
 
     //-----------------------------------------
 
-    stop_progress.store(true, Ordering::Relaxed);
-    tid.join().unwrap();
+    drop(progress);
 
     Ok(CheckMaps {
         metadata_sm: metadata_sm.clone(),
@@ -0,0 +1,283 @@
+use std::collections::{btree_map, BTreeMap, VecDeque};
+use std::sync::Arc;
+
+use crate::io_engine::*;
+use crate::pdata::btree::{self, *};
+use crate::pdata::btree_walker::*;
+use crate::thin::block_time::*;
+use crate::thin::device_detail::*;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+/// A range of a thin device's virtual blocks whose mappings can't be
+/// recovered from its mapping tree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DamagedRegion {
+    pub thin_id: u64,
+    pub keys: KeyRange,
+    pub cause: String,
+}
+
+// Only the walk matters, the mappings themselves aren't needed.
+struct NoopVisitor;
+
+impl NodeVisitor<BlockTime> for NoopVisitor {
+    fn visit(
+        &self,
+        _path: &[u64],
+        _kr: &KeyRange,
+        _h: &NodeHeader,
+        _keys: &[u64],
+        _values: &[BlockTime],
+    ) -> btree::Result<()> {
+        Ok(())
+    }
+
+    fn visit_again(&self, _path: &[u64], _b: u64) -> btree::Result<()> {
+        Ok(())
+    }
+
+    fn end_walk(&self) -> btree::Result<()> {
+        Ok(())
+    }
+}
+
+/// Enumerates the damaged regions of every thin device, a device at a
+/// time, in order of thin id.  Each device's mapping tree is only walked
+/// when the iterator reaches it.
+///
+/// A device that's listed in the details tree, but whose mapping tree
+/// root is lost, is damaged over its whole range.  Devices lost from
+/// both the details and the top level of the mapping tree can't be
+/// reported, since nothing records their ids.
+pub struct DamagedRegions {
+    walker: BTreeWalker,
+    devices: btree_map::IntoIter<u64, Option<u64>>,
+    top_level_damage: Vec<DamagedRange>,
+    pending: VecDeque<DamagedRegion>,
+}
+
+impl DamagedRegions {
+    pub fn new(engine: Arc<dyn IoEngine + Send + Sync>, sb: &Superblock) -> DamagedRegions {
+        let (details, _) =
+            btree_to_map_with_damage::<DeviceDetail>(&mut vec![0], engine.clone(), sb.details_root);
+        let (roots, err) =
+            btree_to_map_with_damage::<u64>(&mut vec![0], engine.clone(), sb.mapping_root);
+
+        let mut devices: BTreeMap<u64, Option<u64>> =
+            details.keys().map(|thin_id| (*thin_id, None)).collect();
+        for (thin_id, root) in roots {
+            devices.insert(thin_id, Some(root));
+        }
+
+        DamagedRegions {
+            walker: BTreeWalker::new(engine, true),
+            devices: devices.into_iter(),
+            top_level_damage: err.map_or_else(Vec::new, |e| e.damaged_ranges().collect()),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn missing_root_cause(&self, thin_id: u64) -> String {
+        self.top_level_damage
+            .iter()
+            .find(|r| {
+                !matches!(r.keys.start, Some(s) if s > thin_id)
+                    && !matches!(r.keys.end, Some(e) if e <= thin_id)
+            })
+            .map_or_else(
+                || "no mapping tree".to_string(),
+                |r| format!("mapping tree root lost: {}", r.cause),
+            )
+    }
+
+    fn walk_device(&mut self, thin_id: u64, root: Option<u64>) {
+        let root = match root {
+            Some(root) => root,
+            None => {
+                let cause = self.missing_root_cause(thin_id);
+                self.pending.push_back(DamagedRegion {
+                    thin_id,
+                    keys: KeyRange::new(),
+                    cause,
+                });
+                return;
+            }
+        };
+
+        // Shared nodes are only walked once, so a damaged node below a
+        // snapshot is reported with the error from its first visit.
+        if let Err(e) = self.walker.walk(&mut vec![0], &NoopVisitor, root) {
+            let mut ranges: Vec<DamagedRange> = e.damaged_ranges().collect();
+            ranges.sort_by_key(|r| r.keys.start);
+            ranges.dedup_by(|a, b| a.keys == b.keys && a.cause == b.cause);
+            for r in ranges {
+                self.pending.push_back(DamagedRegion {
+                    thin_id,
+                    keys: r.keys,
+                    cause: r.cause,
+                });
+            }
+        }
+    }
+}
+
+impl Iterator for DamagedRegions {
+    type Item = DamagedRegion;
+
+    fn next(&mut self) -> Option<DamagedRegion> {
+        loop {
+            if let Some(region) = self.pending.pop_front() {
+                return Some(region);
+            }
+            let (thin_id, root) = self.devices.next()?;
+            self.walk_device(thin_id, root);
+        }
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+
+    use crate::report::*;
+    use crate::thin::metadata_repair::SuperblockOverrides;
+    use crate::thin::restore::{restore, ThinRestoreOptions};
+
+    // Enough mappings in device 1 for its tree to have several leaves.
+    fn mk_metadata(dir: &Path) -> Result<Arc<dyn IoEngine + Send + Sync>> {
+        let xml = dir.join("md.xml");
+        let md = dir.join("md.bin");
+        let mut f = File::create(&xml)?;
+        writeln!(
+            f,
+            r#"<superblock uuid="" time="0" transaction="1" data_block_size="128" nr_data_blocks="4096">"#
+        )?;
+        writeln!(
+            f,
+            r#"<device dev_id="1" mapped_blocks="1000" transaction="0" creation_time="0" snap_time="0">"#
+        )?;
+        for b in 0..1000 {
+            writeln!(
+                f,
+                r#"<single_mapping origin_block="{}" data_block="{}" time="0"/>"#,
+                b * 2,
+                b
+            )?;
+        }
+        writeln!(f, "</device>")?;
+        writeln!(
+            f,
+            r#"<device dev_id="2" mapped_blocks="1" transaction="0" creation_time="0" snap_time="0">"#
+        )?;
+        writeln!(
+            f,
+            r#"<single_mapping origin_block="0" data_block="2000" time="0"/>"#
+        )?;
+        writeln!(f, "</device>\n</superblock>")?;
+        drop(f);
+
+        File::create(&md)?.set_len(4 << 20)?;
+        restore(ThinRestoreOptions {
+            input: &xml,
+            output: &md,
+            async_io: false,
+            nr_io_threads: 1,
+            report: Arc::new(mk_quiet_report()),
+            verify: false,
+            backup_superblock: false,
+            overrides: SuperblockOverrides {
+                transaction_id: None,
+                data_block_size: None,
+                nr_data_blocks: None,
+            },
+        })?;
+        Ok(Arc::new(SyncIoEngine::new(&md, 1, true)?))
+    }
+
+    fn device_root(engine: &Arc<dyn IoEngine + Send + Sync>, sb: &Superblock, thin_id: u64) -> u64 {
+        let roots =
+            btree_to_map::<u64>(&mut vec![0], engine.clone(), false, sb.mapping_root).unwrap();
+        roots[&thin_id]
+    }
+
+    #[test]
+    fn test_clean_metadata_has_no_damage() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let engine = mk_metadata(dir.path())?;
+        let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+        assert_eq!(DamagedRegions::new(engine, &sb).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_damaged_leaf() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let engine = mk_metadata(dir.path())?;
+        let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+
+        let root = device_root(&engine, &sb, 1);
+        let b = engine.read(root)?;
+        let (keys, values) = match unpack_node::<BlockTime>(&[0], b.get_data(), false, true)? {
+            Node::Internal { keys, values, .. } => (keys, values),
+            Node::Leaf { .. } => panic!("device 1 should need more than one leaf"),
+        };
+        assert!(keys.len() > 2);
+        engine.write(&Block::zeroed(values[1]))?;
+
+        let regions: Vec<DamagedRegion> = DamagedRegions::new(engine, &sb).collect();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].thin_id, 1);
+        assert_eq!(
+            regions[0].keys,
+            KeyRange {
+                start: Some(keys[1]),
+                end: Some(keys[2]),
+            }
+        );
+        assert!(regions[0].cause.contains("checksum failed"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_damaged_root() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let engine = mk_metadata(dir.path())?;
+        let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+
+        let root = device_root(&engine, &sb, 2);
+        engine.write(&Block::zeroed(root))?;
+
+        let regions: Vec<DamagedRegion> = DamagedRegions::new(engine, &sb).collect();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].thin_id, 2);
+        assert_eq!(regions[0].keys, KeyRange::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lost_mapping_root() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let engine = mk_metadata(dir.path())?;
+        let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+        engine.write(&Block::zeroed(sb.mapping_root))?;
+
+        let regions: Vec<DamagedRegion> = DamagedRegions::new(engine, &sb).collect();
+        let ids: Vec<u64> = regions.iter().map(|r| r.thin_id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        for r in &regions {
+            assert_eq!(r.keys, KeyRange::new());
+            assert!(r.cause.starts_with("mapping tree root lost"));
+        }
+        Ok(())
+    }
+}
+
+//------------------------------------------
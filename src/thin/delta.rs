@@ -0,0 +1,343 @@
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, Mutex};
+
+use crate::io_engine::*;
+use crate::pdata::btree::{self, *};
+use crate::pdata::btree_walker::*;
+use crate::thin::block_time::*;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Run {
+    thin_begin: u64,
+    data_begin: u64,
+    len: u64,
+}
+
+/// The mappings of a thin device, held as runs of adjacent blocks, ready
+/// to be diffed against another's.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceMappings {
+    runs: Vec<Run>,
+}
+
+struct RunCollector {
+    runs: Mutex<Vec<Run>>,
+}
+
+impl NodeVisitor<BlockTime> for RunCollector {
+    fn visit(
+        &self,
+        _path: &[u64],
+        _kr: &KeyRange,
+        _h: &NodeHeader,
+        keys: &[u64],
+        values: &[BlockTime],
+    ) -> btree::Result<()> {
+        let mut runs = self.runs.lock().unwrap();
+        for (k, v) in keys.iter().zip(values.iter()) {
+            match runs.last_mut() {
+                Some(r) if r.thin_begin + r.len == *k && r.data_begin + r.len == v.block => {
+                    r.len += 1;
+                }
+                _ => runs.push(Run {
+                    thin_begin: *k,
+                    data_begin: v.block,
+                    len: 1,
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_again(&self, _path: &[u64], _b: u64) -> btree::Result<()> {
+        Ok(())
+    }
+
+    fn end_walk(&self) -> btree::Result<()> {
+        Ok(())
+    }
+}
+
+impl DeviceMappings {
+    /// Reads the mapping tree with the given root.
+    pub fn read(engine: Arc<dyn IoEngine + Send + Sync>, root: u64) -> Result<DeviceMappings> {
+        let collector = RunCollector {
+            runs: Mutex::new(Vec::new()),
+        };
+        let walker = BTreeWalker::new(engine, false);
+        walker.walk(&mut vec![0], &collector, root)?;
+        Ok(DeviceMappings {
+            runs: collector.runs.into_inner().unwrap(),
+        })
+    }
+
+    /// Reads the mapping tree of a thin device.
+    pub fn read_device(
+        engine: Arc<dyn IoEngine + Send + Sync>,
+        sb: &Superblock,
+        thin_id: u64,
+    ) -> Result<DeviceMappings> {
+        let roots = btree_to_map::<u64>(&mut vec![0], engine.clone(), false, sb.mapping_root)?;
+        let root = roots
+            .get(&thin_id)
+            .ok_or_else(|| anyhow!("couldn't find thin device {}", thin_id))?;
+        DeviceMappings::read(engine, *root)
+    }
+
+    pub fn nr_mapped(&self) -> u64 {
+        self.runs.iter().map(|r| r.len).sum()
+    }
+}
+
+//------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffKind {
+    LeftOnly,
+    RightOnly,
+    Differ,
+    Same,
+}
+
+/// A run of thin blocks that compare the same way, the same as a
+/// <left_only>, <right_only>, <different> or <same> element of
+/// thin_delta's output.  left and right are the data blocks each
+/// device maps the run to, if any.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiffRun {
+    pub thin_begin: u64,
+    pub len: u64,
+    pub left: Option<u64>,
+    pub right: Option<u64>,
+}
+
+impl DiffRun {
+    pub fn kind(&self) -> DiffKind {
+        match (self.left, self.right) {
+            (Some(l), Some(r)) if l == r => DiffKind::Same,
+            (Some(_), Some(_)) => DiffKind::Differ,
+            (Some(_), None) => DiffKind::LeftOnly,
+            _ => DiffKind::RightOnly,
+        }
+    }
+}
+
+// The runs of one device, with the first partly consumed.
+struct RunStream<'a> {
+    runs: std::slice::Iter<'a, Run>,
+    current: Option<Run>,
+}
+
+impl<'a> RunStream<'a> {
+    fn new(m: &'a DeviceMappings) -> RunStream<'a> {
+        let mut runs = m.runs.iter();
+        let current = runs.next().copied();
+        RunStream { runs, current }
+    }
+
+    fn consume(&mut self, delta: u64) {
+        if let Some(r) = self.current.as_mut() {
+            if delta < r.len {
+                r.thin_begin += delta;
+                r.data_begin += delta;
+                r.len -= delta;
+            } else {
+                self.current = self.runs.next().copied();
+            }
+        }
+    }
+}
+
+struct Diff<'a> {
+    left: RunStream<'a>,
+    right: RunStream<'a>,
+}
+
+impl<'a> Iterator for Diff<'a> {
+    type Item = DiffRun;
+
+    fn next(&mut self) -> Option<DiffRun> {
+        let (run, left_len, right_len) = match (self.left.current, self.right.current) {
+            (None, None) => return None,
+            (Some(l), None) => (mk_run(&l, l.len, Some(l.data_begin), None), l.len, 0),
+            (None, Some(r)) => (mk_run(&r, r.len, None, Some(r.data_begin)), 0, r.len),
+            (Some(l), Some(r)) => {
+                if l.thin_begin < r.thin_begin {
+                    let delta = u64::min(l.len, r.thin_begin - l.thin_begin);
+                    (mk_run(&l, delta, Some(l.data_begin), None), delta, 0)
+                } else if l.thin_begin > r.thin_begin {
+                    let delta = u64::min(r.len, l.thin_begin - r.thin_begin);
+                    (mk_run(&r, delta, None, Some(r.data_begin)), 0, delta)
+                } else {
+                    let delta = u64::min(l.len, r.len);
+                    let run = mk_run(&l, delta, Some(l.data_begin), Some(r.data_begin));
+                    (run, delta, delta)
+                }
+            }
+        };
+
+        self.left.consume(left_len);
+        self.right.consume(right_len);
+        Some(run)
+    }
+}
+
+fn mk_run(r: &Run, len: u64, left: Option<u64>, right: Option<u64>) -> DiffRun {
+    DiffRun {
+        thin_begin: r.thin_begin,
+        len,
+        left,
+        right,
+    }
+}
+
+/// Compares the mappings of two devices, giving the runs in order of
+/// thin block.  Blocks neither device maps are skipped.
+pub fn diff<'a>(
+    dev_a: &'a DeviceMappings,
+    dev_b: &'a DeviceMappings,
+) -> impl Iterator<Item = DiffRun> + 'a {
+    Diff {
+        left: RunStream::new(dev_a),
+        right: RunStream::new(dev_b),
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    use crate::report::*;
+    use crate::thin::metadata_repair::SuperblockOverrides;
+    use crate::thin::restore::{restore, ThinRestoreOptions};
+
+    fn mk_mappings(runs: &[(u64, u64, u64)]) -> DeviceMappings {
+        DeviceMappings {
+            runs: runs
+                .iter()
+                .map(|(thin_begin, data_begin, len)| Run {
+                    thin_begin: *thin_begin,
+                    data_begin: *data_begin,
+                    len: *len,
+                })
+                .collect(),
+        }
+    }
+
+    fn run(thin_begin: u64, len: u64, left: Option<u64>, right: Option<u64>) -> DiffRun {
+        DiffRun {
+            thin_begin,
+            len,
+            left,
+            right,
+        }
+    }
+
+    #[test]
+    fn test_diff_runs() {
+        let left = mk_mappings(&[(0, 100, 10), (20, 200, 5), (40, 400, 4)]);
+        let right = mk_mappings(&[(5, 105, 10), (20, 300, 5), (50, 500, 2)]);
+
+        let runs: Vec<DiffRun> = diff(&left, &right).collect();
+        assert_eq!(
+            runs,
+            vec![
+                run(0, 5, Some(100), None),
+                run(5, 5, Some(105), Some(105)),
+                run(10, 5, None, Some(110)),
+                run(20, 5, Some(200), Some(300)),
+                run(40, 4, Some(400), None),
+                run(50, 2, None, Some(500)),
+            ]
+        );
+
+        let kinds: Vec<DiffKind> = runs.iter().map(|r| r.kind()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                DiffKind::LeftOnly,
+                DiffKind::Same,
+                DiffKind::RightOnly,
+                DiffKind::Differ,
+                DiffKind::LeftOnly,
+                DiffKind::RightOnly,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_empty() {
+        let empty = DeviceMappings::default();
+        let dev = mk_mappings(&[(3, 7, 2)]);
+        assert_eq!(diff(&empty, &empty).count(), 0);
+        assert_eq!(
+            diff(&empty, &dev).collect::<Vec<DiffRun>>(),
+            vec![run(3, 2, None, Some(7))]
+        );
+    }
+
+    const XML: &str = r#"<superblock uuid="" time="1" transaction="1" data_block_size="128" nr_data_blocks="100">
+  <device dev_id="1" mapped_blocks="6" transaction="0" creation_time="0" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="4" time="0"/>
+    <single_mapping origin_block="8" data_block="10" time="0"/>
+    <single_mapping origin_block="9" data_block="11" time="0"/>
+  </device>
+  <device dev_id="2" mapped_blocks="5" transaction="0" creation_time="1" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="2" time="0"/>
+    <range_mapping origin_begin="2" data_begin="20" length="2" time="1"/>
+    <single_mapping origin_block="9" data_block="11" time="0"/>
+  </device>
+</superblock>
+"#;
+
+    #[test]
+    fn test_diff_devices() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let xml = dir.path().join("md.xml");
+        let md = dir.path().join("md.bin");
+        File::create(&xml)?.write_all(XML.as_bytes())?;
+        File::create(&md)?.set_len(4 << 20)?;
+        restore(ThinRestoreOptions {
+            input: &xml,
+            output: &md,
+            async_io: false,
+            nr_io_threads: 1,
+            report: Arc::new(mk_quiet_report()),
+            verify: false,
+            backup_superblock: false,
+            overrides: SuperblockOverrides {
+                transaction_id: None,
+                data_block_size: None,
+                nr_data_blocks: None,
+            },
+        })?;
+
+        let engine: Arc<dyn IoEngine + Send + Sync> = Arc::new(SyncIoEngine::new(&md, 1, false)?);
+        let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+        let origin = DeviceMappings::read_device(engine.clone(), &sb, 1)?;
+        let snap = DeviceMappings::read_device(engine.clone(), &sb, 2)?;
+        assert_eq!(origin.nr_mapped(), 6);
+        assert!(DeviceMappings::read_device(engine, &sb, 3).is_err());
+
+        let runs: Vec<DiffRun> = diff(&origin, &snap).collect();
+        assert_eq!(
+            runs,
+            vec![
+                run(0, 2, Some(0), Some(0)),
+                run(2, 2, Some(2), Some(20)),
+                run(8, 1, Some(10), None),
+                run(9, 1, Some(11), Some(11)),
+            ]
+        );
+        Ok(())
+    }
+}
+
+//------------------------------------------
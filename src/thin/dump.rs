@@ -1,19 +1,29 @@
 use anyhow::{anyhow, Result};
+use fixedbitset::FixedBitSet;
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::File;
 use std::io::BufWriter;
 use std::io::Write;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, SyncSender};
 use std::sync::{Arc, Mutex};
+use threadpool::ThreadPool;
+use tracing::{instrument, Span};
 
 use crate::checksum;
 use crate::io_engine::{AsyncIoEngine, Block, IoEngine, SyncIoEngine};
 use crate::pdata::btree::{self, *};
 use crate::pdata::btree_walker::*;
 use crate::pdata::space_map_common::*;
+use crate::pdata::space_map_disk::read_shared_blocks;
 use crate::pdata::unpack::*;
 use crate::report::*;
 use crate::thin::block_time::*;
-use crate::thin::ir::{self, MetadataVisitor};
+use crate::thin::human;
+use crate::thin::index::*;
+use crate::thin::ir::{self, MetadataVisitor, Visit};
+use crate::thin::labels::*;
 use crate::thin::metadata::*;
 use crate::thin::metadata_repair::*;
 use crate::thin::superblock::*;
@@ -40,6 +50,7 @@ impl RunBuilder {
                     data_begin: data_block,
                     time,
                     len: 1,
+                    shared: None,
                 });
                 None
             }
@@ -48,6 +59,7 @@ impl RunBuilder {
                 data_begin,
                 time: mtime,
                 len,
+                ..
             }) => {
                 if thin_block == (thin_begin + len)
                     && data_block == (data_begin + len)
@@ -61,6 +73,7 @@ impl RunBuilder {
                         data_begin: data_block,
                         time,
                         len: 1,
+                        shared: None,
                     })
                 }
             }
@@ -141,14 +154,112 @@ impl<'a> NodeVisitor<BlockTime> for MappingVisitor<'a> {
 
 //------------------------------------------
 
+// Splits each run wherever its data blocks go from shared to exclusive,
+// or back, marking every piece.
+struct SharedAnnotator<'a> {
+    out: &'a mut dyn MetadataVisitor,
+    shared: FixedBitSet,
+}
+
+impl<'a> MetadataVisitor for SharedAnnotator<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.out.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.out.superblock_e()
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.out.def_shared_b(name)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        self.out.def_shared_e()
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        self.out.device_b(d)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        self.out.device_e()
+    }
+
+    fn device_labels(&mut self, labels: &ir::DeviceLabels) -> Result<Visit> {
+        self.out.device_labels(labels)
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        let shared_blocks = &self.shared;
+        let is_shared = |b: u64| shared_blocks.contains(b as usize);
+
+        let mut begin = 0;
+        while begin < m.len {
+            let shared = is_shared(m.data_begin + begin);
+            let mut end = begin + 1;
+            while end < m.len && is_shared(m.data_begin + end) == shared {
+                end += 1;
+            }
+
+            self.out.map(&ir::Map {
+                thin_begin: m.thin_begin + begin,
+                data_begin: m.data_begin + begin,
+                time: m.time,
+                len: end - begin,
+                shared: Some(shared),
+            })?;
+            begin = end;
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        self.out.ref_shared(name)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.out.eof()
+    }
+}
+
+//------------------------------------------
+
 const MAX_CONCURRENT_IO: u32 = 1024;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Xml,
+
+    // A summary table of the pool and its devices.
+    Human,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "xml" => Ok(OutputFormat::Xml),
+            "human" => Ok(OutputFormat::Human),
+            _ => Err(anyhow!("unknown output format '{}'", s)),
+        }
+    }
+}
+
 pub struct ThinDumpOptions<'a> {
     pub input: &'a Path,
     pub output: Option<&'a Path>,
+    pub index: Option<&'a Path>,
     pub async_io: bool,
+    pub nr_io_threads: usize,
     pub report: Arc<Report>,
     pub repair: bool,
+    pub canonical: bool,
+    pub skip_mappings: bool,
+    pub annotate_shared: bool,
+    pub min_range: u64,
+    pub format: OutputFormat,
     pub overrides: SuperblockOverrides,
 }
 
@@ -163,8 +274,7 @@ fn mk_context(opts: &ThinDumpOptions) -> Result<Context> {
     if opts.async_io {
         engine = Arc::new(AsyncIoEngine::new(opts.input, MAX_CONCURRENT_IO, false)?);
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
-        engine = Arc::new(SyncIoEngine::new(opts.input, nr_threads, false)?);
+        engine = Arc::new(SyncIoEngine::new(opts.input, opts.nr_io_threads, false)?);
     }
 
     Ok(Context {
@@ -273,67 +383,333 @@ fn override_<'a, T>(opt: &'a Option<T>, default: &'a T) -> &'a T {
     }
 }
 
+//------------------------------------------
+
+// Devices are walked concurrently, but have to be emitted in order.  Each
+// walk streams its output through its own bounded channel, and no more
+// than one walk per thread is started ahead of the device being emitted,
+// so memory use stays bounded however many devices there are.
+
+const ITEMS_PER_BATCH: usize = 4096;
+const BATCHES_IN_FLIGHT: usize = 4;
+
+enum Item {
+    Map(ir::Map),
+    Ref(String),
+}
+
+type Batch = Result<Vec<Item>>;
+
+// Collects the output of emit_entries() into batches for the emitting
+// thread.  Only mappings and refs are ever sent.
+struct ItemSender {
+    tx: SyncSender<Batch>,
+    batch: Vec<Item>,
+}
+
+impl ItemSender {
+    fn new(tx: SyncSender<Batch>) -> ItemSender {
+        ItemSender {
+            tx,
+            batch: Vec::with_capacity(ITEMS_PER_BATCH),
+        }
+    }
+
+    fn push(&mut self, item: Item) -> Result<Visit> {
+        self.batch.push(item);
+        if self.batch.len() >= ITEMS_PER_BATCH {
+            self.flush()?;
+        }
+        Ok(Visit::Continue)
+    }
+
+    // Fails if the emitting thread has given up.
+    fn flush(&mut self) -> Result<()> {
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(ITEMS_PER_BATCH));
+        self.tx
+            .send(Ok(batch))
+            .map_err(|_| anyhow!("dump abandoned"))
+    }
+}
+
+impl MetadataVisitor for ItemSender {
+    fn superblock_b(&mut self, _sb: &ir::Superblock) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, _name: &str) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn device_b(&mut self, _d: &ir::Device) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn device_labels(&mut self, _labels: &ir::DeviceLabels) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        self.push(Item::Map(m.clone()))
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        self.push(Item::Ref(name.to_string()))
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+}
+
+fn walk_entries(engine: Arc<dyn IoEngine + Send + Sync>, entries: &[Entry], tx: SyncSender<Batch>) {
+    let mut sender = ItemSender::new(tx.clone());
+    let r = emit_entries(engine, &mut sender, entries).and_then(|_| sender.flush());
+    if let Err(e) = r {
+        // Nothing to do if the receiver has gone.
+        let _ = tx.send(Err(e));
+    }
+}
+
+enum Section<'a> {
+    Def(&'a Def),
+    Dev(&'a Device),
+}
+
+impl<'a> Section<'a> {
+    fn entries(&self) -> &'a [Entry] {
+        match self {
+            Section::Def(d) => &d.map.entries,
+            Section::Dev(dev) => &dev.map.entries,
+        }
+    }
+
+    fn begin(&self, out: &mut dyn MetadataVisitor, labels: &LabelMap) -> Result<Visit> {
+        match self {
+            Section::Def(d) => out.def_shared_b(&format!("{}", d.def_id)),
+            Section::Dev(dev) => {
+                out.device_b(&ir::Device {
+                    dev_id: dev.thin_id,
+                    mapped_blocks: dev.detail.mapped_blocks,
+                    transaction: dev.detail.transaction_id,
+                    creation_time: dev.detail.creation_time,
+                    snap_time: dev.detail.snapshotted_time,
+                })?;
+                match labels.get(&dev.thin_id) {
+                    Some(l) => out.device_labels(l),
+                    None => Ok(Visit::Continue),
+                }
+            }
+        }
+    }
+
+    fn end(&self, out: &mut dyn MetadataVisitor) -> Result<Visit> {
+        match self {
+            Section::Def(_) => out.def_shared_e(),
+            Section::Dev(_) => out.device_e(),
+        }
+    }
+}
+
+fn emit_sections(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    out: &mut dyn MetadataVisitor,
+    sections: Vec<Section>,
+    labels: &LabelMap,
+) -> Result<()> {
+    let nr_threads = std::cmp::max(1, std::cmp::min(num_cpus::get(), sections.len()));
+    let pool = ThreadPool::new(nr_threads);
+
+    let mut pending = VecDeque::new();
+    let mut sections = sections.into_iter();
+    loop {
+        // Every pending walk has a thread to itself, so the one being
+        // emitted is never stuck behind walks that are waiting on us.
+        while pending.len() < nr_threads {
+            match sections.next() {
+                Some(section) => {
+                    let (tx, rx) = sync_channel(BATCHES_IN_FLIGHT);
+                    let engine = engine.clone();
+                    let entries = section.entries().to_vec();
+                    pool.execute(move || walk_entries(engine, &entries, tx));
+                    pending.push_back((section, rx));
+                }
+                None => break,
+            }
+        }
+
+        let (section, rx) = match pending.pop_front() {
+            Some(p) => p,
+            None => break,
+        };
+
+        section.begin(out, labels)?;
+        for batch in rx {
+            for item in batch? {
+                match item {
+                    Item::Map(m) => out.map(&m)?,
+                    Item::Ref(name) => out.ref_shared(&name)?,
+                };
+            }
+        }
+        section.end(out)?;
+    }
+
+    Ok(())
+}
+
+//------------------------------------------
+
 pub fn dump_metadata(
-    engine: Arc<dyn IoEngine>,
+    engine: Arc<dyn IoEngine + Send + Sync>,
     out: &mut dyn MetadataVisitor,
     sb: &Superblock,
     md: &Metadata,
     overrides: &SuperblockOverrides,
 ) -> Result<()> {
     let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+
+    // Labels are a nicety, so damaged ones are dropped rather than
+    // stopping the dump.
+    let labels = read_labels(engine.as_ref(), sb.labels_root)
+        .map(|area| area.labels)
+        .unwrap_or_default();
+
     let out_sb = ir::Superblock {
-        uuid: "".to_string(),
+        uuid: uuid_to_string(&sb.uuid),
         time: sb.time,
         transaction: *override_(&overrides.transaction_id, &sb.transaction_id),
         flags: if sb.flags.needs_check { Some(1) } else { None },
-        version: Some(2),
+        version: Some(if labels.is_empty() {
+            2
+        } else {
+            xml::LABELS_XML_VERSION
+        }),
         data_block_size: *override_(&overrides.data_block_size, &sb.data_block_size),
         nr_data_blocks: *override_(&overrides.nr_data_blocks, &data_root.nr_blocks),
         metadata_snap: None,
     };
     out.superblock_b(&out_sb)?;
 
-    for d in &md.defs {
-        out.def_shared_b(&format!("{}", d.def_id))?;
-        emit_entries(engine.clone(), out, &d.map.entries)?;
-        out.def_shared_e()?;
-    }
+    // Defs and devices are always emitted in id order, whatever order
+    // they were gathered in, so identical metadata gives identical dumps.
+    let mut defs: Vec<&Def> = md.defs.iter().collect();
+    defs.sort_by_key(|d| d.def_id);
+    let mut devs: Vec<&Device> = md.devs.iter().collect();
+    devs.sort_by_key(|d| d.thin_id);
+
+    let mut sections: Vec<Section> = defs.into_iter().map(Section::Def).collect();
+    sections.extend(devs.into_iter().map(Section::Dev));
+    emit_sections(engine, out, sections, &labels)?;
 
-    for dev in &md.devs {
-        let device = ir::Device {
-            dev_id: dev.thin_id,
-            mapped_blocks: dev.detail.mapped_blocks,
-            transaction: dev.detail.transaction_id,
-            creation_time: dev.detail.creation_time,
-            snap_time: dev.detail.snapshotted_time,
-        };
-        out.device_b(&device)?;
-        emit_entries(engine.clone(), out, &dev.map.entries)?;
-        out.device_e()?;
-    }
     out.superblock_e()?;
     out.eof()?;
 
     Ok(())
 }
 
+// Shared defs are named after the metadata blocks they live in, so
+// two pools with the same mappings can still dump differently.  A
+// canonical dump expands them, leaving only the logical mappings.
+fn build_dump_metadata(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+    canonical: bool,
+) -> Result<Metadata> {
+    if canonical {
+        build_unshared_metadata(engine, sb)
+    } else {
+        optimise_metadata(build_metadata(engine, sb)?)
+    }
+}
+
 //------------------------------------------
 
+fn read_device_roots(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+) -> Result<BTreeMap<u32, u64>> {
+    let mut path = vec![0];
+    let roots = btree_to_map::<u64>(&mut path, engine, true, sb.mapping_root)?;
+    Ok(roots
+        .into_iter()
+        .map(|(thin_id, root)| (thin_id as u32, root))
+        .collect())
+}
+
+// Reuses the index if it's up to date, otherwise walks the metadata,
+// writing a fresh index if one was asked for.  Exclusive counts left
+// in the index by thin_ls are kept if the metadata hasn't changed.
+fn read_dump_metadata(ctx: &Context, opts: &ThinDumpOptions, sb: &Superblock) -> Result<Metadata> {
+    let path = match opts.index {
+        Some(path) => path,
+        None => return build_dump_metadata(ctx.engine.clone(), sb, opts.canonical),
+    };
+
+    let exclusives = match read_current_index(path, sb) {
+        Some(index) if index.layout && index.canonical == opts.canonical => {
+            ctx.report.verbose("using the metadata index");
+            return Ok(index.metadata);
+        }
+        Some(index) => index.exclusives,
+        None => None,
+    };
+
+    let md = build_dump_metadata(ctx.engine.clone(), sb, opts.canonical)?;
+    let index = MetadataIndex {
+        generation: Generation::new(sb),
+        canonical: opts.canonical,
+        layout: true,
+        roots: read_device_roots(ctx.engine.clone(), sb)?,
+        exclusives,
+        metadata: md,
+    };
+
+    // The dump doesn't depend on the index, so failing to write it
+    // isn't fatal.
+    if let Err(e) = write_index(path, &index) {
+        ctx.report
+            .info(&format!("couldn't write index {}: {}", path.display(), e));
+    }
+    Ok(index.metadata)
+}
+
+#[instrument(skip_all, fields(nr_metadata_blocks, nr_devices))]
 pub fn dump(opts: ThinDumpOptions) -> Result<()> {
     let ctx = mk_context(&opts)?;
-    let sb;
-    if opts.repair {
-        sb = read_or_rebuild_superblock(
+    Span::current().record("nr_metadata_blocks", ctx.engine.get_nr_blocks());
+    let sb = if opts.repair {
+        read_or_rebuild_superblock(
             ctx.engine.clone(),
             ctx.report.clone(),
             SUPERBLOCK_LOCATION,
             &opts.overrides,
-        )?;
+        )?
     } else {
-        sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
-    }
-    let md = build_metadata(ctx.engine.clone(), &sb)?;
-    let md = optimise_metadata(md)?;
+        read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?
+    };
+    check_features(&sb, &ctx.report, false)?;
+
+    // The inventory is cheap enough to never be worth an index.
+    let md = if opts.skip_mappings {
+        build_inventory(ctx.engine.clone(), &sb)?
+    } else {
+        read_dump_metadata(&ctx, &opts, &sb)?
+    };
+    report_synthesised_details(&sb, &md, &ctx.report);
+    Span::current().record("nr_devices", md.devs.len());
 
     let writer: Box<dyn Write>;
     if opts.output.is_some() {
@@ -341,9 +717,21 @@ pub fn dump(opts: ThinDumpOptions) -> Result<()> {
     } else {
         writer = Box::new(BufWriter::new(std::io::stdout()));
     }
-    let mut out = xml::XmlWriter::new(writer);
+    let mut out: Box<dyn MetadataVisitor> = match opts.format {
+        OutputFormat::Xml => Box::new(xml::XmlWriter::with_min_range(writer, opts.min_range)),
+        OutputFormat::Human => Box::new(human::HumanWriter::new(writer)),
+    };
 
-    dump_metadata(ctx.engine, &mut out, &sb, &md, &opts.overrides)
+    if opts.annotate_shared {
+        let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+        let mut annotator = SharedAnnotator {
+            out: out.as_mut(),
+            shared: read_shared_blocks(ctx.engine.clone(), &data_root)?,
+        };
+        dump_metadata(ctx.engine, &mut annotator, &sb, &md, &opts.overrides)
+    } else {
+        dump_metadata(ctx.engine, out.as_mut(), &sb, &md, &opts.overrides)
+    }
 }
 
 //------------------------------------------
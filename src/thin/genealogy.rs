@@ -0,0 +1,329 @@
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::io_engine::*;
+use crate::pdata::btree::{self, *};
+use crate::pdata::btree_walker::*;
+use crate::thin::block_time::*;
+use crate::thin::device_detail::*;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+const MAX_CONCURRENT_IO: u32 = 1024;
+
+//------------------------------------------
+
+pub struct ThinGenealogyOptions<'a> {
+    pub input: &'a Path,
+    pub async_io: bool,
+    pub nr_io_threads: usize,
+    pub use_metadata_snap: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceNode {
+    pub dev_id: u64,
+    pub creation_time: u32,
+    pub snap_time: u32,
+
+    /// The number of btree nodes in the device's mapping tree.
+    pub nr_nodes: u64,
+}
+
+/// A device, the child, that was probably created as a snapshot of
+/// another, the parent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnapshotEdge {
+    pub parent: u64,
+    pub child: u64,
+
+    /// When the snapshot was taken, the creation time of the child.
+    pub time: u32,
+
+    /// How many mapping tree nodes the two devices still share.  A
+    /// snapshot starts out sharing its whole tree with its origin, and
+    /// loses a little of it with every write to either device.
+    pub shared_nodes: u64,
+}
+
+/// The probable snapshot tree of a pool.  The metadata doesn't record
+/// which device a snapshot was taken of, so it's reconstructed from the
+/// mapping trees the devices share, and their creation and snapshot
+/// times.  A snapshot that no longer shares any of its tree, because it
+/// or its origin has been entirely overwritten, appears as a root.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Genealogy {
+    pub devices: BTreeMap<u64, DeviceNode>,
+
+    /// Ordered by child.
+    pub edges: Vec<SnapshotEdge>,
+}
+
+impl Genealogy {
+    pub fn parent(&self, dev_id: u64) -> Option<&SnapshotEdge> {
+        self.edges.iter().find(|e| e.child == dev_id)
+    }
+
+    pub fn children(&self, dev_id: u64) -> impl Iterator<Item = &SnapshotEdge> {
+        self.edges.iter().filter(move |e| e.parent == dev_id)
+    }
+
+    /// The devices that weren't snapshots of another, in order of id.
+    pub fn roots(&self) -> Vec<u64> {
+        self.devices
+            .keys()
+            .filter(|dev_id| self.parent(**dev_id).is_none())
+            .copied()
+            .collect()
+    }
+}
+
+//------------------------------------------
+
+// Walks a mapping tree a level at a time, like stat::tree_stats.
+fn tree_nodes(engine: &dyn IoEngine, root: u64) -> Result<Vec<u64>> {
+    let mut nodes = Vec::new();
+    let mut level = vec![root];
+    let mut is_root = true;
+
+    while !level.is_empty() {
+        let mut next = Vec::new();
+        for chunk in level.chunks(engine.get_batch_size()) {
+            let blocks = engine.read_many(chunk)?;
+            for (loc, b) in chunk.iter().zip(blocks) {
+                let b = b.map_err(|_| anyhow!("unable to read btree node {}", loc))?;
+                if let btree::Node::Internal { values, .. } =
+                    unpack_node::<BlockTime>(&[0], b.get_data(), false, is_root)?
+                {
+                    next.extend(values);
+                }
+            }
+        }
+        nodes.append(&mut level);
+        level = next;
+        is_root = false;
+    }
+
+    Ok(nodes)
+}
+
+/// Reconstructs the snapshot tree from the metadata of the given
+/// superblock.
+///
+/// Devices are considered in order of creation.  The parent of each is
+/// the earlier device it shares the most of its mapping tree with,
+/// among those snapshotted no earlier than it was created.  A device
+/// whose last snapshot was taken exactly then is preferred, and ties
+/// go to the most recently created, which is the nearer ancestor.
+pub fn build_genealogy(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+) -> Result<Genealogy> {
+    let details =
+        btree_to_map::<DeviceDetail>(&mut vec![0], engine.clone(), false, sb.details_root)?;
+    let roots = btree_to_map::<u64>(&mut vec![0], engine.clone(), false, sb.mapping_root)?;
+
+    let mut order: Vec<(u64, DeviceDetail)> = details.into_iter().collect();
+    order.sort_by_key(|(dev_id, detail)| (detail.creation_time, *dev_id));
+
+    let mut genealogy = Genealogy::default();
+
+    // The devices, as indexes into order, whose trees contain each node.
+    let mut owners: HashMap<u64, Vec<usize>> = HashMap::new();
+
+    for (i, (dev_id, detail)) in order.iter().enumerate() {
+        let root = roots
+            .get(dev_id)
+            .ok_or_else(|| anyhow!("device {} has no mapping tree", dev_id))?;
+        let nodes = tree_nodes(engine.as_ref(), *root)?;
+
+        let mut shared: BTreeMap<usize, u64> = BTreeMap::new();
+        for n in &nodes {
+            let devs = owners.entry(*n).or_default();
+            for j in devs.iter() {
+                *shared.entry(*j).or_default() += 1;
+            }
+            devs.push(i);
+        }
+
+        let parent = shared
+            .into_iter()
+            .filter(|(j, _)| order[*j].1.snapshotted_time >= detail.creation_time)
+            .max_by_key(|(j, nr_shared)| {
+                let exact = order[*j].1.snapshotted_time == detail.creation_time;
+                (exact, *nr_shared, *j)
+            });
+        if let Some((j, shared_nodes)) = parent {
+            genealogy.edges.push(SnapshotEdge {
+                parent: order[j].0,
+                child: *dev_id,
+                time: detail.creation_time,
+                shared_nodes,
+            });
+        }
+
+        genealogy.devices.insert(
+            *dev_id,
+            DeviceNode {
+                dev_id: *dev_id,
+                creation_time: detail.creation_time,
+                snap_time: detail.snapshotted_time,
+                nr_nodes: nodes.len() as u64,
+            },
+        );
+    }
+
+    genealogy.edges.sort_by_key(|e| e.child);
+    Ok(genealogy)
+}
+
+pub fn genealogy(opts: ThinGenealogyOptions) -> Result<Genealogy> {
+    let engine: Arc<dyn IoEngine + Send + Sync> = if opts.async_io {
+        Arc::new(AsyncIoEngine::new(opts.input, MAX_CONCURRENT_IO, false)?)
+    } else {
+        Arc::new(SyncIoEngine::new(opts.input, opts.nr_io_threads, false)?)
+    };
+
+    let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    if opts.use_metadata_snap {
+        if sb.metadata_snap == 0 {
+            return Err(anyhow!("no metadata snapshot is held"));
+        }
+        sb = read_superblock(engine.as_ref(), sb.metadata_snap)?;
+    }
+
+    build_genealogy(engine, &sb)
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    use crate::report::*;
+    use crate::thin::metadata_repair::SuperblockOverrides;
+    use crate::thin::restore::{restore, ThinRestoreOptions};
+
+    fn mappings(f: &mut File, begin: u64, len: u64, data_begin: u64) -> Result<()> {
+        writeln!(
+            f,
+            r#"<range_mapping origin_begin="{}" data_begin="{}" length="{}" time="0"/>"#,
+            begin, data_begin, len
+        )?;
+        Ok(())
+    }
+
+    // Writes a device whose mappings are the shared leaves, followed by
+    // any of its own.
+    fn device(f: &mut File, dev_id: u64, times: (u32, u32), own: Option<u64>) -> Result<()> {
+        writeln!(
+            f,
+            r#"<device dev_id="{}" mapped_blocks="0" transaction="0" creation_time="{}" snap_time="{}">"#,
+            dev_id, times.0, times.1
+        )?;
+        writeln!(f, r#"<ref name="base"/>"#)?;
+        if let Some(data_begin) = own {
+            mappings(f, 10000, 1000, data_begin)?;
+        }
+        writeln!(f, "</device>")?;
+        Ok(())
+    }
+
+    // Device 1 is the origin, snapshotted as 2 at time 1 and as 3 at
+    // time 2.  Device 4 is a snapshot of 2, taken at time 3.  Device 5
+    // is a fresh thin device created alongside 3.
+    fn mk_metadata(dir: &Path) -> Result<std::path::PathBuf> {
+        let xml = dir.join("md.xml");
+        let md = dir.join("md.bin");
+        let mut f = File::create(&xml)?;
+        writeln!(
+            f,
+            r#"<superblock uuid="" time="3" transaction="1" data_block_size="128" nr_data_blocks="20000">"#
+        )?;
+        writeln!(f, r#"<def name="base">"#)?;
+        mappings(&mut f, 0, 1000, 0)?;
+        writeln!(f, "</def>")?;
+
+        device(&mut f, 1, (0, 2), Some(1000))?;
+        device(&mut f, 2, (1, 3), Some(2000))?;
+        device(&mut f, 3, (2, 2), Some(3000))?;
+        device(&mut f, 4, (3, 3), None)?;
+        writeln!(
+            f,
+            r#"<device dev_id="5" mapped_blocks="0" transaction="0" creation_time="2" snap_time="2">"#
+        )?;
+        mappings(&mut f, 0, 1000, 5000)?;
+        writeln!(f, "</device>\n</superblock>")?;
+        drop(f);
+
+        File::create(&md)?.set_len(4 << 20)?;
+        restore(ThinRestoreOptions {
+            input: &xml,
+            output: &md,
+            async_io: false,
+            nr_io_threads: 1,
+            report: Arc::new(mk_quiet_report()),
+            verify: false,
+            backup_superblock: false,
+            overrides: SuperblockOverrides {
+                transaction_id: None,
+                data_block_size: None,
+                nr_data_blocks: None,
+            },
+        })?;
+        Ok(md)
+    }
+
+    #[test]
+    fn test_genealogy() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let md = mk_metadata(dir.path())?;
+        let g = genealogy(ThinGenealogyOptions {
+            input: &md,
+            async_io: false,
+            nr_io_threads: 1,
+            use_metadata_snap: false,
+        })?;
+
+        let edges: Vec<(u64, u64, u32)> = g
+            .edges
+            .iter()
+            .map(|e| (e.parent, e.child, e.time))
+            .collect();
+        assert_eq!(edges, vec![(1, 2, 1), (1, 3, 2), (2, 4, 3)]);
+        assert_eq!(g.roots(), vec![1, 5]);
+
+        let children: Vec<u64> = g.children(1).map(|e| e.child).collect();
+        assert_eq!(children, vec![2, 3]);
+        assert!(g.parent(5).is_none());
+
+        for e in &g.edges {
+            assert!(e.shared_nodes > 0);
+            assert!(e.shared_nodes < g.devices[&e.child].nr_nodes);
+        }
+        assert_eq!(g.devices[&3].snap_time, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_genealogy_needs_metadata_snap() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let md = mk_metadata(dir.path())?;
+        assert!(genealogy(ThinGenealogyOptions {
+            input: &md,
+            async_io: false,
+            nr_io_threads: 1,
+            use_metadata_snap: true,
+        })
+        .is_err());
+        Ok(())
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,287 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::thin::ir::*;
+
+//---------------------------------------
+
+// The data blocks mapped by a device, or a shared subtree, as
+// (data_begin, len) runs.
+type Runs = Vec<(u64, u64)>;
+
+struct DeviceSummary {
+    dev: Device,
+    labels: Option<DeviceLabels>,
+    runs: Runs,
+}
+
+/// Summarises the metadata as a table, rather than dumping every
+/// mapping, for a quick look at a pool.  Nothing is written until the
+/// end, since which blocks are shared isn't known until every device
+/// has been seen.
+pub struct HumanWriter<W: Write> {
+    w: W,
+    sb: Option<Superblock>,
+    shared: BTreeMap<String, Runs>,
+    devs: Vec<DeviceSummary>,
+
+    // The runs being collected, for either a device or a shared subtree.
+    current: Option<Runs>,
+    current_def: Option<String>,
+}
+
+impl<W: Write> HumanWriter<W> {
+    pub fn new(w: W) -> HumanWriter<W> {
+        HumanWriter {
+            w,
+            sb: None,
+            shared: BTreeMap::new(),
+            devs: Vec::new(),
+            current: None,
+            current_def: None,
+        }
+    }
+}
+
+//---------------------------------------
+
+fn fmt_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
+
+fn percent(n: u64, total: u64) -> String {
+    if total == 0 {
+        return "-".to_string();
+    }
+    format!("{:.1}%", n as f64 * 100.0 / total as f64)
+}
+
+// Sweeps over every run, from every device, returning the number of
+// data blocks in use, and the sorted, disjoint ranges of blocks that
+// are mapped more than once.
+fn find_shared(devs: &[DeviceSummary]) -> (u64, Vec<(u64, u64)>) {
+    let mut edges = Vec::new();
+    for d in devs {
+        for (begin, len) in &d.runs {
+            edges.push((*begin, 1i64));
+            edges.push((*begin + *len, -1i64));
+        }
+    }
+    edges.sort_unstable();
+
+    let mut in_use = 0;
+    let mut shared: Vec<(u64, u64)> = Vec::new();
+    let mut depth = 0;
+    let mut last = 0;
+    for (b, delta) in edges {
+        if depth > 0 {
+            in_use += b - last;
+        }
+        if depth > 1 && b > last {
+            match shared.last_mut() {
+                Some(s) if s.1 == last => s.1 = b,
+                _ => shared.push((last, b)),
+            }
+        }
+        depth += delta;
+        last = b;
+    }
+
+    (in_use, shared)
+}
+
+// The number of blocks in the runs that fall within the shared ranges.
+fn count_shared(runs: &[(u64, u64)], shared: &[(u64, u64)]) -> u64 {
+    let mut n = 0;
+    for (begin, len) in runs {
+        let end = begin + len;
+        let mut i = shared.partition_point(|s| s.1 <= *begin);
+        while i < shared.len() && shared[i].0 < end {
+            let (s_begin, s_end) = shared[i];
+            n += std::cmp::min(s_end, end) - std::cmp::max(s_begin, *begin);
+            i += 1;
+        }
+    }
+    n
+}
+
+impl<W: Write> HumanWriter<W> {
+    fn write_summary(&mut self) -> Result<()> {
+        let sb = self
+            .sb
+            .as_ref()
+            .ok_or_else(|| anyhow!("no superblock to summarise"))?;
+        let block_bytes = sb.data_block_size as u64 * 512;
+        let (in_use, shared) = find_shared(&self.devs);
+
+        let w = &mut self.w;
+        writeln!(w, "Pool")?;
+        writeln!(
+            w,
+            "  data block size:    {} sectors ({})",
+            sb.data_block_size,
+            fmt_size(block_bytes)
+        )?;
+        writeln!(
+            w,
+            "  data blocks:        {} ({})",
+            sb.nr_data_blocks,
+            fmt_size(sb.nr_data_blocks * block_bytes)
+        )?;
+        writeln!(
+            w,
+            "  in use:             {} ({}, {})",
+            in_use,
+            fmt_size(in_use * block_bytes),
+            percent(in_use, sb.nr_data_blocks)
+        )?;
+        writeln!(w, "  transaction:        {}", sb.transaction)?;
+        writeln!(w, "  time:               {}", sb.time)?;
+        match sb.metadata_snap {
+            Some(b) => writeln!(w, "  metadata snapshot:  block {}", b)?,
+            None => writeln!(w, "  metadata snapshot:  none")?,
+        }
+        writeln!(w, "  devices:            {}", self.devs.len())?;
+
+        if self.devs.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(w)?;
+        writeln!(
+            w,
+            "{:>8} {:>12} {:>10} {:>7} {:>7} {:>8} {:>8}",
+            "dev", "mapped", "size", "pool", "shared", "created", "snapped"
+        )?;
+        for d in &self.devs {
+            let mapped: u64 = d.runs.iter().map(|(_, len)| len).sum();
+            writeln!(
+                w,
+                "{:>8} {:>12} {:>10} {:>7} {:>7} {:>8} {:>8}",
+                d.dev.dev_id,
+                mapped,
+                fmt_size(mapped * block_bytes),
+                percent(mapped, sb.nr_data_blocks),
+                percent(count_shared(&d.runs, &shared), mapped),
+                d.dev.creation_time,
+                d.dev.snap_time
+            )?;
+        }
+
+        let labelled: Vec<&DeviceSummary> =
+            self.devs.iter().filter(|d| d.labels.is_some()).collect();
+        if labelled.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(w)?;
+        writeln!(w, "Labels")?;
+        for d in labelled {
+            let labels = d.labels.as_ref().unwrap();
+            writeln!(
+                w,
+                "  device {}: {}",
+                d.dev.dev_id,
+                labels.label.as_deref().unwrap_or("-")
+            )?;
+            if let Some(uuid) = &labels.uuid {
+                writeln!(w, "    uuid = {}", uuid)?;
+            }
+            for (key, value) in &labels.attributes {
+                writeln!(w, "    {} = {}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> MetadataVisitor for HumanWriter<W> {
+    fn superblock_b(&mut self, sb: &Superblock) -> Result<Visit> {
+        self.sb = Some(sb.clone());
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.current = Some(Vec::new());
+        self.current_def = Some(name.to_string());
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        if let (Some(name), Some(runs)) = (self.current_def.take(), self.current.take()) {
+            self.shared.insert(name, runs);
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn device_b(&mut self, d: &Device) -> Result<Visit> {
+        self.current = Some(Vec::new());
+        self.devs.push(DeviceSummary {
+            dev: d.clone(),
+            labels: None,
+            runs: Vec::new(),
+        });
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        if let (Some(d), Some(runs)) = (self.devs.last_mut(), self.current.take()) {
+            d.runs = runs;
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn device_labels(&mut self, labels: &DeviceLabels) -> Result<Visit> {
+        if let Some(d) = self.devs.last_mut() {
+            d.labels = Some(labels.clone());
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, m: &Map) -> Result<Visit> {
+        let runs = self
+            .current
+            .as_mut()
+            .ok_or_else(|| anyhow!("mapping outside of a device"))?;
+        runs.push((m.data_begin, m.len));
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        let def = self
+            .shared
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown shared subtree '{}'", name))?;
+        let runs = self
+            .current
+            .as_mut()
+            .ok_or_else(|| anyhow!("reference outside of a device"))?;
+        runs.extend_from_slice(def);
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.write_summary()?;
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+}
+
+//---------------------------------------
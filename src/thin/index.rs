@@ -0,0 +1,424 @@
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc32c::crc32c;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::pdata::btree::KeyRange;
+use crate::thin::device_detail::DeviceDetail;
+use crate::thin::metadata::*;
+use crate::thin::superblock::Superblock;
+
+//------------------------------------------
+
+// A sidecar index records the result of walking the metadata: the device
+// details and mapping roots, the leaves each device and shared def is
+// made of, and the exclusive block counts.  Reading it back avoids
+// walking the mapping and details trees again, which is most of the cost
+// of a dump or listing on a big pool.  The leaves themselves are still
+// read from the metadata.
+//
+// The index is only used if the superblock it was built from is still
+// current.  The kernel writes a new superblock on every commit, so any
+// change to the mappings gives a new generation.
+//
+// thin_dump and thin_ls share the format (see
+// thin-provisioning/metadata_index.h), each filling in the parts it
+// needs, and keeping the other's if the generation hasn't changed.
+// Everything is little endian:
+//
+//   u64 magic, u32 version, u32 flags
+//   u64 transaction id, u32 time, u64 metadata snap,
+//   u64 mapping root, u64 details root
+//   u64 nr defs, then for each:
+//     u64 def id, entries
+//   u64 nr devs, then for each:
+//     u32 thin id, u64 mapping root, u64 mapped blocks,
+//     u64 transaction id, u32 creation time, u32 snapshotted time,
+//     u64 exclusive blocks, entries
+//   u32 crc32c of everything above
+//
+// where entries are a u64 count followed by that many u64 leaf blocks,
+// with the top bit set for refs to a def.  Without FLAG_LAYOUT there
+// are no defs, and every device has no entries.  Without
+// FLAG_EXCLUSIVES the exclusive blocks are 0.
+
+const INDEX_MAGIC: u64 = 0x7468696e696478; // "thinidx"
+const INDEX_VERSION: u32 = 2;
+
+const FLAG_CANONICAL: u32 = 0x1;
+const FLAG_LAYOUT: u32 = 0x2;
+const FLAG_EXCLUSIVES: u32 = 0x4;
+
+// Refs are stored in the same u64 as leaves, with the top bit set.
+const REF_BIT: u64 = 1 << 63;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Generation {
+    pub transaction_id: u64,
+    pub time: u32,
+    pub metadata_snap: u64,
+    pub mapping_root: u64,
+    pub details_root: u64,
+}
+
+impl Generation {
+    pub fn new(sb: &Superblock) -> Generation {
+        Generation {
+            transaction_id: sb.transaction_id,
+            time: sb.time,
+            metadata_snap: sb.metadata_snap,
+            mapping_root: sb.mapping_root,
+            details_root: sb.details_root,
+        }
+    }
+}
+
+pub struct MetadataIndex {
+    pub generation: Generation,
+
+    /// Whether the layout is of the unshared metadata.
+    pub canonical: bool,
+
+    /// Whether the metadata holds the leaves, or just the device details.
+    pub layout: bool,
+
+    /// The mapping root of each device.
+    pub roots: BTreeMap<u32, u64>,
+
+    /// The exclusive block counts, if thin_ls has worked them out.
+    pub exclusives: Option<BTreeMap<u32, u64>>,
+
+    pub metadata: Metadata,
+}
+
+//------------------------------------------
+
+fn pack_entries<W: Write>(w: &mut W, entries: &[Entry]) -> Result<()> {
+    w.write_u64::<LittleEndian>(entries.len() as u64)?;
+    for e in entries {
+        match e {
+            Entry::Leaf(b) => w.write_u64::<LittleEndian>(*b)?,
+            Entry::Ref(id) => w.write_u64::<LittleEndian>(*id | REF_BIT)?,
+        }
+    }
+    Ok(())
+}
+
+fn unpack_entries<R: Read>(r: &mut R) -> Result<Vec<Entry>> {
+    let nr_entries = r.read_u64::<LittleEndian>()?;
+    let mut entries = Vec::new();
+    for _ in 0..nr_entries {
+        let v = r.read_u64::<LittleEndian>()?;
+        if v & REF_BIT != 0 {
+            entries.push(Entry::Ref(v & !REF_BIT));
+        } else {
+            entries.push(Entry::Leaf(v));
+        }
+    }
+    Ok(entries)
+}
+
+fn pack_index<W: Write>(w: &mut W, index: &MetadataIndex) -> Result<()> {
+    let g = &index.generation;
+    w.write_u64::<LittleEndian>(INDEX_MAGIC)?;
+    w.write_u32::<LittleEndian>(INDEX_VERSION)?;
+    let mut flags = 0;
+    if index.canonical {
+        flags |= FLAG_CANONICAL;
+    }
+    if index.layout {
+        flags |= FLAG_LAYOUT;
+    }
+    if index.exclusives.is_some() {
+        flags |= FLAG_EXCLUSIVES;
+    }
+    w.write_u32::<LittleEndian>(flags)?;
+    w.write_u64::<LittleEndian>(g.transaction_id)?;
+    w.write_u32::<LittleEndian>(g.time)?;
+    w.write_u64::<LittleEndian>(g.metadata_snap)?;
+    w.write_u64::<LittleEndian>(g.mapping_root)?;
+    w.write_u64::<LittleEndian>(g.details_root)?;
+
+    let md = &index.metadata;
+    w.write_u64::<LittleEndian>(md.defs.len() as u64)?;
+    for d in &md.defs {
+        w.write_u64::<LittleEndian>(d.def_id)?;
+        pack_entries(w, &d.map.entries)?;
+    }
+
+    w.write_u64::<LittleEndian>(md.devs.len() as u64)?;
+    for dev in &md.devs {
+        let root = index
+            .roots
+            .get(&dev.thin_id)
+            .ok_or_else(|| anyhow!("no mapping root for device {}", dev.thin_id))?;
+        let exclusive = index
+            .exclusives
+            .as_ref()
+            .and_then(|es| es.get(&dev.thin_id))
+            .unwrap_or(&0);
+
+        w.write_u32::<LittleEndian>(dev.thin_id)?;
+        w.write_u64::<LittleEndian>(*root)?;
+        w.write_u64::<LittleEndian>(dev.detail.mapped_blocks)?;
+        w.write_u64::<LittleEndian>(dev.detail.transaction_id)?;
+        w.write_u32::<LittleEndian>(dev.detail.creation_time)?;
+        w.write_u32::<LittleEndian>(dev.detail.snapshotted_time)?;
+        w.write_u64::<LittleEndian>(*exclusive)?;
+        pack_entries(w, &dev.map.entries)?;
+    }
+
+    Ok(())
+}
+
+fn unpack_index<R: Read>(r: &mut R) -> Result<MetadataIndex> {
+    if r.read_u64::<LittleEndian>()? != INDEX_MAGIC {
+        return Err(anyhow!("not a metadata index"));
+    }
+
+    let version = r.read_u32::<LittleEndian>()?;
+    if version != INDEX_VERSION {
+        return Err(anyhow!("unsupported index version {}", version));
+    }
+
+    let flags = r.read_u32::<LittleEndian>()?;
+    let generation = Generation {
+        transaction_id: r.read_u64::<LittleEndian>()?,
+        time: r.read_u32::<LittleEndian>()?,
+        metadata_snap: r.read_u64::<LittleEndian>()?,
+        mapping_root: r.read_u64::<LittleEndian>()?,
+        details_root: r.read_u64::<LittleEndian>()?,
+    };
+
+    let mut defs = Vec::new();
+    for _ in 0..r.read_u64::<LittleEndian>()? {
+        let def_id = r.read_u64::<LittleEndian>()?;
+        let entries = unpack_entries(r)?;
+        defs.push(Def {
+            def_id,
+            map: Mapping {
+                kr: KeyRange::new(),
+                entries,
+            },
+        });
+    }
+
+    let mut devs = Vec::new();
+    let mut roots = BTreeMap::new();
+    let mut exclusives = BTreeMap::new();
+    for _ in 0..r.read_u64::<LittleEndian>()? {
+        let thin_id = r.read_u32::<LittleEndian>()?;
+        roots.insert(thin_id, r.read_u64::<LittleEndian>()?);
+        let detail = DeviceDetail {
+            mapped_blocks: r.read_u64::<LittleEndian>()?,
+            transaction_id: r.read_u64::<LittleEndian>()?,
+            creation_time: r.read_u32::<LittleEndian>()?,
+            snapshotted_time: r.read_u32::<LittleEndian>()?,
+        };
+        exclusives.insert(thin_id, r.read_u64::<LittleEndian>()?);
+        let entries = unpack_entries(r)?;
+        devs.push(Device {
+            thin_id,
+            detail,
+            map: Mapping {
+                kr: KeyRange::new(),
+                entries,
+            },
+        });
+    }
+
+    Ok(MetadataIndex {
+        generation,
+        canonical: flags & FLAG_CANONICAL != 0,
+        layout: flags & FLAG_LAYOUT != 0,
+        roots,
+        exclusives: if flags & FLAG_EXCLUSIVES != 0 {
+            Some(exclusives)
+        } else {
+            None
+        },
+        metadata: Metadata { defs, devs },
+    })
+}
+
+//------------------------------------------
+
+/// Reads an index, checking the trailing crc so a truncated or damaged
+/// file is rejected rather than half used.
+pub fn read_index(path: &Path) -> Result<MetadataIndex> {
+    let mut buf = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut buf)?;
+    if buf.len() < 4 {
+        return Err(anyhow!("index file too short"));
+    }
+
+    let (body, tail) = buf.split_at(buf.len() - 4);
+    let csum = Cursor::new(tail).read_u32::<LittleEndian>()?;
+    if crc32c(body) != csum {
+        return Err(anyhow!("bad checksum in index file"));
+    }
+
+    let mut cursor = Cursor::new(body);
+    let index = unpack_index(&mut cursor)?;
+    if cursor.position() != body.len() as u64 {
+        return Err(anyhow!("trailing data in index file"));
+    }
+    Ok(index)
+}
+
+/// Writes an index.  The new index is written alongside the old one and
+/// renamed over it, so a reader never sees a partial file.
+pub fn write_index(path: &Path, index: &MetadataIndex) -> Result<()> {
+    let mut body = Vec::new();
+    pack_index(&mut body, index)?;
+    let csum = crc32c(&body);
+
+    let mut tmp = PathBuf::from(path);
+    tmp.set_extension("tmp");
+    {
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        w.write_all(&body)?;
+        w.write_u32::<LittleEndian>(csum)?;
+        w.flush()?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Returns the index at path if it was built from the same superblock.
+/// A missing, damaged or stale index just means the metadata has to be
+/// walked again.
+pub fn read_current_index(path: &Path, sb: &Superblock) -> Option<MetadataIndex> {
+    let index = read_index(path).ok()?;
+    if index.generation == Generation::new(sb) {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_index() -> MetadataIndex {
+        let detail = DeviceDetail {
+            mapped_blocks: 100,
+            transaction_id: 3,
+            creation_time: 1,
+            snapshotted_time: 2,
+        };
+        let map = |entries| Mapping {
+            kr: KeyRange::new(),
+            entries,
+        };
+
+        MetadataIndex {
+            generation: Generation {
+                transaction_id: 3,
+                time: 2,
+                metadata_snap: 0,
+                mapping_root: 10,
+                details_root: 11,
+            },
+            canonical: false,
+            layout: true,
+            roots: vec![(0, 40), (1, 41)].into_iter().collect(),
+            exclusives: Some(vec![(0, 60), (1, 70)].into_iter().collect()),
+            metadata: Metadata {
+                defs: vec![Def {
+                    def_id: 20,
+                    map: map(vec![Entry::Leaf(20), Entry::Leaf(21)]),
+                }],
+                devs: vec![
+                    Device {
+                        thin_id: 0,
+                        detail,
+                        map: map(vec![Entry::Ref(20), Entry::Leaf(30)]),
+                    },
+                    Device {
+                        thin_id: 1,
+                        detail,
+                        map: map(vec![Entry::Leaf(31), Entry::Ref(20)]),
+                    },
+                ],
+            },
+        }
+    }
+
+    fn entry_values(es: &[Entry]) -> Vec<(bool, u64)> {
+        es.iter()
+            .map(|e| match e {
+                Entry::Leaf(b) => (false, *b),
+                Entry::Ref(id) => (true, *id),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let index = mk_index();
+        let mut buf = Vec::new();
+        pack_index(&mut buf, &index)?;
+        let copy = unpack_index(&mut Cursor::new(&buf))?;
+
+        assert_eq!(copy.generation, index.generation);
+        assert_eq!(copy.canonical, index.canonical);
+        assert_eq!(copy.layout, index.layout);
+        assert_eq!(copy.roots, index.roots);
+        assert_eq!(copy.exclusives, index.exclusives);
+        assert_eq!(copy.metadata.defs.len(), 1);
+        assert_eq!(copy.metadata.defs[0].def_id, 20);
+        assert_eq!(copy.metadata.devs.len(), 2);
+        for (a, b) in copy.metadata.devs.iter().zip(index.metadata.devs.iter()) {
+            assert_eq!(a.thin_id, b.thin_id);
+            assert_eq!(a.detail.mapped_blocks, b.detail.mapped_blocks);
+            assert_eq!(entry_values(&a.map.entries), entry_values(&b.map.entries));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_details_only_round_trip() -> Result<()> {
+        let mut index = mk_index();
+        index.layout = false;
+        index.exclusives = None;
+        index.metadata.defs.clear();
+        for dev in &mut index.metadata.devs {
+            dev.map.entries.clear();
+        }
+
+        let mut buf = Vec::new();
+        pack_index(&mut buf, &index)?;
+        let copy = unpack_index(&mut Cursor::new(&buf))?;
+        assert!(!copy.layout);
+        assert_eq!(copy.exclusives, None);
+        assert_eq!(copy.roots, index.roots);
+        assert_eq!(copy.metadata.devs.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_damaged_index_rejected() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("md.idx");
+        write_index(&path, &mk_index())?;
+        assert!(read_index(&path).is_ok());
+
+        let mut buf = fs::read(&path)?;
+        buf[20] ^= 0xff;
+        fs::write(&path, &buf)?;
+        assert!(read_index(&path).is_err());
+
+        fs::write(&path, &buf[..buf.len() / 2])?;
+        assert!(read_index(&path).is_err());
+        Ok(())
+    }
+}
+
+//------------------------------------------
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use std::collections::BTreeMap;
 
 //------------------------------------------
 
@@ -23,12 +24,24 @@ pub struct Device {
     pub snap_time: u32,
 }
 
+/// Userland's names for a device.  The kernel never sees these, they're
+/// kept in an area of the metadata of their own (see thin::labels).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceLabels {
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+    pub attributes: BTreeMap<String, String>,
+}
+
 #[derive(Clone)]
 pub struct Map {
     pub thin_begin: u64,
     pub data_begin: u64,
     pub time: u32,
     pub len: u64,
+
+    // Whether the data blocks have a ref count above one, if known.
+    pub shared: Option<bool>,
 }
 
 //------------------------------------------
@@ -51,6 +64,9 @@ pub trait MetadataVisitor {
     fn device_b(&mut self, d: &Device) -> Result<Visit>;
     fn device_e(&mut self) -> Result<Visit>;
 
+    // Only for devices that have labels, straight after device_b.
+    fn device_labels(&mut self, labels: &DeviceLabels) -> Result<Visit>;
+
     fn map(&mut self, m: &Map) -> Result<Visit>;
     fn ref_shared(&mut self, name: &str) -> Result<Visit>;
 
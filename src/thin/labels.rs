@@ -0,0 +1,416 @@
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use nom::{bytes::complete::*, number::complete::*, IResult};
+use std::collections::BTreeMap;
+use std::io::{Cursor, Write};
+
+use crate::checksum::*;
+use crate::io_engine::*;
+use crate::math::div_up;
+use crate::thin::ir::DeviceLabels;
+use crate::thin::superblock::backup_superblock_location;
+
+//------------------------------------------
+
+// Device labels are only for userland, the kernel knows nothing of them.
+// They're kept in a run of blocks at the end of the metadata, below the
+// block a backup superblock would use.  The blocks are marked as in use in
+// the metadata space map, and the superblock records the top block of the
+// run, which the kernel leaves alone, so checkers can account for them.
+// The top block records the length of the run.
+//
+// Every block starts with a header: its checksum, its own location, its
+// index within the run, counting down from the top one, the number of
+// blocks in the run, and the length of the payload that follows.  The
+// payloads, in index order, hold the labels.
+//
+// Like the backup superblock, the run is only written by thin_restore and
+// thin_repair.  The superblock points at it, so it's still found if the
+// metadata device is grown.
+
+const HEADER_SIZE: usize = 24;
+const PAYLOAD_SIZE: usize = BLOCK_SIZE - HEADER_SIZE;
+
+/// The most blocks the labels may take up.
+pub const MAX_LABEL_BLOCKS: u32 = 256;
+
+/// The longest label, uuid, attribute name or value, in bytes.
+pub const MAX_LABEL_LEN: usize = 4096;
+
+/// The labels of each device, by device id.
+pub type LabelMap = BTreeMap<u32, DeviceLabels>;
+
+/// The labels read from the metadata, and the blocks holding them.
+pub struct LabelArea {
+    pub blocks: Vec<u64>,
+    pub labels: LabelMap,
+}
+
+/// Where the run of label blocks ends.  It grows down from here.
+pub fn labels_end(nr_metadata_blocks: u64) -> u64 {
+    backup_superblock_location(nr_metadata_blocks)
+}
+
+//------------------------------------------
+
+fn check_string(what: &str, s: &str) -> Result<()> {
+    if s.is_empty() {
+        return Err(anyhow!("{} is empty", what));
+    }
+    if s.len() > MAX_LABEL_LEN {
+        return Err(anyhow!(
+            "{} is {} bytes long, the limit is {}",
+            what,
+            s.len(),
+            MAX_LABEL_LEN
+        ));
+    }
+    Ok(())
+}
+
+/// Errors if the labels can't be stored.
+pub fn check_labels(labels: &DeviceLabels) -> Result<()> {
+    if let Some(label) = &labels.label {
+        check_string("label", label)?;
+    }
+    if let Some(uuid) = &labels.uuid {
+        check_string("uuid", uuid)?;
+    }
+    for (key, value) in &labels.attributes {
+        check_string("attribute name", key)?;
+        check_string(&format!("attribute '{}'", key), value)?;
+    }
+    Ok(())
+}
+
+//------------------------------------------
+
+// Unset labels are written as empty strings, which check_labels() doesn't
+// allow otherwise.
+fn pack_string<W: Write>(w: &mut W, s: Option<&str>) -> Result<()> {
+    let s = s.unwrap_or("");
+    w.write_u32::<LittleEndian>(s.len() as u32)?;
+    w.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn pack_payload(labels: &LabelMap) -> Result<Vec<u8>> {
+    let mut w = Vec::new();
+    w.write_u32::<LittleEndian>(labels.len() as u32)?;
+    for (dev_id, l) in labels {
+        w.write_u32::<LittleEndian>(*dev_id)?;
+        pack_string(&mut w, l.label.as_deref())?;
+        pack_string(&mut w, l.uuid.as_deref())?;
+        w.write_u32::<LittleEndian>(l.attributes.len() as u32)?;
+        for (key, value) in &l.attributes {
+            pack_string(&mut w, Some(key))?;
+            pack_string(&mut w, Some(value))?;
+        }
+    }
+    Ok(w)
+}
+
+fn unpack_string(i: &[u8]) -> IResult<&[u8], Option<String>> {
+    let (i, len) = le_u32(i)?;
+    let (i, bytes) = take(len)(i)?;
+    if bytes.is_empty() {
+        Ok((i, None))
+    } else {
+        Ok((i, Some(String::from_utf8_lossy(bytes).into_owned())))
+    }
+}
+
+fn unpack_device(i: &[u8]) -> IResult<&[u8], (u32, DeviceLabels)> {
+    let (i, dev_id) = le_u32(i)?;
+    let (i, label) = unpack_string(i)?;
+    let (i, uuid) = unpack_string(i)?;
+    let (mut i, nr_attributes) = le_u32(i)?;
+
+    let mut attributes = BTreeMap::new();
+    for _ in 0..nr_attributes {
+        let (rest, key) = unpack_string(i)?;
+        let (rest, value) = unpack_string(rest)?;
+        attributes.insert(key.unwrap_or_default(), value.unwrap_or_default());
+        i = rest;
+    }
+
+    Ok((
+        i,
+        (
+            dev_id,
+            DeviceLabels {
+                label,
+                uuid,
+                attributes,
+            },
+        ),
+    ))
+}
+
+fn unpack_payload(data: &[u8]) -> Result<LabelMap> {
+    let bad = |_| anyhow!("couldn't unpack the device labels");
+    let (mut i, nr_devices) = le_u32::<_, nom::error::Error<&[u8]>>(data).map_err(bad)?;
+
+    let mut labels = LabelMap::new();
+    for _ in 0..nr_devices {
+        let (rest, (dev_id, l)) = unpack_device(i).map_err(bad)?;
+        labels.insert(dev_id, l);
+        i = rest;
+    }
+    Ok(labels)
+}
+
+//------------------------------------------
+
+/// Packs the labels into the run of blocks ending at `end`, top block
+/// first.  No labels need no blocks.
+pub fn pack_labels(labels: &LabelMap, end: u64) -> Result<Vec<Block>> {
+    if labels.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let payload = pack_payload(labels)?;
+    let nr_blocks = div_up(payload.len(), PAYLOAD_SIZE);
+    if nr_blocks > MAX_LABEL_BLOCKS as usize || nr_blocks as u64 >= end {
+        return Err(anyhow!(
+            "the device labels need {} metadata blocks, no more than {} are allowed",
+            nr_blocks,
+            std::cmp::min(MAX_LABEL_BLOCKS as u64, end.saturating_sub(1))
+        ));
+    }
+
+    let mut blocks = Vec::with_capacity(nr_blocks);
+    for (index, chunk) in payload.chunks(PAYLOAD_SIZE).enumerate() {
+        let b = Block::zeroed(end - 1 - index as u64);
+        {
+            let mut w = Cursor::new(b.get_data());
+            w.write_u32::<LittleEndian>(0)?; // checksum, which we don't know yet
+            w.write_u64::<LittleEndian>(b.loc)?;
+            w.write_u32::<LittleEndian>(index as u32)?;
+            w.write_u32::<LittleEndian>(nr_blocks as u32)?;
+            w.write_u32::<LittleEndian>(chunk.len() as u32)?;
+            w.write_all(chunk)?;
+        }
+        write_checksum(b.get_data(), BT::DEVICE_LABELS)?;
+        blocks.push(b);
+    }
+    Ok(blocks)
+}
+
+struct Header {
+    loc: u64,
+    index: u32,
+    nr_blocks: u32,
+    len: u32,
+}
+
+fn unpack_header(i: &[u8]) -> IResult<&[u8], Header> {
+    let (i, _csum) = le_u32(i)?;
+    let (i, loc) = le_u64(i)?;
+    let (i, index) = le_u32(i)?;
+    let (i, nr_blocks) = le_u32(i)?;
+    let (i, len) = le_u32(i)?;
+    Ok((
+        i,
+        Header {
+            loc,
+            index,
+            nr_blocks,
+            len,
+        },
+    ))
+}
+
+// Returns the length of the run, and the payload, of the label block at
+// `loc`, which has to say it's that far into the run.
+fn read_label_block(engine: &dyn IoEngine, loc: u64, index: u32) -> Result<(u32, Vec<u8>)> {
+    let b = engine.read(loc)?;
+    let data = b.get_data();
+    if metadata_block_type(data) != BT::DEVICE_LABELS {
+        return Err(anyhow!("no device labels in block {}", loc));
+    }
+
+    let (_, h) = unpack_header(data).map_err(|_| anyhow!("couldn't unpack label block"))?;
+    if h.loc != loc || h.index != index || h.len as usize > PAYLOAD_SIZE {
+        return Err(anyhow!("label block {} is out of place", loc));
+    }
+    Ok((
+        h.nr_blocks,
+        data[HEADER_SIZE..HEADER_SIZE + h.len as usize].to_vec(),
+    ))
+}
+
+/// Reads the labels from the run whose top block is `root`, as recorded
+/// in the superblock.
+pub fn read_labels(engine: &dyn IoEngine, root: u64) -> Result<LabelArea> {
+    if root == 0 || root >= engine.get_nr_blocks() {
+        return Err(anyhow!("no device labels at block {}", root));
+    }
+
+    let (nr_blocks, mut payload) = read_label_block(engine, root, 0)?;
+    if nr_blocks == 0 || nr_blocks > MAX_LABEL_BLOCKS || nr_blocks as u64 > root {
+        return Err(anyhow!("bad number of label blocks ({})", nr_blocks));
+    }
+
+    let mut blocks = vec![root];
+    for index in 1..nr_blocks {
+        let loc = root - index as u64;
+        let (n, data) = read_label_block(engine, loc, index)?;
+        if n != nr_blocks {
+            return Err(anyhow!("label block {} is from another run", loc));
+        }
+        payload.extend_from_slice(&data);
+        blocks.push(loc);
+    }
+
+    Ok(LabelArea {
+        blocks,
+        labels: unpack_payload(&payload)?,
+    })
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Mutex;
+
+    // Blocks that haven't been written read as zeroes.
+    struct MemEngine {
+        nr_blocks: u64,
+        blocks: Mutex<BTreeMap<u64, Vec<u8>>>,
+    }
+
+    impl MemEngine {
+        fn new(nr_blocks: u64) -> MemEngine {
+            MemEngine {
+                nr_blocks,
+                blocks: Mutex::new(BTreeMap::new()),
+            }
+        }
+    }
+
+    impl IoEngine for MemEngine {
+        fn get_nr_blocks(&self) -> u64 {
+            self.nr_blocks
+        }
+
+        fn get_batch_size(&self) -> usize {
+            1
+        }
+
+        fn read(&self, loc: u64) -> io::Result<Block> {
+            let b = Block::zeroed(loc);
+            if let Some(data) = self.blocks.lock().unwrap().get(&loc) {
+                b.get_data().copy_from_slice(data);
+            }
+            Ok(b)
+        }
+
+        fn read_many(&self, blocks: &[u64]) -> io::Result<Vec<io::Result<Block>>> {
+            Ok(blocks.iter().map(|b| self.read(*b)).collect())
+        }
+
+        fn write(&self, b: &Block) -> io::Result<()> {
+            self.blocks
+                .lock()
+                .unwrap()
+                .insert(b.loc, b.get_data().to_vec());
+            Ok(())
+        }
+
+        fn write_many(&self, blocks: &[Block]) -> io::Result<Vec<io::Result<()>>> {
+            Ok(blocks.iter().map(|b| self.write(b)).collect())
+        }
+    }
+
+    fn mk_labels(nr_devices: u32, value_len: usize) -> LabelMap {
+        let mut labels = LabelMap::new();
+        for dev_id in 0..nr_devices {
+            let mut l = DeviceLabels {
+                label: Some(format!("dev{}", dev_id)),
+                uuid: if dev_id % 2 == 0 {
+                    Some(format!("uuid-{}", dev_id))
+                } else {
+                    None
+                },
+                attributes: BTreeMap::new(),
+            };
+            l.attributes
+                .insert("owner".to_string(), "x".repeat(value_len));
+            labels.insert(dev_id, l);
+        }
+        labels
+    }
+
+    fn write_blocks(engine: &dyn IoEngine, blocks: &[Block]) {
+        for b in blocks {
+            engine.write(b).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let engine = MemEngine::new(64);
+        let labels = mk_labels(3, 10);
+        let blocks = pack_labels(&labels, labels_end(64))?;
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].loc, 62);
+        write_blocks(&engine, &blocks);
+
+        let area = read_labels(&engine, 62)?;
+        assert_eq!(area.blocks, vec![62]);
+        assert_eq!(area.labels, labels);
+        Ok(())
+    }
+
+    #[test]
+    fn test_labels_span_blocks() -> Result<()> {
+        let engine = MemEngine::new(64);
+        let labels = mk_labels(10, 1000);
+        let blocks = pack_labels(&labels, labels_end(64))?;
+        assert_eq!(blocks.len(), 3);
+        write_blocks(&engine, &blocks);
+
+        let area = read_labels(&engine, 62)?;
+        assert_eq!(area.blocks, vec![62, 61, 60]);
+        assert_eq!(area.labels, labels);
+
+        // a damaged block loses the lot
+        engine.write(&Block::zeroed(61))?;
+        assert!(read_labels(&engine, 62).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_labels() -> Result<()> {
+        let engine = MemEngine::new(64);
+        assert!(pack_labels(&LabelMap::new(), labels_end(64))?.is_empty());
+        assert!(read_labels(&engine, 62).is_err());
+        assert!(read_labels(&engine, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_labels_must_fit() {
+        assert!(pack_labels(&mk_labels(10, 1000), labels_end(3)).is_err());
+    }
+
+    #[test]
+    fn test_check_labels() {
+        let mut l = mk_labels(1, 10).remove(&0).unwrap();
+        assert!(check_labels(&l).is_ok());
+
+        l.uuid = Some(String::new());
+        assert!(check_labels(&l).is_err());
+
+        l.uuid = None;
+        l.attributes
+            .insert("big".to_string(), "x".repeat(MAX_LABEL_LEN + 1));
+        assert!(check_labels(&l).is_err());
+    }
+}
+
+//------------------------------------------
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::collections::{BTreeMap, BTreeSet};
 use std::sync::{Arc, Mutex};
 
@@ -7,6 +7,7 @@ use crate::pdata::btree::{self, *};
 use crate::pdata::btree_leaf_walker::*;
 use crate::pdata::btree_walker::*;
 use crate::pdata::space_map::*;
+use crate::pdata::unpack::Unpack;
 use crate::thin::block_time::*;
 use crate::thin::device_detail::*;
 use crate::thin::runs::*;
@@ -76,14 +77,20 @@ impl LeafVisitor<BlockTime> for CollectLeaves {
     }
 }
 
+// If share_leaves is set, subtrees that have already been walked for
+// another root are recorded as refs rather than walked again.
 fn collect_leaves(
     engine: Arc<dyn IoEngine + Send + Sync>,
     roots: &BTreeSet<u64>,
+    share_leaves: bool,
 ) -> Result<BTreeMap<u64, Vec<Entry>>> {
     let mut map: BTreeMap<u64, Vec<Entry>> = BTreeMap::new();
     let mut sm = RestrictedSpaceMap::new(engine.get_nr_blocks());
 
     for r in roots {
+        if !share_leaves {
+            sm = RestrictedSpaceMap::new(engine.get_nr_blocks());
+        }
         let mut w = LeafWalker::new(engine.clone(), &mut sm, false);
         let mut v = CollectLeaves::new();
         let mut path = vec![0];
@@ -95,16 +102,79 @@ fn collect_leaves(
     Ok(map)
 }
 
+// Counts the mappings in each tree from the headers of its leaves.
+fn count_mappings(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    roots: &BTreeSet<u64>,
+) -> Result<BTreeMap<u64, u64>> {
+    let mut counts = BTreeMap::new();
+    for (root, es) in collect_leaves(engine.clone(), roots, false)? {
+        let mut nr_mappings = 0;
+        for e in es {
+            if let Entry::Leaf(b) = e {
+                let blk = engine.read(b)?;
+                let (_, hdr) = NodeHeader::unpack(blk.get_data())?;
+                nr_mappings += hdr.nr_entries as u64;
+            }
+        }
+        counts.insert(root, nr_mappings);
+    }
+    Ok(counts)
+}
+
+/// Stands in for the device details when the details tree has been lost,
+/// which a repaired superblock records with a details root of 0.  Only
+/// the mapped blocks can be recovered, by counting the mappings; the
+/// transaction id and times are taken from the superblock.  A snapshot
+/// time no older than any mapping marks every mapping as shared, so the
+/// kernel copies blocks rather than overwriting ones another device may
+/// still use.
+fn synthesise_details(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+    roots: &BTreeMap<u64, u64>,
+) -> Result<BTreeMap<u64, DeviceDetail>> {
+    let mapping_roots = roots.values().cloned().collect();
+    let counts = count_mappings(engine, &mapping_roots)?;
+
+    Ok(roots
+        .iter()
+        .map(|(thin_id, root)| {
+            let detail = DeviceDetail {
+                mapped_blocks: counts[root],
+                transaction_id: sb.transaction_id,
+                creation_time: sb.time,
+                snapshotted_time: sb.time,
+            };
+            (*thin_id, detail)
+        })
+        .collect())
+}
+
 //------------------------------------------
 
 pub fn build_metadata(
     engine: Arc<dyn IoEngine + Send + Sync>,
     sb: &Superblock,
 ) -> Result<Metadata> {
-    let mut path = vec![0];
+    build_metadata_(engine, sb, true)
+}
 
-    // report.set_title("Reading device details");
-    let details = btree_to_map::<DeviceDetail>(&mut path, engine.clone(), true, sb.details_root)?;
+/// As build_metadata(), but every device lists all of its own leaves,
+/// so nothing depends on how the btrees happen to share nodes.
+pub fn build_unshared_metadata(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+) -> Result<Metadata> {
+    build_metadata_(engine, sb, false)
+}
+
+fn build_metadata_(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+    share_leaves: bool,
+) -> Result<Metadata> {
+    let mut path = vec![0];
 
     // report.set_title("Reading mappings roots");
     let roots;
@@ -114,15 +184,28 @@ pub fn build_metadata(
             btree_to_map_with_path::<u64>(&mut path, engine.clone(), sm, true, sb.mapping_root)?;
     }
 
+    // report.set_title("Reading device details");
+    let details = if sb.details_root == 0 {
+        let roots = roots
+            .iter()
+            .map(|(thin_id, (_, root))| (*thin_id, *root))
+            .collect();
+        synthesise_details(engine.clone(), sb, &roots)?
+    } else {
+        btree_to_map::<DeviceDetail>(&mut path, engine.clone(), true, sb.details_root)?
+    };
+
     // report.set_title(&format!("Collecting leaves for {} roots", roots.len()));
     let mapping_roots = roots.values().map(|(_, root)| *root).collect();
-    let entry_map = collect_leaves(engine.clone(), &mapping_roots)?;
+    let entry_map = collect_leaves(engine.clone(), &mapping_roots, share_leaves)?;
 
     let defs = Vec::new();
     let mut devs = Vec::new();
     for (thin_id, (_path, root)) in roots {
         let id = thin_id as u64;
-        let detail = details.get(&id).expect("couldn't find device details");
+        let detail = details
+            .get(&id)
+            .ok_or_else(|| anyhow!("couldn't find the details of device {}", id))?;
         let es = entry_map.get(&root).unwrap();
         let kr = KeyRange::new(); // FIXME: finish
         devs.push(Device {
@@ -138,6 +221,41 @@ pub fn build_metadata(
     Ok(Metadata { defs, devs })
 }
 
+/// Lists the devices from the details tree alone, each with no
+/// mappings.  Only the details tree is read, never the mapping trees,
+/// so this is cheap however many mappings there are, and works however
+/// damaged they are.  The exception is a lost details tree, when the
+/// mappings have to be counted to synthesise the details.
+pub fn build_inventory(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+) -> Result<Metadata> {
+    let mut path = vec![0];
+    let details = if sb.details_root == 0 {
+        let roots = btree_to_map::<u64>(&mut path, engine.clone(), true, sb.mapping_root)?;
+        synthesise_details(engine, sb, &roots)?
+    } else {
+        btree_to_map::<DeviceDetail>(&mut path, engine, true, sb.details_root)?
+    };
+
+    let devs = details
+        .into_iter()
+        .map(|(thin_id, detail)| Device {
+            thin_id: thin_id as u32,
+            detail,
+            map: Mapping {
+                kr: KeyRange::new(),
+                entries: Vec::new(),
+            },
+        })
+        .collect();
+
+    Ok(Metadata {
+        defs: Vec::new(),
+        devs,
+    })
+}
+
 //------------------------------------------
 
 fn gather_entries(g: &mut Gatherer, es: &[Entry]) {
@@ -0,0 +1,94 @@
+use anyhow::Result;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::io_engine::*;
+use crate::report::*;
+use crate::thin::canonical::*;
+use crate::thin::dump::dump_metadata;
+use crate::thin::metadata::build_unshared_metadata;
+use crate::thin::metadata_repair::SuperblockOverrides;
+use crate::thin::superblock::*;
+use crate::thin::xml;
+
+//------------------------------------------
+
+pub struct ThinMetadataDiffOptions<'a> {
+    pub left: &'a Path,
+    pub right: &'a Path,
+    pub async_io: bool,
+    pub nr_io_threads: usize,
+    pub report: Arc<Report>,
+}
+
+const MAX_CONCURRENT_IO: u32 = 1024;
+
+// Either input may be an xml dump, rather than binary metadata.
+fn is_xml(path: &Path) -> Result<bool> {
+    let mut data = Vec::new();
+    File::open(path)?.take(16).read_to_end(&mut data)?;
+    let start = data
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .unwrap_or(data.len());
+    Ok(data[start..].starts_with(b"<"))
+}
+
+fn read_metadata(path: &Path, opts: &ThinMetadataDiffOptions) -> Result<CanonicalMetadata> {
+    let mut builder = CanonicalBuilder::new();
+
+    if is_xml(path)? {
+        xml::read(OpenOptions::new().read(true).open(path)?, &mut builder)?;
+        return Ok(builder.complete());
+    }
+
+    let engine: Arc<dyn IoEngine + Send + Sync> = if opts.async_io {
+        Arc::new(AsyncIoEngine::new(path, MAX_CONCURRENT_IO, false)?)
+    } else {
+        Arc::new(SyncIoEngine::new(path, opts.nr_io_threads, false)?)
+    };
+
+    // Shared subtrees are expanded, so metadata that shares its leaves
+    // differently still compares equal.
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let md = build_unshared_metadata(engine.clone(), &sb)?;
+    dump_metadata(
+        engine,
+        &mut builder,
+        &sb,
+        &md,
+        &SuperblockOverrides {
+            transaction_id: None,
+            data_block_size: None,
+            nr_data_blocks: None,
+        },
+    )?;
+    Ok(builder.complete())
+}
+
+fn print_difference(report: &Report, d: &Difference, depth: usize) {
+    report.to_stdout(&format!("{:width$}{}", "", d.what, width = depth * 2));
+    for child in &d.children {
+        print_difference(report, child, depth + 1);
+    }
+}
+
+/// Compares two copies of the metadata, printing what differs.  Returns
+/// the number of top level differences, the superblock and each device
+/// counting as one.
+pub fn metadata_diff(opts: ThinMetadataDiffOptions) -> Result<usize> {
+    opts.report.set_title("Reading the left metadata");
+    let lhs = read_metadata(opts.left, &opts)?;
+    opts.report.set_title("Reading the right metadata");
+    let rhs = read_metadata(opts.right, &opts)?;
+
+    let diffs = differences(&lhs, &rhs);
+    for d in &diffs {
+        print_difference(&opts.report, d, 0);
+    }
+    Ok(diffs.len())
+}
+
+//------------------------------------------
@@ -0,0 +1,106 @@
+use anyhow::Result;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::io_engine::*;
+use crate::report::*;
+use crate::thin::superblock::*;
+use crate::transaction::TransactionEngine;
+
+//------------------------------------------
+
+pub enum UuidChange {
+    Keep,
+    Set([u8; UUID_SIZE]),
+    Generate,
+}
+
+pub enum TransactionIdChange {
+    Keep,
+    Set(u64),
+    Bump,
+}
+
+pub struct ThinSetMetadataIdOptions<'a> {
+    pub input: &'a Path,
+    pub async_io: bool,
+    pub uuid: UuidChange,
+    pub transaction_id: TransactionIdChange,
+    pub report: Arc<Report>,
+}
+
+const MAX_CONCURRENT_IO: u32 = 1024;
+
+fn new_uuid(old: [u8; UUID_SIZE], change: &UuidChange) -> [u8; UUID_SIZE] {
+    match change {
+        UuidChange::Keep => old,
+        UuidChange::Set(uuid) => *uuid,
+        UuidChange::Generate => random_uuid(),
+    }
+}
+
+fn new_transaction_id(old: u64, change: &TransactionIdChange) -> Result<u64> {
+    match change {
+        TransactionIdChange::Keep => Ok(old),
+        TransactionIdChange::Set(tid) => Ok(*tid),
+        TransactionIdChange::Bump => old
+            .checked_add(1)
+            .ok_or_else(|| anyhow::anyhow!("transaction id {} can't be bumped", old)),
+    }
+}
+
+fn describe_uuid(uuid: &[u8; UUID_SIZE]) -> String {
+    let s = uuid_to_string(uuid);
+    if s.is_empty() {
+        "unset".to_string()
+    } else {
+        s
+    }
+}
+
+/// Gives the metadata a new identity, so a copy of a pool's devices
+/// can be activated alongside the original.  Only the superblock, and
+/// the backup copy if there is one, are rewritten.
+pub fn set_metadata_id(opts: ThinSetMetadataIdOptions) -> Result<()> {
+    let engine: Arc<dyn IoEngine + Send + Sync> = if opts.async_io {
+        Arc::new(AsyncIoEngine::new(opts.input, MAX_CONCURRENT_IO, true)?)
+    } else {
+        Arc::new(SyncIoEngine::new(opts.input, 1, true)?)
+    };
+    let engine = TransactionEngine::new(engine);
+
+    let mut sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    check_features(&sb, &opts.report, true)?;
+
+    let uuid = new_uuid(sb.uuid, &opts.uuid);
+    let transaction_id = new_transaction_id(sb.transaction_id, &opts.transaction_id)?;
+
+    opts.report.to_stdout(&format!(
+        "uuid: {} -> {}",
+        describe_uuid(&sb.uuid),
+        describe_uuid(&uuid)
+    ));
+    opts.report.to_stdout(&format!(
+        "transaction id: {} -> {}",
+        sb.transaction_id, transaction_id
+    ));
+
+    sb.uuid = uuid;
+    sb.transaction_id = transaction_id;
+    write_superblock(&engine, SUPERBLOCK_LOCATION, &sb)?;
+
+    // A stale identity in the backup would come back if the superblock
+    // were ever rebuilt from it.
+    if let Ok(Some(mut backup)) = read_recorded_backup(&engine, &sb) {
+        backup.uuid = uuid;
+        backup.transaction_id = transaction_id;
+        write_superblock(&engine, backup.block, &backup)?;
+    }
+
+    // Nothing reaches the device until both copies are updated, and
+    // then the primary superblock goes last.
+    engine.commit()?;
+    Ok(())
+}
+
+//------------------------------------------
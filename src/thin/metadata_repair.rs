@@ -14,22 +14,30 @@ use crate::pdata::unpack::Unpack;
 use crate::report::Report;
 use crate::thin::block_time::*;
 use crate::thin::device_detail::*;
+use crate::thin::metadata::Metadata;
 use crate::thin::superblock::*;
 
 //------------------------------------------
 
+#[derive(Clone)]
 pub struct SuperblockOverrides {
     pub transaction_id: Option<u64>,
     pub data_block_size: Option<u32>,
     pub nr_data_blocks: Option<u64>,
 }
 
+/// A pair of top level mapping and device details roots that agree
+/// with each other, from which the superblock could be rebuilt.  If no
+/// details tree agrees with the mappings the details root is 0, and the
+/// details are synthesised from the mappings.
 pub struct FoundRoots {
-    mapping_root: u64,
-    details_root: u64,
-    time: u32,
-    transaction_id: u64,
-    nr_data_blocks: u64,
+    pub mapping_root: u64,
+    pub details_root: u64,
+    pub time: u32,
+    pub transaction_id: u64,
+    pub nr_data_blocks: u64,
+    pub nr_devices: u64,
+    pub nr_mappings: u64,
 }
 
 fn merge_time_counts(lhs: &mut BTreeMap<u32, u32>, rhs: &BTreeMap<u32, u32>) -> Result<()> {
@@ -663,24 +671,48 @@ impl NodeCollector {
             time: std::cmp::max(dev_info.age, details_info.age),
             transaction_id: details_info.max_tid + 1, // tid in superblock is ahead by 1
             nr_data_blocks: dev_info.highest_mapped_data_block + 1,
+            nr_devices: dev_info.nr_devices,
+            nr_mappings: dev_info.nr_mappings,
+        })
+    }
+
+    // For a mapping tree whose details tree is lost.  The details are
+    // synthesised from the mappings later, so there's no transaction id
+    // to go on.
+    fn to_found_roots_without_details(&self, dev_root: u64) -> Result<FoundRoots> {
+        let dev_info;
+        if let NodeInfo::Dev(i) = self.read_info(dev_root)? {
+            dev_info = i;
+        } else {
+            return Err(anyhow!("not a top-level root"));
+        }
+
+        Ok(FoundRoots {
+            mapping_root: dev_root,
+            details_root: 0,
+            time: dev_info.age,
+            transaction_id: 0,
+            nr_data_blocks: dev_info.highest_mapped_data_block + 1,
+            nr_devices: dev_info.nr_devices,
+            nr_mappings: dev_info.nr_mappings,
         })
     }
 
     fn log_results(&self, dev_roots: &[u64], details_roots: &[u64], pairs: &[(u64, u64)]) {
         self.report
-            .info(&format!("mapping candidates ({}):", dev_roots.len()));
+            .verbose(&format!("mapping candidates ({}):", dev_roots.len()));
         for dev_root in dev_roots {
             if let Ok(NodeInfo::Dev(info)) = self.read_info(*dev_root) {
-                self.report.info(&format!("b={}, nr_devices={}, nr_mappings={}, highest_mapped={}, age={}, time_counts={:?}",
+                self.report.verbose(&format!("b={}, nr_devices={}, nr_mappings={}, highest_mapped={}, age={}, time_counts={:?}",
                 info.b, info.nr_devices, info.nr_mappings, info.highest_mapped_data_block, info.age, info.time_counts));
             }
         }
 
         self.report
-            .info(&format!("\ndevice candidates ({}):", details_roots.len()));
+            .verbose(&format!("\ndevice candidates ({}):", details_roots.len()));
         for details_root in details_roots {
             if let Ok(NodeInfo::Details(info)) = self.read_info(*details_root) {
-                self.report.info(&format!(
+                self.report.verbose(&format!(
                     "b={}, nr_devices={}, nr_mappings={}, max_tid={}, age={}",
                     info._b, info.nr_devices, info.nr_mappings, info.max_tid, info.age
                 ));
@@ -688,23 +720,43 @@ impl NodeCollector {
         }
 
         self.report
-            .info(&format!("\ncompatible roots ({}):", pairs.len()));
+            .verbose(&format!("\ncompatible roots ({}):", pairs.len()));
         for pair in pairs {
-            self.report.info(&format!("({}, {})", pair.0, pair.1));
+            self.report.verbose(&format!("({}, {})", pair.0, pair.1));
         }
     }
 
-    pub fn find_roots(mut self) -> Result<FoundRoots> {
+    pub fn find_roots(self) -> Result<FoundRoots> {
+        self.find_all_roots()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("no compatible roots found"))
+    }
+
+    pub fn find_all_roots(mut self) -> Result<Vec<FoundRoots>> {
         self.collect_infos()?;
         let (dev_roots, details_roots) = self.gather_roots()?;
         let pairs = self.find_root_pairs(&dev_roots, &details_roots)?;
         self.log_results(&dev_roots, &details_roots, &pairs);
 
-        if pairs.is_empty() {
-            return Err(anyhow!("no compatible roots found"));
-        }
-
-        self.to_found_roots(pairs[0].0, pairs[0].1)
+        // Without a details tree to go with them, the mapping trees are
+        // still worth having.
+        if pairs.is_empty() && !dev_roots.is_empty() {
+            self.report.info(
+                "No device details match the mappings, the details will be synthesised from the mappings.",
+            );
+            let lone: Vec<(u64, u64)> = dev_roots.iter().map(|r| (*r, 0)).collect();
+            return self
+                .sort_roots(&lone)?
+                .iter()
+                .map(|(dev_root, _)| self.to_found_roots_without_details(*dev_root))
+                .collect();
+        }
+
+        pairs
+            .iter()
+            .map(|(dev_root, details_root)| self.to_found_roots(*dev_root, *details_root))
+            .collect()
     }
 }
 
@@ -715,6 +767,23 @@ fn check_data_block_size(bs: u32) -> Result<u32> {
     Ok(bs)
 }
 
+/// Lists the device details that were synthesised, rather than read,
+/// because the superblock was rebuilt without a details tree.
+pub fn report_synthesised_details(sb: &Superblock, md: &Metadata, report: &Report) {
+    if sb.details_root != 0 {
+        return;
+    }
+
+    report.info("The device details were lost, and have been synthesised:");
+    for dev in &md.devs {
+        let d = &dev.detail;
+        report.info(&format!(
+            "  device {}: mapped_blocks {} (counted from the mappings), transaction {}, creation_time {}, snap_time {} (from the superblock)",
+            dev.thin_id, d.mapped_blocks, d.transaction_id, d.creation_time, d.snapshotted_time
+        ));
+    }
+}
+
 //------------------------------------------
 
 #[derive(Debug)]
@@ -756,31 +825,56 @@ pub fn rebuild_superblock(
     ref_sb: Option<Superblock>,
     opts: &SuperblockOverrides,
 ) -> Result<Superblock> {
-    // 1. Takes the user overrides
-    // 2. Takes the reference if there's no user overrides
-    // 3. Returns Err if none of the values above are present
-    // 4. Validates the taken value
-    let data_block_size = opts
-        .data_block_size
-        .or_else(|| ref_sb.as_ref().map(|sb| sb.data_block_size))
+    // The data block size can't be recovered from the btrees, so check
+    // for it before the slow search for roots.
+    rebuild_data_block_size(ref_sb.as_ref(), opts)?;
+
+    let c = NodeCollector::new(engine, report);
+    let roots = c.find_roots()?;
+    rebuild_superblock_from(ref_sb.as_ref(), &roots, opts)
+}
+
+fn rebuild_data_block_size(ref_sb: Option<&Superblock>, opts: &SuperblockOverrides) -> Result<u32> {
+    opts.data_block_size
+        .or_else(|| ref_sb.map(|sb| sb.data_block_size))
         .ok_or_else(|| {
             anyhow!("data block size needs to be provided due to corruption in the superblock")
         })
-        .and_then(check_data_block_size)?;
+        .and_then(check_data_block_size)
+}
 
-    let c = NodeCollector::new(engine.clone(), report);
-    let roots = c.find_roots()?;
+/// Searches the metadata for every compatible pair of roots, most
+/// recent first.
+pub fn find_all_roots(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+) -> Result<Vec<FoundRoots>> {
+    NodeCollector::new(engine, report).find_all_roots()
+}
+
+/// Builds a superblock around a given pair of roots, eg, one picked
+/// from those find_all_roots() returns.
+pub fn rebuild_superblock_from(
+    ref_sb: Option<&Superblock>,
+    roots: &FoundRoots,
+    opts: &SuperblockOverrides,
+) -> Result<Superblock> {
+    // 1. Takes the user overrides
+    // 2. Takes the reference if there's no user overrides
+    // 3. Returns Err if none of the values above are present
+    // 4. Validates the taken value
+    let data_block_size = rebuild_data_block_size(ref_sb, opts)?;
 
     let transaction_id = opts
         .transaction_id
-        .or_else(|| ref_sb.as_ref().map(|sb| sb.transaction_id))
+        .or_else(|| ref_sb.map(|sb| sb.transaction_id))
         .filter(|tid| *tid > roots.transaction_id)
         .unwrap_or(roots.transaction_id);
 
     let nr_data_blocks = opts
         .nr_data_blocks
         .or_else(|| {
-            ref_sb.as_ref().and_then(|sb| {
+            ref_sb.and_then(|sb| {
                 unpack_root(&sb.data_sm_root)
                     .ok()
                     .map(|root| root.nr_blocks)
@@ -798,8 +892,12 @@ pub fn rebuild_superblock(
     let data_sm_root = pack_root(&sm_root, SPACE_MAP_ROOT_SIZE)?;
 
     Ok(Superblock {
-        flags: SuperblockFlags { needs_check: false },
+        flags: SuperblockFlags {
+            needs_check: false,
+            unknown: 0,
+        },
         block: SUPERBLOCK_LOCATION,
+        uuid: ref_sb.map(|sb| sb.uuid).unwrap_or([0; UUID_SIZE]),
         version: 2,
         time: roots.time,
         transaction_id,
@@ -810,23 +908,67 @@ pub fn rebuild_superblock(
         details_root: roots.details_root,
         data_block_size,
         nr_metadata_blocks: 0,
+        compat_flags: 0,
+        compat_ro_flags: 0,
+        incompat_flags: 0,
+        backup_superblock: 0,
+        labels_root: 0,
     })
 }
 
+/// What was found at the superblock location.
+pub enum SuperblockState {
+    Intact(Superblock),
+
+    // The superblock is unreadable, or its roots disagree.  The
+    // reference is the damaged superblock, if it could be read, or else
+    // the backup copy, for the values the btrees can't give.
+    Damaged {
+        reason: anyhow::Error,
+        reference: Option<Superblock>,
+    },
+}
+
+pub fn examine_superblock(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    loc: u64,
+) -> SuperblockState {
+    match read_superblock(engine.as_ref(), loc)
+        .and_then(|sb| is_superblock_consistent(sb, engine.clone()))
+    {
+        Ok(sb) => SuperblockState::Intact(sb),
+        Err(reason) => {
+            // Fall back to the backup copy, if there is one, for the values
+            // that can't be recovered from the btrees.
+            let reference = reason
+                .downcast_ref::<SuperblockError>()
+                .and_then(|err| err.failed_sb.clone())
+                .or_else(|| {
+                    let sb = read_backup_superblock(engine.as_ref()).ok()?;
+                    report.info(&format!(
+                        "using the backup superblock at block {}",
+                        sb.block
+                    ));
+                    Some(sb)
+                });
+            SuperblockState::Damaged { reason, reference }
+        }
+    }
+}
+
 pub fn read_or_rebuild_superblock(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
     loc: u64,
     opts: &SuperblockOverrides,
 ) -> Result<Superblock> {
-    read_superblock(engine.as_ref(), loc)
-        .and_then(|sb| is_superblock_consistent(sb, engine.clone()))
-        .or_else(|e| {
-            let ref_sb = e
-                .downcast_ref::<SuperblockError>()
-                .and_then(|err| err.failed_sb.clone());
-            rebuild_superblock(engine, report, ref_sb, opts)
-        })
+    match examine_superblock(engine.clone(), report.clone(), loc) {
+        SuperblockState::Intact(sb) => Ok(sb),
+        SuperblockState::Damaged { reference, .. } => {
+            rebuild_superblock(engine, report, reference, opts)
+        }
+    }
 }
 
 //------------------------------------------
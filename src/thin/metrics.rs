@@ -0,0 +1,427 @@
+use anyhow::{anyhow, Result};
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::report::*;
+use crate::thin::stat::*;
+
+//------------------------------------------
+
+/// A pool to inspect, given on the command line as [NAME=]DEVICE.  The
+/// name labels its metrics, and defaults to the device path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pool {
+    pub name: String,
+    pub input: PathBuf,
+}
+
+impl FromStr for Pool {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, input) = match s.split_once('=') {
+            Some((name, input)) => (name, input),
+            None => (s, s),
+        };
+        if name.is_empty() || input.is_empty() {
+            return Err(anyhow!("bad pool '{}', expected [NAME=]DEVICE", s));
+        }
+        Ok(Pool {
+            name: name.to_string(),
+            input: PathBuf::from(input),
+        })
+    }
+}
+
+pub struct ThinMetricsOptions {
+    pub pools: Vec<Pool>,
+    pub async_io: bool,
+    pub nr_io_threads: usize,
+    pub use_metadata_snap: bool,
+
+    /// Serve the metrics over http on this address, refreshing them
+    /// every interval.  They're written to stdout once otherwise.
+    pub listen: Option<String>,
+    pub interval: Duration,
+    pub report: Arc<Report>,
+}
+
+//------------------------------------------
+
+// The samples of one metric, across all the pools.
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    samples: Vec<(String, String)>,
+}
+
+#[derive(Default)]
+struct Exposition {
+    families: Vec<Family>,
+}
+
+impl Exposition {
+    fn add<V: Display>(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: V,
+    ) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+            .collect();
+        let sample = (format!("{{{}}}", labels.join(",")), value.to_string());
+
+        match self.families.iter_mut().find(|f| f.name == name) {
+            Some(f) => f.samples.push(sample),
+            None => self.families.push(Family {
+                name,
+                help,
+                samples: vec![sample],
+            }),
+        }
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        for f in &self.families {
+            writeln!(w, "# HELP {} {}", f.name, f.help)?;
+            writeln!(w, "# TYPE {} gauge", f.name)?;
+            for (labels, value) in &f.samples {
+                writeln!(w, "{}{} {}", f.name, labels, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn add_tree(e: &mut Exposition, pool: &str, tree: &str, stats: &TreeStats) {
+    let labels = [("pool", pool), ("tree", tree)];
+    e.add(
+        "thin_pool_btree_height",
+        "Number of levels in a btree.",
+        &labels,
+        stats.height,
+    );
+    e.add(
+        "thin_pool_btree_internal_nodes",
+        "Number of internal nodes in a btree.",
+        &labels,
+        stats.nr_internal,
+    );
+    e.add(
+        "thin_pool_btree_leaves",
+        "Number of leaf nodes in a btree.",
+        &labels,
+        stats.nr_leaves,
+    );
+    e.add(
+        "thin_pool_btree_entries",
+        "Number of entries in the leaves of a btree.",
+        &labels,
+        stats.nr_entries,
+    );
+}
+
+fn add_space_map(e: &mut Exposition, pool: &str, sm: &str, stats: &SpaceMapStats) {
+    let labels = [("pool", pool), ("space_map", sm)];
+    e.add(
+        "thin_pool_blocks",
+        "Number of blocks in a space map.",
+        &labels,
+        stats.nr_blocks,
+    );
+    e.add(
+        "thin_pool_allocated_blocks",
+        "Number of allocated blocks, as recorded in the space map root.",
+        &labels,
+        stats.nr_allocated,
+    );
+    e.add(
+        "thin_pool_free_blocks",
+        "Number of free blocks, as counted in the bitmaps.",
+        &labels,
+        stats.nr_free,
+    );
+    e.add(
+        "thin_pool_free_runs",
+        "Number of runs of adjacent free blocks.",
+        &labels,
+        stats.nr_free_runs,
+    );
+    e.add(
+        "thin_pool_largest_free_run_blocks",
+        "Length of the longest run of free blocks.",
+        &labels,
+        stats.largest_free_run,
+    );
+    e.add(
+        "thin_pool_fragmentation_ratio",
+        "Fraction of the free blocks outside the longest free run.",
+        &labels,
+        format!("{:.6}", stats.fragmentation()),
+    );
+}
+
+fn add_pool(e: &mut Exposition, pool: &str, stats: &Result<ThinStats>) {
+    let labels = [("pool", pool)];
+    e.add(
+        "thin_pool_up",
+        "Whether the pool metadata could be read.",
+        &labels,
+        stats.is_ok() as u32,
+    );
+
+    let stats = match stats {
+        Ok(stats) => stats,
+        Err(_) => return,
+    };
+
+    e.add(
+        "thin_pool_transaction_id",
+        "Transaction id of the metadata.",
+        &labels,
+        stats.transaction_id,
+    );
+    e.add(
+        "thin_pool_data_block_size_sectors",
+        "Size of a data block, in 512 byte sectors.",
+        &labels,
+        stats.data_block_size,
+    );
+    e.add(
+        "thin_pool_devices",
+        "Number of thin devices.",
+        &labels,
+        stats.devices.len(),
+    );
+    add_tree(e, pool, "details", &stats.details);
+    add_tree(e, pool, "mapping", &stats.mapping_roots);
+    add_space_map(e, pool, "metadata", &stats.metadata_sm);
+    add_space_map(e, pool, "data", &stats.data_sm);
+
+    for dev in &stats.devices {
+        let dev_id = dev.dev_id.to_string();
+        let labels = [("pool", pool), ("dev_id", dev_id.as_str())];
+        e.add(
+            "thin_device_mapped_blocks",
+            "Number of data blocks mapped by a thin device.",
+            &labels,
+            dev.mapped_blocks,
+        );
+        e.add(
+            "thin_device_btree_height",
+            "Number of levels in the mapping tree of a thin device.",
+            &labels,
+            dev.mappings.height,
+        );
+        e.add(
+            "thin_device_btree_leaves",
+            "Number of leaf nodes in the mapping tree of a thin device.",
+            &labels,
+            dev.mappings.nr_leaves,
+        );
+    }
+}
+
+/// Writes the stats of each pool in the Prometheus text format.  A pool
+/// that couldn't be read only gets thin_pool_up, set to 0.
+pub fn write_metrics<W: Write>(w: &mut W, pools: &[(&Pool, Result<ThinStats>)]) -> Result<()> {
+    let mut e = Exposition::default();
+    for (pool, stats) in pools {
+        add_pool(&mut e, &pool.name, stats);
+    }
+    e.write(w)
+}
+
+//------------------------------------------
+
+fn scrape(opts: &ThinMetricsOptions) -> Result<Vec<u8>> {
+    let mut pools = Vec::with_capacity(opts.pools.len());
+    for pool in &opts.pools {
+        let stats = stat(ThinStatOptions {
+            input: &pool.input,
+            async_io: opts.async_io,
+            nr_io_threads: opts.nr_io_threads,
+            use_metadata_snap: opts.use_metadata_snap,
+        });
+        if let Err(e) = &stats {
+            opts.report.info(&format!("{}: {}", pool.name, e));
+        }
+        pools.push((pool, stats));
+    }
+
+    let mut buf = Vec::new();
+    write_metrics(&mut buf, &pools)?;
+    Ok(buf)
+}
+
+fn respond(stream: TcpStream, latest: &Mutex<Vec<u8>>) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    // The headers aren't needed, but must be read before replying.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut stream = reader.into_inner();
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    if request.starts_with("GET ") && path == "/metrics" {
+        let body = latest.lock().unwrap().clone();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+    } else {
+        write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )?;
+    }
+    Ok(())
+}
+
+pub fn metrics(opts: ThinMetricsOptions) -> Result<()> {
+    let addr = match &opts.listen {
+        Some(addr) => addr.clone(),
+        None => {
+            std::io::stdout().write_all(&scrape(&opts)?)?;
+            return Ok(());
+        }
+    };
+
+    let listener =
+        TcpListener::bind(&addr).map_err(|e| anyhow!("couldn't listen on {}: {}", addr, e))?;
+    opts.report
+        .info(&format!("serving metrics on http://{}/metrics", addr));
+
+    // Scrapes are served from the last refresh, so a slow pool can't
+    // hold up Prometheus.
+    let latest = Arc::new(Mutex::new(scrape(&opts)?));
+    let report = opts.report.clone();
+    {
+        let latest = latest.clone();
+        thread::spawn(move || loop {
+            thread::sleep(opts.interval);
+            match scrape(&opts) {
+                Ok(buf) => *latest.lock().unwrap() = buf,
+                Err(e) => opts.report.info(&format!("refresh failed: {}", e)),
+            }
+        });
+    }
+
+    for stream in listener.incoming() {
+        let r = stream
+            .map_err(anyhow::Error::from)
+            .and_then(|s| respond(s, &latest));
+        if let Err(e) = r {
+            report.debug(&format!("request failed: {}", e));
+        }
+    }
+    Ok(())
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pool() {
+        let p: Pool = "fast=/dev/vg/pool_tmeta".parse().unwrap();
+        assert_eq!(p.name, "fast");
+        assert_eq!(p.input, PathBuf::from("/dev/vg/pool_tmeta"));
+
+        let p: Pool = "/dev/vg/pool_tmeta".parse().unwrap();
+        assert_eq!(p.name, "/dev/vg/pool_tmeta");
+
+        assert!("=/dev/vg/pool_tmeta".parse::<Pool>().is_err());
+        assert!("fast=".parse::<Pool>().is_err());
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label(r#"a"b\c"#), r#"a\"b\\c"#);
+    }
+
+    fn sample_stats() -> ThinStats {
+        let tree = TreeStats {
+            height: 1,
+            nr_internal: 0,
+            nr_leaves: 1,
+            nr_entries: 2,
+        };
+        ThinStats {
+            transaction_id: 7,
+            time: 2,
+            data_block_size: 128,
+            nr_metadata_blocks: 1024,
+            details: tree,
+            mapping_roots: tree,
+            devices: vec![DeviceStats {
+                dev_id: 3,
+                mapped_blocks: 40,
+                transaction_id: 1,
+                creation_time: 0,
+                snap_time: 0,
+                mappings: tree,
+            }],
+            metadata_sm: SpaceMapStats::default(),
+            data_sm: SpaceMapStats {
+                nr_blocks: 100,
+                nr_allocated: 40,
+                nr_free: 60,
+                nr_free_runs: 2,
+                largest_free_run: 45,
+            },
+        }
+    }
+
+    #[test]
+    fn test_write_metrics() {
+        let good: Pool = "good=/dev/a".parse().unwrap();
+        let bad: Pool = "bad=/dev/b".parse().unwrap();
+        let pools = vec![(&good, Ok(sample_stats())), (&bad, Err(anyhow!("eio")))];
+
+        let mut buf = Vec::new();
+        write_metrics(&mut buf, &pools).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+
+        // Each family is declared once, with the samples of every pool.
+        assert_eq!(text.matches("# TYPE thin_pool_up gauge").count(), 1);
+        assert!(text.contains("thin_pool_up{pool=\"good\"} 1\n"));
+        assert!(text.contains("thin_pool_up{pool=\"bad\"} 0\n"));
+        assert_eq!(text.matches("pool=\"bad\"").count(), 1);
+
+        assert!(text.contains("thin_pool_transaction_id{pool=\"good\"} 7\n"));
+        assert!(text.contains("thin_pool_free_blocks{pool=\"good\",space_map=\"data\"} 60\n"));
+        assert!(text.contains(
+            "thin_pool_fragmentation_ratio{pool=\"good\",space_map=\"data\"} 0.250000\n"
+        ));
+        assert!(text.contains("thin_pool_btree_entries{pool=\"good\",tree=\"details\"} 2\n"));
+        assert!(text.contains("thin_device_mapped_blocks{pool=\"good\",dev_id=\"3\"} 40\n"));
+    }
+}
+
+//------------------------------------------
@@ -1,13 +1,26 @@
+pub mod bench;
 pub mod block_time;
+pub mod canonical;
 pub mod check;
+pub mod damage;
+pub mod delta;
 pub mod device_detail;
 pub mod dump;
+pub mod genealogy;
+pub mod human;
+pub mod index;
 pub mod ir;
+pub mod labels;
 pub mod metadata;
+pub mod metadata_diff;
+pub mod metadata_id;
 pub mod metadata_repair;
 pub mod metadata_size;
+pub mod metrics;
 pub mod repair;
 pub mod restore;
 pub mod runs;
+pub mod stat;
 pub mod superblock;
+pub mod validate;
 pub mod xml;
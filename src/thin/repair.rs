@@ -1,10 +1,18 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader};
 use std::path::Path;
 use std::sync::Arc;
+use tracing::instrument;
 
 use crate::io_engine::*;
+use crate::memory;
+use crate::pdata::btree_walker::*;
+use crate::pdata::space_map_common::*;
 use crate::pdata::space_map_metadata::*;
+use crate::pdata::unpack::*;
 use crate::report::*;
+use crate::thin::device_detail::*;
 use crate::thin::dump::*;
 use crate::thin::metadata::*;
 use crate::thin::metadata_repair::*;
@@ -18,8 +26,11 @@ pub struct ThinRepairOptions<'a> {
     pub input: &'a Path,
     pub output: &'a Path,
     pub async_io: bool,
+    pub nr_io_threads: usize,
     pub report: Arc<Report>,
     pub overrides: SuperblockOverrides,
+    pub backup_superblock: bool,
+    pub interactive: bool,
 }
 
 struct Context {
@@ -38,9 +49,8 @@ fn new_context(opts: &ThinRepairOptions) -> Result<Context> {
         engine_in = Arc::new(AsyncIoEngine::new(opts.input, MAX_CONCURRENT_IO, false)?);
         engine_out = Arc::new(AsyncIoEngine::new(opts.output, MAX_CONCURRENT_IO, true)?);
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
-        engine_in = Arc::new(SyncIoEngine::new(opts.input, nr_threads, false)?);
-        engine_out = Arc::new(SyncIoEngine::new(opts.output, nr_threads, true)?);
+        engine_in = Arc::new(SyncIoEngine::new(opts.input, opts.nr_io_threads, false)?);
+        engine_out = Arc::new(SyncIoEngine::new(opts.output, opts.nr_io_threads, true)?);
     }
 
     Ok(Context {
@@ -52,18 +62,43 @@ fn new_context(opts: &ThinRepairOptions) -> Result<Context> {
 
 //------------------------------------------
 
+#[instrument(skip_all)]
 pub fn repair(opts: ThinRepairOptions) -> Result<()> {
     let ctx = new_context(&opts)?;
 
+    if opts.interactive {
+        let mut dialogue = Dialogue::new(ctx.report.clone());
+        return repair_interactively(ctx, &opts, &mut dialogue);
+    }
+
     let sb = read_or_rebuild_superblock(
         ctx.engine_in.clone(),
         ctx.report.clone(),
         SUPERBLOCK_LOCATION,
         &opts.overrides,
     )?;
+
+    // The repaired metadata is written with a fresh superblock, so any
+    // features the input has would be lost.
+    check_features(&sb, &ctx.report, true)?;
     let md = build_metadata(ctx.engine_in.clone(), &sb)?;
+    report_synthesised_details(&sb, &md, &ctx.report);
     let md = optimise_metadata(md)?;
 
+    write_metadata(ctx, &opts, &sb, &md, &opts.overrides)
+}
+
+fn write_metadata(
+    ctx: Context,
+    opts: &ThinRepairOptions,
+    sb: &Superblock,
+    md: &Metadata,
+    overrides: &SuperblockOverrides,
+) -> Result<()> {
+    let _metadata_sm_claim = memory::claim(
+        core_metadata_sm_bytes(ctx.engine_out.get_nr_blocks(), u32::MAX),
+        "metadata space map",
+    )?;
     let sm = core_metadata_sm(ctx.engine_out.get_nr_blocks(), u32::MAX);
     let mut w = WriteBatcher::new(
         ctx.engine_out.clone(),
@@ -71,8 +106,216 @@ pub fn repair(opts: ThinRepairOptions) -> Result<()> {
         ctx.engine_out.get_batch_size(),
     );
     let mut restorer = Restorer::new(&mut w, ctx.report);
+    if opts.backup_superblock {
+        restorer.keep_backup_superblock()?;
+    }
+
+    dump_metadata(ctx.engine_in, &mut restorer, sb, md, overrides)
+}
+
+//------------------------------------------
+
+// Puts questions to the user on stdout, and reads the answers from stdin.
+struct Dialogue {
+    report: Arc<Report>,
+    input: Box<dyn BufRead>,
+}
+
+impl Dialogue {
+    fn new(report: Arc<Report>) -> Dialogue {
+        Dialogue {
+            report,
+            input: Box::new(BufReader::new(io::stdin())),
+        }
+    }
+
+    fn say(&self, txt: &str) {
+        self.report.to_stdout(txt);
+    }
+
+    fn ask(&mut self, question: &str) -> Result<String> {
+        self.say(question);
+        let mut answer = String::new();
+        if self.input.read_line(&mut answer)? == 0 {
+            return Err(anyhow!("no answer given, nothing was written"));
+        }
+        Ok(answer.trim().to_string())
+    }
+
+    fn confirm(&mut self, question: &str) -> Result<bool> {
+        let answer = self.ask(&format!("{} [y/N]", question))?;
+        Ok(matches!(answer.to_lowercase().as_str(), "y" | "yes"))
+    }
+
+    // Stops the repair, before anything is written, unless the user agrees.
+    fn proceed(&mut self, question: &str) -> Result<()> {
+        if self.confirm(question)? {
+            Ok(())
+        } else {
+            Err(anyhow!("repair abandoned, nothing was written"))
+        }
+    }
+
+    // A blank answer takes the default, if there is one.
+    fn ask_number(&mut self, question: &str, default: Option<u64>) -> Result<u64> {
+        loop {
+            let answer = match default {
+                Some(n) => self.ask(&format!("{} [{}]", question, n))?,
+                None => self.ask(question)?,
+            };
+            match (answer.parse::<u64>(), default) {
+                (Ok(n), _) => return Ok(n),
+                (Err(_), Some(n)) if answer.is_empty() => return Ok(n),
+                _ => self.say(&format!("'{}' isn't a number.", answer)),
+            }
+        }
+    }
+
+    // Returns an index into the choices, which are numbered from 1.
+    fn choose(&mut self, question: &str, nr_choices: usize) -> Result<usize> {
+        loop {
+            let n = self.ask_number(&format!("{} (1-{})", question, nr_choices), Some(1))?;
+            if (1..=nr_choices as u64).contains(&n) {
+                return Ok(n as usize - 1);
+            }
+            self.say(&format!("There's no choice {}.", n));
+        }
+    }
+}
+
+fn repair_interactively(
+    ctx: Context,
+    opts: &ThinRepairOptions,
+    dialogue: &mut Dialogue,
+) -> Result<()> {
+    let mut overrides = SuperblockOverrides {
+        transaction_id: opts.overrides.transaction_id,
+        data_block_size: opts.overrides.data_block_size,
+        nr_data_blocks: opts.overrides.nr_data_blocks,
+    };
+
+    let (sb, lost) = match examine_superblock(
+        ctx.engine_in.clone(),
+        ctx.report.clone(),
+        SUPERBLOCK_LOCATION,
+    ) {
+        SuperblockState::Intact(sb) => {
+            dialogue.say(&format!(
+                "The superblock is intact: transaction id {}, data block size {}.",
+                sb.transaction_id, sb.data_block_size
+            ));
+            (sb, BTreeSet::new())
+        }
+        SuperblockState::Damaged { reason, reference } => {
+            dialogue.say(&format!("The superblock can't be used: {}", reason));
+            choose_superblock(&ctx, dialogue, reference, &mut overrides)?
+        }
+    };
+
+    check_features(&sb, &ctx.report, true)?;
+
+    if !lost.is_empty() {
+        let ids: Vec<String> = lost.iter().map(|id| id.to_string()).collect();
+        dialogue.say(&format!(
+            "These devices aren't in the chosen trees, and will be dropped: {}",
+            ids.join(", ")
+        ));
+        dialogue.proceed("Drop them?")?;
+    }
+
+    let md = build_metadata(ctx.engine_in.clone(), &sb)?;
+    report_synthesised_details(&sb, &md, &ctx.report);
+    let md = optimise_metadata(md)?;
+
+    let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+    dialogue.say(&format!(
+        "The repaired metadata holds {} devices, with transaction id {}, data block size {} and {} data blocks.",
+        md.devs.len(),
+        overrides.transaction_id.unwrap_or(sb.transaction_id),
+        overrides.data_block_size.unwrap_or(sb.data_block_size),
+        overrides.nr_data_blocks.unwrap_or(data_root.nr_blocks),
+    ));
+    dialogue.proceed(&format!(
+        "Overwrite {} with the repaired metadata?",
+        opts.output.display()
+    ))?;
+
+    write_metadata(ctx, opts, &sb, &md, &overrides)
+}
+
+// Rebuilds the superblock around a pair of roots the user picks, and
+// returns it with the devices the other pairs have that it lacks.
+fn choose_superblock(
+    ctx: &Context,
+    dialogue: &mut Dialogue,
+    reference: Option<Superblock>,
+    overrides: &mut SuperblockOverrides,
+) -> Result<(Superblock, BTreeSet<u64>)> {
+    if overrides.data_block_size.is_none() && reference.is_none() {
+        let bs = dialogue.ask_number(
+            "The data block size can't be recovered.  What is it, in sectors?",
+            None,
+        )?;
+        overrides.data_block_size = Some(bs as u32);
+    }
+
+    dialogue.say("Searching the metadata for btree roots ...");
+    let candidates = find_all_roots(ctx.engine_in.clone(), ctx.report.clone())?;
+    if candidates.is_empty() {
+        return Err(anyhow!("no compatible roots found"));
+    }
+
+    dialogue.say("The superblock could be rebuilt from these roots, most recent first:");
+    let mut ids = Vec::new();
+    for (i, c) in candidates.iter().enumerate() {
+        // A details root of 0 means the details tree was lost, so the
+        // devices are those in the mapping tree.
+        let mut path = vec![0];
+        let details = if c.details_root == 0 {
+            ids.push(btree_to_key_set::<u64>(
+                &mut path,
+                ctx.engine_in.clone(),
+                true,
+                c.mapping_root,
+            )?);
+            "no details root (to be synthesised)".to_string()
+        } else {
+            ids.push(btree_to_key_set::<DeviceDetail>(
+                &mut path,
+                ctx.engine_in.clone(),
+                true,
+                c.details_root,
+            )?);
+            format!("details root {}", c.details_root)
+        };
+        dialogue.say(&format!(
+            "  {}) mapping root {}, {}: {} devices, {} mappings, transaction id {}",
+            i + 1,
+            c.mapping_root,
+            details,
+            c.nr_devices,
+            c.nr_mappings,
+            c.transaction_id
+        ));
+    }
+    let choice = dialogue.choose("Which roots should be used?", candidates.len())?;
+
+    let sb = rebuild_superblock_from(reference.as_ref(), &candidates[choice], overrides)?;
+    let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+    overrides.transaction_id =
+        Some(dialogue.ask_number("Transaction id?", Some(sb.transaction_id))?);
+    overrides.nr_data_blocks =
+        Some(dialogue.ask_number("Number of data blocks?", Some(data_root.nr_blocks))?);
+
+    let kept = &ids[choice];
+    let lost = ids
+        .iter()
+        .flatten()
+        .filter(|id| !kept.contains(id))
+        .cloned()
+        .collect();
 
-    dump_metadata(ctx.engine_in, &mut restorer, &sb, &md, &opts.overrides)
+    Ok((sb, lost))
 }
 
 //------------------------------------------
@@ -5,8 +5,11 @@ use std::fs::OpenOptions;
 use std::ops::Deref;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use tracing::{instrument, Span};
 
+use crate::file_utils;
 use crate::io_engine::*;
+use crate::memory;
 use crate::pdata::btree_builder::*;
 use crate::pdata::space_map::*;
 use crate::pdata::space_map_common::pack_root;
@@ -14,8 +17,13 @@ use crate::pdata::space_map_disk::*;
 use crate::pdata::space_map_metadata::*;
 use crate::report::*;
 use crate::thin::block_time::*;
+use crate::thin::canonical::*;
 use crate::thin::device_detail::*;
+use crate::thin::dump::dump_metadata;
 use crate::thin::ir::{self, MetadataVisitor, Visit};
+use crate::thin::labels::*;
+use crate::thin::metadata::build_unshared_metadata;
+use crate::thin::metadata_repair::SuperblockOverrides;
 use crate::thin::superblock::{self, *};
 use crate::thin::xml;
 use crate::write_batcher::*;
@@ -80,7 +88,16 @@ pub struct Restorer<'a> {
     sb: Option<ir::Superblock>,
     devices: BTreeMap<u32, (DeviceDetail, u64)>,
     data_sm: Option<Arc<Mutex<dyn SpaceMap>>>,
+    _data_sm_claim: Option<memory::Claim>,
     in_section: Section,
+
+    // Where to write a backup copy of the superblock, if requested
+    backup_loc: Option<u64>,
+
+    labels: LabelMap,
+
+    // Replace the values in the xml's superblock
+    overrides: SuperblockOverrides,
 }
 
 impl<'a> Restorer<'a> {
@@ -94,8 +111,58 @@ impl<'a> Restorer<'a> {
             sb: None,
             devices: BTreeMap::new(),
             data_sm: None,
+            _data_sm_claim: None,
             in_section: Section::None,
+            backup_loc: None,
+            labels: LabelMap::new(),
+            overrides: SuperblockOverrides {
+                transaction_id: None,
+                data_block_size: None,
+                nr_data_blocks: None,
+            },
+        }
+    }
+
+    pub fn set_overrides(&mut self, overrides: SuperblockOverrides) {
+        self.overrides = overrides;
+    }
+
+    /// Reserves the last metadata block for a backup copy of the
+    /// superblock, which is written once the restore completes.
+    pub fn keep_backup_superblock(&mut self) -> Result<()> {
+        let nr_blocks = self.w.sm.lock().unwrap().get_nr_blocks()?;
+        if nr_blocks <= 1 {
+            return Err(anyhow!("no room for a backup superblock"));
         }
+
+        let loc = backup_superblock_location(nr_blocks);
+        self.w.pin(loc)?;
+        self.backup_loc = Some(loc);
+        Ok(())
+    }
+
+    // A backup left by an earlier restore would describe the wrong metadata.
+    fn clear_stale_backup(&mut self) -> Result<()> {
+        let engine = self.w.engine.as_ref();
+        if let Ok(backup) = read_backup_superblock(engine) {
+            if !self.w.get_reserved_range().contains(&backup.block) {
+                engine.write(&Block::zeroed(backup.block))?;
+            }
+        }
+        Ok(())
+    }
+
+    // The label blocks are claimed before the space maps are built, so
+    // they're recorded as in use.
+    fn pin_labels(&mut self) -> Result<Vec<Block>> {
+        let nr_blocks = self.w.sm.lock().unwrap().get_nr_blocks()?;
+        let blocks = pack_labels(&self.labels, labels_end(nr_blocks))?;
+        for b in &blocks {
+            self.w
+                .pin(b.loc)
+                .map_err(|_| anyhow!("no room for the device labels at the end of the metadata"))?;
+        }
+        Ok(blocks)
     }
 
     fn begin_section(&mut self, section: MappedSection) -> Result<Visit> {
@@ -170,6 +237,7 @@ impl<'a> Restorer<'a> {
         let (details_root, mapping_root) = self.build_device_details()?;
 
         self.release_subtrees()?;
+        let label_blocks = self.pin_labels()?;
 
         // Build data space map
         let data_sm = self.data_sm.as_ref().unwrap();
@@ -181,8 +249,12 @@ impl<'a> Restorer<'a> {
 
         // Write the superblock
         let sb = superblock::Superblock {
-            flags: SuperblockFlags { needs_check: false },
+            flags: SuperblockFlags {
+                needs_check: false,
+                unknown: 0,
+            },
             block: SUPERBLOCK_LOCATION,
+            uuid: parse_uuid(&src_sb.uuid)?,
             version: 2,
             time: src_sb.time as u32,
             transaction_id: src_sb.transaction,
@@ -193,8 +265,22 @@ impl<'a> Restorer<'a> {
             details_root,
             data_block_size: src_sb.data_block_size,
             nr_metadata_blocks: metadata_sm.nr_blocks,
+            compat_flags: 0,
+            compat_ro_flags: 0,
+            incompat_flags: 0,
+            backup_superblock: self.backup_loc.unwrap_or(0),
+            labels_root: label_blocks.first().map(|b| b.loc).unwrap_or(0),
         };
+        for b in &label_blocks {
+            self.w.engine.write(b)?;
+        }
+
         write_superblock(self.w.engine.as_ref(), SUPERBLOCK_LOCATION, &sb)?;
+        if let Some(loc) = self.backup_loc {
+            write_backup_superblock(self.w.engine.as_ref(), loc, &sb)?;
+        } else {
+            self.clear_stale_backup()?;
+        }
         self.in_section = Section::Finalized;
 
         Ok(())
@@ -207,11 +293,16 @@ impl<'a> MetadataVisitor for Restorer<'a> {
             return Err(anyhow!("duplicated superblock"));
         }
 
+        let sb = apply_overrides(sb, &self.overrides);
         if !(128..=2097152).contains(&sb.data_block_size) || (sb.data_block_size & 0x7F != 0) {
             return Err(anyhow!("invalid data block size"));
         }
 
         self.sb = Some(sb.clone());
+        self._data_sm_claim = Some(memory::claim(
+            core_sm_bytes(sb.nr_data_blocks, u32::MAX),
+            "data space map",
+        )?);
         self.data_sm = Some(core_sm(sb.nr_data_blocks, u32::MAX));
         let b = self.w.alloc()?;
         if b.loc != SUPERBLOCK_LOCATION {
@@ -250,7 +341,7 @@ impl<'a> MetadataVisitor for Restorer<'a> {
             return Err(anyhow!("missing superblock"));
         }
         self.report
-            .info(&format!("building btree for device {}", d.dev_id));
+            .verbose(&format!("building btree for device {}", d.dev_id));
         self.current_dev = Some(DeviceDetail {
             mapped_blocks: d.mapped_blocks,
             transaction_id: d.transaction,
@@ -276,7 +367,40 @@ impl<'a> MetadataVisitor for Restorer<'a> {
         }
     }
 
+    fn device_labels(&mut self, labels: &ir::DeviceLabels) -> Result<Visit> {
+        let thin_id = match (&self.in_section, self.current_map.as_ref()) {
+            (Section::Device, Some((MappedSection::Dev(thin_id), _))) => *thin_id,
+            _ => {
+                return Err(anyhow!(
+                    "<labels> tags may only occur within <device> sections."
+                ))
+            }
+        };
+
+        check_labels(labels).map_err(|e| anyhow!("device {}: {}", thin_id, e))?;
+        if self.labels.insert(thin_id, labels.clone()).is_some() {
+            return Err(anyhow!(
+                "device {} has more than one set of labels",
+                thin_id
+            ));
+        }
+        Ok(Visit::Continue)
+    }
+
     fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        let nr_data_blocks = self.sb.as_ref().map_or(0, |sb| sb.nr_data_blocks);
+        match m.data_begin.checked_add(m.len) {
+            Some(end) if end <= nr_data_blocks => {}
+            _ => {
+                return Err(anyhow!(
+                    "mapping of data blocks {}..+{} is past the end of the pool ({} blocks)",
+                    m.data_begin,
+                    m.len,
+                    nr_data_blocks
+                ))
+            }
+        }
+
         if let Some((_, builder)) = self.current_map.as_mut() {
             for i in 0..m.len {
                 let bt = BlockTime {
@@ -327,6 +451,20 @@ impl<'a> MetadataVisitor for Restorer<'a> {
 
 //------------------------------------------
 
+fn apply_overrides(sb: &ir::Superblock, overrides: &SuperblockOverrides) -> ir::Superblock {
+    let mut sb = sb.clone();
+    if let Some(tid) = overrides.transaction_id {
+        sb.transaction = tid;
+    }
+    if let Some(bs) = overrides.data_block_size {
+        sb.data_block_size = bs;
+    }
+    if let Some(nr) = overrides.nr_data_blocks {
+        sb.nr_data_blocks = nr;
+    }
+    sb
+}
+
 /// Writes a data space map to disk.  Returns the space map root that needs
 /// to be written to the superblock.
 fn build_data_sm(w: &mut WriteBatcher, sm: &dyn SpaceMap) -> Result<Vec<u8>> {
@@ -342,7 +480,11 @@ pub struct ThinRestoreOptions<'a> {
     pub input: &'a Path,
     pub output: &'a Path,
     pub async_io: bool,
+    pub nr_io_threads: usize,
     pub report: Arc<Report>,
+    pub verify: bool,
+    pub backup_superblock: bool,
+    pub overrides: SuperblockOverrides,
 }
 
 struct Context {
@@ -358,8 +500,7 @@ fn new_context(opts: &ThinRestoreOptions) -> Result<Context> {
     if opts.async_io {
         engine = Arc::new(AsyncIoEngine::new(opts.output, MAX_CONCURRENT_IO, true)?);
     } else {
-        let nr_threads = std::cmp::max(8, num_cpus::get() * 2);
-        engine = Arc::new(SyncIoEngine::new(opts.output, nr_threads, true)?);
+        engine = Arc::new(SyncIoEngine::new(opts.output, opts.nr_io_threads, true)?);
     }
 
     Ok(Context {
@@ -370,6 +511,7 @@ fn new_context(opts: &ThinRestoreOptions) -> Result<Context> {
 
 //------------------------------------------
 
+#[instrument(skip_all, fields(nr_metadata_blocks, nr_allocated))]
 pub fn restore(opts: ThinRestoreOptions) -> Result<()> {
     let input = OpenOptions::new()
         .read(true)
@@ -377,14 +519,104 @@ pub fn restore(opts: ThinRestoreOptions) -> Result<()> {
         .open(opts.input)?;
 
     let ctx = new_context(&opts)?;
+    Span::current().record("nr_metadata_blocks", ctx.engine.get_nr_blocks());
     let max_count = u32::MAX;
 
+    let _metadata_sm_claim = memory::claim(
+        core_metadata_sm_bytes(ctx.engine.get_nr_blocks(), max_count),
+        "metadata space map",
+    )?;
     let sm = core_metadata_sm(ctx.engine.get_nr_blocks(), max_count);
     let mut w = WriteBatcher::new(ctx.engine.clone(), sm.clone(), ctx.engine.get_batch_size());
+    let report = ctx.report.clone();
     let mut restorer = Restorer::new(&mut w, ctx.report);
-    xml::read(input, &mut restorer)?;
+    if opts.backup_superblock {
+        restorer.keep_backup_superblock()?;
+    }
+    restorer.set_overrides(opts.overrides.clone());
+    xml::read_with_report(input, &mut restorer, &report)?;
+    Span::current().record("nr_allocated", sm.lock().unwrap().get_nr_allocated()?);
+
+    // Only the blocks the restore wrote should take up space, rather
+    // than whatever the output held before.  This waits until the
+    // restore has succeeded, so a failure leaves the old contents of
+    // the unwritten blocks in place.
+    punch_unused(opts.output, &*sm.lock().unwrap())?;
+
+    if opts.verify {
+        report.verbose("verifying restored metadata");
+        verify(opts.input, &opts.overrides, ctx.engine)?;
+    }
 
     Ok(())
 }
 
+// Deallocates the runs of blocks that the new metadata doesn't use.
+// Block devices are left alone.
+fn punch_unused(path: &Path, sm: &dyn SpaceMap) -> Result<()> {
+    if !file_utils::is_file(path) {
+        return Ok(());
+    }
+
+    let file = OpenOptions::new().write(true).open(path)?;
+    let nr_blocks = sm.get_nr_blocks()?;
+    let mut b = 0;
+    while b < nr_blocks {
+        if sm.get(b)? != 0 {
+            b += 1;
+            continue;
+        }
+
+        let begin = b;
+        while b < nr_blocks && sm.get(b)? == 0 {
+            b += 1;
+        }
+        file_utils::punch_hole(
+            &file,
+            begin * BLOCK_SIZE as u64,
+            (b - begin) * BLOCK_SIZE as u64,
+        )?;
+    }
+
+    Ok(())
+}
+
+// Reads back the metadata just written and checks it holds the same
+// mappings as the xml, so builder bugs are caught before the metadata
+// is handed to the kernel.
+fn verify(
+    input: &Path,
+    overrides: &SuperblockOverrides,
+    engine: Arc<dyn IoEngine + Send + Sync>,
+) -> Result<()> {
+    let mut expected = CanonicalBuilder::new();
+    xml::read(OpenOptions::new().read(true).open(input)?, &mut expected)?;
+    let mut expected = expected.complete();
+    if let Some(sb) = expected.sb.as_mut() {
+        sb.transaction = overrides.transaction_id.unwrap_or(sb.transaction);
+        sb.data_block_size = overrides.data_block_size.unwrap_or(sb.data_block_size);
+        sb.nr_data_blocks = overrides.nr_data_blocks.unwrap_or(sb.nr_data_blocks);
+    }
+
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let md = build_unshared_metadata(engine.clone(), &sb)?;
+    let mut actual = CanonicalBuilder::new();
+    dump_metadata(
+        engine,
+        &mut actual,
+        &sb,
+        &md,
+        &SuperblockOverrides {
+            transaction_id: None,
+            data_block_size: None,
+            nr_data_blocks: None,
+        },
+    )?;
+
+    match first_difference(&expected, &actual.complete()) {
+        Some(msg) => Err(anyhow!("verification of restored metadata failed: {}", msg)),
+        None => Ok(()),
+    }
+}
+
 //------------------------------------------
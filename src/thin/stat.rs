@@ -0,0 +1,376 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::checksum;
+use crate::io_engine::*;
+use crate::pdata::btree::{self, *};
+use crate::pdata::btree_walker::*;
+use crate::pdata::space_map_common::*;
+use crate::pdata::space_map_metadata::*;
+use crate::pdata::unpack::*;
+use crate::thin::block_time::*;
+use crate::thin::device_detail::*;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+const MAX_CONCURRENT_IO: u32 = 1024;
+
+//------------------------------------------
+
+pub struct ThinStatOptions<'a> {
+    pub input: &'a Path,
+    pub async_io: bool,
+    pub nr_io_threads: usize,
+
+    /// Read the metadata snapshot held by the kernel, rather than the
+    /// live superblock, so a running pool can be inspected.
+    pub use_metadata_snap: bool,
+}
+
+/// The shape of a btree.  A tree with just a root leaf has height 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    pub height: u32,
+    pub nr_internal: u64,
+    pub nr_leaves: u64,
+    pub nr_entries: u64,
+}
+
+pub struct DeviceStats {
+    pub dev_id: u64,
+    pub mapped_blocks: u64,
+    pub transaction_id: u64,
+    pub creation_time: u32,
+    pub snap_time: u32,
+    pub mappings: TreeStats,
+}
+
+/// Free space in a space map, as read from its bitmaps.  A run is a
+/// stretch of adjacent free blocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpaceMapStats {
+    pub nr_blocks: u64,
+    pub nr_allocated: u64,
+    pub nr_free: u64,
+    pub nr_free_runs: u64,
+    pub largest_free_run: u64,
+}
+
+impl SpaceMapStats {
+    /// How much of the free space lies outside the largest run, from 0.0
+    /// for a single run to nearly 1.0 when it's scattered block by block.
+    pub fn fragmentation(&self) -> f64 {
+        if self.nr_free == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free_run as f64 / self.nr_free as f64
+        }
+    }
+}
+
+pub struct ThinStats {
+    pub transaction_id: u64,
+    pub time: u32,
+    pub data_block_size: u32,
+    pub nr_metadata_blocks: u64,
+    pub details: TreeStats,
+
+    /// The top level of the mapping tree, which maps each device to
+    /// the root of its own tree.
+    pub mapping_roots: TreeStats,
+    pub devices: Vec<DeviceStats>,
+    pub metadata_sm: SpaceMapStats,
+    pub data_sm: SpaceMapStats,
+}
+
+//------------------------------------------
+
+/// Walks a btree a level at a time, so each level is a single batch of
+/// io.  Shared nodes are counted every time they're reached.
+pub fn tree_stats<V: Unpack>(engine: &dyn IoEngine, root: u64) -> Result<TreeStats> {
+    let mut stats = TreeStats::default();
+    let mut level = vec![root];
+    let mut is_root = true;
+
+    while !level.is_empty() {
+        stats.height += 1;
+        let mut next = Vec::new();
+        for chunk in level.chunks(engine.get_batch_size()) {
+            let blocks = engine.read_many(chunk)?;
+            for (loc, b) in chunk.iter().zip(blocks) {
+                let b = b.map_err(|_| anyhow!("unable to read btree node {}", loc))?;
+                match unpack_node::<V>(&[0], b.get_data(), false, is_root)? {
+                    btree::Node::Internal { values, .. } => {
+                        stats.nr_internal += 1;
+                        next.extend(values);
+                    }
+                    btree::Node::Leaf { keys, .. } => {
+                        stats.nr_leaves += 1;
+                        stats.nr_entries += keys.len() as u64;
+                    }
+                }
+            }
+        }
+        level = next;
+        is_root = false;
+    }
+
+    Ok(stats)
+}
+
+// Tallies the free blocks, in order, across all the bitmaps.
+#[derive(Default)]
+struct FreeRuns {
+    nr_free: u64,
+    nr_runs: u64,
+    largest: u64,
+    current: u64,
+}
+
+impl FreeRuns {
+    fn push(&mut self, free: bool) {
+        if free {
+            if self.current == 0 {
+                self.nr_runs += 1;
+            }
+            self.current += 1;
+            self.nr_free += 1;
+            self.largest = u64::max(self.largest, self.current);
+        } else {
+            self.current = 0;
+        }
+    }
+}
+
+fn space_map_stats(
+    engine: &dyn IoEngine,
+    root: &SMRoot,
+    entries: &[IndexEntry],
+) -> Result<SpaceMapStats> {
+    let mut runs = FreeRuns::default();
+    let mut blocknr = 0;
+
+    let locs: Vec<u64> = entries.iter().map(|ie| ie.blocknr).collect();
+    for chunk in locs.chunks(engine.get_batch_size()) {
+        let blocks = engine.read_many(chunk)?;
+        for (loc, b) in chunk.iter().zip(blocks) {
+            let b = b.map_err(|_| anyhow!("unable to read bitmap block {}", loc))?;
+            if checksum::metadata_block_type(b.get_data()) != checksum::BT::BITMAP {
+                return Err(anyhow!("block {} isn't a bitmap", loc));
+            }
+
+            let bitmap = unpack::<Bitmap>(b.get_data())?;
+            for e in bitmap.entries.iter() {
+                if blocknr >= root.nr_blocks {
+                    break;
+                }
+                runs.push(*e == BitmapEntry::Small(0));
+                blocknr += 1;
+            }
+        }
+    }
+
+    Ok(SpaceMapStats {
+        nr_blocks: root.nr_blocks,
+        nr_allocated: root.nr_allocated,
+        nr_free: runs.nr_free,
+        nr_free_runs: runs.nr_runs,
+        largest_free_run: runs.largest,
+    })
+}
+
+fn metadata_sm_stats(engine: &dyn IoEngine, sb: &Superblock) -> Result<SpaceMapStats> {
+    let root = unpack_root(&sb.metadata_sm_root)?;
+    let b = engine.read(root.bitmap_root)?;
+    let entries = unpack::<MetadataIndex>(b.get_data())?.indexes;
+    space_map_stats(engine, &root, &entries)
+}
+
+fn data_sm_stats(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+) -> Result<SpaceMapStats> {
+    let root = unpack_root(&sb.data_sm_root)?;
+    let entries =
+        btree_to_map::<IndexEntry>(&mut vec![0], engine.clone(), false, root.bitmap_root)?;
+    let entries: Vec<IndexEntry> = entries.values().cloned().collect();
+    space_map_stats(engine.as_ref(), &root, &entries)
+}
+
+//------------------------------------------
+
+pub fn stat(opts: ThinStatOptions) -> Result<ThinStats> {
+    let engine: Arc<dyn IoEngine + Send + Sync> = if opts.async_io {
+        Arc::new(AsyncIoEngine::new(opts.input, MAX_CONCURRENT_IO, false)?)
+    } else {
+        Arc::new(SyncIoEngine::new(opts.input, opts.nr_io_threads, false)?)
+    };
+
+    let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    if opts.use_metadata_snap {
+        if sb.metadata_snap == 0 {
+            return Err(anyhow!("no metadata snapshot is held"));
+        }
+        sb = read_superblock(engine.as_ref(), sb.metadata_snap)?;
+    }
+
+    let details =
+        btree_to_map::<DeviceDetail>(&mut vec![0], engine.clone(), false, sb.details_root)?;
+    let roots = btree_to_map::<u64>(&mut vec![0], engine.clone(), false, sb.mapping_root)?;
+
+    let mut devices = Vec::with_capacity(details.len());
+    for (dev_id, detail) in &details {
+        let root = roots
+            .get(dev_id)
+            .ok_or_else(|| anyhow!("device {} has no mapping tree", dev_id))?;
+        devices.push(DeviceStats {
+            dev_id: *dev_id,
+            mapped_blocks: detail.mapped_blocks,
+            transaction_id: detail.transaction_id,
+            creation_time: detail.creation_time,
+            snap_time: detail.snapshotted_time,
+            mappings: tree_stats::<BlockTime>(engine.as_ref(), *root)?,
+        });
+    }
+
+    Ok(ThinStats {
+        transaction_id: sb.transaction_id,
+        time: sb.time,
+        data_block_size: sb.data_block_size,
+        nr_metadata_blocks: sb.nr_metadata_blocks,
+        details: tree_stats::<DeviceDetail>(engine.as_ref(), sb.details_root)?,
+        mapping_roots: tree_stats::<u64>(engine.as_ref(), sb.mapping_root)?,
+        devices,
+        metadata_sm: metadata_sm_stats(engine.as_ref(), &sb)?,
+        data_sm: data_sm_stats(engine, &sb)?,
+    })
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    use crate::report::*;
+    use crate::thin::metadata_repair::SuperblockOverrides;
+    use crate::thin::restore::{restore, ThinRestoreOptions};
+
+    fn free_runs(bits: &[u8]) -> FreeRuns {
+        let mut runs = FreeRuns::default();
+        for b in bits {
+            runs.push(*b == 0);
+        }
+        runs
+    }
+
+    #[test]
+    fn test_free_runs() {
+        let runs = free_runs(&[1, 0, 0, 1, 0, 1, 0, 0, 0]);
+        assert_eq!(runs.nr_free, 6);
+        assert_eq!(runs.nr_runs, 3);
+        assert_eq!(runs.largest, 3);
+    }
+
+    #[test]
+    fn test_free_runs_all_allocated() {
+        let runs = free_runs(&[1, 1, 1]);
+        assert_eq!((runs.nr_free, runs.nr_runs, runs.largest), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let sm = SpaceMapStats {
+            nr_blocks: 10,
+            nr_allocated: 2,
+            nr_free: 8,
+            nr_free_runs: 2,
+            largest_free_run: 6,
+        };
+        assert!((sm.fragmentation() - 0.25).abs() < 1e-9);
+        assert_eq!(SpaceMapStats::default().fragmentation(), 0.0);
+    }
+
+    const XML: &str = r#"<superblock uuid="" time="2" transaction="7" data_block_size="128" nr_data_blocks="100">
+  <device dev_id="1" mapped_blocks="4" transaction="3" creation_time="0" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="4" time="0"/>
+  </device>
+  <device dev_id="5" mapped_blocks="2" transaction="6" creation_time="1" snap_time="2">
+    <single_mapping origin_block="0" data_block="10" time="1"/>
+    <single_mapping origin_block="8" data_block="20" time="1"/>
+  </device>
+</superblock>
+"#;
+
+    #[test]
+    fn test_stat_restored_metadata() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let xml = dir.path().join("md.xml");
+        let md = dir.path().join("md.bin");
+        File::create(&xml)?.write_all(XML.as_bytes())?;
+        File::create(&md)?.set_len(4 << 20)?;
+
+        restore(ThinRestoreOptions {
+            input: &xml,
+            output: &md,
+            async_io: false,
+            nr_io_threads: 1,
+            report: Arc::new(mk_quiet_report()),
+            verify: false,
+            backup_superblock: false,
+            overrides: SuperblockOverrides {
+                transaction_id: None,
+                data_block_size: None,
+                nr_data_blocks: None,
+            },
+        })?;
+
+        let stats = stat(ThinStatOptions {
+            input: &md,
+            async_io: false,
+            nr_io_threads: 1,
+            use_metadata_snap: false,
+        })?;
+
+        assert_eq!(stats.transaction_id, 7);
+        assert_eq!(stats.time, 2);
+        assert_eq!(stats.data_block_size, 128);
+        assert_eq!(stats.details.nr_entries, 2);
+        assert_eq!(stats.mapping_roots.nr_entries, 2);
+
+        let devs: Vec<(u64, u64, u64)> = stats
+            .devices
+            .iter()
+            .map(|d| (d.dev_id, d.mapped_blocks, d.mappings.nr_entries))
+            .collect();
+        assert_eq!(devs, vec![(1, 4, 4), (5, 2, 2)]);
+        assert_eq!(stats.devices[1].snap_time, 2);
+        assert_eq!(stats.devices[0].mappings.height, 1);
+
+        // Data blocks 0..4, 10 and 20 are in use.
+        let data = stats.data_sm;
+        assert_eq!((data.nr_blocks, data.nr_allocated), (100, 6));
+        assert_eq!(data.nr_free, 94);
+        assert_eq!(data.nr_free_runs, 3);
+        assert_eq!(data.largest_free_run, 79);
+
+        let meta = stats.metadata_sm;
+        assert_eq!(meta.nr_blocks, 1024);
+        assert_eq!(meta.nr_free, meta.nr_blocks - meta.nr_allocated);
+
+        assert!(stat(ThinStatOptions {
+            input: &md,
+            async_io: false,
+            nr_io_threads: 1,
+            use_metadata_snap: true,
+        })
+        .is_err());
+        Ok(())
+    }
+}
+
+//------------------------------------------
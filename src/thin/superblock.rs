@@ -1,22 +1,40 @@
 use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, WriteBytesExt};
 use nom::{bytes::complete::*, number::complete::*, IResult};
+use std::convert::TryInto;
 use std::fmt;
 use std::io::Cursor;
+use tracing::instrument;
 
 use crate::checksum::*;
 use crate::io_engine::*;
+use crate::pdata::space_map_metadata::MAX_METADATA_BLOCKS;
+use crate::report::Report;
 
 //----------------------------------------
 
 pub const MAGIC: u64 = 27022010;
 pub const SUPERBLOCK_LOCATION: u64 = 0;
-const UUID_SIZE: usize = 16;
+pub const UUID_SIZE: usize = 16;
 pub const SPACE_MAP_ROOT_SIZE: usize = 128;
 
+// The metadata versions these tools understand.
+pub const MIN_VERSION: u32 = 1;
+pub const MAX_VERSION: u32 = 2;
+
+const NEEDS_CHECK_FLAG: u32 = 0x1;
+
+// The kernel hasn't defined any feature flags yet, so any that are set
+// come from something newer than these tools.
+const COMPAT_RO_SUPP: u32 = 0;
+const INCOMPAT_SUPP: u32 = 0;
+
 #[derive(Debug, Clone)]
 pub struct SuperblockFlags {
     pub needs_check: bool,
+
+    // Any other bits set, kept so they can be written back.
+    pub unknown: u32,
 }
 
 impl fmt::Display for SuperblockFlags {
@@ -33,7 +51,7 @@ impl fmt::Display for SuperblockFlags {
 pub struct Superblock {
     pub flags: SuperblockFlags,
     pub block: u64,
-    //uuid: [u8; UUID_SIZE],
+    pub uuid: [u8; UUID_SIZE],
     pub version: u32,
     pub time: u32,
     pub transaction_id: u64,
@@ -44,13 +62,25 @@ pub struct Superblock {
     pub details_root: u64,
     pub data_block_size: u32,
     pub nr_metadata_blocks: u64,
+    pub compat_flags: u32,
+    pub compat_ro_flags: u32,
+    pub incompat_flags: u32,
+
+    // Userland fields, after those the kernel knows about.  The kernel
+    // rewrites the superblock in place, so they survive a commit.
+
+    // Where the backup copy is, or 0 if there isn't one.
+    pub backup_superblock: u64,
+
+    // The top block of the device labels, or 0 if there aren't any.
+    pub labels_root: u64,
 }
 
 fn unpack(data: &[u8]) -> IResult<&[u8], Superblock> {
     let (i, _csum) = le_u32(data)?;
     let (i, flags) = le_u32(i)?;
     let (i, block) = le_u64(i)?;
-    let (i, _uuid) = take(16usize)(i)?;
+    let (i, uuid) = take(UUID_SIZE)(i)?;
     let (i, _magic) = le_u64(i)?;
     let (i, version) = le_u32(i)?;
     let (i, time) = le_u32(i)?;
@@ -63,15 +93,21 @@ fn unpack(data: &[u8]) -> IResult<&[u8], Superblock> {
     let (i, data_block_size) = le_u32(i)?;
     let (i, _metadata_block_size) = le_u32(i)?;
     let (i, nr_metadata_blocks) = le_u64(i)?;
+    let (i, compat_flags) = le_u32(i)?;
+    let (i, compat_ro_flags) = le_u32(i)?;
+    let (i, incompat_flags) = le_u32(i)?;
+    let (i, backup_superblock) = le_u64(i)?;
+    let (i, labels_root) = le_u64(i)?;
 
     Ok((
         i,
         Superblock {
             flags: SuperblockFlags {
-                needs_check: (flags & 0x1) != 0,
+                needs_check: (flags & NEEDS_CHECK_FLAG) != 0,
+                unknown: flags & !NEEDS_CHECK_FLAG,
             },
             block,
-            //uuid: uuid[0..UUID_SIZE],
+            uuid: uuid.try_into().unwrap(),
             version,
             time,
             transaction_id,
@@ -82,10 +118,16 @@ fn unpack(data: &[u8]) -> IResult<&[u8], Superblock> {
             details_root,
             data_block_size,
             nr_metadata_blocks,
+            compat_flags,
+            compat_ro_flags,
+            incompat_flags,
+            backup_superblock,
+            labels_root,
         },
     ))
 }
 
+#[instrument(level = "debug", skip(engine))]
 pub fn read_superblock(engine: &dyn IoEngine, loc: u64) -> Result<Superblock> {
     let b = engine.read(loc)?;
 
@@ -102,19 +144,83 @@ pub fn read_superblock(engine: &dyn IoEngine, loc: u64) -> Result<Superblock> {
 
 //------------------------------
 
+// Features that change how the metadata is laid out, so it may be
+// misread.
+fn unreadable_features(sb: &Superblock) -> Vec<String> {
+    let mut features = Vec::new();
+    if sb.version < MIN_VERSION || sb.version > MAX_VERSION {
+        features.push(format!("metadata version {}", sb.version));
+    }
+
+    let incompat = sb.incompat_flags & !INCOMPAT_SUPP;
+    if incompat != 0 {
+        features.push(format!("incompatible feature flags {:#x}", incompat));
+    }
+    features
+}
+
+// Features that would be lost, or left inconsistent, if the metadata
+// were written.
+fn unwritable_features(sb: &Superblock) -> Vec<String> {
+    let mut features = unreadable_features(sb);
+    let compat_ro = sb.compat_ro_flags & !COMPAT_RO_SUPP;
+    if compat_ro != 0 {
+        features.push(format!(
+            "read only compatible feature flags {:#x}",
+            compat_ro
+        ));
+    }
+
+    if sb.flags.unknown != 0 {
+        features.push(format!("superblock flags {:#x}", sb.flags.unknown));
+    }
+    features
+}
+
+/// Errors if the metadata has any features that these tools would drop,
+/// or break, by changing it.
+pub fn check_writable(sb: &Superblock) -> Result<()> {
+    let features = unwritable_features(sb);
+    if !features.is_empty() {
+        return Err(anyhow!(
+            "refusing to modify metadata with unsupported {}",
+            features.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Warns about anything in the superblock that means these tools may
+/// misread the metadata, and calls check_writable() if `write` is set.
+pub fn check_features(sb: &Superblock, report: &Report, write: bool) -> Result<()> {
+    for f in unreadable_features(sb) {
+        report.non_fatal(&format!(
+            "The metadata has {}, which these tools don't support, so it may be misread",
+            f
+        ));
+    }
+
+    if write {
+        check_writable(sb)?;
+    }
+    Ok(())
+}
+
+//------------------------------
+
 fn pack_superblock<W: WriteBytesExt>(sb: &Superblock, w: &mut W) -> Result<()> {
     // checksum, which we don't know yet
     w.write_u32::<LittleEndian>(0)?;
 
     // flags
     if sb.flags.needs_check {
-        w.write_u32::<LittleEndian>(0x1)?;
+        w.write_u32::<LittleEndian>(NEEDS_CHECK_FLAG | sb.flags.unknown)?;
     } else {
-        w.write_u32::<LittleEndian>(0)?;
+        w.write_u32::<LittleEndian>(sb.flags.unknown)?;
     }
 
     w.write_u64::<LittleEndian>(sb.block)?;
-    w.write_all(&[0; UUID_SIZE])?;
+    w.write_all(&sb.uuid)?;
     w.write_u64::<LittleEndian>(MAGIC)?;
     w.write_u32::<LittleEndian>(sb.version)?;
     w.write_u32::<LittleEndian>(sb.time)?;
@@ -127,12 +233,18 @@ fn pack_superblock<W: WriteBytesExt>(sb: &Superblock, w: &mut W) -> Result<()> {
     w.write_u32::<LittleEndian>(sb.data_block_size)?;
     w.write_u32::<LittleEndian>((BLOCK_SIZE >> SECTOR_SHIFT) as u32)?; // metadata block size
     w.write_u64::<LittleEndian>(sb.nr_metadata_blocks)?;
+    w.write_u32::<LittleEndian>(sb.compat_flags)?;
+    w.write_u32::<LittleEndian>(sb.compat_ro_flags)?;
+    w.write_u32::<LittleEndian>(sb.incompat_flags)?;
+    w.write_u64::<LittleEndian>(sb.backup_superblock)?;
+    w.write_u64::<LittleEndian>(sb.labels_root)?;
 
     Ok(())
 }
 
-pub fn write_superblock(engine: &dyn IoEngine, _loc: u64, sb: &Superblock) -> Result<()> {
-    let b = Block::zeroed(SUPERBLOCK_LOCATION);
+#[instrument(level = "debug", skip(engine, sb))]
+pub fn write_superblock(engine: &dyn IoEngine, loc: u64, sb: &Superblock) -> Result<()> {
+    let b = Block::zeroed(loc);
 
     // pack the superblock
     {
@@ -149,3 +261,139 @@ pub fn write_superblock(engine: &dyn IoEngine, _loc: u64, sb: &Superblock) -> Re
 }
 
 //------------------------------
+
+// A backup copy of the superblock may be kept in the last block of the
+// metadata space map.  It's only written on request, by thin_restore or
+// thin_repair, and is never updated by the kernel, so the roots it holds
+// go stale as soon as the pool is used.  It's still enough to recover the
+// data block size and device sizes when block 0 has been wiped.  The
+// primary superblock records where the copy is, so checkers can account
+// for its block.
+pub fn backup_superblock_location(nr_metadata_blocks: u64) -> u64 {
+    std::cmp::min(nr_metadata_blocks, MAX_METADATA_BLOCKS as u64) - 1
+}
+
+pub fn write_backup_superblock(engine: &dyn IoEngine, loc: u64, sb: &Superblock) -> Result<()> {
+    let mut backup = sb.clone();
+    backup.block = loc;
+    write_superblock(engine, loc, &backup)
+}
+
+/// Looks for a backup superblock at the end of the metadata device, for
+/// when the primary superblock can't be read.  The copy has to record its
+/// own location, so an old superblock that happens to have been left in
+/// that block isn't mistaken for one.
+#[instrument(level = "debug", skip(engine))]
+pub fn read_backup_superblock(engine: &dyn IoEngine) -> Result<Superblock> {
+    let nr_blocks = engine.get_nr_blocks();
+    if nr_blocks <= 1 {
+        return Err(anyhow!("metadata device too small for a backup superblock"));
+    }
+
+    let loc = backup_superblock_location(nr_blocks);
+    let sb = read_superblock(engine, loc)?;
+    if sb.block != loc {
+        return Err(anyhow!("superblock at block {} isn't a backup copy", loc));
+    }
+
+    Ok(sb)
+}
+
+/// Reads the backup copy recorded in the primary superblock, if any.
+pub fn read_recorded_backup(engine: &dyn IoEngine, sb: &Superblock) -> Result<Option<Superblock>> {
+    if sb.backup_superblock == 0 {
+        return Ok(None);
+    }
+
+    let backup = read_superblock(engine, sb.backup_superblock)?;
+    if backup.block != sb.backup_superblock {
+        return Err(anyhow!(
+            "superblock at block {} isn't a backup copy",
+            sb.backup_superblock
+        ));
+    }
+    Ok(Some(backup))
+}
+
+//------------------------------
+
+// The kernel doesn't interpret the uuid, it's for userland to tell pools
+// apart.  Metadata that's never been given one has a zeroed uuid.
+
+/// Formats a uuid in the usual hyphenated form, or returns an empty
+/// string if it's unset.
+pub fn uuid_to_string(uuid: &[u8; UUID_SIZE]) -> String {
+    if uuid.iter().all(|b| *b == 0) {
+        return String::new();
+    }
+
+    let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Parses a uuid, with or without hyphens.  An empty string is the
+/// unset, zeroed, uuid.
+pub fn parse_uuid(s: &str) -> Result<[u8; UUID_SIZE]> {
+    let mut uuid = [0u8; UUID_SIZE];
+    if s.is_empty() {
+        return Ok(uuid);
+    }
+
+    let hex: Vec<u8> = s.bytes().filter(|c| *c != b'-').collect();
+    if hex.len() != UUID_SIZE * 2 {
+        return Err(anyhow!("bad uuid '{}'", s));
+    }
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let pair = std::str::from_utf8(pair).map_err(|_| anyhow!("bad uuid '{}'", s))?;
+        uuid[i] = u8::from_str_radix(pair, 16).map_err(|_| anyhow!("bad uuid '{}'", s))?;
+    }
+    Ok(uuid)
+}
+
+/// Generates a random (version 4) uuid.
+pub fn random_uuid() -> [u8; UUID_SIZE] {
+    let mut uuid: [u8; UUID_SIZE] = rand::random();
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
+}
+
+//------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uuid_round_trip() {
+        let s = "0123abcd-4567-89ef-0123-456789abcdef";
+        let uuid = parse_uuid(s).unwrap();
+        assert_eq!(uuid[0], 0x01);
+        assert_eq!(uuid_to_string(&uuid), s);
+        assert_eq!(
+            parse_uuid("0123abcd456789ef0123456789abcdef").unwrap(),
+            uuid
+        );
+
+        assert_eq!(parse_uuid("").unwrap(), [0; UUID_SIZE]);
+        assert_eq!(uuid_to_string(&[0; UUID_SIZE]), "");
+        assert!(parse_uuid("0123abcd-4567").is_err());
+        assert!(parse_uuid("0123abcd-4567-89ef-0123-456789abcdeg").is_err());
+    }
+
+    #[test]
+    fn test_random_uuid() {
+        let uuid = random_uuid();
+        assert_ne!(uuid, random_uuid());
+        assert_eq!(uuid_to_string(&uuid).as_bytes()[14], b'4');
+    }
+}
+
+//------------------------------
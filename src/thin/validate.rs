@@ -0,0 +1,436 @@
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use crate::report::Report;
+use crate::thin::ir::{self, MetadataVisitor, Visit};
+use crate::thin::labels::check_labels;
+use crate::thin::xml;
+
+//------------------------------------------
+
+enum Section {
+    Def(String),
+    Dev(u32),
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Section::Def(name) => write!(f, "def {}", name),
+            Section::Dev(dev_id) => write!(f, "device {}", dev_id),
+        }
+    }
+}
+
+// The mappings of a def or device.  A dump lists them in order of thin
+// block, which the restore relies on, so only the end of the last one
+// is needed to spot an overlap.
+#[derive(Clone, Default)]
+struct Mappings {
+    thin_begin: Option<u64>,
+    thin_end: u64,
+    nr_mappings: u64,
+    nr_overlapping: u64,
+
+    // Mapped data blocks past the end of the pool, and the first of them
+    nr_beyond: u64,
+    first_beyond: Option<u64>,
+}
+
+impl Mappings {
+    fn push(&mut self, thin_begin: u64, thin_end: u64, nr_mappings: u64) {
+        if thin_begin < self.thin_end {
+            self.nr_overlapping += 1;
+        }
+        self.thin_begin.get_or_insert(thin_begin);
+        self.thin_end = std::cmp::max(self.thin_end, thin_end);
+        self.nr_mappings += nr_mappings;
+    }
+
+    // Merges in a def's mappings, which have already been checked.
+    fn push_def(&mut self, def: &Mappings) {
+        if let Some(thin_begin) = def.thin_begin {
+            self.push(thin_begin, def.thin_end, def.nr_mappings);
+        }
+    }
+}
+
+/// What a dump holds, if it's valid.
+pub struct DumpSummary {
+    pub nr_devices: u64,
+    pub nr_mappings: u64,
+}
+
+/// Checks a dump would restore to consistent metadata.  Damage to the
+/// structure of the dump, eg, a mapping outside a device, stops the
+/// walk, but inconsistencies, eg, overlapping mappings, are collected,
+/// at most a few for each device, so they can all be reported.
+#[derive(Default)]
+pub struct DumpValidator {
+    sb: Option<ir::Superblock>,
+    finished: bool,
+    defs: BTreeMap<String, Mappings>,
+    devices: BTreeSet<u32>,
+    labelled: BTreeSet<u32>,
+    current: Option<(Section, Mappings)>,
+    current_dev: Option<ir::Device>,
+    nr_mappings: u64,
+    problems: Vec<String>,
+}
+
+impl DumpValidator {
+    pub fn new() -> DumpValidator {
+        DumpValidator::default()
+    }
+
+    fn problem(&mut self, msg: String) {
+        self.problems.push(msg);
+    }
+
+    fn begin_section(&mut self, section: Section) -> Result<Visit> {
+        if self.sb.is_none() || self.finished {
+            return Err(anyhow!("{} is outside the superblock", section));
+        }
+        if let Some((current, _)) = &self.current {
+            return Err(anyhow!("{} is inside {}", section, current));
+        }
+        self.current = Some((section, Mappings::default()));
+        Ok(Visit::Continue)
+    }
+
+    fn end_section(&mut self) -> Result<(Section, Mappings)> {
+        let (section, m) = self
+            .current
+            .take()
+            .ok_or_else(|| anyhow!("unexpected end of a def or device"))?;
+
+        if m.nr_overlapping > 0 {
+            self.problem(format!(
+                "{}: {} mappings overlap, or are out of order with, the ones before",
+                section, m.nr_overlapping
+            ));
+        }
+        if let Some(first) = m.first_beyond {
+            let nr_data_blocks = self.sb.as_ref().map(|sb| sb.nr_data_blocks).unwrap_or(0);
+            self.problem(format!(
+                "{}: {} mapped data blocks are past the end of the pool, which has {}, the first is block {}",
+                section, m.nr_beyond, nr_data_blocks, first
+            ));
+        }
+        Ok((section, m))
+    }
+
+    /// Returns the problems found, or what the dump holds if there are
+    /// none.
+    pub fn complete(self) -> std::result::Result<DumpSummary, Vec<String>> {
+        if self.problems.is_empty() {
+            Ok(DumpSummary {
+                nr_devices: self.devices.len() as u64,
+                nr_mappings: self.nr_mappings,
+            })
+        } else {
+            Err(self.problems)
+        }
+    }
+}
+
+impl MetadataVisitor for DumpValidator {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        if self.sb.is_some() {
+            return Err(anyhow!("duplicated superblock"));
+        }
+        if !(128..=2097152).contains(&sb.data_block_size) || (sb.data_block_size & 0x7F != 0) {
+            self.problem(format!("invalid data block size {}", sb.data_block_size));
+        }
+        self.sb = Some(sb.clone());
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        if self.sb.is_none() || self.current.is_some() {
+            return Err(anyhow!("unexpected </superblock>"));
+        }
+        self.finished = true;
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        if self.defs.contains_key(name) {
+            self.problem(format!("def {} is defined more than once", name));
+        }
+        self.begin_section(Section::Def(name.to_string()))
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        match self.end_section()? {
+            (Section::Def(name), m) => {
+                self.defs.insert(name, m);
+                Ok(Visit::Continue)
+            }
+            (section, _) => Err(anyhow!("unexpected </def> in {}", section)),
+        }
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        self.begin_section(Section::Dev(d.dev_id))?;
+        if !self.devices.insert(d.dev_id) {
+            self.problem(format!("device {} is listed more than once", d.dev_id));
+        }
+        self.current_dev = Some(d.clone());
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        let d = self
+            .current_dev
+            .take()
+            .ok_or_else(|| anyhow!("unexpected </device>"))?;
+        let (_, m) = self.end_section()?;
+        if d.mapped_blocks != m.nr_mappings {
+            self.problem(format!(
+                "device {}: mapped_blocks is {} but it has {} mappings",
+                d.dev_id, d.mapped_blocks, m.nr_mappings
+            ));
+        }
+        self.nr_mappings += m.nr_mappings;
+        Ok(Visit::Continue)
+    }
+
+    fn device_labels(&mut self, labels: &ir::DeviceLabels) -> Result<Visit> {
+        let dev_id = match &self.current_dev {
+            Some(d) => d.dev_id,
+            None => {
+                return Err(anyhow!(
+                    "<labels> tags may only occur within <device> sections"
+                ))
+            }
+        };
+
+        if !self.labelled.insert(dev_id) {
+            self.problem(format!(
+                "device {}: has more than one set of labels",
+                dev_id
+            ));
+        }
+        if let Err(e) = check_labels(labels) {
+            self.problem(format!("device {}: {}", dev_id, e));
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        let nr_data_blocks = self.sb.as_ref().map(|sb| sb.nr_data_blocks).unwrap_or(0);
+        let (_, mappings) = self
+            .current
+            .as_mut()
+            .ok_or_else(|| anyhow!("mapping tags must appear within a <def> or <device> tag"))?;
+
+        mappings.push(m.thin_begin, m.thin_begin + m.len, m.len);
+
+        let data_end = m.data_begin + m.len;
+        if data_end > nr_data_blocks {
+            let first = std::cmp::max(m.data_begin, nr_data_blocks);
+            mappings.nr_beyond += data_end - first;
+            mappings.first_beyond.get_or_insert(first);
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        if self.current_dev.is_none() {
+            return Err(anyhow!(
+                "<ref> tags may only occur within <device> sections"
+            ));
+        }
+
+        match self.defs.get(name).cloned() {
+            Some(def) => {
+                if let Some((_, mappings)) = self.current.as_mut() {
+                    mappings.push_def(&def);
+                }
+            }
+            None => {
+                let dev_id = self.current_dev.as_ref().unwrap().dev_id;
+                self.problem(format!(
+                    "device {}: refers to def {}, which isn't defined before it",
+                    dev_id, name
+                ));
+            }
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        if !self.finished {
+            return Err(anyhow!("incomplete source metadata"));
+        }
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
+/// Parses a dump and checks it, writing nothing.  Each problem found is
+/// reported, and makes this fail.
+pub fn validate_dump(input: &Path, report: &Report) -> Result<DumpSummary> {
+    let input = OpenOptions::new().read(true).open(input)?;
+    let mut v = DumpValidator::new();
+    xml::read_with_report(input, &mut v, report)?;
+
+    v.complete().map_err(|problems| {
+        for p in &problems {
+            report.fatal(p);
+        }
+        anyhow!("{} problems found in the dump", problems.len())
+    })
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sb(nr_data_blocks: u64) -> ir::Superblock {
+        ir::Superblock {
+            uuid: "".to_string(),
+            time: 0,
+            transaction: 1,
+            flags: None,
+            version: Some(2),
+            data_block_size: 128,
+            nr_data_blocks,
+            metadata_snap: None,
+        }
+    }
+
+    fn dev(dev_id: u32, mapped_blocks: u64) -> ir::Device {
+        ir::Device {
+            dev_id,
+            mapped_blocks,
+            transaction: 0,
+            creation_time: 0,
+            snap_time: 0,
+        }
+    }
+
+    fn map(thin_begin: u64, data_begin: u64, len: u64) -> ir::Map {
+        ir::Map {
+            thin_begin,
+            data_begin,
+            time: 0,
+            len,
+            shared: None,
+        }
+    }
+
+    // Visits each device in turn, with its mappings.
+    fn validate(nr_data_blocks: u64, devs: &[(ir::Device, Vec<ir::Map>)]) -> Vec<String> {
+        let mut v = DumpValidator::new();
+        v.superblock_b(&sb(nr_data_blocks)).unwrap();
+        for (d, maps) in devs {
+            v.device_b(d).unwrap();
+            for m in maps {
+                v.map(m).unwrap();
+            }
+            v.device_e().unwrap();
+        }
+        v.superblock_e().unwrap();
+        v.eof().unwrap();
+        v.complete().err().unwrap_or_default()
+    }
+
+    #[test]
+    fn test_valid_dump() -> Result<()> {
+        let mut v = DumpValidator::new();
+        v.superblock_b(&sb(100))?;
+        v.def_shared_b("1")?;
+        v.map(&map(0, 0, 10))?;
+        v.def_shared_e()?;
+        v.device_b(&dev(0, 15))?;
+        v.ref_shared("1")?;
+        v.map(&map(10, 50, 5))?;
+        v.device_e()?;
+        v.device_b(&dev(1, 10))?;
+        v.ref_shared("1")?;
+        v.device_e()?;
+        v.superblock_e()?;
+        v.eof()?;
+
+        let summary = v.complete().unwrap();
+        assert_eq!(summary.nr_devices, 2);
+        assert_eq!(summary.nr_mappings, 25);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlapping_mappings() {
+        let problems = validate(100, &[(dev(0, 15), vec![map(0, 0, 10), map(5, 20, 5)])]);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("device 0: 1 mappings overlap"));
+    }
+
+    #[test]
+    fn test_data_blocks_beyond_the_pool() {
+        let problems = validate(100, &[(dev(3, 20), vec![map(0, 90, 20)])]);
+        assert_eq!(
+            problems,
+            vec!["device 3: 10 mapped data blocks are past the end of the pool, which has 100, the first is block 100"]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_devices() {
+        let problems = validate(100, &[(dev(1, 0), vec![]), (dev(1, 0), vec![])]);
+        assert_eq!(problems, vec!["device 1 is listed more than once"]);
+    }
+
+    #[test]
+    fn test_wrong_mapped_blocks() {
+        let problems = validate(100, &[(dev(0, 3), vec![map(0, 0, 10)])]);
+        assert_eq!(
+            problems,
+            vec!["device 0: mapped_blocks is 3 but it has 10 mappings"]
+        );
+    }
+
+    #[test]
+    fn test_bad_labels() -> Result<()> {
+        let mut v = DumpValidator::new();
+        v.superblock_b(&sb(100))?;
+        v.device_b(&dev(0, 0))?;
+        v.device_labels(&ir::DeviceLabels {
+            label: Some(String::new()),
+            ..Default::default()
+        })?;
+        v.device_labels(&ir::DeviceLabels::default())?;
+        v.device_e()?;
+        assert!(v.device_labels(&ir::DeviceLabels::default()).is_err());
+        v.superblock_e()?;
+        v.eof()?;
+
+        assert_eq!(
+            v.complete().err().unwrap(),
+            vec![
+                "device 0: label is empty",
+                "device 0: has more than one set of labels"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_structural_damage_stops_the_walk() {
+        let mut v = DumpValidator::new();
+        assert!(v.map(&map(0, 0, 1)).is_err());
+        assert!(v.device_b(&dev(0, 0)).is_err());
+        v.superblock_b(&sb(100)).unwrap();
+        assert!(v.ref_shared("1").is_err());
+        assert!(v.eof().is_err());
+    }
+}
+
+//------------------------------------------
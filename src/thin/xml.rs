@@ -1,9 +1,11 @@
 use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
 use std::{io::prelude::*, io::BufReader, io::Write};
 
 use quick_xml::events::{BytesEnd, BytesStart, Event};
 use quick_xml::{Reader, Writer};
 
+use crate::report::Report;
 use crate::thin::ir::*;
 use crate::xml::*;
 
@@ -11,17 +13,36 @@ use crate::xml::*;
 
 pub struct XmlWriter<W: Write> {
     w: Writer<W>,
+    min_range: u64,
 }
 
+/// Runs shorter than this are written as single mappings.
+pub const DEFAULT_MIN_RANGE: u64 = 2;
+
 impl<W: Write> XmlWriter<W> {
     pub fn new(w: W) -> XmlWriter<W> {
+        XmlWriter::with_min_range(w, DEFAULT_MIN_RANGE)
+    }
+
+    /// Writes runs of fewer than `min_range` mappings as a series of
+    /// single mappings, so dumps from tools that coalesce differently
+    /// can be compared.  `u64::MAX` never writes a range.
+    pub fn with_min_range(w: W, min_range: u64) -> XmlWriter<W> {
         XmlWriter {
             w: Writer::new_with_indent(w, 0x20, 2),
+            min_range,
         }
     }
 }
 
-const XML_VERSION: u32 = 2;
+/// The newest schema version the reader knows about.  Dumps are written
+/// as version 2 unless they say they need something newer, so older tools
+/// can still read them.
+const XML_VERSION: u32 = 3;
+const BASE_XML_VERSION: u32 = 2;
+
+/// The version that added device labels.
+pub const LABELS_XML_VERSION: u32 = 3;
 
 impl<W: Write> MetadataVisitor for XmlWriter<W> {
     fn superblock_b(&mut self, sb: &Superblock) -> Result<Visit> {
@@ -35,7 +56,11 @@ impl<W: Write> MetadataVisitor for XmlWriter<W> {
             elem.push_attribute(mk_attr(b"flags", flags));
         }
 
-        elem.push_attribute(mk_attr(b"version", XML_VERSION));
+        let version = sb
+            .version
+            .unwrap_or(BASE_XML_VERSION)
+            .clamp(BASE_XML_VERSION, XML_VERSION);
+        elem.push_attribute(mk_attr(b"version", version));
         elem.push_attribute(mk_attr(b"data_block_size", sb.data_block_size));
         elem.push_attribute(mk_attr(b"nr_data_blocks", sb.nr_data_blocks));
 
@@ -84,25 +109,59 @@ impl<W: Write> MetadataVisitor for XmlWriter<W> {
         Ok(Visit::Continue)
     }
 
+    // Labels are written by users, so have to be escaped.
+    fn device_labels(&mut self, labels: &DeviceLabels) -> Result<Visit> {
+        let tag = b"labels";
+        let mut elem = BytesStart::owned(tag.to_vec(), tag.len());
+        if let Some(label) = &labels.label {
+            elem.push_attribute(("label", label.as_str()));
+        }
+        if let Some(uuid) = &labels.uuid {
+            elem.push_attribute(("uuid", uuid.as_str()));
+        }
+
+        if labels.attributes.is_empty() {
+            self.w.write_event(Event::Empty(elem))?;
+            return Ok(Visit::Continue);
+        }
+
+        self.w.write_event(Event::Start(elem))?;
+        for (key, value) in &labels.attributes {
+            let tag = b"attribute";
+            let mut elem = BytesStart::owned(tag.to_vec(), tag.len());
+            elem.push_attribute(("key", key.as_str()));
+            elem.push_attribute(("value", value.as_str()));
+            self.w.write_event(Event::Empty(elem))?;
+        }
+        self.w
+            .write_event(Event::End(BytesEnd::borrowed(b"labels")))?;
+        Ok(Visit::Continue)
+    }
+
     fn map(&mut self, m: &Map) -> Result<Visit> {
-        match m.len {
-            1 => {
+        if m.len < self.min_range {
+            for i in 0..m.len {
                 let tag = b"single_mapping";
                 let mut elem = BytesStart::owned(tag.to_vec(), tag.len());
-                elem.push_attribute(mk_attr(b"origin_block", m.thin_begin));
-                elem.push_attribute(mk_attr(b"data_block", m.data_begin));
+                elem.push_attribute(mk_attr(b"origin_block", m.thin_begin + i));
+                elem.push_attribute(mk_attr(b"data_block", m.data_begin + i));
                 elem.push_attribute(mk_attr(b"time", m.time));
+                if let Some(shared) = m.shared {
+                    elem.push_attribute(mk_attr(b"shared", shared));
+                }
                 self.w.write_event(Event::Empty(elem))?;
             }
-            _ => {
-                let tag = b"range_mapping";
-                let mut elem = BytesStart::owned(tag.to_vec(), tag.len());
-                elem.push_attribute(mk_attr(b"origin_begin", m.thin_begin));
-                elem.push_attribute(mk_attr(b"data_begin", m.data_begin));
-                elem.push_attribute(mk_attr(b"length", m.len));
-                elem.push_attribute(mk_attr(b"time", m.time));
-                self.w.write_event(Event::Empty(elem))?;
+        } else {
+            let tag = b"range_mapping";
+            let mut elem = BytesStart::owned(tag.to_vec(), tag.len());
+            elem.push_attribute(mk_attr(b"origin_begin", m.thin_begin));
+            elem.push_attribute(mk_attr(b"data_begin", m.data_begin));
+            elem.push_attribute(mk_attr(b"length", m.len));
+            elem.push_attribute(mk_attr(b"time", m.time));
+            if let Some(shared) = m.shared {
+                elem.push_attribute(mk_attr(b"shared", shared));
             }
+            self.w.write_event(Event::Empty(elem))?;
         }
         Ok(Visit::Continue)
     }
@@ -124,7 +183,83 @@ impl<W: Write> MetadataVisitor for XmlWriter<W> {
 
 //---------------------------------------
 
-fn parse_superblock(e: &BytesStart) -> Result<Superblock> {
+// Dumps declaring a newer schema version than ours may contain
+// attributes and elements we don't understand.  Rather than refusing
+// them they're skipped, with a warning, so metadata dumped by newer
+// tools can still be restored.  Unknown names in dumps of a version we
+// do know are still errors, they're most likely typos.
+struct ReaderState<'a> {
+    report: Option<&'a Report>,
+    newer_version: bool,
+
+    // Nesting depth within an unknown element that's being skipped.
+    skip_depth: usize,
+    warned: BTreeSet<String>,
+
+    // The labels being read, until </labels> is reached.
+    labels: Option<DeviceLabels>,
+}
+
+impl<'a> ReaderState<'a> {
+    fn new(report: Option<&'a Report>) -> Self {
+        ReaderState {
+            report,
+            newer_version: false,
+            skip_depth: 0,
+            warned: BTreeSet::new(),
+            labels: None,
+        }
+    }
+
+    fn warn_once(&mut self, msg: String) {
+        if let Some(report) = self.report {
+            if !self.warned.contains(&msg) {
+                report.info(&format!("warning: {}", msg));
+                self.warned.insert(msg);
+            }
+        }
+    }
+
+    fn set_version(&mut self, version: Option<u32>) {
+        if let Some(v) = version {
+            if v > XML_VERSION {
+                self.newer_version = true;
+                self.warn_once(format!(
+                    "xml schema version {} is newer than this tool supports ({}), \
+                     unknown attributes and elements will be ignored",
+                    v, XML_VERSION
+                ));
+            }
+        }
+    }
+
+    fn unknown_attr(&mut self, tag: &str, attr: &[u8]) -> Result<()> {
+        if !self.newer_version {
+            return bad_attr(tag, attr);
+        }
+
+        self.warn_once(format!(
+            "ignoring unknown attribute '{}' in tag '{}'",
+            String::from_utf8_lossy(attr),
+            tag
+        ));
+        Ok(())
+    }
+
+    fn unknown_elem(&mut self, name: &[u8], pos: usize) -> Result<()> {
+        if !self.newer_version {
+            return Err(anyhow!("Parse error at byte {}", pos));
+        }
+
+        self.warn_once(format!(
+            "ignoring unknown element '{}'",
+            String::from_utf8_lossy(name)
+        ));
+        Ok(())
+    }
+}
+
+fn parse_superblock(e: &BytesStart, state: &mut ReaderState) -> Result<Superblock> {
     let mut uuid: Option<String> = None;
     let mut time: Option<u32> = None;
     let mut transaction: Option<u64> = None;
@@ -133,6 +268,7 @@ fn parse_superblock(e: &BytesStart) -> Result<Superblock> {
     let mut data_block_size: Option<u32> = None;
     let mut nr_data_blocks: Option<u64> = None;
     let mut metadata_snap: Option<u64> = None;
+    let mut unknown = Vec::new();
 
     for a in e.attributes() {
         let kv = a.unwrap();
@@ -145,12 +281,18 @@ fn parse_superblock(e: &BytesStart) -> Result<Superblock> {
             b"data_block_size" => data_block_size = Some(u32_val(&kv)?),
             b"nr_data_blocks" => nr_data_blocks = Some(u64_val(&kv)?),
             b"metadata_snap" => metadata_snap = Some(u64_val(&kv)?),
-            _ => return bad_attr("superblock", kv.key),
+            _ => unknown.push(kv.key.to_vec()),
         }
     }
 
     let tag = "superblock";
 
+    // The version may follow the attributes it introduced.
+    state.set_version(version);
+    for attr in unknown {
+        state.unknown_attr(tag, &attr)?;
+    }
+
     Ok(Superblock {
         uuid: check_attr(tag, "uuid", uuid)?,
         time: check_attr(tag, "time", time)?,
@@ -163,7 +305,7 @@ fn parse_superblock(e: &BytesStart) -> Result<Superblock> {
     })
 }
 
-fn parse_def(e: &BytesStart, tag: &str) -> Result<String> {
+fn parse_def(e: &BytesStart, tag: &str, state: &mut ReaderState) -> Result<String> {
     let mut name: Option<String> = None;
 
     for a in e.attributes() {
@@ -172,14 +314,14 @@ fn parse_def(e: &BytesStart, tag: &str) -> Result<String> {
             b"name" => {
                 name = Some(string_val(&kv));
             }
-            _ => return bad_attr(tag, kv.key),
+            _ => state.unknown_attr(tag, kv.key)?,
         }
     }
 
-    Ok(name.unwrap())
+    check_attr(tag, "name", name)
 }
 
-fn parse_device(e: &BytesStart) -> Result<Device> {
+fn parse_device(e: &BytesStart, state: &mut ReaderState) -> Result<Device> {
     let mut dev_id: Option<u32> = None;
     let mut mapped_blocks: Option<u64> = None;
     let mut transaction: Option<u64> = None;
@@ -194,7 +336,7 @@ fn parse_device(e: &BytesStart) -> Result<Device> {
             b"transaction" => transaction = Some(u64_val(&kv)?),
             b"creation_time" => creation_time = Some(u32_val(&kv)?),
             b"snap_time" => snap_time = Some(u32_val(&kv)?),
-            _ => return bad_attr("device", kv.key),
+            _ => state.unknown_attr("device", kv.key)?,
         }
     }
 
@@ -209,10 +351,46 @@ fn parse_device(e: &BytesStart) -> Result<Device> {
     })
 }
 
-fn parse_single_map(e: &BytesStart) -> Result<Map> {
+fn parse_labels(e: &BytesStart, state: &mut ReaderState) -> Result<DeviceLabels> {
+    let mut labels = DeviceLabels::default();
+
+    for a in e.attributes() {
+        let kv = a.unwrap();
+        match kv.key {
+            b"label" => labels.label = Some(string_val(&kv)),
+            b"uuid" => labels.uuid = Some(string_val(&kv)),
+            _ => state.unknown_attr("labels", kv.key)?,
+        }
+    }
+
+    Ok(labels)
+}
+
+fn parse_attribute(e: &BytesStart, state: &mut ReaderState) -> Result<(String, String)> {
+    let mut key: Option<String> = None;
+    let mut value: Option<String> = None;
+
+    for a in e.attributes() {
+        let kv = a.unwrap();
+        match kv.key {
+            b"key" => key = Some(string_val(&kv)),
+            b"value" => value = Some(string_val(&kv)),
+            _ => state.unknown_attr("attribute", kv.key)?,
+        }
+    }
+
+    let tag = "attribute";
+    Ok((
+        check_attr(tag, "key", key)?,
+        check_attr(tag, "value", value)?,
+    ))
+}
+
+fn parse_single_map(e: &BytesStart, state: &mut ReaderState) -> Result<Map> {
     let mut thin_begin: Option<u64> = None;
     let mut data_begin: Option<u64> = None;
     let mut time: Option<u32> = None;
+    let mut shared: Option<bool> = None;
 
     for a in e.attributes() {
         let kv = a.unwrap();
@@ -220,7 +398,8 @@ fn parse_single_map(e: &BytesStart) -> Result<Map> {
             b"origin_block" => thin_begin = Some(u64_val(&kv)?),
             b"data_block" => data_begin = Some(u64_val(&kv)?),
             b"time" => time = Some(u32_val(&kv)?),
-            _ => return bad_attr("single_mapping", kv.key),
+            b"shared" => shared = Some(bool_val(&kv)?),
+            _ => state.unknown_attr("single_mapping", kv.key)?,
         }
     }
 
@@ -231,14 +410,16 @@ fn parse_single_map(e: &BytesStart) -> Result<Map> {
         data_begin: check_attr(tag, "data_block", data_begin)?,
         time: check_attr(tag, "time", time)?,
         len: 1,
+        shared,
     })
 }
 
-fn parse_range_map(e: &BytesStart) -> Result<Map> {
+fn parse_range_map(e: &BytesStart, state: &mut ReaderState) -> Result<Map> {
     let mut thin_begin: Option<u64> = None;
     let mut data_begin: Option<u64> = None;
     let mut time: Option<u32> = None;
     let mut length: Option<u64> = None;
+    let mut shared: Option<bool> = None;
 
     for a in e.attributes() {
         let kv = a.unwrap();
@@ -247,7 +428,8 @@ fn parse_range_map(e: &BytesStart) -> Result<Map> {
             b"data_begin" => data_begin = Some(u64_val(&kv)?),
             b"time" => time = Some(u32_val(&kv)?),
             b"length" => length = Some(u64_val(&kv)?),
-            _ => return bad_attr("range_mapping", kv.key),
+            b"shared" => shared = Some(bool_val(&kv)?),
+            _ => state.unknown_attr("range_mapping", kv.key)?,
         }
     }
 
@@ -258,32 +440,97 @@ fn parse_range_map(e: &BytesStart) -> Result<Map> {
         data_begin: check_attr(tag, "data_begin", data_begin)?,
         time: check_attr(tag, "time", time)?,
         len: check_attr(tag, "length", length)?,
+        shared,
     })
 }
 
-fn handle_event<R, M>(reader: &mut Reader<R>, buf: &mut Vec<u8>, visitor: &mut M) -> Result<Visit>
+// Everything within an unknown element is discarded, including any
+// elements we would otherwise recognise.
+fn skip_event<R>(reader: &mut Reader<R>, buf: &mut Vec<u8>, state: &mut ReaderState) -> Result<()>
+where
+    R: Read + BufRead,
+{
+    match reader.read_event(buf) {
+        Ok(Event::Start(_)) => state.skip_depth += 1,
+        Ok(Event::End(_)) => state.skip_depth -= 1,
+        Ok(Event::Eof) => {
+            return Err(anyhow!(
+                "Parse error at byte {}: unexpected end of file",
+                reader.buffer_position()
+            ))
+        }
+        Ok(_) => {}
+        Err(e) => {
+            return Err(anyhow!(
+                "Parse error at byte {}: {:?}",
+                reader.buffer_position(),
+                e
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn handle_event<R, M>(
+    reader: &mut Reader<R>,
+    buf: &mut Vec<u8>,
+    visitor: &mut M,
+    state: &mut ReaderState,
+) -> Result<Visit>
 where
     R: Read + BufRead,
     M: MetadataVisitor,
 {
+    if state.skip_depth > 0 {
+        skip_event(reader, buf, state)?;
+        return Ok(Visit::Continue);
+    }
+
     match reader.read_event(buf) {
         Ok(Event::Start(ref e)) => match e.name() {
-            b"superblock" => visitor.superblock_b(&parse_superblock(e)?),
-            b"device" => visitor.device_b(&parse_device(e)?),
-            b"def" => visitor.def_shared_b(&parse_def(e, "def")?),
-            _ => return Err(anyhow!("Parse error at byte {}", reader.buffer_position())),
+            b"superblock" => visitor.superblock_b(&parse_superblock(e, state)?),
+            b"device" => visitor.device_b(&parse_device(e, state)?),
+            b"def" => visitor.def_shared_b(&parse_def(e, "def", state)?),
+            b"labels" => {
+                state.labels = Some(parse_labels(e, state)?);
+                Ok(Visit::Continue)
+            }
+            name => {
+                state.unknown_elem(name, reader.buffer_position())?;
+                state.skip_depth = 1;
+                Ok(Visit::Continue)
+            }
         },
         Ok(Event::End(ref e)) => match e.name() {
             b"superblock" => visitor.superblock_e(),
             b"device" => visitor.device_e(),
             b"def" => visitor.def_shared_e(),
+            b"labels" => match state.labels.take() {
+                Some(labels) => visitor.device_labels(&labels),
+                None => Err(anyhow!("Parse error at byte {}", reader.buffer_position())),
+            },
             _ => return Err(anyhow!("Parse error at byte {}", reader.buffer_position())),
         },
         Ok(Event::Empty(ref e)) => match e.name() {
-            b"single_mapping" => visitor.map(&parse_single_map(e)?),
-            b"range_mapping" => visitor.map(&parse_range_map(e)?),
-            b"ref" => visitor.ref_shared(&parse_def(e, "ref")?),
-            _ => return Err(anyhow!("Parse error at byte {}", reader.buffer_position())),
+            b"single_mapping" => visitor.map(&parse_single_map(e, state)?),
+            b"range_mapping" => visitor.map(&parse_range_map(e, state)?),
+            b"ref" => visitor.ref_shared(&parse_def(e, "ref", state)?),
+            b"labels" => visitor.device_labels(&parse_labels(e, state)?),
+            b"attribute" => {
+                let (key, value) = parse_attribute(e, state)?;
+                let labels = state.labels.as_mut().ok_or_else(|| {
+                    anyhow!(
+                        "Parse error at byte {}: <attribute> outside <labels>",
+                        reader.buffer_position()
+                    )
+                })?;
+                labels.attributes.insert(key, value);
+                Ok(Visit::Continue)
+            }
+            name => {
+                state.unknown_elem(name, reader.buffer_position())?;
+                Ok(Visit::Continue)
+            }
         },
         Ok(Event::Text(_)) => Ok(Visit::Continue),
         Ok(Event::Comment(_)) => Ok(Visit::Continue),
@@ -302,7 +549,7 @@ where
     }
 }
 
-pub fn read<R, M>(input: R, visitor: &mut M) -> Result<()>
+fn read_<R, M>(input: R, visitor: &mut M, report: Option<&Report>) -> Result<()>
 where
     R: Read,
     M: MetadataVisitor,
@@ -312,11 +559,30 @@ where
 
     reader.trim_text(true);
     let mut buf = Vec::new();
+    let mut state = ReaderState::new(report);
 
-    while let Visit::Continue = handle_event(&mut reader, &mut buf, visitor)? {}
+    while let Visit::Continue = handle_event(&mut reader, &mut buf, visitor, &mut state)? {}
     Ok(())
 }
 
+pub fn read<R, M>(input: R, visitor: &mut M) -> Result<()>
+where
+    R: Read,
+    M: MetadataVisitor,
+{
+    read_(input, visitor, None)
+}
+
+/// As read(), but anything skipped in a dump from a newer version of
+/// the tools is reported.
+pub fn read_with_report<R, M>(input: R, visitor: &mut M, report: &Report) -> Result<()>
+where
+    R: Read,
+    M: MetadataVisitor,
+{
+    read_(input, visitor, Some(report))
+}
+
 //---------------------------------------
 
 struct SBVisitor {
@@ -348,6 +614,10 @@ impl MetadataVisitor for SBVisitor {
         Ok(Visit::Continue)
     }
 
+    fn device_labels(&mut self, _labels: &DeviceLabels) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
     fn map(&mut self, _m: &Map) -> Result<Visit> {
         Ok(Visit::Continue)
     }
@@ -371,3 +641,161 @@ where
 }
 
 //---------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter {
+        nr_devices: u64,
+        nr_mapped: u64,
+        labels: Vec<DeviceLabels>,
+    }
+
+    impl MetadataVisitor for Counter {
+        fn superblock_b(&mut self, _sb: &Superblock) -> Result<Visit> {
+            Ok(Visit::Continue)
+        }
+
+        fn superblock_e(&mut self) -> Result<Visit> {
+            Ok(Visit::Continue)
+        }
+
+        fn def_shared_b(&mut self, _name: &str) -> Result<Visit> {
+            Ok(Visit::Continue)
+        }
+
+        fn def_shared_e(&mut self) -> Result<Visit> {
+            Ok(Visit::Continue)
+        }
+
+        fn device_b(&mut self, _d: &Device) -> Result<Visit> {
+            self.nr_devices += 1;
+            Ok(Visit::Continue)
+        }
+
+        fn device_e(&mut self) -> Result<Visit> {
+            Ok(Visit::Continue)
+        }
+
+        fn device_labels(&mut self, labels: &DeviceLabels) -> Result<Visit> {
+            self.labels.push(labels.clone());
+            Ok(Visit::Continue)
+        }
+
+        fn map(&mut self, m: &Map) -> Result<Visit> {
+            self.nr_mapped += m.len;
+            Ok(Visit::Continue)
+        }
+
+        fn ref_shared(&mut self, _name: &str) -> Result<Visit> {
+            Ok(Visit::Continue)
+        }
+
+        fn eof(&mut self) -> Result<Visit> {
+            Ok(Visit::Stop)
+        }
+    }
+
+    fn mk_xml(version: u32) -> String {
+        format!(
+            r#"<superblock uuid="" time="0" transaction="1" colour="blue" version="{}" data_block_size="128" nr_data_blocks="100">
+  <device dev_id="1" mapped_blocks="3" transaction="0" creation_time="0" snap_time="0" label="root">
+    <range_mapping origin_begin="0" data_begin="0" length="2" time="0" checksum="1234"/>
+    <annotation kind="note">
+      <single_mapping origin_block="10" data_block="10" time="0"/>
+    </annotation>
+    <hint value="1"/>
+    <single_mapping origin_block="2" data_block="2" time="0"/>
+  </device>
+</superblock>
+"#,
+            version
+        )
+    }
+
+    #[test]
+    fn test_unknowns_rejected_for_known_versions() {
+        let mut v = Counter::default();
+        assert!(read(mk_xml(XML_VERSION).as_bytes(), &mut v).is_err());
+    }
+
+    #[test]
+    fn test_unknowns_skipped_for_newer_versions() {
+        let mut v = Counter::default();
+        read(mk_xml(XML_VERSION + 1).as_bytes(), &mut v).unwrap();
+        assert_eq!(v.nr_devices, 1);
+        assert_eq!(v.nr_mapped, 3);
+    }
+
+    fn write_labelled(version: Option<u32>, labels: &DeviceLabels) -> Result<String> {
+        let mut buf = Vec::new();
+        let mut w = XmlWriter::new(&mut buf);
+        w.superblock_b(&Superblock {
+            uuid: "".to_string(),
+            time: 0,
+            transaction: 1,
+            flags: None,
+            version,
+            data_block_size: 128,
+            nr_data_blocks: 100,
+            metadata_snap: None,
+        })?;
+        w.device_b(&Device {
+            dev_id: 1,
+            mapped_blocks: 0,
+            transaction: 0,
+            creation_time: 0,
+            snap_time: 0,
+        })?;
+        w.device_labels(labels)?;
+        w.device_e()?;
+        w.superblock_e()?;
+        w.eof()?;
+        Ok(String::from_utf8(buf)?)
+    }
+
+    #[test]
+    fn test_labels_round_trip() -> Result<()> {
+        let mut labels = DeviceLabels {
+            label: Some("db <main> & \"co\"".to_string()),
+            uuid: Some("1234".to_string()),
+            ..Default::default()
+        };
+        labels
+            .attributes
+            .insert("owner".to_string(), "k8s".to_string());
+
+        let xml = write_labelled(Some(LABELS_XML_VERSION), &labels)?;
+        assert!(xml.contains(&format!("version=\"{}\"", LABELS_XML_VERSION)));
+
+        let mut v = Counter::default();
+        read(xml.as_bytes(), &mut v)?;
+        assert_eq!(v.labels, vec![labels]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_written() -> Result<()> {
+        let labels = DeviceLabels::default();
+        assert!(write_labelled(None, &labels)?.contains("version=\"2\""));
+        assert!(write_labelled(Some(1), &labels)?.contains("version=\"2\""));
+        assert!(write_labelled(Some(XML_VERSION + 1), &labels)?
+            .contains(&format!("version=\"{}\"", XML_VERSION)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_attribute_outside_labels() {
+        let xml = r#"<superblock uuid="" time="0" transaction="1" version="3" data_block_size="128" nr_data_blocks="100">
+  <device dev_id="1" mapped_blocks="0" transaction="0" creation_time="0" snap_time="0">
+    <attribute key="owner" value="k8s"/>
+  </device>
+</superblock>"#;
+        let mut v = Counter::default();
+        assert!(read(xml.as_bytes(), &mut v).is_err());
+    }
+}
+
+//---------------------------------------
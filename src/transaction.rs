@@ -0,0 +1,417 @@
+use fixedbitset::FixedBitSet;
+use std::collections::BTreeMap;
+use std::io::{self, Result};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::io_engine::*;
+use crate::memory;
+
+//------------------------------------------
+
+// Thin, cache and era metadata all keep their superblock in block 0.
+const SUPERBLOCK_LOCATION: u64 = 0;
+
+/// An IoEngine for tools that change metadata in place.  Writes are
+/// held in memory, as shadows of the blocks they replace, until
+/// commit().  Reads see the shadows, so the tool sees its own changes,
+/// but the device doesn't, and a tool that fails, or is killed, part
+/// way through leaves the metadata as it was.
+///
+/// The superblock holds the roots of everything else, so changes are
+/// written copy-on-write, to blocks the metadata on the device doesn't
+/// use, and commit() writes the superblock last, once they've been
+/// flushed.  It's the only block overwritten in place, so a crash while
+/// committing leaves either the old metadata or the new.  protect()
+/// marks the blocks in use, and commit() refuses to overwrite them.
+///
+/// The shadows are claimed against the memory budget.  If it runs out,
+/// the shadows of blocks that aren't protected are written early to
+/// make room, which leaves the metadata on the device intact, since it
+/// doesn't use them.  Nothing is written early before protect() has
+/// been called.
+pub struct TransactionEngine {
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    shadows: RwLock<BTreeMap<u64, Vec<u8>>>,
+    protected: RwLock<FixedBitSet>,
+    claim: Mutex<memory::Claim>,
+}
+
+fn to_block(loc: u64, data: &[u8]) -> Block {
+    let b = Block::new(loc);
+    b.get_data().copy_from_slice(data);
+    b
+}
+
+impl TransactionEngine {
+    pub fn new(engine: Arc<dyn IoEngine + Send + Sync>) -> TransactionEngine {
+        TransactionEngine::with_claim(engine, memory::claim_up_to(0, 0))
+    }
+
+    // The shadows grow the claim, from whichever budget it's against.
+    fn with_claim(
+        engine: Arc<dyn IoEngine + Send + Sync>,
+        claim: memory::Claim,
+    ) -> TransactionEngine {
+        TransactionEngine {
+            engine,
+            shadows: RwLock::new(BTreeMap::new()),
+            protected: RwLock::new(FixedBitSet::new()),
+            claim: Mutex::new(claim),
+        }
+    }
+
+    fn release_shadows(&self) {
+        let mut claim = self.claim.lock().unwrap();
+        let bytes = claim.bytes();
+        claim.shrink(bytes);
+    }
+
+    // Writes out the shadows of the blocks that aren't protected,
+    // returning how many there were.
+    fn write_early(&self, shadows: &mut BTreeMap<u64, Vec<u8>>) -> Result<usize> {
+        let protected = self.protected.read().unwrap();
+        if protected.len() == 0 {
+            return Ok(0);
+        }
+
+        let blocks: Vec<Block> = shadows
+            .iter()
+            .filter(|(loc, _)| {
+                **loc != SUPERBLOCK_LOCATION
+                    && (**loc as usize) < protected.len()
+                    && !protected.contains(**loc as usize)
+            })
+            .map(|(loc, data)| to_block(*loc, data))
+            .collect();
+        for chunk in blocks.chunks(self.engine.get_batch_size()) {
+            for r in self.engine.write_many(chunk)? {
+                r?;
+            }
+        }
+
+        for b in &blocks {
+            shadows.remove(&b.loc);
+        }
+        Ok(blocks.len())
+    }
+
+    fn add_shadows(&self, blocks: &[Block]) -> Result<()> {
+        let mut shadows = self.shadows.write().unwrap();
+        let mut claim = self.claim.lock().unwrap();
+        for b in blocks {
+            if shadows.insert(b.loc, b.get_data().to_vec()).is_some()
+                || claim.try_grow(BLOCK_SIZE as u64)
+            {
+                continue;
+            }
+
+            // The claim doesn't cover this shadow yet.
+            let nr_written = self.write_early(&mut shadows)?;
+            claim.shrink((nr_written * BLOCK_SIZE) as u64);
+            let needed = (shadows.len() * BLOCK_SIZE) as u64;
+            if needed > claim.bytes() {
+                let bytes = needed - claim.bytes();
+                claim.grow(bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks blocks the metadata on the device uses, which commit() may
+    /// not overwrite.  The superblock is always written.
+    pub fn protect(&self, blocks: &FixedBitSet) {
+        let mut protected = self.protected.write().unwrap();
+        protected.grow(blocks.len());
+        protected.union_with(blocks);
+    }
+
+    /// The number of blocks changed since the last commit.
+    pub fn nr_shadowed(&self) -> usize {
+        self.shadows.read().unwrap().len()
+    }
+
+    /// Writes out the changes, the superblock last, and returns the
+    /// number of blocks written.  Nothing is written if a change would
+    /// overwrite a protected block.
+    pub fn commit(&self) -> Result<usize> {
+        let mut shadows = std::mem::take(&mut *self.shadows.write().unwrap());
+        self.release_shadows();
+        let nr_blocks = shadows.len();
+        let sb = shadows.remove(&SUPERBLOCK_LOCATION);
+
+        let protected = self.protected.read().unwrap();
+        if let Some(loc) = shadows
+            .keys()
+            .find(|loc| protected.contains(**loc as usize))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("block {} is in use, and can't be overwritten", loc),
+            ));
+        }
+
+        let blocks: Vec<Block> = shadows
+            .iter()
+            .map(|(loc, data)| to_block(*loc, data))
+            .collect();
+        for chunk in blocks.chunks(self.engine.get_batch_size()) {
+            for r in self.engine.write_many(chunk)? {
+                r?;
+            }
+        }
+
+        if let Some(sb) = sb {
+            self.engine.flush()?;
+            self.engine.write(&to_block(SUPERBLOCK_LOCATION, &sb))?;
+        }
+        self.engine.flush()?;
+        Ok(nr_blocks)
+    }
+
+    /// Throws away the changes.
+    pub fn abort(&self) {
+        self.shadows.write().unwrap().clear();
+        self.release_shadows();
+    }
+}
+
+impl IoEngine for TransactionEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.engine.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.engine.get_batch_size()
+    }
+
+    fn read(&self, loc: u64) -> Result<Block> {
+        if let Some(data) = self.shadows.read().unwrap().get(&loc) {
+            return Ok(to_block(loc, data));
+        }
+        self.engine.read(loc)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        let shadows = self.shadows.read().unwrap();
+        if shadows.is_empty() {
+            return self.engine.read_many(blocks);
+        }
+
+        let unshadowed: Vec<u64> = blocks
+            .iter()
+            .filter(|loc| !shadows.contains_key(loc))
+            .copied()
+            .collect();
+        let mut read = self.engine.read_many(&unshadowed)?.into_iter();
+
+        Ok(blocks
+            .iter()
+            .map(|loc| match shadows.get(loc) {
+                Some(data) => Ok(to_block(*loc, data)),
+                None => read.next().unwrap(),
+            })
+            .collect())
+    }
+
+    fn write(&self, b: &Block) -> Result<()> {
+        self.add_shadows(std::slice::from_ref(b))
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        self.add_shadows(blocks)?;
+        Ok(blocks.iter().map(|_| Ok(())).collect())
+    }
+
+    // Nothing in use reaches the device until commit().
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault_engine::{FaultEngine, FaultScript};
+    use std::sync::Mutex;
+
+    // Blocks are filled with a single byte, and writes are logged.
+    struct LoggingEngine {
+        blocks: Mutex<Vec<u8>>,
+        log: Mutex<Vec<Option<u64>>>,
+    }
+
+    impl LoggingEngine {
+        fn new(nr_blocks: u64) -> LoggingEngine {
+            LoggingEngine {
+                blocks: Mutex::new((0..nr_blocks).map(|loc| loc as u8).collect()),
+                log: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn contents(&self, loc: u64) -> u8 {
+            self.blocks.lock().unwrap()[loc as usize]
+        }
+    }
+
+    impl IoEngine for LoggingEngine {
+        fn get_nr_blocks(&self) -> u64 {
+            self.blocks.lock().unwrap().len() as u64
+        }
+
+        fn get_batch_size(&self) -> usize {
+            2
+        }
+
+        fn read(&self, loc: u64) -> Result<Block> {
+            Ok(filled(loc, self.contents(loc)))
+        }
+
+        fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+            Ok(blocks.iter().map(|loc| self.read(*loc)).collect())
+        }
+
+        fn write(&self, b: &Block) -> Result<()> {
+            self.blocks.lock().unwrap()[b.loc as usize] = b.get_data()[0];
+            self.log.lock().unwrap().push(Some(b.loc));
+            Ok(())
+        }
+
+        fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+            Ok(blocks.iter().map(|b| self.write(b)).collect())
+        }
+
+        // Logged as None.
+        fn flush(&self) -> Result<()> {
+            self.log.lock().unwrap().push(None);
+            Ok(())
+        }
+    }
+
+    fn filled(loc: u64, byte: u8) -> Block {
+        let b = Block::new(loc);
+        b.get_data().fill(byte);
+        b
+    }
+
+    fn byte(b: Result<Block>) -> u8 {
+        b.unwrap().get_data()[0]
+    }
+
+    #[test]
+    fn test_writes_are_shadowed() -> Result<()> {
+        let engine = Arc::new(LoggingEngine::new(8));
+        let txn = TransactionEngine::new(engine.clone());
+
+        txn.write(&filled(3, 0xaa))?;
+        txn.write_many(&[filled(5, 0xbb), filled(3, 0xcc)])?;
+        assert_eq!(txn.nr_shadowed(), 2);
+
+        assert_eq!(byte(txn.read(3)), 0xcc);
+        let bs: Vec<u8> = txn
+            .read_many(&[2, 3, 4, 5])?
+            .into_iter()
+            .map(byte)
+            .collect();
+        assert_eq!(bs, vec![2, 0xcc, 4, 0xbb]);
+
+        assert_eq!(engine.contents(3), 3);
+        assert!(engine.log.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_superblock_is_committed_last() -> Result<()> {
+        let engine = Arc::new(LoggingEngine::new(8));
+        let txn = TransactionEngine::new(engine.clone());
+
+        txn.write_many(&[filled(0, 0xaa), filled(6, 0xbb), filled(2, 0xcc)])?;
+        assert_eq!(txn.commit()?, 3);
+        assert_eq!(txn.nr_shadowed(), 0);
+
+        assert_eq!(
+            *engine.log.lock().unwrap(),
+            vec![Some(2), Some(6), None, Some(0), None]
+        );
+        assert_eq!(engine.contents(0), 0xaa);
+        assert_eq!(engine.contents(6), 0xbb);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_commit_keeps_the_old_superblock() -> anyhow::Result<()> {
+        let engine = Arc::new(LoggingEngine::new(8));
+        let script = "write 6 eio".parse::<FaultScript>()?;
+        let txn = TransactionEngine::new(Arc::new(FaultEngine::new(engine.clone(), script)));
+
+        txn.write_many(&[filled(0, 0xaa), filled(6, 0xbb)])?;
+        assert!(txn.commit().is_err());
+        assert_eq!(engine.contents(0), 0);
+        assert_eq!(engine.contents(6), 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_protected_blocks_are_never_overwritten() -> Result<()> {
+        let engine = Arc::new(LoggingEngine::new(8));
+        let txn = TransactionEngine::new(engine.clone());
+
+        let mut in_use = FixedBitSet::with_capacity(8);
+        in_use.insert(0);
+        in_use.insert(6);
+        txn.protect(&in_use);
+
+        txn.write_many(&[filled(0, 0xaa), filled(6, 0xbb)])?;
+        assert!(txn.commit().is_err());
+        assert!(engine.log.lock().unwrap().is_empty());
+
+        txn.write_many(&[filled(0, 0xaa), filled(5, 0xbb)])?;
+        assert_eq!(txn.commit()?, 2);
+        assert_eq!(engine.contents(0), 0xaa);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shadows_written_early_when_memory_runs_out() -> Result<()> {
+        let engine = Arc::new(LoggingEngine::new(8));
+        let budget = memory::test_budget(Some(2 * BLOCK_SIZE as u64));
+        let txn = TransactionEngine::with_claim(engine.clone(), budget.claim_up_to(0, 0));
+
+        // Nothing's written early until the blocks in use are known.
+        txn.write_many(&[filled(0, 0xaa), filled(3, 0xbb), filled(4, 0xcc)])?;
+        assert!(engine.log.lock().unwrap().is_empty());
+        assert_eq!(txn.nr_shadowed(), 3);
+
+        let mut in_use = FixedBitSet::with_capacity(8);
+        in_use.insert(0);
+        in_use.insert(6);
+        txn.protect(&in_use);
+
+        // The shadows of 3, 4 and 5 make room, but the superblock and 6
+        // stay in memory.
+        txn.write_many(&[filled(5, 0xdd), filled(6, 0xee)])?;
+        assert_eq!(txn.nr_shadowed(), 2);
+        assert_eq!(engine.contents(3), 0xbb);
+        assert_eq!(engine.contents(6), 6);
+        assert_eq!(byte(txn.read(4)), 0xcc);
+        assert_eq!(byte(txn.read(6)), 0xee);
+        assert!(txn.commit().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_abort_changes_nothing() -> Result<()> {
+        let engine = Arc::new(LoggingEngine::new(8));
+        let txn = TransactionEngine::new(engine.clone());
+
+        txn.write(&filled(0, 0xaa))?;
+        txn.abort();
+        assert_eq!(byte(txn.read(0)), 0);
+        assert_eq!(txn.commit()?, 0);
+        assert_eq!(engine.contents(0), 0);
+        Ok(())
+    }
+}
+
+//------------------------------------------
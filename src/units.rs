@@ -1,4 +1,4 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, Result};
 use std::str::FromStr;
 
 //------------------------------------------
@@ -47,7 +47,7 @@ impl Units {
 impl FromStr for Units {
     type Err = anyhow::Error;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s {
             "byte" | "b" => Ok(Units::Byte),
             "sector" | "s" => Ok(Units::Sector),
@@ -102,3 +102,145 @@ pub fn to_units(bytes: u64, unit: Units) -> f64 {
 }
 
 //------------------------------------------
+
+// Splits '128k' into (128, Some(Kibibyte)).
+fn split_size(s: &str) -> Result<(u64, Option<Units>)> {
+    let s = s.trim();
+    let i = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    if i == 0 {
+        return Err(anyhow!("invalid size '{}'", s));
+    }
+
+    let n = s[..i]
+        .parse::<u64>()
+        .map_err(|_| anyhow!("invalid size '{}'", s))?;
+    let unit = if i == s.len() {
+        None
+    } else {
+        Some(
+            s[i..]
+                .parse::<Units>()
+                .map_err(|_| anyhow!("invalid unit in size '{}'", s))?,
+        )
+    };
+
+    Ok((n, unit))
+}
+
+fn checked_bytes(n: u64, unit: &Units, s: &str) -> Result<u64> {
+    n.checked_mul(unit.size_bytes())
+        .ok_or_else(|| anyhow!("size '{}' is too large", s))
+}
+
+/// True if the size has an explicit unit, eg, '4T' rather than '4'.
+pub fn has_unit_suffix(s: &str) -> bool {
+    matches!(split_size(s), Ok((_, Some(_))))
+}
+
+/// Parses a size such as '2048', '128k' or '4T' into bytes.  The
+/// suffixes are those accepted by Units, so lower case letters are
+/// powers of two and upper case powers of ten.  A number without a
+/// suffix is taken to be in default_unit.
+pub fn parse_size(s: &str, default_unit: Units) -> Result<u64> {
+    let (n, unit) = split_size(s)?;
+    checked_bytes(n, &unit.unwrap_or(default_unit), s)
+}
+
+/// As parse_size, but returns a whole number of sectors.
+pub fn parse_sectors(s: &str) -> Result<u64> {
+    let bytes = parse_size(s, Units::Sector)?;
+    if bytes % 512 != 0 {
+        return Err(anyhow!("size '{}' is not a multiple of 512 bytes", s));
+    }
+    Ok(bytes / 512)
+}
+
+/// A number of data blocks given on the command line.  Either a plain
+/// count, or an amount of storage (eg, '4T') which can only be turned
+/// into blocks once the block size is known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockCount {
+    Blocks(u64),
+    Bytes(u64),
+}
+
+impl FromStr for BlockCount {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match split_size(s)? {
+            (n, None) => Ok(BlockCount::Blocks(n)),
+            (n, Some(unit)) => Ok(BlockCount::Bytes(checked_bytes(n, &unit, s)?)),
+        }
+    }
+}
+
+impl BlockCount {
+    /// Resolves to a number of blocks, given the block size in sectors.
+    /// Sizes that aren't a whole number of blocks are rejected rather
+    /// than rounded.
+    pub fn to_blocks(&self, block_size: u64) -> Result<u64> {
+        match self {
+            BlockCount::Blocks(n) => Ok(*n),
+            BlockCount::Bytes(bytes) => {
+                let block_bytes = block_size * 512;
+                if block_bytes == 0 {
+                    return Err(anyhow!("block size must be non-zero"));
+                }
+                if bytes % block_bytes != 0 {
+                    return Err(anyhow!(
+                        "{} bytes is not a whole number of {} sector blocks",
+                        bytes,
+                        block_size
+                    ));
+                }
+                Ok(bytes / block_bytes)
+            }
+        }
+    }
+
+    pub fn needs_block_size(&self) -> bool {
+        matches!(self, BlockCount::Bytes(_))
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("2048", Units::Sector).unwrap(), 2048 * 512);
+        assert_eq!(parse_size("128k", Units::Sector).unwrap(), 128 * 1024);
+        assert_eq!(parse_size("2G", Units::Byte).unwrap(), 2000000000);
+        assert!(parse_size("k", Units::Byte).is_err());
+        assert!(parse_size("12x", Units::Byte).is_err());
+        assert!(parse_size("100000e", Units::Byte).is_err());
+    }
+
+    #[test]
+    fn test_parse_sectors() {
+        assert_eq!(parse_sectors("64k").unwrap(), 128);
+        assert_eq!(parse_sectors("128").unwrap(), 128);
+        assert!(parse_sectors("1000b").is_err());
+    }
+
+    #[test]
+    fn test_block_count() {
+        let n: BlockCount = "1024".parse().unwrap();
+        assert_eq!(n.to_blocks(0).unwrap(), 1024);
+
+        let n: BlockCount = "4t".parse().unwrap();
+        assert!(n.needs_block_size());
+        assert_eq!(n.to_blocks(128).unwrap(), 64 * 1024 * 1024);
+        assert!("1000K"
+            .parse::<BlockCount>()
+            .unwrap()
+            .to_blocks(128)
+            .is_err());
+    }
+}
+
+//------------------------------------------
@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Result};
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex};
+use tracing::instrument;
 
 use crate::checksum;
 use crate::io_engine::*;
@@ -24,6 +25,9 @@ pub struct WriteBatcher {
     // transactional fashion, that simplifies block allocationas
     // as well as tracking.
     reserved: std::ops::Range<u64>,
+
+    // Blocks claimed at fixed locations, outside the reserved range.
+    pinned: Vec<u64>,
 }
 
 pub fn find_free(sm: &mut dyn SpaceMap, reserved: &std::ops::Range<u64>) -> Result<u64> {
@@ -62,6 +66,7 @@ impl WriteBatcher {
                 start: alloc_begin,
                 end: alloc_begin,
             },
+            pinned: Vec::new(),
         }
     }
 
@@ -85,6 +90,22 @@ impl WriteBatcher {
         Ok(Block::zeroed(b))
     }
 
+    /// Claims a block at a fixed location, so it's neither handed out by
+    /// alloc() nor recorded as free when the space map is written.
+    pub fn pin(&mut self, b: u64) -> Result<()> {
+        let mut sm = self.sm.lock().unwrap();
+        if b >= sm.get_nr_blocks()? || sm.get(b)? != 0 || self.reserved.contains(&b) {
+            return Err(anyhow!("block {} is not available", b));
+        }
+        sm.set(b, 1)?;
+        self.pinned.push(b);
+        Ok(())
+    }
+
+    pub fn get_pinned(&self) -> &[u64] {
+        &self.pinned
+    }
+
     pub fn get_reserved_range(&self) -> std::ops::Range<u64> {
         std::ops::Range {
             start: self.reserved.start,
@@ -127,11 +148,14 @@ impl WriteBatcher {
             .map_err(|_| anyhow!("read block error"))
     }
 
-    pub fn flush_(&mut self, queue: Vec<Block>) -> Result<()> {
+    pub fn flush_(&mut self, mut queue: Vec<Block>) -> Result<()> {
+        // Sorted, so the engine can coalesce adjacent blocks.
+        queue.sort_by_key(|b| b.loc);
         self.engine.write_many(&queue)?;
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     pub fn flush(&mut self) -> Result<()> {
         let mut tmp = Vec::new();
         std::mem::swap(&mut tmp, &mut self.queue);
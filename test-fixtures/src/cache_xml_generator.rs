@@ -0,0 +1,201 @@
+use anyhow::Result;
+use quickcheck::{Arbitrary, Gen};
+use rand::prelude::*;
+use rand::seq::index::sample;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::path::Path;
+use thinp::cache::ir::{self, MetadataVisitor};
+use thinp::cache::xml;
+
+use crate::random::test_rng;
+use crate::round_trip::{model_rng, shrink_list, Model};
+
+//------------------------------------------
+
+pub trait XmlGen {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()>;
+}
+
+pub fn write_xml(path: &Path, g: &mut dyn XmlGen) -> Result<()> {
+    let xml_out = OpenOptions::new()
+        .read(false)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    let mut w = xml::XmlWriter::new(xml_out);
+
+    g.generate_xml(&mut w)
+}
+
+pub struct CacheGen {
+    block_size: u32,
+    nr_cache_blocks: u32,
+    nr_origin_blocks: u64,
+    percent_resident: u8,
+    #[allow(dead_code)]
+    percent_dirty: u8,
+}
+
+impl CacheGen {
+    pub fn new(
+        block_size: u32,
+        nr_cache_blocks: u32,
+        nr_origin_blocks: u64,
+        percent_resident: u8,
+        percent_dirty: u8,
+    ) -> Self {
+        CacheGen {
+            block_size,
+            nr_cache_blocks,
+            nr_origin_blocks,
+            percent_resident,
+            percent_dirty,
+        }
+    }
+}
+
+impl XmlGen for CacheGen {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        v.superblock_b(&ir::Superblock {
+            uuid: "".to_string(),
+            block_size: self.block_size,
+            nr_cache_blocks: self.nr_cache_blocks,
+            policy: "smq".to_string(),
+            hint_width: 4,
+        })?;
+
+        let nr_resident = (self.nr_cache_blocks * self.percent_resident as u32) / 100u32;
+        let mut cblocks = (0..self.nr_cache_blocks).collect::<Vec<u32>>();
+        let mut rng = test_rng();
+        cblocks.shuffle(&mut rng);
+        cblocks.truncate(nr_resident as usize);
+        cblocks.sort_unstable();
+
+        v.mappings_b()?;
+        {
+            let mut used = HashSet::new();
+            for cblock in cblocks {
+                let mut oblock = 0u64;
+                while used.contains(&oblock) {
+                    oblock = rng.gen_range(0..self.nr_origin_blocks);
+                }
+
+                used.insert(oblock);
+                // FIXME: dirty should vary
+                v.mapping(&ir::Map {
+                    cblock,
+                    oblock,
+                    dirty: false,
+                })?;
+            }
+        }
+        v.mappings_e()?;
+
+        v.superblock_e()?;
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+// A random cache for the round trip properties.  Every mapping has a
+// hint, holding an smq style level.
+#[derive(Clone, Debug)]
+pub struct CacheModel {
+    pub nr_cache_blocks: u32,
+    pub mappings: Vec<CacheMappingModel>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheMappingModel {
+    pub cblock: u32,
+    pub oblock: u64,
+    pub dirty: bool,
+    pub level: u32,
+}
+
+impl Arbitrary for CacheModel {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let size = g.size() as u32;
+        let mut rng = model_rng(g);
+        let nr_cache_blocks = rng.gen_range(1..=size * 2);
+        let nr_origin_blocks = nr_cache_blocks as usize * 4;
+
+        let nr_mappings = rng.gen_range(0..=nr_cache_blocks) as usize;
+        let mut cblocks = sample(&mut rng, nr_cache_blocks as usize, nr_mappings).into_vec();
+        cblocks.sort_unstable();
+        let oblocks = sample(&mut rng, nr_origin_blocks, nr_mappings);
+
+        let mappings = cblocks
+            .into_iter()
+            .zip(oblocks)
+            .map(|(cblock, oblock)| CacheMappingModel {
+                cblock: cblock as u32,
+                oblock: oblock as u64,
+                dirty: rng.gen(),
+                level: rng.gen_range(0..64),
+            })
+            .collect();
+
+        CacheModel {
+            nr_cache_blocks,
+            mappings,
+        }
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let nr_cache_blocks = self.nr_cache_blocks;
+        Box::new(
+            shrink_list(&self.mappings)
+                .into_iter()
+                .map(move |mappings| CacheModel {
+                    nr_cache_blocks,
+                    mappings,
+                }),
+        )
+    }
+}
+
+impl XmlGen for CacheModel {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        v.superblock_b(&ir::Superblock {
+            uuid: "".to_string(),
+            block_size: 128,
+            nr_cache_blocks: self.nr_cache_blocks,
+            policy: "smq".to_string(),
+            hint_width: 4,
+        })?;
+
+        v.mappings_b()?;
+        for m in &self.mappings {
+            v.mapping(&ir::Map {
+                cblock: m.cblock,
+                oblock: m.oblock,
+                dirty: m.dirty,
+            })?;
+        }
+        v.mappings_e()?;
+
+        v.hints_b()?;
+        for m in &self.mappings {
+            v.hint(&ir::Hint {
+                cblock: m.cblock,
+                data: m.level.to_le_bytes().to_vec(),
+            })?;
+        }
+        v.hints_e()?;
+
+        v.superblock_e()?;
+        Ok(())
+    }
+}
+
+impl Model for CacheModel {
+    fn write_xml(&self, path: &Path) -> Result<()> {
+        write_xml(path, &mut self.clone())
+    }
+}
+
+//------------------------------------------
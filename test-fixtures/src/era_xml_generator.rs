@@ -0,0 +1,278 @@
+use anyhow::Result;
+use quickcheck::{Arbitrary, Gen};
+use rand::prelude::*;
+use std::fs::OpenOptions;
+use std::ops::Range;
+use std::path::Path;
+use thinp::era::ir::{self, MetadataVisitor};
+use thinp::era::xml;
+
+use crate::random::test_rng;
+use crate::round_trip::{model_rng, shrink_list, Model};
+
+//------------------------------------------
+
+pub trait XmlGen {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()>;
+}
+
+pub fn write_xml(path: &Path, g: &mut dyn XmlGen) -> Result<()> {
+    let xml_out = OpenOptions::new()
+        .read(false)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    let mut w = xml::XmlWriter::new(xml_out, false);
+
+    g.generate_xml(&mut w)
+}
+
+//------------------------------------------
+
+// Ordered sequence generator where each element has an independent probability
+// of being present.
+struct IndependentSequence<'a, R: Rng> {
+    begin: u32,
+    end: u32,
+    prob: u32,
+    rng: &'a mut R,
+}
+
+impl<'a, R: Rng> IndependentSequence<'a, R> {
+    fn new(begin: u32, end: u32, prob: u32, rng: &'a mut R) -> IndependentSequence<'a, R> {
+        IndependentSequence {
+            begin,
+            end,
+            prob,
+            rng,
+        }
+    }
+}
+
+impl<'a, R: Rng> Iterator for IndependentSequence<'a, R> {
+    type Item = std::ops::Range<u32>;
+
+    // FIXME: reduce complexity
+    fn next(&mut self) -> Option<std::ops::Range<u32>> {
+        if self.begin >= self.end {
+            return None;
+        }
+
+        let mut b = self.begin;
+        while b < self.end && self.rng.gen_range(0..100) >= self.prob {
+            b += 1;
+        }
+
+        if b == self.end {
+            return None;
+        }
+
+        let mut e = b + 1;
+        while e < self.end && self.rng.gen_range(0..100) < self.prob {
+            e += 1;
+        }
+        self.begin = e + 1;
+
+        Some(std::ops::Range { start: b, end: e })
+    }
+}
+
+//------------------------------------------
+
+fn create_superblock(block_size: u32, nr_blocks: u32, current_era: u32) -> ir::Superblock {
+    ir::Superblock {
+        uuid: "".to_string(),
+        block_size,
+        nr_blocks,
+        current_era,
+    }
+}
+
+pub struct CleanShutdownMeta {
+    block_size: u32,
+    nr_blocks: u32,
+    current_era: u32,
+    nr_writesets: u32,
+}
+
+impl CleanShutdownMeta {
+    pub fn new(block_size: u32, nr_blocks: u32, current_era: u32, nr_writesets: u32) -> Self {
+        CleanShutdownMeta {
+            block_size,
+            nr_blocks,
+            current_era,
+            nr_writesets,
+        }
+    }
+
+    fn generate_writeset<R: Rng>(
+        v: &mut dyn MetadataVisitor,
+        ws: &ir::Writeset,
+        rng: &mut R,
+    ) -> Result<()> {
+        v.writeset_b(ws)?;
+        let gen = IndependentSequence::new(0, ws.nr_bits, 10, rng);
+        for seq in gen {
+            v.writeset_blocks(&ir::MarkedBlocks {
+                begin: seq.start,
+                len: seq.end - seq.start,
+            })?;
+        }
+        v.writeset_e()?;
+
+        Ok(())
+    }
+
+    fn generate_era_array<R: Rng>(
+        v: &mut dyn MetadataVisitor,
+        nr_blocks: u32,
+        max_era: u32,
+        rng: &mut R,
+    ) -> Result<()> {
+        v.era_b()?;
+        for b in 0..nr_blocks {
+            let era = rng.gen_range(0..max_era);
+            v.era(&ir::Era { block: b, era })?;
+        }
+        v.era_e()?;
+
+        Ok(())
+    }
+}
+
+impl XmlGen for CleanShutdownMeta {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        v.superblock_b(&create_superblock(
+            self.block_size,
+            self.nr_blocks,
+            self.current_era,
+        ))?;
+
+        let mut rng = test_rng();
+        let era_low = self.current_era - self.nr_writesets + 1;
+        for era in era_low..self.current_era + 1 {
+            Self::generate_writeset(
+                v,
+                &ir::Writeset {
+                    era,
+                    nr_bits: self.nr_blocks,
+                },
+                &mut rng,
+            )?;
+        }
+
+        Self::generate_era_array(v, self.nr_blocks, era_low, &mut rng)?;
+
+        v.superblock_e()?;
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+// A random era device for the round trip properties: write sets for
+// the most recent eras, and an era array predating them all.
+#[derive(Clone, Debug)]
+pub struct EraModel {
+    pub nr_blocks: u32,
+    pub current_era: u32,
+
+    // The marked blocks of the write sets, oldest first, ending with
+    // the current era.
+    pub writesets: Vec<Vec<Range<u32>>>,
+    pub eras: Vec<u32>,
+}
+
+impl EraModel {
+    fn era_low(&self) -> u32 {
+        self.current_era + 1 - self.writesets.len() as u32
+    }
+}
+
+impl Arbitrary for EraModel {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let size = g.size() as u32;
+        let mut rng = model_rng(g);
+        let nr_blocks = rng.gen_range(1..=size * 4);
+        let nr_writesets = rng.gen_range(1..=4);
+        let current_era = rng.gen_range(nr_writesets..nr_writesets + 100);
+
+        let mut writesets = Vec::new();
+        for _ in 0..nr_writesets {
+            let prob = rng.gen_range(5..50);
+            writesets.push(IndependentSequence::new(0, nr_blocks, prob, &mut rng).collect());
+        }
+
+        let era_low = current_era + 1 - nr_writesets;
+        let eras = (0..nr_blocks).map(|_| rng.gen_range(0..era_low)).collect();
+
+        EraModel {
+            nr_blocks,
+            current_era,
+            writesets,
+            eras,
+        }
+    }
+
+    // Dropping the oldest write sets leaves the era array valid.
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let mut smaller = Vec::new();
+        for n in 1..self.writesets.len() {
+            smaller.push(EraModel {
+                writesets: self.writesets[n..].to_vec(),
+                ..self.clone()
+            });
+        }
+
+        for (i, ws) in self.writesets.iter().enumerate() {
+            for ranges in shrink_list(ws) {
+                let mut m = self.clone();
+                m.writesets[i] = ranges;
+                smaller.push(m);
+            }
+        }
+
+        Box::new(smaller.into_iter())
+    }
+}
+
+impl XmlGen for EraModel {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        v.superblock_b(&create_superblock(128, self.nr_blocks, self.current_era))?;
+
+        for (era, ws) in (self.era_low()..).zip(self.writesets.iter()) {
+            v.writeset_b(&ir::Writeset {
+                era,
+                nr_bits: self.nr_blocks,
+            })?;
+            for r in ws {
+                v.writeset_blocks(&ir::MarkedBlocks {
+                    begin: r.start,
+                    len: r.end - r.start,
+                })?;
+            }
+            v.writeset_e()?;
+        }
+
+        v.era_b()?;
+        for (block, era) in self.eras.iter().enumerate() {
+            v.era(&ir::Era {
+                block: block as u32,
+                era: *era,
+            })?;
+        }
+        v.era_e()?;
+
+        v.superblock_e()?;
+        Ok(())
+    }
+}
+
+impl Model for EraModel {
+    fn write_xml(&self, path: &Path) -> Result<()> {
+        write_xml(path, &mut self.clone())
+    }
+}
+
+//------------------------------------------
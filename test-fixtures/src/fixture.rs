@@ -5,7 +5,7 @@ use std::path::PathBuf;
 
 use thinp::file_utils;
 
-use crate::common::test_dir::TestDir;
+use crate::test_dir::TestDir;
 
 //------------------------------------------
 
@@ -0,0 +1,23 @@
+//! Fixtures for testing code that reads or writes device-mapper thin,
+//! cache and era metadata: scratch directories, xml generators for
+//! realistic metadata, and helpers for damaging it.
+//!
+//! Everything random is derived from one seed, which is printed, and
+//! can be replayed by setting THINP_TEST_SEED.  The helpers that drive
+//! the C++ metadata generators, such as `thin::prep_metadata`, look for
+//! them in THINP_CPP_TOOLS_DIR.
+//!
+//! `round_trip` has property tests that any tool reading or writing
+//! metadata can opt into, run against random metadata models that
+//! shrink on failure.
+
+pub mod cache_xml_generator;
+pub mod era_xml_generator;
+pub mod fixture;
+pub mod process;
+pub mod random;
+pub mod round_trip;
+pub mod test_dir;
+pub mod thin;
+pub mod thin_xml_generator;
+pub mod tools;
@@ -21,6 +21,7 @@ macro_rules! args {
 pub struct Command {
     program: OsString,
     args: Vec<OsString>,
+    stdin: Option<Vec<u8>>,
 }
 
 #[macro_export]
@@ -34,13 +35,30 @@ macro_rules! cmd {
     };
 }
 
+// So a glob import of this module brings in the macros too.
+pub use crate::{args, cmd};
+
 impl Command {
     pub fn new(program: OsString, args: Vec<OsString>) -> Self {
-        Command { program, args }
+        Command {
+            program,
+            args,
+            stdin: None,
+        }
+    }
+
+    // Feeds the command this input, rather than inheriting stdin.
+    pub fn with_stdin(mut self, input: &str) -> Self {
+        self.stdin = Some(input.as_bytes().to_vec());
+        self
     }
 
     fn to_expr(&self) -> duct::Expression {
-        duct::cmd(&self.program, &self.args)
+        let expr = duct::cmd(&self.program, &self.args);
+        match &self.stdin {
+            Some(input) => expr.stdin_bytes(input.clone()),
+            None => expr,
+        }
     }
 }
 
@@ -0,0 +1,32 @@
+use rand::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Once;
+
+//------------------------------------------
+
+/// The seed everything random in a test run is derived from.  It's
+/// printed so a failure can be replayed, on any machine, by setting
+/// THINP_TEST_SEED to it.
+pub fn test_seed() -> u64 {
+    static INIT: Once = Once::new();
+    static SEED: AtomicU64 = AtomicU64::new(0);
+
+    INIT.call_once(|| {
+        let seed = match std::env::var("THINP_TEST_SEED") {
+            Ok(s) => s
+                .parse()
+                .unwrap_or_else(|_| panic!("bad THINP_TEST_SEED '{}'", s)),
+            Err(_) => thread_rng().gen(),
+        };
+        SEED.store(seed, Ordering::Relaxed);
+    });
+    let seed = SEED.load(Ordering::Relaxed);
+    eprintln!("THINP_TEST_SEED={}", seed);
+    seed
+}
+
+pub fn test_rng() -> StdRng {
+    StdRng::seed_from_u64(test_seed())
+}
+
+//------------------------------------------
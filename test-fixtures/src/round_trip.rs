@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Result};
+use quickcheck::{Arbitrary, Gen, QuickCheck, StdGen, TestResult};
+use rand::prelude::*;
+use rand07::SeedableRng as _;
+use std::fmt::Debug;
+use std::path::Path;
+
+use crate::fixture::*;
+use crate::process::*;
+use crate::random::test_seed;
+use crate::test_dir::TestDir;
+
+//------------------------------------------
+
+/// Random, but valid, metadata that can be written as xml.  The
+/// Arbitrary impl shrinks a failing model towards a minimal one.
+pub trait Model: Arbitrary + Debug {
+    fn write_xml(&self, path: &Path) -> Result<()>;
+}
+
+// Bounds the number of devices, mappings and blocks in a model.
+const GEN_SIZE: usize = 64;
+
+/// Runs the property against nr_tests models, shrinking any failure.
+/// The models are derived from the test seed, so a failure can be
+/// replayed with THINP_TEST_SEED.
+pub fn quickcheck<M: Model>(nr_tests: u64, prop: fn(M) -> TestResult) {
+    let rng = rand07::rngs::StdRng::seed_from_u64(test_seed());
+    QuickCheck::new()
+        .gen(StdGen::new(rng, GEN_SIZE))
+        .tests(nr_tests)
+        .quickcheck(prop);
+}
+
+// quickcheck's Gen is built on an older rand, so the models draw from
+// an rng seeded by it instead.
+pub(crate) fn model_rng<G: Gen>(g: &mut G) -> StdRng {
+    StdRng::seed_from_u64(g.next_u64())
+}
+
+// Smaller versions of a list for shrinking: each half, then the list
+// without each element in turn.
+pub(crate) fn shrink_list<T: Clone>(xs: &[T]) -> Vec<Vec<T>> {
+    let mut r = Vec::new();
+    if xs.len() > 1 {
+        let mid = xs.len() / 2;
+        r.push(xs[..mid].to_vec());
+        r.push(xs[mid..].to_vec());
+    }
+    for i in 0..xs.len() {
+        let mut ys = xs.to_vec();
+        ys.remove(i);
+        r.push(ys);
+    }
+    r
+}
+
+pub fn to_test_result(r: Result<()>) -> TestResult {
+    match r {
+        Ok(()) => TestResult::passed(),
+        Err(e) => TestResult::error(format!("{:?}", e)),
+    }
+}
+
+//------------------------------------------
+
+// An error rather than a panic, so each shrinking step doesn't print
+// a backtrace.
+fn ensure_same(dump1: &str, dump2: &str) -> Result<()> {
+    if dump1 != dump2 {
+        return Err(anyhow!(
+            "dumps differ:\n{}\n----- second dump -----\n{}",
+            dump1,
+            dump2
+        ));
+    }
+    Ok(())
+}
+
+fn restore_model<M, R>(td: &mut TestDir, model: &M, restore: &R) -> Result<std::path::PathBuf>
+where
+    M: Model,
+    R: Fn(&Path, &Path) -> Command,
+{
+    let xml = td.mk_path("model.xml");
+    model.write_xml(&xml)?;
+    let md = mk_zeroed_md(td)?;
+    run_ok(restore(&xml, &md))?;
+    Ok(md)
+}
+
+/// Restores the model, then checks that dumping it, restoring the dump
+/// and dumping again gives the same xml.  `restore` takes the xml and
+/// metadata paths, `dump` writes xml for the metadata to stdout.
+pub fn check_dump_restore_dump<M, R, D>(model: &M, restore: R, dump: D) -> Result<()>
+where
+    M: Model,
+    R: Fn(&Path, &Path) -> Command,
+    D: Fn(&Path) -> Command,
+{
+    let mut td = TestDir::new()?;
+    let md = restore_model(&mut td, model, &restore)?;
+    let dump1 = run_ok(dump(&md))?;
+
+    let xml = td.mk_path("dump.xml");
+    std::fs::write(&xml, &dump1)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(restore(&xml, &md2))?;
+    let dump2 = run_ok(dump(&md2))?;
+
+    ensure_same(&dump1, &dump2)
+}
+
+/// Restores the model, then checks that packing and unpacking the
+/// metadata doesn't change what's dumped.  `pack` and `unpack` take
+/// their input and output paths.
+pub fn check_pack_unpack<M, R, D, P, U>(
+    model: &M,
+    restore: R,
+    dump: D,
+    pack: P,
+    unpack: U,
+) -> Result<()>
+where
+    M: Model,
+    R: Fn(&Path, &Path) -> Command,
+    D: Fn(&Path) -> Command,
+    P: Fn(&Path, &Path) -> Command,
+    U: Fn(&Path, &Path) -> Command,
+{
+    let mut td = TestDir::new()?;
+    let md = restore_model(&mut td, model, &restore)?;
+
+    let packed = td.mk_path("meta.pack");
+    run_ok(pack(&md, &packed))?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(unpack(&packed, &md2))?;
+
+    ensure_same(&run_ok(dump(&md))?, &run_ok(dump(&md2))?)
+}
+
+//------------------------------------------
@@ -0,0 +1,122 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+use thinp::io_engine::*;
+
+use crate::fixture::*;
+use crate::process::*;
+use crate::random::test_seed;
+use crate::test_dir::TestDir;
+use crate::tools::*;
+
+//-----------------------------------------------
+
+/// Builds a pool with a thin device and ten snapshots of it, using the
+/// C++ metadata generators.
+pub fn prep_metadata(td: &mut TestDir) -> Result<PathBuf> {
+    let md = mk_zeroed_md(td)?;
+    let seed = test_seed();
+    let args = args!["-o", &md, "--format", "--nr-data-blocks", "102400"];
+    run_ok(thin_generate_metadata_cmd(args))?;
+
+    // Create a 2GB device
+    let args = args!["-o", &md, "--create-thin", "1"];
+    run_ok(thin_generate_metadata_cmd(args))?;
+    let seed_str = seed.to_string();
+    let args = args![
+        "-o",
+        &md,
+        "--dev-id",
+        "1",
+        "--size",
+        "2097152",
+        "--rw=randwrite",
+        "--seq-nr=16",
+        "--seed",
+        &seed_str
+    ];
+    run_ok(thin_generate_mappings_cmd(args))?;
+
+    // Take a few snapshots.
+    let mut snap_id = 2;
+    for _i in 0..10 {
+        // take a snapshot
+        let snap_id_str = snap_id.to_string();
+        let args = args!["-o", &md, "--create-snap", &snap_id_str, "--origin", "1"];
+        run_ok(thin_generate_metadata_cmd(args))?;
+
+        // partially overwrite the origin (64MB)
+        let seed_str = seed.wrapping_add(snap_id).to_string();
+        let args = args![
+            "-o",
+            &md,
+            "--dev-id",
+            "1",
+            "--size",
+            "2097152",
+            "--io-size",
+            "131072",
+            "--rw=randwrite",
+            "--seq-nr=16",
+            "--seed",
+            &seed_str
+        ];
+        run_ok(thin_generate_mappings_cmd(args))?;
+        snap_id += 1;
+    }
+
+    Ok(md)
+}
+
+pub fn set_needs_check(md: &PathBuf) -> Result<()> {
+    let args = args!["-o", &md, "--set-needs-check"];
+    run_ok(thin_generate_metadata_cmd(args))?;
+    Ok(())
+}
+
+pub fn generate_metadata_leaks(
+    md: &PathBuf,
+    nr_blocks: u64,
+    expected: u32,
+    actual: u32,
+) -> Result<()> {
+    let nr_blocks_str = nr_blocks.to_string();
+    let expected_str = expected.to_string();
+    let actual_str = actual.to_string();
+    let seed = test_seed().to_string();
+    let args = args![
+        "-o",
+        &md,
+        "--create-metadata-leaks",
+        "--nr-blocks",
+        &nr_blocks_str,
+        "--expected",
+        &expected_str,
+        "--actual",
+        &actual_str,
+        "--seed",
+        &seed
+    ];
+    run_ok(thin_generate_damage_cmd(args))?;
+
+    Ok(())
+}
+
+pub fn get_needs_check(md: &PathBuf) -> Result<bool> {
+    use thinp::thin::superblock::*;
+
+    let engine = SyncIoEngine::new(md, 1, false)?;
+    let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    Ok(sb.flags.needs_check)
+}
+
+pub fn set_incompat_flags(md: &Path, flags: u32) -> Result<()> {
+    use thinp::thin::superblock::*;
+
+    let engine = SyncIoEngine::new(md, 1, true)?;
+    let mut sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    sb.incompat_flags = flags;
+    write_superblock(&engine, SUPERBLOCK_LOCATION, &sb)
+}
+
+//-----------------------------------------------
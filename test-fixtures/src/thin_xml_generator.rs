@@ -0,0 +1,949 @@
+use anyhow::{anyhow, Result};
+use quickcheck::{Arbitrary, Gen};
+use rand::prelude::*;
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::ops::Range;
+use std::path::Path;
+use thinp::thin::ir::{self, MetadataVisitor};
+use thinp::thin::xml;
+
+use crate::random::test_rng;
+use crate::round_trip::{model_rng, shrink_list, Model};
+
+//------------------------------------------
+
+pub trait XmlGen {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()>;
+}
+
+pub fn write_xml(path: &Path, g: &mut dyn XmlGen) -> Result<()> {
+    let xml_out = OpenOptions::new()
+        .read(false)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    let mut w = xml::XmlWriter::new(xml_out);
+
+    g.generate_xml(&mut w)
+}
+
+fn common_sb(nr_blocks: u64) -> ir::Superblock {
+    ir::Superblock {
+        uuid: "".to_string(),
+        time: 0,
+        transaction: 1,
+        flags: None,
+        version: None,
+        data_block_size: 128,
+        nr_data_blocks: nr_blocks,
+        metadata_snap: None,
+    }
+}
+
+//------------------------------------------
+
+pub struct EmptyPoolS {}
+
+impl XmlGen for EmptyPoolS {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        v.superblock_b(&common_sb(1024))?;
+        v.superblock_e()?;
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+pub struct SingleThinS {
+    pub offset: u64,
+    pub len: u64,
+    pub old_nr_data_blocks: u64,
+    pub new_nr_data_blocks: u64,
+}
+
+impl SingleThinS {
+    pub fn new(offset: u64, len: u64, old_nr_data_blocks: u64, new_nr_data_blocks: u64) -> Self {
+        SingleThinS {
+            offset,
+            len,
+            old_nr_data_blocks,
+            new_nr_data_blocks,
+        }
+    }
+}
+
+impl XmlGen for SingleThinS {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        v.superblock_b(&common_sb(self.old_nr_data_blocks))?;
+        v.device_b(&ir::Device {
+            dev_id: 0,
+            mapped_blocks: self.len,
+            transaction: 0,
+            creation_time: 0,
+            snap_time: 0,
+        })?;
+        v.map(&ir::Map {
+            thin_begin: 0,
+            data_begin: self.offset,
+            time: 0,
+            len: self.len,
+            shared: None,
+        })?;
+        v.device_e()?;
+        v.superblock_e()?;
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+pub struct FragmentedS {
+    pub nr_thins: u32,
+    pub thin_size: u64,
+    pub old_nr_data_blocks: u64,
+    pub new_nr_data_blocks: u64,
+}
+
+impl FragmentedS {
+    pub fn new(nr_thins: u32, thin_size: u64) -> Self {
+        let old_size = (nr_thins as u64) * thin_size;
+        FragmentedS {
+            nr_thins,
+            thin_size,
+            old_nr_data_blocks: (nr_thins as u64) * thin_size,
+            new_nr_data_blocks: old_size * 3 / 4,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ThinRun {
+    thin_id: u32,
+    thin_begin: u64,
+    len: u64,
+}
+
+#[derive(Clone, Debug, Copy)]
+struct MappedRun {
+    thin_id: u32,
+    thin_begin: u64,
+    data_begin: u64,
+    len: u64,
+}
+
+fn mk_runs<R: Rng>(
+    thin_id: u32,
+    total_len: u64,
+    run_len: std::ops::Range<u64>,
+    rng: &mut R,
+) -> Vec<ThinRun> {
+    let mut runs = Vec::new();
+    let mut b = 0u64;
+    while b < total_len {
+        let len = u64::min(total_len - b, rng.gen_range(run_len.start..run_len.end));
+        runs.push(ThinRun {
+            thin_id,
+            thin_begin: b,
+            len,
+        });
+        b += len;
+    }
+    runs
+}
+
+impl XmlGen for FragmentedS {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        // Allocate each thin fully, in runs between 1 and 16.
+        let mut rng = test_rng();
+        let mut runs = Vec::new();
+        for thin in 0..self.nr_thins {
+            runs.append(&mut mk_runs(thin, self.thin_size, 1..17, &mut rng));
+        }
+
+        // Shuffle
+        runs.shuffle(&mut rng);
+
+        // map across the data
+        let mut maps = Vec::new();
+        let mut b = 0;
+        for r in &runs {
+            maps.push(MappedRun {
+                thin_id: r.thin_id,
+                thin_begin: r.thin_begin,
+                data_begin: b,
+                len: r.len,
+            });
+            b += r.len;
+        }
+
+        // drop half the mappings, which leaves us free runs
+        let mut dropped = Vec::new();
+        for (i, m) in maps.iter().enumerate() {
+            if i % 2 == 0 {
+                dropped.push(*m);
+            }
+        }
+
+        // Unshuffle.  This isn't strictly necc. but makes the xml
+        // more readable.
+        use std::cmp::Ordering;
+        maps.sort_by(|&l, &r| match l.thin_id.cmp(&r.thin_id) {
+            Ordering::Equal => l.thin_begin.cmp(&r.thin_begin),
+            o => o,
+        });
+
+        // write the xml
+        v.superblock_b(&common_sb(self.old_nr_data_blocks))?;
+        for thin in 0..self.nr_thins {
+            v.device_b(&ir::Device {
+                dev_id: thin,
+                mapped_blocks: self.thin_size,
+                transaction: 0,
+                creation_time: 0,
+                snap_time: 0,
+            })?;
+
+            for m in &dropped {
+                if m.thin_id != thin {
+                    continue;
+                }
+
+                v.map(&ir::Map {
+                    thin_begin: m.thin_begin,
+                    data_begin: m.data_begin,
+                    time: 0,
+                    len: m.len,
+                    shared: None,
+                })?;
+            }
+
+            v.device_e()?;
+        }
+        v.superblock_e()?;
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+struct Allocator {
+    runs: VecDeque<Range<u64>>,
+}
+
+impl Allocator {
+    fn new_shuffled<R: Rng>(total_len: u64, run_len: Range<u64>, rng: &mut R) -> Allocator {
+        let mut runs = Vec::new();
+
+        let mut b = 0u64;
+        while b < total_len {
+            let len = u64::min(total_len - b, rng.gen_range(run_len.start..run_len.end));
+            runs.push(b..(b + len));
+            b += len;
+        }
+
+        runs.shuffle(rng);
+        let runs: VecDeque<Range<u64>> = runs.iter().cloned().collect();
+        Allocator { runs }
+    }
+
+    #[allow(dead_code)]
+    fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    fn alloc(&mut self, len: u64) -> Result<Vec<Range<u64>>> {
+        let mut len = len;
+        let mut runs = Vec::new();
+
+        while len > 0 {
+            let r = self.runs.pop_front();
+
+            if r.is_none() {
+                return Err(anyhow!("could not allocate; out of space"));
+            }
+
+            let r = r.unwrap();
+            let rlen = r.end - r.start;
+            if len < rlen {
+                runs.push(r.start..(r.start + len));
+
+                // We need to push something back.
+                self.runs.push_front((r.start + len)..r.end);
+                len = 0;
+            } else {
+                runs.push(r.start..r.end);
+                len -= rlen;
+            }
+        }
+
+        Ok(runs)
+    }
+}
+
+// Having explicitly unmapped regions makes it easier to
+// apply snapshots.
+#[derive(Clone)]
+enum Run {
+    Mapped {
+        data_begin: u64,
+        len: u64,
+        time: u32,
+    },
+    UnMapped {
+        len: u64,
+    },
+}
+
+impl Run {
+    #[allow(dead_code)]
+    fn len(&self) -> u64 {
+        match self {
+            Run::Mapped { len, .. } => *len,
+            Run::UnMapped { len } => *len,
+        }
+    }
+
+    fn split(&self, n: u64) -> (Option<Run>, Option<Run>) {
+        if n == 0 {
+            (None, Some(self.clone()))
+        } else if self.len() <= n {
+            (Some(self.clone()), None)
+        } else {
+            match self {
+                Run::Mapped {
+                    data_begin,
+                    len,
+                    time,
+                } => (
+                    Some(Run::Mapped {
+                        data_begin: *data_begin,
+                        len: n,
+                        time: *time,
+                    }),
+                    Some(Run::Mapped {
+                        data_begin: data_begin + n,
+                        len: len - n,
+                        time: *time,
+                    }),
+                ),
+                Run::UnMapped { len } => (
+                    Some(Run::UnMapped { len: n }),
+                    Some(Run::UnMapped { len: len - n }),
+                ),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ThinDev {
+    thin_id: u32,
+    #[allow(dead_code)]
+    dev_size: u64,
+    creation_time: u32,
+    snap_time: u32,
+    runs: Vec<Run>,
+}
+
+impl ThinDev {
+    fn mapped_blocks(&self) -> u64 {
+        self.runs
+            .iter()
+            .map(|r| match r {
+                Run::Mapped { len, .. } => *len,
+                Run::UnMapped { .. } => 0,
+            })
+            .sum()
+    }
+
+    fn emit(&self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        v.device_b(&ir::Device {
+            dev_id: self.thin_id,
+            mapped_blocks: self.mapped_blocks(),
+            transaction: 0,
+            creation_time: self.creation_time,
+            snap_time: self.snap_time,
+        })?;
+
+        let mut b = 0;
+        for r in &self.runs {
+            match r {
+                Run::Mapped {
+                    data_begin,
+                    len,
+                    time,
+                } => {
+                    v.map(&ir::Map {
+                        thin_begin: b,
+                        data_begin: *data_begin,
+                        time: *time,
+                        len: *len,
+                        shared: None,
+                    })?;
+                    b += len;
+                }
+                Run::UnMapped { len } => {
+                    b += len;
+                }
+            }
+        }
+
+        v.device_e()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+enum SnapRunType {
+    Same,
+    Diff,
+    Hole,
+}
+
+#[derive(Clone)]
+struct SnapRun(SnapRunType, u64);
+
+fn mk_origin<R: Rng>(
+    thin_id: u32,
+    total_len: u64,
+    allocator: &mut Allocator,
+    rng: &mut R,
+) -> Result<ThinDev> {
+    let mut runs = Vec::new();
+    let mut b = 0;
+    while b < total_len {
+        let len = u64::min(rng.gen_range(16..64), total_len - b);
+        match rng.gen_range(0..2) {
+            0 => {
+                for data in allocator.alloc(len)? {
+                    assert!(data.end >= data.start);
+                    runs.push(Run::Mapped {
+                        data_begin: data.start,
+                        len: data.end - data.start,
+                        time: 0,
+                    });
+                }
+            }
+            1 => {
+                runs.push(Run::UnMapped { len });
+            }
+            _ => {
+                return Err(anyhow!("bad value returned from rng"));
+            }
+        };
+
+        b += len;
+    }
+
+    Ok(ThinDev {
+        thin_id,
+        dev_size: total_len,
+        creation_time: 0,
+        snap_time: 0,
+        runs,
+    })
+}
+
+fn mk_snap_mapping<R: Rng>(
+    total_len: u64,
+    run_len: Range<u64>,
+    same_percent: usize,
+    diff_percent: usize,
+    rng: &mut R,
+) -> Vec<SnapRun> {
+    let mut runs = Vec::new();
+
+    let mut b = 0u64;
+    while b < total_len {
+        let len = u64::min(total_len - b, rng.gen_range(run_len.start..run_len.end));
+
+        let n = rng.gen_range(0..100);
+
+        if n < same_percent {
+            runs.push(SnapRun(SnapRunType::Same, len));
+        } else if n < diff_percent {
+            runs.push(SnapRun(SnapRunType::Diff, len));
+        } else {
+            runs.push(SnapRun(SnapRunType::Hole, len));
+        }
+
+        b += len;
+    }
+
+    runs
+}
+
+fn split_runs(mut n: u64, runs: &[Run]) -> (Vec<Run>, Vec<Run>) {
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+
+    for r in runs {
+        match r.split(n) {
+            (Some(lhs), None) => {
+                before.push(lhs);
+            }
+            (Some(lhs), Some(rhs)) => {
+                before.push(lhs);
+                after.push(rhs);
+            }
+            (None, Some(rhs)) => {
+                after.push(rhs);
+            }
+            (None, None) => {}
+        }
+        n = n.saturating_sub(r.len());
+    }
+
+    (before, after)
+}
+
+// Runs that differ from the origin are given fresh data blocks,
+// written at the given time.
+fn apply_snap_runs(
+    origin: &[Run],
+    snap: &[SnapRun],
+    allocator: &mut Allocator,
+    time: u32,
+) -> Result<Vec<Run>> {
+    let mut origin = origin.to_owned();
+    let mut runs = Vec::new();
+
+    for SnapRun(st, slen) in snap {
+        let (os, rest) = split_runs(*slen, &origin);
+        match st {
+            SnapRunType::Same => {
+                for o in os {
+                    runs.push(o);
+                }
+            }
+            SnapRunType::Diff => {
+                for data in allocator.alloc(*slen)? {
+                    runs.push(Run::Mapped {
+                        data_begin: data.start,
+                        len: data.end - data.start,
+                        time,
+                    });
+                }
+            }
+            SnapRunType::Hole => {
+                runs.push(Run::UnMapped { len: *slen });
+            }
+        }
+
+        origin = rest;
+    }
+
+    Ok(runs)
+}
+
+// Snapshots share mappings, not neccessarily the entire ranges.
+pub struct SnapS {
+    pub len: u64,
+    pub nr_snaps: u32,
+
+    // Snaps will differ from the origin by this percentage
+    pub percent_change: usize,
+    pub old_nr_data_blocks: u64,
+    pub new_nr_data_blocks: u64,
+}
+
+impl SnapS {
+    pub fn new(len: u64, nr_snaps: u32, percent_change: usize) -> Self {
+        let delta = len * (nr_snaps as u64) * (percent_change as u64) / 100;
+        let old_nr_data_blocks = len + 3 * delta;
+        let new_nr_data_blocks = len + 2 * delta;
+
+        SnapS {
+            len,
+            nr_snaps,
+            percent_change,
+            old_nr_data_blocks,
+            new_nr_data_blocks,
+        }
+    }
+}
+
+impl XmlGen for SnapS {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        let mut rng = test_rng();
+        let mut allocator = Allocator::new_shuffled(self.old_nr_data_blocks, 64..512, &mut rng);
+        let origin = mk_origin(0, self.len, &mut allocator, &mut rng)?;
+
+        v.superblock_b(&common_sb(self.old_nr_data_blocks))?;
+        origin.emit(v)?;
+        v.superblock_e()?;
+
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+// The generators below build bigger, more awkward pools for tests and
+// benchmarks.  Everything random is drawn from a seeded rng, so a
+// failing case can be reproduced from its seed.
+
+fn emit_pool(v: &mut dyn MetadataVisitor, nr_data_blocks: u64, devs: &[ThinDev]) -> Result<()> {
+    v.superblock_b(&common_sb(nr_data_blocks))?;
+    for dev in devs {
+        dev.emit(v)?;
+    }
+    v.superblock_e()?;
+    Ok(())
+}
+
+fn total_mapped(devs: &[ThinDev]) -> u64 {
+    devs.iter().map(|d| d.mapped_blocks()).sum()
+}
+
+//------------------------------------------
+
+// Lots of independent thins, each partially provisioned from a
+// shared, shuffled pool.
+pub struct ManyThinsS {
+    devs: Vec<ThinDev>,
+    pub old_nr_data_blocks: u64,
+    pub new_nr_data_blocks: u64,
+}
+
+impl ManyThinsS {
+    pub fn new(nr_thins: u32, thin_size: u64, percent_mapped: usize, seed: u64) -> Result<Self> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let old_nr_data_blocks = nr_thins as u64 * thin_size;
+        let mut allocator = Allocator::new_shuffled(old_nr_data_blocks, 8..64, &mut rng);
+
+        let mut devs = Vec::new();
+        for thin_id in 0..nr_thins {
+            let mut runs = Vec::new();
+            for r in mk_runs(thin_id, thin_size, 16..64, &mut rng) {
+                if rng.gen_range(0..100) < percent_mapped {
+                    for data in allocator.alloc(r.len)? {
+                        runs.push(Run::Mapped {
+                            data_begin: data.start,
+                            len: data.end - data.start,
+                            time: 0,
+                        });
+                    }
+                } else {
+                    runs.push(Run::UnMapped { len: r.len });
+                }
+            }
+
+            devs.push(ThinDev {
+                thin_id,
+                dev_size: thin_size,
+                creation_time: 0,
+                snap_time: 0,
+                runs,
+            });
+        }
+
+        let new_nr_data_blocks = total_mapped(&devs);
+        Ok(ManyThinsS {
+            devs,
+            old_nr_data_blocks,
+            new_nr_data_blocks,
+        })
+    }
+}
+
+impl XmlGen for ManyThinsS {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        emit_pool(v, self.old_nr_data_blocks, &self.devs)
+    }
+}
+
+//------------------------------------------
+
+// A chain of snapshots, each taken of the previous one and then
+// partially overwritten.  Blocks are shared all the way down the
+// chain until they're overwritten.
+pub struct SnapChainS {
+    devs: Vec<ThinDev>,
+    pub old_nr_data_blocks: u64,
+}
+
+impl SnapChainS {
+    pub fn new(len: u64, depth: u32, percent_change: usize, seed: u64) -> Result<Self> {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let changes: Vec<Vec<SnapRun>> = (0..depth)
+            .map(|_| mk_snap_mapping(len, 16..64, 100 - percent_change, 100, &mut rng))
+            .collect();
+        let nr_changed: u64 = changes
+            .iter()
+            .flatten()
+            .filter_map(|SnapRun(st, len)| match st {
+                SnapRunType::Diff => Some(*len),
+                _ => None,
+            })
+            .sum();
+
+        let old_nr_data_blocks = len + nr_changed;
+        let mut allocator = Allocator::new_shuffled(old_nr_data_blocks, 8..64, &mut rng);
+
+        let mut devs = vec![mk_origin(0, len, &mut allocator, &mut rng)?];
+        for (i, change) in changes.iter().enumerate() {
+            let time = i as u32 + 1;
+            let parent = devs.last_mut().unwrap();
+            parent.snap_time = time;
+            let runs = apply_snap_runs(&parent.runs, change, &mut allocator, time)?;
+
+            devs.push(ThinDev {
+                thin_id: time,
+                dev_size: len,
+                creation_time: time,
+                snap_time: time,
+                runs,
+            });
+        }
+
+        Ok(SnapChainS {
+            devs,
+            old_nr_data_blocks,
+        })
+    }
+}
+
+impl XmlGen for SnapChainS {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        emit_pool(v, self.old_nr_data_blocks, &self.devs)
+    }
+}
+
+//------------------------------------------
+
+// Many snapshots of a single origin, each differing from it by only a
+// few percent, so nearly all of each device's mappings are shared.
+pub struct SharedSnapsS {
+    devs: Vec<ThinDev>,
+    pub old_nr_data_blocks: u64,
+}
+
+impl SharedSnapsS {
+    pub fn new(len: u64, nr_snaps: u32, percent_change: usize, seed: u64) -> Result<Self> {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let changes: Vec<Vec<SnapRun>> = (0..nr_snaps)
+            .map(|_| mk_snap_mapping(len, 4..32, 100 - percent_change, 100, &mut rng))
+            .collect();
+        let nr_changed: u64 = changes
+            .iter()
+            .flatten()
+            .filter_map(|SnapRun(st, len)| match st {
+                SnapRunType::Diff => Some(*len),
+                _ => None,
+            })
+            .sum();
+
+        let old_nr_data_blocks = len + nr_changed;
+        let mut allocator = Allocator::new_shuffled(old_nr_data_blocks, 8..64, &mut rng);
+
+        let mut origin = mk_origin(0, len, &mut allocator, &mut rng)?;
+        origin.snap_time = nr_snaps;
+
+        let mut devs = Vec::new();
+        for (i, change) in changes.iter().enumerate() {
+            let time = i as u32 + 1;
+            let runs = apply_snap_runs(&origin.runs, change, &mut allocator, time)?;
+            devs.push(ThinDev {
+                thin_id: time,
+                dev_size: len,
+                creation_time: time,
+                snap_time: time,
+                runs,
+            });
+        }
+        devs.insert(0, origin);
+
+        Ok(SharedSnapsS {
+            devs,
+            old_nr_data_blocks,
+        })
+    }
+}
+
+impl XmlGen for SharedSnapsS {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        emit_pool(v, self.old_nr_data_blocks, &self.devs)
+    }
+}
+
+//------------------------------------------
+
+// The worst case for run length encoding and for shrink: every thin
+// block is mapped to a random data block, half the pool is left free,
+// and the free space is as scattered as the mapped space.
+pub struct PathologicalFragS {
+    devs: Vec<ThinDev>,
+    pub old_nr_data_blocks: u64,
+    pub new_nr_data_blocks: u64,
+}
+
+impl PathologicalFragS {
+    pub fn new(nr_thins: u32, thin_size: u64, seed: u64) -> Result<Self> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let new_nr_data_blocks = nr_thins as u64 * thin_size;
+        let old_nr_data_blocks = new_nr_data_blocks * 2;
+        let mut allocator = Allocator::new_shuffled(old_nr_data_blocks, 1..2, &mut rng);
+
+        let mut devs = Vec::new();
+        for thin_id in 0..nr_thins {
+            let mut runs = Vec::new();
+            for data in allocator.alloc(thin_size)? {
+                runs.push(Run::Mapped {
+                    data_begin: data.start,
+                    len: data.end - data.start,
+                    time: 0,
+                });
+            }
+
+            devs.push(ThinDev {
+                thin_id,
+                dev_size: thin_size,
+                creation_time: 0,
+                snap_time: 0,
+                runs,
+            });
+        }
+
+        Ok(PathologicalFragS {
+            devs,
+            old_nr_data_blocks,
+            new_nr_data_blocks,
+        })
+    }
+}
+
+impl XmlGen for PathologicalFragS {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        emit_pool(v, self.old_nr_data_blocks, &self.devs)
+    }
+}
+
+//------------------------------------------
+
+// A random pool for the round trip properties: a few thins, with
+// mappings allocated from disjoint data blocks.
+#[derive(Clone, Debug)]
+pub struct ThinModel {
+    pub time: u32,
+    pub nr_data_blocks: u64,
+    pub devs: Vec<ThinDevModel>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ThinDevModel {
+    pub dev_id: u32,
+    pub maps: Vec<ThinMapModel>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ThinMapModel {
+    pub thin_begin: u64,
+    pub data_begin: u64,
+    pub len: u64,
+    pub time: u32,
+}
+
+impl Arbitrary for ThinModel {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let size = g.size() as u64;
+        let mut rng = model_rng(g);
+        let time = rng.gen_range(0..4);
+
+        let mut dev_ids: Vec<u32> = (0..16).collect();
+        dev_ids.shuffle(&mut rng);
+        dev_ids.truncate(rng.gen_range(1..5));
+        dev_ids.sort_unstable();
+
+        let mut data_begin = 0;
+        let mut devs = Vec::new();
+        for dev_id in dev_ids {
+            let mut thin_begin = 0;
+            let mut maps = Vec::new();
+            for _ in 0..rng.gen_range(0..=size) {
+                thin_begin += rng.gen_range(0..size);
+                data_begin += rng.gen_range(0..4);
+                let len = rng.gen_range(1..=32);
+                maps.push(ThinMapModel {
+                    thin_begin,
+                    data_begin,
+                    len,
+                    time: rng.gen_range(0..=time),
+                });
+                thin_begin += len;
+                data_begin += len;
+            }
+            devs.push(ThinDevModel { dev_id, maps });
+        }
+
+        ThinModel {
+            time,
+            nr_data_blocks: data_begin + rng.gen_range(1..=size),
+            devs,
+        }
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let mut smaller = Vec::new();
+        if self.devs.len() > 1 {
+            for devs in shrink_list(&self.devs) {
+                smaller.push(ThinModel {
+                    devs,
+                    ..self.clone()
+                });
+            }
+        }
+
+        for (i, dev) in self.devs.iter().enumerate() {
+            for maps in shrink_list(&dev.maps) {
+                let mut m = self.clone();
+                m.devs[i].maps = maps;
+                smaller.push(m);
+            }
+        }
+
+        Box::new(smaller.into_iter())
+    }
+}
+
+impl XmlGen for ThinModel {
+    fn generate_xml(&mut self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        v.superblock_b(&ir::Superblock {
+            time: self.time,
+            ..common_sb(self.nr_data_blocks)
+        })?;
+        for dev in &self.devs {
+            v.device_b(&ir::Device {
+                dev_id: dev.dev_id,
+                mapped_blocks: dev.maps.iter().map(|m| m.len).sum(),
+                transaction: 0,
+                creation_time: 0,
+                snap_time: 0,
+            })?;
+            for m in &dev.maps {
+                v.map(&ir::Map {
+                    thin_begin: m.thin_begin,
+                    data_begin: m.data_begin,
+                    time: m.time,
+                    len: m.len,
+                    shared: None,
+                })?;
+            }
+            v.device_e()?;
+        }
+        v.superblock_e()?;
+        Ok(())
+    }
+}
+
+impl Model for ThinModel {
+    fn write_xml(&self, path: &Path) -> Result<()> {
+        write_xml(path, &mut self.clone())
+    }
+}
+
+//------------------------------------------
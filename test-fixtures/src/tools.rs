@@ -0,0 +1,58 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use crate::process::*;
+
+//------------------------------------------
+
+/// The C++ tools, including the metadata generators, are looked for in
+/// THINP_CPP_TOOLS_DIR, or in ./bin of a thin-provisioning-tools build
+/// otherwise.
+fn cpp_tools_dir() -> PathBuf {
+    std::env::var_os("THINP_CPP_TOOLS_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("bin"))
+}
+
+pub fn cpp_cmd<S, I>(cmd: S, args: I) -> Command
+where
+    S: Into<OsString>,
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    let mut bin = cpp_tools_dir();
+    bin.push(Into::<OsString>::into(cmd));
+
+    let mut args_ = Vec::new();
+    for a in args {
+        args_.push(Into::<OsString>::into(a));
+    }
+
+    Command::new(Into::<OsString>::into(bin.as_path()), args_)
+}
+
+pub fn thin_generate_metadata_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    cpp_cmd("thin_generate_metadata", args)
+}
+
+pub fn thin_generate_mappings_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    cpp_cmd("thin_generate_mappings", args)
+}
+
+pub fn thin_generate_damage_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    cpp_cmd("thin_generate_damage", args)
+}
+
+//------------------------------------------
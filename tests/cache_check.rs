@@ -16,18 +16,29 @@ use common::test_dir::*;
 const USAGE: &str = "cache_check 0.9.0
 
 USAGE:
-    cache_check [FLAGS] <INPUT>
+    cache_check [FLAGS] [OPTIONS] <INPUT>
 
 FLAGS:
         --auto-repair                Auto repair trivial issues.
+        --error-if-needs-check       Fail if the needs_check flag is set, even if no damage is found
         --ignore-non-fatal-errors    Only return a non-zero exit code if a fatal error is found.
     -q, --quiet                      Suppress output messages, return only exit code.
         --super-block-only           Only check the superblock.
         --skip-discards              Don't check the discard bitset
         --skip-hints                 Don't check the hint array
+    -v, --verbose                    Increase the verbosity of output messages, may be repeated
     -h, --help                       Prints help information
     -V, --version                    Prints version information
 
+OPTIONS:
+        --config <FILE>          Read default options from this file instead of the system wide one
+        --journal <FILE>         Save overwritten blocks to this file, for --rollback
+        --log-file <FILE>        Append full diagnostics to this file
+        --max-memory <SIZE>      Limit memory use, in MiB unless a unit is given
+        --policy <FILE>          Read what to do about each kind of finding from this file
+        --rollback <JOURNAL>     Undo the repair recorded in this journal
+        --trace-output <FILE>    Write a Chrome trace of the main phases to this file
+
 ARGS:
     <INPUT>    Specify the input device to check";
 
@@ -130,6 +141,158 @@ fn valid_metadata_passes() -> Result<()> {
     Ok(())
 }
 
+// smq hints hold a level, which is always below 64.
+fn mk_md_with_bad_hints(td: &mut TestDir) -> Result<std::path::PathBuf> {
+    let xml = td.mk_path("bad_hints.xml");
+    std::fs::write(
+        &xml,
+        r#"<superblock uuid="" block_size="128" nr_cache_blocks="16" policy="smq" hint_width="4">
+  <mappings>
+    <mapping cache_block="0" origin_block="10" dirty="false"/>
+    <mapping cache_block="3" origin_block="11" dirty="true"/>
+  </mappings>
+  <hints>
+    <hint cache_block="0" data="AQAAAA=="/>
+    <hint cache_block="3" data="AAEAAA=="/>
+  </hints>
+</superblock>
+"#,
+    )?;
+    let md = mk_zeroed_md(td)?;
+    run_ok(cache_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+#[test]
+fn out_of_range_hints_are_non_fatal() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_md_with_bad_hints(&mut td)?;
+
+    let stderr = run_fail(cache_check_cmd(args![&md]))?;
+    assert!(stderr.contains("1 hints are out of range for the smq policy"));
+    assert!(stderr.contains("cache block 3"));
+
+    run_ok(cache_check_cmd(args!["--ignore-non-fatal-errors", &md]))?;
+    run_ok(cache_check_cmd(args!["--skip-hints", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn policy_decides_what_hint_damage_does() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_md_with_bad_hints(&mut td)?;
+    let policy = td.mk_path("check.policy");
+
+    std::fs::write(&policy, "hint_array = warn\n")?;
+    let output = run_ok_raw(cache_check_cmd(args!["--policy", &policy, &md]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("warning: hint array is damaged"));
+
+    std::fs::write(&policy, "hint_array = fix\n")?;
+    let stderr = run_fail(cache_check_cmd(args!["--policy", &policy, &md]))?;
+    assert!(stderr.contains("hint_array can't be fixed"));
+    Ok(())
+}
+
+// Without a terminal the progress is written as plain lines.
+#[test]
+fn reports_progress() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let output = run_ok_raw(cache_check_cmd(args![&md]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("Checking cache metadata"));
+    assert!(stderr.contains("mapping array"));
+    assert!(stderr.contains("hint array"));
+
+    let output = run_ok_raw(cache_check_cmd(args!["--skip-hints", &md]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(!stderr.contains("hint array"));
+    Ok(())
+}
+
+#[test]
+fn writes_chrome_trace() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let trace = td.mk_path("trace.json");
+    run_ok(cache_check_cmd(args!["--trace-output", &trace, &md]))?;
+
+    // The closing ']' is left off, so the trace survives an early exit.
+    let text = std::fs::read_to_string(&trace)?;
+    let events = json::parse(&format!("{}]", text.trim_end().trim_end_matches(',')))?;
+    let check = events
+        .members()
+        .find(|e| e["name"] == "check")
+        .expect("no span for the check");
+    assert_eq!(check["ph"], "X");
+    assert!(check["dur"].as_f64().unwrap() > 0.0);
+    assert_eq!(check["args"]["nr_metadata_blocks"], 4096);
+    Ok(())
+}
+
+#[test]
+fn log_file_gets_everything() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_md_with_bad_hints(&mut td)?;
+    let log = td.mk_path("cache_check.log");
+
+    // Quiet on the console, but not in the log, which is appended to.
+    for _ in 0..2 {
+        let output = run_fail_raw(cache_check_cmd(args!["-q", "--log-file", &log, &md]))?;
+        assert_eq!(output.stderr.len(), 0);
+    }
+
+    let text = std::fs::read_to_string(&log)?;
+    assert_eq!(text.matches("cache_check -q --log-file").count(), 2);
+    assert!(text.contains("Checking cache metadata"));
+    assert!(text.contains("1 hints are out of range for the smq policy"));
+
+    // Spans are logged with their timings as they close.
+    assert!(text.contains("check{nr_metadata_blocks=4096}: thinp::cache::check: close"));
+    assert!(text.contains("time.busy"));
+    Ok(())
+}
+
+#[test]
+fn rollback_of_a_clean_repair() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let before = std::fs::read(&md)?;
+
+    let journal = td.mk_path("repair.journal");
+    run_ok(cache_check_cmd(args![
+        "--auto-repair",
+        "--journal",
+        &journal,
+        &md
+    ]))?;
+    run_ok(cache_check_cmd(args!["--rollback", &journal, &md]))?;
+    assert!(std::fs::read(&md)? == before);
+
+    // A journal is only kept of repairs.
+    run_fail(cache_check_cmd(args!["--journal", &journal, &md]))?;
+    Ok(())
+}
+
+#[test]
+fn truncated_metadata_is_reported() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let file = std::fs::OpenOptions::new().write(true).open(&md)?;
+
+    // Nothing in use is lost, but the device is too small.
+    file.set_len(2048 * 4096)?;
+    let stderr = run_fail(cache_check_cmd(args![&md]))?;
+    assert!(stderr.contains("it may have been truncated"));
+
+    // Only the superblock is left.
+    file.set_len(4096 + 2048)?;
+    let stderr = run_fail(cache_check_cmd(args![&md]))?;
+    assert!(stderr.contains("past the end of the metadata device"));
+    Ok(())
+}
+
 // FIXME: put back in, I don't want to add the --debug- arg to the
 // tool again, so we should have a little library function for tweaking
 // metadata version.
@@ -5,11 +5,13 @@ use std::io::Write;
 mod common;
 
 use common::cache::*;
+use common::cache_xml_generator::CacheModel;
 use common::common_args::*;
 use common::fixture::*;
 use common::input_arg::*;
 use common::process::*;
 use common::program::*;
+use common::round_trip::{self, *};
 use common::target::*;
 use common::test_dir::*;
 
@@ -22,12 +24,18 @@ USAGE:
     cache_dump [FLAGS] [OPTIONS] <INPUT>
 
 FLAGS:
+    -q, --quiet      Suppress output messages, return only exit code.
     -r, --repair     Repair the metadata whilst dumping it
+    -v, --verbose    Increase the verbosity of output messages, may be repeated
     -h, --help       Prints help information
     -V, --version    Prints version information
 
 OPTIONS:
-    -o, --output <FILE>    Specify the output file rather than stdout
+        --config <FILE>          Read default options from this file instead of the system wide one
+        --log-file <FILE>        Append full diagnostics to this file
+        --max-memory <SIZE>      Limit memory use, in MiB unless a unit is given
+    -o, --output <FILE>          Specify the output file rather than stdout
+        --trace-output <FILE>    Write a Chrome trace of the main phases to this file
 
 ARGS:
     <INPUT>    Specify the input device to dump";
@@ -117,3 +125,35 @@ fn dump_restore_cycle() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn dump_restore_cycle_of_random_metadata() {
+    round_trip::quickcheck(20, |m: CacheModel| {
+        to_test_result(check_dump_restore_dump(
+            &m,
+            |xml, md| cache_restore_cmd(args!["-i", xml, "-o", md]),
+            |md| cache_dump_cmd(args![md]),
+        ))
+    });
+}
+
+//------------------------------------------
+
+#[test]
+fn failing_quiet() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    let output = run_fail_raw(cache_dump_cmd(args!["--quiet", &md]))?;
+    assert_eq!(output.stdout.len(), 0);
+    assert_eq!(output.stderr.len(), 0);
+    Ok(())
+}
+
+#[test]
+fn quiet_conflicts_with_verbose() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stderr = run_fail(cache_dump_cmd(args!["-q", "-v", &md]))?;
+    assert!(stderr.contains("cannot be used with"));
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use anyhow::Result;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+mod common;
+
+use common::cache::*;
+use common::common_args::*;
+use common::fixture::*;
+use common::input_arg::*;
+use common::output_option::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+const USAGE: &str = "cache_invalidate 0.9.0
+Remove selected mappings from cache metadata, writing the result to a different device or file
+
+USAGE:
+    cache_invalidate [FLAGS] [OPTIONS] --input <FILE> --output <FILE> <--cblocks <LIST>|--oblocks <LIST>>
+
+FLAGS:
+        --force      Drop dirty blocks too, losing any data not yet written back
+    -q, --quiet      Suppress output messages, return only exit code.
+    -v, --verbose    Increase the verbosity of output messages, may be repeated
+    -h, --help       Prints help information
+    -V, --version    Prints version information
+
+OPTIONS:
+        --cblocks <LIST>       Invalidate these cache blocks, eg. 1,5,10-20
+        --config <FILE>        Read default options from this file instead of the system wide one
+    -i, --input <FILE>         Specify the input device
+        --log-file <FILE>      Append full diagnostics to this file
+        --max-memory <SIZE>    Limit memory use, in MiB unless a unit is given
+        --oblocks <LIST>       Invalidate the cache blocks mapping these origin blocks
+    -o, --output <FILE>        Specify the output device";
+
+//-----------------------------------------
+
+struct CacheInvalidate;
+
+impl<'a> Program<'a> for CacheInvalidate {
+    fn name() -> &'a str {
+        "cache_invalidate"
+    }
+
+    // A selection is always needed, so the common tests get past it.
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        let mut all: Vec<OsString> = vec!["--cblocks".into(), "0".into()];
+        all.extend(args.into_iter().map(|a| a.into()));
+        cache_invalidate_cmd(all)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::IoOptions
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+impl<'a> InputProgram<'a> for CacheInvalidate {
+    fn mk_valid_input(td: &mut TestDir) -> Result<std::path::PathBuf> {
+        mk_valid_md(td)
+    }
+
+    fn file_not_found() -> &'a str {
+        msg::FILE_NOT_FOUND
+    }
+
+    fn missing_input_arg() -> &'a str {
+        msg::MISSING_INPUT_ARG
+    }
+
+    fn corrupted_input() -> &'a str {
+        "bad checksum in superblock"
+    }
+}
+
+impl<'a> OutputProgram<'a> for CacheInvalidate {
+    fn missing_output_arg() -> &'a str {
+        msg::MISSING_OUTPUT_ARG
+    }
+}
+
+impl<'a> MetadataWriter<'a> for CacheInvalidate {
+    fn file_not_found() -> &'a str {
+        msg::FILE_NOT_FOUND
+    }
+}
+
+//-----------------------------------------
+
+test_accepts_help!(CacheInvalidate);
+test_accepts_version!(CacheInvalidate);
+test_rejects_bad_option!(CacheInvalidate);
+
+test_input_file_not_found!(CacheInvalidate);
+test_input_cannot_be_a_directory!(CacheInvalidate);
+test_corrupted_input_data!(CacheInvalidate);
+
+test_missing_output_option!(CacheInvalidate);
+
+//-----------------------------------------
+
+const XML: &str = r#"<superblock uuid="" block_size="128" nr_cache_blocks="200" policy="smq" hint_width="4">
+  <mappings>
+    <mapping cache_block="0" origin_block="10" dirty="false"/>
+    <mapping cache_block="1" origin_block="11" dirty="false"/>
+    <mapping cache_block="70" origin_block="12" dirty="true"/>
+    <mapping cache_block="130" origin_block="13" dirty="false"/>
+  </mappings>
+  <hints>
+    <hint cache_block="0" data="AQAAAA=="/>
+    <hint cache_block="1" data="AgAAAA=="/>
+    <hint cache_block="70" data="BQAAAA=="/>
+    <hint cache_block="130" data="AAAAAA=="/>
+  </hints>
+</superblock>
+"#;
+
+fn mk_md(td: &mut TestDir) -> Result<PathBuf> {
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(&xml, XML)?;
+    let md = mk_zeroed_md(td)?;
+    run_ok(cache_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+// Invalidates the selection, and returns the cache blocks left mapped.
+fn invalidate(selection: &[&str]) -> Result<Vec<String>> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+
+    let mut args = args!["-i", &md1, "-o", &md2].to_vec();
+    args.extend(selection.iter().map(std::ffi::OsStr::new));
+    run_ok(cache_invalidate_cmd(args))?;
+    run_ok(cache_check_cmd(args![&md2]))?;
+
+    let dump = run_ok(cache_dump_cmd(args![&md2]))?;
+    let mapped = dump
+        .lines()
+        .filter(|l| l.trim().starts_with("<mapping "))
+        .map(|l| l.trim().to_string())
+        .collect();
+    Ok(mapped)
+}
+
+#[test]
+fn invalidates_cache_blocks() -> Result<()> {
+    let mapped = invalidate(&["--cblocks", "1,100-200"])?;
+    assert_eq!(
+        mapped,
+        vec![
+            r#"<mapping cache_block="0" origin_block="10" dirty="false"/>"#,
+            r#"<mapping cache_block="70" origin_block="12" dirty="true"/>"#,
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn invalidates_origin_blocks() -> Result<()> {
+    let mapped = invalidate(&["--oblocks", "10-12,13"])?;
+    assert_eq!(
+        mapped,
+        vec![r#"<mapping cache_block="70" origin_block="12" dirty="true"/>"#]
+    );
+    Ok(())
+}
+
+#[test]
+fn drops_hints_of_invalidated_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(cache_invalidate_cmd(args![
+        "-i",
+        &md1,
+        "-o",
+        &md2,
+        "--cblocks",
+        "1"
+    ]))?;
+
+    let dump = run_ok(cache_dump_cmd(args![&md2]))?;
+    assert!(!dump.contains(r#"<hint cache_block="1" "#));
+    assert!(dump.contains(r#"<hint cache_block="70" data="BQAAAA=="/>"#));
+    Ok(())
+}
+
+#[test]
+fn refuses_to_drop_dirty_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(cache_invalidate_cmd(args![
+        "-i",
+        &md1,
+        "-o",
+        &md2,
+        "--oblocks",
+        "12"
+    ]))?;
+    assert!(stderr.contains("cache block 70 is dirty"));
+    Ok(())
+}
+
+#[test]
+fn force_drops_dirty_blocks() -> Result<()> {
+    let mapped = invalidate(&["--force", "--cblocks", "70"])?;
+    assert_eq!(mapped.len(), 3);
+    assert!(mapped.iter().all(|m| !m.contains("cache_block=\"70\"")));
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_block_list() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(cache_invalidate_cmd(args![
+        "-i",
+        &md1,
+        "-o",
+        &md2,
+        "--cblocks",
+        "5-2"
+    ]))?;
+    assert!(stderr.contains("empty block range"));
+    Ok(())
+}
+
+#[test]
+fn selection_is_required() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_fail(cache_invalidate_cmd(args!["-i", &md1, "-o", &md2]))?;
+    Ok(())
+}
+
+//-----------------------------------------
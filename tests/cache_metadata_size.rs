@@ -183,4 +183,33 @@ fn large_nr_blocks() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn unit_suffixed_sizes() -> Result<()> {
+    let out = run_ok_raw(cache_metadata_size_cmd(args![
+        "--device-size",
+        "50m",
+        "--block-size",
+        "50k"
+    ]))?;
+    let stdout = std::str::from_utf8(&out.stdout[..]).unwrap();
+    assert_eq!(stdout.trim_end(), "8248 sectors");
+
+    let stderr = std::str::from_utf8(&out.stderr[..]).unwrap();
+    assert!(stderr.contains("device size: 50m = 102400 sectors"));
+    assert!(stderr.contains("block size: 50k = 100 sectors"));
+    Ok(())
+}
+
+#[test]
+fn unaligned_size_fails() -> Result<()> {
+    let stderr = run_fail(cache_metadata_size_cmd(args![
+        "--device-size",
+        "1000b",
+        "--block-size",
+        "128"
+    ]))?;
+    assert!(stderr.contains("not a multiple of 512 bytes"));
+    Ok(())
+}
+
 //------------------------------------------
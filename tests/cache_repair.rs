@@ -4,8 +4,10 @@ mod common;
 
 use common::cache::*;
 use common::common_args::*;
+use common::fixture::*;
 use common::input_arg::*;
 use common::output_option::*;
+use common::process::*;
 use common::program::*;
 use common::target::*;
 use common::test_dir::*;
@@ -16,16 +18,21 @@ const USAGE: &str = "cache_repair 0.9.0
 Repair binary cache metadata, and write it to a different device or file
 
 USAGE:
-    cache_repair [FLAGS] --input <FILE> --output <FILE>
+    cache_repair [FLAGS] [OPTIONS] --input <FILE> --output <FILE>
 
 FLAGS:
     -q, --quiet      Suppress output messages, return only exit code.
+    -v, --verbose    Increase the verbosity of output messages, may be repeated
     -h, --help       Prints help information
     -V, --version    Prints version information
 
 OPTIONS:
-    -i, --input <FILE>     Specify the input device
-    -o, --output <FILE>    Specify the output device";
+        --config <FILE>             Read default options from this file instead of the system wide one
+    -i, --input <FILE>              Specify the input device
+        --log-file <FILE>           Append full diagnostics to this file
+        --max-memory <SIZE>         Limit memory use, in MiB unless a unit is given
+        --metadata-version <NUM>    Specify the output metadata version [default: 2]  [possible values: 1, 2]
+    -o, --output <FILE>             Specify the output device";
 
 //-----------------------------------------
 
@@ -100,3 +107,69 @@ test_corrupted_input_data!(CacheRepair);
 test_missing_output_option!(CacheRepair);
 
 //-----------------------------------------
+
+const DIRTY_XML: &str = r#"<superblock uuid="" block_size="128" nr_cache_blocks="200" policy="smq" hint_width="4">
+  <mappings>
+    <mapping cache_block="0" origin_block="10" dirty="true"/>
+    <mapping cache_block="1" origin_block="11" dirty="false"/>
+    <mapping cache_block="70" origin_block="12" dirty="true"/>
+    <mapping cache_block="130" origin_block="13" dirty="true"/>
+  </mappings>
+  <hints>
+    <hint cache_block="0" data="AQAAAA=="/>
+    <hint cache_block="1" data="AAAAAA=="/>
+    <hint cache_block="70" data="BQAAAA=="/>
+    <hint cache_block="130" data="AAAAAA=="/>
+  </hints>
+</superblock>
+"#;
+
+fn metadata_version(md: &std::path::Path) -> Result<String> {
+    let stdout = run_ok(cache_stat_cmd(args![md]))?;
+    Ok(stdout.lines().next().unwrap().to_string())
+}
+
+// The dirty flags move between the mappings and the dirty bitset.
+fn test_convert(from: &str, to: &str) -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(&xml, DIRTY_XML)?;
+
+    let md1 = mk_zeroed_md(&mut td)?;
+    run_ok(cache_restore_cmd(args![
+        "-i",
+        &xml,
+        "-o",
+        &md1,
+        "--metadata-version",
+        from
+    ]))?;
+
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(cache_repair_cmd(args![
+        "-i",
+        &md1,
+        "-o",
+        &md2,
+        "--metadata-version",
+        to
+    ]))?;
+
+    assert_eq!(metadata_version(&md2)?, format!("metadata version: {}", to));
+    run_ok(cache_check_cmd(args![&md2]))?;
+    let dump = run_ok(cache_dump_cmd(args![&md2]))?;
+    assert_eq!(dump.trim_end(), DIRTY_XML.trim_end());
+    Ok(())
+}
+
+#[test]
+fn upgrades_metadata_version_1() -> Result<()> {
+    test_convert("1", "2")
+}
+
+#[test]
+fn downgrades_metadata_version_2() -> Result<()> {
+    test_convert("2", "1")
+}
+
+//-----------------------------------------
@@ -0,0 +1,135 @@
+use anyhow::Result;
+
+mod common;
+
+use common::cache::*;
+use common::common_args::*;
+use common::fixture::*;
+use common::input_arg::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+const USAGE: &str = "cache_stat 0.9.0
+Summarise the cache metadata
+
+USAGE:
+    cache_stat [FLAGS] [OPTIONS] <INPUT>
+
+FLAGS:
+    -q, --quiet      Suppress output messages, return only exit code.
+    -v, --verbose    Increase the verbosity of output messages, may be repeated
+    -h, --help       Prints help information
+    -V, --version    Prints version information
+
+OPTIONS:
+        --buckets <NUM>        Split the origin into this many parts for the histogram [default: 10]
+        --config <FILE>        Read default options from this file instead of the system wide one
+        --log-file <FILE>      Append full diagnostics to this file
+        --max-memory <SIZE>    Limit memory use, in MiB unless a unit is given
+
+ARGS:
+    <INPUT>    Specify the input device";
+
+//------------------------------------------
+
+struct CacheStat;
+
+impl<'a> Program<'a> for CacheStat {
+    fn name() -> &'a str {
+        "cache_stat"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        cache_stat_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::InputArg
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+impl<'a> InputProgram<'a> for CacheStat {
+    fn mk_valid_input(td: &mut TestDir) -> Result<std::path::PathBuf> {
+        mk_valid_md(td)
+    }
+
+    fn file_not_found() -> &'a str {
+        msg::FILE_NOT_FOUND
+    }
+
+    fn missing_input_arg() -> &'a str {
+        msg::MISSING_INPUT_ARG
+    }
+
+    fn corrupted_input() -> &'a str {
+        msg::BAD_SUPERBLOCK
+    }
+}
+
+//------------------------------------------
+
+test_accepts_help!(CacheStat);
+test_accepts_version!(CacheStat);
+test_rejects_bad_option!(CacheStat);
+
+test_missing_input_arg!(CacheStat);
+test_input_file_not_found!(CacheStat);
+test_input_cannot_be_a_directory!(CacheStat);
+
+//------------------------------------------
+
+#[test]
+fn summarises_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(
+        &xml,
+        r#"<superblock uuid="" block_size="128" nr_cache_blocks="8" policy="smq" hint_width="4">
+  <mappings>
+    <mapping cache_block="0" origin_block="0" dirty="true"/>
+    <mapping cache_block="1" origin_block="1" dirty="false"/>
+    <mapping cache_block="2" origin_block="2" dirty="false"/>
+    <mapping cache_block="5" origin_block="99" dirty="true"/>
+  </mappings>
+  <hints>
+  </hints>
+</superblock>
+"#,
+    )?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(cache_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+
+    let stdout = run_ok(cache_stat_cmd(args![&md, "--buckets", "2"]))?;
+    assert!(stdout.contains("resident:         4 (50.00%)"));
+    assert!(stdout.contains("dirty:            2 (50.00% of resident)"));
+    assert!(stdout.contains("policy:           smq 2.0.0"));
+    assert!(stdout.contains("0..50                    3"));
+    assert!(stdout.contains("50..100                  1"));
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_bucket_count() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(cache_stat_cmd(args![&md, "--buckets", "many"]))?;
+    Ok(())
+}
+
+//------------------------------------------
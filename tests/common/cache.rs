@@ -4,7 +4,6 @@ use std::path::PathBuf;
 use thinp::file_utils;
 //use thinp::io_engine::*;
 
-use crate::args;
 use crate::common::cache_xml_generator::{write_xml, CacheGen};
 use crate::common::process::*;
 use crate::common::target::*;
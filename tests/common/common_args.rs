@@ -2,7 +2,6 @@ use anyhow::Result;
 
 use thinp::version::tools_version;
 
-use crate::args;
 use crate::common::process::*;
 use crate::common::program::*;
 
@@ -3,7 +3,6 @@ use std::path::PathBuf;
 
 use thinp::file_utils;
 
-use crate::args;
 use crate::common::era_xml_generator::{write_xml, CleanShutdownMeta};
 use crate::common::process::*;
 use crate::common::target::*;
@@ -3,7 +3,6 @@ use std::ffi::OsStr;
 
 use thinp::file_utils;
 
-use crate::args;
 use crate::common::fixture::*;
 use crate::common::process::*;
 use crate::common::program::*;
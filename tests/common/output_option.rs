@@ -2,7 +2,6 @@ use anyhow::Result;
 
 use thinp::file_utils;
 
-use crate::args;
 use crate::common::process::*;
 use crate::common::program::*;
 use crate::common::test_dir::*;
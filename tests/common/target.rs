@@ -1,41 +1,33 @@
 use std::ffi::OsString;
-use std::path::PathBuf;
 
 use crate::common::process::*;
 
+pub use thinp_test_fixtures::tools::*;
+
 //------------------------------------------
 
-pub fn cpp_cmd<S, I>(cmd: S, args: I) -> Command
+pub fn rust_cmd<S, I>(cmd: S, args: I) -> Command
 where
     S: Into<OsString>,
     I: IntoIterator,
     I::Item: Into<OsString>,
 {
-    let mut bin = PathBuf::from("bin");
-    bin.push(Into::<OsString>::into(cmd));
+    const RUST_PATH: &str = env!(concat!("CARGO_BIN_EXE_", "pdata_tools"));
 
-    let mut args_ = Vec::new();
+    let mut all_args = vec![Into::<OsString>::into(cmd)];
     for a in args {
-        args_.push(Into::<OsString>::into(a));
+        all_args.push(Into::<OsString>::into(a));
     }
 
-    Command::new(Into::<OsString>::into(bin.as_path()), args_)
+    Command::new(Into::<OsString>::into(RUST_PATH), all_args)
 }
 
-pub fn rust_cmd<S, I>(cmd: S, args: I) -> Command
+pub fn thin_bench_cmd<I>(args: I) -> Command
 where
-    S: Into<OsString>,
     I: IntoIterator,
     I::Item: Into<OsString>,
 {
-    const RUST_PATH: &str = env!(concat!("CARGO_BIN_EXE_", "pdata_tools"));
-
-    let mut all_args = vec![Into::<OsString>::into(cmd)];
-    for a in args {
-        all_args.push(Into::<OsString>::into(a));
-    }
-
-    Command::new(Into::<OsString>::into(RUST_PATH), all_args)
+    rust_cmd("thin_bench", args)
 }
 
 pub fn thin_check_cmd<I>(args: I) -> Command
@@ -54,61 +46,69 @@ where
     cpp_cmd("thin_rmap", args)
 }
 
-pub fn thin_generate_metadata_cmd<I>(args: I) -> Command
+pub fn thin_restore_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
     I::Item: Into<OsString>,
 {
-    cpp_cmd("thin_generate_metadata", args)
+    // rust_cmd("thin_restore", args)
+    cpp_cmd("thin_restore", args)
 }
 
-pub fn thin_generate_mappings_cmd<I>(args: I) -> Command
+pub fn thin_repair_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
     I::Item: Into<OsString>,
 {
-    cpp_cmd("thin_generate_mappings", args)
+    rust_cmd("thin_restore", args)
 }
 
-pub fn thin_generate_damage_cmd<I>(args: I) -> Command
+pub fn thin_dump_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
     I::Item: Into<OsString>,
 {
-    cpp_cmd("thin_generate_damage", args)
+    rust_cmd("thin_dump", args)
 }
 
-pub fn thin_restore_cmd<I>(args: I) -> Command
+pub fn thin_metrics_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
     I::Item: Into<OsString>,
 {
-    // rust_cmd("thin_restore", args)
-    cpp_cmd("thin_restore", args)
+    rust_cmd("thin_metrics", args)
 }
 
-pub fn thin_repair_cmd<I>(args: I) -> Command
+pub fn thin_set_metadata_id_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
     I::Item: Into<OsString>,
 {
-    rust_cmd("thin_restore", args)
+    rust_cmd("thin_set_metadata_id", args)
 }
 
-pub fn thin_dump_cmd<I>(args: I) -> Command
+pub fn thin_delta_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
     I::Item: Into<OsString>,
 {
-    rust_cmd("thin_dump", args)
+    cpp_cmd("thin_delta", args)
 }
 
-pub fn thin_delta_cmd<I>(args: I) -> Command
+pub fn thin_ls_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
     I::Item: Into<OsString>,
 {
-    cpp_cmd("thin_delta", args)
+    cpp_cmd("thin_ls", args)
+}
+
+pub fn thin_metadata_diff_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("thin_metadata_diff", args)
 }
 
 pub fn thin_metadata_pack_cmd<I>(args: I) -> Command
@@ -159,6 +159,22 @@ where
     rust_cmd("cache_restore", args)
 }
 
+pub fn cache_stat_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("cache_stat", args)
+}
+
+pub fn cache_invalidate_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("cache_invalidate", args)
+}
+
 pub fn cache_repair_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
@@ -183,6 +199,14 @@ where
     rust_cmd("era_dump", args)
 }
 
+pub fn era_stat_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("era_stat", args)
+}
+
 pub fn era_restore_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
@@ -199,6 +223,18 @@ where
     rust_cmd("era_repair", args)
 }
 
+// A dev tool, so it's a binary of its own rather than part of pdata_tools.
+pub fn pdata_corpus_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    const CORPUS_PATH: &str = env!(concat!("CARGO_BIN_EXE_", "pdata_corpus"));
+
+    let args: Vec<OsString> = args.into_iter().map(Into::<OsString>::into).collect();
+    Command::new(Into::<OsString>::into(CORPUS_PATH), args)
+}
+
 //------------------------------------------
 
 pub mod msg {
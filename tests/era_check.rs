@@ -16,15 +16,22 @@ use common::test_dir::*;
 const USAGE: &str = "era_check 0.9.0
 
 USAGE:
-    era_check [FLAGS] <INPUT>
+    era_check [FLAGS] [OPTIONS] <INPUT>
 
 FLAGS:
         --ignore-non-fatal-errors    Only return a non-zero exit code if a fatal error is found.
     -q, --quiet                      Suppress output messages, return only exit code.
         --super-block-only           Only check the superblock.
+    -v, --verbose                    Increase the verbosity of output messages, may be repeated
     -h, --help                       Prints help information
     -V, --version                    Prints version information
 
+OPTIONS:
+        --config <FILE>          Read default options from this file instead of the system wide one
+        --log-file <FILE>        Append full diagnostics to this file
+        --max-memory <SIZE>      Limit memory use, in MiB unless a unit is given
+        --trace-output <FILE>    Write a Chrome trace of the main phases to this file
+
 ARGS:
     <INPUT>    Specify the input device to check";
 
@@ -6,10 +6,12 @@ mod common;
 
 use common::common_args::*;
 use common::era::*;
+use common::era_xml_generator::EraModel;
 use common::fixture::*;
 use common::input_arg::*;
 use common::process::*;
 use common::program::*;
+use common::round_trip::{self, *};
 use common::target::*;
 use common::test_dir::*;
 
@@ -23,12 +25,19 @@ USAGE:
 
 FLAGS:
         --logical    Fold any unprocessed write sets into the final era array
+    -q, --quiet      Suppress output messages, return only exit code.
     -r, --repair     Repair the metadata whilst dumping it
+    -v, --verbose    Increase the verbosity of output messages, may be repeated
     -h, --help       Prints help information
     -V, --version    Prints version information
 
 OPTIONS:
-    -o, --output <FILE>    Specify the output file rather than stdout
+        --config <FILE>          Read default options from this file instead of the system wide one
+    -f, --format <FORMAT>        Write xml, or json ranges of blocks sharing an era [default: xml]
+        --log-file <FILE>        Append full diagnostics to this file
+        --max-memory <SIZE>      Limit memory use, in MiB unless a unit is given
+    -o, --output <FILE>          Specify the output file rather than stdout
+        --trace-output <FILE>    Write a Chrome trace of the main phases to this file
 
 ARGS:
     <INPUT>    Specify the input device to dump";
@@ -121,3 +130,72 @@ fn dump_restore_cycle() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn dump_restore_cycle_of_random_metadata() {
+    round_trip::quickcheck(20, |m: EraModel| {
+        to_test_result(check_dump_restore_dump(
+            &m,
+            |xml, md| era_restore_cmd(args!["-i", xml, "-o", md]),
+            |md| era_dump_cmd(args![md]),
+        ))
+    });
+}
+
+//------------------------------------------
+
+// The (block, era) pairs of an xml era array.
+fn xml_eras(xml: &str) -> Vec<(u32, u32)> {
+    let attr = |line: &str, name: &str| -> u32 {
+        let start = line.find(&format!("{}=\"", name)).unwrap() + name.len() + 2;
+        let len = line[start..].find('"').unwrap();
+        line[start..start + len].parse().unwrap()
+    };
+
+    xml.lines()
+        .map(|l| l.trim())
+        .filter(|l| l.starts_with("<era "))
+        .map(|l| (attr(l, "block"), attr(l, "era")))
+        .collect()
+}
+
+#[test]
+fn json_ranges_match_logical_xml() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let xml = run_ok(era_dump_cmd(args!["--logical", &md]))?;
+    let output = run_ok(era_dump_cmd(args!["--logical", "--format", "json", &md]))?;
+
+    let v = json::parse(&output)?;
+    let mut eras = Vec::new();
+    let mut last: Option<(u32, u32)> = None;
+    for r in v["eras"].members() {
+        let begin = r["block_begin"].as_u32().unwrap();
+        let end = r["block_end"].as_u32().unwrap();
+        let era = r["era"].as_u32().unwrap();
+        assert!(begin < end);
+
+        // adjacent ranges would have been merged
+        if let Some((last_end, last_era)) = last {
+            assert!(last_end != begin || last_era != era);
+        }
+        last = Some((end, era));
+
+        eras.extend((begin..end).map(|b| (b, era)));
+    }
+
+    assert!(!eras.is_empty());
+    assert_eq!(eras, xml_eras(&xml));
+    Ok(())
+}
+
+#[test]
+fn json_requires_logical() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stderr = run_fail(era_dump_cmd(args!["--format", "json", &md]))?;
+    assert!(stderr.contains("--logical"));
+    Ok(())
+}
+
+//------------------------------------------
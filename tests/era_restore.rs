@@ -18,16 +18,21 @@ const USAGE: &str = "era_restore 0.9.0
 Convert XML format metadata to binary.
 
 USAGE:
-    era_restore [FLAGS] --input <FILE> --output <FILE>
+    era_restore [FLAGS] [OPTIONS] --input <FILE> --output <FILE>
 
 FLAGS:
     -q, --quiet      Suppress output messages, return only exit code.
+    -v, --verbose    Increase the verbosity of output messages, may be repeated
     -h, --help       Prints help information
     -V, --version    Prints version information
 
 OPTIONS:
-    -i, --input <FILE>     Specify the input xml
-    -o, --output <FILE>    Specify the output device to check";
+        --config <FILE>          Read default options from this file instead of the system wide one
+    -i, --input <FILE>           Specify the input xml
+        --log-file <FILE>        Append full diagnostics to this file
+        --max-memory <SIZE>      Limit memory use, in MiB unless a unit is given
+    -o, --output <FILE>          Specify the output device to check
+        --trace-output <FILE>    Write a Chrome trace of the main phases to this file";
 
 //------------------------------------------
 
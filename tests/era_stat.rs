@@ -0,0 +1,138 @@
+use anyhow::Result;
+
+mod common;
+
+use common::common_args::*;
+use common::era::*;
+use common::fixture::*;
+use common::input_arg::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+const USAGE: &str = "era_stat 0.9.0
+Summarise the era metadata
+
+USAGE:
+    era_stat [FLAGS] [OPTIONS] <INPUT>
+
+FLAGS:
+    -q, --quiet      Suppress output messages, return only exit code.
+    -v, --verbose    Increase the verbosity of output messages, may be repeated
+    -h, --help       Prints help information
+    -V, --version    Prints version information
+
+OPTIONS:
+        --config <FILE>        Read default options from this file instead of the system wide one
+        --log-file <FILE>      Append full diagnostics to this file
+        --max-memory <SIZE>    Limit memory use, in MiB unless a unit is given
+
+ARGS:
+    <INPUT>    Specify the input device";
+
+//------------------------------------------
+
+struct EraStat;
+
+impl<'a> Program<'a> for EraStat {
+    fn name() -> &'a str {
+        "era_stat"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        era_stat_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::InputArg
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+impl<'a> InputProgram<'a> for EraStat {
+    fn mk_valid_input(td: &mut TestDir) -> Result<std::path::PathBuf> {
+        mk_valid_md(td)
+    }
+
+    fn file_not_found() -> &'a str {
+        msg::FILE_NOT_FOUND
+    }
+
+    fn missing_input_arg() -> &'a str {
+        msg::MISSING_INPUT_ARG
+    }
+
+    fn corrupted_input() -> &'a str {
+        msg::BAD_SUPERBLOCK
+    }
+}
+
+//------------------------------------------
+
+test_accepts_help!(EraStat);
+test_accepts_version!(EraStat);
+test_rejects_bad_option!(EraStat);
+
+test_missing_input_arg!(EraStat);
+test_input_file_not_found!(EraStat);
+test_input_cannot_be_a_directory!(EraStat);
+
+//------------------------------------------
+
+#[test]
+fn summarises_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(
+        &xml,
+        r#"<superblock uuid="" block_size="128" nr_blocks="8" current_era="10">
+  <writeset era="9" nr_bits="8">
+    <marked block_begin="1" len="2"/>
+  </writeset>
+  <writeset era="10" nr_bits="8">
+    <marked block_begin="2" len="1"/>
+  </writeset>
+  <era_array>
+    <era block="0" era="1"/>
+    <era block="1" era="5"/>
+    <era block="2" era="8"/>
+    <era block="3" era="8"/>
+    <era block="4" era="0"/>
+    <era block="5" era="3"/>
+    <era block="6" era="7"/>
+    <era block="7" era="2"/>
+  </era_array>
+</superblock>
+"#,
+    )?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(era_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+
+    let stdout = run_ok(era_stat_cmd(args![&md]))?;
+    assert!(stdout.contains("current era:      10"));
+    assert!(stdout.contains("writesets:        2"));
+    assert!(stdout.contains("era 9: 2 blocks marked"));
+    assert!(stdout.contains("era 10: 1 blocks marked"));
+
+    // the ages come from the era array with the write sets folded in
+    assert!(stdout.contains("0                        1"));
+    assert!(stdout.contains("2-3                      2"));
+    assert!(stdout.contains("8-15                     3"));
+    Ok(())
+}
+
+//------------------------------------------
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+mod common;
+
+use common::cache::*;
+use common::process::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+// The corpus is a tree of files, which TestDir can't clean up.
+fn mk_corpus(td: &mut TestDir) -> Result<tempfile::TempDir> {
+    let corpus = tempfile::Builder::new().prefix("corpus").tempdir()?;
+
+    let md = mk_valid_md(td)?;
+    run_ok(pdata_corpus_cmd(args![
+        "-c",
+        corpus.path(),
+        "add",
+        "--kind",
+        "cache",
+        "-i",
+        &md,
+        "--description",
+        "valid cache",
+        "valid"
+    ]))?;
+    Ok(corpus)
+}
+
+fn expected(corpus: &Path, run: &str) -> PathBuf {
+    corpus
+        .join("valid")
+        .join("expected")
+        .join(format!("{}.out", run))
+}
+
+//------------------------------------------
+
+// Every entry checked in must still give the output recorded for it.
+#[test]
+fn repo_corpus_is_unchanged() -> Result<()> {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    run_ok(pdata_corpus_cmd(args!["-c", &corpus, "run"]))?;
+    Ok(())
+}
+
+#[test]
+fn add_records_every_tool() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let dir = mk_corpus(&mut td)?;
+    let corpus = dir.path();
+
+    for run in &["cache_check", "cache_dump", "cache_repair", "cache_stat"] {
+        assert!(expected(corpus, run).is_file());
+    }
+
+    let dump = std::fs::read_to_string(expected(corpus, "cache_dump"))?;
+    assert!(dump.starts_with("$ cache_dump {md}\nexit: 0\n"));
+
+    let list = run_ok(pdata_corpus_cmd(args!["-c", corpus, "list"]))?;
+    assert!(list.contains("valid") && list.contains("valid cache"));
+    Ok(())
+}
+
+#[test]
+fn run_passes_when_nothing_changed() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let dir = mk_corpus(&mut td)?;
+    let corpus = dir.path();
+    let stdout = run_ok(pdata_corpus_cmd(args!["-c", corpus, "run"]))?;
+    assert!(stdout.contains("ok      valid"));
+    Ok(())
+}
+
+#[test]
+fn run_reports_changed_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let dir = mk_corpus(&mut td)?;
+    let corpus = dir.path();
+
+    let path = expected(corpus, "cache_stat");
+    let text = std::fs::read_to_string(&path)?;
+    std::fs::write(&path, text.replace("cache blocks:", "cache blocks: 0"))?;
+
+    let output = run_fail_raw(pdata_corpus_cmd(args!["-c", corpus, "run"]))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("CHANGED valid cache_stat"));
+    assert!(stdout.contains("-cache blocks: 0"));
+    assert!(!stdout.contains("CHANGED valid cache_dump"));
+    Ok(())
+}
+
+#[test]
+fn record_accepts_changed_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let dir = mk_corpus(&mut td)?;
+    let corpus = dir.path();
+
+    std::fs::remove_file(expected(corpus, "cache_check"))?;
+    run_fail(pdata_corpus_cmd(args!["-c", corpus, "run"]))?;
+
+    run_ok(pdata_corpus_cmd(args!["-c", corpus, "record", "valid"]))?;
+    run_ok(pdata_corpus_cmd(args!["-c", corpus, "run"]))?;
+    Ok(())
+}
+
+#[test]
+fn add_refuses_existing_entry() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let dir = mk_corpus(&mut td)?;
+    let corpus = dir.path();
+    let md = mk_valid_md(&mut td)?;
+    let stderr = run_fail(pdata_corpus_cmd(args![
+        "-c", corpus, "add", "--kind", "cache", "-i", &md, "valid"
+    ]))?;
+    assert!(stderr.contains("already exists"));
+    Ok(())
+}
+
+#[test]
+fn run_rejects_unknown_entry() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let dir = mk_corpus(&mut td)?;
+    let corpus = dir.path();
+    let stderr = run_fail(pdata_corpus_cmd(args!["-c", corpus, "run", "nonexistent"]))?;
+    assert!(stderr.contains("no corpus entry named 'nonexistent'"));
+    Ok(())
+}
+
+//------------------------------------------
@@ -0,0 +1,216 @@
+use anyhow::Result;
+use std::ffi::OsString;
+
+mod common;
+
+use common::fixture::*;
+use common::process::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+fn pdata_tools_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    const RUST_PATH: &str = env!(concat!("CARGO_BIN_EXE_", "pdata_tools"));
+    let args_ = args.into_iter().map(Into::<OsString>::into).collect();
+    Command::new(OsString::from(RUST_PATH), args_)
+}
+
+//------------------------------------------
+
+#[test]
+fn no_command_prints_usage() -> Result<()> {
+    let stderr = run_fail(pdata_tools_cmd(Vec::<&str>::new()))?;
+    assert!(stderr.starts_with("Usage: <command> <args>"));
+    assert!(stderr.contains("  thin_check\n"));
+    Ok(())
+}
+
+#[test]
+fn unknown_command() -> Result<()> {
+    let stderr = run_fail(pdata_tools_cmd(args!["thin_hedgehog"]))?;
+    assert!(stderr.starts_with("Unknown command 'thin_hedgehog'"));
+    Ok(())
+}
+
+#[test]
+fn dispatches_every_command() -> Result<()> {
+    let stderr = run_fail(pdata_tools_cmd(Vec::<&str>::new()))?;
+    let cmds: Vec<&str> = stderr
+        .lines()
+        .skip_while(|l| *l != "commands:")
+        .skip(1)
+        .take_while(|l| !l.is_empty())
+        .map(|l| l.trim())
+        .collect();
+    assert!(cmds.contains(&"era_invalidate"));
+    assert!(cmds.contains(&"era_repair"));
+
+    for cmd in cmds {
+        let stdout = run_ok(rust_cmd(cmd, args!["-V"]))?;
+        assert!(stdout.starts_with(cmd));
+    }
+    Ok(())
+}
+
+#[test]
+fn completions_cover_long_options() -> Result<()> {
+    let stdout = run_ok(pdata_tools_cmd(args!["completions", "bash", "thin_check"]))?;
+    assert!(stdout.contains("_thin_check()"));
+    assert!(stdout.contains("--clear-needs-check-flag"));
+    assert!(!stdout.contains("_thin_dump()"));
+    Ok(())
+}
+
+#[test]
+fn completions_unknown_shell() -> Result<()> {
+    run_fail(pdata_tools_cmd(args!["completions", "tcsh"]))?;
+    Ok(())
+}
+
+//------------------------------------------
+// exit codes
+
+fn exit_code(cmd: Command) -> Result<i32> {
+    let output = run_fail_raw(cmd)?;
+    Ok(output.status.code().unwrap())
+}
+
+#[test]
+fn bad_command_line_exits_with_2() -> Result<()> {
+    for cmd in &["thin_check", "thin_dump", "cache_check", "era_dump"] {
+        assert_eq!(exit_code(rust_cmd(cmd, args!["--hedgehog"]))?, 2);
+    }
+    assert_eq!(exit_code(pdata_tools_cmd(args!["thin_hedgehog"]))?, 2);
+    assert_eq!(
+        exit_code(rust_cmd(
+            "thin_metadata_size",
+            args!["-b", "0", "-s", "1g", "-m", "1"]
+        ))?,
+        2
+    );
+    Ok(())
+}
+
+#[test]
+fn missing_input_exits_with_1() -> Result<()> {
+    assert_eq!(exit_code(rust_cmd("thin_check", args!["no-such-file"]))?, 1);
+    Ok(())
+}
+
+#[test]
+fn damaged_metadata_exits_with_3() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    assert_eq!(exit_code(rust_cmd("thin_check", args![&md]))?, 3);
+    assert_eq!(exit_code(rust_cmd("cache_check", args![&md]))?, 3);
+    Ok(())
+}
+
+// The C++ tools share the same codes.
+
+#[test]
+fn cpp_bad_command_line_exits_with_2() -> Result<()> {
+    assert_eq!(exit_code(thin_ls_cmd(args!["--hedgehog"]))?, 2);
+    assert_eq!(exit_code(thin_delta_cmd(args!["--hedgehog"]))?, 2);
+    assert_eq!(exit_code(thin_rmap_cmd(args!["--hedgehog"]))?, 2);
+    assert_eq!(exit_code(thin_ls_cmd(args!["-o", "HEDGEHOG", "md"]))?, 2);
+    assert_eq!(exit_code(thin_delta_cmd(args!["--snap1", "1", "md"]))?, 2);
+    assert_eq!(exit_code(thin_rmap_cmd(args!["md"]))?, 2);
+    Ok(())
+}
+
+#[test]
+fn cpp_missing_input_exits_with_1() -> Result<()> {
+    assert_eq!(exit_code(thin_ls_cmd(args!["no-such-file"]))?, 1);
+    assert_eq!(
+        exit_code(thin_delta_cmd(args![
+            "--snap1",
+            "1",
+            "--snap2",
+            "2",
+            "no-such-file"
+        ]))?,
+        1
+    );
+    assert_eq!(
+        exit_code(thin_rmap_cmd(args!["--region", "0..1", "no-such-file"]))?,
+        1
+    );
+    Ok(())
+}
+
+#[test]
+fn cpp_damaged_metadata_exits_with_3() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    assert_eq!(exit_code(thin_ls_cmd(args![&md]))?, 3);
+    assert_eq!(
+        exit_code(thin_delta_cmd(args!["--snap1", "1", "--snap2", "2", &md]))?,
+        3
+    );
+    assert_eq!(exit_code(thin_rmap_cmd(args!["--region", "0..1", &md]))?, 3);
+    Ok(())
+}
+
+//------------------------------------------
+// front ends
+
+fn mk_thin_md(td: &mut TestDir) -> Result<std::path::PathBuf> {
+    let xml = common::thin::mk_valid_xml(td)?;
+    let md = mk_zeroed_md(td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+#[test]
+fn front_ends_detect_the_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let thin = mk_thin_md(&mut td)?;
+    let cache = common::cache::mk_valid_md(&mut td)?;
+    let era = common::era::mk_valid_md(&mut td)?;
+
+    run_ok(rust_cmd("check", args![&thin]))?;
+    run_ok(rust_cmd("check", args!["-q", &cache]))?;
+    run_ok(rust_cmd("check", args![&era]))?;
+
+    let stdout = run_ok(rust_cmd("dump", args![&thin]))?;
+    assert!(stdout.contains("nr_data_blocks="));
+    let stdout = run_ok(rust_cmd("dump", args![&cache]))?;
+    assert!(stdout.contains("nr_cache_blocks="));
+    let stdout = run_ok(rust_cmd("dump", args![&era]))?;
+    assert!(stdout.contains("current_era="));
+    Ok(())
+}
+
+#[test]
+fn front_ends_take_an_explicit_type() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let thin = mk_thin_md(&mut td)?;
+
+    run_ok(rust_cmd("check", args!["--type", "thin", &thin]))?;
+    assert_eq!(
+        exit_code(rust_cmd("check", args!["--type=cache", &thin]))?,
+        3
+    );
+    assert_eq!(
+        exit_code(rust_cmd("dump", args!["--type", "vdo", &thin]))?,
+        2
+    );
+    Ok(())
+}
+
+#[test]
+fn front_ends_reject_unknown_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(rust_cmd("check", args![&md]))?;
+    assert!(stderr.contains("use --type to say"));
+    Ok(())
+}
+
+//------------------------------------------
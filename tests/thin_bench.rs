@@ -0,0 +1,146 @@
+use anyhow::Result;
+
+mod common;
+
+use common::common_args::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+const USAGE: &str = concat!(
+    "thin_bench ",
+    include_str!("../VERSION"),
+    "Time restoring, checking and dumping generated thin metadata.\n\
+     \n\
+     USAGE:\n    \
+         thin_bench [FLAGS] [OPTIONS]\n\
+     \n\
+     FLAGS:\n        \
+             --async-io    Use io_uring rather than synchronous io\n    \
+         -q, --quiet       Suppress output messages, return only exit code.\n        \
+             --sync-io     Use synchronous io, even if the config file selects io_uring\n    \
+         -v, --verbose     Increase the verbosity of output messages, may be repeated\n    \
+         -h, --help        Prints help information\n    \
+         -V, --version     Prints version information\n\
+     \n\
+     OPTIONS:\n        \
+             --config <FILE>        Read default options from this file instead of the system wide one\n        \
+             --dir <DIR>            Create the scratch files in this directory\n        \
+             --io-threads <NUM>     Number of sync io threads, overriding the config file\n        \
+             --iterations <NUM>     Number of times to run each operation [default: 3]\n        \
+             --log-file <FILE>      Append full diagnostics to this file\n        \
+             --max-memory <SIZE>    Limit memory use, in MiB unless a unit is given\n        \
+             --nr-mappings <NUM>    Number of mappings in each thin device [default: 250000]\n        \
+             --nr-thins <NUM>       Number of thin devices to generate [default: 4]\n        \
+             --seed <NUM>           Seed for the generated metadata [default: 0]"
+);
+
+//------------------------------------------
+
+struct ThinBench;
+
+impl<'a> Program<'a> for ThinBench {
+    fn name() -> &'a str {
+        "thin_bench"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        thin_bench_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::InputArg
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+//------------------------------------------
+
+test_accepts_help!(ThinBench);
+test_accepts_version!(ThinBench);
+test_rejects_bad_option!(ThinBench);
+
+//------------------------------------------
+
+#[test]
+fn times_each_operation() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let dir = td.mk_path("scratch");
+    std::fs::create_dir(&dir)?;
+
+    let stdout = run_ok(thin_bench_cmd(args![
+        "-q",
+        "--dir",
+        &dir,
+        "--nr-thins",
+        "3",
+        "--nr-mappings",
+        "2000",
+        "--iterations",
+        "2"
+    ]))?;
+
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].starts_with("io engine: "));
+    for (line, op) in lines[2..].iter().zip(["restore", "check", "dump"]) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0], op);
+        assert!(fields[1].ends_with('s') && fields[2].ends_with('s'));
+    }
+
+    // the scratch files are cleaned up
+    assert_eq!(std::fs::read_dir(&dir)?.count(), 0);
+    std::fs::remove_dir(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn sync_io_overrides_config() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let dir = td.mk_path("scratch");
+    std::fs::create_dir(&dir)?;
+    let config = td.mk_path("async.conf");
+    std::fs::write(&config, "io_engine = async\n")?;
+
+    let stdout = run_ok(thin_bench_cmd(args![
+        "-q",
+        "--config",
+        &config,
+        "--sync-io",
+        "--dir",
+        &dir,
+        "--nr-thins",
+        "1",
+        "--nr-mappings",
+        "100",
+        "--iterations",
+        "1"
+    ]))?;
+    assert!(stdout.starts_with("io engine: sync"));
+    std::fs::remove_dir(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn zero_iterations_rejected() -> Result<()> {
+    run_fail(thin_bench_cmd(args!["--iterations", "0"]))?;
+    Ok(())
+}
+
+//------------------------------------------
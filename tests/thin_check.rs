@@ -1,4 +1,25 @@
 use anyhow::Result;
+use byteorder::{ByteOrder, LittleEndian};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use thinp::checksum::{write_checksum, BT};
+use thinp::fault_engine::{FaultEngine, FaultScript};
+use thinp::file_utils;
+use thinp::io_engine::*;
+use thinp::pdata::btree::{unpack_node, Node};
+use thinp::pdata::btree_walker::btree_to_map;
+use thinp::pdata::space_map_common::{Bitmap, BitmapEntry, IndexEntry, SMRoot, ENTRIES_PER_BITMAP};
+use thinp::pdata::space_map_metadata::MetadataIndex;
+use thinp::pdata::unpack::{unpack, Pack};
+use thinp::policy::{Action, Policy};
+use thinp::report::mk_quiet_report;
+use thinp::thin::block_time::BlockTime;
+use thinp::thin::check::{check, ThinCheckOptions, POLICY_CATEGORIES};
+use thinp::thin::device_detail::DeviceDetail;
+use thinp::thin::superblock::{
+    backup_superblock_location, read_superblock, write_superblock, SUPERBLOCK_LOCATION,
+};
 
 mod common;
 
@@ -20,18 +41,33 @@ USAGE:
     thin_check [FLAGS] [OPTIONS] <INPUT>
 
 FLAGS:
+        --audit                      List every check made, and what it found
         --auto-repair                Auto repair trivial issues.
         --clear-needs-check-flag     Clears the 'needs_check' flag in the superblock
+        --error-if-needs-check       Fail if the needs_check flag is set, even if no damage is found
+        --fix-out-of-range           Drop mappings that point past the end of the pool
         --ignore-non-fatal-errors    Only return a non-zero exit code if a fatal error is found.
     -m, --metadata-snapshot          Check the metadata snapshot on a live pool
     -q, --quiet                      Suppress output messages, return only exit code.
         --super-block-only           Only check the superblock.
         --skip-mappings              Don't check the mapping tree
+    -v, --verbose                    Increase the verbosity of output messages, may be repeated
     -h, --help                       Prints help information
     -V, --version                    Prints version information
 
 OPTIONS:
+        --config <FILE>
+            Read default options from this file instead of the system wide one
+
+        --data-device <DEV>                                Check the pool fits on this data device
+        --journal <FILE>                                   Save overwritten blocks to this file, for --rollback
+        --log-file <FILE>                                  Append full diagnostics to this file
+        --max-memory <SIZE>                                Limit memory use, in MiB unless a unit is given
         --override-mapping-root <OVERRIDE_MAPPING_ROOT>    Specify a mapping root to use
+        --policy <FILE>                                    Read what to do about each kind of finding from this file
+        --rollback <JOURNAL>                               Undo the repair recorded in this journal
+        --timeout <SECS>                                   Stop, changing nothing, after this many seconds
+        --trace-output <FILE>                              Write a Chrome trace of the main phases to this file
 
 ARGS:
     <INPUT>    Specify the input device to check";
@@ -363,3 +399,856 @@ fn auto_repair_clears_needs_check() -> Result<()> {
 }
 
 //------------------------------------------
+
+// The block holding a backup superblock isn't a leak.
+#[test]
+fn accepts_backup_superblock() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd(
+        "thin_restore",
+        args!["-i", &xml, "-o", &md, "--backup-superblock"],
+    ))?;
+    run_ok(rust_cmd("thin_check", args![&md]))?;
+    Ok(())
+}
+
+// Only the copy the superblock points at is accounted for, so one it
+// doesn't know about is a leak.
+#[test]
+fn unrecorded_backup_superblock_is_leaked() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd(
+        "thin_restore",
+        args!["-i", &xml, "-o", &md, "--backup-superblock"],
+    ))?;
+
+    let engine = SyncIoEngine::new(&md, 1, true)?;
+    let mut sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    assert_eq!(
+        sb.backup_superblock,
+        backup_superblock_location(engine.get_nr_blocks())
+    );
+    sb.backup_superblock = 0;
+    write_superblock(&engine, SUPERBLOCK_LOCATION, &sb)?;
+    drop(engine);
+
+    let stderr = run_fail(rust_cmd("thin_check", args![&md]))?;
+    assert!(stderr.contains("leaked"));
+    Ok(())
+}
+
+//------------------------------------------
+
+const SHRUNK_POOL_XML: &str = r#"<superblock uuid="" time="0" transaction="1" version="2" data_block_size="128" nr_data_blocks="1000">
+  <device dev_id="0" mapped_blocks="20" transaction="0" creation_time="0" snap_time="0">
+    <range_mapping origin_begin="0" data_begin="0" length="10" time="0"/>
+    <range_mapping origin_begin="10" data_begin="900" length="10" time="0"/>
+  </device>
+</superblock>
+"#;
+
+#[test]
+fn data_device_fits() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(&xml, SHRUNK_POOL_XML)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    let data = td.mk_path("data.bin");
+    let _file = file_utils::create_sized_file(&data, 1000 * 128 * 512);
+    run_ok(thin_check_cmd(args!["--data-device", &data, &md]))?;
+    Ok(())
+}
+
+#[test]
+fn data_device_too_small() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(&xml, SHRUNK_POOL_XML)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    let data = td.mk_path("data.bin");
+    let _file = file_utils::create_sized_file(&data, 500 * 128 * 512);
+    let stderr = run_fail(thin_check_cmd(args!["--data-device", &data, &md]))?;
+    assert!(
+        stderr.contains("The data device holds 500 blocks of 128 sectors, but the pool has 1000")
+    );
+    assert!(stderr.contains(
+        "10 mapped data blocks lie beyond the end of the data device, the first is block 900"
+    ));
+
+    // a superblock only check still catches the size
+    run_fail(thin_check_cmd(args![
+        "--super-block-only",
+        "--data-device",
+        &data,
+        &md
+    ]))?;
+    Ok(())
+}
+
+// A damaged block size is caught before it's used to size the data
+// device.
+#[test]
+fn bad_data_block_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(&xml, SHRUNK_POOL_XML)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    let data = td.mk_path("data.bin");
+    let _file = file_utils::create_sized_file(&data, 1000 * 128 * 512);
+    for bs in &[0, 100] {
+        let engine = SyncIoEngine::new(&md, 1, true)?;
+        let mut sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+        sb.data_block_size = *bs;
+        write_superblock(&engine, SUPERBLOCK_LOCATION, &sb)?;
+        drop(engine);
+
+        let stderr = run_fail(thin_check_cmd(args!["--data-device", &data, &md]))?;
+        assert!(stderr.contains(&format!(
+            "data block size of {} sectors is not a non-zero multiple of 128 sectors",
+            bs
+        )));
+    }
+    Ok(())
+}
+
+//------------------------------------------
+
+// A device with a run of mappings starting at block zero for each length.
+fn mk_devs_md(td: &mut TestDir, lens: &[u64]) -> Result<PathBuf> {
+    let xml = td.mk_path("meta.xml");
+    let mut text = String::from("<superblock uuid=\"\" time=\"0\" transaction=\"1\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"1000000\">\n");
+    let mut data_begin = 0;
+    for (dev, len) in lens.iter().enumerate() {
+        text.push_str(&format!("  <device dev_id=\"{}\" mapped_blocks=\"{}\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">\n", dev, len));
+        text.push_str(&format!(
+            "    <range_mapping origin_begin=\"0\" data_begin=\"{}\" length=\"{}\" time=\"0\"/>\n",
+            data_begin, len
+        ));
+        text.push_str("  </device>\n");
+        data_begin += len;
+    }
+    text.push_str("</superblock>\n");
+    std::fs::write(&xml, text)?;
+
+    let md = mk_zeroed_md(td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+fn device_roots(md: &Path) -> Result<Vec<u64>> {
+    let engine = Arc::new(SyncIoEngine::new(md, 1, false)?);
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![0], engine, false, sb.mapping_root)?;
+    Ok(roots.values().cloned().collect())
+}
+
+// Points the first child of an internal node at another block.
+fn redirect_first_child(md: &Path, node: u64, target: u64) -> Result<()> {
+    let engine = SyncIoEngine::new(md, 1, true)?;
+    let b = engine.read(node)?;
+    let data = b.get_data();
+    let max_entries = LittleEndian::read_u32(&data[20..24]) as usize;
+    let offset = 32 + max_entries * 8;
+    LittleEndian::write_u64(&mut data[offset..offset + 8], target);
+    write_checksum(data, BT::NODE)?;
+    engine.write(&b)?;
+    Ok(())
+}
+
+#[test]
+fn detects_cycle() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000])?;
+    let roots = device_roots(&md)?;
+    redirect_first_child(&md, roots[0], roots[0])?;
+
+    let stderr = run_fail(thin_check_cmd(args![&md]))?;
+    assert!(stderr.contains(&format!(
+        "node {} points back to its ancestor {}",
+        roots[0], roots[0]
+    )));
+    Ok(())
+}
+
+#[test]
+fn detects_aliased_node() -> Result<()> {
+    let mut td = TestDir::new()?;
+    // Device 0 is three levels deep, device 1 just a leaf, whose keys
+    // are in range for the first child of device 0's root.
+    let md = mk_devs_md(&mut td, &[70000, 100])?;
+    let roots = device_roots(&md)?;
+    redirect_first_child(&md, roots[0], roots[1])?;
+
+    let stderr = run_fail(thin_check_cmd(args![&md]))?;
+    assert!(stderr.contains("one is aliased with another tree"));
+    Ok(())
+}
+
+// Both devices share a subtree of 2000 mappings, so thin_check only
+// walks it once.
+fn mk_shared_md(td: &mut TestDir, mapped_blocks: &[u64]) -> Result<PathBuf> {
+    let xml = td.mk_path("meta.xml");
+    let mut text = String::from("<superblock uuid=\"\" time=\"0\" transaction=\"1\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"10000\">\n");
+    text.push_str("  <def name=\"0\">\n");
+    text.push_str(
+        "    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"2000\" time=\"0\"/>\n",
+    );
+    text.push_str("  </def>\n");
+    for (dev, mapped) in mapped_blocks.iter().enumerate() {
+        text.push_str(&format!("  <device dev_id=\"{}\" mapped_blocks=\"{}\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">\n", dev, mapped));
+        text.push_str("    <ref name=\"0\"/>\n");
+        text.push_str(&format!(
+            "    <range_mapping origin_begin=\"5000\" data_begin=\"{}\" length=\"10\" time=\"0\"/>\n",
+            5000 + dev * 10
+        ));
+        text.push_str("  </device>\n");
+    }
+    text.push_str("</superblock>\n");
+    std::fs::write(&xml, text)?;
+
+    let md = mk_zeroed_md(td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+#[test]
+fn counts_shared_mappings() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_shared_md(&mut td, &[2010, 2010])?;
+    run_ok(thin_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+#[test]
+fn detects_wrong_mapped_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_shared_md(&mut td, &[2010, 10])?;
+    let stderr = run_fail(thin_check_cmd(args![&md]))?;
+    assert!(stderr.contains("device 1: mapped_blocks is 10 but its mapping tree holds 2010"));
+    run_ok(thin_check_cmd(args!["--ignore-non-fatal-errors", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn auto_repair_fixes_mapped_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_shared_md(&mut td, &[2010, 10])?;
+    run_ok(thin_check_cmd(args!["--auto-repair", &md]))?;
+    run_ok(thin_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+// Sets a metadata block's count in the on-disk space map, leaving the
+// index entries alone since thin_check doesn't look at them.
+fn set_metadata_ref_count(engine: &SyncIoEngine, b: u64, count: u8) -> Result<()> {
+    let sb = read_superblock(engine, SUPERBLOCK_LOCATION)?;
+    let root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
+    let index = unpack::<MetadataIndex>(engine.read(root.bitmap_root)?.get_data())?;
+    let blk = engine.read(index.indexes[b as usize / ENTRIES_PER_BITMAP].blocknr)?;
+    let mut bitmap = unpack::<Bitmap>(blk.get_data())?;
+    bitmap.entries[b as usize % ENTRIES_PER_BITMAP] = BitmapEntry::Small(count);
+    bitmap.pack(&mut std::io::Cursor::new(blk.get_data()))?;
+    write_checksum(blk.get_data(), BT::BITMAP)?;
+    engine.write(&blk)?;
+    Ok(())
+}
+
+// Takes a metadata snapshot the way the kernel does: the superblock is
+// copied to a free block, and the roots it points at gain a reference.
+// Returns the location of the copy.
+fn take_metadata_snap(md: &Path, inc_roots: bool) -> Result<u64> {
+    let engine = SyncIoEngine::new(md, 1, true)?;
+    let mut sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    let snap = engine.get_nr_blocks() / 2;
+    write_superblock(&engine, snap, &sb)?;
+    set_metadata_ref_count(&engine, snap, 1)?;
+    if inc_roots {
+        set_metadata_ref_count(&engine, sb.details_root, 2)?;
+        set_metadata_ref_count(&engine, sb.mapping_root, 2)?;
+    }
+
+    sb.metadata_snap = snap;
+    write_superblock(&engine, SUPERBLOCK_LOCATION, &sb)?;
+    Ok(snap)
+}
+
+#[test]
+fn counts_metadata_snap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000, 100])?;
+    take_metadata_snap(&md, true)?;
+    run_ok(thin_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+#[test]
+fn metadata_snap_roots_must_be_shared() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000, 100])?;
+    take_metadata_snap(&md, false)?;
+    let stderr = run_fail(thin_check_cmd(args![&md]))?;
+    assert!(stderr.contains("Expected 2, but space map contains 1"));
+    Ok(())
+}
+
+#[test]
+fn detects_stale_metadata_snap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000, 100])?;
+    let snap = take_metadata_snap(&md, true)?;
+    {
+        let engine = SyncIoEngine::new(&md, 1, true)?;
+        let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+        set_metadata_ref_count(&engine, snap, 0)?;
+        set_metadata_ref_count(&engine, sb.details_root, 1)?;
+        set_metadata_ref_count(&engine, sb.mapping_root, 1)?;
+    }
+
+    let stderr = run_fail(thin_check_cmd(args![&md]))?;
+    assert!(stderr.contains(&format!(
+        "metadata_snap points at block {}, which is free, so the snapshot is stale",
+        snap
+    )));
+
+    run_ok(thin_check_cmd(args!["--auto-repair", &md]))?;
+    run_ok(thin_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+// The repair is written copy-on-write, so the snapshot keeps the old
+// details.
+#[test]
+fn mapped_blocks_can_be_repaired_under_a_metadata_snap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_shared_md(&mut td, &[2010, 10])?;
+    let snap = take_metadata_snap(&md, true)?;
+
+    run_ok(thin_check_cmd(args!["--auto-repair", &md]))?;
+    run_ok(thin_check_cmd(args![&md]))?;
+    let dump = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    assert!(dump.contains("dev_id=\"1\" mapped_blocks=\"2010\""));
+
+    let engine = Arc::new(SyncIoEngine::new(&md, 1, false)?);
+    let sb = read_superblock(engine.as_ref(), snap)?;
+    let devs = btree_to_map::<DeviceDetail>(&mut vec![0], engine, false, sb.details_root)?;
+    assert_eq!(devs[&1].mapped_blocks, 10);
+    Ok(())
+}
+
+// Rewrites the data space map's nr_blocks, as a resize that was
+// interrupted part way through might, and marks data block `b` in use.
+fn resize_data_sm(md: &Path, nr_blocks: u64, b: Option<u64>) -> Result<()> {
+    let engine = Arc::new(SyncIoEngine::new(md, 1, true)?);
+    let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let mut root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+
+    if let Some(b) = b {
+        let index =
+            btree_to_map::<IndexEntry>(&mut vec![0], engine.clone(), false, root.bitmap_root)?;
+        let blk = engine.read(index[&(b / ENTRIES_PER_BITMAP as u64)].blocknr)?;
+        let mut bitmap = unpack::<Bitmap>(blk.get_data())?;
+        bitmap.entries[b as usize % ENTRIES_PER_BITMAP] = BitmapEntry::Small(1);
+        bitmap.pack(&mut std::io::Cursor::new(blk.get_data()))?;
+        write_checksum(blk.get_data(), BT::BITMAP)?;
+        engine.write(&blk)?;
+    }
+
+    root.nr_blocks = nr_blocks;
+    let mut cursor = std::io::Cursor::new(&mut sb.data_sm_root[0..]);
+    root.pack(&mut cursor)?;
+    write_superblock(engine.as_ref(), SUPERBLOCK_LOCATION, &sb)?;
+    Ok(())
+}
+
+#[test]
+fn detects_allocations_beyond_nr_data_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000, 100])?;
+    resize_data_sm(&md, 2000, Some(5000))?;
+
+    let stderr = run_fail(thin_check_cmd(args![&md]))?;
+    assert!(stderr.contains(
+        "1 data blocks beyond the end of the space map, which has 2000 blocks, are marked in use."
+    ));
+
+    run_ok(thin_check_cmd(args!["--auto-repair", &md]))?;
+    run_ok(thin_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+#[test]
+fn detects_data_sm_short_of_nr_data_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000, 100])?;
+    resize_data_sm(&md, 2000000, None)?;
+
+    let stderr = run_fail(thin_check_cmd(args!["--auto-repair", &md]))?;
+    assert!(stderr.contains("but the superblock says the pool has 2000000"));
+    Ok(())
+}
+
+#[test]
+fn unknown_incompat_features_are_read_only() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[100])?;
+    set_incompat_flags(&md, 0x8)?;
+
+    let output = run_ok_raw(thin_check_cmd(args![&md]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains(
+        "The metadata has incompatible feature flags 0x8, which these tools don't support"
+    ));
+
+    for flag in &["--auto-repair", "--clear-needs-check-flag"] {
+        let stderr = run_fail(thin_check_cmd(args![flag, &md]))?;
+        assert!(stderr.contains(
+            "refusing to modify metadata with unsupported incompatible feature flags 0x8"
+        ));
+    }
+    Ok(())
+}
+
+fn set_needs_check_flag(md: &Path) -> Result<()> {
+    let engine = SyncIoEngine::new(md, 1, true)?;
+    let mut sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    sb.flags.needs_check = true;
+    write_superblock(&engine, SUPERBLOCK_LOCATION, &sb)
+}
+
+#[test]
+fn timeout_leaves_needs_check() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[70000, 70000])?;
+    set_needs_check_flag(&md)?;
+
+    let output = run_fail_raw(thin_check_cmd(args![
+        "--clear-needs-check-flag",
+        "--timeout",
+        "0.000001",
+        &md
+    ]))?;
+    assert_eq!(output.status.code(), Some(5));
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("the check ran out of time"));
+    assert!(get_needs_check(&md)?);
+    Ok(())
+}
+
+#[test]
+fn completes_within_timeout() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000])?;
+    set_needs_check_flag(&md)?;
+    run_ok(thin_check_cmd(args![
+        "--clear-needs-check-flag",
+        "--timeout",
+        "600",
+        &md
+    ]))?;
+    assert!(!get_needs_check(&md)?);
+    Ok(())
+}
+
+// A shrink that didn't move the mappings off the end of the pool first
+// leaves them pointing past it.
+#[test]
+fn detects_out_of_range_mappings() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000, 100])?;
+    resize_data_sm(&md, 1050, None)?;
+
+    let stderr = run_fail(thin_check_cmd(args![&md]))?;
+    assert!(stderr.contains(
+        "device 1: 50 mappings point past the end of the pool, which has 1050 data blocks"
+    ));
+
+    // --auto-repair never drops mappings.
+    run_fail(thin_check_cmd(args!["--auto-repair", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn out_of_range_mappings_can_be_dropped() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000, 100])?;
+    resize_data_sm(&md, 1050, None)?;
+    let before = std::fs::read(&md)?;
+
+    let stderr = run_fail(thin_check_cmd(args!["--fix-out-of-range", &md]))?;
+    assert!(stderr.contains("needs --journal"));
+
+    // The blocks past the end are still marked in use, so the data
+    // space map needs repairing too.
+    let journal = td.mk_path("repair.journal");
+    run_ok(thin_check_cmd(args![
+        "--fix-out-of-range",
+        "--auto-repair",
+        "--journal",
+        &journal,
+        &md
+    ]))?;
+    run_ok(thin_check_cmd(args![&md]))?;
+    let dump = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    assert!(dump.contains("dev_id=\"1\" mapped_blocks=\"50\""));
+    assert!(dump.contains("data_begin=\"1000\" length=\"50\""));
+
+    run_ok(thin_check_cmd(args!["--rollback", &journal, &md]))?;
+    assert!(std::fs::read(&md)? == before);
+    Ok(())
+}
+
+// Repairs are written to free blocks, and only the superblock is
+// overwritten, so until it is the old metadata is intact.
+#[test]
+fn failed_repair_leaves_the_old_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000, 100])?;
+    resize_data_sm(&md, 1050, None)?;
+    let before = std::fs::read(&md)?;
+    let stderr = run_fail(thin_check_cmd(args![&md]))?;
+
+    let mut policy = Policy::new(POLICY_CATEGORIES);
+    for category in [
+        "out_of_range",
+        "mapped_blocks",
+        "data_leaks",
+        "metadata_leaks",
+    ] {
+        policy.set(category, Action::Fix)?;
+    }
+    let engine = Arc::new(SyncIoEngine::new(&md, 1, true)?);
+    let script = "write 0 eio".parse::<FaultScript>()?;
+    let opts = ThinCheckOptions {
+        engine: Arc::new(FaultEngine::new(engine, script)),
+        sb_only: false,
+        skip_mappings: false,
+        ignore_non_fatal: false,
+        policy,
+        data_device_size: None,
+        timeout: None,
+        audit: false,
+        report: Arc::new(mk_quiet_report()),
+    };
+    assert!(check(opts).is_err());
+
+    // The copies were written, but nothing the old metadata uses.
+    let after = std::fs::read(&md)?;
+    assert!(after[..BLOCK_SIZE] == before[..BLOCK_SIZE]);
+    assert!(after != before);
+    assert_eq!(run_fail(thin_check_cmd(args![&md]))?, stderr);
+    run_ok(thin_check_cmd(args!["--ignore-non-fatal-errors", &md]))?;
+    Ok(())
+}
+
+// Collects the (nr_entries, max_entries) of the leaves below a node.
+fn leaf_sizes(engine: &SyncIoEngine, b: u64, sizes: &mut Vec<(u32, u32)>) -> Result<()> {
+    let blk = engine.read(b)?;
+    match unpack_node::<BlockTime>(&[b], blk.get_data(), true, true)? {
+        Node::Internal { values, .. } => {
+            for child in values {
+                leaf_sizes(engine, child, sizes)?;
+            }
+        }
+        Node::Leaf { header, .. } => sizes.push((header.nr_entries, header.max_entries)),
+    }
+    Ok(())
+}
+
+// The middle run of mappings, a few leaves' worth, is past the end, so
+// dropping it would leave empty leaves if the tree weren't rebuilt.
+#[test]
+fn dropping_out_of_range_mappings_leaves_no_empty_leaves() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(
+        &xml,
+        "<superblock uuid=\"\" time=\"0\" transaction=\"1\" data_block_size=\"128\" nr_data_blocks=\"3000\">
+  <device dev_id=\"0\" mapped_blocks=\"1800\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"600\" time=\"0\"/>
+    <range_mapping origin_begin=\"600\" data_begin=\"2000\" length=\"600\" time=\"0\"/>
+    <range_mapping origin_begin=\"1200\" data_begin=\"600\" length=\"600\" time=\"0\"/>
+  </device>
+</superblock>
+",
+    )?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    resize_data_sm(&md, 1500, None)?;
+
+    let journal = td.mk_path("repair.journal");
+    run_ok(thin_check_cmd(args![
+        "--fix-out-of-range",
+        "--auto-repair",
+        "--journal",
+        &journal,
+        &md
+    ]))?;
+    run_ok(thin_check_cmd(args![&md]))?;
+
+    let engine = SyncIoEngine::new(&md, 1, false)?;
+    let roots = device_roots(&md)?;
+    let mut sizes = Vec::new();
+    leaf_sizes(&engine, roots[0], &mut sizes)?;
+    assert!(sizes.len() > 1);
+    for (nr_entries, max_entries) in &sizes {
+        assert!(
+            *nr_entries >= max_entries / 3,
+            "{} of {}",
+            nr_entries,
+            max_entries
+        );
+    }
+    assert_eq!(sizes.iter().map(|(n, _)| *n).sum::<u32>(), 1200);
+    Ok(())
+}
+
+//------------------------------------------
+
+#[test]
+fn repair_can_be_rolled_back() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000])?;
+    set_needs_check_flag(&md)?;
+    let before = std::fs::read(&md)?;
+
+    let journal = td.mk_path("repair.journal");
+    run_ok(thin_check_cmd(args![
+        "--auto-repair",
+        "--journal",
+        &journal,
+        &md
+    ]))?;
+    assert!(!get_needs_check(&md)?);
+
+    run_ok(thin_check_cmd(args!["--rollback", &journal, &md]))?;
+    assert!(std::fs::read(&md)? == before);
+    Ok(())
+}
+
+#[test]
+fn journal_is_never_overwritten() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000])?;
+    set_needs_check_flag(&md)?;
+
+    let journal = td.mk_path("repair.journal");
+    std::fs::write(&journal, "an earlier journal")?;
+    let stderr = run_fail(thin_check_cmd(args![
+        "--auto-repair",
+        "--journal",
+        &journal,
+        &md
+    ]))?;
+    assert!(stderr.contains("unable to create the journal"));
+    assert!(get_needs_check(&md)?);
+    Ok(())
+}
+
+#[test]
+fn journal_needs_a_repair() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000])?;
+    let journal = td.mk_path("repair.journal");
+    run_fail(thin_check_cmd(args!["--journal", &journal, &md]))?;
+    run_fail(thin_check_cmd(args![
+        "--rollback",
+        &journal,
+        "--auto-repair",
+        &md
+    ]))?;
+    Ok(())
+}
+
+//------------------------------------------
+
+#[test]
+fn audit_lists_every_check() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000, 2000])?;
+    let stdout = run_ok(thin_check_cmd(args!["--audit", &md]))?;
+
+    for check in [
+        "superblock",
+        "device details tree",
+        "mapping tree top level",
+        "mapping tree bottom level",
+        "mapped_blocks counts",
+        "data space map",
+        "metadata space map",
+    ] {
+        let line = stdout
+            .lines()
+            .find(|l| l.trim_start().starts_with(check))
+            .unwrap_or_else(|| panic!("no audit line for {}", check));
+        assert!(line.contains(" pass "), "{}", line);
+    }
+    assert!(stdout.contains("3000 mappings"));
+    assert!(stdout.contains("no snapshot is held"));
+    Ok(())
+}
+
+#[test]
+fn audit_shows_what_skip_mappings_omits() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000])?;
+    let stdout = run_ok(thin_check_cmd(args!["--audit", "--skip-mappings", &md]))?;
+
+    assert!(stdout.contains("1 devices"));
+    let omitted = stdout
+        .lines()
+        .filter(|l| l.contains("omitted by --skip-mappings"))
+        .count();
+    assert_eq!(omitted, 5);
+    Ok(())
+}
+
+// Restores metadata whose only fault is a wrong mapped_blocks count.
+fn mk_wrong_mapped_blocks_md(td: &mut TestDir) -> Result<PathBuf> {
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(
+        &xml,
+        "<superblock uuid=\"\" time=\"0\" transaction=\"1\" data_block_size=\"128\" nr_data_blocks=\"1000\">
+  <device dev_id=\"0\" mapped_blocks=\"10\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"100\" time=\"0\"/>
+  </device>
+</superblock>
+",
+    )?;
+    let md = mk_zeroed_md(td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+#[test]
+fn audit_records_failures() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_wrong_mapped_blocks_md(&mut td)?;
+
+    let output = run_fail_raw(thin_check_cmd(args!["--audit", &md]))?;
+    let stdout = std::str::from_utf8(&output.stdout)?;
+    let line = stdout
+        .lines()
+        .find(|l| l.contains("mapped_blocks counts"))
+        .unwrap();
+    assert!(line.contains(" fail "));
+    assert!(line.contains("1 of 1 devices are wrong"));
+    Ok(())
+}
+
+//------------------------------------------
+
+#[test]
+fn policy_can_downgrade_a_failure() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_wrong_mapped_blocks_md(&mut td)?;
+    let policy = td.mk_path("check.policy");
+    std::fs::write(&policy, "# tolerate bad counts\nmapped_blocks = warn\n")?;
+
+    let output = run_ok_raw(thin_check_cmd(args!["--policy", &policy, &md]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("warning: device details hold incorrect mapped_blocks"));
+    run_fail(thin_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+#[test]
+fn policy_can_fix() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_wrong_mapped_blocks_md(&mut td)?;
+    let policy = td.mk_path("check.policy");
+    std::fs::write(&policy, "mapped_blocks = fix\n")?;
+
+    run_ok(thin_check_cmd(args!["--policy", &policy, &md]))?;
+    run_ok(thin_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+#[test]
+fn policy_can_fail_needs_check() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000])?;
+    set_needs_check_flag(&md)?;
+    let policy = td.mk_path("check.policy");
+    std::fs::write(&policy, "needs_check = fail\n")?;
+
+    let output = run_fail_raw(thin_check_cmd(args!["--policy", &policy, &md]))?;
+    assert_eq!(output.status.code(), Some(3));
+
+    // the command line takes precedence
+    run_ok(thin_check_cmd(args![
+        "--policy",
+        &policy,
+        "--clear-needs-check-flag",
+        &md
+    ]))?;
+    assert!(!get_needs_check(&md)?);
+    Ok(())
+}
+
+#[test]
+fn bad_policy_is_rejected() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000])?;
+    let policy = td.mk_path("check.policy");
+
+    std::fs::write(&policy, "mapped_blocks = mend\n")?;
+    let stderr = run_fail(thin_check_cmd(args!["--policy", &policy, &md]))?;
+    assert!(stderr.contains("line 1: unknown action 'mend'"));
+
+    std::fs::write(&policy, "data_leaks = fix\n")?;
+    run_fail(thin_check_cmd(args!["--policy", &policy, "-m", &md]))?;
+    Ok(())
+}
+
+// Cuts the metadata off half way through a block, as an interrupted
+// copy would.
+fn truncate_md(md: &Path, nr_blocks: u64) -> Result<()> {
+    let file = std::fs::OpenOptions::new().write(true).open(md)?;
+    file.set_len(nr_blocks * BLOCK_SIZE as u64 + BLOCK_SIZE as u64 / 2)?;
+    Ok(())
+}
+
+#[test]
+fn truncated_metadata_is_reported() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000, 2000])?;
+
+    // Nothing in use is lost, but the device is too small.
+    truncate_md(&md, 2048)?;
+    let output = run_fail_raw(thin_check_cmd(args![&md]))?;
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("it may have been truncated"));
+    Ok(())
+}
+
+#[test]
+fn missing_blocks_are_reported() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_devs_md(&mut td, &[1000, 2000])?;
+    let root = *device_roots(&md)?.iter().max().unwrap();
+    truncate_md(&md, root)?;
+
+    let output = run_fail_raw(thin_check_cmd(args![&md]))?;
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("past the end of the metadata device"));
+
+    let output = run_fail_raw(rust_cmd("thin_dump", args![&md]))?;
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("past the end of the metadata device"));
+    Ok(())
+}
+
+//------------------------------------------
@@ -1,6 +1,12 @@
 use anyhow::Result;
+use std::collections::BTreeMap;
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::sync::Arc;
+use thinp::io_engine::*;
+use thinp::pdata::btree_walker::btree_to_map;
+use thinp::thin::index::{read_index, write_index};
+use thinp::thin::superblock::{read_superblock, SUPERBLOCK_LOCATION};
 
 mod common;
 
@@ -9,9 +15,12 @@ use common::fixture::*;
 use common::input_arg::*;
 use common::process::*;
 use common::program::*;
+use common::random::test_seed;
+use common::round_trip::{self, *};
 use common::target::*;
 use common::test_dir::*;
 use common::thin::*;
+use common::thin_xml_generator::{write_xml, SharedSnapsS, SingleThinS, ThinModel};
 
 //------------------------------------------
 
@@ -22,17 +31,28 @@ USAGE:
     thin_dump [FLAGS] [OPTIONS] <INPUT>
 
 FLAGS:
-    -q, --quiet            Suppress output messages, return only exit code.
-    -r, --repair           Repair the metadata whilst dumping it
-        --skip-mappings    Do not dump the mappings
-    -h, --help             Prints help information
-    -V, --version          Prints version information
+        --annotate-shared    Mark each mapping with whether its data blocks are shared
+        --canonical          Expand shared mappings so dumps of the same mappings are identical
+        --no-coalesce        Write every mapping as a single mapping, rather than as ranges
+    -q, --quiet              Suppress output messages, return only exit code.
+    -r, --repair             Repair the metadata whilst dumping it
+        --skip-mappings      Do not dump the mappings
+    -v, --verbose            Increase the verbosity of output messages, may be repeated
+    -h, --help               Prints help information
+    -V, --version            Prints version information
 
 OPTIONS:
+        --config <FILE>                            Read default options from this file instead of the system wide one
         --data-block-size <SECTORS>                Provide the data block size for repairing
+    -f, --format <FORMAT>                          Write xml, or a human readable summary table [default: xml]
+        --index <FILE>                             Reuse, or rebuild if stale, an index of the metadata layout
+        --log-file <FILE>                          Append full diagnostics to this file
+        --max-memory <SIZE>                        Limit memory use, in MiB unless a unit is given
     -m, --metadata-snapshot <METADATA_SNAPSHOT>    Access the metadata snapshot on a live pool
+        --min-range <NUM>                          Shortest run of mappings to write as a range [default: 2]
         --nr-data-blocks <NUM>                     Override the number of data blocks if needed
     -o, --output <FILE>                            Specify the output file rather than stdout
+        --trace-output <FILE>                      Write a Chrome trace of the main phases to this file
         --transaction-id <NUM>                     Override the transaction id if needed
 
 ARGS:
@@ -125,6 +145,369 @@ fn dump_restore_cycle() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn dump_restore_cycle_of_random_metadata() {
+    round_trip::quickcheck(20, |m: ThinModel| {
+        to_test_result(check_dump_restore_dump(
+            &m,
+            |xml, md| thin_restore_cmd(args!["-i", xml, "-o", md]),
+            |md| thin_dump_cmd(args!["--canonical", md]),
+        ))
+    });
+}
+
+//------------------------------------------
+// test canonical dumps only depend on the mappings
+
+#[test]
+fn canonical_dump_is_stable() -> Result<()> {
+    let mut td = TestDir::new()?;
+
+    let md = mk_valid_md(&mut td)?;
+    let output = run_ok_raw(thin_dump_cmd(args!["--canonical", &md]))?;
+    let output2 = run_ok_raw(thin_dump_cmd(args!["--canonical", &md]))?;
+    assert_eq!(output.stdout, output2.stdout);
+
+    // Restoring lays the btrees out differently, but the mappings
+    // are the same.
+    let xml = td.mk_path("meta.xml");
+    let mut file = OpenOptions::new()
+        .read(false)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&xml)?;
+    file.write_all(&output.stdout[0..])?;
+    drop(file);
+
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md2]))?;
+
+    let output3 = run_ok_raw(thin_dump_cmd(args!["--canonical", &md2]))?;
+    assert_eq!(output.stdout, output3.stdout);
+
+    Ok(())
+}
+
+//------------------------------------------
+// test the index sidecar gives the same dump, and is rebuilt when stale
+
+// Index files are only supported by the rust tools.
+#[test]
+fn index_matches_plain_dump() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    let index = td.mk_path("meta.idx");
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    let plain = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    let built = run_ok(rust_cmd("thin_dump", args!["--index", &index, &md]))?;
+    let reused = run_ok(rust_cmd("thin_dump", args!["--index", &index, &md]))?;
+    assert_eq!(plain, built);
+    assert_eq!(plain, reused);
+
+    // new mappings make the index stale
+    let xml2 = td.mk_path("meta2.xml");
+    write_xml(&xml2, &mut SingleThinS::new(512, 1024, 2048, 2048))?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml2, "-o", &md]))?;
+    let plain2 = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    assert_ne!(plain, plain2);
+    let reused2 = run_ok(rust_cmd("thin_dump", args!["--index", &index, &md]))?;
+    assert_eq!(plain2, reused2);
+
+    // and a damaged index is ignored
+    std::fs::write(&index, b"garbage")?;
+    let rebuilt = run_ok(rust_cmd("thin_dump", args!["--index", &index, &md]))?;
+    assert_eq!(plain2, rebuilt);
+    Ok(())
+}
+
+// The index is shared with thin_ls, which adds the exclusive counts
+// without the layout.  thin_dump fills in the layout, keeping them.
+#[test]
+fn index_records_device_roots_and_keeps_exclusives() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    let index = td.mk_path("meta.idx");
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    let plain = run_ok(rust_cmd("thin_dump", args![&md]))?;
+
+    let engine = Arc::new(SyncIoEngine::new(&md, 1, false)?);
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots: BTreeMap<u32, u64> =
+        btree_to_map::<u64>(&mut vec![0], engine, true, sb.mapping_root)?
+            .into_iter()
+            .map(|(thin_id, root)| (thin_id as u32, root))
+            .collect();
+
+    // a listing's index, with exclusives but no layout
+    run_ok(rust_cmd("thin_dump", args!["--index", &index, &md]))?;
+    let mut listed = read_index(&index)?;
+    assert!(listed.layout);
+    assert_eq!(listed.roots, roots);
+    listed.layout = false;
+    listed.metadata.defs.clear();
+    for dev in &mut listed.metadata.devs {
+        dev.map.entries.clear();
+    }
+    let exclusives: BTreeMap<u32, u64> = roots.keys().map(|thin_id| (*thin_id, 7)).collect();
+    listed.exclusives = Some(exclusives.clone());
+    write_index(&index, &listed)?;
+
+    let dumped = run_ok(rust_cmd("thin_dump", args!["--index", &index, &md]))?;
+    assert_eq!(plain, dumped);
+    let indexed = read_index(&index)?;
+    assert!(indexed.layout);
+    assert_eq!(indexed.roots, roots);
+    assert_eq!(indexed.exclusives, Some(exclusives));
+    Ok(())
+}
+
+//------------------------------------------
+// test the human readable summary
+
+#[test]
+fn human_summary() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(
+        &xml,
+        r#"<superblock uuid="" time="3" transaction="4" version="2" data_block_size="128" nr_data_blocks="100000">
+  <device dev_id="0" mapped_blocks="1000" transaction="0" creation_time="0" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="1000" time="0"/>
+  </device>
+  <device dev_id="1" mapped_blocks="600" transaction="0" creation_time="1" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="500" time="0"/>
+    <range_mapping origin_begin="500" data_begin="2000" length="100" time="1"/>
+  </device>
+</superblock>
+"#,
+    )?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    let stdout = run_ok(rust_cmd("thin_dump", args!["--format", "human", &md]))?;
+    assert!(stdout.contains("data block size:    128 sectors (64.0KiB)"));
+    assert!(stdout.contains("in use:             1100 (68.8MiB, 1.1%)"));
+    assert!(stdout.contains("devices:            2"));
+    let devs: Vec<Vec<&str>> = stdout
+        .lines()
+        .skip_while(|l| l.split_whitespace().next() != Some("dev"))
+        .skip(1)
+        .map(|l| l.split_whitespace().collect())
+        .collect();
+    assert_eq!(
+        devs,
+        vec![
+            vec!["0", "1000", "62.5MiB", "1.0%", "50.0%", "0", "1"],
+            vec!["1", "600", "37.5MiB", "0.6%", "83.3%", "1", "1"],
+        ]
+    );
+    Ok(())
+}
+
+//------------------------------------------
+// test runs are split, and marked, where their data blocks become shared
+
+#[test]
+fn annotate_shared() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(
+        &xml,
+        r#"<superblock uuid="" time="1" transaction="1" version="2" data_block_size="128" nr_data_blocks="1000">
+  <device dev_id="0" mapped_blocks="10" transaction="0" creation_time="0" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="10" time="0"/>
+  </device>
+  <device dev_id="1" mapped_blocks="8" transaction="0" creation_time="1" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="5" time="0"/>
+    <range_mapping origin_begin="5" data_begin="100" length="3" time="1"/>
+  </device>
+</superblock>
+"#,
+    )?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    let stdout = run_ok(rust_cmd("thin_dump", args!["--annotate-shared", &md]))?;
+    let maps: Vec<&str> = stdout
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| l.starts_with("<range_mapping"))
+        .collect();
+    assert_eq!(
+        maps,
+        vec![
+            r#"<range_mapping origin_begin="0" data_begin="0" length="5" time="0" shared="true"/>"#,
+            r#"<range_mapping origin_begin="5" data_begin="5" length="5" time="0" shared="false"/>"#,
+            r#"<range_mapping origin_begin="0" data_begin="0" length="5" time="0" shared="true"/>"#,
+            r#"<range_mapping origin_begin="5" data_begin="100" length="3" time="1" shared="false"/>"#,
+        ]
+    );
+
+    // the annotations are accepted, and ignored, when restoring
+    let annotated = td.mk_path("annotated.xml");
+    std::fs::write(&annotated, &stdout)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd(
+        "thin_restore",
+        args!["-i", &annotated, "-o", &md2],
+    ))?;
+    let plain = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    let plain2 = run_ok(rust_cmd("thin_dump", args![&md2]))?;
+    assert_eq!(plain, plain2);
+    Ok(())
+}
+
+//------------------------------------------
+// test the coalescing of runs into ranges can be tuned
+
+fn mapping_tags(xml: &str) -> Vec<&str> {
+    xml.lines()
+        .filter_map(|l| l.split_whitespace().next())
+        .filter(|t| t.ends_with("_mapping"))
+        .collect()
+}
+
+#[test]
+fn coalescing_is_configurable() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(
+        &xml,
+        r#"<superblock uuid="" time="1" transaction="1" version="2" data_block_size="128" nr_data_blocks="1000">
+  <device dev_id="0" mapped_blocks="6" transaction="0" creation_time="0" snap_time="0">
+    <range_mapping origin_begin="0" data_begin="0" length="3" time="0"/>
+    <range_mapping origin_begin="10" data_begin="10" length="2" time="0"/>
+    <single_mapping origin_block="20" data_block="20" time="0"/>
+  </device>
+</superblock>
+"#,
+    )?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    let default = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    assert_eq!(
+        mapping_tags(&default),
+        vec!["<range_mapping", "<range_mapping", "<single_mapping"]
+    );
+
+    let min3 = run_ok(rust_cmd("thin_dump", args!["--min-range", "3", &md]))?;
+    assert_eq!(
+        mapping_tags(&min3),
+        vec![
+            "<range_mapping",
+            "<single_mapping",
+            "<single_mapping",
+            "<single_mapping"
+        ]
+    );
+
+    let none = run_ok(rust_cmd("thin_dump", args!["--no-coalesce", &md]))?;
+    assert_eq!(mapping_tags(&none), vec!["<single_mapping"; 6]);
+
+    // however it's written, the same mappings are restored
+    let uncoalesced = td.mk_path("uncoalesced.xml");
+    std::fs::write(&uncoalesced, &none)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd(
+        "thin_restore",
+        args!["-i", &uncoalesced, "-o", &md2],
+    ))?;
+    assert_eq!(default, run_ok(rust_cmd("thin_dump", args![&md2]))?);
+    Ok(())
+}
+
+#[test]
+fn no_coalesce_conflicts_with_min_range() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_fail(rust_cmd(
+        "thin_dump",
+        args!["--no-coalesce", "--min-range", "3", &md],
+    ))?;
+    Ok(())
+}
+
+//------------------------------------------
+// test skipping the mappings never reads the mapping trees
+
+#[test]
+fn skip_mappings_ignores_damaged_mapping_tree() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(
+        &xml,
+        r#"<superblock uuid="" time="1" transaction="1" version="2" data_block_size="128" nr_data_blocks="1000">
+  <device dev_id="3" mapped_blocks="10" transaction="0" creation_time="0" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="10" time="0"/>
+  </device>
+  <device dev_id="7" mapped_blocks="5" transaction="1" creation_time="1" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="5" time="0"/>
+  </device>
+</superblock>
+"#,
+    )?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    {
+        let engine = SyncIoEngine::new(&md, 1, true)?;
+        let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+        engine.write(&Block::zeroed(sb.mapping_root))?;
+    }
+    run_fail(rust_cmd("thin_dump", args![&md]))?;
+
+    let stdout = run_ok(rust_cmd("thin_dump", args!["--skip-mappings", &md]))?;
+    let devs: Vec<&str> = stdout
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| l.starts_with("<device"))
+        .collect();
+    assert_eq!(
+        devs,
+        vec![
+            r#"<device dev_id="3" mapped_blocks="10" transaction="0" creation_time="0" snap_time="1">"#,
+            r#"<device dev_id="7" mapped_blocks="5" transaction="1" creation_time="1" snap_time="1">"#,
+        ]
+    );
+    assert!(!stdout.contains("_mapping"));
+    Ok(())
+}
+
+//------------------------------------------
+// test devices walked in parallel are still dumped in order
+
+#[test]
+fn many_snapshots_dump_in_order() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    write_xml(&xml, &mut SharedSnapsS::new(1024, 64, 5, test_seed())?)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    let output = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    let ids: Vec<u32> = output
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("<device dev_id=\""))
+        .map(|l| l.split('"').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(ids, (0..65).collect::<Vec<u32>>());
+
+    // the mappings survive a dump and restore
+    let xml2 = td.mk_path("meta2.xml");
+    std::fs::write(&xml2, &output)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml2, "-o", &md2]))?;
+    let canonical = run_ok(rust_cmd("thin_dump", args!["--canonical", &md]))?;
+    let canonical2 = run_ok(rust_cmd("thin_dump", args!["--canonical", &md2]))?;
+    assert_eq!(canonical, canonical2);
+    Ok(())
+}
+
 //------------------------------------------
 // test no stderr with a normal dump
 
@@ -140,6 +523,21 @@ fn no_stderr() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn warns_of_unknown_incompat_features() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    set_incompat_flags(&md, 0x8)?;
+
+    let output = run_ok_raw(thin_dump_cmd(args![&md]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("incompatible feature flags 0x8"));
+    assert!(!output.stdout.is_empty());
+    Ok(())
+}
+
 //------------------------------------------
 // test superblock overriding & repair
 // TODO: share with thin_repair
@@ -242,3 +640,28 @@ fn missing_nr_data_blocks() -> Result<()> {
 }
 
 //------------------------------------------
+// test the config file
+
+#[test]
+fn missing_config_file() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    let config = td.mk_path("missing.conf");
+    let stderr = run_fail(thin_dump_cmd(args!["--config", &config, &md]))?;
+    assert!(stderr.contains("couldn't read config file"));
+    Ok(())
+}
+
+#[test]
+fn bad_config_file() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    let config = td.mk_path("bad.conf");
+    std::fs::write(&config, "# defaults\nio_engine = carrier-pigeon\n")?;
+    let stderr = run_fail(thin_dump_cmd(args!["--config", &config, &md]))?;
+    assert!(stderr.contains("line 2"));
+    assert!(stderr.contains("unknown io engine"));
+    Ok(())
+}
+
+//------------------------------------------
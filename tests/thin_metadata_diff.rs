@@ -0,0 +1,156 @@
+use anyhow::Result;
+use std::path::PathBuf;
+
+mod common;
+
+use common::common_args::*;
+use common::fixture::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+const USAGE: &str = "thin_metadata_diff 0.9.0
+Compare two copies of thin metadata, and list everything that differs
+
+USAGE:
+    thin_metadata_diff [FLAGS] [OPTIONS] <LEFT> <RIGHT>
+
+FLAGS:
+    -q, --quiet      Suppress output messages, return only exit code.
+    -v, --verbose    Increase the verbosity of output messages, may be repeated
+    -h, --help       Prints help information
+    -V, --version    Prints version information
+
+OPTIONS:
+        --config <FILE>        Read default options from this file instead of the system wide one
+        --log-file <FILE>      Append full diagnostics to this file
+        --max-memory <SIZE>    Limit memory use, in MiB unless a unit is given
+
+ARGS:
+    <LEFT>     Specify the first metadata device, file or xml dump
+    <RIGHT>    Specify the second metadata device, file or xml dump";
+
+//------------------------------------------
+
+struct ThinMetadataDiff;
+
+impl<'a> Program<'a> for ThinMetadataDiff {
+    fn name() -> &'a str {
+        "thin_metadata_diff"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        thin_metadata_diff_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::InputArg
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+//------------------------------------------
+
+test_accepts_help!(ThinMetadataDiff);
+test_accepts_version!(ThinMetadataDiff);
+test_rejects_bad_option!(ThinMetadataDiff);
+
+//------------------------------------------
+
+fn mk_xml(td: &mut TestDir, name: &str, transaction: u64, dev1: &str) -> Result<PathBuf> {
+    let xml = td.mk_path(name);
+    std::fs::write(
+        &xml,
+        format!(
+            r#"<superblock uuid="" time="1" transaction="{}" data_block_size="128" nr_data_blocks="1000">
+  <device dev_id="1" mapped_blocks="20" transaction="0" creation_time="0" snap_time="0">
+    {}
+  </device>
+  <device dev_id="2" mapped_blocks="10" transaction="0" creation_time="0" snap_time="0">
+    <range_mapping origin_begin="0" data_begin="500" length="10" time="0"/>
+  </device>
+</superblock>
+"#,
+            transaction, dev1
+        ),
+    )?;
+    Ok(xml)
+}
+
+const DEV1: &str = r#"<range_mapping origin_begin="0" data_begin="0" length="20" time="0"/>"#;
+
+#[test]
+fn binary_matches_its_xml() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_xml(&mut td, "meta.xml", 1, DEV1)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    let stdout = run_ok(thin_metadata_diff_cmd(args![&xml, &md]))?;
+    assert_eq!(stdout, "");
+    Ok(())
+}
+
+#[test]
+fn lists_differences_hierarchically() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let lhs = mk_xml(&mut td, "left.xml", 1, DEV1)?;
+    let rhs = mk_xml(
+        &mut td,
+        "right.xml",
+        2,
+        r#"<range_mapping origin_begin="0" data_begin="0" length="10" time="0"/>
+    <range_mapping origin_begin="10" data_begin="100" length="10" time="0"/>"#,
+    )?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &rhs, "-o", &md]))?;
+
+    let output = run_fail_raw(thin_metadata_diff_cmd(args![&lhs, &md]))?;
+    assert_eq!(output.status.code(), Some(6));
+    assert_eq!(
+        std::str::from_utf8(&output.stdout)?,
+        "superblock
+  transaction: 1 vs 2
+device 1
+  mappings: 10 blocks differ, in 1 runs
+    thin 10..20: data 10..20 at time 0 vs data 100..110 at time 0
+"
+    );
+    Ok(())
+}
+
+#[test]
+fn reports_missing_devices() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let lhs = mk_xml(&mut td, "left.xml", 1, DEV1)?;
+    let rhs = td.mk_path("right.xml");
+    std::fs::write(
+        &rhs,
+        std::fs::read_to_string(&lhs)?.replace(r#"dev_id="2""#, r#"dev_id="3""#),
+    )?;
+
+    let output = run_fail_raw(thin_metadata_diff_cmd(args![&lhs, &rhs]))?;
+    assert_eq!(
+        std::str::from_utf8(&output.stdout)?,
+        "device 2: only in left, 10 mapped blocks
+device 3: only in right, 10 mapped blocks
+"
+    );
+    Ok(())
+}
+
+//------------------------------------------
@@ -18,15 +18,22 @@ const USAGE: &str = concat!(
     "Produces a compressed file of thin metadata.  Only packs metadata blocks that are actually used.\n\
      \n\
      USAGE:\n    \
-         thin_metadata_pack -i <DEV> -o <FILE>\n\
+         thin_metadata_pack [FLAGS] [OPTIONS] -i <DEV> -o <FILE>\n\
      \n\
      FLAGS:\n    \
+         -q, --quiet      Suppress output messages, return only exit code.\n    \
+         -v, --verbose    Increase the verbosity of output messages, may be repeated\n    \
          -h, --help       Prints help information\n    \
          -V, --version    Prints version information\n\
      \n\
-     OPTIONS:\n    \
-         -i <DEV>         Specify thinp metadata binary device/file\n    \
-         -o <FILE>        Specify packed output file"
+     OPTIONS:\n        \
+             --config <FILE>        Read default options from this file instead of the system wide one\n        \
+             --format <FORMAT>      Specify the pack container to write [default: native]  [possible values: native, c-\n                               \
+         compat]\n    \
+         -i <DEV>                   Specify thinp metadata binary device/file\n        \
+             --log-file <FILE>      Append full diagnostics to this file\n        \
+             --max-memory <SIZE>    Limit memory use, in MiB unless a unit is given\n    \
+         -o <FILE>                  Specify packed output file"
 );
 
 //------------------------------------------
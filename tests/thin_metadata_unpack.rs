@@ -8,9 +8,11 @@ use common::input_arg::*;
 use common::output_option::*;
 use common::process::*;
 use common::program::*;
+use common::round_trip::{self, *};
 use common::target::*;
 use common::test_dir::*;
 use common::thin::*;
+use common::thin_xml_generator::ThinModel;
 
 //------------------------------------------
 
@@ -20,15 +22,20 @@ const USAGE: &str = concat!(
     "Unpack a compressed file of thin metadata.\n\
      \n\
      USAGE:\n    \
-         thin_metadata_unpack -i <DEV> -o <FILE>\n\
+         thin_metadata_unpack [FLAGS] [OPTIONS] -i <DEV> -o <FILE>\n\
      \n\
      FLAGS:\n    \
+         -q, --quiet      Suppress output messages, return only exit code.\n    \
+         -v, --verbose    Increase the verbosity of output messages, may be repeated\n    \
          -h, --help       Prints help information\n    \
          -V, --version    Prints version information\n\
      \n\
-     OPTIONS:\n    \
-         -i <DEV>         Specify thinp metadata binary device/file\n    \
-         -o <FILE>        Specify packed output file"
+     OPTIONS:\n        \
+             --config <FILE>        Read default options from this file instead of the system wide one\n    \
+         -i <DEV>                   Specify thinp metadata binary device/file\n        \
+             --log-file <FILE>      Append full diagnostics to this file\n        \
+             --max-memory <SIZE>    Limit memory use, in MiB unless a unit is given\n    \
+         -o <FILE>                  Specify packed output file"
 );
 
 //------------------------------------------
@@ -101,8 +108,7 @@ test_missing_output_option!(ThinMetadataUnpack);
 
 // TODO: share with thin_restore/cache_restore/era_restore
 
-#[test]
-fn end_to_end() -> Result<()> {
+fn pack_unpack(format: &str) -> Result<()> {
     let mut td = TestDir::new()?;
     let md_in = mk_valid_md(&mut td)?;
     let md_out = mk_zeroed_md(&mut td)?;
@@ -110,7 +116,9 @@ fn end_to_end() -> Result<()> {
         "-i",
         &md_in,
         "-o",
-        "meta.pack"
+        "meta.pack",
+        "--format",
+        format
     ]))?;
     run_ok(thin_metadata_unpack_cmd(args![
         "-i",
@@ -125,4 +133,52 @@ fn end_to_end() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn end_to_end() -> Result<()> {
+    pack_unpack("native")
+}
+
+#[test]
+fn end_to_end_c_compat() -> Result<()> {
+    pack_unpack("c-compat")
+}
+
+#[test]
+fn pack_unpack_random_metadata() {
+    round_trip::quickcheck(10, |m: ThinModel| {
+        to_test_result(check_pack_unpack(
+            &m,
+            |xml, md| thin_restore_cmd(args!["-i", xml, "-o", md]),
+            |md| thin_dump_cmd(args!["--canonical", md]),
+            |md, pack| thin_metadata_pack_cmd(args!["-i", md, "-o", pack]),
+            |pack, md| thin_metadata_unpack_cmd(args!["-i", pack, "-o", md]),
+        ))
+    });
+}
+
+// Blocks that aren't in the pack are left as holes in a regular file.
+#[test]
+fn output_file_is_sparse() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md_in = td.mk_path("meta.bin");
+    std::fs::File::create(&md_in)?.set_len(16 << 20)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md_in]))?;
+
+    let packed = td.mk_path("meta.pack");
+    let md_out = td.mk_path("meta.out");
+    run_ok(thin_metadata_pack_cmd(args!["-i", &md_in, "-o", &packed]))?;
+    run_ok(thin_metadata_unpack_cmd(args![
+        "-i", &packed, "-o", &md_out
+    ]))?;
+
+    let md_meta = std::fs::metadata(&md_out)?;
+    assert_eq!(md_meta.len(), 16 << 20);
+    assert!(md_meta.blocks() * 512 < 1 << 20);
+    assert!(std::fs::read(&md_in)? == std::fs::read(&md_out)?);
+    Ok(())
+}
+
 //------------------------------------------
@@ -0,0 +1,217 @@
+use anyhow::{anyhow, Result};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Stdio};
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+use common::common_args::*;
+use common::fixture::*;
+use common::input_arg::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+use common::thin::*;
+
+//------------------------------------------
+
+const USAGE: &str = "thin_metrics 0.9.0
+Export the usage and health of thin pools as Prometheus metrics
+
+USAGE:
+    thin_metrics [FLAGS] [OPTIONS] <POOLS>...
+
+FLAGS:
+    -m, --metadata-snapshot    Read the metadata snapshot of each live pool
+    -q, --quiet                Suppress output messages, return only exit code.
+    -v, --verbose              Increase the verbosity of output messages, may be repeated
+    -h, --help                 Prints help information
+    -V, --version              Prints version information
+
+OPTIONS:
+        --config <FILE>            Read default options from this file instead of the system wide one
+        --interval <SECS>          Seconds between refreshes of the served metrics [default: 60]
+        --metrics-listen <ADDR>    Serve the metrics over http, rather than printing them once
+        --log-file <FILE>          Append full diagnostics to this file
+        --max-memory <SIZE>        Limit memory use, in MiB unless a unit is given
+
+ARGS:
+    <POOLS>...    Specify the metadata devices, each as [NAME=]DEVICE";
+
+//------------------------------------------
+
+struct ThinMetrics;
+
+impl<'a> Program<'a> for ThinMetrics {
+    fn name() -> &'a str {
+        "thin_metrics"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        thin_metrics_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::InputArg
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+impl<'a> InputProgram<'a> for ThinMetrics {
+    fn mk_valid_input(td: &mut TestDir) -> Result<std::path::PathBuf> {
+        mk_valid_md(td)
+    }
+
+    fn file_not_found() -> &'a str {
+        msg::FILE_NOT_FOUND
+    }
+
+    fn missing_input_arg() -> &'a str {
+        msg::MISSING_INPUT_ARG
+    }
+
+    fn corrupted_input() -> &'a str {
+        msg::BAD_SUPERBLOCK
+    }
+}
+
+//------------------------------------------
+
+test_accepts_help!(ThinMetrics);
+test_accepts_version!(ThinMetrics);
+test_rejects_bad_option!(ThinMetrics);
+
+test_missing_input_arg!(ThinMetrics);
+test_input_file_not_found!(ThinMetrics);
+test_input_cannot_be_a_directory!(ThinMetrics);
+
+//------------------------------------------
+
+#[test]
+fn exports_pool_metrics() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(
+        &xml,
+        r#"<superblock uuid="" time="1" transaction="3" data_block_size="128" nr_data_blocks="64">
+  <device dev_id="1" mapped_blocks="2" transaction="0" creation_time="0" snap_time="0">
+    <single_mapping origin_block="0" data_block="0" time="0"/>
+    <single_mapping origin_block="1" data_block="8" time="0"/>
+  </device>
+</superblock>
+"#,
+    )?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+
+    let pool = format!("fast={}", md.display());
+    let stdout = run_ok(thin_metrics_cmd(args![&pool]))?;
+    assert!(stdout.contains("thin_pool_up{pool=\"fast\"} 1\n"));
+    assert!(stdout.contains("thin_pool_transaction_id{pool=\"fast\"} 3\n"));
+    assert!(stdout.contains("thin_pool_devices{pool=\"fast\"} 1\n"));
+    assert!(stdout.contains("thin_pool_free_blocks{pool=\"fast\",space_map=\"data\"} 62\n"));
+    assert!(stdout.contains("thin_pool_free_runs{pool=\"fast\",space_map=\"data\"} 2\n"));
+    assert!(stdout.contains("thin_device_mapped_blocks{pool=\"fast\",dev_id=\"1\"} 2\n"));
+    Ok(())
+}
+
+#[test]
+fn unreadable_pool_is_down() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    let pool = format!("broken={}", md.display());
+    let stdout = run_ok(thin_metrics_cmd(args![&pool]))?;
+    assert_eq!(
+        stdout,
+        "# HELP thin_pool_up Whether the pool metadata could be read.\n\
+         # TYPE thin_pool_up gauge\n\
+         thin_pool_up{pool=\"broken\"} 0"
+    );
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_pool() -> Result<()> {
+    let stderr = run_fail(thin_metrics_cmd(args!["fast="]))?;
+    assert!(stderr.contains("expected [NAME=]DEVICE"));
+    Ok(())
+}
+
+#[test]
+fn rejects_zero_interval() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_fail(thin_metrics_cmd(args![&md, "--interval", "0"]))?;
+    Ok(())
+}
+
+//------------------------------------------
+
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_addr() -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.to_string())
+}
+
+fn get(addr: &str, path: &str) -> Result<String> {
+    for _ in 0..100 {
+        if let Ok(mut stream) = TcpStream::connect(addr) {
+            write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr)?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            return Ok(response);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Err(anyhow!("nothing listening on {}", addr))
+}
+
+#[test]
+fn serves_metrics_over_http() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    let addr = free_addr()?;
+    let _server = Server(
+        std::process::Command::new(env!("CARGO_BIN_EXE_pdata_tools"))
+            .arg("thin_metrics")
+            .arg("--metrics-listen")
+            .arg(&addr)
+            .arg(format!("broken={}", md.display()))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?,
+    );
+
+    let response = get(&addr, "/metrics")?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    assert!(response.ends_with("thin_pool_up{pool=\"broken\"} 0\n"));
+
+    let response = get(&addr, "/")?;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    Ok(())
+}
+
+//------------------------------------------
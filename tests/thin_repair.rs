@@ -1,4 +1,8 @@
 use anyhow::Result;
+use std::path::Path;
+
+use thinp::io_engine::*;
+use thinp::thin::superblock::*;
 
 mod common;
 
@@ -222,3 +226,130 @@ fn missing_nr_data_blocks() -> Result<()> {
 }
 
 //-----------------------------------------
+
+// Backup superblocks are only supported by the rust tools.
+#[test]
+fn recovers_from_backup_superblock() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md1 = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd(
+        "thin_restore",
+        args!["-i", &xml, "-o", &md1, "--backup-superblock"],
+    ))?;
+    let original = run_ok(rust_cmd("thin_dump", args![&md1]))?;
+
+    damage_superblock(&md1)?;
+    let stderr = run_fail(rust_cmd("thin_check", args![&md1]))?;
+    assert!(stderr.contains("backup superblock"));
+
+    // no overrides needed, the backup provides them
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_repair", args!["-i", &md1, "-o", &md2]))?;
+    let repaired = run_ok(rust_cmd("thin_dump", args![&md2]))?;
+    assert_eq!(original, repaired);
+    Ok(())
+}
+
+//-----------------------------------------
+
+// Zeroes the root of the device details tree.
+fn lose_details_tree(md: &Path) -> Result<()> {
+    let engine = SyncIoEngine::new(md, 1, true)?;
+    let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    engine.write(&Block::zeroed(sb.details_root))?;
+    Ok(())
+}
+
+fn mapped_blocks(dump: &str) -> Vec<&str> {
+    dump.split("mapped_blocks=\"")
+        .skip(1)
+        .map(|s| s.split('"').next().unwrap())
+        .collect()
+}
+
+#[test]
+fn synthesises_lost_device_details() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md1 = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md1]))?;
+    let original = run_ok(rust_cmd("thin_dump", args![&md1]))?;
+
+    lose_details_tree(&md1)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let output = run_ok_raw(rust_cmd("thin_repair", args!["-i", &md1, "-o", &md2]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("device details were lost"));
+    assert!(stderr.contains("counted from the mappings"));
+
+    run_ok(rust_cmd("thin_check", args![&md2]))?;
+    let repaired = run_ok(rust_cmd("thin_dump", args![&md2]))?;
+    assert_eq!(mapped_blocks(&original), mapped_blocks(&repaired));
+    Ok(())
+}
+
+//-----------------------------------------
+
+#[test]
+fn interactive_repair_asks_before_writing() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md1 = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md1]))?;
+    let original = run_ok(rust_cmd("thin_dump", args![&md1]))?;
+
+    let md2 = mk_zeroed_md(&mut td)?;
+    let output = run_fail_raw(
+        rust_cmd(
+            "thin_repair",
+            args!["--interactive", "-i", &md1, "-o", &md2],
+        )
+        .with_stdin("n\n"),
+    )?;
+    let stdout = std::str::from_utf8(&output.stdout)?;
+    assert!(stdout.contains("The superblock is intact"));
+    assert!(std::str::from_utf8(&output.stderr)?.contains("nothing was written"));
+    run_fail(rust_cmd("thin_dump", args![&md2]))?;
+
+    run_ok(
+        rust_cmd(
+            "thin_repair",
+            args!["--interactive", "-i", &md1, "-o", &md2],
+        )
+        .with_stdin("y\n"),
+    )?;
+    let repaired = run_ok(rust_cmd("thin_dump", args![&md2]))?;
+    assert_eq!(original, repaired);
+    Ok(())
+}
+
+#[test]
+fn interactive_repair_rebuilds_superblock() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md1 = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md1]))?;
+    let original = run_ok(rust_cmd("thin_dump", args![&md1]))?;
+    damage_superblock(&md1)?;
+
+    // data block size, the roots, the transaction id, nr data blocks, and
+    // the go ahead.
+    let md2 = mk_zeroed_md(&mut td)?;
+    let stdout = run_ok(
+        rust_cmd(
+            "thin_repair",
+            args!["--interactive", "-i", &md1, "-o", &md2],
+        )
+        .with_stdin("128\n\n\n\ny\n"),
+    )?;
+    assert!(stdout.contains("The superblock can't be used"));
+    assert!(stdout.contains("  1) mapping root"));
+
+    let repaired = run_ok(rust_cmd("thin_dump", args![&md2]))?;
+    let devices = |dump: &str| dump.matches("<device ").count();
+    assert_eq!(devices(&original), devices(&repaired));
+    Ok(())
+}
+
+//-----------------------------------------
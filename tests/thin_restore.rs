@@ -1,4 +1,5 @@
 use anyhow::Result;
+use thinp::file_utils;
 
 mod common;
 
@@ -159,4 +160,277 @@ fn override_nr_data_blocks() -> Result<()> {
     override_something("--nr-data-blocks", "234500", "nr_data_blocks=\"234500\"")
 }
 
+// The rust thin_restore takes sizes with a unit suffix, converted with
+// the block size in the xml unless --data-block-size is given.
+const SMALL_POOL_XML: &str = r#"<superblock uuid="" time="0" transaction="1" version="2" data_block_size="128" nr_data_blocks="100">
+  <device dev_id="1" mapped_blocks="10" transaction="0" creation_time="0" snap_time="0">
+    <range_mapping origin_begin="0" data_begin="0" length="10" time="0"/>
+  </device>
+</superblock>
+"#;
+
+#[test]
+fn override_sizes_with_units() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("small.xml");
+    std::fs::write(&xml, SMALL_POOL_XML)?;
+    let md = mk_zeroed_md(&mut td)?;
+
+    let output = run_ok_raw(rust_cmd(
+        "thin_restore",
+        args!["-i", &xml, "-o", &md, "--nr-data-blocks", "1g"],
+    ))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("nr data blocks: 1g = 16384 blocks of 128 sectors"));
+    let dump = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    assert!(dump.contains("data_block_size=\"128\" nr_data_blocks=\"16384\""));
+    run_ok(rust_cmd("thin_check", args![&md]))?;
+
+    run_ok(rust_cmd(
+        "thin_restore",
+        args![
+            "-i",
+            &xml,
+            "-o",
+            &md,
+            "--data-block-size",
+            "128k",
+            "--nr-data-blocks",
+            "1g",
+            "--verify"
+        ],
+    ))?;
+    let dump = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    assert!(dump.contains("data_block_size=\"256\" nr_data_blocks=\"8192\""));
+    Ok(())
+}
+
+#[test]
+fn override_nr_data_blocks_too_small() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("small.xml");
+    std::fs::write(&xml, SMALL_POOL_XML)?;
+    let md = mk_zeroed_md(&mut td)?;
+
+    let stderr = run_fail(rust_cmd(
+        "thin_restore",
+        args!["-i", &xml, "-o", &md, "--nr-data-blocks", "5"],
+    ))?;
+    assert!(stderr.contains("past the end of the pool (5 blocks)"));
+    Ok(())
+}
+
+//-----------------------------------------
+
+// --verify is only supported by the rust thin_restore.
+#[test]
+fn verify_restored_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+
+    run_ok(rust_cmd(
+        "thin_restore",
+        args!["-i", &xml, "-o", &md, "--verify"],
+    ))?;
+    Ok(())
+}
+
+//-----------------------------------------
+
+// The rust tools lock the metadata while they use it.
+#[test]
+fn refuses_metadata_in_use() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+
+    let lock = file_utils::lock_file(&md, false)?;
+    let stderr = run_fail(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    assert!(stderr.contains("in use by another tool"));
+    drop(lock);
+
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    Ok(())
+}
+
+// A restore that fails, whether because another tool has the metadata
+// or because the xml is bad, leaves the output as it was.
+#[test]
+fn failed_restore_leaves_output_unchanged() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = td.mk_path("meta.bin");
+    let contents = vec![0xff; 4 << 20];
+    std::fs::write(&md, &contents)?;
+
+    let lock = file_utils::lock_file(&md, false)?;
+    let stderr = run_fail(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    assert!(stderr.contains("in use by another tool"));
+    drop(lock);
+    assert!(std::fs::read(&md)? == contents);
+
+    let bad_xml = td.mk_path("bad.xml");
+    std::fs::write(&bad_xml, "<superblock uuid=\"\" hedgehog")?;
+    run_fail(rust_cmd("thin_restore", args!["-i", &bad_xml, "-o", &md]))?;
+    assert!(std::fs::read(&md)? == contents);
+    Ok(())
+}
+
+//-----------------------------------------
+
+// Only the blocks the restore writes take up space in a regular file,
+// whatever it held before.
+#[test]
+fn output_file_is_sparse() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = td.mk_path("meta.bin");
+    std::fs::write(&md, vec![0xff; 16 << 20])?;
+
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    let md_meta = std::fs::metadata(&md)?;
+    assert_eq!(md_meta.len(), 16 << 20);
+    assert!(md_meta.blocks() * 512 < 1 << 20);
+
+    run_ok(rust_cmd("thin_check", args![&md]))?;
+    Ok(())
+}
+
+//-----------------------------------------
+
+// --validate-only checks the xml without needing an output.
+#[test]
+fn validate_only_accepts_a_valid_dump() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+
+    let output = run_ok_raw(rust_cmd(
+        "thin_restore",
+        args!["--validate-only", "-i", &xml],
+    ))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("The dump is consistent"));
+    Ok(())
+}
+
+#[test]
+fn validate_only_reports_inconsistencies() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("bad.xml");
+    std::fs::write(
+        &xml,
+        r#"<superblock uuid="" time="0" transaction="1" version="2" data_block_size="128" nr_data_blocks="100">
+  <device dev_id="1" mapped_blocks="20" transaction="0" creation_time="0" snap_time="0">
+    <range_mapping origin_begin="0" data_begin="0" length="10" time="0"/>
+    <range_mapping origin_begin="5" data_begin="10" length="10" time="0"/>
+  </device>
+  <device dev_id="1" mapped_blocks="10" transaction="0" creation_time="0" snap_time="0">
+    <range_mapping origin_begin="0" data_begin="95" length="10" time="0"/>
+  </device>
+</superblock>
+"#,
+    )?;
+
+    let stderr = run_fail(rust_cmd(
+        "thin_restore",
+        args!["--validate-only", "-i", &xml],
+    ))?;
+    assert!(stderr.contains("device 1: 1 mappings overlap"));
+    assert!(stderr.contains("device 1 is listed more than once"));
+    assert!(stderr.contains("5 mapped data blocks are past the end of the pool"));
+    assert!(stderr.contains("3 problems found in the dump"));
+    Ok(())
+}
+
+#[test]
+fn validate_only_conflicts_with_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+
+    run_fail(rust_cmd(
+        "thin_restore",
+        args!["--validate-only", "-i", &xml, "-o", &md],
+    ))?;
+    Ok(())
+}
+
+//-----------------------------------------
+
+// Device labels are kept in the metadata, and dumped again.
+const LABELLED_XML: &str = r#"<superblock uuid="" time="0" transaction="1" version="3" data_block_size="128" nr_data_blocks="100">
+  <device dev_id="1" mapped_blocks="10" transaction="0" creation_time="0" snap_time="0">
+    <labels label="db &quot;main&quot;" uuid="5c1e-77">
+      <attribute key="owner" value="k8s &amp; friends"/>
+    </labels>
+    <range_mapping origin_begin="0" data_begin="0" length="10" time="0"/>
+  </device>
+  <device dev_id="2" mapped_blocks="0" transaction="0" creation_time="0" snap_time="0">
+  </device>
+</superblock>
+"#;
+
+#[test]
+fn device_labels_round_trip() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("labelled.xml");
+    std::fs::write(&xml, LABELLED_XML)?;
+    let md = mk_zeroed_md(&mut td)?;
+
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    run_ok(rust_cmd("thin_check", args![&md]))?;
+
+    let dump = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    assert!(dump.contains(r#"version="3""#));
+    assert!(dump.contains(r#"<labels label="db &quot;main&quot;" uuid="5c1e-77">"#));
+    assert!(dump.contains(r#"<attribute key="owner" value="k8s &amp; friends"/>"#));
+    assert_eq!(dump.matches("<labels").count(), 1);
+
+    let human = run_ok(rust_cmd("thin_dump", args!["--format", "human", &md]))?;
+    assert!(human.contains("device 1: db \"main\""));
+    assert!(human.contains("owner = k8s & friends"));
+    Ok(())
+}
+
+#[test]
+fn stale_device_labels_are_cleared() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let labelled = td.mk_path("labelled.xml");
+    std::fs::write(&labelled, LABELLED_XML)?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+
+    run_ok(rust_cmd("thin_restore", args!["-i", &labelled, "-o", &md]))?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+    run_ok(rust_cmd("thin_check", args![&md]))?;
+
+    let dump = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    assert!(dump.contains(r#"version="2""#));
+    assert!(!dump.contains("<labels"));
+    Ok(())
+}
+
+// The superblock points at the labels, so they're still found, and
+// accounted for, once the metadata device has grown.
+#[test]
+fn device_labels_survive_growing_the_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("labelled.xml");
+    std::fs::write(&xml, LABELLED_XML)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    let f = std::fs::OpenOptions::new().write(true).open(&md)?;
+    f.set_len(f.metadata()?.len() * 2)?;
+    drop(f);
+
+    run_ok(rust_cmd("thin_check", args![&md]))?;
+    let dump = run_ok(rust_cmd("thin_dump", args![&md]))?;
+    assert!(dump.contains(r#"<labels label="db &quot;main&quot;" uuid="5c1e-77">"#));
+    Ok(())
+}
+
 //-----------------------------------------
@@ -3,6 +3,7 @@ use anyhow::Result;
 mod common;
 
 use common::common_args::*;
+use common::fixture::*;
 use common::process::*;
 use common::program::*;
 use common::target::*;
@@ -15,6 +16,7 @@ const USAGE: &str = "Usage: thin_rmap [options] {device|file}\n\
                      Options:\n  \
                        {-h|--help}\n  \
                        {-V|--version}\n  \
+                       {-f|--format} {text|xml}\n  \
                        {--region <block range>}*\n\
                      Where:\n  \
                        <block range> is of the form <begin>..<one-past-the-end>\n  \
@@ -104,4 +106,50 @@ fn junk_input() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn xml_format_writes_mapping_elements() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("meta.xml");
+    std::fs::write(
+        &xml,
+        r#"<superblock uuid="" time="1" transaction="1" version="2" data_block_size="128" nr_data_blocks="1000">
+  <device dev_id="0" mapped_blocks="8" transaction="0" creation_time="0" snap_time="1">
+    <range_mapping origin_begin="100" data_begin="0" length="4" time="0"/>
+    <range_mapping origin_begin="104" data_begin="4" length="4" time="1"/>
+  </device>
+  <device dev_id="1" mapped_blocks="1" transaction="0" creation_time="1" snap_time="1">
+    <single_mapping origin_block="7" data_block="20" time="1"/>
+  </device>
+</superblock>
+"#,
+    )?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_restore", args!["-i", &xml, "-o", &md]))?;
+
+    let stdout = run_ok(thin_rmap_cmd(args![
+        "--format", "xml", "--region", "2..6", "--region", "20..21", &md
+    ]))?;
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        vec![
+            "<!-- device 0 -->",
+            r#"<range_mapping origin_begin="102" data_begin="2" length="2" time="0"/>"#,
+            r#"<range_mapping origin_begin="104" data_begin="4" length="2" time="1"/>"#,
+            "<!-- device 1 -->",
+            r#"<single_mapping origin_block="7" data_block="20" time="1"/>"#,
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn unknown_format_should_fail() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_fail(thin_rmap_cmd(args![
+        "--format", "yaml", "--region", "1..23", &md
+    ]))?;
+    Ok(())
+}
+
 //------------------------------------------
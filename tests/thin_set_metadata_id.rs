@@ -0,0 +1,195 @@
+use anyhow::Result;
+
+mod common;
+
+use common::common_args::*;
+use common::fixture::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+use common::thin::*;
+
+//------------------------------------------
+
+const USAGE: &str = "thin_set_metadata_id 0.9.0
+Change the uuid or transaction id of thin metadata, eg, after cloning a pool
+
+USAGE:
+    thin_set_metadata_id [FLAGS] [OPTIONS] <INPUT>
+
+FLAGS:
+        --bump-transaction-id    Increment the transaction id
+        --new-uuid               Generate a new, random, uuid
+    -q, --quiet                  Suppress output messages, return only exit code.
+    -v, --verbose                Increase the verbosity of output messages, may be repeated
+    -h, --help                   Prints help information
+    -V, --version                Prints version information
+
+OPTIONS:
+        --config <FILE>           Read default options from this file instead of the system wide one
+        --log-file <FILE>         Append full diagnostics to this file
+        --max-memory <SIZE>       Limit memory use, in MiB unless a unit is given
+        --transaction-id <NUM>    Set the transaction id
+        --uuid <UUID>             Set the uuid, or clear it if given ''
+
+ARGS:
+    <INPUT>    Specify the input device to change";
+
+//------------------------------------------
+
+struct ThinSetMetadataId;
+
+impl<'a> Program<'a> for ThinSetMetadataId {
+    fn name() -> &'a str {
+        "thin_set_metadata_id"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        thin_set_metadata_id_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::InputArg
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+//------------------------------------------
+
+test_accepts_help!(ThinSetMetadataId);
+test_accepts_version!(ThinSetMetadataId);
+test_rejects_bad_option!(ThinSetMetadataId);
+
+//------------------------------------------
+
+fn mk_md(td: &mut TestDir) -> Result<std::path::PathBuf> {
+    let xml = mk_valid_xml(td)?;
+    let md = mk_zeroed_md(td)?;
+    run_ok(rust_cmd(
+        "thin_restore",
+        args!["-i", &xml, "-o", &md, "--backup-superblock"],
+    ))?;
+    Ok(md)
+}
+
+fn dump_superblock(md: &std::path::Path) -> Result<String> {
+    let dump = run_ok(rust_cmd("thin_dump", args![md]))?;
+    Ok(dump.lines().next().unwrap().to_string())
+}
+
+#[test]
+fn sets_uuid_and_transaction_id() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_md(&mut td)?;
+
+    let uuid = "0123abcd-4567-89ef-0123-456789abcdef";
+    let stdout = run_ok(thin_set_metadata_id_cmd(args![
+        &md,
+        "--uuid",
+        uuid,
+        "--transaction-id",
+        "42"
+    ]))?;
+    assert!(stdout.contains(&format!("uuid: unset -> {}", uuid)));
+
+    let sb = dump_superblock(&md)?;
+    assert!(sb.contains(&format!("uuid=\"{}\"", uuid)));
+    assert!(sb.contains("transaction=\"42\""));
+    run_ok(rust_cmd("thin_check", args![&md]))?;
+    Ok(())
+}
+
+#[test]
+fn bumps_transaction_id() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_md(&mut td)?;
+    run_ok(thin_set_metadata_id_cmd(args![
+        &md,
+        "--transaction-id",
+        "7"
+    ]))?;
+    let stdout = run_ok(thin_set_metadata_id_cmd(args![
+        &md,
+        "--bump-transaction-id"
+    ]))?;
+    assert!(stdout.contains("transaction id: 7 -> 8"));
+    assert!(dump_superblock(&md)?.contains("transaction=\"8\""));
+    Ok(())
+}
+
+#[test]
+fn new_uuid_is_random() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_md(&mut td)?;
+    run_ok(thin_set_metadata_id_cmd(args![&md, "--new-uuid"]))?;
+    let first = dump_superblock(&md)?;
+    run_ok(thin_set_metadata_id_cmd(args![&md, "--new-uuid"]))?;
+    let second = dump_superblock(&md)?;
+    assert!(!first.contains("uuid=\"\""));
+    assert_ne!(first, second);
+    Ok(())
+}
+
+#[test]
+fn backup_superblock_keeps_new_identity() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_md(&mut td)?;
+    let uuid = "0123abcd-4567-89ef-0123-456789abcdef";
+    run_ok(thin_set_metadata_id_cmd(args![&md, "--uuid", uuid]))?;
+
+    damage_superblock(&md)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(rust_cmd("thin_repair", args!["-i", &md, "-o", &md2]))?;
+    assert!(dump_superblock(&md2)?.contains(&format!("uuid=\"{}\"", uuid)));
+    Ok(())
+}
+
+#[test]
+fn needs_a_change() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_md(&mut td)?;
+    run_fail(thin_set_metadata_id_cmd(args![&md]))?;
+    Ok(())
+}
+
+#[test]
+fn one_uuid_change_at_a_time() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_md(&mut td)?;
+    run_fail(thin_set_metadata_id_cmd(args![
+        &md,
+        "--new-uuid",
+        "--uuid",
+        "0123abcd-4567-89ef-0123-456789abcdef"
+    ]))?;
+    run_fail(thin_set_metadata_id_cmd(args![
+        &md,
+        "--bump-transaction-id",
+        "--transaction-id",
+        "2"
+    ]))?;
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_uuid() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_md(&mut td)?;
+    let stderr = run_fail(thin_set_metadata_id_cmd(args![&md, "--uuid", "0123"]))?;
+    assert!(stderr.contains("bad uuid"));
+    Ok(())
+}
+
+//------------------------------------------
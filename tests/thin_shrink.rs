@@ -1,17 +1,24 @@
 use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use rand::prelude::*;
-use std::fs::OpenOptions;
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::sync::Arc;
 
 use thinp::file_utils;
+use thinp::report::mk_quiet_report;
+use thinp::shrink::policy::mk_policy;
+use thinp::shrink::toplevel::{plan, shrink, ThinShrinkOptions, ThinShrinkPlanOptions};
 use thinp::thin::ir::{self, MetadataVisitor, Visit};
 use thinp::thin::xml;
 
 mod common;
+use common::random::test_seed;
 use common::test_dir::*;
-use common::thin_xml_generator::{write_xml, EmptyPoolS, FragmentedS, SingleThinS, SnapS, XmlGen};
+use common::thin_xml_generator::{
+    write_xml, EmptyPoolS, FragmentedS, ManyThinsS, PathologicalFragS, SingleThinS, SnapS, XmlGen,
+};
 
 //------------------------------------
 
@@ -119,6 +126,10 @@ impl<'a, V: ThinVisitor> MetadataVisitor for ThinXmlVisitor<'a, V> {
         Ok(Visit::Continue)
     }
 
+    fn device_labels(&mut self, _labels: &ir::DeviceLabels) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
     fn map(&mut self, m: &ir::Map) -> Result<Visit> {
         for i in 0..m.len {
             let block = ThinBlock {
@@ -303,6 +314,13 @@ trait Scenario {
 }
 
 fn test_shrink<S>(scenario: &mut S) -> Result<()>
+where
+    S: Scenario + XmlGen,
+{
+    test_shrink_with(scenario, "first-fit", None)
+}
+
+fn test_shrink_with<S>(scenario: &mut S, policy: &str, throttle_mbps: Option<u64>) -> Result<()>
 where
     S: Scenario + XmlGen,
 {
@@ -314,14 +332,24 @@ where
     write_xml(&xml_before, scenario)?;
     create_data_file(&data_path, &xml_before)?;
 
-    let mut rng = rand::thread_rng();
-    let seed = rng.gen::<u64>();
+    let seed = test_seed();
 
     stamp(&xml_before, &data_path, seed)?;
     verify(&xml_before, &data_path, seed)?;
 
     let new_nr_blocks = scenario.get_new_nr_blocks();
-    thinp::shrink::toplevel::shrink(&xml_before, &xml_after, &data_path, new_nr_blocks, true)?;
+    let opts = ThinShrinkOptions {
+        input: &xml_before,
+        output: &xml_after,
+        data_device: &data_path,
+        nr_blocks: new_nr_blocks,
+        do_copy: true,
+        verify: true,
+        throttle_mbps,
+        policy: mk_policy(policy)?,
+        report: Arc::new(mk_quiet_report()),
+    };
+    shrink(opts)?;
 
     verify(&xml_after, &data_path, seed)?;
     Ok(())
@@ -388,6 +416,36 @@ fn shrink_insufficient_space() -> Result<()> {
     }
 }
 
+#[test]
+fn shrink_data_device_too_small() -> Result<()> {
+    let mut s = SingleThinS::new(1024, 1024, 2048, 1280);
+    let mut td = TestDir::new()?;
+    let xml_before = td.mk_path("before.xml");
+    let xml_after = td.mk_path("after.xml");
+    let data_path = td.mk_path("metadata.bin");
+
+    write_xml(&xml_before, &mut s)?;
+    let sb = xml::read_superblock(File::open(&xml_before)?)?;
+    let block_size = sb.data_block_size as u64 * 512;
+    let _file = file_utils::create_sized_file(&data_path, 1024 * block_size)?;
+
+    let opts = ThinShrinkOptions {
+        input: &xml_before,
+        output: &xml_after,
+        data_device: &data_path,
+        nr_blocks: s.get_new_nr_blocks(),
+        do_copy: true,
+        verify: false,
+        throttle_mbps: None,
+        policy: mk_policy("first-fit")?,
+        report: Arc::new(mk_quiet_report()),
+    };
+    match shrink(opts) {
+        Ok(_) => Err(anyhow!("Shrink unexpectedly succeeded")),
+        Err(_) => Ok(()),
+    }
+}
+
 //------------------------------------
 
 impl Scenario for FragmentedS {
@@ -435,3 +493,221 @@ fn shrink_identical_snap() -> Result<()> {
 }
 
 //------------------------------------
+
+impl Scenario for ManyThinsS {
+    fn get_new_nr_blocks(&self) -> u64 {
+        self.new_nr_data_blocks
+    }
+}
+
+#[test]
+fn shrink_many_thins() -> Result<()> {
+    let mut s = ManyThinsS::new(64, 256, 50, 1)?;
+    test_shrink(&mut s)
+}
+
+//------------------------------------
+
+impl Scenario for PathologicalFragS {
+    fn get_new_nr_blocks(&self) -> u64 {
+        self.new_nr_data_blocks
+    }
+}
+
+#[test]
+fn shrink_pathological_fragmentation() -> Result<()> {
+    let mut s = PathologicalFragS::new(4, 1024, 1)?;
+    test_shrink(&mut s)
+}
+
+// test the other vacate policies move the data correctly
+
+#[test]
+fn shrink_fragmented_best_fit() -> Result<()> {
+    let mut s = FragmentedS::new(2, 2048);
+    test_shrink_with(&mut s, "best-fit", None)
+}
+
+#[test]
+fn shrink_fragmented_near_owner() -> Result<()> {
+    let mut s = FragmentedS::new(2, 2048);
+    test_shrink_with(&mut s, "near-owner", None)
+}
+
+#[test]
+fn shrink_many_thins_best_fit() -> Result<()> {
+    let mut s = ManyThinsS::new(64, 256, 50, 1)?;
+    test_shrink_with(&mut s, "best-fit", None)
+}
+
+#[test]
+fn shrink_many_thins_near_owner() -> Result<()> {
+    let mut s = ManyThinsS::new(64, 256, 50, 1)?;
+    test_shrink_with(&mut s, "near-owner", None)
+}
+
+#[test]
+fn shrink_pathological_fragmentation_near_owner() -> Result<()> {
+    let mut s = PathologicalFragS::new(4, 1024, 1)?;
+    test_shrink_with(&mut s, "near-owner", None)
+}
+
+// test a throttled copy still moves the data correctly
+
+#[test]
+fn shrink_single_partial_move_throttled() -> Result<()> {
+    let mut s = SingleThinS::new(1024, 1024, 2048, 1280);
+    test_shrink_with(&mut s, "first-fit", Some(1000))
+}
+
+// External origins aren't recorded in the metadata, so a device using
+// one just looks sparse.  Only the provisioned blocks may be moved, and
+// the unprovisioned ones, which read through to the origin, must stay
+// unmapped.
+
+struct MappedBlocks {
+    blocks: Vec<(u32, u64)>,
+}
+
+impl ThinVisitor for MappedBlocks {
+    fn thin_block(&mut self, b: &ThinBlock) -> Result<()> {
+        self.blocks.push((b.thin_id, b.thin_block));
+        Ok(())
+    }
+}
+
+fn mapped_blocks(xml_path: &Path) -> Result<Vec<(u32, u64)>> {
+    let mut v = MappedBlocks { blocks: Vec::new() };
+    thin_visit(File::open(xml_path)?, &mut v)?;
+    Ok(v.blocks)
+}
+
+#[test]
+fn shrink_leaves_unprovisioned_blocks_unmapped() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml_before = td.mk_path("before.xml");
+    let xml_after = td.mk_path("after.xml");
+    let data_path = td.mk_path("metadata.bin");
+
+    std::fs::write(
+        &xml_before,
+        r#"<superblock uuid="" time="0" transaction="1" version="2" data_block_size="128" nr_data_blocks="2048">
+  <device dev_id="0" mapped_blocks="13" transaction="0" creation_time="0" snap_time="0">
+    <range_mapping origin_begin="0" data_begin="1500" length="8" time="0"/>
+    <range_mapping origin_begin="1000" data_begin="10" length="4" time="0"/>
+    <single_mapping origin_block="5000" data_block="2000" time="0"/>
+  </device>
+</superblock>
+"#,
+    )?;
+    create_data_file(&data_path, &xml_before)?;
+
+    let seed = test_seed();
+    stamp(&xml_before, &data_path, seed)?;
+
+    let opts = ThinShrinkOptions {
+        input: &xml_before,
+        output: &xml_after,
+        data_device: &data_path,
+        nr_blocks: 1024,
+        do_copy: true,
+        verify: true,
+        throttle_mbps: None,
+        policy: mk_policy("first-fit")?,
+        report: Arc::new(mk_quiet_report()),
+    };
+    shrink(opts)?;
+
+    verify(&xml_after, &data_path, seed)?;
+    assert_eq!(mapped_blocks(&xml_before)?, mapped_blocks(&xml_after)?);
+    Ok(())
+}
+
+//------------------------------------
+
+struct DataBlocks {
+    blocks: BTreeSet<u64>,
+}
+
+impl ThinVisitor for DataBlocks {
+    fn thin_block(&mut self, b: &ThinBlock) -> Result<()> {
+        self.blocks.insert(b.data_block);
+        Ok(())
+    }
+}
+
+fn nr_blocks_beyond(xml_path: &Path, nr_blocks: u64) -> Result<u64> {
+    let mut v = DataBlocks {
+        blocks: BTreeSet::new(),
+    };
+    thin_visit(File::open(xml_path)?, &mut v)?;
+    Ok(v.blocks.range(nr_blocks..).count() as u64)
+}
+
+// Runs each stage of a plan as its own shrink, checking the stages move
+// what the plan said they would.
+fn test_staged_shrink<S>(scenario: &mut S, policy: &str, max_moved: u64) -> Result<()>
+where
+    S: Scenario + XmlGen,
+{
+    let mut td = TestDir::new()?;
+    let mut xml_path = td.mk_path("stage0.xml");
+    let data_path = td.mk_path("metadata.bin");
+
+    write_xml(&xml_path, scenario)?;
+    create_data_file(&data_path, &xml_path)?;
+
+    let seed = test_seed();
+    stamp(&xml_path, &data_path, seed)?;
+
+    let new_nr_blocks = scenario.get_new_nr_blocks();
+    let stages = plan(ThinShrinkPlanOptions {
+        input: &xml_path,
+        nr_blocks: new_nr_blocks,
+        max_moved,
+        policy: mk_policy(policy)?,
+        report: Arc::new(mk_quiet_report()),
+    })?;
+    assert!(stages.len() > 1);
+    assert_eq!(stages.last().unwrap().nr_blocks, new_nr_blocks);
+
+    for (i, stage) in stages.iter().enumerate() {
+        assert!(stage.nr_moved <= max_moved);
+        assert_eq!(
+            nr_blocks_beyond(&xml_path, stage.nr_blocks)?,
+            stage.nr_moved
+        );
+
+        let next_path = td.mk_path(&format!("stage{}.xml", i + 1));
+        shrink(ThinShrinkOptions {
+            input: &xml_path,
+            output: &next_path,
+            data_device: &data_path,
+            nr_blocks: stage.nr_blocks,
+            do_copy: true,
+            verify: true,
+            throttle_mbps: None,
+            policy: mk_policy(policy)?,
+            report: Arc::new(mk_quiet_report()),
+        })?;
+        verify(&next_path, &data_path, seed)?;
+        xml_path = next_path;
+    }
+
+    assert_eq!(nr_blocks_beyond(&xml_path, new_nr_blocks)?, 0);
+    Ok(())
+}
+
+#[test]
+fn shrink_fragmented_in_stages() -> Result<()> {
+    let mut s = FragmentedS::new(2, 2048);
+    test_staged_shrink(&mut s, "first-fit", 100)
+}
+
+#[test]
+fn shrink_fragmented_in_stages_best_fit() -> Result<()> {
+    let mut s = FragmentedS::new(2, 2048);
+    test_staged_shrink(&mut s, "best-fit", 100)
+}
+
+//------------------------------------
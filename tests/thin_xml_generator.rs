@@ -0,0 +1,140 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+use thinp::thin::ir::{self, MetadataVisitor, Visit};
+
+mod common;
+use common::thin_xml_generator::{ManyThinsS, PathologicalFragS, SharedSnapsS, SnapChainS, XmlGen};
+
+//------------------------------------
+
+// Records who maps each data block.
+#[derive(Default, PartialEq, Debug)]
+struct Pool {
+    nr_data_blocks: u64,
+    devs: Vec<u32>,
+    nr_maps: u64,
+    refs: BTreeMap<u64, Vec<u32>>,
+    current: Option<u32>,
+}
+
+impl MetadataVisitor for Pool {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.nr_data_blocks = sb.nr_data_blocks;
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, _name: &str) -> Result<Visit> {
+        todo!();
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        todo!();
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        self.devs.push(d.dev_id);
+        self.current = Some(d.dev_id);
+        Ok(Visit::Continue)
+    }
+
+    fn device_labels(&mut self, _labels: &ir::DeviceLabels) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        self.current = None;
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        self.nr_maps += 1;
+        for b in m.data_begin..(m.data_begin + m.len) {
+            assert!(b < self.nr_data_blocks);
+            self.refs.entry(b).or_default().push(self.current.unwrap());
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, _name: &str) -> Result<Visit> {
+        todo!();
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Stop)
+    }
+}
+
+fn generate(g: &mut dyn XmlGen) -> Result<Pool> {
+    let mut pool = Pool::default();
+    g.generate_xml(&mut pool)?;
+    Ok(pool)
+}
+
+fn nr_shared(pool: &Pool) -> usize {
+    pool.refs.values().filter(|r| r.len() > 1).count()
+}
+
+//------------------------------------
+
+#[test]
+fn same_seed_same_pool() -> Result<()> {
+    let p1 = generate(&mut SnapChainS::new(1024, 8, 10, 42)?)?;
+    let p2 = generate(&mut SnapChainS::new(1024, 8, 10, 42)?)?;
+    let p3 = generate(&mut SnapChainS::new(1024, 8, 10, 43)?)?;
+    assert_eq!(p1, p2);
+    assert_ne!(p1, p3);
+    Ok(())
+}
+
+#[test]
+fn many_thins_are_unshared() -> Result<()> {
+    let s = ManyThinsS::new(100, 512, 50, 1)?;
+    let nr_mapped = s.new_nr_data_blocks;
+    let pool = generate(&mut { s })?;
+    assert_eq!(pool.devs.len(), 100);
+    assert_eq!(pool.refs.len() as u64, nr_mapped);
+    assert_eq!(nr_shared(&pool), 0);
+    Ok(())
+}
+
+#[test]
+fn snap_chain_shares_down_the_chain() -> Result<()> {
+    let pool = generate(&mut SnapChainS::new(1024, 16, 5, 1)?)?;
+    assert_eq!(pool.devs, (0..17).collect::<Vec<u32>>());
+
+    // some of the origin's blocks should survive to the end of the chain
+    assert!(pool.refs.values().any(|r| r.len() == 17));
+    Ok(())
+}
+
+#[test]
+fn shared_snaps_are_mostly_shared() -> Result<()> {
+    let pool = generate(&mut SharedSnapsS::new(1024, 32, 2, 1)?)?;
+    assert_eq!(pool.devs.len(), 33);
+
+    // most mappings should be of shared blocks
+    let nr_mappings: usize = pool.refs.values().map(|r| r.len()).sum();
+    let nr_shared_mappings: usize = pool
+        .refs
+        .values()
+        .filter(|r| r.len() > 1)
+        .map(|r| r.len())
+        .sum();
+    assert!(nr_shared_mappings * 10 > nr_mappings * 9);
+    Ok(())
+}
+
+#[test]
+fn pathological_fragmentation_has_no_runs() -> Result<()> {
+    let pool = generate(&mut PathologicalFragS::new(4, 1024, 1)?)?;
+    assert_eq!(pool.refs.len(), 4 * 1024);
+    assert_eq!(pool.nr_maps, 4 * 1024);
+    Ok(())
+}
+
+//------------------------------------
@@ -73,6 +73,13 @@ namespace thin_provisioning {
 
 	//------------------------------------------------------
 
+	class thin_age_metadata_cmd : public base::command {
+	public:
+		thin_age_metadata_cmd();
+		virtual void usage(std::ostream &out) const;
+		virtual int run(int argc, char **argv);
+	};
+
 	class thin_debug_cmd : public base::command {
 	public:
 		thin_debug_cmd();
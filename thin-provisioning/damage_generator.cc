@@ -1,23 +1,30 @@
-#include <chrono>
+#include <algorithm>
 #include <random>
+#include <boost/optional.hpp>
 #include "damage_generator.h"
+#include "persistent-data/data-structures/btree_counter.h"
+#include "persistent-data/data-structures/btree_disk_structures.h"
+#include "persistent-data/space-maps/disk_structures.h"
+#include "persistent-data/validators.h"
 
 using namespace thin_provisioning;
 
 //----------------------------------------------------------------
 
 namespace {
+	using namespace btree_detail;
+
+	// Picks nr_blocks random blocks with the expected ref count, or
+	// with any non-zero count if expected isn't given.
 	void find_blocks(space_map::ptr sm,
 			 block_address nr_blocks,
-			 ref_t expected,
+			 boost::optional<ref_t> expected,
+			 std::mt19937 &rand_engine,
 			 std::set<block_address> &found) {
 		block_address sm_size = sm->get_nr_blocks();
 		base::run_set<block_address> visited;
 		block_address nr_visited = 0;
 
-		uint64_t rand_seed(std::chrono::high_resolution_clock::now().time_since_epoch().count());
-		std::mt19937 rand_engine(rand_seed);
-
 		while (nr_blocks) {
 			if (nr_visited == sm_size)
 				break;
@@ -27,7 +34,7 @@ namespace {
 				continue;
 
 			ref_t c = sm->get_count(b);
-			if (c == expected) {
+			if (expected ? c == *expected : c > 0) {
 				found.insert(b);
 				--nr_blocks;
 			}
@@ -38,16 +45,54 @@ namespace {
 
 		if (nr_blocks) {
 			ostringstream out;
-			out << "cannot find " << (nr_blocks + found.size())
-			    << " blocks of ref-count " << expected;
+			out << "cannot find " << (nr_blocks + found.size());
+			if (expected)
+				out << " blocks of ref-count " << *expected;
+			else
+				out << " blocks in use";
 			throw runtime_error(out.str());
 		}
 	}
+
+	// Byte ranges of a node header that the checker looks at.  The
+	// csum and block nr are rewritten by the validator, and the
+	// padding is never read.
+	struct byte_range {
+		unsigned begin_;
+		unsigned end_;
+	};
+
+	byte_range const header_fields[] = {
+		{4, 8},		// flags
+		{16, 28}	// nr_entries, max_entries, value_size
+	};
+
+	unsigned pick_live_byte(node_header const &hdr, std::mt19937 &rand_engine) {
+		unsigned nr_entries = to_cpu<uint32_t>(hdr.nr_entries);
+		unsigned value_size = to_cpu<uint32_t>(hdr.value_size);
+		unsigned end = sizeof(node_header) + nr_entries * (sizeof(uint64_t) + value_size);
+		if (end > MD_BLOCK_SIZE)
+			end = MD_BLOCK_SIZE;
+
+		unsigned nr_bytes = end - sizeof(node_header);
+		for (auto const &r : header_fields)
+			nr_bytes += r.end_ - r.begin_;
+
+		unsigned n = rand_engine() % nr_bytes;
+		for (auto const &r : header_fields) {
+			if (n < r.end_ - r.begin_)
+				return r.begin_ + n;
+			n -= r.end_ - r.begin_;
+		}
+
+		return sizeof(node_header) + n;
+	}
 }
 
 //----------------------------------------------------------------
 
-damage_generator::damage_generator(block_manager::ptr bm)
+damage_generator::damage_generator(block_manager::ptr bm, uint64_t seed)
+	: rand_engine_(seed)
 {
 	md_ = metadata::ptr(new metadata(bm, true));
 }
@@ -61,8 +106,153 @@ void damage_generator::create_metadata_leaks(block_address nr_leaks,
 					     ref_t expected, ref_t actual)
 {
 	std::set<block_address> leaks;
-	find_blocks(md_->metadata_sm_, nr_leaks, expected, leaks);
+	find_blocks(md_->metadata_sm_, nr_leaks, expected, rand_engine_, leaks);
+
+	mark_live_blocks_shadowed();
+
+	for (auto const &b : leaks)
+		md_->metadata_sm_->set_count(b, actual);
+}
+
+void damage_generator::flip_node_bits(node_type t, block_address nr_nodes)
+{
+	std::set<block_address> nodes;
+	find_nodes(t, nr_nodes, nodes);
+
+	block_manager::ptr bm = md_->tm_->get_bm();
+	for (auto const &b : nodes) {
+		block_manager::write_ref wr = bm->write_lock(b, create_btree_node_validator());
+		unsigned char *data = reinterpret_cast<unsigned char *>(wr.data());
+		node_header const *hdr = reinterpret_cast<node_header const *>(data);
+
+		unsigned byte = pick_live_byte(*hdr, rand_engine_);
+		data[byte] ^= 1 << (rand_engine_() % 8);
+	}
+}
+
+void damage_generator::corrupt_node_checksums(node_type t, block_address nr_nodes)
+{
+	std::set<block_address> nodes;
+	find_nodes(t, nr_nodes, nodes);
+
+	block_manager::ptr bm = md_->tm_->get_bm();
+	for (auto const &b : nodes) {
+		// the noop validator leaves the damaged checksum alone
+		block_manager::write_ref wr = bm->write_lock(b);
+		node_header *hdr = reinterpret_cast<node_header *>(wr.data());
+		hdr->csum = to_disk<base::le32>(~to_cpu<uint32_t>(hdr->csum));
+	}
+}
+
+void damage_generator::truncate_subtree(uint64_t dev_id)
+{
+	uint64_t key[1] = {dev_id};
+	dev_tree::maybe_value root = md_->mappings_top_level_->lookup(key);
+	if (!root) {
+		ostringstream out;
+		out << "unknown device id " << dev_id;
+		throw runtime_error(out.str());
+	}
+
+	block_manager::write_ref wr =
+		md_->tm_->get_bm()->write_lock(*root, create_btree_node_validator());
+	node_header *hdr = reinterpret_cast<node_header *>(wr.data());
+
+	uint32_t nr_entries = to_cpu<uint32_t>(hdr->nr_entries);
+	if (nr_entries < 2) {
+		ostringstream out;
+		out << "mapping tree of device " << dev_id << " is too small to truncate";
+		throw runtime_error(out.str());
+	}
+
+	hdr->nr_entries = to_disk<base::le32>(nr_entries / 2);
+}
+
+void damage_generator::break_details_tree()
+{
+	md_->tm_->get_bm()->write_lock_zero(md_->details_->get_root());
+}
+
+void damage_generator::damage_metadata_snap()
+{
+	block_address snap = md_->sb_.metadata_snap_;
+	if (snap == superblock_detail::SUPERBLOCK_LOCATION)
+		throw runtime_error("no metadata snapshot present");
+
+	md_->tm_->get_bm()->write_lock_zero(snap);
+}
+
+block_address damage_generator::pick_truncation_point(node_type t)
+{
+	std::set<block_address> nodes;
+	find_nodes(t, 1, nodes);
+	return *nodes.begin();
+}
 
+void damage_generator::corrupt_space_map(space_map_type t, space_map_damage d,
+					 block_address nr_blocks)
+{
+	sm_disk_detail::sm_root root = get_sm_root(t);
+
+	block_manager::ptr bm = md_->tm_->get_bm();
+	std::set<block_address> targets;
+
+	switch (d) {
+	case SM_DAMAGE_INDEX:
+		targets.insert(root.bitmap_root_);
+		break;
+
+	case SM_DAMAGE_BITMAP: {
+		std::vector<block_address> bitmaps;
+		find_bitmaps(t, bitmaps);
+		if (bitmaps.size() < nr_blocks) {
+			ostringstream out;
+			out << "cannot find " << nr_blocks << " bitmaps, only "
+			    << bitmaps.size() << " present";
+			throw runtime_error(out.str());
+		}
+
+		std::shuffle(bitmaps.begin(), bitmaps.end(), rand_engine_);
+		targets.insert(bitmaps.begin(), bitmaps.begin() + nr_blocks);
+		break;
+	}
+
+	case SM_DAMAGE_REF_COUNT: {
+		space_map::ptr sm = (t == SM_METADATA) ? md_->metadata_sm_ : md_->data_sm_;
+		std::set<block_address> blocks;
+		find_blocks(sm, nr_blocks, boost::optional<ref_t>(), rand_engine_, blocks);
+
+		// update the bitmaps in place, so no block that's
+		// still referenced gets reallocated by the commit.
+		mark_live_blocks_shadowed();
+		for (auto const &b : blocks)
+			sm->set_count(b, sm->get_count(b) - 1);
+		return;
+	}
+	}
+
+	// The index block, index btree nodes and bitmaps all start
+	// with a checksum.  The noop validator leaves it damaged.
+	for (auto const &b : targets) {
+		block_manager::write_ref wr = bm->write_lock(b);
+		base::le32 *csum = reinterpret_cast<base::le32 *>(wr.data());
+		*csum = to_disk<base::le32>(~to_cpu<uint32_t>(*csum));
+	}
+}
+
+sm_disk_detail::sm_root damage_generator::get_sm_root(space_map_type t) const
+{
+	unsigned char const *raw = (t == SM_METADATA) ?
+		md_->sb_.metadata_space_map_root_ : md_->sb_.data_space_map_root_;
+
+	sm_disk_detail::sm_root root;
+	sm_disk_detail::sm_root_traits::unpack(
+		*reinterpret_cast<sm_disk_detail::sm_root_disk const *>(raw), root);
+	return root;
+}
+
+void damage_generator::mark_live_blocks_shadowed()
+{
 	block_counter bc(true);
 	md_->metadata_sm_->count_metadata(bc);
 	block_address nr_blocks = md_->metadata_sm_->get_nr_blocks();
@@ -70,9 +260,74 @@ void damage_generator::create_metadata_leaks(block_address nr_leaks,
 		if (bc.get_count(b))
 			md_->tm_->mark_shadowed(b);
 	}
+}
 
-	for (auto const &b : leaks)
-		md_->metadata_sm_->set_count(b, actual);
+void damage_generator::find_bitmaps(space_map_type t, std::vector<block_address> &bitmaps)
+{
+	using namespace sm_disk_detail;
+
+	sm_root root = get_sm_root(t);
+
+	block_address entries_per_block = (MD_BLOCK_SIZE - sizeof(bitmap_header)) * ENTRIES_PER_BYTE;
+	block_address nr_bitmaps = (root.nr_blocks_ + entries_per_block - 1) / entries_per_block;
+
+	if (t == SM_METADATA) {
+		block_manager::read_ref rr = md_->tm_->get_bm()->read_lock(root.bitmap_root_);
+		metadata_index const *mi = reinterpret_cast<metadata_index const *>(rr.data());
+		for (block_address i = 0; i < nr_bitmaps && i < MAX_METADATA_BITMAPS; i++)
+			bitmaps.push_back(to_cpu<uint64_t>(mi->index[i].blocknr_));
+	} else {
+		btree<1, index_entry_traits> index(*md_->tm_, root.bitmap_root_,
+						   index_entry_traits::ref_counter());
+		for (block_address i = 0; i < nr_bitmaps; i++) {
+			uint64_t key[1] = {i};
+			btree<1, index_entry_traits>::maybe_value ie = index.lookup(key);
+			if (ie)
+				bitmaps.push_back(ie->blocknr_);
+		}
+	}
+}
+
+void damage_generator::find_nodes(node_type t, block_address nr_nodes,
+				  std::set<block_address> &found)
+{
+	block_counter details, top_level, mappings;
+
+	if (t == NODE_DETAILS || t == NODE_ANY) {
+		noop_value_counter<device_tree_detail::device_details> vc;
+		count_btree_blocks(*md_->details_, details, vc);
+	}
+
+	if (t != NODE_DETAILS) {
+		noop_value_counter<uint64_t> vc;
+		count_btree_blocks(*md_->mappings_top_level_, top_level, vc);
+	}
+
+	if (t == NODE_BOTTOM_LEVEL || t == NODE_ANY) {
+		noop_value_counter<mapping_tree_detail::block_time> vc;
+		count_btree_blocks(*md_->mappings_, mappings, vc);
+	}
+
+	// the mapping tree walk sees the top level nodes too
+	std::vector<block_address> candidates;
+	for (auto const &p : details.get_counts())
+		candidates.push_back(p.first);
+	if (t != NODE_BOTTOM_LEVEL)
+		for (auto const &p : top_level.get_counts())
+			candidates.push_back(p.first);
+	for (auto const &p : mappings.get_counts())
+		if (!top_level.get_count(p.first))
+			candidates.push_back(p.first);
+
+	if (candidates.size() < nr_nodes) {
+		ostringstream out;
+		out << "cannot find " << nr_nodes << " nodes of the requested type, only "
+		    << candidates.size() << " present";
+		throw runtime_error(out.str());
+	}
+
+	std::shuffle(candidates.begin(), candidates.end(), rand_engine_);
+	found.insert(candidates.begin(), candidates.begin() + nr_nodes);
 }
 
 //----------------------------------------------------------------
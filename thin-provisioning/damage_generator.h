@@ -2,6 +2,9 @@
 #define METADATA_DAMAGE_GENERATOR_H
 
 #include "metadata.h"
+#include "persistent-data/space-maps/disk_structures.h"
+
+#include <random>
 
 //----------------------------------------------------------------
 
@@ -9,12 +12,64 @@ class damage_generator {
 public:
 	typedef std::shared_ptr<damage_generator> ptr;
 
-	damage_generator(block_manager::ptr bm);
+	// The btree nodes that node damage can be aimed at
+	enum node_type {
+		NODE_DETAILS,
+		NODE_TOP_LEVEL,
+		NODE_BOTTOM_LEVEL,
+		NODE_ANY
+	};
+
+	enum space_map_type {
+		SM_METADATA,
+		SM_DATA
+	};
+
+	enum space_map_damage {
+		SM_DAMAGE_INDEX,
+		SM_DAMAGE_BITMAP,
+		SM_DAMAGE_REF_COUNT
+	};
+
+	damage_generator(block_manager::ptr bm, uint64_t seed);
 	void commit();
 	void create_metadata_leaks(block_address nr_leaks, ref_t expected, ref_t actual);
 
+	// Flips a bit in the live part of each node, then rewrites the
+	// checksum so only the contents are wrong.
+	void flip_node_bits(node_type t, block_address nr_nodes);
+
+	// Damages the checksum of each node, leaving the contents intact.
+	void corrupt_node_checksums(node_type t, block_address nr_nodes);
+
+	// Drops the upper half of the entries in the root node of a
+	// device's mapping tree.
+	void truncate_subtree(uint64_t dev_id);
+
+	// Wipes the root node of the device details tree.
+	void break_details_tree();
+
+	// Wipes the superblock copy of the metadata snapshot.
+	void damage_metadata_snap();
+
+	// Picks an in use node to truncate the metadata at, losing it
+	// and everything after it.
+	block_address pick_truncation_point(node_type t);
+
+	// Damages the checksum of the index, or of nr_blocks bitmaps,
+	// or lowers the ref count of nr_blocks in use blocks by one.
+	void corrupt_space_map(space_map_type t, space_map_damage d,
+			       block_address nr_blocks);
+
 private:
+	persistent_data::sm_disk_detail::sm_root get_sm_root(space_map_type t) const;
+	void mark_live_blocks_shadowed();
+	void find_bitmaps(space_map_type t, std::vector<block_address> &bitmaps);
+	void find_nodes(node_type t, block_address nr_nodes,
+			std::set<block_address> &found);
+
 	thin_provisioning::metadata::ptr md_;
+	std::mt19937 rand_engine_;
 };
 
 //----------------------------------------------------------------
@@ -0,0 +1,168 @@
+#include "thin-provisioning/device_labels.h"
+
+#include "persistent-data/checksum.h"
+#include "persistent-data/errors.h"
+
+#include <sstream>
+#include <stdexcept>
+
+using namespace base;
+using namespace persistent_data;
+using namespace thin_provisioning;
+using namespace device_labels_detail;
+using namespace std;
+
+//----------------------------------------------------------------
+
+namespace {
+	uint32_t const LABELS_CSUM_XOR = 271214;
+	size_t const PAYLOAD_SIZE = MD_BLOCK_SIZE - sizeof(labels_header_disk);
+
+	struct labels_validator : public bcache::validator {
+		virtual void check(void const *raw, block_address location) const {
+			labels_header_disk const *h = reinterpret_cast<labels_header_disk const *>(raw);
+			if (!check_raw(raw)) {
+				ostringstream out;
+				out << "bad checksum in device labels (block " << location << ")";
+				throw checksum_error(out.str());
+			}
+
+			if (to_cpu<uint64_t>(h->blocknr_) != location) {
+				ostringstream out;
+				out << "bad block nr in device labels (block " << location << ")";
+				throw checksum_error(out.str());
+			}
+		}
+
+		virtual bool check_raw(void const *raw) const {
+			labels_header_disk const *h = reinterpret_cast<labels_header_disk const *>(raw);
+			crc32c sum(LABELS_CSUM_XOR);
+			sum.append(&h->blocknr_, MD_BLOCK_SIZE - sizeof(uint32_t));
+			return sum.get_sum() == to_cpu<uint32_t>(h->csum_);
+		}
+
+		virtual void prepare(void *raw, block_address location) const {
+			labels_header_disk *h = reinterpret_cast<labels_header_disk *>(raw);
+			h->blocknr_ = to_disk<base::le64, uint64_t>(location);
+
+			crc32c sum(LABELS_CSUM_XOR);
+			sum.append(&h->blocknr_, MD_BLOCK_SIZE - sizeof(uint32_t));
+			h->csum_ = to_disk<base::le32>(sum.get_sum());
+		}
+	};
+
+	// Walks the payload, which is a sequence of little endian u32
+	// counts and length prefixed strings.
+	class payload_reader {
+	public:
+		payload_reader(vector<unsigned char> const &data)
+			: data_(data),
+			  pos_(0) {
+		}
+
+		uint32_t read_u32() {
+			if (data_.size() - pos_ < sizeof(uint32_t))
+				throw runtime_error("couldn't unpack the device labels");
+
+			uint32_t v = 0;
+			for (unsigned i = 0; i < sizeof(uint32_t); i++)
+				v |= static_cast<uint32_t>(data_[pos_ + i]) << (8 * i);
+			pos_ += sizeof(uint32_t);
+			return v;
+		}
+
+		// Unset strings are stored empty.
+		boost::optional<string> read_string() {
+			uint32_t len = read_u32();
+			if (data_.size() - pos_ < len)
+				throw runtime_error("couldn't unpack the device labels");
+
+			string s(data_.begin() + pos_, data_.begin() + pos_ + len);
+			pos_ += len;
+			if (s.empty())
+				return boost::optional<string>();
+			return s;
+		}
+
+	private:
+		vector<unsigned char> const &data_;
+		size_t pos_;
+	};
+
+	label_map unpack_payload(vector<unsigned char> const &data) {
+		payload_reader r(data);
+		label_map labels;
+
+		uint32_t nr_devices = r.read_u32();
+		for (uint32_t i = 0; i < nr_devices; i++) {
+			uint32_t dev_id = r.read_u32();
+			device_labels &l = labels[dev_id];
+			l.label_ = r.read_string();
+			l.uuid_ = r.read_string();
+
+			uint32_t nr_attributes = r.read_u32();
+			for (uint32_t a = 0; a < nr_attributes; a++) {
+				boost::optional<string> key = r.read_string();
+				boost::optional<string> value = r.read_string();
+				l.attributes_[key ? *key : ""] = value ? *value : "";
+			}
+		}
+
+		return labels;
+	}
+}
+
+//----------------------------------------------------------------
+
+bcache::validator::ptr
+thin_provisioning::device_labels_validator()
+{
+	return bcache::validator::ptr(new labels_validator);
+}
+
+label_area
+thin_provisioning::read_device_labels(block_manager const &bm, block_address root)
+{
+	if (root == 0 || root >= bm.get_nr_blocks()) {
+		ostringstream out;
+		out << "no device labels at block " << root;
+		throw runtime_error(out.str());
+	}
+
+	label_area area;
+	vector<unsigned char> payload;
+	uint32_t nr_blocks = 1;
+
+	for (uint32_t index = 0; index < nr_blocks; index++) {
+		block_address loc = root - index;
+		block_manager::read_ref rr = bm.read_lock(loc, device_labels_validator());
+		labels_header_disk const *h = reinterpret_cast<labels_header_disk const *>(rr.data());
+
+		uint32_t n = to_cpu<uint32_t>(h->nr_blocks_);
+		uint32_t len = to_cpu<uint32_t>(h->len_);
+		if (index == 0) {
+			if (n == 0 || n > MAX_LABEL_BLOCKS || n > root) {
+				ostringstream out;
+				out << "bad number of label blocks (" << n << ")";
+				throw runtime_error(out.str());
+			}
+			nr_blocks = n;
+		}
+
+		if (to_cpu<uint32_t>(h->index_) != index || n != nr_blocks || len > PAYLOAD_SIZE) {
+			ostringstream out;
+			out << "label block " << loc << " is out of place";
+			throw runtime_error(out.str());
+		}
+
+		unsigned char const *data = reinterpret_cast<unsigned char const *>(rr.data()) +
+			sizeof(labels_header_disk);
+		payload.insert(payload.end(), data, data + len);
+		area.blocks_.push_back(loc);
+	}
+
+	area.labels_ = unpack_payload(payload);
+	return area;
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,52 @@
+#ifndef THIN_DEVICE_LABELS_H
+#define THIN_DEVICE_LABELS_H
+
+#include "base/endian_utils.h"
+#include "persistent-data/block.h"
+
+#include <boost/optional.hpp>
+#include <map>
+#include <string>
+#include <vector>
+
+//----------------------------------------------------------------
+
+// Device labels are written by the userland tools into a run of blocks
+// near the end of the metadata.  The superblock's labels_root_ points at
+// the top block of the run, which records how many blocks follow it.
+namespace thin_provisioning {
+	namespace device_labels_detail {
+		struct labels_header_disk {
+			base::le32 csum_;
+			base::le64 blocknr_;
+			base::le32 index_;
+			base::le32 nr_blocks_;
+			base::le32 len_;
+		} __attribute__ ((packed));
+
+		uint32_t const MAX_LABEL_BLOCKS = 256;
+	}
+
+	struct device_labels {
+		boost::optional<std::string> label_;
+		boost::optional<std::string> uuid_;
+		std::map<std::string, std::string> attributes_;
+	};
+
+	typedef std::map<uint32_t, device_labels> label_map;
+
+	struct label_area {
+		std::vector<persistent_data::block_address> blocks_;
+		label_map labels_;
+	};
+
+	bcache::validator::ptr device_labels_validator();
+
+	// Throws if the run is damaged.
+	label_area read_device_labels(persistent_data::block_manager const &bm,
+				      persistent_data::block_address root);
+}
+
+//----------------------------------------------------------------
+
+#endif
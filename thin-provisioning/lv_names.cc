@@ -0,0 +1,272 @@
+#include "thin-provisioning/lv_names.h"
+
+#include <boost/lexical_cast.hpp>
+#include <fstream>
+#include <set>
+#include <sstream>
+#include <stdexcept>
+#include <vector>
+
+using namespace std;
+using namespace thin_provisioning;
+
+//----------------------------------------------------------------
+
+namespace {
+	string trim(string const &str) {
+		string::size_type b = str.find_first_not_of(" \t\r");
+		if (b == string::npos)
+			return "";
+
+		string::size_type e = str.find_last_not_of(" \t\r");
+		return str.substr(b, e - b + 1);
+	}
+
+	// Drops comments and the contents of quoted strings, which in
+	// LVM metadata can hold anything, including braces.
+	string strip_line(string const &line, vector<string> &strings) {
+		string r;
+		bool quoted = false;
+		string current;
+
+		for (string::size_type i = 0; i < line.size(); i++) {
+			char c = line[i];
+
+			if (quoted) {
+				if (c == '\\' && i + 1 < line.size())
+					current += line[++i];
+				else if (c == '"') {
+					quoted = false;
+					strings.push_back(current);
+					r += '"';
+				} else
+					current += c;
+
+			} else if (c == '"') {
+				quoted = true;
+				current.clear();
+				r += '"';
+
+			} else if (c == '#')
+				break;
+
+			else
+				r += c;
+		}
+
+		return trim(r);
+	}
+
+	uint64_t parse_dev_id(string const &str, string const &path, unsigned line_nr) {
+		try {
+			return boost::lexical_cast<uint64_t>(str);
+		} catch (...) {
+			ostringstream out;
+			out << path << ":" << line_nr << ": bad device id '" << str << "'";
+			throw runtime_error(out.str());
+		}
+	}
+
+	//--------------------------------
+
+	struct thin_lv {
+		string pool_;
+		uint64_t dev_id_;
+		string name_;
+	};
+
+	class lvm_metadata_parser {
+	public:
+		lvm_metadata_parser(string const &path)
+			: path_(path) {
+		}
+
+		// Only the segments of thin volumes are of interest:
+		//
+		// vg {
+		//     logical_volumes {
+		//         lv {
+		//             segment1 {
+		//                 type = "thin"
+		//                 thin_pool = "pool"
+		//                 device_id = 1
+		void parse_line(string const &raw, unsigned line_nr) {
+			vector<string> strings;
+			string line = strip_line(raw, strings);
+
+			if (line.empty())
+				return;
+
+			if (line[line.size() - 1] == '{') {
+				sections_.push_back(trim(line.substr(0, line.size() - 1)));
+				if (in_segment())
+					segment_ = segment();
+
+			} else if (line == "}") {
+				if (sections_.empty()) {
+					ostringstream out;
+					out << path_ << ":" << line_nr << ": unbalanced '}'";
+					throw runtime_error(out.str());
+				}
+
+				if (in_segment())
+					end_segment();
+				sections_.pop_back();
+
+			} else if (in_segment()) {
+				string::size_type eq = line.find('=');
+				if (eq == string::npos)
+					return;
+
+				string key = trim(line.substr(0, eq));
+				string value = trim(line.substr(eq + 1));
+
+				if (key == "type" && strings.size())
+					segment_.type_ = strings[0];
+				else if (key == "thin_pool" && strings.size())
+					segment_.pool_ = strings[0];
+				else if (key == "device_id")
+					segment_.dev_id_ = parse_dev_id(value, path_, line_nr);
+			}
+		}
+
+		vector<thin_lv> const &get_lvs() const {
+			return lvs_;
+		}
+
+	private:
+		struct segment {
+			string type_;
+			string pool_;
+			boost::optional<uint64_t> dev_id_;
+		};
+
+		bool in_segment() const {
+			return sections_.size() == 4 &&
+				sections_[1] == "logical_volumes" &&
+				sections_[3].compare(0, 7, "segment") == 0;
+		}
+
+		void end_segment() {
+			if (segment_.type_ != "thin" || !segment_.dev_id_)
+				return;
+
+			thin_lv lv;
+			lv.pool_ = segment_.pool_;
+			lv.dev_id_ = *segment_.dev_id_;
+			lv.name_ = sections_[0] + "/" + sections_[2];
+			lvs_.push_back(lv);
+		}
+
+		string path_;
+		vector<string> sections_;
+		segment segment_;
+		vector<thin_lv> lvs_;
+	};
+
+	//--------------------------------
+
+	lv_name_map select_pool(vector<thin_lv> const &lvs,
+				boost::optional<string> const &pool) {
+		vector<thin_lv>::const_iterator it;
+		set<string> pools;
+		for (it = lvs.begin(); it != lvs.end(); ++it)
+			pools.insert(it->pool_);
+
+		if (!pool && pools.size() > 1) {
+			ostringstream out;
+			out << "the LVM metadata describes several thin pools, choose one of:";
+			set<string>::const_iterator p;
+			for (p = pools.begin(); p != pools.end(); ++p)
+				out << " " << *p;
+			throw runtime_error(out.str());
+		}
+
+		if (pool && !pools.count(*pool))
+			throw runtime_error("no thin volumes found in pool '" + *pool + "'");
+
+		lv_name_map names;
+		for (it = lvs.begin(); it != lvs.end(); ++it)
+			if (!pool || it->pool_ == *pool)
+				names[it->dev_id_] = it->name_;
+
+		return names;
+	}
+
+	bool is_lvm_metadata(vector<string> const &lines) {
+		vector<string>::const_iterator it;
+		for (it = lines.begin(); it != lines.end(); ++it) {
+			vector<string> strings;
+			string line = strip_line(*it, strings);
+			if (!line.empty() && line[line.size() - 1] == '{')
+				return true;
+		}
+
+		return false;
+	}
+
+	lv_name_map read_lvm_metadata(string const &path,
+				      vector<string> const &lines,
+				      boost::optional<string> const &pool) {
+		lvm_metadata_parser parser(path);
+		for (unsigned i = 0; i < lines.size(); i++)
+			parser.parse_line(lines[i], i + 1);
+
+		return select_pool(parser.get_lvs(), pool);
+	}
+
+	// A line with just a name is a volume without a device id, eg,
+	// the pool itself in the output of lvs.
+	lv_name_map read_name_list(string const &path, vector<string> const &lines) {
+		lv_name_map names;
+
+		for (unsigned i = 0; i < lines.size(); i++) {
+			vector<string> strings;
+			istringstream in(strip_line(lines[i], strings));
+			vector<string> fields;
+			string f;
+
+			while (in >> f)
+				fields.push_back(f);
+
+			if (fields.size() < 2)
+				continue;
+
+			if (fields.size() > 2) {
+				ostringstream out;
+				out << path << ":" << i + 1 << ": expected '<dev id> <name>'";
+				throw runtime_error(out.str());
+			}
+
+			names[parse_dev_id(fields[0], path, i + 1)] = fields[1];
+		}
+
+		return names;
+	}
+}
+
+//----------------------------------------------------------------
+
+lv_name_map
+thin_provisioning::read_lv_names(string const &path,
+				 boost::optional<string> const &pool)
+{
+	ifstream in(path.c_str());
+	if (!in)
+		throw runtime_error("couldn't open " + path);
+
+	vector<string> lines;
+	string line;
+	while (getline(in, line))
+		lines.push_back(line);
+
+	if (is_lvm_metadata(lines))
+		return read_lvm_metadata(path, lines, pool);
+
+	if (pool)
+		throw runtime_error("a pool can only be chosen with LVM metadata");
+
+	return read_name_list(path, lines);
+}
+
+//----------------------------------------------------------------
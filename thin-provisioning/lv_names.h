@@ -0,0 +1,28 @@
+#ifndef THIN_LV_NAMES_H
+#define THIN_LV_NAMES_H
+
+#include <boost/optional.hpp>
+#include <map>
+#include <string>
+
+//----------------------------------------------------------------
+
+namespace thin_provisioning {
+	typedef std::map<uint64_t, std::string> lv_name_map;
+
+	// Reads the names of the thin volumes, as 'vg/lv', keyed on
+	// their device ids.  The file is either LVM text metadata, eg,
+	// from /etc/lvm/backup or vgcfgbackup, or a list of
+	// '<dev id> <name>' lines, such as the output of:
+	//
+	//   lvs --noheadings -o thin_id,lv_full_name
+	//
+	// LVM metadata may describe several pools, whose device ids
+	// overlap, in which case the pool must be given.
+	lv_name_map read_lv_names(std::string const &path,
+				  boost::optional<std::string> const &pool);
+}
+
+//----------------------------------------------------------------
+
+#endif
@@ -1,4 +1,5 @@
 #include "thin-provisioning/metadata_counter.h"
+#include "thin-provisioning/device_labels.h"
 #include "persistent-data/space-maps/core.h"
 #include "persistent-data/space-maps/disk_structures.h"
 
@@ -74,6 +75,22 @@ bool thin_provisioning::count_metadata(transaction_manager::ptr tm,
 	bc.inc(superblock_detail::SUPERBLOCK_LOCATION);
 	ret &= count_trees(tm, sb, bc, ignore_non_fatal);
 
+	// Count the backup superblock, if one was written
+	if (sb.backup_superblock_ != superblock_detail::SUPERBLOCK_LOCATION)
+		bc.inc(sb.backup_superblock_);
+
+	// Count the device labels, if any (no-throw)
+	if (sb.labels_root_) {
+		try {
+			label_area area = read_device_labels(*tm->get_bm(), sb.labels_root_);
+			for (auto b : area.blocks_)
+				bc.inc(b);
+		} catch (std::exception &e) {
+			cerr << e.what() << endl;
+			ret = false;
+		}
+	}
+
 	// Count the metadata snap, if present
 	if (!skip_metadata_snap && sb.metadata_snap_ != superblock_detail::SUPERBLOCK_LOCATION) {
 		bc.inc(sb.metadata_snap_);
@@ -788,6 +788,21 @@ namespace {
 		mapping_tree_detail::damage_visitor::ptr damage_policy_;
 	};
 
+	// Lists the devices straight from the details tree, without
+	// touching the mapping trees, so it works however damaged they are.
+	void emit_inventory(emitter::ptr e, dd_map const &dd) {
+		dd_map::const_iterator it;
+		for (it = dd.begin(); it != dd.end(); ++it) {
+			device_tree_detail::device_details const &d = it->second;
+			e->begin_device(it->first,
+					d.mapped_blocks_,
+					d.transaction_id_,
+					d.creation_time_,
+					d.snapshotted_time_);
+			e->end_device();
+		}
+	}
+
 	block_address get_nr_blocks(metadata &md) {
 		if (md.data_sm_)
 			return md.data_sm_->get_nr_blocks();
@@ -917,7 +932,10 @@ thin_provisioning::metadata_dump(metadata::ptr md, emitter::ptr e, dump_options
 			    get_nr_blocks(*md),
 			    boost::optional<block_address>());
 
-	{
+	if (opts.skip_mappings_)
+		emit_inventory(e, de.get_details());
+
+	else {
 		mapping_tree_detail::damage_visitor::ptr md_policy(mapping_damage_policy(false));
 		mapping_tree_emit_visitor mte(opts, *md->tm_, e, de.get_details(), mapping_damage_policy(false));
 		walk_mapping_tree(*md->mappings_top_level_, mte, *md_policy, true);
@@ -0,0 +1,261 @@
+#include "thin-provisioning/metadata_index.h"
+
+#include "persistent-data/checksum.h"
+
+#include <cstdio>
+#include <fstream>
+#include <iterator>
+#include <stdexcept>
+
+using namespace base;
+using namespace std;
+using namespace thin_provisioning;
+
+//----------------------------------------------------------------
+
+namespace {
+	uint64_t const INDEX_MAGIC = 0x7468696e696478ull; // "thinidx"
+	uint32_t const INDEX_VERSION = 2;
+
+	uint32_t const FLAG_CANONICAL = 0x1;
+	uint32_t const FLAG_LAYOUT = 0x2;
+	uint32_t const FLAG_EXCLUSIVES = 0x4;
+
+	// A plain crc32c, the same as the crc32c crate the rust tools use.
+	uint32_t index_checksum(vector<unsigned char> const &data, size_t len) {
+		crc32c sum(0xffffffff);
+		sum.append(data.data(), len);
+		return sum.get_sum();
+	}
+
+	class index_reader {
+	public:
+		index_reader(vector<unsigned char> const &data, size_t len)
+			: data_(data),
+			  len_(len),
+			  pos_(0) {
+		}
+
+		uint64_t read(unsigned bytes) {
+			if (len_ - pos_ < bytes)
+				throw runtime_error("index file too short");
+
+			uint64_t v = 0;
+			for (unsigned i = 0; i < bytes; i++)
+				v |= static_cast<uint64_t>(data_[pos_ + i]) << (8 * i);
+			pos_ += bytes;
+			return v;
+		}
+
+		uint32_t read_u32() {
+			return static_cast<uint32_t>(read(sizeof(uint32_t)));
+		}
+
+		uint64_t read_u64() {
+			return read(sizeof(uint64_t));
+		}
+
+		vector<uint64_t> read_entries() {
+			vector<uint64_t> entries;
+			uint64_t nr_entries = read_u64();
+			for (uint64_t i = 0; i < nr_entries; i++)
+				entries.push_back(read_u64());
+			return entries;
+		}
+
+		bool at_end() const {
+			return pos_ == len_;
+		}
+
+	private:
+		vector<unsigned char> const &data_;
+		size_t len_;
+		size_t pos_;
+	};
+
+	class index_writer {
+	public:
+		void write(uint64_t v, unsigned bytes) {
+			for (unsigned i = 0; i < bytes; i++)
+				data_.push_back(static_cast<unsigned char>(v >> (8 * i)));
+		}
+
+		void write_u32(uint32_t v) {
+			write(v, sizeof(uint32_t));
+		}
+
+		void write_u64(uint64_t v) {
+			write(v, sizeof(uint64_t));
+		}
+
+		void write_entries(vector<uint64_t> const &entries) {
+			write_u64(entries.size());
+			vector<uint64_t>::const_iterator it;
+			for (it = entries.begin(); it != entries.end(); ++it)
+				write_u64(*it);
+		}
+
+		vector<unsigned char> &get_data() {
+			return data_;
+		}
+
+	private:
+		vector<unsigned char> data_;
+	};
+
+	// Any commit writes a new superblock, so these change whenever
+	// the metadata does.
+	void write_generation(index_writer &w, superblock_detail::superblock const &sb) {
+		w.write_u64(sb.trans_id_);
+		w.write_u32(sb.time_);
+		w.write_u64(sb.metadata_snap_);
+		w.write_u64(sb.data_mapping_root_);
+		w.write_u64(sb.device_details_root_);
+	}
+
+	bool same_generation(index_reader &r, superblock_detail::superblock const &sb) {
+		bool same = r.read_u64() == sb.trans_id_;
+		same = (r.read_u32() == sb.time_) && same;
+		same = (r.read_u64() == sb.metadata_snap_) && same;
+		same = (r.read_u64() == sb.data_mapping_root_) && same;
+		same = (r.read_u64() == sb.device_details_root_) && same;
+		return same;
+	}
+
+	metadata_index unpack_index(index_reader &r, superblock_detail::superblock const &sb) {
+		if (r.read_u64() != INDEX_MAGIC)
+			throw runtime_error("not a metadata index");
+
+		if (r.read_u32() != INDEX_VERSION)
+			throw runtime_error("unsupported index version");
+
+		metadata_index index;
+		uint32_t flags = r.read_u32();
+		index.canonical_ = flags & FLAG_CANONICAL;
+		index.have_layout_ = flags & FLAG_LAYOUT;
+		index.have_exclusives_ = flags & FLAG_EXCLUSIVES;
+
+		if (!same_generation(r, sb))
+			throw runtime_error("stale index");
+
+		uint64_t nr_defs = r.read_u64();
+		for (uint64_t i = 0; i < nr_defs; i++) {
+			uint64_t def_id = r.read_u64();
+			index.defs_[def_id] = r.read_entries();
+		}
+
+		uint64_t nr_devs = r.read_u64();
+		for (uint64_t i = 0; i < nr_devs; i++) {
+			index_device &dev = index.devices_[r.read_u32()];
+			dev.root_ = r.read_u64();
+			dev.details_.mapped_blocks_ = r.read_u64();
+			dev.details_.transaction_id_ = r.read_u64();
+			dev.details_.creation_time_ = r.read_u32();
+			dev.details_.snapshotted_time_ = r.read_u32();
+			dev.exclusive_ = r.read_u64();
+			dev.entries_ = r.read_entries();
+		}
+
+		if (!r.at_end())
+			throw runtime_error("trailing data in index file");
+
+		return index;
+	}
+}
+
+//----------------------------------------------------------------
+
+index_device::index_device()
+	: root_(0),
+	  exclusive_(0)
+{
+}
+
+metadata_index::metadata_index()
+	: canonical_(false),
+	  have_layout_(false),
+	  have_exclusives_(false)
+{
+}
+
+boost::optional<metadata_index>
+thin_provisioning::read_metadata_index(string const &path,
+				       superblock_detail::superblock const &sb)
+{
+	ifstream in(path.c_str(), ios::binary);
+	vector<unsigned char> data((istreambuf_iterator<char>(in)),
+				   istreambuf_iterator<char>());
+	if (!in.eof() || data.size() < sizeof(uint32_t))
+		return boost::optional<metadata_index>();
+
+	// The crc of everything before it is the last u32.
+	size_t len = data.size() - sizeof(uint32_t);
+	uint32_t csum = 0;
+	for (unsigned i = 0; i < sizeof(uint32_t); i++)
+		csum |= static_cast<uint32_t>(data[len + i]) << (8 * i);
+	if (csum != index_checksum(data, len))
+		return boost::optional<metadata_index>();
+
+	try {
+		index_reader r(data, len);
+		return unpack_index(r, sb);
+
+	} catch (std::exception &e) {
+		return boost::optional<metadata_index>();
+	}
+}
+
+void
+thin_provisioning::write_metadata_index(string const &path,
+					superblock_detail::superblock const &sb,
+					metadata_index const &index)
+{
+	index_writer w;
+	w.write_u64(INDEX_MAGIC);
+	w.write_u32(INDEX_VERSION);
+	w.write_u32((index.canonical_ ? FLAG_CANONICAL : 0) |
+		    (index.have_layout_ ? FLAG_LAYOUT : 0) |
+		    (index.have_exclusives_ ? FLAG_EXCLUSIVES : 0));
+	write_generation(w, sb);
+
+	w.write_u64(index.defs_.size());
+	map<uint64_t, vector<uint64_t> >::const_iterator def;
+	for (def = index.defs_.begin(); def != index.defs_.end(); ++def) {
+		w.write_u64(def->first);
+		w.write_entries(def->second);
+	}
+
+	w.write_u64(index.devices_.size());
+	map<uint32_t, index_device>::const_iterator dev;
+	for (dev = index.devices_.begin(); dev != index.devices_.end(); ++dev) {
+		w.write_u32(dev->first);
+		w.write_u64(dev->second.root_);
+		w.write_u64(dev->second.details_.mapped_blocks_);
+		w.write_u64(dev->second.details_.transaction_id_);
+		w.write_u32(dev->second.details_.creation_time_);
+		w.write_u32(dev->second.details_.snapshotted_time_);
+		w.write_u64(dev->second.exclusive_);
+		w.write_entries(dev->second.entries_);
+	}
+
+	vector<unsigned char> &data = w.get_data();
+	w.write_u32(index_checksum(data, data.size()));
+
+	string tmp = path + ".tmp";
+	{
+		ofstream out(tmp.c_str(), ios::binary);
+		out.write(reinterpret_cast<char const *>(data.data()), data.size());
+		out.flush();
+		if (!out) {
+			::remove(tmp.c_str());
+			throw runtime_error("couldn't write index " + path);
+		}
+	}
+
+	if (::rename(tmp.c_str(), path.c_str())) {
+		::remove(tmp.c_str());
+		throw runtime_error("couldn't write index " + path);
+	}
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,57 @@
+#ifndef THIN_METADATA_INDEX_H
+#define THIN_METADATA_INDEX_H
+
+#include "thin-provisioning/device_tree.h"
+#include "thin-provisioning/superblock.h"
+
+#include <boost/optional.hpp>
+#include <map>
+#include <string>
+#include <vector>
+
+//----------------------------------------------------------------
+
+// The sidecar index records the result of walking the metadata, so
+// later runs against the same superblock needn't walk it again.  It's
+// shared by thin_dump, which records the leaves making up each device,
+// and thin_ls, which records the exclusive block counts.  Each tool
+// keeps the other's part if the metadata hasn't changed.  The format is
+// described in src/thin/index.rs.
+namespace thin_provisioning {
+	struct index_device {
+		index_device();
+
+		uint64_t root_;
+		device_tree_detail::device_details details_;
+		uint64_t exclusive_;
+
+		// Leaf blocks, with the top bit set for refs to a def.
+		std::vector<uint64_t> entries_;
+	};
+
+	struct metadata_index {
+		metadata_index();
+
+		bool canonical_;
+		bool have_layout_;
+		bool have_exclusives_;
+
+		std::map<uint64_t, std::vector<uint64_t> > defs_;
+		std::map<uint32_t, index_device> devices_;
+	};
+
+	// A missing, damaged or stale index gives nothing.
+	boost::optional<metadata_index>
+	read_metadata_index(std::string const &path,
+			    superblock_detail::superblock const &sb);
+
+	// Written to a temporary file and renamed into place, so readers
+	// never see a partial index.  Throws on failure.
+	void write_metadata_index(std::string const &path,
+				  superblock_detail::superblock const &sb,
+				  metadata_index const &index);
+}
+
+//----------------------------------------------------------------
+
+#endif
@@ -0,0 +1,239 @@
+#include "thin-provisioning/pool_status.h"
+
+#include "persistent-data/space-maps/disk_structures.h"
+
+#include <boost/lexical_cast.hpp>
+#include <fcntl.h>
+#include <linux/dm-ioctl.h>
+#include <sstream>
+#include <stdexcept>
+#include <string.h>
+#include <sys/ioctl.h>
+#include <unistd.h>
+#include <vector>
+
+using namespace persistent_data;
+using namespace std;
+using namespace thin_provisioning;
+
+//----------------------------------------------------------------
+
+namespace {
+	void bad_status(string const &line) {
+		throw runtime_error("couldn't parse thin-pool status '" + line + "'");
+	}
+
+	uint64_t parse_number(string const &line, string const &str) {
+		try {
+			return boost::lexical_cast<uint64_t>(str);
+		} catch (...) {
+			bad_status(line);
+		}
+
+		return 0;
+	}
+
+	// <used>/<total>
+	void parse_fraction(string const &line, string const &str,
+			    block_address &used, block_address &total) {
+		string::size_type slash = str.find('/');
+		if (slash == string::npos)
+			bad_status(line);
+
+		used = parse_number(line, str.substr(0, slash));
+		total = parse_number(line, str.substr(slash + 1));
+	}
+
+	pool_status::pool_mode parse_mode(string const &line, string const &str) {
+		if (str == "rw")
+			return pool_status::READ_WRITE;
+		else if (str == "ro")
+			return pool_status::READ_ONLY;
+		else if (str == "out_of_data_space")
+			return pool_status::OUT_OF_DATA_SPACE;
+
+		bad_status(line);
+		return pool_status::FAILED;
+	}
+
+	//--------------------------------
+
+	class dm_control {
+	public:
+		dm_control()
+			: fd_(::open("/dev/" DM_DIR "/" DM_CONTROL_NODE, O_RDWR)) {
+			if (fd_ < 0)
+				throw runtime_error(string("couldn't open device-mapper control: ") +
+						    strerror(errno));
+		}
+
+		~dm_control() {
+			::close(fd_);
+		}
+
+		// Returns the type and parameters of the target at the
+		// start of the device.
+		void table_status(string const &name, string &type, string &params) {
+			size_t len = 16384;
+
+			for (;;) {
+				vector<char> buffer(len, 0);
+				struct dm_ioctl *ctl = reinterpret_cast<struct dm_ioctl *>(buffer.data());
+
+				ctl->version[0] = DM_VERSION_MAJOR;
+				ctl->version[1] = DM_VERSION_MINOR;
+				ctl->version[2] = DM_VERSION_PATCHLEVEL;
+				ctl->data_size = len;
+				ctl->data_start = sizeof(*ctl);
+
+				if (name.size() >= DM_NAME_LEN)
+					throw runtime_error("device name too long: " + name);
+				strncpy(ctl->name, name.c_str(), DM_NAME_LEN - 1);
+
+				if (::ioctl(fd_, DM_TABLE_STATUS, ctl) < 0)
+					throw runtime_error("couldn't get the status of '" + name +
+							    "': " + strerror(errno));
+
+				if (ctl->flags & DM_BUFFER_FULL_FLAG) {
+					len *= 2;
+					continue;
+				}
+
+				if (!ctl->target_count)
+					throw runtime_error("'" + name + "' has no table loaded");
+
+				struct dm_target_spec *spec =
+					reinterpret_cast<struct dm_target_spec *>(buffer.data() + ctl->data_start);
+				type = spec->target_type;
+				params = reinterpret_cast<char *>(spec + 1);
+				return;
+			}
+		}
+
+	private:
+		int fd_;
+	};
+
+	//--------------------------------
+
+	block_address nr_allocated(unsigned char const *root,
+				   block_address &nr_blocks) {
+		sm_disk_detail::sm_root_disk d;
+		sm_disk_detail::sm_root v;
+
+		memcpy(&d, root, sizeof(d));
+		sm_disk_detail::sm_root_traits::unpack(d, v);
+
+		nr_blocks = v.nr_blocks_;
+		return v.nr_allocated_;
+	}
+
+	template <typename T>
+	bool check_field(ostream &out, char const *what, T live, T on_disk) {
+		if (live == on_disk)
+			return true;
+
+		out << boolalpha
+		    << "warning: " << what << " differs, pool reports "
+		    << live << " but the metadata has " << on_disk << endl;
+		return false;
+	}
+}
+
+//----------------------------------------------------------------
+
+pool_status::pool_status()
+	: mode_(READ_WRITE),
+	  transaction_id_(0),
+	  used_metadata_blocks_(0),
+	  nr_metadata_blocks_(0),
+	  used_data_blocks_(0),
+	  nr_data_blocks_(0),
+	  needs_check_(false)
+{
+}
+
+// <transaction id> <used metadata>/<total metadata>
+// <used data>/<total data> <held metadata root> ro|rw|out_of_data_space
+// [no_]discard_passdown [error|queue]_if_no_space needs_check|-
+// <metadata low watermark>
+pool_status
+thin_provisioning::parse_pool_status(string const &line)
+{
+	pool_status s;
+	istringstream in(line);
+	vector<string> fields;
+	string f;
+
+	while (in >> f)
+		fields.push_back(f);
+
+	if (fields.size() == 1 && (fields[0] == "Fail" || fields[0] == "Error")) {
+		s.mode_ = pool_status::FAILED;
+		return s;
+	}
+
+	if (fields.size() < 5)
+		bad_status(line);
+
+	s.transaction_id_ = parse_number(line, fields[0]);
+	parse_fraction(line, fields[1], s.used_metadata_blocks_, s.nr_metadata_blocks_);
+	parse_fraction(line, fields[2], s.used_data_blocks_, s.nr_data_blocks_);
+
+	if (fields[3] != "-")
+		s.held_root_ = parse_number(line, fields[3]);
+
+	s.mode_ = parse_mode(line, fields[4]);
+
+	// Older kernels stop before needs_check.
+	for (unsigned i = 5; i < fields.size(); i++)
+		if (fields[i] == "needs_check")
+			s.needs_check_ = true;
+
+	return s;
+}
+
+pool_status
+thin_provisioning::get_pool_status(string const &dm_name)
+{
+	dm_control dm;
+	string type, params;
+
+	dm.table_status(dm_name, type, params);
+	if (type != "thin-pool")
+		throw runtime_error("'" + dm_name + "' is a " + type +
+				    " target, not a thin-pool");
+
+	return parse_pool_status(params);
+}
+
+// The kernel reports its in core counts, so changes it hasn't yet
+// committed will show up as differences too.
+bool
+thin_provisioning::check_pool_status(pool_status const &status,
+				     superblock_detail::superblock const &sb,
+				     ostream &out)
+{
+	if (status.mode_ == pool_status::FAILED) {
+		out << "warning: the pool has failed, its status can't be compared" << endl;
+		return false;
+	}
+
+	block_address nr_metadata_blocks, nr_data_blocks;
+	block_address used_metadata = nr_allocated(sb.metadata_space_map_root_,
+						   nr_metadata_blocks);
+	block_address used_data = nr_allocated(sb.data_space_map_root_,
+					       nr_data_blocks);
+
+	bool ok = true;
+	ok = check_field(out, "transaction id", status.transaction_id_, sb.trans_id_) && ok;
+	ok = check_field(out, "used metadata blocks", status.used_metadata_blocks_, used_metadata) && ok;
+	ok = check_field(out, "metadata blocks", status.nr_metadata_blocks_, nr_metadata_blocks) && ok;
+	ok = check_field(out, "used data blocks", status.used_data_blocks_, used_data) && ok;
+	ok = check_field(out, "data blocks", status.nr_data_blocks_, nr_data_blocks) && ok;
+	ok = check_field(out, "needs_check", status.needs_check_, sb.get_needs_check_flag()) && ok;
+
+	return ok;
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,54 @@
+#ifndef THIN_POOL_STATUS_H
+#define THIN_POOL_STATUS_H
+
+#include "persistent-data/block.h"
+#include "thin-provisioning/superblock.h"
+
+#include <boost/optional.hpp>
+#include <iosfwd>
+#include <string>
+
+//----------------------------------------------------------------
+
+namespace thin_provisioning {
+	// The status of a live thin-pool target, as reported by the
+	// kernel (see Documentation/admin-guide/device-mapper/thin-provisioning.rst).
+	struct pool_status {
+		enum pool_mode {
+			READ_WRITE,
+			READ_ONLY,
+			OUT_OF_DATA_SPACE,
+			FAILED
+		};
+
+		pool_status();
+
+		pool_mode mode_;
+		uint64_t transaction_id_;
+		persistent_data::block_address used_metadata_blocks_;
+		persistent_data::block_address nr_metadata_blocks_;
+		persistent_data::block_address used_data_blocks_;
+		persistent_data::block_address nr_data_blocks_;
+		boost::optional<persistent_data::block_address> held_root_;
+		bool needs_check_;
+	};
+
+	// Parses the status line of a thin-pool target.  A failed pool
+	// reports nothing but its mode.
+	pool_status parse_pool_status(std::string const &line);
+
+	// Asks device-mapper for the status of a live pool, eg,
+	// 'vg-pool-tpool'.
+	pool_status get_pool_status(std::string const &dm_name);
+
+	// Compares the live status with the superblock on the metadata
+	// device, writing a warning for each difference.  Returns true
+	// if they agree.
+	bool check_pool_status(pool_status const &status,
+			       superblock_detail::superblock const &sb,
+			       std::ostream &out);
+}
+
+//----------------------------------------------------------------
+
+#endif
@@ -7,7 +7,8 @@ using namespace thin_provisioning;
 
 //----------------------------------------------------------------
 
-rmap_visitor::rmap_visitor()
+rmap_visitor::rmap_visitor(bool split_on_time)
+	: split_on_time_(split_on_time)
 {
 }
 
@@ -25,7 +26,7 @@ rmap_visitor::visit(btree_path const &path,
 		uint32_t thin_dev = path[0];
 		block_address thin_block = path[1];
 
-		visit_block(thin_dev, thin_block, bt.block_);
+		visit_block(thin_dev, thin_block, bt.block_, bt.time_);
 	}
 }
 
@@ -66,24 +67,26 @@ rmap_visitor::in_regions(block_address b) const
 bool
 rmap_visitor::adjacent_block(rmap_region const &rr,
 			     uint32_t thin_dev, block_address thin_block,
-			     block_address data_block) const
+			     block_address data_block, uint32_t time) const
 {
 	block_address run_length = rr.data_end - rr.data_begin;
 
 	return (rr.thin_dev == thin_dev) &&
 		(data_block == rr.data_end) &&
-		(thin_block == rr.thin_begin + run_length);
+		(thin_block == rr.thin_begin + run_length) &&
+		(!split_on_time_ || time == rr.time);
 }
 
 void
 rmap_visitor::insert_new_region(uint32_t thin_dev, block_address thin_block,
-				block_address data_block)
+				block_address data_block, uint32_t time)
 {
 	rmap_region rr;
 	rr.data_begin = data_block;
 	rr.data_end = data_block + 1;
 	rr.thin_dev = thin_dev;
 	rr.thin_begin = thin_block;
+	rr.time = time;
 
 	current_rmap_ = rr;
 }
@@ -97,18 +100,18 @@ rmap_visitor::push_current()
 
 void
 rmap_visitor::visit_block(uint32_t thin_dev, block_address thin_block,
-			  block_address data_block)
+			  block_address data_block, uint32_t time)
 {
 	if (current_rmap_) {
-		if (adjacent_block(*current_rmap_, thin_dev, thin_block, data_block))
+		if (adjacent_block(*current_rmap_, thin_dev, thin_block, data_block, time))
 			current_rmap_->data_end++;
 		else {
 			push_current();
-			insert_new_region(thin_dev, thin_block, data_block);
+			insert_new_region(thin_dev, thin_block, data_block, time);
 		}
 
 	} else
-		insert_new_region(thin_dev, thin_block, data_block);
+		insert_new_region(thin_dev, thin_block, data_block, time);
 }
 
 //----------------------------------------------------------------
@@ -18,7 +18,10 @@ namespace thin_provisioning {
 	public:
 		typedef run<block_address> region;
 
-		rmap_visitor();
+		// If split_on_time is set, blocks written at different times
+		// are never put in the same region, so each region has a
+		// single time.
+		rmap_visitor(bool split_on_time = false);
 
 		// Specify which regions of the data device you want the rmap for.
 		void add_data_region(region const &r);
@@ -39,6 +42,9 @@ namespace thin_provisioning {
 
 			uint32_t thin_dev;
 			block_address thin_begin;
+
+			// The time of the first block; not compared.
+			uint32_t time;
 		};
 
 		void complete();
@@ -48,14 +54,15 @@ namespace thin_provisioning {
 		bool in_regions(block_address b) const;
 		bool adjacent_block(rmap_region const &rr,
 				    uint32_t thin_dev, block_address thin_block,
-				    block_address data_block) const;
+				    block_address data_block, uint32_t time) const;
 		void insert_new_region(uint32_t thin_dev, block_address thin_block,
-				       block_address data_block);
+				       block_address data_block, uint32_t time);
 		void push_current();
 
 		void visit_block(uint32_t thin_dev, block_address thin_block,
-				 block_address data_block);
+				 block_address data_block, uint32_t time);
 
+		bool split_on_time_;
 		vector<region> regions_;
 
 		boost::optional<rmap_region> current_rmap_;
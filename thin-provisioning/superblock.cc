@@ -43,6 +43,9 @@ superblock_traits::unpack(superblock_disk const &disk, superblock &value)
 	value.compat_flags_ = to_cpu<uint32_t>(disk.compat_flags_);
 	value.compat_ro_flags_ = to_cpu<uint32_t>(disk.compat_ro_flags_);
 	value.incompat_flags_ = to_cpu<uint32_t>(disk.incompat_flags_);
+
+	value.backup_superblock_ = to_cpu<uint64_t>(disk.backup_superblock_);
+	value.labels_root_ = to_cpu<uint64_t>(disk.labels_root_);
 }
 
 void
@@ -77,6 +80,9 @@ superblock_traits::pack(superblock const &value, superblock_disk &disk)
 	disk.compat_flags_ = to_disk<le32>(value.compat_flags_);
 	disk.compat_ro_flags_ = to_disk<le32>(value.compat_ro_flags_);
 	disk.incompat_flags_ = to_disk<le32>(value.incompat_flags_);
+
+	disk.backup_superblock_ = to_disk<le64>(value.backup_superblock_);
+	disk.labels_root_ = to_disk<le64>(value.labels_root_);
 }
 
 //----------------------------------------------------------------
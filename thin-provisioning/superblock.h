@@ -48,6 +48,17 @@ namespace thin_provisioning {
 			le32 compat_flags_;
 			le32 compat_ro_flags_;
 			le32 incompat_flags_;
+
+			/*
+			 * Userland fields.  The kernel updates the superblock
+			 * in place, so these survive its commits.
+			 */
+
+			/* location of the backup copy, or 0 if there isn't one */
+			le64 backup_superblock_;
+
+			/* top block of the device labels, or 0 if there aren't any */
+			le64 labels_root_;
 		} __attribute__ ((packed));
 
 		struct superblock {
@@ -82,6 +93,9 @@ namespace thin_provisioning {
 			uint32_t compat_ro_flags_;
 			uint32_t incompat_flags_;
 
+			uint64_t backup_superblock_;
+			uint64_t labels_root_;
+
 			bool get_needs_check_flag() const;
 			void set_needs_check_flag(bool val = true);
 		};
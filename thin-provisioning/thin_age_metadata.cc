@@ -0,0 +1,320 @@
+// This file is part of the thin-provisioning-tools source.
+//
+// thin-provisioning-tools is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// thin-provisioning-tools is distributed in the hope that it will be
+// useful, but WITHOUT ANY WARRANTY; without even the implied warranty
+// of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with thin-provisioning-tools.  If not, see
+// <http://www.gnu.org/licenses/>.
+
+#include "base/output_file_requirements.h"
+#include "base/sequence_generator.h"
+#include "persistent-data/file_utils.h"
+#include "thin-provisioning/commands.h"
+#include "thin-provisioning/thin_pool.h"
+#include "version.h"
+
+#include <boost/optional.hpp>
+#include <deque>
+#include <getopt.h>
+#include <random>
+#include <unistd.h>
+
+using namespace boost;
+using namespace thin_provisioning;
+
+//----------------------------------------------------------------
+
+namespace {
+	struct flags {
+		flags()
+			: nr_cycles(1000),
+			  nr_origins(1),
+			  overwrite_percent(10),
+			  max_snaps(16),
+			  commit_interval(1),
+			  stats_interval(100)
+		{
+		}
+
+		bool check_conformance();
+
+		boost::optional<string> output;
+		boost::optional<base::sector_t> thin_size;
+		uint64_t nr_cycles;
+		uint64_t nr_origins;
+		unsigned overwrite_percent;
+		uint64_t max_snaps;
+		uint64_t commit_interval;
+		uint64_t stats_interval;
+		boost::optional<uint64_t> seed;
+	};
+
+	bool flags::check_conformance() {
+		if (!output) {
+			cerr << "No output file provided." << endl;
+			return false;
+		}
+
+		if (!thin_size) {
+			cerr << "No thin device size specified" << endl;
+			return false;
+		}
+
+		if (!nr_origins) {
+			cerr << "At least one origin is needed" << endl;
+			return false;
+		}
+
+		if (overwrite_percent > 100) {
+			cerr << "Overwrite percentage must be between 0 and 100" << endl;
+			return false;
+		}
+
+		if (!commit_interval || !stats_interval) {
+			cerr << "Intervals must be non-zero" << endl;
+			return false;
+		}
+
+		check_output_file_requirements(*output);
+
+		return true;
+	}
+
+	//--------------------------------
+
+	// Ages a pool by running the life cycle of a typical snapshot
+	// user: snapshot an origin, keep writing to the origin, and
+	// drop the oldest snapshots once there are too many.  Each
+	// overwrite breaks sharing, so the mapping trees and the space
+	// maps fragment much as they do on a long lived pool.
+	class pool_ager {
+	public:
+		pool_ager(flags const &fs, thin_pool::ptr pool)
+			: fs_(fs),
+			  pool_(pool),
+			  nr_thin_blocks_(base::div_up<base::sector_t>(*fs.thin_size,
+								       pool->get_data_block_size())),
+			  next_dev_(fs.nr_origins),
+			  rand_engine_(!fs.seed ? base::default_seed() : *fs.seed) {
+		}
+
+		void run() {
+			create_origins();
+
+			cout << "cycle,transaction,metadata_blocks,data_blocks,snapshots" << endl;
+			print_stats(0);
+
+			for (uint64_t cycle = 1; cycle <= fs_.nr_cycles; cycle++) {
+				age_once();
+
+				if (cycle % fs_.commit_interval == 0)
+					commit();
+
+				if (cycle % fs_.stats_interval == 0)
+					print_stats(cycle);
+			}
+
+			commit();
+			if (fs_.nr_cycles % fs_.stats_interval)
+				print_stats(fs_.nr_cycles);
+		}
+
+	private:
+		void create_origins() {
+			for (thin_dev_t dev = 0; dev < fs_.nr_origins; dev++) {
+				pool_->create_thin(dev);
+
+				thin::ptr td = pool_->open_thin(dev);
+				for (block_address b = 0; b < nr_thin_blocks_; b++)
+					write_block(td, b);
+				pool_->close_thin(td);
+			}
+
+			commit();
+		}
+
+		void age_once() {
+			std::uniform_int_distribution<thin_dev_t> pick_origin(0, fs_.nr_origins - 1);
+			thin_dev_t origin = pick_origin(rand_engine_);
+
+			pool_->create_snap(next_dev_, origin);
+			snaps_.push_back(next_dev_++);
+
+			overwrite(origin);
+
+			while (snaps_.size() > fs_.max_snaps) {
+				pool_->del(snaps_.front());
+				snaps_.pop_front();
+			}
+		}
+
+		void overwrite(thin_dev_t origin) {
+			block_address nr_writes = nr_thin_blocks_ * fs_.overwrite_percent / 100;
+			std::uniform_int_distribution<block_address> pick_block(0, nr_thin_blocks_ - 1);
+
+			thin::ptr td = pool_->open_thin(origin);
+			for (block_address i = 0; i < nr_writes; i++)
+				write_block(td, pick_block(rand_engine_));
+			pool_->close_thin(td);
+		}
+
+		void write_block(thin::ptr td, block_address b) {
+			if (!pool_->get_nr_free_data_blocks())
+				throw runtime_error("out of data space, try fewer snapshots "
+						    "or a bigger data device");
+
+			process_write(td, pool_, b * pool_->get_data_block_size());
+		}
+
+		void commit() {
+			pool_->set_transaction_id(pool_->get_transaction_id() + 1);
+			pool_->commit();
+		}
+
+		void print_stats(uint64_t cycle) {
+			cout << cycle << ","
+			     << pool_->get_transaction_id() << ","
+			     << pool_->get_metadata_dev_size() - pool_->get_nr_free_metadata_blocks() << ","
+			     << pool_->get_data_dev_size() - pool_->get_nr_free_data_blocks() << ","
+			     << snaps_.size() << endl;
+		}
+
+		flags const &fs_;
+		thin_pool::ptr pool_;
+		block_address nr_thin_blocks_;
+		thin_dev_t next_dev_;
+		std::deque<thin_dev_t> snaps_;
+		std::mt19937 rand_engine_;
+	};
+
+	int age_metadata(flags const &fs) {
+		block_manager::ptr bm = open_bm(*fs.output, block_manager::READ_WRITE);
+		thin_pool::ptr pool(new thin_pool(bm));
+
+		try {
+			pool_ager ager(fs, pool);
+			ager.run();
+		} catch (std::exception &e) {
+			cerr << e.what() << endl;
+			return 1;
+		}
+
+		return 0;
+	}
+}
+
+//----------------------------------------------------------------
+
+thin_age_metadata_cmd::thin_age_metadata_cmd()
+	: command("thin_age_metadata")
+{
+}
+
+void
+thin_age_metadata_cmd::usage(std::ostream &out) const
+{
+	out << "Usage: " << get_name() << " [options]\n"
+	    << "Options:\n"
+	    << "  {-h|--help}\n"
+	    << "  {-o|--output} <output device or file>\n"
+	    << "  {--cycles} <nr. of snapshot/overwrite/delete cycles>\n"
+	    << "  {--origins} <nr. of origin devices>\n"
+	    << "  {--thin-size} <origin size in sectors, or with a unit suffix>\n"
+	    << "  {--overwrite} <percentage of the origin written per cycle>\n"
+	    << "  {--max-snaps} <nr. of snapshots kept before deleting the oldest>\n"
+	    << "  {--commit-interval} <nr. of cycles per transaction>\n"
+	    << "  {--stats-interval} <nr. of cycles between stats lines>\n"
+	    << "  {--seed} <random seed>\n"
+	    << "  {-V|--version}" << endl;
+}
+
+int
+thin_age_metadata_cmd::run(int argc, char **argv)
+{
+	int c;
+	struct flags fs;
+	const char *shortopts = "ho:V";
+	const struct option longopts[] = {
+		{ "help", no_argument, NULL, 'h' },
+		{ "output", required_argument, NULL, 'o' },
+		{ "cycles", required_argument, NULL, 1 },
+		{ "origins", required_argument, NULL, 2 },
+		{ "thin-size", required_argument, NULL, 3 },
+		{ "overwrite", required_argument, NULL, 4 },
+		{ "max-snaps", required_argument, NULL, 5 },
+		{ "commit-interval", required_argument, NULL, 6 },
+		{ "stats-interval", required_argument, NULL, 7 },
+		{ "seed", required_argument, NULL, 8 },
+		{ "version", no_argument, NULL, 'V' },
+		{ NULL, no_argument, NULL, 0 }
+	};
+
+	while ((c = getopt_long(argc, argv, shortopts, longopts, NULL)) != -1) {
+		switch(c) {
+		case 'h':
+			usage(cout);
+			return 0;
+
+		case 'o':
+			fs.output = optarg;
+			break;
+
+		case 1:
+			fs.nr_cycles = parse_uint64(optarg, "cycles");
+			break;
+
+		case 2:
+			fs.nr_origins = parse_uint64(optarg, "origins");
+			break;
+
+		case 3:
+			fs.thin_size = parse_sectors(optarg, "thin_size");
+			break;
+
+		case 4:
+			fs.overwrite_percent = parse_uint64(optarg, "overwrite");
+			break;
+
+		case 5:
+			fs.max_snaps = parse_uint64(optarg, "max_snaps");
+			break;
+
+		case 6:
+			fs.commit_interval = parse_uint64(optarg, "commit_interval");
+			break;
+
+		case 7:
+			fs.stats_interval = parse_uint64(optarg, "stats_interval");
+			break;
+
+		case 8:
+			fs.seed = parse_uint64(optarg, "seed");
+			break;
+
+		case 'V':
+			cout << THIN_PROVISIONING_TOOLS_VERSION << endl;
+			return 0;
+
+		default:
+			usage(cerr);
+			return 1;
+		}
+	}
+
+	if (!fs.check_conformance()) {
+		usage(cerr);
+		return 1;
+	}
+
+	return age_metadata(fs);
+}
+
+//----------------------------------------------------------------
@@ -106,6 +106,8 @@ namespace {
 			field(*f, "compat_flags", sb.compat_flags_);
 			field(*f, "compat_ro_flags", sb.compat_ro_flags_);
 			field(*f, "incompat_flags", sb.incompat_flags_);
+			field(*f, "backup_superblock", sb.backup_superblock_);
+			field(*f, "labels_root", sb.labels_root_);
 
 			f->output(out, 0);
 		}
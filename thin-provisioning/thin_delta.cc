@@ -1,17 +1,30 @@
 #include <boost/lexical_cast.hpp>
 #include <boost/optional.hpp>
+#include <algorithm>
+#include <cstring>
+#include <deque>
+#include <future>
 #include <getopt.h>
 #include <iostream>
 #include <libgen.h>
+#include <limits>
+#include <map>
+#include <memory>
+#include <set>
+#include <sstream>
+#include <thread>
 
 #include "version.h"
 
+#include "base/exit_codes.h"
 #include "base/indented_stream.h"
 #include "base/run.h"
-#include "persistent-data/data-structures/btree_damage_visitor.h"
+#include "persistent-data/data-structures/btree.h"
+#include "persistent-data/errors.h"
 #include "persistent-data/space-maps/core.h"
 #include "persistent-data/space-maps/disk.h"
 #include "persistent-data/file_utils.h"
+#include "persistent-data/validators.h"
 #include "thin-provisioning/superblock.h"
 #include "thin-provisioning/mapping_tree.h"
 #include "thin-provisioning/metadata.h"
@@ -23,21 +36,49 @@ using namespace thin_provisioning;
 //----------------------------------------------------------------
 
 namespace {
+	// A side of a diff, given as either a thin id or the root of a
+	// mapping tree.
+	struct dev_ref {
+		boost::optional<uint64_t> snap;
+		boost::optional<uint64_t> root;
+	};
+
+	enum output_units {
+		UNITS_BLOCKS,
+		UNITS_SECTORS,
+		UNITS_BYTES
+	};
+
+	enum output_format {
+		FORMAT_XML,
+		FORMAT_CSV
+	};
+
 	struct flags {
 		flags()
 			: verbose(false),
-			  use_metadata_snap(false) {
+			  use_metadata_snap(false),
+			  ancestry(false),
+			  stats(false),
+			  units(UNITS_BLOCKS),
+			  format(FORMAT_XML),
+			  units_given(false) {
 		}
 
 		bool verbose;
 		bool use_metadata_snap;
+		bool ancestry;
+		bool stats;
+		output_units units;
+		output_format format;
+		bool units_given;
 
 		boost::optional<string> dev;
 		boost::optional<uint64_t> metadata_snap;
-		boost::optional<uint64_t> snap1;
-		boost::optional<uint64_t> snap2;
-		boost::optional<uint64_t> root1;
-		boost::optional<uint64_t> root2;
+
+		// The i'th left device is diffed against the i'th right.
+		vector<dev_ref> lefts;
+		vector<dev_ref> rights;
 	};
 
 	//--------------------------------
@@ -46,16 +87,22 @@ namespace {
 		mapping()
 			: vbegin_(0),
 			  dbegin_(0),
-			  len_(0) {
+			  len_(0),
+			  fresh_(false) {
 		}
 
-		mapping(uint64_t vbegin, uint64_t dbegin, uint64_t len)
+		mapping(uint64_t vbegin, uint64_t dbegin, uint64_t len, bool fresh)
 			: vbegin_(vbegin),
 			  dbegin_(dbegin),
-			  len_(len) {
+			  len_(len),
+			  fresh_(fresh) {
 		}
 
 		uint64_t vbegin_, dbegin_, len_;
+
+		// Written since the snapshot was taken.  Only tracked for
+		// --ancestry.
+		bool fresh_;
 	};
 
 	//--------------------------------
@@ -66,7 +113,8 @@ namespace {
 		mapping_stream(Container const &c)
 		: it_(c.begin()),
 		  end_(c.end()) {
-			m_ = *it_;
+			if (it_ != end_)
+				m_ = *it_;
 		}
 
 		mapping const &get_mapping() const {
@@ -86,7 +134,8 @@ namespace {
 
 			if (delta == m_.len_) {
 				++it_;
-				m_ = *it_;
+				if (it_ != end_)
+					m_ = *it_;
 
 			} else {
 				m_.vbegin_ += delta;
@@ -108,12 +157,15 @@ namespace {
 	// Builds up an in core rep of the mappings for a device.
 	class mapping_recorder {
 	public:
-		mapping_recorder() {
+		// Mappings with a time of at least 'since' are marked as
+		// fresh, and a run of mappings is split where that changes.
+		mapping_recorder(boost::optional<uint32_t> since)
+			: since_(since) {
 			no_range();
 		}
 
-		void visit(btree_path const &path, mapping_tree_detail::block_time const &bt) {
-			record(path[0], bt.block_);
+		void visit(uint64_t oblock, mapping_tree_detail::block_time const &bt) {
+			record(oblock, bt.block_, since_ && bt.time_ >= *since_);
 		}
 
 		void complete() {
@@ -138,9 +190,10 @@ namespace {
 			dend_++;
 		}
 
-		void begin_range(uint64_t oblock, uint64_t dblock) {
+		void begin_range(uint64_t oblock, uint64_t dblock, bool fresh) {
 			obegin_ = oend_ = oblock;
 			dbegin_ = dend_ = dblock;
+			fresh_ = fresh;
 			inc_range();
 		}
 
@@ -148,41 +201,154 @@ namespace {
 			return oend_ != obegin_;
 		}
 
-		bool continues_range(uint64_t oblock, uint64_t dblock) {
-			return (oblock == oend_) && (dblock == dend_);
+		bool continues_range(uint64_t oblock, uint64_t dblock, bool fresh) {
+			return (oblock == oend_) && (dblock == dend_) && (fresh == fresh_);
 		}
 
 		void push_range() {
-			mapping m(obegin_, dbegin_, oend_ - obegin_);
+			mapping m(obegin_, dbegin_, oend_ - obegin_, fresh_);
 			mappings_.push_back(m);
 		}
 
-		void record(uint64_t oblock, uint64_t dblock) {
+		void record(uint64_t oblock, uint64_t dblock, bool fresh) {
 			if (!range_in_progress())
-				begin_range(oblock, dblock);
+				begin_range(oblock, dblock, fresh);
 
-			else if (!continues_range(oblock, dblock)) {
+			else if (!continues_range(oblock, dblock, fresh)) {
 				push_range();
-				begin_range(oblock, dblock);
+				begin_range(oblock, dblock, fresh);
 			} else
 				inc_range();
 		}
 
+		boost::optional<uint32_t> since_;
+
 		uint64_t obegin_, oend_;
 		uint64_t dbegin_, dend_;
+		bool fresh_;
 
 		mapping_deque mappings_;
 	};
 
 	//--------------------------------
 
-	class damage_visitor {
-	public:
-		virtual void visit(btree_path const &path, btree_detail::damage const &d) {
-			throw std::runtime_error("damage in mapping tree, please run thin_check");
+	// The delta is worked out a range of thin blocks at a time, with
+	// several ranges in flight.  Each range only reads the parts of
+	// the two trees that overlap it.
+
+	struct key_range {
+		key_range(uint64_t begin, uint64_t end)
+			: begin_(begin),
+			  end_(end) {
 		}
+
+		uint64_t begin_, end_;
 	};
 
+	typedef btree_detail::node_ref<block_traits> internal_node;
+	typedef btree_detail::node_ref<mapping_tree_detail::block_traits> leaf_node;
+
+	void raise_mapping_damage() {
+		throw base::metadata_damage("damage in mapping tree, please run thin_check");
+	}
+
+	// Records the mappings of the subtree at b that fall within kr.
+	// Child i of an internal node covers [key i, key i + 1).
+	void walk_range(transaction_manager &tm, bcache::validator::ptr v,
+			block_address b, key_range const &kr,
+			mapping_recorder &mr) {
+		block_manager::read_ref blk = tm.read_lock(b, v);
+		internal_node n = btree_detail::to_node<block_traits>(blk);
+
+		if (n.get_type() == btree_detail::INTERNAL) {
+			unsigned nr_entries = n.get_nr_entries();
+			for (unsigned i = 0; i < nr_entries; i++) {
+				if (n.key_at(i) >= kr.end_)
+					break;
+
+				if (i + 1 < nr_entries) {
+					if (n.key_at(i + 1) <= n.key_at(i))
+						raise_mapping_damage();
+
+					if (n.key_at(i + 1) <= kr.begin_)
+						continue;
+				}
+
+				walk_range(tm, v, n.value_at(i), kr, mr);
+			}
+
+		} else {
+			leaf_node l = btree_detail::to_node<mapping_tree_detail::block_traits>(blk);
+			unsigned nr_entries = l.get_nr_entries();
+			for (unsigned i = 0; i < nr_entries; i++) {
+				uint64_t k = l.key_at(i);
+				if (i > 0 && k <= l.key_at(i - 1))
+					raise_mapping_damage();
+
+				if (k < kr.begin_)
+					continue;
+
+				if (k >= kr.end_)
+					break;
+
+				mr.visit(k, l.value_at(i));
+			}
+		}
+	}
+
+	// Gathers keys from the top levels of a tree, stopping at the
+	// first level with at least nr_keys of them, to use as range
+	// boundaries.
+	void collect_split_keys(transaction_manager &tm, bcache::validator::ptr v,
+				block_address root, unsigned nr_keys,
+				set<uint64_t> &keys) {
+		vector<block_address> level(1, root);
+
+		while (!level.empty()) {
+			vector<block_address> next;
+			vector<block_address>::const_iterator it;
+			for (it = level.begin(); it != level.end(); ++it) {
+				block_manager::read_ref blk = tm.read_lock(*it, v);
+				internal_node n = btree_detail::to_node<block_traits>(blk);
+				if (n.get_type() != btree_detail::INTERNAL)
+					return;
+
+				for (unsigned i = 0; i < n.get_nr_entries(); i++) {
+					keys.insert(n.key_at(i));
+					next.push_back(n.value_at(i));
+				}
+			}
+
+			if (next.size() >= nr_keys)
+				return;
+
+			level.swap(next);
+		}
+	}
+
+	vector<key_range> split_key_space(transaction_manager &tm,
+					  set<block_address> const &roots,
+					  unsigned nr_ranges) {
+		bcache::validator::ptr v = create_btree_node_validator();
+		set<uint64_t> keys;
+		set<block_address>::const_iterator root;
+		for (root = roots.begin(); root != roots.end(); ++root)
+			collect_split_keys(tm, v, *root, nr_ranges, keys);
+
+		vector<uint64_t> bounds(keys.begin(), keys.end());
+		size_t stride = max<size_t>(1, bounds.size() / nr_ranges);
+
+		vector<key_range> ranges;
+		uint64_t begin = 0;
+		for (size_t i = stride; i < bounds.size(); i += stride) {
+			ranges.push_back(key_range(begin, bounds[i]));
+			begin = bounds[i];
+		}
+		ranges.push_back(key_range(begin, numeric_limits<uint64_t>::max()));
+
+		return ranges;
+	}
+
 	//--------------------------------
 
 	class diff_emitter {
@@ -191,6 +357,8 @@ namespace {
 		: out_(out) {
 		}
 
+		virtual ~diff_emitter() {}
+
 		virtual void left_only(uint64_t vbegin, uint64_t dbegin, uint64_t len) = 0;
 		virtual void right_only(uint64_t vbegin, uint64_t dbegin, uint64_t len) = 0;
 		virtual void blocks_differ(uint64_t vbegin, uint64_t left_dbegin, uint64_t right_dbegin, uint64_t len) = 0;
@@ -405,9 +573,425 @@ namespace {
 
 	//----------------------------------------------------------------
 
+	struct diff_event {
+		enum event_type {
+			LEFT_ONLY,
+			RIGHT_ONLY,
+			DIFFER,
+			SAME
+		};
+
+		diff_event(event_type t, uint64_t vbegin,
+			   uint64_t left_dbegin, uint64_t right_dbegin,
+			   uint64_t len,
+			   bool left_fresh = false, bool right_fresh = false)
+			: type_(t),
+			  vbegin_(vbegin),
+			  left_dbegin_(left_dbegin),
+			  right_dbegin_(right_dbegin),
+			  len_(len),
+			  left_fresh_(left_fresh),
+			  right_fresh_(right_fresh) {
+		}
+
+		// Does e carry on where this event leaves off?  Only the
+		// sides that are mapped have to follow on.
+		bool continued_by(diff_event const &e) const {
+			if (e.type_ != type_ || e.vbegin_ != vbegin_ + len_)
+				return false;
+
+			if (e.left_fresh_ != left_fresh_ || e.right_fresh_ != right_fresh_)
+				return false;
+
+			if (type_ != RIGHT_ONLY && e.left_dbegin_ != left_dbegin_ + len_)
+				return false;
+
+			if (type_ != LEFT_ONLY && e.right_dbegin_ != right_dbegin_ + len_)
+				return false;
+
+			return true;
+		}
+
+		void emit(diff_emitter &e) const {
+			switch (type_) {
+			case LEFT_ONLY:
+				e.left_only(vbegin_, left_dbegin_, len_);
+				break;
+
+			case RIGHT_ONLY:
+				e.right_only(vbegin_, right_dbegin_, len_);
+				break;
+
+			case DIFFER:
+				e.blocks_differ(vbegin_, left_dbegin_, right_dbegin_, len_);
+				break;
+
+			case SAME:
+				e.blocks_same(vbegin_, left_dbegin_, len_);
+				break;
+			}
+		}
+
+		event_type type_;
+		uint64_t vbegin_, left_dbegin_, right_dbegin_, len_;
+		bool left_fresh_, right_fresh_;
+	};
+
+	typedef vector<diff_event> event_vector;
+
+	// Records the diff of a single range, so it can be emitted once
+	// the ranges before it are done.
+	class event_recorder {
+	public:
+		void left_only(mapping const &m, uint64_t len) {
+			events_.push_back(diff_event(diff_event::LEFT_ONLY, m.vbegin_, m.dbegin_, 0, len,
+						     m.fresh_, false));
+		}
+
+		void right_only(mapping const &m, uint64_t len) {
+			events_.push_back(diff_event(diff_event::RIGHT_ONLY, m.vbegin_, 0, m.dbegin_, len,
+						     false, m.fresh_));
+		}
+
+		void blocks_differ(mapping const &l, mapping const &r, uint64_t len) {
+			events_.push_back(diff_event(diff_event::DIFFER, l.vbegin_, l.dbegin_, r.dbegin_, len,
+						     l.fresh_, r.fresh_));
+		}
+
+		void blocks_same(mapping const &l, mapping const &r, uint64_t len) {
+			events_.push_back(diff_event(diff_event::SAME, l.vbegin_, l.dbegin_, l.dbegin_, len,
+						     l.fresh_, r.fresh_));
+		}
+
+		void complete() {
+		}
+
+		event_vector const &get_events() const {
+			return events_;
+		}
+
+	private:
+		event_vector events_;
+	};
+
+	// Takes the events from every range, in order.
+	class event_sink {
+	public:
+		virtual ~event_sink() {}
+		virtual void add(diff_event const &ev) = 0;
+		virtual void complete() = 0;
+	};
+
+	// Runs that straddle a range boundary are split in two; this
+	// joins them up again, so the output doesn't depend on how the
+	// work was divided.
+	class event_merger : public event_sink {
+	public:
+		event_merger(diff_emitter &e)
+			: e_(e) {
+		}
+
+		void add(diff_event const &ev) {
+			if (pending_ && pending_->continued_by(ev)) {
+				pending_->len_ += ev.len_;
+				return;
+			}
+
+			if (pending_)
+				pending_->emit(e_);
+
+			pending_ = ev;
+		}
+
+		void complete() {
+			if (pending_)
+				pending_->emit(e_);
+
+			e_.complete();
+		}
+
+	private:
+		diff_emitter &e_;
+		boost::optional<diff_event> pending_;
+	};
+
+	// Converts the block numbers and lengths of each event to
+	// sectors or bytes, before passing it on.
+	class unit_scaler : public event_sink {
+	public:
+		unit_scaler(event_sink &sink, uint64_t multiplier)
+			: sink_(sink),
+			  multiplier_(multiplier) {
+		}
+
+		void add(diff_event const &ev) {
+			diff_event scaled(ev);
+			scaled.vbegin_ *= multiplier_;
+			scaled.left_dbegin_ *= multiplier_;
+			scaled.right_dbegin_ *= multiplier_;
+			scaled.len_ *= multiplier_;
+			sink_.add(scaled);
+		}
+
+		void complete() {
+			sink_.complete();
+		}
+
+	private:
+		event_sink &sink_;
+		uint64_t multiplier_;
+	};
+
+	// Groups the differences between a snapshot and its origin by
+	// which side changed since the snapshot was taken, ie, the
+	// changes that merging the snapshot, or rolling the origin back
+	// to it, would keep or throw away.  A block written since the
+	// snapshot is fresh.  A block only one side maps, which isn't
+	// fresh, was shared and has since been discarded by the other.
+	class ancestry_grouper : public event_sink {
+	public:
+		ancestry_grouper(indented_stream &out, bool left_is_origin,
+				 uint64_t origin, uint64_t snapshot, uint32_t snap_time)
+			: out_(out),
+			  left_is_origin_(left_is_origin),
+			  origin_(origin),
+			  snapshot_(snapshot),
+			  snap_time_(snap_time) {
+		}
+
+		void add(diff_event const &ev) {
+			if (ev.type_ == diff_event::SAME)
+				return;
+
+			bool left_changed = false, right_changed = false;
+			switch (ev.type_) {
+			case diff_event::LEFT_ONLY:
+				left_changed = ev.left_fresh_;
+				right_changed = !ev.left_fresh_;
+				break;
+
+			case diff_event::RIGHT_ONLY:
+				left_changed = !ev.right_fresh_;
+				right_changed = ev.right_fresh_;
+				break;
+
+			case diff_event::DIFFER:
+				left_changed = ev.left_fresh_;
+				right_changed = ev.right_fresh_;
+				break;
+
+			case diff_event::SAME:
+				break;
+			}
+
+			bool origin_changed = left_is_origin_ ? left_changed : right_changed;
+			bool snap_changed = left_is_origin_ ? right_changed : left_changed;
+
+			group g;
+			if (origin_changed && snap_changed)
+				g = BOTH_CHANGED;
+			else if (origin_changed)
+				g = ORIGIN_CHANGED;
+			else if (snap_changed)
+				g = SNAPSHOT_CHANGED;
+			else
+				g = UNATTRIBUTED;
+
+			vector<grouped_range> &ranges = groups_[g];
+			if (!ranges.empty() &&
+			    ranges.back().type_ == ev.type_ &&
+			    ranges.back().vbegin_ + ranges.back().len_ == ev.vbegin_)
+				ranges.back().len_ += ev.len_;
+			else
+				ranges.push_back(grouped_range(ev.type_, ev.vbegin_, ev.len_));
+		}
+
+		void complete() {
+			out_.indent();
+			out_ << "<ancestry origin=\"" << origin_ << "\""
+			     << " snapshot=\"" << snapshot_ << "\""
+			     << " snap_time=\"" << snap_time_ << "\">\n";
+			out_.inc();
+
+			for (unsigned g = 0; g < NR_GROUPS; g++)
+				emit_group(static_cast<group>(g));
+
+			out_.dec();
+			out_.indent();
+			out_ << "</ancestry>\n";
+		}
+
+	private:
+		enum group {
+			ORIGIN_CHANGED,
+			SNAPSHOT_CHANGED,
+			BOTH_CHANGED,
+			UNATTRIBUTED,
+			NR_GROUPS
+		};
+
+		struct grouped_range {
+			grouped_range(diff_event::event_type t, uint64_t vbegin, uint64_t len)
+				: type_(t),
+				  vbegin_(vbegin),
+				  len_(len) {
+			}
+
+			diff_event::event_type type_;
+			uint64_t vbegin_, len_;
+		};
+
+		static char const *group_name(group g) {
+			switch (g) {
+			case ORIGIN_CHANGED:
+				return "origin_changed";
+
+			case SNAPSHOT_CHANGED:
+				return "snapshot_changed";
+
+			case BOTH_CHANGED:
+				return "both_changed";
+
+			default:
+				return "unattributed";
+			}
+		}
+
+		static char const *type_name(diff_event::event_type t) {
+			switch (t) {
+			case diff_event::LEFT_ONLY:
+				return "left_only";
+
+			case diff_event::RIGHT_ONLY:
+				return "right_only";
+
+			case diff_event::DIFFER:
+				return "different";
+
+			default:
+				return "same";
+			}
+		}
+
+		void emit_group(group g) {
+			vector<grouped_range> const &ranges = groups_[g];
+			if (ranges.empty())
+				return;
+
+			out_.indent();
+			out_ << "<" << group_name(g) << ">\n";
+			out_.inc();
+
+			vector<grouped_range>::const_iterator it;
+			for (it = ranges.begin(); it != ranges.end(); ++it) {
+				out_.indent();
+				out_ << "<" << type_name(it->type_)
+				     << " begin=\"" << it->vbegin_ << "\""
+				     << " length=\"" << it->len_ << "\"/>\n";
+			}
+
+			out_.dec();
+			out_.indent();
+			out_ << "</" << group_name(g) << ">\n";
+		}
+
+		indented_stream &out_;
+		bool left_is_origin_;
+		uint64_t origin_, snapshot_;
+		uint32_t snap_time_;
+		vector<grouped_range> groups_[NR_GROUPS];
+	};
+
+	// The number of blocks of each kind, for --stats.  The left
+	// device is taken to be the older, so blocks only the right maps
+	// were added.
+	struct delta_stats {
+		delta_stats()
+			: added_(0),
+			  removed_(0),
+			  changed_(0),
+			  shared_(0) {
+		}
+
+		uint64_t added_, removed_, changed_, shared_;
+	};
+
+	class stats_counter : public event_sink {
+	public:
+		void add(diff_event const &ev) {
+			switch (ev.type_) {
+			case diff_event::LEFT_ONLY:
+				stats_.removed_ += ev.len_;
+				break;
+
+			case diff_event::RIGHT_ONLY:
+				stats_.added_ += ev.len_;
+				break;
+
+			case diff_event::DIFFER:
+				stats_.changed_ += ev.len_;
+				break;
+
+			case diff_event::SAME:
+				stats_.shared_ += ev.len_;
+				break;
+			}
+		}
+
+		void complete() {
+		}
+
+		delta_stats const &get_stats() const {
+			return stats_;
+		}
+
+	private:
+		delta_stats stats_;
+	};
+
+	void emit_stats(indented_stream &out, delta_stats const &stats) {
+		out.indent();
+		out << "<stats added=\"" << stats.added_ << "\""
+		    << " removed=\"" << stats.removed_ << "\""
+		    << " changed=\"" << stats.changed_ << "\""
+		    << " shared=\"" << stats.shared_ << "\"/>\n";
+	}
+
+	// A thin id, or root:<block> for a side given by --root1 or
+	// --root2.
+	string csv_dev_name(dev_ref const &d) {
+		ostringstream out;
+		if (d.snap)
+			out << *d.snap;
+		else
+			out << "root:" << *d.root;
+		return out.str();
+	}
+
+	void emit_csv_header(ostream &out) {
+		out << "left,right,"
+		    << "added_blocks,added_bytes,"
+		    << "removed_blocks,removed_bytes,"
+		    << "changed_blocks,changed_bytes,"
+		    << "shared_blocks,shared_bytes\n";
+	}
+
+	void emit_csv_row(ostream &out, dev_ref const &left, dev_ref const &right,
+			  delta_stats const &stats, uint64_t block_bytes) {
+		out << csv_dev_name(left) << ","
+		    << csv_dev_name(right) << ","
+		    << stats.added_ << "," << stats.added_ * block_bytes << ","
+		    << stats.removed_ << "," << stats.removed_ * block_bytes << ","
+		    << stats.changed_ << "," << stats.changed_ * block_bytes << ","
+		    << stats.shared_ << "," << stats.shared_ * block_bytes << "\n";
+	}
+
+	//----------------------------------------------------------------
+
+	template <typename Emitter>
 	void dump_diff(mapping_deque const &left,
 		       mapping_deque const &right,
-		       diff_emitter &e) {
+		       Emitter &e) {
 
 		// We iterate through both sets of mappings in parallel
 		// noting any differences.
@@ -420,23 +1004,23 @@ namespace {
 
 			if (lm.vbegin_ < rm.vbegin_) {
 				auto delta = min<uint64_t>(lm.len_, rm.vbegin_ - lm.vbegin_);
-				e.left_only(lm.vbegin_, lm.dbegin_, delta);
+				e.left_only(lm, delta);
 				ls.consume(delta);
 
 			} else if (lm.vbegin_ > rm.vbegin_) {
 				auto delta = min<uint64_t>(rm.len_, lm.vbegin_ - rm.vbegin_);
-				e.right_only(rm.vbegin_, rm.dbegin_, delta);
+				e.right_only(rm, delta);
 				rs.consume(delta);
 
 			} else if (lm.dbegin_ != rm.dbegin_) {
 				auto delta = min<uint64_t>(lm.len_, rm.len_);
-				e.blocks_differ(lm.vbegin_, lm.dbegin_, rm.dbegin_, delta);
+				e.blocks_differ(lm, rm, delta);
 				ls.consume(delta);
 				rs.consume(delta);
 
 			} else {
 				auto delta = min<uint64_t>(lm.len_, rm.len_);
-				e.blocks_same(lm.vbegin_, lm.dbegin_, delta);
+				e.blocks_same(lm, rm, delta);
 				ls.consume(delta);
 				rs.consume(delta);
 			}
@@ -444,13 +1028,13 @@ namespace {
 
 		while (ls.more_mappings()) {
 			auto &lm = ls.get_mapping();
-			e.left_only(lm.vbegin_, lm.dbegin_, lm.len_);
+			e.left_only(lm, lm.len_);
 			ls.consume(lm.len_);
 		}
 
 		while (rs.more_mappings()) {
 			auto &rm = rs.get_mapping();
-			e.right_only(rm.vbegin_, rm.dbegin_, rm.len_);
+			e.right_only(rm, rm.len_);
 			rs.consume(rm.len_);
 		}
 
@@ -487,22 +1071,27 @@ namespace {
 
 	// FIXME: always show the blocknr?
 	void begin_diff(indented_stream &out,
-			boost::optional<uint64_t> snap1,
-			boost::optional<uint64_t> root1,
-			boost::optional<uint64_t> snap2,
-			boost::optional<uint64_t> root2) {
+			dev_ref const &left, dev_ref const &right,
+			output_units units) {
 		out.indent();
 		out << "<diff";
 
-		if (snap1)
-			out << " left=\"" << *snap1 << "\"";
-		else if (root1)
-			out << " left_root=\"" << *root1 << "\"";
+		if (left.snap)
+			out << " left=\"" << *left.snap << "\"";
+		else if (left.root)
+			out << " left_root=\"" << *left.root << "\"";
 
-		if (snap2)
-			out << " right=\"" << *snap2 << "\"";
-		else if (root2)
-			out << " right_root=\"" << *root2 << "\"";
+		if (right.snap)
+			out << " right=\"" << *right.snap << "\"";
+		else if (right.root)
+			out << " right_root=\"" << *right.root << "\"";
+
+		// Blocks, the default, aren't labelled so the output is
+		// unchanged for existing scripts.
+		if (units == UNITS_SECTORS)
+			out << " units=\"sectors\"";
+		else if (units == UNITS_BYTES)
+			out << " units=\"bytes\"";
 
 		out << ">\n";
 		out.inc();
@@ -514,78 +1103,296 @@ namespace {
 		out << "</diff>\n";
 	}
 
+	// A mapping tree to walk, and the time from which its mappings
+	// count as fresh for --ancestry.  A device diffed against
+	// several others is only walked once per range, unless the
+	// times differ.
+	struct tree_walk {
+		tree_walk(block_address root, boost::optional<uint32_t> since)
+			: root_(root),
+			  since_(since) {
+		}
+
+		bool operator <(tree_walk const &rhs) const {
+			if (root_ != rhs.root_)
+				return root_ < rhs.root_;
+			return since_ < rhs.since_;
+		}
+
+		block_address root_;
+		boost::optional<uint32_t> since_;
+	};
+
+	// Indexes into the walks for the two sides of a diff.
+	typedef pair<unsigned, unsigned> walk_pair;
+
+	// Returns the events of each pair, for a single range.
+	vector<event_vector> delta_range(string const &dev,
+					 vector<tree_walk> const &walks,
+					 vector<walk_pair> const &pairs,
+					 key_range kr) {
+		deque<mapping_recorder> recorders;
+
+		// The block cache isn't thread safe, so each range has
+		// its own.  The device is already held exclusively by the
+		// main thread.
+		block_manager::ptr bm = open_bm(dev, block_manager::READ_ONLY, false);
+		transaction_manager::ptr tm = open_tm(bm, superblock_detail::SUPERBLOCK_LOCATION);
+		bcache::validator::ptr v = create_btree_node_validator();
+
+		try {
+			vector<tree_walk>::const_iterator w;
+			for (w = walks.begin(); w != walks.end(); ++w) {
+				recorders.push_back(mapping_recorder(w->since_));
+				walk_range(*tm, v, w->root_, kr, recorders.back());
+				recorders.back().complete();
+			}
+
+		} catch (std::runtime_error const &e) {
+			raise_mapping_damage();
+		}
+
+		vector<event_vector> events;
+		vector<walk_pair>::const_iterator p;
+		for (p = pairs.begin(); p != pairs.end(); ++p) {
+			event_recorder er;
+			dump_diff(recorders[p->first].get_mappings(),
+				  recorders[p->second].get_mappings(), er);
+			events.push_back(er.get_events());
+		}
+
+		return events;
+	}
+
+	// At most one range per thread is in flight, so only a small part
+	// of the trees is held in core at once.
+	void delta_ranges(string const &dev,
+			  vector<tree_walk> const &walks,
+			  vector<walk_pair> const &pairs,
+			  vector<key_range> const &ranges, unsigned nr_threads,
+			  vector<event_sink *> const &sinks) {
+		deque<future<vector<event_vector> > > pending;
+		vector<key_range>::const_iterator next = ranges.begin();
+
+		for (;;) {
+			while (pending.size() < nr_threads && next != ranges.end()) {
+				pending.push_back(async(launch::async, delta_range,
+							dev, walks, pairs, *next));
+				++next;
+			}
+
+			if (pending.empty())
+				break;
+
+			vector<event_vector> events = pending.front().get();
+			pending.pop_front();
+
+			for (unsigned i = 0; i < events.size(); i++) {
+				event_vector::const_iterator it;
+				for (it = events[i].begin(); it != events[i].end(); ++it)
+					sinks[i]->add(*it);
+			}
+		}
+
+		vector<event_sink *>::const_iterator sink;
+		for (sink = sinks.begin(); sink != sinks.end(); ++sink)
+			(*sink)->complete();
+	}
+
+	device_tree_detail::device_details lookup_details(metadata &md, uint64_t dev_id) {
+		device_tree::key k = {dev_id};
+		boost::optional<device_tree_detail::device_details> dd = md.details_->lookup(k);
+		if (!dd) {
+			ostringstream out;
+			out << "Unable to find the details of device " << dev_id;
+			throw std::runtime_error(out.str());
+		}
+
+		return *dd;
+	}
+
+	// Every range in flight has its own block cache, so the number of
+	// threads is capped to keep memory use down on big machines.
+	unsigned const MAX_THREADS = 16;
+
+	// Several ranges per thread keeps them all busy when the mappings
+	// aren't spread evenly.
+	unsigned const RANGES_PER_THREAD = 8;
+
+	block_address lookup_root(metadata &md, dev_ref const &d, char const *side) {
+		if (d.root)
+			return *d.root;
+
+		dev_tree::key k = {*d.snap};
+		boost::optional<uint64_t> root = md.mappings_top_level_->lookup(k);
+		if (!root) {
+			ostringstream out;
+			out << "Unable to find mapping tree for " << side << " (" << *d.snap << ")";
+			throw std::runtime_error(out.str());
+		}
+
+		return *root;
+	}
+
+	// Everything needed to write out the diff of a single pair.
+	// Unless there's only one pair, the output is held in core until
+	// every pair is done, since the ranges of all the pairs are
+	// worked out together.
+	struct pair_output {
+		pair_output(indented_stream &direct, bool buffered)
+			: out_(buffered ? new indented_stream(buf_) : nullptr),
+			  is_(buffered ? *out_ : direct) {
+			// level with the direct output, within the superblock
+			if (buffered)
+				is_.inc();
+		}
+
+		ostringstream buf_;
+		unique_ptr<indented_stream> out_;
+		indented_stream &is_;
+
+		unique_ptr<diff_emitter> emitter_;
+		unique_ptr<stats_counter> counter_;
+		unique_ptr<event_sink> sink_;
+		unique_ptr<unit_scaler> scaler_;
+	};
+
 	void delta_(flags const &fs) {
-		mapping_recorder mr1;
-		mapping_recorder mr2;
-		damage_visitor damage_v;
 		superblock_detail::superblock sb;
 		block_address nr_data_blocks = 0ull;
+		vector<key_range> ranges;
+
+		unsigned nr_pairs = fs.lefts.size();
+		vector<tree_walk> walks;
+		vector<walk_pair> pairs;
 
+		// For --ancestry, the snapshot is whichever device was
+		// created later, and its creation time is when the two
+		// diverged.
+		vector<bool> left_is_origin(nr_pairs, true);
+		vector<uint32_t> snap_time(nr_pairs, 0);
+
+		unsigned nr_threads = min(MAX_THREADS, max(1u, std::thread::hardware_concurrency()));
+
+		block_manager::ptr bm = open_bm(*fs.dev, block_manager::READ_ONLY, !fs.use_metadata_snap);
 		{
-			block_manager::ptr bm = open_bm(*fs.dev, block_manager::READ_ONLY, !fs.use_metadata_snap);
 			metadata::ptr md(fs.use_metadata_snap ? new metadata(bm, fs.metadata_snap) : new metadata(bm));
 			sb = md->sb_;
 
-			boost::optional<uint64_t> snap1_root;
-			if (fs.snap1) {
-				dev_tree::key k = {*fs.snap1};
-				snap1_root = md->mappings_top_level_->lookup(k);
-			} else if (fs.root1)
-				snap1_root = *fs.root1;
-
-			if (!snap1_root) {
-				ostringstream out;
-				out << "Unable to find mapping tree for snap1 (" << *fs.snap1 << ")";
-				throw std::runtime_error(out.str());
+			map<tree_walk, unsigned> walk_index;
+			set<block_address> roots;
+			for (unsigned i = 0; i < nr_pairs; i++) {
+				block_address root1 = lookup_root(*md, fs.lefts[i], "snap1");
+				block_address root2 = lookup_root(*md, fs.rights[i], "snap2");
+				roots.insert(root1);
+				roots.insert(root2);
+
+				boost::optional<uint32_t> since;
+				if (fs.ancestry) {
+					device_tree_detail::device_details dd1 = lookup_details(*md, *fs.lefts[i].snap);
+					device_tree_detail::device_details dd2 = lookup_details(*md, *fs.rights[i].snap);
+					left_is_origin[i] = dd1.creation_time_ <= dd2.creation_time_;
+					snap_time[i] = max(dd1.creation_time_, dd2.creation_time_);
+					since = snap_time[i];
+				}
+
+				tree_walk w[2] = {tree_walk(root1, since), tree_walk(root2, since)};
+				unsigned index[2];
+				for (unsigned side = 0; side < 2; side++) {
+					map<tree_walk, unsigned>::const_iterator it = walk_index.find(w[side]);
+					if (it == walk_index.end()) {
+						index[side] = walks.size();
+						walk_index.insert(make_pair(w[side], index[side]));
+						walks.push_back(w[side]);
+					} else
+						index[side] = it->second;
+				}
+				pairs.push_back(walk_pair(index[0], index[1]));
 			}
 
-			single_mapping_tree snap1(*md->tm_, *snap1_root,
-						  mapping_tree_detail::block_traits::ref_counter(md->tm_->get_sm()));
+			try {
+				ranges = split_key_space(*md->tm_, roots,
+							 nr_threads * RANGES_PER_THREAD);
+			} catch (std::runtime_error const &e) {
+				raise_mapping_damage();
+			}
 
-			boost::optional<uint64_t> snap2_root;
-			if (fs.snap2) {
-				dev_tree::key k = {*fs.snap2};
-				snap2_root = md->mappings_top_level_->lookup(k);
-			} else if (fs.root2)
-				snap2_root = *fs.root2;
+			if (md->data_sm_)
+				nr_data_blocks = md->data_sm_->get_nr_blocks();
+		}
+
+		// The csv has a row per pair, and nothing else.
+		bool csv = fs.format == FORMAT_CSV;
 
-			if (!snap2_root) {
-				ostringstream out;
-				out << "Unable to find mapping tree for snap2 (" << *fs.snap2 << ")";
-				throw std::runtime_error(out.str());
+		indented_stream is(cout);
+		if (!csv)
+			begin_superblock(is, "", sb.time_,
+					 sb.trans_id_,
+					 sb.data_block_size_,
+					 nr_data_blocks,
+					 sb.metadata_snap_ ?
+					 boost::optional<block_address>(sb.metadata_snap_) :
+					 boost::optional<block_address>());
+
+		uint64_t multiplier = 1;
+		if (fs.units == UNITS_SECTORS)
+			multiplier = sb.data_block_size_;
+		else if (fs.units == UNITS_BYTES)
+			multiplier = sb.data_block_size_ * 512ull;
+
+		vector<unique_ptr<pair_output> > outputs;
+		vector<event_sink *> sinks;
+		for (unsigned i = 0; i < nr_pairs; i++) {
+			outputs.push_back(unique_ptr<pair_output>(new pair_output(is, nr_pairs > 1 && !csv)));
+			pair_output &o = *outputs.back();
+			if (!csv)
+				begin_diff(o.is_, fs.lefts[i], fs.rights[i], fs.units);
+
+			if (fs.stats) {
+				o.counter_.reset(new stats_counter());
+				o.scaler_.reset(new unit_scaler(*o.counter_, multiplier));
+				sinks.push_back(o.scaler_.get());
+				continue;
 			}
 
-			single_mapping_tree snap2(*md->tm_, *snap2_root,
-						  mapping_tree_detail::block_traits::ref_counter(md->tm_->get_sm()));
-			btree_visit_values(snap1, mr1, damage_v);
-			mr1.complete();
+			if (fs.ancestry) {
+				uint64_t left = *fs.lefts[i].snap, right = *fs.rights[i].snap;
+				o.sink_.reset(new ancestry_grouper(o.is_, left_is_origin[i],
+								   left_is_origin[i] ? left : right,
+								   left_is_origin[i] ? right : left,
+								   snap_time[i]));
+			} else {
+				if (fs.verbose)
+					o.emitter_.reset(new verbose_emitter(o.is_));
+				else
+					o.emitter_.reset(new simple_emitter(o.is_));
+				o.sink_.reset(new event_merger(*o.emitter_));
+			}
 
-			btree_visit_values(snap2, mr2, damage_v);
-			mr2.complete();
+			o.scaler_.reset(new unit_scaler(*o.sink_, multiplier));
+			sinks.push_back(o.scaler_.get());
+		}
 
-			if (md->data_sm_)
-				nr_data_blocks = md->data_sm_->get_nr_blocks();
+		delta_ranges(*fs.dev, walks, pairs, ranges, nr_threads, sinks);
+
+		if (csv) {
+			emit_csv_header(cout);
+			for (unsigned i = 0; i < nr_pairs; i++)
+				emit_csv_row(cout, fs.lefts[i], fs.rights[i],
+					     outputs[i]->counter_->get_stats(),
+					     sb.data_block_size_ * 512ull);
+			return;
 		}
 
-		indented_stream is(cout);
-		begin_superblock(is, "", sb.time_,
-				 sb.trans_id_,
-				 sb.data_block_size_,
-				 nr_data_blocks,
-				 sb.metadata_snap_ ?
-				 boost::optional<block_address>(sb.metadata_snap_) :
-				 boost::optional<block_address>());
-		begin_diff(is, fs.snap1, fs.root1, fs.snap2, fs.root2);
-
-		if (fs.verbose) {
-			verbose_emitter e(is);
-			dump_diff(mr1.get_mappings(), mr2.get_mappings(), e);
-		} else {
-			simple_emitter e(is);
-			dump_diff(mr1.get_mappings(), mr2.get_mappings(), e);
+		for (unsigned i = 0; i < nr_pairs; i++) {
+			pair_output &o = *outputs[i];
+			if (fs.stats)
+				emit_stats(o.is_, o.counter_->get_stats());
+			end_diff(o.is_);
+			if (nr_pairs > 1)
+				cout << o.buf_.str();
 		}
 
-		end_diff(is);
 		end_superblock(is);
 	}
 
@@ -594,10 +1401,10 @@ namespace {
 			delta_(fs);
 		} catch (exception const &e) {
 			cerr << e.what() << endl;
-			return 1;
+			return base::exit_code(e);
 		}
 
-		return 0;
+		return base::exit_codes::SUCCESS;
 	}
 }
 
@@ -617,8 +1424,13 @@ thin_delta_cmd::usage(std::ostream &out) const
 	    << "Options:\n"
 	    << "  {--thin1, --snap1, --root1}\n"
 	    << "  {--thin2, --snap2, --root2}\n"
+	    << "  {--chain <thin id>,<thin id>[,...]}\n"
 	    << "  {-m, --metadata-snap} [block#]\n"
 	    << "  {--verbose}\n"
+	    << "  {--ancestry}\n"
+	    << "  {--units blocks|sectors|bytes}\n"
+	    << "  {--stats}\n"
+	    << "  {--format xml|csv}\n"
 	    << "  {-h|--help}\n"
 	    << "  {-V|--version}" << endl;
 }
@@ -628,6 +1440,7 @@ thin_delta_cmd::run(int argc, char **argv)
 {
 	int c;
 	flags fs;
+	vector<dev_ref> chain_lefts, chain_rights;
 
 	char const shortopts[] = "hVm::";
 	option const longopts[] = {
@@ -641,6 +1454,11 @@ thin_delta_cmd::run(int argc, char **argv)
 		{ "verbose", no_argument, NULL, 4 },
 		{ "root1", required_argument, NULL, 5 },
 		{ "root2", required_argument, NULL, 6 },
+		{ "ancestry", no_argument, NULL, 7 },
+		{ "units", required_argument, NULL, 8 },
+		{ "chain", required_argument, NULL, 9 },
+		{ "stats", no_argument, NULL, 10 },
+		{ "format", required_argument, NULL, 11 },
 		{ NULL, no_argument, NULL, 0 }
 	};
 
@@ -660,29 +1478,91 @@ thin_delta_cmd::run(int argc, char **argv)
 			cout << THIN_PROVISIONING_TOOLS_VERSION << endl;
 			return 0;
 
-		case 1:
-			fs.snap1 = parse_uint64(optarg, "thin id 1");
+		case 1: {
+			dev_ref d;
+			d.snap = parse_uint64(optarg, "thin id 1");
+			fs.lefts.push_back(d);
 			break;
+		}
 
-		case 2:
-			fs.snap2 = parse_uint64(optarg, "thin id 2");
+		case 2: {
+			dev_ref d;
+			d.snap = parse_uint64(optarg, "thin id 2");
+			fs.rights.push_back(d);
 			break;
+		}
 
 		case 4:
 			fs.verbose = true;
 			break;
 
-		case 5:
-			fs.root1 = parse_uint64(optarg, "thin root 1");
+		case 5: {
+			dev_ref d;
+			d.root = parse_uint64(optarg, "thin root 1");
+			fs.lefts.push_back(d);
+			break;
+		}
+
+		case 6: {
+			dev_ref d;
+			d.root = parse_uint64(optarg, "thin root 2");
+			fs.rights.push_back(d);
+			break;
+		}
+
+		case 7:
+			fs.ancestry = true;
+			break;
+
+		case 9: {
+			// Each device is diffed against the next.
+			stringstream in(optarg);
+			string item;
+			vector<uint64_t> chain;
+			while (getline(in, item, ','))
+				chain.push_back(parse_uint64(item.c_str(), "chain thin id"));
+
+			if (chain.size() < 2)
+				die("--chain needs at least two thin ids.");
+
+			for (unsigned i = 0; i + 1 < chain.size(); i++) {
+				dev_ref left, right;
+				left.snap = chain[i];
+				right.snap = chain[i + 1];
+				chain_lefts.push_back(left);
+				chain_rights.push_back(right);
+			}
+			break;
+		}
+
+		case 8:
+			if (!strcmp(optarg, "blocks"))
+				fs.units = UNITS_BLOCKS;
+			else if (!strcmp(optarg, "sectors"))
+				fs.units = UNITS_SECTORS;
+			else if (!strcmp(optarg, "bytes"))
+				fs.units = UNITS_BYTES;
+			else
+				die("--units must be blocks, sectors or bytes.");
+			fs.units_given = true;
+			break;
+
+		case 10:
+			fs.stats = true;
 			break;
 
-		case 6:
-			fs.root2 = parse_uint64(optarg, "thin root 2");
+		case 11:
+			if (!strcmp(optarg, "xml"))
+				fs.format = FORMAT_XML;
+			else if (!strcmp(optarg, "csv"))
+				fs.format = FORMAT_CSV;
+			else
+				die("--format must be xml or csv.");
 			break;
 
 		default:
 			usage(cerr);
-			return 1;
+			return base::exit_codes::USAGE;
 		}
 	}
 
@@ -691,16 +1571,41 @@ thin_delta_cmd::run(int argc, char **argv)
 	else
 		fs.dev = argv[optind];
 
-	if (!fs.snap1 && !fs.root1)
+	// Kept apart until now, so they can't upset the pairing of
+	// --snap1 and --snap2.
+	fs.lefts.insert(fs.lefts.end(), chain_lefts.begin(), chain_lefts.end());
+	fs.rights.insert(fs.rights.end(), chain_rights.begin(), chain_rights.end());
+
+	if (fs.lefts.empty())
 		die("--snap1 or --root1 not specified.");
-	if (!!fs.snap1 && !!fs.root1)
-		die("--snap1 and --root1 are not compatible.");
 
-	if (!fs.snap2 && !fs.root2)
+	if (fs.rights.empty())
 		die("--snap2 or --root2 not specified.");
-	if (!!fs.snap2 && !!fs.root2)
-		die("--snap2 and --root2 are not compatible.");
 
+	// The sides are paired up in the order they're given.
+	if (fs.lefts.size() != fs.rights.size())
+		die("every --snap1 or --root1 needs a matching --snap2 or --root2.");
+
+	if (fs.stats && (fs.ancestry || fs.verbose))
+		die("--stats can't be combined with --ancestry or --verbose.");
+
+	if (fs.format == FORMAT_CSV) {
+		if (!fs.stats)
+			die("--format csv needs --stats.");
+
+		// Each row gives both blocks and bytes.
+		if (fs.units_given)
+			die("--units can't be combined with --format csv.");
+	}
+
+	// The device details say which device is the snapshot.
+	if (fs.ancestry) {
+		for (unsigned i = 0; i < fs.lefts.size(); i++)
+			if (!fs.lefts[i].snap || !fs.rights[i].snap)
+				die("--ancestry needs --snap1 and --snap2.");
+	}
+
+	base::exit_on_interrupt();
 	return delta(fs);
 }
 
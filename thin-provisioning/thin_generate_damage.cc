@@ -1,10 +1,17 @@
 #include "base/output_file_requirements.h"
+#include "base/sequence_generator.h"
 #include "persistent-data/file_utils.h"
 #include "thin-provisioning/commands.h"
 #include "thin-provisioning/damage_generator.h"
 #include "version.h"
 
+#include <boost/optional.hpp>
+#include <cerrno>
+#include <cstring>
 #include <getopt.h>
+#include <sstream>
+#include <stdexcept>
+#include <sys/stat.h>
 #include <unistd.h>
 
 using namespace persistent_data;
@@ -17,25 +24,57 @@ namespace {
 		enum damage_operations {
 			DAMAGE_OP_NONE,
 			DAMAGE_OP_CREATE_METADATA_LEAKS,
+			DAMAGE_OP_FLIP_NODE_BITS,
+			DAMAGE_OP_CORRUPT_CHECKSUMS,
+			DAMAGE_OP_TRUNCATE_SUBTREE,
+			DAMAGE_OP_BREAK_DETAILS_TREE,
+			DAMAGE_OP_DAMAGE_METADATA_SNAP,
+			DAMAGE_OP_CORRUPT_SPACE_MAP,
+			DAMAGE_OP_TRUNCATE_METADATA,
 			DAMAGE_OP_LAST
 		};
 
 		flags()
 			: op(DAMAGE_OP_NONE),
 			  nr_blocks(0),
+			  node_type(damage_generator::NODE_ANY),
+			  sm_type(damage_generator::SM_METADATA),
 			  expected_rc(0),
 			  actual_rc(0) {
 		}
 
 		bool check_conformance();
+		bool needs_nr_blocks() const;
+		bool needs_commit() const;
 
 		damage_operations op;
 		string output;
 		block_address nr_blocks;
+		damage_generator::node_type node_type;
+		boost::optional<uint64_t> dev_id;
+		damage_generator::space_map_type sm_type;
+		boost::optional<damage_generator::space_map_damage> sm_damage;
 		ref_t expected_rc;
 		ref_t actual_rc;
+		boost::optional<uint64_t> seed;
 	};
 
+	bool flags::needs_nr_blocks() const {
+		return op == DAMAGE_OP_CREATE_METADATA_LEAKS ||
+		       op == DAMAGE_OP_FLIP_NODE_BITS ||
+		       op == DAMAGE_OP_CORRUPT_CHECKSUMS ||
+		       (op == DAMAGE_OP_CORRUPT_SPACE_MAP &&
+			sm_damage != damage_generator::SM_DAMAGE_INDEX);
+	}
+
+	// The other operations write the damage straight to the
+	// blocks; committing would read them back.
+	bool flags::needs_commit() const {
+		return op == DAMAGE_OP_CREATE_METADATA_LEAKS ||
+		       (op == DAMAGE_OP_CORRUPT_SPACE_MAP &&
+			sm_damage == damage_generator::SM_DAMAGE_REF_COUNT);
+	}
+
 	bool flags::check_conformance() {
 		if (op == DAMAGE_OP_NONE || op >= DAMAGE_OP_LAST) {
 			cerr << "Invalid operation." << endl;
@@ -47,11 +86,21 @@ namespace {
 			return false;
 		}
 
-		if (!nr_blocks) {
+		if (op == DAMAGE_OP_CORRUPT_SPACE_MAP && !sm_damage) {
+			cerr << "No space map damage mode provided." << endl;
+			return false;
+		}
+
+		if (needs_nr_blocks() && !nr_blocks) {
 			cerr << "Invalid number of blocks" << endl;
 			return false;
 		}
 
+		if (op == DAMAGE_OP_TRUNCATE_SUBTREE && !dev_id) {
+			cerr << "No device id provided." << endl;
+			return false;
+		}
+
 		if (op == DAMAGE_OP_CREATE_METADATA_LEAKS &&
 		    expected_rc == actual_rc) {
 			cerr << "Invalid reference count parameters" << endl;
@@ -60,25 +109,115 @@ namespace {
 
 		check_output_file_requirements(output);
 
+		if (op == DAMAGE_OP_TRUNCATE_METADATA) {
+			struct stat info;
+			if (::stat(output.c_str(), &info) || !S_ISREG(info.st_mode)) {
+				cerr << "Only a metadata file can be truncated." << endl;
+				return false;
+			}
+		}
+
 		return true;
 	}
 
+	// Cuts the file off half way through block b, as an interrupted
+	// copy of the metadata would.
+	void truncate_metadata(string const &path, block_address b) {
+		off_t len = b * MD_BLOCK_SIZE + MD_BLOCK_SIZE / 2;
+		if (::truncate(path.c_str(), len)) {
+			ostringstream out;
+			out << "couldn't truncate " << path << ": " << strerror(errno);
+			throw runtime_error(out.str());
+		}
+	}
+
 	int generate_damage(flags const &fs) {
 		block_manager::ptr bm = open_bm(fs.output, block_manager::READ_WRITE);
-		damage_generator::ptr gen = damage_generator::ptr(new damage_generator(bm));
+		uint64_t seed = !fs.seed ? base::default_seed() : *fs.seed;
+		damage_generator::ptr gen = damage_generator::ptr(new damage_generator(bm, seed));
+		boost::optional<block_address> truncate_at;
 
 		switch (fs.op) {
 		case flags::DAMAGE_OP_CREATE_METADATA_LEAKS:
 			gen->create_metadata_leaks(fs.nr_blocks, fs.expected_rc, fs.actual_rc);
 			break;
+		case flags::DAMAGE_OP_FLIP_NODE_BITS:
+			gen->flip_node_bits(fs.node_type, fs.nr_blocks);
+			break;
+		case flags::DAMAGE_OP_CORRUPT_CHECKSUMS:
+			gen->corrupt_node_checksums(fs.node_type, fs.nr_blocks);
+			break;
+		case flags::DAMAGE_OP_TRUNCATE_SUBTREE:
+			gen->truncate_subtree(*fs.dev_id);
+			break;
+		case flags::DAMAGE_OP_BREAK_DETAILS_TREE:
+			gen->break_details_tree();
+			break;
+		case flags::DAMAGE_OP_DAMAGE_METADATA_SNAP:
+			gen->damage_metadata_snap();
+			break;
+		case flags::DAMAGE_OP_CORRUPT_SPACE_MAP:
+			gen->corrupt_space_map(fs.sm_type, *fs.sm_damage, fs.nr_blocks);
+			break;
+		case flags::DAMAGE_OP_TRUNCATE_METADATA:
+			truncate_at = gen->pick_truncation_point(fs.node_type);
+			break;
 		default:
 			break;
 		}
 
-		gen->commit();
+		if (fs.needs_commit())
+			gen->commit();
+
+		if (truncate_at) {
+			// the block manager has to let go of the file first
+			gen.reset();
+			bm.reset();
+			truncate_metadata(fs.output, *truncate_at);
+		}
 
 		return 0;
 	}
+
+	bool parse_node_type(string const &str, damage_generator::node_type &t) {
+		if (str == "details")
+			t = damage_generator::NODE_DETAILS;
+		else if (str == "top-level")
+			t = damage_generator::NODE_TOP_LEVEL;
+		else if (str == "bottom-level")
+			t = damage_generator::NODE_BOTTOM_LEVEL;
+		else if (str == "any")
+			t = damage_generator::NODE_ANY;
+		else
+			return false;
+
+		return true;
+	}
+
+	bool parse_space_map_type(string const &str, damage_generator::space_map_type &t) {
+		if (str == "metadata")
+			t = damage_generator::SM_METADATA;
+		else if (str == "data")
+			t = damage_generator::SM_DATA;
+		else
+			return false;
+
+		return true;
+	}
+
+	bool parse_space_map_damage(string const &str,
+				    boost::optional<damage_generator::space_map_damage> &d) {
+		if (str == "index")
+			d = damage_generator::SM_DAMAGE_INDEX;
+		else if (str == "bitmap")
+			d = damage_generator::SM_DAMAGE_BITMAP;
+		else if (str == "ref-count")
+			d = damage_generator::SM_DAMAGE_REF_COUNT;
+		else
+			return false;
+
+		return true;
+	}
 }
 
 //----------------------------------------------------------------
@@ -96,9 +235,20 @@ thin_generate_damage_cmd::usage(std::ostream &out) const
 	    << "  {-h|--help}\n"
 	    << "  {-o|--output} <output device or file>\n"
 	    << "  {--create-metadata-leaks}\n"
+	    << "  {--flip-node-bits}\n"
+	    << "  {--corrupt-checksums}\n"
+	    << "  {--truncate-subtree}\n"
+	    << "  {--break-details-tree}\n"
+	    << "  {--damage-metadata-snap}\n"
+	    << "  {--corrupt-space-map} <metadata|data>\n"
+	    << "  {--truncate-metadata}\n"
 	    << "  {--nr-blocks} <block counts>\n"
 	    << "  {--expected} <expected ref-count>\n"
 	    << "  {--actual} <actual ref-count>\n"
+	    << "  {--node-type} <details|top-level|bottom-level|any>\n"
+	    << "  {--dev-id} <dev-id>\n"
+	    << "  {--mode} <index|bitmap|ref-count>\n"
+	    << "  {--seed} <random seed>\n"
 	    << "  {-V|--version}" << endl;
 }
 
@@ -112,9 +262,20 @@ thin_generate_damage_cmd::run(int argc, char **argv)
 		{ "help", no_argument, NULL, 'h' },
 		{ "output", required_argument, NULL, 'o' },
 		{ "create-metadata-leaks", no_argument, NULL, 1 },
+		{ "flip-node-bits", no_argument, NULL, 2 },
+		{ "corrupt-checksums", no_argument, NULL, 3 },
+		{ "truncate-subtree", no_argument, NULL, 4 },
+		{ "break-details-tree", no_argument, NULL, 5 },
+		{ "damage-metadata-snap", no_argument, NULL, 6 },
+		{ "corrupt-space-map", required_argument, NULL, 7 },
+		{ "truncate-metadata", no_argument, NULL, 8 },
 		{ "nr-blocks", required_argument, NULL, 1001 },
 		{ "expected", required_argument, NULL, 1002 },
 		{ "actual", required_argument, NULL, 1003 },
+		{ "node-type", required_argument, NULL, 1004 },
+		{ "dev-id", required_argument, NULL, 1005 },
+		{ "mode", required_argument, NULL, 1006 },
+		{ "seed", required_argument, NULL, 1007 },
 		{ "version", no_argument, NULL, 'V' },
 		{ NULL, no_argument, NULL, 0 }
 	};
@@ -130,6 +291,29 @@ thin_generate_damage_cmd::run(int argc, char **argv)
 		case 1:
 			fs.op = flags::DAMAGE_OP_CREATE_METADATA_LEAKS;
 			break;
+		case 2:
+			fs.op = flags::DAMAGE_OP_FLIP_NODE_BITS;
+			break;
+		case 3:
+			fs.op = flags::DAMAGE_OP_CORRUPT_CHECKSUMS;
+			break;
+		case 4:
+			fs.op = flags::DAMAGE_OP_TRUNCATE_SUBTREE;
+			break;
+		case 5:
+			fs.op = flags::DAMAGE_OP_BREAK_DETAILS_TREE;
+			break;
+		case 6:
+			fs.op = flags::DAMAGE_OP_DAMAGE_METADATA_SNAP;
+			break;
+		case 7:
+			fs.op = flags::DAMAGE_OP_CORRUPT_SPACE_MAP;
+			if (!parse_space_map_type(optarg, fs.sm_type))
+				die(string("Unknown space map '") + optarg + "'");
+			break;
+		case 8:
+			fs.op = flags::DAMAGE_OP_TRUNCATE_METADATA;
+			break;
 		case 1001:
 			fs.nr_blocks = parse_uint64(optarg, "nr_blocks");
 			break;
@@ -139,6 +323,20 @@ thin_generate_damage_cmd::run(int argc, char **argv)
 		case 1003:
 			fs.actual_rc = parse_uint64(optarg, "actual");
 			break;
+		case 1004:
+			if (!parse_node_type(optarg, fs.node_type))
+				die(string("Unknown node type '") + optarg + "'");
+			break;
+		case 1005:
+			fs.dev_id = parse_uint64(optarg, "dev_id");
+			break;
+		case 1006:
+			if (!parse_space_map_damage(optarg, fs.sm_damage))
+				die(string("Unknown space map damage mode '") + optarg + "'");
+			break;
+		case 1007:
+			fs.seed = parse_uint64(optarg, "seed");
+			break;
 		}
 	}
 
@@ -15,14 +15,19 @@
 // <http://www.gnu.org/licenses/>.
 
 #include "base/io_generator.h"
+#include "base/io_trace.h"
+#include "base/sequence_generator.h"
 #include "base/output_file_requirements.h"
 #include "persistent-data/file_utils.h"
 #include "thin-provisioning/commands.h"
 #include "thin-provisioning/thin_pool.h"
 #include "version.h"
 
+#include <boost/lexical_cast.hpp>
 #include <boost/optional.hpp>
 #include <getopt.h>
+#include <sstream>
+#include <stdexcept>
 #include <unistd.h>
 
 using namespace boost;
@@ -48,6 +53,9 @@ namespace {
 		boost::optional<base::sector_t> size;
 		boost::optional<base::sector_t> io_size;
 		boost::optional<unsigned> nr_seq_blocks;
+		boost::optional<string> trace;
+		boost::optional<uint64_t> seed;
+		boost::optional<double> discard_fraction;
 	};
 
 	bool flags::check_conformance() {
@@ -61,7 +69,13 @@ namespace {
 			return false;
 		}
 
-		if (!size) {
+		if (trace) {
+			if (nr_seq_blocks || io_size) {
+				cerr << "Cannot specify the io pattern"
+					" while replaying a trace" << endl;
+				return false;
+			}
+		} else if (!size) {
 			cerr << "No device size specified" << endl;
 			return false;
 		}
@@ -74,11 +88,35 @@ namespace {
 			}
 		}
 
+		if (discard_fraction) {
+			if (!size) {
+				cerr << "No device size specified"
+					" for the discard pass" << endl;
+				return false;
+			}
+
+			if (*discard_fraction < 0.0 || *discard_fraction > 1.0) {
+				cerr << "The discard fraction must be"
+					" between 0 and 1" << endl;
+				return false;
+			}
+		}
+
 		check_output_file_requirements(*output);
 
 		return true;
 	}
 
+	double parse_fraction(char const *str) {
+		try {
+			return lexical_cast<double>(str);
+		} catch (...) {
+			ostringstream out;
+			out << "Couldn't parse discard_fraction: '" << str << "'";
+			throw runtime_error(out.str());
+		}
+	}
+
 	//--------------------------------
 
 	thin_pool::ptr open_pool(flags const &fs) {
@@ -86,10 +124,12 @@ namespace {
 		return thin_pool::ptr(new thin_pool(bm));
 	}
 
-	int generate_mappings(flags const &fs) {
-		thin_pool::ptr pool = open_pool(fs);
-
-		thin::ptr td = pool->open_thin(*fs.dev_id);
+	io_generator::ptr create_generator(flags const &fs, thin_pool::ptr pool) {
+		if (fs.trace)
+			return create_trace_io_generator(*fs.trace,
+							 pool->get_data_block_size(),
+							 fs.offset,
+							 fs.size ? *fs.size : 0);
 
 		io_generator_options opts;
 		opts.pattern_ = fs.pattern;
@@ -100,8 +140,31 @@ namespace {
 		opts.size_ = *fs.size;
 		opts.io_size_ = !fs.io_size ? *fs.size : *fs.io_size;
 		opts.nr_seq_blocks_ = !fs.nr_seq_blocks ? 1 : *fs.nr_seq_blocks;
-		io_generator::ptr gen = create_io_generator(opts);
+		opts.seed_ = !fs.seed ? base::default_seed() : *fs.seed;
+		return create_io_generator(opts);
+	}
 
+	// Discards a random selection of the blocks in the range, as
+	// fstrim does once files are deleted, leaving holes in the
+	// mappings.  The offsets never repeat, so the given fraction of
+	// the range is discarded, in runs of up to --seq-nr blocks.
+	io_generator::ptr create_discard_generator(flags const &fs, thin_pool::ptr pool) {
+		io_generator_options opts;
+		opts.pattern_ = base::io_pattern("randtrim");
+		opts.block_size_ = !fs.block_size ?
+				   pool->get_data_block_size() :
+				   *fs.block_size;
+		opts.offset_ = fs.offset;
+		opts.size_ = *fs.size;
+		opts.io_size_ = static_cast<base::sector_t>(*fs.size * *fs.discard_fraction);
+		opts.io_size_ -= opts.io_size_ % opts.block_size_;
+		opts.nr_seq_blocks_ = !fs.nr_seq_blocks ? 1 : *fs.nr_seq_blocks;
+		// a different sequence to the one that wrote the mappings
+		opts.seed_ = (!fs.seed ? base::default_seed() : *fs.seed) + 1;
+		return create_io_generator(opts);
+	}
+
+	void run_generator(io_generator::ptr gen, thin::ptr td, thin_pool::ptr pool) {
 		base::io io;
 		while (gen->next(io)) {
 			// TODO: support io.size_
@@ -117,6 +180,16 @@ namespace {
 				break;
 			}
 		}
+	}
+
+	int generate_mappings(flags const &fs) {
+		thin_pool::ptr pool = open_pool(fs);
+
+		thin::ptr td = pool->open_thin(*fs.dev_id);
+		run_generator(create_generator(fs, pool), td, pool);
+
+		if (fs.discard_fraction && *fs.discard_fraction > 0.0)
+			run_generator(create_discard_generator(fs, pool), td, pool);
 
 		pool->commit();
 
@@ -139,11 +212,14 @@ thin_generate_mappings_cmd::usage(std::ostream &out) const
 	    << "  {-h|--help}\n"
 	    << "  {-o|--output} <output device or file>\n"
 	    << "  {--dev-id} <dev-id>\n"
-	    << "  {--offset} <offset in sectors>\n"
-	    << "  {--io-size} <io-size in sectors>\n"
+	    << "  {--offset} <offset in sectors, or with a unit suffix>\n"
+	    << "  {--io-size} <io-size in sectors, or with a unit suffix>\n"
 	    << "  {--rw write|trim|randwrite|randtrim|randtw}\n"
-	    << "  {--size} <size in sectors>\n"
+	    << "  {--size} <size in sectors, or with a unit suffix>\n"
 	    << "  {--seq-nr} <max nr. of sequential ios>\n"
+	    << "  {--trace} <blkparse output or fio iolog to replay>\n"
+	    << "  {--seed} <random seed>\n"
+	    << "  {--discard-fraction} <fraction of the range to discard afterwards>\n"
 	    << "  {-V|--version}" << endl;
 }
 
@@ -162,6 +238,9 @@ thin_generate_mappings_cmd::run(int argc, char **argv)
 		{ "size", required_argument, NULL, 4 },
 		{ "io-size", required_argument, NULL, 5 },
 		{ "seq-nr", required_argument, NULL, 6 },
+		{ "trace", required_argument, NULL, 7 },
+		{ "seed", required_argument, NULL, 8 },
+		{ "discard-fraction", required_argument, NULL, 9 },
 		{ "version", no_argument, NULL, 'V' },
 		{ NULL, no_argument, NULL, 0 }
 	};
@@ -185,21 +264,33 @@ thin_generate_mappings_cmd::run(int argc, char **argv)
 			break;
 
 		case 3:
-			fs.offset = parse_uint64(optarg, "offset");
+			fs.offset = parse_sectors(optarg, "offset");
 			break;
 
 		case 4:
-			fs.size = parse_uint64(optarg, "size");
+			fs.size = parse_sectors(optarg, "size");
 			break;
 
 		case 5:
-			fs.io_size = parse_uint64(optarg, "io_size");
+			fs.io_size = parse_sectors(optarg, "io_size");
 			break;
 
 		case 6:
 			fs.nr_seq_blocks = parse_uint64(optarg, "seq_nr");
 			break;
 
+		case 7:
+			fs.trace = optarg;
+			break;
+
+		case 8:
+			fs.seed = parse_uint64(optarg, "seed");
+			break;
+
+		case 9:
+			fs.discard_fraction = parse_fraction(optarg);
+			break;
+
 		case 'V':
 			cout << THIN_PROVISIONING_TOOLS_VERSION << endl;
 			return 0;
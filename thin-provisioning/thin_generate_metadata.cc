@@ -24,6 +24,7 @@
 
 #include <boost/optional.hpp>
 #include <getopt.h>
+#include <limits>
 #include <unistd.h>
 
 using namespace boost;
@@ -44,6 +45,8 @@ namespace {
 			METADATA_OP_RESERVE_METADATA_SNAP,
 			METADATA_OP_RELEASE_METADATA_SNAP,
 			METADATA_OP_SET_NEEDS_CHECK,
+			METADATA_OP_CREATE_THINS,
+			METADATA_OP_CREATE_SNAPS,
 			METADATA_OP_LAST
 		};
 
@@ -55,18 +58,49 @@ namespace {
 		}
 
 		bool check_conformance();
+		bool resolve_nr_data_blocks();
+		bool overrides_superblock() const;
 
 		metadata_operations op;
 		sector_t data_block_size;
 		block_address nr_data_blocks;
+		optional<uint64_t> nr_data_bytes;
 		optional<thin_dev_t> dev_id;
 		optional<thin_dev_t> origin;
+		optional<uint64_t> nr_devs;
+		optional<uint64_t> first_dev_id;
 		optional<uint64_t> trans_id;
 		optional<string> output;
+
+		// superblock fields written verbatim once the operation
+		// has been committed
+		optional<uint64_t> sb_time;
+		optional<block_address> sb_metadata_snap;
+		optional<uint64_t> sb_version;
 	};
 
+	// The kernel takes device ids of up to 24 bits.
+	uint64_t const MAX_DEV_ID = (1ull << 24) - 1;
+
+	// Bulk creation starts at --first-dev-id, defaulting to 0 for
+	// thins and to the id after the origin for snapshots.
+	uint64_t first_bulk_dev_id(flags const &fs) {
+		if (fs.first_dev_id)
+			return *fs.first_dev_id;
+
+		return fs.origin ? static_cast<uint64_t>(*fs.origin) + 1 : 0;
+	}
+
 	// FIXME: modulize the conditions
 	bool flags::check_conformance() {
+		// The transaction id and superblock fields may accompany
+		// any operation, or be set on their own.
+		if (op == METADATA_OP_NONE && trans_id)
+			op = METADATA_OP_SET_TRANSACTION_ID;
+
+		if (op == METADATA_OP_NONE && overrides_superblock())
+			op = METADATA_OP_OPEN;
+
 		if (op == METADATA_OP_NONE || op >= METADATA_OP_LAST) {
 			cerr << "Invalid operation." << endl;
 			return false;
@@ -93,27 +127,93 @@ namespace {
 			return false;
 		}
 
+		if (op == METADATA_OP_CREATE_SNAPS && !origin) {
+			cerr << "no origin provided." << endl;
+			return false;
+		}
+
 		if (op == METADATA_OP_SET_TRANSACTION_ID && !trans_id) {
 			cerr << "no transaction id provided." << endl;
 			return false;
 		}
 
+		if ((op == METADATA_OP_CREATE_THINS || op == METADATA_OP_CREATE_SNAPS) &&
+		    nr_devs && *nr_devs) {
+			uint64_t first = first_bulk_dev_id(*this);
+			if (first > MAX_DEV_ID || *nr_devs - 1 > MAX_DEV_ID - first) {
+				cerr << "device ids must not exceed " << MAX_DEV_ID << "." << endl;
+				return false;
+			}
+		}
+
+		if (sb_time && *sb_time > numeric_limits<uint32_t>::max()) {
+			cerr << "time doesn't fit in 32 bits." << endl;
+			return false;
+		}
+
+		if (sb_version && *sb_version > numeric_limits<uint32_t>::max()) {
+			cerr << "version doesn't fit in 32 bits." << endl;
+			return false;
+		}
+
 		return true;
 	}
 
-	//--------------------------------
+	bool flags::overrides_superblock() const {
+		return sb_time || sb_metadata_snap || sb_version;
+	}
 
-	thin_pool::ptr open_or_create_pool(flags const &fs) {
-		block_manager::ptr bm = open_bm(*fs.output, block_manager::READ_WRITE);
+	// A unit suffixed --nr-data-blocks, eg, 4T, can only be turned
+	// into blocks once the data block size is known.
+	bool flags::resolve_nr_data_blocks() {
+		if (!nr_data_bytes)
+			return true;
+
+		uint64_t block_bytes = data_block_size * 512;
+		if (!block_bytes || *nr_data_bytes % block_bytes) {
+			cerr << "nr data blocks is not a whole number of "
+			     << data_block_size << " sector blocks." << endl;
+			return false;
+		}
 
+		nr_data_blocks = *nr_data_bytes / block_bytes;
+		cerr << "nr data blocks: " << *nr_data_bytes << " bytes = "
+		     << nr_data_blocks << " blocks" << endl;
+
+		return true;
+	}
+
+	//--------------------------------
+
+	thin_pool::ptr open_or_create_pool(flags const &fs, block_manager::ptr bm) {
 		if (fs.op == flags::METADATA_OP_FORMAT)
 			return thin_pool::ptr(new thin_pool(bm, fs.data_block_size, fs.nr_data_blocks));
 		else
 			return thin_pool::ptr(new thin_pool(bm));
 	}
 
+	// The pool would refuse to write inconsistent values, so these
+	// go straight to the superblock.  This is how tests construct
+	// edge cases such as a time about to overflow, or a metadata
+	// snap pointing at a block that isn't a superblock.
+	void override_superblock(flags const &fs, block_manager::ptr bm) {
+		superblock_detail::superblock sb = read_superblock(bm);
+
+		if (fs.sb_time)
+			sb.time_ = *fs.sb_time;
+
+		if (fs.sb_metadata_snap)
+			sb.metadata_snap_ = *fs.sb_metadata_snap;
+
+		if (fs.sb_version)
+			sb.version_ = *fs.sb_version;
+
+		write_superblock(bm, sb);
+	}
+
 	int generate_metadata(flags const &fs) {
-		thin_pool::ptr pool = open_or_create_pool(fs);
+		block_manager::ptr bm = open_bm(*fs.output, block_manager::READ_WRITE);
+		thin_pool::ptr pool = open_or_create_pool(fs, bm);
 
 		switch (fs.op) {
 		case flags::METADATA_OP_CREATE_THIN:
@@ -125,9 +225,6 @@ namespace {
 		case flags::METADATA_OP_DELETE_DEV:
 			pool->del(*fs.dev_id);
 			break;
-		case flags::METADATA_OP_SET_TRANSACTION_ID:
-			pool->set_transaction_id(*fs.trans_id);
-			break;
 		case flags::METADATA_OP_RESERVE_METADATA_SNAP:
 			pool->reserve_metadata_snap();
 			break;
@@ -137,11 +234,26 @@ namespace {
 		case flags::METADATA_OP_SET_NEEDS_CHECK:
 			pool->set_needs_check();
 			break;
+		case flags::METADATA_OP_CREATE_THINS:
+			for (uint64_t i = 0; i < *fs.nr_devs; i++)
+				pool->create_thin(static_cast<thin_dev_t>(first_bulk_dev_id(fs) + i));
+			break;
+		case flags::METADATA_OP_CREATE_SNAPS:
+			for (uint64_t i = 0; i < *fs.nr_devs; i++)
+				pool->create_snap(static_cast<thin_dev_t>(first_bulk_dev_id(fs) + i), *fs.origin);
+			break;
 		default:
 			break;
 		}
 
+		if (fs.trans_id)
+			pool->set_transaction_id(*fs.trans_id);
+
 		pool->commit();
+		pool.reset();
+
+		if (fs.overrides_superblock())
+			override_superblock(fs, bm);
 
 		return 0;
 	}
@@ -163,14 +275,20 @@ thin_generate_metadata_cmd::usage(std::ostream &out) const
 	    << "  {--format}\n"
 	    << "  {--create-thin} <dev-id>\n"
 	    << "  {--create-snap} <dev-id>\n"
+	    << "  {--create-thins} <count>\n"
+	    << "  {--create-snaps} <count>\n"
 	    << "  {--delete} <dev-id>\n"
 	    << "  {--reserve-metadata-snap}\n"
 	    << "  {--release-metadata-snap}\n"
 	    << "  {--set-transaction-id} <tid>\n"
 	    << "  {--set-needs-check}\n"
-	    << "  {--data-block-size} <block size>\n"
-	    << "  {--nr-data-blocks} <nr>\n"
+	    << "  {--set-time} <time>\n"
+	    << "  {--set-metadata-snap} <blocknr>\n"
+	    << "  {--set-version} <metadata format version>\n"
+	    << "  {--data-block-size} <block size in sectors, or with a unit suffix>\n"
+	    << "  {--nr-data-blocks} <nr, or a size with a unit suffix>\n"
 	    << "  {--origin} <origin-id>\n"
+	    << "  {--first-dev-id} <dev-id>\n"
 	    << "  {-o|--output} <output device or file>\n"
 	    << "  {-V|--version}" << endl;
 }
@@ -193,9 +311,15 @@ thin_generate_metadata_cmd::run(int argc, char **argv)
 		{ "reserve-metadata-snap", no_argument, NULL, 7 },
 		{ "release-metadata-snap", no_argument, NULL, 8 },
 		{ "set-needs-check", no_argument, NULL, 9 },
+		{ "create-thins", required_argument, NULL, 10 },
+		{ "create-snaps", required_argument, NULL, 11 },
 		{ "data-block-size", required_argument, NULL, 1001 },
 		{ "nr-data-blocks", required_argument, NULL, 1002 },
+		{ "set-time", required_argument, NULL, 2001 },
+		{ "set-metadata-snap", required_argument, NULL, 2002 },
+		{ "set-version", required_argument, NULL, 2003 },
 		{ "origin", required_argument, NULL, 4001 },
+		{ "first-dev-id", required_argument, NULL, 4002 },
 		{ "version", no_argument, NULL, 'V' },
 		{ NULL, no_argument, NULL, 0 }
 	};
@@ -234,7 +358,6 @@ thin_generate_metadata_cmd::run(int argc, char **argv)
 			break;
 
 		case 6:
-			fs.op = flags::METADATA_OP_SET_TRANSACTION_ID;
 			fs.trans_id = parse_uint64(optarg, "transaction id");
 			break;
 
@@ -250,18 +373,50 @@ thin_generate_metadata_cmd::run(int argc, char **argv)
 			fs.op = flags::METADATA_OP_SET_NEEDS_CHECK;
 			break;
 
+		case 10:
+			fs.op = flags::METADATA_OP_CREATE_THINS;
+			fs.nr_devs = parse_uint64(optarg, "device count");
+			break;
+
+		case 11:
+			fs.op = flags::METADATA_OP_CREATE_SNAPS;
+			fs.nr_devs = parse_uint64(optarg, "device count");
+			break;
+
 		case 1001:
-			fs.data_block_size = parse_uint64(optarg, "data block size");
+			fs.data_block_size = parse_sectors(optarg, "data block size");
+			break;
+
+		case 1002: {
+			bool has_suffix;
+			uint64_t n = parse_size(optarg, "nr data blocks", 1, &has_suffix);
+			if (has_suffix)
+				fs.nr_data_bytes = n;
+			else
+				fs.nr_data_blocks = n;
+			break;
+		}
+
+		case 2001:
+			fs.sb_time = parse_uint64(optarg, "time");
 			break;
 
-		case 1002:
-			fs.nr_data_blocks = parse_uint64(optarg, "nr data blocks");
+		case 2002:
+			fs.sb_metadata_snap = parse_uint64(optarg, "metadata snap");
+			break;
+
+		case 2003:
+			fs.sb_version = parse_uint64(optarg, "version");
 			break;
 
 		case 4001:
 			fs.origin = parse_uint64(optarg, "origin");
 			break;
 
+		case 4002:
+			fs.first_dev_id = parse_uint64(optarg, "first device id");
+			break;
+
 		case 'V':
 			cout << THIN_PROVISIONING_TOOLS_VERSION << endl;
 			return 0;
@@ -272,7 +427,7 @@ thin_generate_metadata_cmd::run(int argc, char **argv)
 		}
 	}
 
-	if (!fs.check_conformance()) {
+	if (!fs.resolve_nr_data_blocks() || !fs.check_conformance()) {
 		usage(cerr);
 		return 1;
 	}
@@ -16,21 +16,31 @@
 // with thin-provisioning-tools.  If not, see
 // <http://www.gnu.org/licenses/>.
 
+#include <algorithm>
+#include <cstdio>
+#include <cstring>
 #include <fstream>
 #include <iostream>
+#include <sstream>
 #include <getopt.h>
 #include <libgen.h>
 
 #include "base/disk_units.h"
+#include "base/exit_codes.h"
 #include "base/grid_layout.h"
 #include "boost/lexical_cast.hpp"
 #include "boost/optional.hpp"
 #include "boost/range.hpp"
+#include "persistent-data/errors.h"
 #include "persistent-data/file_utils.h"
 #include "thin-provisioning/commands.h"
+#include "thin-provisioning/device_labels.h"
 #include "thin-provisioning/human_readable_format.h"
+#include "thin-provisioning/lv_names.h"
 #include "thin-provisioning/metadata.h"
 #include "thin-provisioning/metadata_dumper.h"
+#include "thin-provisioning/metadata_index.h"
+#include "thin-provisioning/pool_status.h"
 #include "thin-provisioning/xml_format.h"
 #include "version.h"
 
@@ -101,6 +111,8 @@ namespace {
 
 	enum output_field {
 		DEV_ID,
+		LV_NAME,
+		LABEL,
 		MAPPED_BLOCKS,
 		EXCLUSIVE_BLOCKS,
 		SHARED_BLOCKS,
@@ -124,6 +136,8 @@ namespace {
 
 	char const *field_names[] = {
 		"DEV",
+		"NAME",
+		"LABEL",
 		"MAPPED_BLOCKS",
 		"EXCLUSIVE_BLOCKS",
 		"SHARED_BLOCKS",
@@ -168,10 +182,130 @@ namespace {
 
 	//------------------------------------------------
 
+	// Keys for --sort and --filter.  Sizes are compared in bytes.
+	enum device_key {
+		KEY_DEV_ID,
+		KEY_MAPPED_BLOCKS,
+		KEY_EXCLUSIVE_BLOCKS,
+		KEY_SHARED_BLOCKS,
+		KEY_MAPPED,
+		KEY_EXCLUSIVE,
+		KEY_SHARED,
+		KEY_TRANSACTION,
+		KEY_CREATION_TIME,
+		KEY_SNAPSHOT_TIME	// make sure this is always the last one
+	};
+
+	char const *key_names[] = {
+		"dev_id",
+		"mapped_blocks",
+		"exclusive_blocks",
+		"shared_blocks",
+		"mapped",
+		"exclusive",
+		"shared",
+		"transaction",
+		"create_time",
+		"snap_time"
+	};
+
+	optional<device_key> string_to_key(string const &str) {
+		for (unsigned i = 0; i < size(key_names); i++)
+			if (str == key_names[i])
+				return static_cast<device_key>(i);
+
+		return optional<device_key>();
+	}
+
+	bool size_key(device_key k) {
+		return k == KEY_MAPPED || k == KEY_EXCLUSIVE || k == KEY_SHARED;
+	}
+
+	bool exclusive_key(device_key k) {
+		return k == KEY_EXCLUSIVE_BLOCKS || k == KEY_SHARED_BLOCKS ||
+			k == KEY_EXCLUSIVE || k == KEY_SHARED;
+	}
+
+	// Sizes sort largest first, ids and times smallest, so oldest,
+	// first.  Either way --top picks the devices most likely to be
+	// of interest.
+	bool descending_key(device_key k) {
+		return k == KEY_MAPPED_BLOCKS || k == KEY_EXCLUSIVE_BLOCKS ||
+			k == KEY_SHARED_BLOCKS || size_key(k);
+	}
+
+	enum comparison {
+		CMP_LT,
+		CMP_LE,
+		CMP_GT,
+		CMP_GE,
+		CMP_EQ,
+		CMP_NE
+	};
+
+	struct device_filter {
+		device_key key;
+		comparison cmp;
+		::uint64_t value;
+
+		bool matches(::uint64_t v) const {
+			switch (cmp) {
+			case CMP_LT:
+				return v < value;
+			case CMP_LE:
+				return v <= value;
+			case CMP_GT:
+				return v > value;
+			case CMP_GE:
+				return v >= value;
+			case CMP_EQ:
+				return v == value;
+			case CMP_NE:
+				return v != value;
+			}
+
+			return false;
+		}
+	};
+
+	// Splits an expression such as 'mapped>10G' into its key,
+	// comparison and the text of the value.
+	bool split_filter(string const &expr, device_key &key,
+			  comparison &cmp, string &value) {
+		string::size_type op = expr.find_first_of("<>=!");
+		if (op == string::npos || op == 0)
+			return false;
+
+		optional<device_key> k = string_to_key(expr.substr(0, op));
+		if (!k)
+			return false;
+		key = *k;
+
+		char const *ops[] = {"<=", ">=", "==", "!=", "<", ">", "="};
+		comparison cmps[] = {CMP_LE, CMP_GE, CMP_EQ, CMP_NE, CMP_LT, CMP_GT, CMP_EQ};
+		for (unsigned i = 0; i < size(ops); i++) {
+			string o(ops[i]);
+			if (expr.compare(op, o.size(), o) == 0) {
+				cmp = cmps[i];
+				value = expr.substr(op + o.size());
+				return !value.empty();
+			}
+		}
+
+		return false;
+	}
+
+	enum output_format {
+		OUTPUT_TABLE,
+		OUTPUT_CSV,
+		OUTPUT_JSON
+	};
+
 	struct flags {
 		flags()
 			: use_metadata_snap(false),
-			  headers(true) {
+			  headers(true),
+			  output(OUTPUT_TABLE) {
 
 			fields.push_back(DEV_ID);
 			fields.push_back(MAPPED);
@@ -181,7 +315,15 @@ namespace {
 
 		bool use_metadata_snap;
 		bool headers;
+		output_format output;
 		vector<output_field> fields;
+		optional<device_key> sort;
+		vector<device_filter> filters;
+		optional<unsigned> top;
+		optional<string> pool;
+		optional<string> lv_names;
+		optional<string> lv_pool;
+		optional<string> index;
 	};
 
 	//------------------------------------------------
@@ -222,7 +364,7 @@ namespace {
 	};
 
 	void raise_metadata_damage() {
-		throw std::runtime_error("metadata contains errors (run thin_check for details).");
+		throw metadata_damage("metadata contains errors (run thin_check for details).");
 	}
 
 	class fatal_mapping_damage : public mapping_tree_detail::damage_visitor {
@@ -299,7 +441,81 @@ namespace {
 
 	//------------------------------------------------
 
-	bool pass1_needed(vector<output_field> const &fields) {
+	// What thin_ls reads from the metadata: the device details, and
+	// the exclusive counts if they've been worked out.  These are
+	// kept in the sidecar index shared with thin_dump (see
+	// metadata_index.h), so later runs against the same superblock
+	// needn't walk the trees again.
+	typedef map<block_address, block_address> exclusive_map;
+
+	struct ls_index {
+		ls_index()
+			: have_exclusives(false) {
+		}
+
+		bool have_exclusives;
+		dd_map details;
+		exclusive_map exclusives;
+	};
+
+	ls_index from_metadata_index(metadata_index const &mi) {
+		ls_index index;
+		index.have_exclusives = mi.have_exclusives_;
+
+		map<uint32_t, index_device>::const_iterator it;
+		for (it = mi.devices_.begin(); it != mi.devices_.end(); ++it) {
+			index.details.insert(make_pair(it->first, it->second.details_));
+			index.exclusives.insert(make_pair(it->first, it->second.exclusive_));
+		}
+
+		return index;
+	}
+
+	// Anything thin_dump recorded for the same superblock, such as
+	// the layout, is kept.  The listing doesn't depend on the index,
+	// so failing to write it is only a warning.
+	void save_index(string const &path, metadata::ptr md,
+			optional<metadata_index> const &cached,
+			ls_index const &index) {
+		try {
+			metadata_index mi = cached ? *cached : metadata_index();
+
+			dd_map::const_iterator it;
+			for (it = index.details.begin(); it != index.details.end(); ++it) {
+				dev_tree::key k = {it->first};
+				optional< ::uint64_t> root = md->mappings_top_level_->lookup(k);
+				if (!root)
+					throw runtime_error("couldn't find mapping tree root");
+
+				index_device &dev = mi.devices_[it->first];
+				dev.root_ = *root;
+				dev.details_ = it->second;
+				if (index.have_exclusives)
+					dev.exclusive_ = index.exclusives.find(it->first)->second;
+			}
+
+			if (index.have_exclusives)
+				mi.have_exclusives_ = true;
+
+			write_metadata_index(path, md->sb_, mi);
+
+		} catch (std::exception &e) {
+			cerr << "couldn't write index " << path << ": " << e.what() << endl;
+		}
+	}
+
+	//------------------------------------------------
+
+	bool pass1_needed(struct flags const &flags) {
+		if (flags.sort && exclusive_key(*flags.sort))
+			return true;
+
+		vector<device_filter>::const_iterator filter;
+		for (filter = flags.filters.begin(); filter != flags.filters.end(); ++filter)
+			if (exclusive_key(filter->key))
+				return true;
+
+		vector<output_field> const &fields = flags.fields;
 		vector<output_field>::const_iterator it;
 		for (it = fields.begin(); it != fields.end(); ++it) {
 			if (*it == EXCLUSIVE_BLOCKS ||
@@ -316,124 +532,378 @@ namespace {
 		return false;
 	}
 
-	void ls_(string const &path, ostream &out, struct flags &flags) {
+	::uint64_t key_value(device_key k, block_address dev_id,
+			     device_tree_detail::device_details const &dd,
+			     block_address exclusive, block_address block_size) {
+		block_address block_bytes = block_size * disk_unit_multiplier(UNIT_SECTOR);
+
+		switch (k) {
+		case KEY_DEV_ID:
+			return dev_id;
+
+		case KEY_MAPPED_BLOCKS:
+			return dd.mapped_blocks_;
+
+		case KEY_EXCLUSIVE_BLOCKS:
+			return exclusive;
+
+		case KEY_SHARED_BLOCKS:
+			return dd.mapped_blocks_ - exclusive;
+
+		case KEY_MAPPED:
+			return dd.mapped_blocks_ * block_bytes;
+
+		case KEY_EXCLUSIVE:
+			return exclusive * block_bytes;
+
+		case KEY_SHARED:
+			return (dd.mapped_blocks_ - exclusive) * block_bytes;
+
+		case KEY_TRANSACTION:
+			return dd.transaction_id_;
+
+		case KEY_CREATION_TIME:
+			return dd.creation_time_;
+
+		case KEY_SNAPSHOT_TIME:
+			return dd.snapshotted_time_;
+		}
+
+		throw runtime_error("unknown key");
+	}
+
+	// Applies --filter, --sort and --top, returning the devices to
+	// list in order.
+	vector<block_address> select_devices(struct flags const &flags,
+					     ls_index &index,
+					     block_address block_size) {
+		typedef pair< ::uint64_t, block_address> keyed_dev;
+		vector<keyed_dev> devs;
+
+		dd_map::const_iterator it;
+		for (it = index.details.begin(); it != index.details.end(); ++it) {
+			block_address exclusive = index.exclusives[it->first];
+
+			bool keep = true;
+			vector<device_filter>::const_iterator f;
+			for (f = flags.filters.begin(); keep && f != flags.filters.end(); ++f)
+				keep = f->matches(key_value(f->key, it->first, it->second,
+							    exclusive, block_size));
+			if (!keep)
+				continue;
+
+			::uint64_t k = flags.sort ?
+				key_value(*flags.sort, it->first, it->second, exclusive, block_size) : 0;
+			devs.push_back(make_pair(k, it->first));
+		}
+
+		// The details are in dev id order, so a stable sort keeps
+		// devices with equal keys in that order.
+		if (flags.sort) {
+			if (descending_key(*flags.sort))
+				stable_sort(devs.begin(), devs.end(),
+					    [](keyed_dev const &l, keyed_dev const &r) {
+						    return l.first > r.first;
+					    });
+			else
+				stable_sort(devs.begin(), devs.end(),
+					    [](keyed_dev const &l, keyed_dev const &r) {
+						    return l.first < r.first;
+					    });
+		}
+
+		if (flags.top && devs.size() > *flags.top)
+			devs.resize(*flags.top);
+
+		vector<block_address> dev_ids;
+		vector<keyed_dev>::const_iterator d;
+		for (d = devs.begin(); d != devs.end(); ++d)
+			dev_ids.push_back(d->second);
+
+		return dev_ids;
+	}
+
+	template <typename T>
+	string to_cell(T const &t) {
+		return lexical_cast<string>(t);
+	}
+
+	string field_value(output_field f, block_address dev_id,
+			   device_tree_detail::device_details const &dd,
+			   block_address exclusive, block_address block_size,
+			   lv_name_map const &names, label_map const &labels) {
+		block_address shared = dd.mapped_blocks_ - exclusive;
+		block_address sector_bytes = disk_unit_multiplier(UNIT_SECTOR);
+
+		switch (f) {
+		case DEV_ID:
+			return to_cell(dev_id);
+
+		case LV_NAME: {
+			lv_name_map::const_iterator n = names.find(dev_id);
+			return n == names.end() ? string("-") : n->second;
+		}
+
+		case LABEL: {
+			label_map::const_iterator l = labels.find(dev_id);
+			return (l == labels.end() || !l->second.label_) ? string("-") : *l->second.label_;
+		}
+
+		case MAPPED_BLOCKS:
+			return to_cell(dd.mapped_blocks_);
+
+		case EXCLUSIVE_BLOCKS:
+			return to_cell(exclusive);
+
+		case SHARED_BLOCKS:
+			return to_cell(shared);
+
+		case MAPPED_SECTORS:
+			return to_cell(dd.mapped_blocks_ * block_size);
+
+		case EXCLUSIVE_SECTORS:
+			return to_cell(exclusive * block_size);
+
+		case SHARED_SECTORS:
+			return to_cell(shared * block_size);
+
+		case MAPPED_BYTES:
+			return to_cell(dd.mapped_blocks_ * block_size * sector_bytes);
+
+		case EXCLUSIVE_BYTES:
+			return to_cell(exclusive * block_size * sector_bytes);
+
+		case SHARED_BYTES:
+			return to_cell(shared * block_size * sector_bytes);
+
+		case MAPPED:
+			return format_disk_unit(dd.mapped_blocks_ * block_size, UNIT_SECTOR);
+
+		case EXCLUSIVE:
+			return format_disk_unit(exclusive * block_size, UNIT_SECTOR);
+
+		case SHARED:
+			return format_disk_unit(shared * block_size, UNIT_SECTOR);
+
+		case TRANSACTION_ID:
+			return to_cell(dd.transaction_id_);
+
+		case CREATION_TIME:
+			return to_cell(dd.creation_time_);
+
+		case SNAPSHOT_TIME:
+			return to_cell(dd.snapshotted_time_);
+		}
+
+		throw runtime_error("unknown field");
+	}
+
+	// Everything but the names and the human readable sizes is a
+	// plain number, and is written to json unquoted.
+	bool numeric_field(output_field f) {
+		return f != LV_NAME && f != LABEL && f != MAPPED && f != EXCLUSIVE && f != SHARED;
+	}
+
+	void render_table(ostream &out, struct flags const &flags,
+			  vector<vector<string> > const &rows) {
 		grid_layout grid;
 
+		if (flags.headers)
+			print_headers(grid, flags.fields);
+
+		vector<vector<string> >::const_iterator r;
+		for (r = rows.begin(); r != rows.end(); ++r) {
+			vector<string>::const_iterator c;
+			for (c = r->begin(); c != r->end(); ++c)
+				grid.field(*c);
+			grid.new_row();
+		}
+
+		grid.render(out);
+	}
+
+	// RFC 4180: fields holding a comma, quote or line break are
+	// quoted, with any quotes doubled.
+	string csv_escape(string const &str) {
+		if (str.find_first_of(",\"\r\n") == string::npos)
+			return str;
+
+		string r = "\"";
+		for (string::const_iterator c = str.begin(); c != str.end(); ++c) {
+			if (*c == '"')
+				r += '"';
+			r += *c;
+		}
+		return r + "\"";
+	}
+
+	void render_csv(ostream &out, struct flags const &flags,
+			vector<vector<string> > const &rows) {
+		if (flags.headers) {
+			for (unsigned i = 0; i < flags.fields.size(); i++)
+				out << (i ? "," : "") << field_to_string(flags.fields[i]);
+			out << "\n";
+		}
+
+		vector<vector<string> >::const_iterator r;
+		for (r = rows.begin(); r != rows.end(); ++r) {
+			for (unsigned i = 0; i < r->size(); i++)
+				out << (i ? "," : "") << csv_escape((*r)[i]);
+			out << "\n";
+		}
+	}
+
+	string json_escape(string const &str) {
+		ostringstream r;
+		r << '"';
+		for (string::const_iterator c = str.begin(); c != str.end(); ++c) {
+			switch (*c) {
+			case '"':
+				r << "\\\"";
+				break;
+
+			case '\\':
+				r << "\\\\";
+				break;
+
+			case '\n':
+				r << "\\n";
+				break;
+
+			default:
+				if (static_cast<unsigned char>(*c) < 0x20) {
+					char buf[8];
+					snprintf(buf, sizeof(buf), "\\u%04x", static_cast<unsigned char>(*c));
+					r << buf;
+				} else
+					r << *c;
+			}
+		}
+		r << '"';
+		return r.str();
+	}
+
+	// An array with an object per device, keyed by the field names,
+	// so the headers are always present.
+	void render_json(ostream &out, struct flags const &flags,
+			 vector<vector<string> > const &rows) {
+		out << "[";
+		for (unsigned r = 0; r < rows.size(); r++) {
+			out << (r ? ",\n  {" : "\n  {");
+			for (unsigned i = 0; i < flags.fields.size(); i++) {
+				output_field f = flags.fields[i];
+				out << (i ? ", " : "") << json_escape(field_to_string(f)) << ": ";
+				if (numeric_field(f))
+					out << rows[r][i];
+				else
+					out << json_escape(rows[r][i]);
+			}
+			out << "}";
+		}
+		out << (rows.empty() ? "]\n" : "\n]\n");
+	}
+
+	void ls_(string const &path, ostream &out, struct flags &flags) {
 		block_manager::ptr bm(open_bm(path, block_manager::READ_ONLY,
 						!flags.use_metadata_snap));
 		metadata::ptr md;
 
+		// Compared against the live superblock, even when listing
+		// the metadata snap.
+		if (flags.pool)
+			check_pool_status(get_pool_status(*flags.pool),
+					  read_superblock(bm), cerr);
+
+		// Without any exclusive fields only the superblock and the
+		// details tree are read, so listing a pool is cheap, and works
+		// even if the mapping trees or space maps are damaged.
+		bool some_exclusive_fields = pass1_needed(flags);
+
 		if (flags.use_metadata_snap)
 			md.reset(new metadata(bm, optional<block_address>()));
 		else
-			md.reset(new metadata(bm));
+			md.reset(new metadata(bm, some_exclusive_fields));
 
 		block_address block_size = md->sb_.data_block_size_;
 
-		details_extractor de;
-		device_tree_detail::damage_visitor::ptr dd_policy(details_damage_policy());
-		walk_device_tree(*md->details_, de, *dd_policy);
+		lv_name_map names;
+		if (flags.lv_names)
+			names = read_lv_names(*flags.lv_names, flags.lv_pool);
+
+		// Labels are a nicety, so damaged ones are left out rather
+		// than stopping the listing.
+		label_map labels;
+		if (find(flags.fields.begin(), flags.fields.end(), LABEL) != flags.fields.end() &&
+		    md->sb_.labels_root_) {
+			try {
+				labels = read_device_labels(*bm, md->sb_.labels_root_).labels_;
+			} catch (std::exception &e) {
+				cerr << "couldn't read the device labels: " << e.what() << endl;
+			}
+		}
 
-		mapping_set mappings;
-		dd_map::const_iterator it;
-		dd_map const &map = de.get_details();
+		optional<metadata_index> cached;
+		if (flags.index)
+			cached = read_metadata_index(*flags.index, md->sb_);
 
-		bool some_exclusive_fields = pass1_needed(flags.fields);
-		if (some_exclusive_fields) {
-			for (it = map.begin(); it != map.end(); ++it)
-				pass1(md, mappings, it->first);
-		}
+		ls_index index;
+		if (cached && (cached->have_exclusives_ || !some_exclusive_fields))
+			index = from_metadata_index(*cached);
 
-		if (flags.headers)
-			print_headers(grid, flags.fields);
+		else {
+			details_extractor de;
+			device_tree_detail::damage_visitor::ptr dd_policy(details_damage_policy());
+			walk_device_tree(*md->details_, de, *dd_policy);
+			index.details = de.get_details();
 
-		for (it = map.begin(); it != map.end(); ++it) {
-			vector<output_field>::const_iterator f;
+			if (some_exclusive_fields) {
+				mapping_set mappings;
+				dd_map::const_iterator it;
+				for (it = index.details.begin(); it != index.details.end(); ++it)
+					pass1(md, mappings, it->first);
+
+				for (it = index.details.begin(); it != index.details.end(); ++it)
+					index.exclusives[it->first] = count_exclusives(md, mappings, it->first);
+
+				index.have_exclusives = true;
+			}
+
+			if (flags.index)
+				save_index(*flags.index, md, cached, index);
+		}
 
+		typedef vector<string> row;
+		vector<row> rows;
+
+		vector<block_address> dev_ids = select_devices(flags, index, block_size);
+		vector<block_address>::const_iterator dev_id;
+		for (dev_id = dev_ids.begin(); dev_id != dev_ids.end(); ++dev_id) {
 			block_address exclusive = 0;
 
 			if (some_exclusive_fields)
-				exclusive = count_exclusives(md, mappings, it->first);
-
-			for (f = flags.fields.begin(); f != flags.fields.end(); ++f) {
-				switch (*f) {
-				case DEV_ID:
-					grid.field(it->first);
-					break;
-
-				case MAPPED_BLOCKS:
-					grid.field(it->second.mapped_blocks_);
-					break;
-
-				case EXCLUSIVE_BLOCKS:
-					grid.field(exclusive);
-					break;
-
-				case SHARED_BLOCKS:
-					grid.field(it->second.mapped_blocks_ - exclusive);
-					break;
-
-				case MAPPED_SECTORS:
-					grid.field(it->second.mapped_blocks_ * block_size);
-					break;
-
-				case EXCLUSIVE_SECTORS:
-					grid.field(exclusive * block_size);
-					break;
-
-				case SHARED_SECTORS:
-					grid.field((it->second.mapped_blocks_ - exclusive) * block_size);
-					break;
-
-				case MAPPED_BYTES:
-					grid.field(it->second.mapped_blocks_ * block_size *
-					           disk_unit_multiplier(UNIT_SECTOR));
-					break;
-
-				case EXCLUSIVE_BYTES:
-					grid.field(exclusive * block_size *
-					           disk_unit_multiplier(UNIT_SECTOR));
-					break;
-
-				case SHARED_BYTES:
-					grid.field((it->second.mapped_blocks_ - exclusive) * block_size *
-					           disk_unit_multiplier(UNIT_SECTOR));
-					break;
-
-				case MAPPED:
-					grid.field(
-						format_disk_unit(it->second.mapped_blocks_ * block_size,
-								 UNIT_SECTOR));
-					break;
-
-				case EXCLUSIVE:
-					grid.field(
-						format_disk_unit(exclusive * block_size,
-								 UNIT_SECTOR));
-					break;
-
-				case SHARED:
-					grid.field(
-						format_disk_unit((it->second.mapped_blocks_ - exclusive) *
-								 block_size, UNIT_SECTOR));
-					break;
-
-				case TRANSACTION_ID:
-					grid.field(it->second.transaction_id_);
-					break;
-
-				case CREATION_TIME:
-					grid.field(it->second.creation_time_);
-					break;
-
-				case SNAPSHOT_TIME:
-					grid.field(it->second.snapshotted_time_);
-				}
-			}
-			grid.new_row();
+				exclusive = index.exclusives[*dev_id];
+
+			row r;
+			vector<output_field>::const_iterator f;
+			for (f = flags.fields.begin(); f != flags.fields.end(); ++f)
+				r.push_back(field_value(*f, *dev_id, index.details[*dev_id],
+							exclusive, block_size, names, labels));
+			rows.push_back(r);
 		}
 
-		grid.render(out);
+		switch (flags.output) {
+		case OUTPUT_TABLE:
+			render_table(out, flags, rows);
+			break;
+
+		case OUTPUT_CSV:
+			render_csv(out, flags, rows);
+			break;
+
+		case OUTPUT_JSON:
+			render_json(out, flags, rows);
+			break;
+		}
 	}
 
 	int ls(string const &path, ostream &out, struct flags &flags) {
@@ -442,10 +912,10 @@ namespace {
 
 		} catch (std::exception &e) {
 			cerr << e.what() << endl;
-			return 1;
+			return exit_code(e);
 		}
 
-		return 0;
+		return exit_codes::SUCCESS;
 	}
 }
 
@@ -464,12 +934,26 @@ thin_ls_cmd::usage(std::ostream &out) const
 	    << "  {-h|--help}\n"
 	    << "  {-m|--metadata-snap}\n"
 	    << "  {--no-headers}\n"
-	    << "  {-o|--format <fields>}\n"
+	    << "  {-o|--format <fields>|table|csv|json}\n"
+	    << "  {--pool <dm name>}\n"
+	    << "  {--lv-names <lvm metadata or name list>}\n"
+	    << "  {--lv-pool <lvm pool name>}\n"
+	    << "  {--index <file>}\n"
+	    << "  {--sort <key>}\n"
+	    << "  {--filter <key><op><value>}\n"
+	    << "  {--top <count>}\n"
 	    << "  {-V|--version}\n\n"
 	    << "where <fields> is a comma separated list from:\n";
 
 	for (unsigned i = 0; i <= static_cast<unsigned>(SNAPSHOT_TIME); i++)
             out << "  " << field_to_string(static_cast<output_field>(i)) << "\n";
+
+	out << "\nand <key> is one of:\n";
+	for (unsigned i = 0; i <= static_cast<unsigned>(KEY_SNAPSHOT_TIME); i++)
+		out << "  " << key_names[i] << "\n";
+
+	out << "\n<op> is one of <, <=, >, >=, = or !=, and sizes take a unit,\n"
+	    << "eg, 'mapped>10g'.\n";
 }
 
 vector<output_field> parse_fields(string const &str)
@@ -489,6 +973,7 @@ thin_ls_cmd::run(int argc, char **argv)
 {
 	int c;
 	struct flags flags;
+	bool fields_given = false;
 	const char shortopts[] = "ho:m::V";
 
 	const struct option longopts[] = {
@@ -497,6 +982,13 @@ thin_ls_cmd::run(int argc, char **argv)
 		{ "version", no_argument, NULL, 'V'},
 		{ "format", required_argument, NULL, 'o' },
 		{ "no-headers", no_argument, NULL, 1 },
+		{ "pool", required_argument, NULL, 2 },
+		{ "lv-names", required_argument, NULL, 3 },
+		{ "lv-pool", required_argument, NULL, 4 },
+		{ "index", required_argument, NULL, 5 },
+		{ "sort", required_argument, NULL, 6 },
+		{ "filter", required_argument, NULL, 7 },
+		{ "top", required_argument, NULL, 8 },
 		{ NULL, no_argument, NULL, 0 }
 	};
 
@@ -511,7 +1003,24 @@ thin_ls_cmd::run(int argc, char **argv)
 			break;
 
 		case 'o':
-			flags.fields = parse_fields(optarg);
+			// The output modes are lower case, so can't be
+			// mistaken for a field.
+			if (!strcmp(optarg, "table"))
+				flags.output = OUTPUT_TABLE;
+			else if (!strcmp(optarg, "csv"))
+				flags.output = OUTPUT_CSV;
+			else if (!strcmp(optarg, "json"))
+				flags.output = OUTPUT_JSON;
+			else {
+				try {
+					flags.fields = parse_fields(optarg);
+				} catch (std::exception &e) {
+					cerr << e.what() << " in '" << optarg << "'" << endl;
+					usage(cerr);
+					return exit_codes::USAGE;
+				}
+				fields_given = true;
+			}
 			break;
 
 		case 'V':
@@ -522,18 +1031,77 @@ thin_ls_cmd::run(int argc, char **argv)
 			flags.headers = false;
 			break;
 
+		case 2:
+			flags.pool = optarg;
+			break;
+
+		case 3:
+			flags.lv_names = optarg;
+			break;
+
+		case 4:
+			flags.lv_pool = optarg;
+			break;
+
+		case 5:
+			flags.index = optarg;
+			break;
+
+		case 6:
+			flags.sort = string_to_key(optarg);
+			if (!flags.sort) {
+				cerr << "unknown sort key '" << optarg << "'" << endl;
+				usage(cerr);
+				return exit_codes::USAGE;
+			}
+			break;
+
+		case 7: {
+			device_filter filter;
+			string value;
+			if (!split_filter(optarg, filter.key, filter.cmp, value)) {
+				cerr << "couldn't parse filter '" << optarg << "'" << endl;
+				usage(cerr);
+				return exit_codes::USAGE;
+			}
+
+			// Plain numbers are bytes for the sizes.
+			if (size_key(filter.key))
+				filter.value = parse_size(value.c_str(), "filter size", 1);
+			else
+				filter.value = parse_uint64(value.c_str(), "filter value");
+
+			flags.filters.push_back(filter);
+			break;
+		}
+
+		case 8:
+			flags.top = parse_uint64(optarg, "top");
+			break;
+
 		default:
 			usage(cerr);
-			return 1;
+			return exit_codes::USAGE;
 		}
 	}
 
+	if (flags.lv_pool && !flags.lv_names) {
+		cerr << "--lv-pool needs --lv-names" << endl;
+		usage(cerr);
+		return exit_codes::USAGE;
+	}
+
+	// Names are shown by default once they're available.
+	if (flags.lv_names && !fields_given)
+		flags.fields.insert(flags.fields.begin() + 1, LV_NAME);
+
 	if (argc == optind) {
 		cerr << "No input file provided." << endl;
 		usage(cerr);
-		return 1;
+		return exit_codes::USAGE;
 	}
 
+	exit_on_interrupt();
 	return ls(argv[optind], cout, flags);
 }
 
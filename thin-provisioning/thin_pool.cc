@@ -316,6 +316,18 @@ thin_pool::get_data_dev_size() const
 	return md_->data_sm_->get_nr_blocks();
 }
 
+block_address
+thin_pool::get_nr_free_metadata_blocks() const
+{
+	return md_->metadata_sm_->get_nr_free();
+}
+
+block_address
+thin_pool::get_metadata_dev_size() const
+{
+	return md_->metadata_sm_->get_nr_blocks();
+}
+
 uint32_t
 thin_pool::get_time() const
 {
@@ -435,6 +447,10 @@ thin_provisioning::process_write(thin::ptr td, thin_pool::ptr tp,
 	// TODO: handle out-of-space errors
 	block_address data_block = tp->alloc_data_block();
 	td->insert(blocknr, data_block);
+
+	// breaking sharing drops this device's reference to the old block
+	if (!!result)
+		tp->free_data_block(result->block_);
 }
 
 void
@@ -446,8 +462,9 @@ thin_provisioning::process_discard(thin::ptr td, thin_pool::ptr tp,
 	if (!result)
 		return;
 	td->remove(blocknr);
-	if (!result->shared_)
-		tp->free_data_block(result->block_);
+
+	// a shared block keeps the references held by the other devices
+	tp->free_data_block(result->block_);
 }
 
 //----------------------------------------------------------------
@@ -94,6 +94,8 @@ namespace thin_provisioning {
 		block_address get_nr_free_data_blocks() const;
 		sector_t get_data_block_size() const;
 		block_address get_data_dev_size() const;
+		block_address get_nr_free_metadata_blocks() const;
+		block_address get_metadata_dev_size() const;
 		uint32_t get_time() const;
 
 		thin::ptr open_thin(thin_dev_t);
@@ -1,3 +1,5 @@
+#include <algorithm>
+#include <cstring>
 #include <iostream>
 #include <getopt.h>
 #include <libgen.h>
@@ -6,8 +8,10 @@
 
 #include "version.h"
 
+#include "base/exit_codes.h"
 #include "base/run.h"
 #include "persistent-data/data-structures/btree_damage_visitor.h"
+#include "persistent-data/errors.h"
 #include "persistent-data/space-maps/core.h"
 #include "persistent-data/file_utils.h"
 #include "thin-provisioning/commands.h"
@@ -27,10 +31,15 @@ namespace {
 	typedef rmap_visitor::region region;
 	typedef rmap_visitor::rmap_region rmap_region;
 
+	enum output_format {
+		OUTPUT_TEXT,
+		OUTPUT_XML
+	};
+
 	class damage_visitor {
 	public:
 		virtual void visit(btree_path const &path, btree_detail::damage const &d) {
-			throw std::runtime_error("Damage in mapping tree, please run thin_check.\n");
+			throw base::metadata_damage("Damage in mapping tree, please run thin_check.\n");
 		}
 	};
 
@@ -47,9 +56,43 @@ namespace {
 		}
 	}
 
-	int rmap(string const &path, vector<region> const &regions) {
+	bool cmp_thin_begin(rmap_region const &lhs, rmap_region const &rhs) {
+		if (lhs.thin_dev != rhs.thin_dev)
+			return lhs.thin_dev < rhs.thin_dev;
+		return lhs.thin_begin < rhs.thin_begin;
+	}
+
+	// Mapping elements in the thin_dump schema, grouped by device, so
+	// they can be pasted into the device elements of a restore input.
+	void display_rmap_xml(ostream &out, vector<rmap_region> rmap) {
+		std::sort(rmap.begin(), rmap.end(), cmp_thin_begin);
+
+		vector<rmap_region>::const_iterator it;
+		for (it = rmap.begin(); it != rmap.end(); ++it) {
+			rmap_region const &r = *it;
+			if (it == rmap.begin() || (it - 1)->thin_dev != r.thin_dev)
+				out << "<!-- device " << r.thin_dev << " -->" << endl;
+
+			block_address len = r.data_end - r.data_begin;
+			if (len == 1)
+				out << "<single_mapping origin_block=\"" << r.thin_begin
+				    << "\" data_block=\"" << r.data_begin
+				    << "\" time=\"" << r.time
+				    << "\"/>" << endl;
+			else
+				out << "<range_mapping origin_begin=\"" << r.thin_begin
+				    << "\" data_begin=\"" << r.data_begin
+				    << "\" length=\"" << len
+				    << "\" time=\"" << r.time
+				    << "\"/>" << endl;
+		}
+	}
+
+	int rmap(string const &path, vector<region> const &regions, output_format format) {
 		damage_visitor dv;
-		rmap_visitor rv;
+
+		// Each xml mapping has a single time.
+		rmap_visitor rv(format == OUTPUT_XML);
 
 		try {
 			vector<region>::const_iterator it;
@@ -66,14 +109,17 @@ namespace {
 
 			btree_visit_values(mtree, rv, dv);
 			rv.complete();
-			display_rmap(cout, rv.get_rmap());
+			if (format == OUTPUT_XML)
+				display_rmap_xml(cout, rv.get_rmap());
+			else
+				display_rmap(cout, rv.get_rmap());
 
 		} catch (std::exception const &e) {
 			cerr << e.what();
-			return 1;
+			return base::exit_code(e);
 		}
 
-		return 0;
+		return base::exit_codes::SUCCESS;
 	}
 
 	region parse_region(string const &str) {
@@ -112,6 +158,7 @@ thin_rmap_cmd::usage(std::ostream &out) const
 	    << "Options:" << endl
 	    << "  {-h|--help}" << endl
 	    << "  {-V|--version}" << endl
+	    << "  {-f|--format} {text|xml}" << endl
 	    << "  {--region <block range>}*" << endl
 	    << "Where:" << endl
 	    << "  <block range> is of the form <begin>..<one-past-the-end>" << endl
@@ -123,10 +170,12 @@ thin_rmap_cmd::run(int argc, char **argv)
 {
 	int c;
 	vector<region> regions;
-	char const shortopts[] = "hV";
+	output_format format = OUTPUT_TEXT;
+	char const shortopts[] = "hVf:";
 	option const longopts[] = {
 		{ "help", no_argument, NULL, 'h'},
 		{ "version", no_argument, NULL, 'V'},
+		{ "format", required_argument, NULL, 'f'},
 		{ "region", required_argument, NULL, 1},
 		{ NULL, no_argument, NULL, 0 }
 	};
@@ -141,6 +190,18 @@ thin_rmap_cmd::run(int argc, char **argv)
 			cout << THIN_PROVISIONING_TOOLS_VERSION << endl;
 			return 0;
 
+		case 'f':
+			if (!strcmp(optarg, "text"))
+				format = OUTPUT_TEXT;
+			else if (!strcmp(optarg, "xml"))
+				format = OUTPUT_XML;
+			else {
+				cerr << "unknown format '" << optarg << "'" << endl;
+				usage(cerr);
+				return base::exit_codes::USAGE;
+			}
+			break;
+
 		case 1:
 			// region
 			try {
@@ -148,30 +209,31 @@ thin_rmap_cmd::run(int argc, char **argv)
 
 			} catch (std::exception const &e) {
 				cerr << e.what();
-				return 1;
+				return base::exit_codes::USAGE;
 			}
 
 			break;
 
 		default:
 			usage(cerr);
-			return 1;
+			return base::exit_codes::USAGE;
 		}
 	}
 
 	if (argc == optind) {
 		cerr << "No input file provided." << endl;
 		usage(cerr);
-		exit(1);
+		exit(base::exit_codes::USAGE);
 	}
 
 	if (!regions.size()) {
 		cerr << "No regions provided." << endl;
 		usage(cerr);
-		exit(1);
+		exit(base::exit_codes::USAGE);
 	}
 
-	return rmap(argv[optind], regions);
+	base::exit_on_interrupt();
+	return rmap(argv[optind], regions, format);
 }
 
 //----------------------------------------------------------------
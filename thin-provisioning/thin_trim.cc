@@ -1,7 +1,11 @@
+#include <chrono>
+#include <fstream>
 #include <iostream>
+#include <thread>
 #include <getopt.h>
 #include <sys/ioctl.h>
 #include <sys/stat.h>
+#include <sys/sysmacros.h>
 #include <linux/fs.h>
 #include <libgen.h>
 #include <fcntl.h>
@@ -24,31 +28,107 @@ using namespace thin_provisioning;
 //----------------------------------------------------------------
 
 namespace {
+	struct discard_options {
+		discard_options()
+			: max_discard_bytes(0),
+			  max_rate(0) {
+		}
+
+		// 0 means the device's own limit
+		uint64_t max_discard_bytes;
+
+		// bytes per second, 0 means unthrottled
+		uint64_t max_rate;
+	};
+
+	// The discard limits the block layer reports for a device.
+	struct queue_limits {
+		uint64_t granularity_;
+		uint64_t alignment_;
+		uint64_t max_bytes_;
+	};
+
+	bool read_sysfs(string const &path, uint64_t &value) {
+		ifstream in(path.c_str());
+		return (in >> value) ? true : false;
+	}
+
+	// A partition's queue belongs to its parent disk.
+	queue_limits get_queue_limits(dev_t dev) {
+		ostringstream dir;
+		dir << "/sys/dev/block/" << major(dev) << ":" << minor(dev);
+
+		queue_limits ql;
+		if (!read_sysfs(dir.str() + "/queue/discard_granularity", ql.granularity_) &&
+		    !read_sysfs(dir.str() + "/../queue/discard_granularity", ql.granularity_))
+			throw runtime_error("Couldn't read the discard limits of the data device");
+
+		if (!read_sysfs(dir.str() + "/queue/discard_max_bytes", ql.max_bytes_) &&
+		    !read_sysfs(dir.str() + "/../queue/discard_max_bytes", ql.max_bytes_))
+			throw runtime_error("Couldn't read the discard limits of the data device");
+
+		if (!read_sysfs(dir.str() + "/discard_alignment", ql.alignment_))
+			ql.alignment_ = 0;
+
+		if (!ql.max_bytes_)
+			throw runtime_error("Data device doesn't support discard");
+
+		// discard_granularity is 0 on some older kernels
+		if (!ql.granularity_)
+			ql.granularity_ = 512;
+		ql.alignment_ %= ql.granularity_;
+
+		return ql;
+	}
+
 	class discard_emitter {
 	public:
-		discard_emitter(string const &data_dev, unsigned block_size, uint64_t nr_blocks)
-			: fd_(open_dev(data_dev, block_size * nr_blocks)),
-			  block_size_(block_size) {
+		discard_emitter(string const &data_dev, unsigned block_size, uint64_t nr_blocks,
+				discard_options const &opts)
+			: fd_(open_dev(data_dev, block_size * nr_blocks, limits_)),
+			  block_size_(block_size),
+			  max_rate_(opts.max_rate),
+			  nr_discards_(0),
+			  nr_bytes_(0),
+			  start_(chrono::steady_clock::now()) {
+
+			// Each discard is a whole number of granules.
+			max_bytes_ = limits_.max_bytes_;
+			if (opts.max_discard_bytes)
+				max_bytes_ = min<uint64_t>(max_bytes_, opts.max_discard_bytes);
+			max_bytes_ -= max_bytes_ % limits_.granularity_;
+			if (!max_bytes_)
+				max_bytes_ = limits_.granularity_;
 		}
 
 		~discard_emitter() {
 			::close(fd_);
 		}
 
+		// Discards the blocks [b, e).  Partial granules at either
+		// end are left alone, the device would ignore them anyway.
 		void emit(block_address b, block_address e) {
-			uint64_t range[2];
+			uint64_t begin = align_up(block_to_byte(b));
+			uint64_t end = align_down(block_to_byte(e));
 
-			range[0] = block_to_byte(b);
-			range[1] = block_to_byte(e) - range[0];
+			while (begin < end) {
+				uint64_t len = min<uint64_t>(end - begin, max_bytes_);
+				discard(begin, len);
+				begin += len;
+			}
+		}
 
-			cerr << "emitting discard for blocks (" << b << ", " << e << "]\n";
+		uint64_t get_nr_discards() const {
+			return nr_discards_;
+		}
 
-			if (ioctl(fd_, BLKDISCARD, &range))
-				throw runtime_error("discard ioctl failed");
+		uint64_t get_nr_bytes() const {
+			return nr_bytes_;
 		}
 
 	private:
-		static int open_dev(string const &data_dev, uint64_t expected_size) {
+		static int open_dev(string const &data_dev, uint64_t expected_size,
+				    queue_limits &limits) {
 			int r, fd;
 			uint64_t blksize;
 			struct stat info;
@@ -75,6 +155,8 @@ namespace {
 				if (blksize != (expected_size << 9))
 					throw runtime_error("Data device is not the expected size");
 
+				limits = get_queue_limits(info.st_rdev);
+
 			} catch (...) {
 				::close(fd);
 				throw;
@@ -83,12 +165,56 @@ namespace {
 			return fd;
 		}
 
+		void discard(uint64_t begin, uint64_t len) {
+			uint64_t range[2] = {begin, len};
+
+			if (ioctl(fd_, BLKDISCARD, &range))
+				throw runtime_error("discard ioctl failed");
+
+			nr_discards_++;
+			nr_bytes_ += len;
+			throttle();
+		}
+
+		// Sleeps until the average rate since we started is
+		// back under the limit.
+		void throttle() {
+			if (!max_rate_)
+				return;
+
+			// Whole seconds, then the part second, since
+			// nr_bytes_ * 10^9 would overflow after 18GB.
+			chrono::seconds whole(nr_bytes_ / max_rate_);
+			chrono::nanoseconds part(static_cast<uint64_t>(
+				(nr_bytes_ % max_rate_) * 1000000000.0L / max_rate_));
+			this_thread::sleep_until(start_ + whole + part);
+		}
+
+		uint64_t offset(uint64_t byte) const {
+			return (byte + limits_.granularity_ - limits_.alignment_) % limits_.granularity_;
+		}
+
+		uint64_t align_up(uint64_t byte) const {
+			uint64_t o = offset(byte);
+			return o ? byte + limits_.granularity_ - o : byte;
+		}
+
+		uint64_t align_down(uint64_t byte) const {
+			return byte - min(offset(byte), byte);
+		}
+
 		uint64_t block_to_byte(block_address b) {
 			return (b * block_size_) << 9;
 		}
 
+		queue_limits limits_;
 		int fd_;
 		unsigned block_size_;
+		uint64_t max_bytes_;
+		uint64_t max_rate_;
+		uint64_t nr_discards_;
+		uint64_t nr_bytes_;
+		chrono::steady_clock::time_point start_;
 	};
 
 	class trim_iterator : public space_map::iterator {
@@ -101,8 +227,9 @@ namespace {
 			highest_ = b;
 
 			if (count) {
-				if (last_referenced_ && (b > *last_referenced_ + 1))
-					emitter_.emit(*last_referenced_ + 1, b);
+				block_address free_begin = last_referenced_ ? *last_referenced_ + 1 : 0;
+				if (b > free_begin)
+					emitter_.emit(free_begin, b);
 
 				last_referenced_ = b;
 			}
@@ -111,7 +238,7 @@ namespace {
 		void complete() {
 			if (last_referenced_) {
 				if (*last_referenced_ != *highest_)
-					emitter_.emit(*last_referenced_, *highest_ + 1ull);
+					emitter_.emit(*last_referenced_ + 1ull, *highest_ + 1ull);
 
 			} else if (highest_)
 				emitter_.emit(0ull, *highest_ + 1);
@@ -123,9 +250,8 @@ namespace {
 		boost::optional<block_address> highest_;
 	};
 
-	int trim(string const &metadata_dev, string const &data_dev) {
-		cerr << "in trim\n";
-
+	int trim(string const &metadata_dev, string const &data_dev,
+		 discard_options const &opts) {
 		// We can trim any block that has zero count in the data
 		// space map.
 		block_manager::ptr bm = open_bm(metadata_dev, block_manager::READ_ONLY);
@@ -137,18 +263,22 @@ namespace {
 		}
 
 		discard_emitter de(data_dev, md.sb_.data_block_size_,
-				   md.data_sm_->get_nr_blocks());
+				   md.data_sm_->get_nr_blocks(), opts);
 		trim_iterator ti(de);
 
 		md.data_sm_->iterate(ti);
 		ti.complete();
 
+		cout << "discarded " << de.get_nr_bytes() << " bytes in "
+		     << de.get_nr_discards() << " requests" << endl;
+
 		return 0;
 	}
 
 	struct flags {
 		boost::optional<string> metadata_dev;
 		boost::optional<string> data_dev;
+		discard_options opts;
 	};
 }
 
@@ -165,6 +295,8 @@ thin_trim_cmd::usage(std::ostream &out) const
 	out << "Usage: " << get_name() << " [options] --metadata-dev {device|file} --data-dev {device|file}\n"
 	    << "Options:\n"
 	    << "  {-h|--help}\n"
+	    << "  {--max-discard} <size of each discard request, eg, 1G>\n"
+	    << "  {--max-rate} <bytes discarded per second, eg, 10G>\n"
 	    << "  {-V|--version}" << endl;
 }
 
@@ -181,6 +313,8 @@ thin_trim_cmd::run(int argc, char **argv)
 		{ "metadata-dev", required_argument, NULL, 0 },
 		{ "data-dev", required_argument, NULL, 1 },
 		{ "pool-inactive", no_argument, NULL, 2 },
+		{ "max-discard", required_argument, NULL, 3 },
+		{ "max-rate", required_argument, NULL, 4 },
 		{ NULL, no_argument, NULL, 0 }
 	};
 
@@ -198,6 +332,14 @@ thin_trim_cmd::run(int argc, char **argv)
 			cerr << "--pool-inactive no longer required since we ensure the metadata device is opened exclusively.\n";
 			break;
 
+		case 3:
+			fs.opts.max_discard_bytes = parse_size(optarg, "max discard", 1);
+			break;
+
+		case 4:
+			fs.opts.max_rate = parse_size(optarg, "max rate", 1);
+			break;
+
 		case 'h':
 			usage(cout);
 			return 0;
@@ -217,7 +359,7 @@ thin_trim_cmd::run(int argc, char **argv)
 		return 1;
 	}
 
-	return trim(*fs.metadata_dev, *fs.data_dev);
+	return trim(*fs.metadata_dev, *fs.data_dev, fs.opts);
 }
 
 //----------------------------------------------------------------
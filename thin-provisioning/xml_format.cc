@@ -25,6 +25,7 @@
 #include <boost/optional.hpp>
 #include <iostream>
 #include <map>
+#include <set>
 #include <sstream>
 #include <stdexcept>
 #include <string.h>
@@ -140,15 +141,50 @@ namespace {
 	//------------------------------------------------
 	// XML parser
 	//------------------------------------------------
-	void parse_superblock(emitter *e, attributes const &attr) {
-		e->begin_superblock(get_attr<string>(attr, "uuid"),
-				    get_attr<uint64_t>(attr, "time"),
-				    get_attr<uint64_t>(attr, "transaction"),
-				    get_opt_attr<uint32_t>(attr, "flags"),
-				    get_opt_attr<uint32_t>(attr, "version"),
-				    get_attr<uint32_t>(attr, "data_block_size"),
-				    get_attr<uint64_t>(attr, "nr_data_blocks"),
-				    get_opt_attr<uint64_t>(attr, "metadata_snap"));
+	// The newest schema version we understand.  Dumps from newer
+	// tools may contain elements we don't know about, these are
+	// skipped, along with everything inside them, with a warning.
+	// In dumps of a version we do know they're still an error.
+	uint32_t const MAX_XML_VERSION = 2;
+
+	struct parser_state {
+		parser_state(emitter *e)
+			: e_(e),
+			  newer_version_(false),
+			  skip_depth_(0) {
+		}
+
+		emitter *e_;
+		bool newer_version_;
+		unsigned skip_depth_;
+		set<string> warned_;
+	};
+
+	void unknown_tag(parser_state *s, char const *el) {
+		if (!s->newer_version_)
+			throw runtime_error("unknown tag type");
+
+		if (s->warned_.insert(el).second)
+			cerr << "warning: ignoring unknown element '" << el << "'" << endl;
+	}
+
+	void parse_superblock(parser_state *s, attributes const &attr) {
+		boost::optional<uint32_t> version = get_opt_attr<uint32_t>(attr, "version");
+		if (version && *version > MAX_XML_VERSION) {
+			s->newer_version_ = true;
+			cerr << "warning: xml schema version " << *version
+			     << " is newer than this tool supports (" << MAX_XML_VERSION
+			     << "), unknown elements will be ignored" << endl;
+		}
+
+		s->e_->begin_superblock(get_attr<string>(attr, "uuid"),
+					get_attr<uint64_t>(attr, "time"),
+					get_attr<uint64_t>(attr, "transaction"),
+					get_opt_attr<uint32_t>(attr, "flags"),
+					version,
+					get_attr<uint32_t>(attr, "data_block_size"),
+					get_attr<uint64_t>(attr, "nr_data_blocks"),
+					get_opt_attr<uint64_t>(attr, "metadata_snap"));
 	}
 
 	void parse_device(emitter *e, attributes const &attr) {
@@ -173,13 +209,19 @@ namespace {
 	}
 
 	void start_tag(void *data, char const *el, char const **attr) {
-		emitter *e = static_cast<emitter *>(data);
+		parser_state *s = static_cast<parser_state *>(data);
+		emitter *e = s->e_;
 		attributes a;
 
+		if (s->skip_depth_) {
+			s->skip_depth_++;
+			return;
+		}
+
 		build_attributes(a, attr);
 
 		if (!strcmp(el, "superblock"))
-			parse_superblock(e, a);
+			parse_superblock(s, a);
 
 		else if (!strcmp(el, "device"))
 			parse_device(e, a);
@@ -190,12 +232,20 @@ namespace {
 		else if (!strcmp(el, "single_mapping"))
 			parse_single_mapping(e, a);
 
-		else
-			throw runtime_error("unknown tag type");
+		else {
+			unknown_tag(s, el);
+			s->skip_depth_ = 1;
+		}
 	}
 
 	void end_tag(void *data, const char *el) {
-		emitter *e = static_cast<emitter *>(data);
+		parser_state *s = static_cast<parser_state *>(data);
+		emitter *e = s->e_;
+
+		if (s->skip_depth_) {
+			s->skip_depth_--;
+			return;
+		}
 
 		if (!strcmp(el, "superblock"))
 			e->end_superblock();
@@ -226,8 +276,9 @@ void
 tp::parse_xml(std::string const &backup_file, emitter::ptr e, bool quiet)
 {
 	xml_parser p;
+	parser_state state(e.get());
 
-	XML_SetUserData(p.get_parser(), e.get());
+	XML_SetUserData(p.get_parser(), &state);
 	XML_SetElementHandler(p.get_parser(), start_tag, end_tag);
 
 	p.parse(backup_file, quiet);
@@ -0,0 +1,115 @@
+#include "gmock/gmock.h"
+
+#include "base/io_trace.h"
+
+#include <fstream>
+#include <unistd.h>
+
+using namespace base;
+using namespace std;
+using namespace testing;
+
+//----------------------------------------------------------------
+
+namespace {
+	class IOTraceTests : public Test {
+	public:
+		IOTraceTests()
+			: path_("./io_trace_t.tmp") {
+		}
+
+		~IOTraceTests() {
+			::unlink(path_.c_str());
+		}
+
+		void write_trace(string const &text) {
+			ofstream out(path_.c_str());
+			out << text;
+		}
+
+		io_generator::ptr create(sector_t block_size,
+					 sector_t offset = 0,
+					 sector_t size = 0) {
+			return create_trace_io_generator(path_, block_size, offset, size);
+		}
+
+		void expect_io(io_generator::ptr gen, unsigned op, sector_t sector, sector_t size) {
+			base::io io;
+			ASSERT_TRUE(gen->next(io));
+			ASSERT_EQ(op, io.op_);
+			ASSERT_EQ(sector, io.sector_);
+			ASSERT_EQ(size, io.size_);
+		}
+
+		void expect_end(io_generator::ptr gen) {
+			base::io io;
+			ASSERT_FALSE(gen->next(io));
+		}
+
+	private:
+		string path_;
+	};
+}
+
+//----------------------------------------------------------------
+
+TEST_F(IOTraceTests, missing_trace_throws)
+{
+	ASSERT_THROW(create_trace_io_generator("./no-such-trace", 128, 0, 0), runtime_error);
+}
+
+TEST_F(IOTraceTests, blktrace_queue_events)
+{
+	write_trace("  8,0    3        1     0.000000000   697  Q  WS 200 + 8 [kjournald]\n"
+		    "  8,0    3        2     0.000000100   697  G  WS 200 + 8 [kjournald]\n"
+		    "  8,0    3        3     0.000000200   697  Q   R 512 + 8 [cat]\n"
+		    "  8,0    3        4     0.000000300   697  Q   D 120 + 272 [fstrim]\n");
+
+	io_generator::ptr gen = create(128);
+	expect_io(gen, REQ_OP_WRITE, 128, 128);
+	expect_io(gen, REQ_OP_DISCARD, 128, 128);
+	expect_io(gen, REQ_OP_DISCARD, 256, 128);
+	expect_end(gen);
+}
+
+TEST_F(IOTraceTests, fio_v2_log)
+{
+	write_trace("fio version 2 iolog\n"
+		    "/dev/sdb add\n"
+		    "/dev/sdb open\n"
+		    "/dev/sdb write 0 131072\n"
+		    "/dev/sdb read 131072 4096\n"
+		    "/dev/sdb trim 262144 65536\n"
+		    "/dev/sdb close\n");
+
+	io_generator::ptr gen = create(128);
+	expect_io(gen, REQ_OP_WRITE, 0, 128);
+	expect_io(gen, REQ_OP_WRITE, 128, 128);
+	expect_io(gen, REQ_OP_DISCARD, 512, 128);
+	expect_end(gen);
+}
+
+TEST_F(IOTraceTests, partial_discards_are_dropped)
+{
+	write_trace("fio version 2 iolog\n"
+		    "/dev/sdb trim 4096 65536\n"
+		    "/dev/sdb trim 65535 65536\n"
+		    "/dev/sdb write 4096 512\n");
+
+	io_generator::ptr gen = create(128);
+	expect_io(gen, REQ_OP_WRITE, 0, 128);
+	expect_end(gen);
+}
+
+TEST_F(IOTraceTests, offset_and_size_clip_extents)
+{
+	write_trace("fio version 3 iolog\n"
+		    "10 /dev/sdb write 0 65536\n"
+		    "20 /dev/sdb write 1048576 65536\n");
+
+	io_generator::ptr gen = create(128, 256, 512);
+	expect_io(gen, REQ_OP_WRITE, 256, 128);
+	expect_end(gen);
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,111 @@
+#include "gmock/gmock.h"
+
+#include "thin-provisioning/lv_names.h"
+
+#include <fstream>
+#include <unistd.h>
+
+using namespace std;
+using namespace testing;
+using namespace thin_provisioning;
+
+//----------------------------------------------------------------
+
+namespace {
+	char const *two_pools =
+		"# Generated by LVM2\n"
+		"description = \"Created *after* executing 'lvcreate {'\"\n"
+		"vg0 {\n"
+		"	logical_volumes {\n"
+		"		pool {\n"
+		"			segment1 {\n"
+		"				type = \"thin-pool\"\n"
+		"				transaction_id = 3\n"
+		"			}\n"
+		"		}\n"
+		"		data {\n"
+		"			status = [\"READ\",\n"
+		"				  \"WRITE\"]\n"
+		"			segment1 {\n"
+		"				type = \"thin\"\n"
+		"				thin_pool = \"pool\"\n"
+		"				device_id = 1\n"
+		"			}\n"
+		"		}\n"
+		"		backup {\n"
+		"			segment1 {\n"
+		"				device_id = 1	# same id, other pool\n"
+		"				thin_pool = \"pool2\"\n"
+		"				type = \"thin\"\n"
+		"			}\n"
+		"		}\n"
+		"	}\n"
+		"}\n";
+
+	class LVNamesTests : public Test {
+	public:
+		LVNamesTests()
+			: path_("./lv_names_t.tmp") {
+		}
+
+		~LVNamesTests() {
+			::unlink(path_.c_str());
+		}
+
+		lv_name_map read(string const &text,
+				 boost::optional<string> const &pool = boost::optional<string>()) {
+			ofstream out(path_.c_str());
+			out << text;
+			out.close();
+
+			return read_lv_names(path_, pool);
+		}
+
+	private:
+		string path_;
+	};
+}
+
+//----------------------------------------------------------------
+
+TEST_F(LVNamesTests, missing_file_throws)
+{
+	ASSERT_THROW(read_lv_names("./no-such-file", boost::optional<string>()), runtime_error);
+}
+
+TEST_F(LVNamesTests, reads_name_list)
+{
+	lv_name_map names = read("  vg0/pool\n  1 vg0/data\n  2 vg0/snap\n");
+	ASSERT_EQ(2u, names.size());
+	ASSERT_EQ("vg0/data", names[1]);
+	ASSERT_EQ("vg0/snap", names[2]);
+}
+
+TEST_F(LVNamesTests, bad_name_list_throws)
+{
+	ASSERT_THROW(read("one vg0/data\n"), runtime_error);
+	ASSERT_THROW(read("1 vg0/data extra\n"), runtime_error);
+}
+
+TEST_F(LVNamesTests, lvm_metadata_needs_a_pool_if_ambiguous)
+{
+	ASSERT_THROW(read(two_pools), runtime_error);
+}
+
+TEST_F(LVNamesTests, reads_lvm_metadata_for_a_pool)
+{
+	lv_name_map names = read(two_pools, string("pool"));
+	ASSERT_EQ(1u, names.size());
+	ASSERT_EQ("vg0/data", names[1]);
+
+	names = read(two_pools, string("pool2"));
+	ASSERT_EQ(1u, names.size());
+	ASSERT_EQ("vg0/backup", names[1]);
+}
+
+TEST_F(LVNamesTests, unknown_pool_throws)
+{
+	ASSERT_THROW(read(two_pools, string("nopool")), runtime_error);
+}
+
+//----------------------------------------------------------------
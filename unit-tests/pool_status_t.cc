@@ -0,0 +1,103 @@
+#include "gmock/gmock.h"
+
+#include "persistent-data/space-maps/disk_structures.h"
+#include "thin-provisioning/pool_status.h"
+
+#include <sstream>
+#include <string.h>
+
+using namespace persistent_data;
+using namespace std;
+using namespace testing;
+using namespace thin_provisioning;
+
+//----------------------------------------------------------------
+
+namespace {
+	class PoolStatusTests : public Test {
+	public:
+		superblock_detail::superblock mk_superblock(uint64_t trans_id,
+							     block_address used_metadata,
+							     block_address nr_metadata,
+							     block_address used_data,
+							     block_address nr_data) {
+			superblock_detail::superblock sb;
+			memset(&sb, 0, sizeof(sb));
+			sb.trans_id_ = trans_id;
+			set_sm_root(sb.metadata_space_map_root_, used_metadata, nr_metadata);
+			set_sm_root(sb.data_space_map_root_, used_data, nr_data);
+			return sb;
+		}
+
+	private:
+		void set_sm_root(unsigned char *root, block_address used, block_address nr) {
+			sm_disk_detail::sm_root v;
+			sm_disk_detail::sm_root_disk d;
+
+			memset(&v, 0, sizeof(v));
+			v.nr_blocks_ = nr;
+			v.nr_allocated_ = used;
+			sm_disk_detail::sm_root_traits::pack(v, d);
+			memcpy(root, &d, sizeof(d));
+		}
+	};
+}
+
+//----------------------------------------------------------------
+
+TEST_F(PoolStatusTests, parses_full_status)
+{
+	pool_status s = parse_pool_status("7 141/4096 1024/65536 - rw discard_passdown "
+					  "queue_if_no_space - 1024");
+	ASSERT_EQ(pool_status::READ_WRITE, s.mode_);
+	ASSERT_EQ(7u, s.transaction_id_);
+	ASSERT_EQ(141u, s.used_metadata_blocks_);
+	ASSERT_EQ(4096u, s.nr_metadata_blocks_);
+	ASSERT_EQ(1024u, s.used_data_blocks_);
+	ASSERT_EQ(65536u, s.nr_data_blocks_);
+	ASSERT_FALSE(s.held_root_);
+	ASSERT_FALSE(s.needs_check_);
+}
+
+TEST_F(PoolStatusTests, parses_held_root_and_needs_check)
+{
+	pool_status s = parse_pool_status("1 10/100 0/10 55 ro discard_passdown "
+					  "error_if_no_space needs_check 64");
+	ASSERT_EQ(pool_status::READ_ONLY, s.mode_);
+	ASSERT_TRUE(s.held_root_);
+	ASSERT_EQ(55u, *s.held_root_);
+	ASSERT_TRUE(s.needs_check_);
+}
+
+TEST_F(PoolStatusTests, parses_failed_pool)
+{
+	ASSERT_EQ(pool_status::FAILED, parse_pool_status("Fail").mode_);
+}
+
+TEST_F(PoolStatusTests, rejects_garbage)
+{
+	ASSERT_THROW(parse_pool_status(""), runtime_error);
+	ASSERT_THROW(parse_pool_status("1 10/100 0/10 - sideways"), runtime_error);
+	ASSERT_THROW(parse_pool_status("x 10/100 0/10 - rw"), runtime_error);
+	ASSERT_THROW(parse_pool_status("1 10-100 0/10 - rw"), runtime_error);
+}
+
+TEST_F(PoolStatusTests, matching_superblock_has_no_warnings)
+{
+	ostringstream out;
+	pool_status s = parse_pool_status("7 141/4096 1024/65536 - rw");
+	ASSERT_TRUE(check_pool_status(s, mk_superblock(7, 141, 4096, 1024, 65536), out));
+	ASSERT_EQ("", out.str());
+}
+
+TEST_F(PoolStatusTests, mismatches_are_reported)
+{
+	ostringstream out;
+	pool_status s = parse_pool_status("8 141/4096 2048/65536 - rw");
+	ASSERT_FALSE(check_pool_status(s, mk_superblock(7, 141, 4096, 1024, 65536), out));
+	ASSERT_THAT(out.str(), HasSubstr("transaction id"));
+	ASSERT_THAT(out.str(), HasSubstr("used data blocks"));
+	ASSERT_THAT(out.str(), Not(HasSubstr("metadata")));
+}
+
+//----------------------------------------------------------------
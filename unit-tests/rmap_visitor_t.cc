@@ -11,21 +11,26 @@ using namespace testing;
 namespace {
 	typedef rmap_visitor::rmap_region rmap_region;
 
+	void visit_block(rmap_visitor &v, uint32_t thin_dev, block_address thin_block,
+			 block_address data_block, uint32_t time) {
+		btree_path path;
+		path.push_back(thin_dev);
+		path.push_back(thin_block);
+
+		mapping_tree_detail::block_time bt;
+		bt.block_ = data_block;
+		bt.time_ = time;
+
+		v.visit(path, bt);
+	}
+
 	class RMapVisitorTests : public Test {
 	public:
 		RMapVisitorTests() {
 		}
 
 		void visit(uint32_t thin_dev, block_address thin_block, block_address data_block) {
-			btree_path path;
-			path.push_back(thin_dev);
-			path.push_back(thin_block);
-
-			mapping_tree_detail::block_time bt;
-			bt.block_ = data_block;
-			bt.time_ = 0;
-
-			rmap_v_.visit(path, bt);
+			visit_block(rmap_v_, thin_dev, thin_block, data_block, 0);
 		}
 
 		void run() {
@@ -176,4 +181,34 @@ TEST_F(RMapVisitorTests, rmap_is_sorted)
 	check_rmap_at(3, 400, 450, 5, 0);
 }
 
+TEST_F(RMapVisitorTests, times_are_ignored_by_default)
+{
+	add_data_region(0, 10);
+	for (block_address b = 0; b < 10; b++)
+		visit_block(rmap_v_, 0, b, b, b / 5);
+
+	run();
+
+	check_rmap_size(1);
+	check_rmap_at(0, 0, 10, 0, 0);
+}
+
+TEST(RMapVisitorSplitTests, regions_split_on_time)
+{
+	rmap_visitor v(true);
+	v.add_data_region(rmap_visitor::region(0, 10));
+	for (block_address b = 0; b < 10; b++)
+		visit_block(v, 0, b, b, b / 5);
+	v.complete();
+
+	vector<rmap_region> const &rmap = v.get_rmap();
+	ASSERT_THAT(rmap.size(), Eq(2u));
+	ASSERT_THAT(rmap[0].data_begin, Eq(0u));
+	ASSERT_THAT(rmap[0].data_end, Eq(5u));
+	ASSERT_THAT(rmap[0].time, Eq(0u));
+	ASSERT_THAT(rmap[1].data_begin, Eq(5u));
+	ASSERT_THAT(rmap[1].data_end, Eq(10u));
+	ASSERT_THAT(rmap[1].time, Eq(1u));
+}
+
 //----------------------------------------------------------------